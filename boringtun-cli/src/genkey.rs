@@ -0,0 +1,133 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! `boringtun-cli genkey`, `pubkey` and `genpsk`, the commands of wireguard-tools of the same
+//! names, to set up peers without wireguard-tools installed.
+//!
+//! Their output, their error messages and their exit codes are those of `wg`, the keys come from
+//! [`boringtun::keys`].
+
+use boringtun::keys::{
+    encode_base64, generate_preshared_key, generate_private_key, public_key_from_private,
+    PrivateKey,
+};
+use std::io::{self, Read, Write};
+
+/// The commands of this module, which an interface can't be named
+pub const COMMANDS: [&str; 3] = ["genkey", "pubkey", "genpsk"];
+
+/// The length of a key in base64, with its padding
+const KEY_LEN_BASE64: usize = 44;
+
+/// Run `command`, one of [`COMMANDS`], with the arguments after it, returns the exit code
+pub fn main(command: &str, args: &[String]) -> i32 {
+    if !args.is_empty() {
+        eprintln!("Usage: boringtun-cli {}", command);
+        return 1;
+    }
+
+    let key = match command {
+        "genkey" => {
+            warn_if_world_accessible();
+            generate_private_key().to_string()
+        }
+        "genpsk" => {
+            warn_if_world_accessible();
+            encode_base64(&generate_preshared_key())
+        }
+        _ => match pubkey(io::stdin().lock()) {
+            Ok(key) => key,
+            Err(message) => {
+                eprintln!("boringtun-cli: {}", message);
+                return 1;
+            }
+        },
+    };
+    match writeln!(io::stdout(), "{}", key) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// The public key of the private key in `input`, which may be followed by whitespace only
+fn pubkey(mut input: impl Read) -> Result<String, &'static str> {
+    const INVALID: &str = "Key is not the correct length or format";
+
+    let mut bytes = Vec::new();
+    if input.read_to_end(&mut bytes).is_err() || bytes.len() < KEY_LEN_BASE64 {
+        return Err(INVALID);
+    }
+    let (key, trailing) = bytes.split_at(KEY_LEN_BASE64);
+    // The whitespace of `isspace`, and the NUL bytes `wg` skips as well
+    if !trailing
+        .iter()
+        .all(|b| matches!(b, b'\0' | b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r'))
+    {
+        return Err("Trailing characters found after key");
+    }
+
+    let private: PrivateKey = std::str::from_utf8(key)
+        .ok()
+        .and_then(|key| key.parse().ok())
+        .ok_or(INVALID)?;
+    Ok(public_key_from_private(&private).to_string())
+}
+
+/// Warn that the key is readable by anyone when written to such a file, like `wg`
+fn warn_if_world_accessible() {
+    #[cfg(unix)]
+    {
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        if unsafe { libc::fstat(libc::STDOUT_FILENO, &mut stat) } == 0
+            && stat.st_mode & libc::S_IFMT == libc::S_IFREG
+            && stat.st_mode & libc::S_IRWXO != 0
+        {
+            eprintln!(
+                "Warning: writing to world accessible file.\n\
+                 Consider setting the umask to 077 and trying again."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pubkey() {
+        // The test vector of RFC 7748, section 6.1, as `echo KEY | wg pubkey`
+        let public = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+        for input in [
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\n",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo= \r\n\0",
+        ] {
+            assert_eq!(pubkey(input.as_bytes()).as_deref(), Ok(public));
+        }
+
+        assert_eq!(
+            pubkey(&b"dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=x\n"[..]),
+            Err("Trailing characters found after key")
+        );
+        for invalid in [
+            "",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo\n",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LC==",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCp=",
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LC!=",
+        ] {
+            assert_eq!(
+                pubkey(invalid.as_bytes()),
+                Err("Key is not the correct length or format")
+            );
+        }
+
+        // A generated key round trips through its encoding
+        let private = generate_private_key();
+        assert_eq!(
+            pubkey(private.to_string().as_bytes()),
+            Ok(public_key_from_private(&private).to_string())
+        );
+    }
+}
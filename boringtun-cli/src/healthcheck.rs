@@ -0,0 +1,243 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! `boringtun-cli healthcheck`, for the liveness and readiness probes of container orchestrators.
+//!
+//! The interface is healthy when it answers a UAPI `get` in time, and meets the criteria given.
+//! The verdict is a single line on the standard output, and the exit code tells the failures
+//! apart:
+//!
+//! | Code | |
+//! |------|-|
+//! | 0 | Healthy |
+//! | 1 | The device answered, but does not meet the criteria |
+//! | 2 | Invalid arguments |
+//! | 3 | The socket of the interface is missing |
+//! | 4 | The device did not answer in time, or not as it should |
+
+use crate::show::{self, Interface};
+use boringtun::keys::PublicKey;
+use clap::Parser;
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EXIT_CRITERIA_NOT_MET: i32 = 1;
+const EXIT_SOCKET_MISSING: i32 = 3;
+const EXIT_UNRESPONSIVE: i32 = 4;
+
+#[derive(Parser)]
+#[command(
+    name = "boringtun-cli healthcheck",
+    about = "Check that an interface answers, exits with 0 if it is healthy"
+)]
+struct Args {
+    /// The name of the interface
+    interface: String,
+
+    /// Require a handshake in the last SECS seconds, with at least one peer, or with each of the
+    /// peers of --require-peer
+    #[arg(long, value_name = "SECS")]
+    require_handshake_within: Option<u64>,
+
+    /// Require the peer with this public key, in base64, to be configured. Repeatable.
+    #[arg(long, value_name = "PUBLIC_KEY")]
+    require_peer: Vec<PublicKey>,
+
+    /// Give up on a device that did not answer after SECS seconds
+    #[arg(long, value_name = "SECS", default_value = "2", value_parser = parse_timeout)]
+    timeout: Duration,
+}
+
+fn parse_timeout(secs: &str) -> Result<Duration, String> {
+    secs.parse::<f64>()
+        .ok()
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("Invalid timeout {}, expected a number of seconds", secs))
+}
+
+/// Run `healthcheck` with the arguments after it, returns the exit code
+pub fn main(args: &[String]) -> i32 {
+    let args = Args::parse_from(
+        std::iter::once("boringtun-cli healthcheck").chain(args.iter().map(String::as_str)),
+    );
+
+    // A daemon that doesn't accept connections anymore blocks the connection once its backlog is
+    // full, the thread is left behind then
+    let (sender, receiver) = mpsc::channel();
+    let name = args.interface.clone();
+    std::thread::spawn(move || sender.send(show::get(&name)));
+    let (code, verdict) = match receiver.recv_timeout(args.timeout) {
+        Ok(Ok(interface)) => check(&interface, &args, now()),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => (
+            EXIT_SOCKET_MISSING,
+            format!("socket missing: {}", args.interface),
+        ),
+        Ok(Err(e)) => (
+            EXIT_UNRESPONSIVE,
+            format!("unresponsive: {}: {}", args.interface, e),
+        ),
+        Err(_) => (
+            EXIT_UNRESPONSIVE,
+            format!(
+                "unresponsive: {}: no answer in {:?}",
+                args.interface, args.timeout
+            ),
+        ),
+    };
+    println!("{}", verdict);
+    code
+}
+
+/// The exit code and the verdict for `interface`, at `now` seconds since the epoch
+fn check(interface: &Interface, args: &Args, now: u64) -> (i32, String) {
+    let not_met = |reason: String| {
+        (
+            EXIT_CRITERIA_NOT_MET,
+            format!("criteria not met: {}: {}", interface.name, reason),
+        )
+    };
+    // When the handshake was, in seconds ago
+    let age = |last_handshake: (u64, u32)| match last_handshake {
+        (0, 0) => None,
+        (secs, _) => Some(now.saturating_sub(secs)),
+    };
+
+    let mut required = vec![];
+    for key in &args.require_peer {
+        match interface
+            .peers
+            .iter()
+            .find(|peer| &peer.public_key == key.as_bytes())
+        {
+            Some(peer) => required.push((key, peer)),
+            None => return not_met(format!("peer {} is not configured", key)),
+        }
+    }
+
+    let latest = interface
+        .peers
+        .iter()
+        .filter_map(|peer| age(peer.last_handshake))
+        .min();
+    if let Some(within) = args.require_handshake_within {
+        let recent = |age: Option<u64>| age.is_some_and(|age| age <= within);
+        if required.is_empty() && !recent(latest) {
+            return not_met(format!("no handshake in the last {} seconds", within));
+        }
+        for (key, peer) in required {
+            if !recent(age(peer.last_handshake)) {
+                return not_met(format!(
+                    "no handshake with peer {} in the last {} seconds",
+                    key, within
+                ));
+            }
+        }
+    }
+
+    let mut verdict = format!(
+        "healthy: {}, {} peers",
+        interface.name,
+        interface.peers.len()
+    );
+    if let Some(age) = latest {
+        verdict += &format!(", latest handshake {} seconds ago", age);
+    }
+    (0, verdict)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use show::Peer;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(["healthcheck", "wg0"].iter().chain(flags)).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let (peer1, peer2) = (PublicKey::from([1; 32]), PublicKey::from([2; 32]));
+        let interface = Interface {
+            name: "wg0".to_owned(),
+            peers: vec![
+                Peer {
+                    public_key: [1; 32],
+                    last_handshake: (1000, 0),
+                    ..Default::default()
+                },
+                Peer {
+                    public_key: [2; 32],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let check = |flags: &[&str]| check(&interface, &args(flags), 1100);
+
+        assert_eq!(
+            check(&[]),
+            (
+                0,
+                "healthy: wg0, 2 peers, latest handshake 100 seconds ago".to_owned()
+            )
+        );
+        assert_eq!(check(&["--require-handshake-within", "100"]).0, 0);
+        assert_eq!(
+            check(&["--require-handshake-within", "99"]),
+            (
+                1,
+                "criteria not met: wg0: no handshake in the last 99 seconds".to_owned()
+            )
+        );
+
+        let peer1 = peer1.to_string();
+        let peer2 = peer2.to_string();
+        let peer3 = PublicKey::from([3; 32]).to_string();
+        assert_eq!(
+            check(&["--require-peer", &peer1, "--require-peer", &peer2]).0,
+            0
+        );
+        assert_eq!(
+            check(&["--require-peer", &peer3]),
+            (
+                1,
+                format!("criteria not met: wg0: peer {} is not configured", peer3)
+            )
+        );
+        let within = ["--require-handshake-within", "180", "--require-peer"];
+        assert_eq!(check(&[&within[..], &[&peer1]].concat()).0, 0);
+        assert_eq!(
+            check(&[&within[..], &[&peer2]].concat()),
+            (
+                1,
+                format!(
+                    "criteria not met: wg0: no handshake with peer {} in the last 180 seconds",
+                    peer2
+                )
+            )
+        );
+
+        let empty = Interface {
+            name: "wg0".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            super::check(&empty, &args(&[]), 1100),
+            (0, "healthy: wg0, 0 peers".to_owned())
+        );
+        assert!(Args::try_parse_from(["healthcheck", "wg0", "--timeout", "0"]).is_err());
+        assert_eq!(
+            args(&["--timeout", "0.5"]).timeout,
+            Duration::from_millis(500)
+        );
+    }
+}
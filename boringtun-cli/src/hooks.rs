@@ -0,0 +1,127 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The `PreUp`, `PostUp`, `PreDown` and `PostDown` commands of a `wg-quick` file, run the way
+//! `wg-quick` does: with `/bin/sh -c`, after `%i` is replaced by the name of the interface.
+
+use boringtun::device::config::Hooks;
+use std::process::{Command, Stdio};
+
+/// The only environment variable of the hooks
+const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// When the commands of [`Hooks`] run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::PreUp => "PreUp",
+            Stage::PostUp => "PostUp",
+            Stage::PreDown => "PreDown",
+            Stage::PostDown => "PostDown",
+        }
+    }
+}
+
+/// Runs the hooks of an interface
+#[derive(Debug, Default, Clone)]
+pub struct HookRunner {
+    pub hooks: Hooks,
+    /// Replaces `%i` in the commands
+    pub interface: String,
+}
+
+impl HookRunner {
+    fn commands(&self, stage: Stage) -> &[String] {
+        match stage {
+            Stage::PreUp => &self.hooks.pre_up,
+            Stage::PostUp => &self.hooks.post_up,
+            Stage::PreDown => &self.hooks.pre_down,
+            Stage::PostDown => &self.hooks.post_down,
+        }
+    }
+
+    /// Run a command of `stage`, its output is logged line by line with the name of the stage,
+    /// at the info level for stdout and the warn level for stderr
+    fn run_command(&self, stage: Stage, command: &str) -> Result<(), String> {
+        let command = command.replace("%i", &self.interface);
+        tracing::info!(message = "Running a hook", hook = stage.name(), command);
+        let output = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&command)
+            .env_clear()
+            .env("PATH", HOOK_PATH)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("{}: {}", command, e))?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            tracing::info!(message = line, hook = stage.name());
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            tracing::warn!(message = line, hook = stage.name());
+        }
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(format!("{}: {}", command, output.status)),
+        }
+    }
+
+    /// Run the commands of `stage` in order, up to the first one that fails
+    pub fn run(&self, stage: Stage) -> Result<(), String> {
+        self.commands(stage)
+            .iter()
+            .try_for_each(|command| self.run_command(stage, command))
+    }
+
+    /// Run all the commands of `stage`, those that fail are logged
+    pub fn run_best_effort(&self, stage: Stage) {
+        for command in self.commands(stage) {
+            if let Err(e) = self.run_command(stage, command) {
+                tracing::error!(message = "A hook failed", hook = stage.name(), error = e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_hooks() {
+        let dir = std::env::temp_dir().join(format!("boringtun-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+        let runner = HookRunner {
+            hooks: Hooks {
+                pre_up: vec![format!("echo %i-$HOME >> {}", out.display())],
+                post_up: vec![
+                    "false".to_owned(),
+                    format!("echo second >> {}", out.display()),
+                ],
+                ..Default::default()
+            },
+            interface: "wg7".to_owned(),
+        };
+
+        // `%i` is replaced, and the environment is cleared
+        runner.run(Stage::PreUp).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "wg7-\n");
+
+        // A failure stops the stage, unless it is best effort
+        assert!(runner.run(Stage::PostUp).is_err());
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "wg7-\n");
+        runner.run_best_effort(Stage::PostUp);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "wg7-\nsecond\n");
+        assert!(runner.run(Stage::PostDown).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
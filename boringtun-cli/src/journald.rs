@@ -0,0 +1,235 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Logging to the systemd journal with its native protocol, for `--journald`: each event is a
+//! datagram sent to the socket of journald, made of `KEY=value` fields.
+//!
+//! The message of an event is its `MESSAGE`, and its level its `PRIORITY`, from 3 for errors to 7
+//! for traces. The other fields of the event and those of its spans, such as the peer of the
+//! events of a peer, are journal fields with their names in uppercase, so `journalctl PEER=...`
+//! selects the logs of a peer. Entries too large for a datagram are dropped.
+
+use std::fmt::{self, Write};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Where journald receives the entries
+const JOURNALD_PATH: &str = "/run/systemd/journal/socket";
+
+/// A layer that sends the events to the journal
+pub struct Layer {
+    sock: UnixDatagram,
+}
+
+/// The journal fields of a span, already encoded
+struct SpanFields(Vec<u8>);
+
+impl Layer {
+    /// Connect to journald. Connecting up front lets the entries through after the privileges are
+    /// dropped, and in a sandbox.
+    pub fn new() -> io::Result<Layer> {
+        Layer::connect(JOURNALD_PATH)
+    }
+
+    fn connect(path: impl AsRef<Path>) -> io::Result<Layer> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path)?;
+        Ok(Layer { sock })
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span was just created");
+        let mut fields = vec![];
+        attrs.record(&mut FieldVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span exists");
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut FieldVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut entry = vec![];
+        put_field(
+            &mut entry,
+            "PRIORITY",
+            priority(metadata.level()).as_bytes(),
+        );
+        put_field(&mut entry, "SYSLOG_IDENTIFIER", b"boringtun");
+        put_field(&mut entry, "TARGET", metadata.target().as_bytes());
+        if let Some(file) = metadata.file() {
+            put_field(&mut entry, "CODE_FILE", file.as_bytes());
+        }
+        if let Some(line) = metadata.line() {
+            put_field(&mut entry, "CODE_LINE", line.to_string().as_bytes());
+        }
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    entry.extend_from_slice(fields);
+                }
+            }
+        }
+        event.record(&mut FieldVisitor(&mut entry));
+        // There is nowhere left to report the failure
+        let _ = self.sock.send(&entry);
+    }
+}
+
+/// The syslog priority of the entries of `level`
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "5",
+        Level::DEBUG => "6",
+        Level::TRACE => "7",
+    }
+}
+
+/// The journal field of a tracing field: uppercase letters, digits and underscores, not starting
+/// with an underscore, which is reserved for the fields journald adds
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches('_').to_owned()
+}
+
+/// Append a field to an entry, in the binary form when the value is on several lines
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+/// Encodes the fields it visits as journal fields
+struct FieldVisitor<'a>(&'a mut Vec<u8>);
+
+impl FieldVisitor<'_> {
+    fn put(&mut self, field: &Field, value: &str) {
+        let name = match field.name() {
+            "message" => "MESSAGE".to_owned(),
+            name => field_name(name),
+        };
+        if !name.is_empty() {
+            put_field(self.0, &name, value.as_bytes());
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.put(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut formatted = String::new();
+        let _ = write!(formatted, "{:?}", value);
+        self.put(field, &formatted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_put_field() {
+        let mut entry = vec![];
+        put_field(&mut entry, "MESSAGE", b"one line");
+        assert_eq!(entry, b"MESSAGE=one line\n");
+
+        let mut entry = vec![];
+        put_field(&mut entry, "MESSAGE", b"two\nlines");
+        assert_eq!(entry, b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n");
+
+        assert_eq!(field_name("log.target"), "LOG_TARGET");
+        assert_eq!(field_name("_hidden"), "HIDDEN");
+    }
+
+    #[test]
+    fn test_journald_layer() {
+        let path = std::env::temp_dir().join(format!("boringtun-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let layer = Layer::connect(&path).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("peer", peer = "dGVzdGtl");
+            let _entered = span.enter();
+            tracing::warn!(message = "Handshake failed", endpoint = ?"127.0.0.1:51820", index = 7);
+        });
+
+        let mut buf = [0u8; 1024];
+        let n = journal.recv(&mut buf).unwrap();
+        let entry = String::from_utf8(buf[..n].to_vec()).unwrap();
+        let fields: Vec<_> = entry.lines().collect();
+        assert!(fields.contains(&"PRIORITY=4"), "{}", entry);
+        assert!(fields.contains(&"SYSLOG_IDENTIFIER=boringtun"));
+        assert!(fields.contains(&"PEER=dGVzdGtl"));
+        assert!(fields.contains(&"MESSAGE=Handshake failed"));
+        assert!(fields.contains(&"ENDPOINT=\"127.0.0.1:51820\""));
+        assert!(fields.contains(&"INDEX=7"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_journald_multiline() {
+        let path =
+            std::env::temp_dir().join(format!("boringtun-journal-ml-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let layer = Layer::connect(&path).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("first line\nsecond line");
+        });
+
+        let mut buf = [0u8; 1024];
+        let n = journal.recv(&mut buf).unwrap();
+        let entry = &buf[..n];
+        // The value is framed by its length as a 64 bit little endian integer, and a newline
+        let mut framed = b"\nMESSAGE\n".to_vec();
+        framed.extend_from_slice(&22u64.to_le_bytes());
+        framed.extend_from_slice(b"first line\nsecond line\n");
+        assert!(
+            entry.ends_with(&framed),
+            "{:?}",
+            String::from_utf8_lossy(entry)
+        );
+        assert!(entry.starts_with(b"PRIORITY=3\n"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
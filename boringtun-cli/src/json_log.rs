@@ -0,0 +1,193 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Logging as JSON, for `--log-format json`: one object per line, like
+//!
+//! ```text
+//! {"timestamp":"2024-05-01T12:00:00.000000Z","level":"WARN","target":"boringtun::device",
+//!  "fields":{"message":"Handshake failed","error":"InvalidMac"},
+//!  "span":{"name":"peer","peer":"dGVzdGtl"},"spans":[{"name":"peer","peer":"dGVzdGtl"}]}
+//! ```
+//!
+//! on a single line. The fields of the event are in `fields`, those of the innermost span in
+//! `span` and those of all the spans, from the outermost, in `spans`, as the JSON format of
+//! `tracing-subscriber` does.
+
+use boringtun::device::dump::rfc3339;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Write;
+use std::time::SystemTime;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// A layer that writes the events as JSON lines to the writers of `W`
+pub struct Layer<W> {
+    make_writer: W,
+}
+
+/// The fields of a span as a JSON object, with its name
+struct SpanFields(Map<String, Value>);
+
+impl<W> Layer<W> {
+    pub fn new(make_writer: W) -> Layer<W> {
+        Layer { make_writer }
+    }
+}
+
+impl<S, W> tracing_subscriber::Layer<S> for Layer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span was just created");
+        let mut fields = Map::new();
+        fields.insert("name".to_owned(), Value::from(span.name()));
+        attrs.record(&mut FieldVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span exists");
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut FieldVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut entry = Map::new();
+        entry.insert(
+            "timestamp".to_owned(),
+            rfc3339(SystemTime::now(), true).into(),
+        );
+        entry.insert("level".to_owned(), metadata.level().as_str().into());
+        entry.insert("target".to_owned(), metadata.target().into());
+        let mut fields = Map::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        entry.insert("fields".to_owned(), fields.into());
+
+        let spans: Vec<Value> = ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .filter_map(|span| {
+                let extensions = span.extensions();
+                let SpanFields(fields) = extensions.get::<SpanFields>()?;
+                Some(Value::from(fields.clone()))
+            })
+            .collect();
+        if let Some(span) = spans.last() {
+            entry.insert("span".to_owned(), span.clone());
+            entry.insert("spans".to_owned(), spans.into());
+        }
+
+        let mut line = Value::from(entry).to_string();
+        line.push('\n');
+        // There is nowhere left to report the failure
+        let _ = self.make_writer.make_writer().write_all(line.as_bytes());
+    }
+}
+
+/// Log the panics of all threads as errors with the `panic` target, instead of writing them to
+/// the standard error, which isn't JSON
+pub fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => payload
+                .downcast_ref::<String>()
+                .map_or("Box<dyn Any>", |message| message.as_str()),
+        };
+        let thread = std::thread::current();
+        tracing::error!(
+            target: "panic",
+            message,
+            thread = thread.name().unwrap_or("<unnamed>"),
+            location = info.location().map(|location| location.to_string()),
+        );
+    }));
+}
+
+/// Adds the fields it visits to a JSON object, keeping numbers and booleans as such
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// A writer whose output the test reads back
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_layer() {
+        let buffer = SharedBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(Layer::new(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("peer", peer = "dGVzdGtl");
+            let _entered = span.enter();
+            tracing::warn!(message = "Handshake failed", endpoint = ?"127.0.0.1:51820", index = 7);
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1, "{}", output);
+        let entry: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(entry["level"], "WARN");
+        assert_eq!(entry["target"], module_path!());
+        assert_eq!(entry["fields"]["message"], "Handshake failed");
+        assert_eq!(entry["fields"]["endpoint"], "\"127.0.0.1:51820\"");
+        assert_eq!(entry["fields"]["index"], 7);
+        assert_eq!(entry["span"]["name"], "peer");
+        assert_eq!(entry["span"]["peer"], "dGVzdGtl");
+        assert_eq!(entry["spans"][0], entry["span"]);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The log file of `--log-target file:PATH`, rotated by size with `--log-max-size`, and reopened
+//! on `SIGUSR2` for logrotate and the like.
+//!
+//! The file is written by the threads that log, each line under a lock, a background writer
+//! thread would not survive daemonizing. The write that would take the file past its maximum
+//! size first moves it to `PATH.1`, the previous `PATH.1` to `PATH.2` and so on up to
+//! `--log-max-files`, the oldest being deleted, and starts a new file. As such it never splits a
+//! line between files.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// When to rotate the log file, and how many rotated files to keep
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+/// Parse a size in bytes, optionally with a `K`, `M` or `G` suffix for KiB, MiB or GiB
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| {
+            format!(
+                "Invalid size {}, expected a number of bytes, such as 10M",
+                size
+            )
+        })
+}
+
+pub struct LogFile(Mutex<Inner>);
+
+struct Inner {
+    file: File,
+    /// Absolute, the working directory changes when the process daemonizes
+    path: PathBuf,
+    /// The bytes written to `file`
+    size: u64,
+    rotation: Option<Rotation>,
+}
+
+impl LogFile {
+    /// Create the file at `path`, truncating it if it exists
+    pub fn create(path: &Path, rotation: Option<Rotation>) -> io::Result<LogFile> {
+        let path = std::path::absolute(path)?;
+        Ok(LogFile(Mutex::new(Inner {
+            file: File::create(&path)?,
+            path,
+            size: 0,
+            rotation,
+        })))
+    }
+
+    /// Open the file at the path again, appending to it, once another process moved it away
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&inner.path)?;
+        inner.size = inner.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // A thread that panicked while logging left the file as usable as it was
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    /// The path of the rotated file `n`, `n` being 1 for the most recent
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        if max_files > 0 {
+            remove_if_exists(&self.rotated(max_files))?;
+            for n in (1..max_files).rev() {
+                rename_if_exists(&self.rotated(n), &self.rotated(n + 1))?;
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Each `write` is a whole line, the log formatters write their lines at once
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if let Some(rotation) = inner.rotation {
+            if inner.size > 0 && inner.size + buf.len() as u64 > rotation.max_size {
+                // The directory may not be writable anymore once the privileges are dropped, or
+                // out of reach in the sandbox. The logs then stay in the current file, which is
+                // offered to rotate again once it grew as much.
+                if inner.rotate(rotation.max_files).is_err() {
+                    inner.size = 0;
+                }
+            }
+        }
+        inner.file.write_all(buf)?;
+        inner.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().file.flush()
+    }
+}
+
+/// Reopen `log_file` every time the process receives `SIGUSR2`, from a thread of its own. Must be
+/// called before any other thread is started, they have to block the signal as well.
+#[cfg(unix)]
+pub fn reopen_on_sigusr2(log_file: std::sync::Arc<LogFile>) -> io::Result<()> {
+    let mut signals = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGUSR2);
+    }
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) } {
+        0 => {}
+        e => return Err(io::Error::from_raw_os_error(e)),
+    }
+
+    std::thread::Builder::new()
+        .name("log-reopen".to_owned())
+        .spawn(move || {
+            // The signals the device handles later on, such as SIGUSR1, would otherwise be
+            // delivered to this thread, and kill the process
+            let mut all = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+            unsafe {
+                libc::sigfillset(&mut all);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &all, std::ptr::null_mut());
+            }
+            loop {
+                let mut signal = 0;
+                if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                    continue;
+                }
+                match log_file.reopen() {
+                    Ok(()) => tracing::info!("Reopened the log file"),
+                    Err(e) => {
+                        tracing::error!(message = "Failed to reopen the log file", error = ?e)
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10MB").is_err());
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let dir = std::env::temp_dir().join(format!("boringtun-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("boringtun.out");
+        let rotation = Rotation {
+            max_size: 10,
+            max_files: 2,
+        };
+        let log_file = LogFile::create(&path, Some(rotation)).unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+        // Lines are never split, a line longer than the maximum gets a file of its own
+        for line in [
+            "1111\n",
+            "2222\n",
+            "3333\n",
+            "4444\n",
+            "55555555555\n",
+            "6666\n",
+        ] {
+            (&log_file).write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(read("boringtun.out"), "6666\n");
+        assert_eq!(read("boringtun.out.1"), "55555555555\n");
+        assert_eq!(read("boringtun.out.2"), "3333\n4444\n");
+        assert!(!dir.join("boringtun.out.3").exists());
+
+        // Once moved away, the file is created again, and the rotation picks up from its size
+        std::fs::rename(&path, dir.join("moved")).unwrap();
+        log_file.reopen().unwrap();
+        (&log_file).write_all(b"7777\n").unwrap();
+        (&log_file).write_all(b"8888\n").unwrap();
+        assert_eq!(read("moved"), "6666\n");
+        assert_eq!(read("boringtun.out"), "7777\n8888\n");
+        log_file.reopen().unwrap();
+        (&log_file).write_all(b"9999\n").unwrap();
+        assert_eq!(read("boringtun.out"), "9999\n");
+        assert_eq!(read("boringtun.out.1"), "7777\n8888\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
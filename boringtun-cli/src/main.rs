@@ -1,25 +1,176 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use boringtun::device::drop_privileges::drop_privileges;
-use boringtun::device::{DeviceConfig, DeviceHandle};
-use clap::{command, Parser};
+mod genkey;
+#[cfg(unix)]
+mod healthcheck;
+#[cfg(unix)]
+mod hooks;
+#[cfg(target_os = "linux")]
+mod journald;
+mod json_log;
+mod log_file;
+#[cfg(unix)]
+mod pid_file;
+#[cfg(unix)]
+mod show;
+#[cfg(unix)]
+mod syslog;
+#[cfg(target_os = "linux")]
+mod systemd;
+
+use boringtun::device::config::{read_private_key, read_private_key_file, WgConfig};
+#[cfg(unix)]
+use boringtun::device::drop_privileges::DropTarget;
+use boringtun::device::peer::AllowedIP;
+use boringtun::device::stats_log::STATS_LOG_TARGET;
+#[cfg(target_os = "linux")]
+use boringtun::device::{socket_activation, ReloadHook};
+use boringtun::device::{DeviceConfig, DeviceHandle, OuterTransportFactory, Socks5, MIN_MTU};
+use boringtun::keys::PrivateKey;
+#[cfg(feature = "grpc")]
+use boringtun_grpc::ServeGrpc;
+#[cfg(feature = "grpc-tls")]
+use boringtun_grpc::{Identity, ServerTlsConfig};
+use clap::{Parser, ValueEnum};
+#[cfg(unix)]
 use daemonize::Daemonize;
+#[cfg(unix)]
+use hooks::{HookRunner, Stage};
+use log_file::{LogFile, Rotation};
+#[cfg(unix)]
+use pid_file::PidFile;
 use std::borrow::Cow;
-use std::fs::File;
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(unix)]
 use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::Arc;
 use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 fn check_tun_name(v: &str) -> Result<String, String> {
-    if cfg!(any(target_os = "macos", target_os = "ios")) {
-        if boringtun::device::tun::parse_utun_name(v).is_ok() {
-            Ok(v.to_owned())
-        } else {
-            Err("Tunnel name must have the format 'utun[0-9]+', use 'utun' for automatic assignment".to_owned())
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    if boringtun::device::tun::parse_utun_name(v).is_err() {
+        return Err(
+            "Tunnel name must have the format 'utun[0-9]+', use 'utun' for automatic assignment"
+                .to_owned(),
+        );
+    }
+    #[cfg(target_os = "freebsd")]
+    if boringtun::device::tun::parse_tun_name(v).is_err() {
+        return Err("Tunnel name must be a valid interface name".to_owned());
+    }
+    #[cfg(target_os = "openbsd")]
+    if boringtun::device::tun::parse_tun_name(v).is_err() {
+        return Err("Tunnel name must have the format 'tun[0-9]+'".to_owned());
+    }
+    #[cfg(windows)]
+    if boringtun::device::tun::parse_tun_name(v).is_err() {
+        return Err("Tunnel name must be a valid adapter name".to_owned());
+    }
+
+    Ok(v.to_owned())
+}
+
+#[cfg(unix)]
+fn default_log_file() -> String {
+    String::from("/tmp/boringtun.out")
+}
+
+#[cfg(windows)]
+fn default_log_file() -> String {
+    std::env::temp_dir()
+        .join("boringtun.out")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Tells the parent process whether the tunnel started, once it is daemonized
+struct StartupNotifier {
+    #[cfg(unix)]
+    sock: UnixDatagram,
+    /// Their PostDown commands run when the startup fails, once the PreUp ones started
+    #[cfg(unix)]
+    hooks: Option<HookRunner>,
+    /// Removed when the startup fails, the process exits without dropping it
+    #[cfg(unix)]
+    pid_file: Option<PathBuf>,
+}
+
+impl StartupNotifier {
+    fn fail(&self) -> ! {
+        #[cfg(unix)]
+        if let Some(hooks) = &self.hooks {
+            hooks.run_best_effort(Stage::PostDown);
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.pid_file {
+            let _ = std::fs::remove_file(path);
+        }
+        #[cfg(unix)]
+        self.sock.send(&[0]).unwrap();
+        exit(1);
+    }
+
+    fn succeed(self) {
+        #[cfg(unix)]
+        self.sock.send(&[1]).unwrap();
+    }
+}
+
+/// How the log lines are formatted, see `--log-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Plain,
+    Json,
+}
+
+/// Where the logs go, see `--log-target`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LogTarget {
+    File(PathBuf),
+    Syslog,
+    Journald,
+    Stderr,
+    /// The standard output, in the foreground without a target
+    Terminal,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogTarget, String> {
+        match s {
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            "stderr" => Ok(LogTarget::Stderr),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(LogTarget::File(PathBuf::from(path))),
+                _ => Err(format!(
+                    "Unknown log target {}, expected file:PATH, syslog, journald or stderr",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogTarget::File(path) => write!(f, "file:{}", path.display()),
+            LogTarget::Syslog => f.write_str("syslog"),
+            LogTarget::Journald => f.write_str("journald"),
+            LogTarget::Stderr => f.write_str("stderr"),
+            LogTarget::Terminal => f.write_str("the terminal"),
         }
-    } else {
-        Ok(v.to_owned())
     }
 }
 
@@ -30,7 +181,8 @@ struct Args {
     #[clap(value_parser = check_tun_name)]
     interface_name: String,
 
-    /// Run and log in the foreground
+    /// Run and log in the foreground. On Windows boringtun never daemonizes, this only selects
+    /// logging to the terminal.
     #[clap(long, short)]
     foreground: bool,
 
@@ -38,67 +190,530 @@ struct Args {
     #[clap(long, short, env = "WG_THREADS", default_value_t = 4)]
     threads: usize,
 
+    /// Number of threads that process handshakes, apart from those forwarding the data. With 0,
+    /// or with a single thread, handshakes are processed inline.
+    #[clap(long, env = "WG_HANDSHAKE_THREADS", default_value_t = 1)]
+    handshake_threads: usize,
+
+    /// Send the packets of the peers through a SOCKS5 proxy that supports UDP ASSOCIATE, given as
+    /// socks5://[user:pass@]host:port. Connected UDP sockets are not used then.
+    #[clap(long, env = "WG_PROXY")]
+    proxy: Option<Socks5>,
+
     /// Log verbosity
     #[clap(long, short, env = "WG_LOG_LEVEL", default_value_t = Level::ERROR)]
     verbosity: Level,
 
+    /// Log the public keys of the peers in full, instead of their first 16 characters
+    #[clap(long)]
+    log_public_keys: bool,
+
     /// File descriptor for the user API. Linux only.
     #[clap(long, env = "WG_UAPI_FD", default_value_t = -1)]
     uapi_fd: i32,
 
-    /// File descriptor for an already-existing TUN device
-    #[clap(long, env = "WG_TUN_FD", default_value_t = -1)]
-    tun_fd: i32,
+    /// File descriptor for an already-existing TUN device. On macOS INTERFACE_NAME must be the name
+    /// of its utun interface. On Linux it can be repeated, or a comma-separated list, for the
+    /// queues of a multi-queue device named INTERFACE_NAME. Not supported on Windows.
+    #[clap(long, env = "WG_TUN_FD", value_delimiter = ',')]
+    tun_fd: Vec<i32>,
 
-    /// Log file
-    #[clap(long, short, env = "WG_LOG_FILE", default_value_t = String::from("/tmp/boringtun.out"))]
+    /// Log file, where the logs go without --log-target unless in the foreground
+    #[clap(long, short, env = "WG_LOG_FILE", default_value_t = default_log_file())]
     log: String,
 
-    /// Do not drop sudo privileges
+    /// Where the logs go: file:PATH, syslog, journald or stderr. syslog sends RFC 5424 messages
+    /// to /dev/log with the facility of --syslog-facility, and is not supported on Windows.
+    /// journald is the same as --journald. When the target can't be opened, boringtun logs to the
+    /// standard error instead, which is lost once it daemonizes.
+    #[clap(long, env = "WG_LOG_TARGET")]
+    log_target: Option<LogTarget>,
+
+    /// Rotate the log file before it grows past this size, in bytes or with a K, M or G suffix:
+    /// FILE is moved to FILE.1, FILE.1 to FILE.2 and so on. By default it grows without bound,
+    /// and is reopened on SIGUSR2 once logrotate moved it.
+    #[clap(long, env = "WG_LOG_MAX_SIZE", value_parser = log_file::parse_size)]
+    log_max_size: Option<u64>,
+
+    /// The number of rotated log files kept with --log-max-size, the oldest is deleted. With 0
+    /// the log file starts over empty.
+    #[clap(long, env = "WG_LOG_MAX_FILES", default_value_t = 5)]
+    log_max_files: usize,
+
+    /// The syslog facility of the messages of --log-target syslog, such as daemon or local0
+    #[cfg(unix)]
+    #[clap(long, value_parser = syslog::parse_facility, default_value = "daemon")]
+    syslog_facility: u8,
+
+    /// The format of the logs, in the log file or the terminal. With json they are one object per
+    /// line, with the timestamp, level, target, the fields of the event and those of its spans,
+    /// such as the peer, and panics are logged as errors.
+    #[clap(long, env = "WG_LOG_FORMAT", value_enum, default_value_t = LogFormat::Plain)]
+    log_format: LogFormat,
+
+    /// Log to the systemd journal instead of the log file or the terminal, with the fields of the
+    /// events, such as the peer, as journal fields, unless --log-target says otherwise. Linux
+    /// only.
+    #[clap(long)]
+    journald: bool,
+
+    /// Write the pid of boringtun to this file, the one of the daemon when it daemonizes, and
+    /// keep it locked until boringtun exits, so another instance given the same file fails to
+    /// start. It is removed on exit. Not supported on Windows.
+    #[clap(long, env = "WG_PID_FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Do not drop sudo privileges. Not supported on Windows, where they are never dropped.
     #[clap(long, env = "WG_SUDO")]
     disable_drop_privileges: bool,
 
-    /// Disable connected UDP sockets to each peer
+    /// Switch to this user, by name or uid, when dropping privileges, instead of the one who
+    /// started boringtun
+    #[clap(long, conflicts_with = "disable_drop_privileges")]
+    user: Option<String>,
+
+    /// Switch to this group, by name or gid, when dropping privileges, instead of the primary
+    /// group of the user
+    #[clap(long, conflicts_with = "disable_drop_privileges")]
+    group: Option<String>,
+
+    /// Capabilities to keep after dropping privileges, CAP_NET_ADMIN and CAP_NET_RAW, e.g. to set
+    /// a fwmark. Linux only.
+    #[clap(
+        long,
+        value_delimiter = ',',
+        conflicts_with = "disable_drop_privileges"
+    )]
+    keep_caps: Vec<String>,
+
+    /// Chroot to this empty directory, given as --sandbox=DIR, /var/empty by default, when
+    /// privileges are dropped. It is created if it doesn't exist. Endpoints must then be
+    /// addresses, not host names. Not supported on Windows.
+    #[clap(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "/var/empty",
+        conflicts_with_all = ["disable_drop_privileges", "config", "peer_state_file"]
+    )]
+    sandbox: Option<PathBuf>,
+
+    /// Disable connected UDP sockets to each peer. They are never used on Windows.
     #[clap(long)]
     disable_connected_udp: bool,
 
     /// Disable using multiple queues for the tunnel interface. Linux only.
     #[clap(long)]
     disable_multi_queue: bool,
+
+    /// Restrict the syscalls of the process with seccomp after dropping privileges. Linux only.
+    #[clap(long)]
+    enable_seccomp: bool,
+
+    /// Add an address to the interface, e.g. 10.0.0.1/24. Can be repeated. Linux only.
+    #[clap(long, value_parser = AllowedIP::parse_address)]
+    address: Vec<AllowedIP>,
+
+    /// The MTU of the network towards the peers, from 1280 to 65535. The MTU of the interface is
+    /// set to it minus the encapsulation overhead. Linux only.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(i64::from(MIN_MTU)..))]
+    mtu: Option<u16>,
+
+    /// Bring the interface up once it is created. Linux only.
+    #[clap(long)]
+    up: bool,
+
+    /// Don't add routes for the allowed IPs of the peers of the configuration file. Linux only.
+    #[clap(long)]
+    no_routes: bool,
+
+    /// Let `wg set` give a peer allowed IPs that contain, or are contained in, those of another
+    /// peer, instead of failing with EEXIST
+    #[clap(long)]
+    allow_overlapping_ips: bool,
+
+    /// Also drop the packets a peer sends from an address that is routed to another peer, which
+    /// its overlapping allowed IPs would let through, see --allow-overlapping-ips
+    #[clap(long)]
+    strict_source_check: bool,
+
+    /// Don't run the PreUp, PostUp, PreDown and PostDown commands of the configuration file
+    #[clap(long)]
+    no_hooks: bool,
+
+    /// Apply this configuration file, in the `wg setconf` format, at startup and again on SIGHUP.
+    /// Like wg-quick, the Address and MTU of the file are set on the interface, and routes are
+    /// added for the allowed IPs of the peers, on Linux. A default route uses the fwmark as its
+    /// routing table, 51820 if unset, with policy rules that need CAP_NET_ADMIN in --keep-caps to
+    /// be deleted on exit. The PreUp, PostUp, PreDown and PostDown commands run with /bin/sh and
+    /// %i replaced by the name of the interface, except on Windows. PreDown and PostDown only
+    /// have the privileges left once they are dropped. The other keys only wg-quick knows are
+    /// ignored with a warning.
+    #[clap(long, env = "WG_CONFIG_FILE")]
+    config: Option<PathBuf>,
+
+    /// Save the last known endpoints of peers to this file, and restore them on startup
+    #[clap(long, env = "WG_PEER_STATE_FILE")]
+    peer_state_file: Option<PathBuf>,
+
+    /// Capture the packets of the tunnel to this pcap file, as they are before encryption and
+    /// after decryption, for Wireshark or tcpdump. The UAPI key bt_pcap_capture=false leaves the
+    /// packets of a peer out.
+    #[clap(long, env = "WG_PCAP_FILE")]
+    pcap_file: Option<PathBuf>,
+
+    /// Move the pcap file to FILE.1 and start a new one before it grows past this size, in bytes
+    /// or with a K, M or G suffix. By default it grows without bound.
+    #[clap(long, env = "WG_PCAP_MAX_SIZE", value_parser = log_file::parse_size)]
+    pcap_max_size: Option<u64>,
+
+    /// Read the private key of the interface from this file, which must only be accessible by
+    /// the user running boringtun, or from the standard input with `-`. It wins over the
+    /// PrivateKey of --config.
+    #[clap(long, env = "WG_PRIVATE_KEY_FILE")]
+    private_key_file: Option<PathBuf>,
+
+    /// Listen on this port from the start, instead of a random one. It wins over the ListenPort
+    /// of --config.
+    #[clap(long, env = "WG_LISTEN_PORT")]
+    listen_port: Option<u16>,
+
+    /// Exit gracefully once no data packet was sent or received with any peer for SECS seconds.
+    /// Keepalives and handshakes don't count, UAPI connections start the period over. With the
+    /// socket activation of systemd, the next wg command starts boringtun again.
+    #[clap(long, env = "WG_EXIT_ON_IDLE", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    exit_on_idle: Option<u64>,
+
+    /// Do not check the owner and permissions of the private key file, for test environments
+    #[clap(long)]
+    skip_key_permission_check: bool,
+
+    /// Serve metrics in the Prometheus format on `/metrics` at this address, such as
+    /// 127.0.0.1:9586
+    #[clap(long, env = "WG_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Serve the gRPC management API of boringtun-grpc at this address, such as 127.0.0.1:51821.
+    /// The API can change the whole configuration, so it must not be reachable by anyone else.
+    #[cfg(feature = "grpc")]
+    #[clap(long, env = "WG_GRPC_LISTEN")]
+    grpc_listen: Option<SocketAddr>,
+
+    /// Serve the gRPC API over TLS with this PEM certificate chain, along with --grpc-tls-key
+    #[cfg(feature = "grpc-tls")]
+    #[clap(long, env = "WG_GRPC_TLS_CERT", requires_all = ["grpc_listen", "grpc_tls_key"])]
+    grpc_tls_cert: Option<PathBuf>,
+
+    /// The PEM private key of --grpc-tls-cert
+    #[cfg(feature = "grpc-tls")]
+    #[clap(long, env = "WG_GRPC_TLS_KEY", requires = "grpc_tls_cert")]
+    grpc_tls_key: Option<PathBuf>,
 }
 
 impl Args {
     pub fn tun_name(&self) -> Cow<'_, str> {
-        if self.tun_fd >= 0 {
-            return Cow::from(self.tun_fd.to_string());
+        // On Linux and macOS the descriptors are passed in the configuration, and the interface
+        // keeps its name
+        if let (Some(fd), false) = (
+            self.tun_fd.first(),
+            cfg!(any(target_os = "linux", target_os = "macos")),
+        ) {
+            return Cow::from(fd.to_string());
         }
         Cow::from(&self.interface_name)
     }
+
+    /// The account to drop privileges to, its user and group must exist
+    #[cfg(unix)]
+    fn drop_target(&self) -> Result<DropTarget, boringtun::device::Error> {
+        let target = DropTarget::lookup(self.user.as_deref(), self.group.as_deref())?;
+        #[cfg(target_os = "linux")]
+        let target = DropTarget {
+            keep_caps: self
+                .keep_caps
+                .iter()
+                .map(|cap| cap.parse())
+                .collect::<Result<_, _>>()
+                .map_err(boringtun::device::Error::DropPrivileges)?,
+            ..target
+        };
+        Ok(target)
+    }
+
+    fn log_target(&self) -> LogTarget {
+        match &self.log_target {
+            Some(target) => target.clone(),
+            None if self.journald => LogTarget::Journald,
+            None if self.foreground => LogTarget::Terminal,
+            None => LogTarget::File(PathBuf::from(&self.log)),
+        }
+    }
+
+    /// Exit with an error if flags that would have no effect on this platform are set
+    #[cfg(not(target_os = "linux"))]
+    fn reject_unsupported_flags(&self) {
+        use clap::{error::ErrorKind, CommandFactory};
+
+        let flags = [
+            ("--uapi-fd", self.uapi_fd >= 0),
+            ("--disable-multi-queue", self.disable_multi_queue),
+            ("--no-routes", self.no_routes),
+            ("--enable-seccomp", self.enable_seccomp),
+            ("--keep-caps", !self.keep_caps.is_empty()),
+            ("--journald", self.journald),
+            (
+                "--log-target journald",
+                self.log_target == Some(LogTarget::Journald),
+            ),
+            #[cfg(windows)]
+            (
+                "--log-target syslog",
+                self.log_target == Some(LogTarget::Syslog),
+            ),
+            #[cfg(windows)]
+            ("--user", self.user.is_some()),
+            #[cfg(windows)]
+            ("--group", self.group.is_some()),
+            #[cfg(windows)]
+            ("--sandbox", self.sandbox.is_some()),
+            ("several --tun-fd", self.tun_fd.len() > 1),
+            #[cfg(windows)]
+            ("--tun-fd", !self.tun_fd.is_empty()),
+            #[cfg(windows)]
+            ("--pid-file", self.pid_file.is_some()),
+            #[cfg(windows)]
+            ("--disable-drop-privileges", self.disable_drop_privileges),
+        ];
+        if let Some((flag, _)) = flags.iter().find(|(_, set)| *set) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{} is not supported on this platform", flag),
+                )
+                .exit();
+        }
+    }
+}
+
+/// Give the log file to the account of `target`, if it was chosen, before switching to it
+#[cfg(unix)]
+fn chown_log_file(
+    log_target: &LogTarget,
+    target: &DropTarget,
+) -> Result<(), boringtun::device::Error> {
+    let path = match log_target {
+        LogTarget::File(path) if target.is_explicit() => path,
+        _ => return Ok(()),
+    };
+    let (uid, gid) = target.resolve()?;
+    std::os::unix::fs::chown(path, Some(uid), Some(gid)).map_err(boringtun::device::Error::IoError)
+}
+
+/// Set up the logs of `--log-target`, falling back to the standard error when the target can't
+/// be opened. Returns where the logs go, and the log file if they go to one.
+fn init_logging(args: &Args) -> (LogTarget, Option<Arc<LogFile>>) {
+    let target = args.log_target();
+    let mut log_file = None;
+    let result = match &target {
+        LogTarget::Terminal => {
+            match args.log_format {
+                LogFormat::Plain => tracing_subscriber::fmt()
+                    .pretty()
+                    .with_max_level(Level::TRACE)
+                    .finish()
+                    .with(log_filter(args.verbosity))
+                    .init(),
+                LogFormat::Json => {
+                    init_log_writer(args, std::io::stdout);
+                }
+            }
+            Ok(())
+        }
+        LogTarget::Stderr => {
+            init_log_writer(args, std::io::stderr);
+            Ok(())
+        }
+        LogTarget::File(path) => {
+            let rotation = args.log_max_size.map(|max_size| Rotation {
+                max_size,
+                max_files: args.log_max_files,
+            });
+            LogFile::create(path, rotation).map(|file| {
+                let file = Arc::new(file);
+                init_log_writer(args, Arc::clone(&file));
+                log_file = Some(file);
+            })
+        }
+        LogTarget::Syslog => {
+            #[cfg(unix)]
+            let layer = syslog::Layer::new(args.syslog_facility);
+            #[cfg(not(unix))]
+            let layer: std::io::Result<tracing_subscriber::layer::Identity> =
+                Err(std::io::ErrorKind::Unsupported.into());
+            layer.map(|layer| {
+                tracing_subscriber::registry()
+                    .with(layer)
+                    .with(log_filter(args.verbosity))
+                    .init();
+                json_log::log_panics();
+            })
+        }
+        LogTarget::Journald => {
+            #[cfg(target_os = "linux")]
+            let layer = journald::Layer::new();
+            #[cfg(not(target_os = "linux"))]
+            let layer: std::io::Result<tracing_subscriber::layer::Identity> =
+                Err(std::io::ErrorKind::Unsupported.into());
+            layer.map(|layer| {
+                tracing_subscriber::registry()
+                    .with(layer)
+                    .with(log_filter(args.verbosity))
+                    .init();
+                json_log::log_panics();
+            })
+        }
+    };
+    match result {
+        Ok(()) => (target, log_file),
+        Err(e) => {
+            eprintln!(
+                "Could not log to {}: {}, logging to the standard error instead",
+                target, e
+            );
+            init_log_writer(args, std::io::stderr);
+            (LogTarget::Stderr, None)
+        }
+    }
+}
+
+/// Write the logs to `writer` in the format of `--log-format`
+fn init_log_writer<W>(args: &Args, writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match args.log_format {
+        LogFormat::Plain => tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .with_writer(writer)
+            .with_ansi(false)
+            .finish()
+            .with(log_filter(args.verbosity))
+            .init(),
+        LogFormat::Json => {
+            tracing_subscriber::registry()
+                .with(json_log::Layer::new(writer))
+                .with(log_filter(args.verbosity))
+                .init();
+            json_log::log_panics();
+        }
+    }
+}
+
+/// Logs at `verbosity`, except for the statistics logged on `SIGUSR1`, which are always logged
+fn log_filter(verbosity: Level) -> Targets {
+    Targets::new()
+        .with_default(verbosity)
+        .with_target(STATS_LOG_TARGET, Level::INFO)
 }
 
 fn main() {
+    // `boringtun-cli show`, `healthcheck`, `genkey`, `pubkey` and `genpsk`, which an interface
+    // can't be named
+    #[cfg(unix)]
+    match std::env::args().nth(1).as_deref() {
+        Some("show") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            exit(show::main(&args));
+        }
+        Some("healthcheck") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            exit(healthcheck::main(&args));
+        }
+        _ => {}
+    }
+    if let Some(command) = std::env::args().nth(1) {
+        if genkey::COMMANDS.contains(&command.as_str()) {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            exit(genkey::main(&command, &args));
+        }
+    }
+
     let args = Args::parse();
+    #[cfg(not(target_os = "linux"))]
+    args.reject_unsupported_flags();
+
+    // The descriptors of systemd are for this process, not for the daemon it forks
+    #[cfg(target_os = "linux")]
+    let uapi_listener = socket_activation::find_uapi_listener(&args.interface_name);
 
     // Create a socketpair to communicate between forked processes
+    #[cfg(unix)]
     let (sock1, sock2) = UnixDatagram::pair().unwrap();
+    #[cfg(unix)]
     let _ = sock1.set_nonblocking(true);
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut startup = StartupNotifier {
+        #[cfg(unix)]
+        sock: sock1,
+        #[cfg(unix)]
+        hooks: None,
+        #[cfg(unix)]
+        pid_file: None,
+    };
 
-    let _guard;
-
-    if !args.foreground {
-        let log_file = File::create(&args.log)
-            .unwrap_or_else(|_| panic!("Could not create log file {}", args.log));
+    #[cfg_attr(windows, allow(unused_variables))]
+    let (log_target, log_file) = init_logging(&args);
 
-        let (non_blocking, guard) = tracing_appender::non_blocking(log_file);
+    // Read before daemonizing, which closes the standard input, before the privileges are
+    // dropped, and before the API serves any request
+    let private_key = args.private_key_file.as_ref().map(|path| {
+        let key = match path.to_str() {
+            Some("-") => read_private_key(std::io::stdin().lock()),
+            _ => read_private_key_file(path, !args.skip_key_permission_check),
+        };
+        match key {
+            Ok(key) => PrivateKey::from(key),
+            Err(e) => {
+                eprintln!("Failed to read the private key: {}", e);
+                exit(1);
+            }
+        }
+    });
 
-        _guard = guard;
+    // The key of the certificate may not be readable once the privileges are dropped
+    #[cfg(feature = "grpc-tls")]
+    let grpc_tls = args
+        .grpc_tls_cert
+        .as_ref()
+        .zip(args.grpc_tls_key.as_ref())
+        .map(
+            |(cert, key)| match (std::fs::read(cert), std::fs::read(key)) {
+                (Ok(cert), Ok(key)) => {
+                    ServerTlsConfig::new().identity(Identity::from_pem(cert, key))
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("Failed to read the certificate of the gRPC API: {}", e);
+                    exit(1);
+                }
+            },
+        );
 
-        tracing_subscriber::fmt()
-            .with_max_level(args.verbosity)
-            .with_writer(non_blocking)
-            .with_ansi(false)
-            .init();
+    // Locked before daemonizing, so that a second instance reports it in the terminal. The lock is
+    // held by the open file, which the daemon inherits.
+    #[cfg(unix)]
+    let mut pid_file = args
+        .pid_file
+        .as_ref()
+        .map(|path| match PidFile::lock(path) {
+            Ok(pid_file) => pid_file,
+            Err(e) => {
+                eprintln!("Failed to lock the pid file {}: {}", path.display(), e);
+                exit(1);
+            }
+        });
 
+    #[cfg(unix)]
+    if !args.foreground {
         let daemonize = Daemonize::new()
             .working_directory("/tmp")
             .exit_action(move || {
@@ -118,20 +733,154 @@ fn main() {
                 exit(1);
             }
         }
-    } else {
-        tracing_subscriber::fmt()
-            .pretty()
-            .with_max_level(args.verbosity)
-            .init();
+    }
+
+    #[cfg(unix)]
+    if let Some(pid_file) = &mut pid_file {
+        startup.pid_file = Some(pid_file.path().to_owned());
+        if let Err(e) = pid_file.write_pid() {
+            tracing::error!(message = "Failed to write the pid file", error = ?e);
+            startup.fail();
+        }
+    }
+
+    // Before the device starts its threads, which block the signal as well
+    #[cfg(unix)]
+    if let Some(log_file) = log_file {
+        if let Err(e) = log_file::reopen_on_sigusr2(log_file) {
+            tracing::error!(message = "Failed to handle SIGUSR2", error = ?e);
+            startup.fail();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    let uapi_listen_fd = match uapi_listener {
+        Ok(fd) => fd,
+        Err(e) => {
+            tracing::error!(message = "Invalid UAPI socket from systemd", error = ?e);
+            startup.fail();
+        }
+    };
+
+    // Under systemd with `Type=notify`, connected before the privileges are dropped
+    #[cfg(target_os = "linux")]
+    let notifier = Arc::new(systemd::Notifier::from_env());
+
+    // Fail before the device is created if the account to switch to doesn't exist
+    #[cfg(unix)]
+    let drop_target = match args.drop_target() {
+        Ok(target) => target,
+        Err(e) => {
+            tracing::error!(message = "Failed to find the account to drop privileges to", error = ?e);
+            startup.fail();
+        }
+    };
+
+    // The interface of a wg-quick file is set up when the device is created, the rest of the file
+    // is applied once it is
+    let file = args
+        .config
+        .as_ref()
+        .map(|path| match WgConfig::from_file(path) {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::error!(message = "Failed to read the configuration file", error = ?e);
+                startup.fail();
+            }
+        })
+        .unwrap_or_default();
+    #[cfg(target_os = "linux")]
+    let routes: Vec<_> = match args.no_routes {
+        true => vec![],
+        false => file
+            .peers
+            .iter()
+            .flat_map(|peer| peer.allowed_ips.iter().copied())
+            .collect(),
+    };
+    #[cfg(target_os = "linux")]
+    let (address, mtu, bring_up) = (
+        [args.address.clone(), file.address.clone()].concat(),
+        // The device takes the MTU of the network towards the peers
+        args.mtu.or(file.mtu.map(|mtu| mtu.saturating_add(80))),
+        args.up || !file.address.is_empty() || !routes.is_empty(),
+    );
+    #[cfg(not(target_os = "linux"))]
+    let (address, mtu, bring_up) = (args.address.clone(), args.mtu, args.up);
+
+    let hooks = match args.no_hooks {
+        true => Default::default(),
+        false => file.hooks,
+    };
+    #[cfg(windows)]
+    if !hooks.is_empty() {
+        tracing::error!("The hooks of the configuration file can't run on Windows, use --no-hooks");
+        startup.fail();
+    }
+    // The commands would be killed by the filter
+    #[cfg(target_os = "linux")]
+    if args.enable_seccomp && !(hooks.pre_down.is_empty() && hooks.post_down.is_empty()) {
+        tracing::error!("PreDown and PostDown can't run with --enable-seccomp, use --no-hooks");
+        startup.fail();
+    }
+    #[cfg(unix)]
+    let mut hooks = HookRunner {
+        hooks,
+        interface: args.interface_name.clone(),
+    };
+    #[cfg(unix)]
+    {
+        startup.hooks = Some(hooks.clone());
+        if let Err(e) = hooks.run(Stage::PreUp) {
+            tracing::error!(message = "A PreUp hook failed", error = e);
+            startup.fail();
+        }
     }
 
     let config = DeviceConfig {
         n_threads: args.threads,
+        handshake_threads: args.handshake_threads,
         #[cfg(target_os = "linux")]
         uapi_fd: args.uapi_fd,
-        use_connected_socket: !args.disable_connected_udp,
+        #[cfg(target_os = "linux")]
+        uapi_listen_fd,
+        use_connected_socket: !args.disable_connected_udp && cfg!(not(windows)),
         #[cfg(target_os = "linux")]
         use_multi_queue: !args.disable_multi_queue,
+        #[cfg(target_os = "linux")]
+        enable_seccomp: args.enable_seccomp,
+        #[cfg(unix)]
+        sandbox: args.sandbox.clone(),
+        #[cfg(target_os = "macos")]
+        tun_fd: args.tun_fd.first().copied(),
+        #[cfg(target_os = "linux")]
+        tun_fds: args.tun_fd.clone(),
+        address,
+        mtu,
+        bring_up,
+        private_key,
+        listen_port: args.listen_port,
+        exit_on_idle: args.exit_on_idle.map(std::time::Duration::from_secs),
+        config_file: args.config.clone(),
+        peer_state_file: args.peer_state_file.clone(),
+        pcap_path: args.pcap_file.clone(),
+        pcap_max_bytes: args.pcap_max_size,
+        metrics_listen: args.metrics_listen,
+        log_public_keys: args.log_public_keys,
+        allow_overlapping_ips: args.allow_overlapping_ips,
+        strict_source_check: args.strict_source_check,
+        #[cfg(target_os = "linux")]
+        on_reload: notifier.is_active().then(|| {
+            let notifier = Arc::clone(&notifier);
+            ReloadHook::new(move |stage| notifier.reload(stage))
+        }),
+        #[cfg(unix)]
+        log_stats_on_sigusr1: true,
+        outer_transport: args
+            .proxy
+            .clone()
+            .map(|proxy| Arc::new(proxy) as Arc<dyn OuterTransportFactory>),
+        ..Default::default()
     };
 
     let mut device_handle: DeviceHandle = match DeviceHandle::new(&args.tun_name(), config) {
@@ -139,24 +888,84 @@ fn main() {
         Err(e) => {
             // Notify parent that tunnel initialization failed
             tracing::error!(message = "Failed to initialize tunnel", error = ?e);
-            sock1.send(&[0]).unwrap();
-            exit(1);
+            startup.fail();
         }
     };
 
+    if args.config.is_some() {
+        if let Err(e) = device_handle.trigger_reload() {
+            tracing::error!(message = "Failed to apply the configuration file", error = ?e);
+            startup.fail();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if !routes.is_empty() {
+        if let Err(e) = device_handle.add_routes(&routes) {
+            tracing::error!(message = "Failed to add the routes", error = ?e);
+            startup.fail();
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        // The name the system picked, or the one of the descriptor
+        if let Ok(name) = device_handle.interface_name() {
+            hooks.interface = name;
+            startup.hooks = Some(hooks.clone());
+        }
+        if let Err(e) = hooks.run(Stage::PostUp) {
+            tracing::error!(message = "A PostUp hook failed", error = e);
+            startup.fail();
+        }
+    }
+
+    #[cfg(unix)]
     if !args.disable_drop_privileges {
-        if let Err(e) = drop_privileges() {
+        if let Err(e) = chown_log_file(&log_target, &drop_target) {
+            tracing::error!(message = "Failed to change the owner of the log file", error = ?e);
+            startup.fail();
+        }
+        if let Err(e) = device_handle.drop_privileges_to(&drop_target) {
             tracing::error!(message = "Failed to drop privileges", error = ?e);
-            sock1.send(&[0]).unwrap();
-            exit(1);
+            startup.fail();
         }
     }
 
     // Notify parent that tunnel initialization succeeded
-    sock1.send(&[1]).unwrap();
-    drop(sock1);
+    startup.succeed();
+    #[cfg(target_os = "linux")]
+    notifier.ready(&device_handle.stats());
 
     tracing::info!("BoringTun started successfully");
 
+    // Serves until the device stops, which makes the waiting below return right away
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc_listen {
+        #[cfg(feature = "grpc-tls")]
+        let served = match grpc_tls {
+            Some(tls) => device_handle.serve_grpc_tls(addr, tls),
+            None => device_handle.serve_grpc(addr),
+        };
+        #[cfg(not(feature = "grpc-tls"))]
+        let served = device_handle.serve_grpc(addr);
+        if let Err(e) = served {
+            tracing::error!(message = "Failed to serve the gRPC API", error = ?e);
+            device_handle.initiate_shutdown();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    notifier.supervise(&mut device_handle);
+    #[cfg(not(target_os = "linux"))]
     device_handle.wait();
+
+    // The interface is gone once the device is dropped
+    #[cfg(unix)]
+    hooks.run_best_effort(Stage::PreDown);
+    drop(device_handle);
+    #[cfg(unix)]
+    hooks.run_best_effort(Stage::PostDown);
+    #[cfg(unix)]
+    drop(pid_file);
 }
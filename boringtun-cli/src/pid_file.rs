@@ -0,0 +1,93 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The pid file of `--pid-file`, for process supervisors and init scripts.
+//!
+//! The file stays locked with `flock` as long as boringtun runs, so another instance given the
+//! same file fails to start. The lock goes away with the process, so a file left behind by a
+//! process that was killed is taken over.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+pub struct PidFile {
+    file: File,
+    /// Absolute, the working directory changes when the process daemonizes
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Open the file at `path`, creating it if needed, and lock it. Fails if another process holds
+    /// the lock. The lock is shared with the child once the process forks.
+    pub fn lock(path: &Path) -> io::Result<PidFile> {
+        let path = std::path::absolute(path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&path)?;
+        if -1 == unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            let pid = std::fs::read_to_string(&path).unwrap_or_default();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("boringtun is already running with pid {}", pid.trim()),
+            ));
+        }
+        Ok(PidFile { file, path })
+    }
+
+    /// Replace the content of the file with the pid of this process, once it is the one that
+    /// keeps running
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Once privileges are dropped the directory may not be writable anymore, the file left
+        // behind is not locked though
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!(message = "Failed to remove the pid file", path = ?self.path, error = ?e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("boringtun-pid-{}", std::process::id()));
+        std::fs::write(&path, "1\n").unwrap();
+
+        // A file that isn't locked is taken over
+        let mut pid_file = PidFile::lock(&path).unwrap();
+        pid_file.write_pid().unwrap();
+        let pid = format!("{}\n", std::process::id());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+
+        // Locks are held by open files, even in the same process
+        let e = PidFile::lock(&path).err().unwrap();
+        assert!(e.to_string().contains("already running"), "{}", e);
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! `boringtun-cli show`, the `wg show` of wireguard-tools for the interfaces of boringtun and of
+//! other userspace implementations, without wireguard-tools installed.
+//!
+//! The state of an interface comes from a UAPI `get` on its socket in
+//! [`boringtun::device::api::SOCK_DIR`]. The output, the usage, the error messages and the exit
+//! codes are those of `wg show`, so that scripts work with either, except that the output has no
+//! colors.
+
+use boringtun::device::api::{UapiError, SOCK_DIR};
+use boringtun::keys::{encode_base64, public_key_from_private, PrivateKey};
+use std::ffi::CStr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "Usage: boringtun-cli show { <interface> | all | interfaces } [public-key | \
+                     private-key | listen-port | fwmark | peers | preshared-keys | endpoints | \
+                     allowed-ips | latest-handshakes | transfer | persistent-keepalive | dump]";
+
+/// The state of an interface, as reported by a UAPI `get`
+#[derive(Debug, Default)]
+pub struct Interface {
+    pub name: String,
+    pub private_key: Option<[u8; 32]>,
+    /// Reported by boringtun instead of its private key
+    pub own_public_key: Option<[u8; 32]>,
+    pub listen_port: u16,
+    pub fwmark: u32,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Default)]
+pub struct Peer {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<String>,
+    /// The seconds and nanoseconds of the last handshake since the epoch, 0 without any
+    pub last_handshake: (u64, u32),
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub persistent_keepalive: u16,
+}
+
+/// Run `show` with the arguments after it, returns the exit code
+pub fn main(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut out = String::new();
+    let code = match args[..] {
+        [_, _, _, ..] => usage(),
+        [] | ["all", ..] => {
+            let names = match list_interfaces() {
+                Ok(names) => names,
+                Err(e) => return fail("Unable to list interfaces", &e),
+            };
+            let mut code = 0;
+            for (i, name) in names.iter().enumerate() {
+                let interface = match get(name) {
+                    Ok(interface) => interface,
+                    Err(e) => {
+                        eprintln!("Unable to access interface {}: {}", name, strerror(&e));
+                        continue;
+                    }
+                };
+                match args.get(1) {
+                    Some(field) => match ugly(&interface, field, true) {
+                        Some(lines) => out.push_str(&lines),
+                        None => {
+                            code = usage();
+                            break;
+                        }
+                    },
+                    None => {
+                        out.push_str(&pretty(&interface, now()));
+                        if i + 1 < names.len() {
+                            out.push('\n');
+                        }
+                    }
+                }
+            }
+            code
+        }
+        ["interfaces", ..] if args.len() > 1 => usage(),
+        ["interfaces"] => match list_interfaces() {
+            Ok(names) if names.is_empty() => 0,
+            Ok(names) => {
+                out = names.join(" ") + "\n";
+                0
+            }
+            Err(e) => return fail("Unable to list interfaces", &e),
+        },
+        ["-h" | "--help" | "help"] => {
+            eprintln!("{}", USAGE);
+            0
+        }
+        [name, ..] => match get(name) {
+            Ok(interface) => match args.get(1) {
+                Some(field) => match ugly(&interface, field, false) {
+                    Some(lines) => {
+                        out = lines;
+                        0
+                    }
+                    None => usage(),
+                },
+                None => {
+                    out = pretty(&interface, now());
+                    0
+                }
+            },
+            Err(e) => return fail("Unable to access interface", &e),
+        },
+    };
+    // Nothing to do if the output was closed, like `wg`
+    let _ = io::stdout().write_all(out.as_bytes());
+    code
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    1
+}
+
+/// Report `e` like `perror`
+fn fail(message: &str, e: &io::Error) -> i32 {
+    eprintln!("{}: {}", message, strerror(e));
+    1
+}
+
+/// The message of `e` without the ` (os error N)` of Rust, as `wg` prints them
+fn strerror(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(errno) => unsafe { CStr::from_ptr(libc::strerror(errno)) }
+            .to_string_lossy()
+            .into_owned(),
+        None => e.to_string(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The names of the interfaces with a socket, sorted
+fn list_interfaces() -> io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(SOCK_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str().and_then(|name| name.strip_suffix(".sock")) {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Get the state of the interface `name` from its socket
+pub fn get(name: &str) -> io::Result<Interface> {
+    if name.contains('/') {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut stream = UnixStream::connect(Path::new(SOCK_DIR).join(format!("{}.sock", name)))?;
+    stream.write_all(b"get=1\n\n")?;
+
+    let mut response = String::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        response.push_str(&line);
+        response.push('\n');
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    parse(name, &response).map(|interface| handshakes_since_epoch(interface, now))
+}
+
+/// boringtun reports how long ago the last handshake of each peer was, rather than when it was,
+/// its interfaces are those with an `own_public_key`
+fn handshakes_since_epoch(mut interface: Interface, now: Duration) -> Interface {
+    if interface.own_public_key.is_some() {
+        for peer in &mut interface.peers {
+            let (secs, nsecs) = peer.last_handshake;
+            if (secs, nsecs) != (0, 0) {
+                let at = now.saturating_sub(Duration::new(secs, nsecs));
+                peer.last_handshake = (at.as_secs(), at.subsec_nanos());
+            }
+        }
+    }
+    interface
+}
+
+/// Parse the response to a `get`, which ends with its `errno`
+fn parse(name: &str, response: &str) -> io::Result<Interface> {
+    let invalid = || io::Error::from_raw_os_error(libc::EPROTO);
+    let mut interface = Interface {
+        name: name.to_owned(),
+        ..Default::default()
+    };
+    let mut errno = None;
+    for line in response.lines() {
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let peer = interface.peers.last_mut();
+        match (key, peer) {
+            ("errno", _) => errno = UapiError::parse_line(line),
+            ("public_key", _) => interface.peers.push(Peer {
+                public_key: parse_hex_key(value).ok_or_else(invalid)?,
+                ..Default::default()
+            }),
+            ("private_key", None) => {
+                let key = parse_hex_key(value).ok_or_else(invalid)?;
+                interface.private_key = Some(key).filter(|key| key != &[0; 32]);
+            }
+            ("own_public_key", None) => {
+                interface.own_public_key = Some(parse_hex_key(value).ok_or_else(invalid)?);
+            }
+            ("listen_port", None) => {
+                interface.listen_port = value.parse().map_err(|_| invalid())?
+            }
+            ("fwmark", None) => interface.fwmark = value.parse().map_err(|_| invalid())?,
+            ("preshared_key", Some(peer)) => {
+                let key = parse_hex_key(value).ok_or_else(invalid)?;
+                peer.preshared_key = Some(key).filter(|key| key != &[0; 32]);
+            }
+            ("endpoint", Some(peer)) => peer.endpoint = Some(value.to_owned()),
+            ("allowed_ip", Some(peer)) => peer.allowed_ips.push(value.to_owned()),
+            ("last_handshake_time_sec", Some(peer)) => {
+                peer.last_handshake.0 = value.parse().map_err(|_| invalid())?
+            }
+            ("last_handshake_time_nsec", Some(peer)) => {
+                peer.last_handshake.1 = value.parse().map_err(|_| invalid())?
+            }
+            ("rx_bytes", Some(peer)) => peer.rx_bytes = value.parse().map_err(|_| invalid())?,
+            ("tx_bytes", Some(peer)) => peer.tx_bytes = value.parse().map_err(|_| invalid())?,
+            ("persistent_keepalive_interval", Some(peer)) => {
+                peer.persistent_keepalive = value.parse().map_err(|_| invalid())?
+            }
+            // Keys of other versions or implementations, such as those of boringtun
+            _ => {}
+        }
+    }
+    match errno {
+        Some(Ok(())) => Ok(interface),
+        Some(Err(UapiError(errno))) => Err(io::Error::from_raw_os_error(errno)),
+        None => Err(invalid()),
+    }
+}
+
+fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    let mut key = [0u8; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+impl Interface {
+    fn public_key(&self) -> Option<[u8; 32]> {
+        match self.private_key {
+            Some(private) => {
+                let private = PrivateKey::from(private);
+                Some(*public_key_from_private(&private).as_bytes())
+            }
+            None => self.own_public_key,
+        }
+    }
+}
+
+/// A key in base64, or `(none)`
+fn maybe_key(key: Option<[u8; 32]>) -> String {
+    key.map_or_else(|| "(none)".to_owned(), |key| encode_base64(&key))
+}
+
+/// The output of `wg show INTERFACE`, at `now` seconds since the epoch
+fn pretty(interface: &Interface, now: u64) -> String {
+    let mut out = format!("interface: {}\n", interface.name);
+    if let Some(key) = interface.public_key() {
+        out += &format!("  public key: {}\n", encode_base64(&key));
+    }
+    if interface.private_key.is_some() {
+        out += "  private key: (hidden)\n";
+    }
+    if interface.listen_port != 0 {
+        out += &format!("  listening port: {}\n", interface.listen_port);
+    }
+    if interface.fwmark != 0 {
+        out += &format!("  fwmark: 0x{:x}\n", interface.fwmark);
+    }
+
+    // The most recent handshakes first, then the peers without any
+    let mut peers: Vec<&Peer> = interface.peers.iter().collect();
+    peers.sort_by_key(|peer| {
+        let (secs, nsecs) = peer.last_handshake;
+        (secs == 0 && nsecs == 0, std::cmp::Reverse((secs, nsecs)))
+    });
+    for peer in peers {
+        out += &format!("\npeer: {}\n", encode_base64(&peer.public_key));
+        if peer.preshared_key.is_some() {
+            out += "  preshared key: (hidden)\n";
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            out += &format!("  endpoint: {}\n", endpoint);
+        }
+        match peer.allowed_ips.is_empty() {
+            true => out += "  allowed ips: (none)\n",
+            false => out += &format!("  allowed ips: {}\n", peer.allowed_ips.join(", ")),
+        }
+        let last_handshake = peer.last_handshake.0;
+        if last_handshake != 0 {
+            let ago = match now.checked_sub(last_handshake) {
+                Some(0) => "Now".to_owned(),
+                Some(secs) => format!("{} ago", pretty_time(secs)),
+                None => "(System clock wound backward; connection problems may ensue.)".to_owned(),
+            };
+            out += &format!("  latest handshake: {}\n", ago);
+        }
+        if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+            out += &format!(
+                "  transfer: {} received, {} sent\n",
+                bytes(peer.rx_bytes),
+                bytes(peer.tx_bytes)
+            );
+        }
+        if peer.persistent_keepalive != 0 {
+            out += &format!(
+                "  persistent keepalive: every {}\n",
+                pretty_time(peer.persistent_keepalive.into())
+            );
+        }
+    }
+    out
+}
+
+/// A duration such as `1 hour, 2 minutes, 1 second`
+fn pretty_time(secs: u64) -> String {
+    let units = [
+        ("year", secs / (365 * 24 * 60 * 60)),
+        ("day", secs % (365 * 24 * 60 * 60) / (24 * 60 * 60)),
+        ("hour", secs % (24 * 60 * 60) / (60 * 60)),
+        ("minute", secs % (60 * 60) / 60),
+        ("second", secs % 60),
+    ];
+    let parts: Vec<String> = units
+        .iter()
+        .filter(|(_, n)| *n != 0)
+        .map(|(unit, n)| format!("{} {}{}", n, unit, if *n == 1 { "" } else { "s" }))
+        .collect();
+    parts.join(", ")
+}
+
+/// A number of bytes such as `1.50 KiB`
+fn bytes(b: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if b < 1024 {
+        return format!("{} B", b);
+    }
+    let mut value = b as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+/// The output of `wg show INTERFACE FIELD`, each line starting with the name of the interface
+/// with `all`. `None` if there is no such field.
+fn ugly(interface: &Interface, field: &str, with_interface: bool) -> Option<String> {
+    let prefix = match with_interface {
+        true => format!("{}\t", interface.name),
+        false => String::new(),
+    };
+    let fwmark = match interface.fwmark {
+        0 => "off".to_owned(),
+        fwmark => format!("0x{:x}", fwmark),
+    };
+    let peer_lines = |line: &dyn Fn(&Peer) -> String| -> String {
+        let lines = interface.peers.iter().map(|peer| {
+            format!(
+                "{}{}\t{}\n",
+                prefix,
+                encode_base64(&peer.public_key),
+                line(peer)
+            )
+        });
+        lines.collect()
+    };
+    let endpoint = |peer: &Peer| peer.endpoint.clone().unwrap_or_else(|| "(none)".to_owned());
+    let allowed_ips = |peer: &Peer, separator: &str| match peer.allowed_ips.is_empty() {
+        true => "(none)".to_owned(),
+        false => peer.allowed_ips.join(separator),
+    };
+    let keepalive = |peer: &Peer| match peer.persistent_keepalive {
+        0 => "off".to_owned(),
+        interval => interval.to_string(),
+    };
+
+    let out = match field {
+        "public-key" => format!("{}{}\n", prefix, maybe_key(interface.public_key())),
+        "private-key" => format!("{}{}\n", prefix, maybe_key(interface.private_key)),
+        "listen-port" => format!("{}{}\n", prefix, interface.listen_port),
+        "fwmark" => format!("{}{}\n", prefix, fwmark),
+        "peers" => interface
+            .peers
+            .iter()
+            .map(|peer| format!("{}{}\n", prefix, encode_base64(&peer.public_key)))
+            .collect(),
+        "preshared-keys" => peer_lines(&|peer| maybe_key(peer.preshared_key)),
+        "endpoints" => peer_lines(&endpoint),
+        "allowed-ips" => peer_lines(&|peer| allowed_ips(peer, " ")),
+        "latest-handshakes" => peer_lines(&|peer| peer.last_handshake.0.to_string()),
+        "transfer" => peer_lines(&|peer| format!("{}\t{}", peer.rx_bytes, peer.tx_bytes)),
+        "persistent-keepalive" => peer_lines(&keepalive),
+        "dump" => {
+            let mut out = format!(
+                "{}{}\t{}\t{}\t{}\n",
+                prefix,
+                maybe_key(interface.private_key),
+                maybe_key(interface.public_key()),
+                interface.listen_port,
+                fwmark
+            );
+            out += &peer_lines(&|peer| {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    maybe_key(peer.preshared_key),
+                    endpoint(peer),
+                    allowed_ips(peer, ","),
+                    peer.last_handshake.0,
+                    peer.rx_bytes,
+                    peer.tx_bytes,
+                    keepalive(peer)
+                )
+            });
+            out
+        }
+        _ => return None,
+    };
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The private key of RFC 7748, section 6.1, and its public key
+    const PRIVATE: &str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+    const PUBLIC: &str = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+
+    fn interface() -> Interface {
+        let response = format!(
+            "private_key={}\nlisten_port=51820\nfwmark=0\nbt_handshake_rate_limited=3\n\
+             public_key={}\npreshared_key={}\nprotocol_version=1\n\
+             endpoint=192.0.2.1:51820\nallowed_ip=10.0.0.2/32\nallowed_ip=fd00::2/128\n\
+             last_handshake_time_sec=1000\nlast_handshake_time_nsec=5\n\
+             rx_bytes=1536\ntx_bytes=100\npersistent_keepalive_interval=25\n\
+             public_key={}\npreshared_key={}\nrx_bytes=0\ntx_bytes=0\n\
+             last_handshake_time_sec=0\nlast_handshake_time_nsec=0\n\
+             persistent_keepalive_interval=0\nerrno=0\n",
+            PRIVATE,
+            "01".repeat(32),
+            "00".repeat(32),
+            "02".repeat(32),
+            "03".repeat(32),
+        );
+        parse("wg0", &response).unwrap()
+    }
+
+    #[test]
+    fn test_pretty() {
+        let peer1 = encode_base64(&[1; 32]);
+        let peer2 = encode_base64(&[2; 32]);
+        let expected = format!(
+            "interface: wg0\n  public key: {}\n  private key: (hidden)\n  listening port: 51820\n\
+             \npeer: {}\n  endpoint: 192.0.2.1:51820\n  allowed ips: 10.0.0.2/32, fd00::2/128\n  \
+             latest handshake: 1 hour, 1 minute, 5 seconds ago\n  transfer: 1.50 KiB received, \
+             100 B sent\n  persistent keepalive: every 25 seconds\n\
+             \npeer: {}\n  preshared key: (hidden)\n  allowed ips: (none)\n",
+            PUBLIC, peer1, peer2
+        );
+        assert_eq!(pretty(&interface(), 1000 + 3665), expected);
+
+        assert_eq!(
+            pretty_time(366 * 24 * 60 * 60 + 1),
+            "1 year, 1 day, 1 second"
+        );
+        assert_eq!(bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+    }
+
+    #[test]
+    fn test_ugly() {
+        let interface = interface();
+        let (peer1, peer2) = (encode_base64(&[1; 32]), encode_base64(&[2; 32]));
+        let psk = encode_base64(&[3; 32]);
+        let private = encode_base64(&parse_hex_key(PRIVATE).unwrap());
+
+        let dump = format!(
+            "{private}\t{PUBLIC}\t51820\toff\n\
+             {peer1}\t(none)\t192.0.2.1:51820\t10.0.0.2/32,fd00::2/128\t1000\t1536\t100\t25\n\
+             {peer2}\t{psk}\t(none)\t(none)\t0\t0\t0\toff\n"
+        );
+        assert_eq!(ugly(&interface, "dump", false).unwrap(), dump);
+        assert_eq!(
+            ugly(&interface, "allowed-ips", true).unwrap(),
+            format!("wg0\t{peer1}\t10.0.0.2/32 fd00::2/128\nwg0\t{peer2}\t(none)\n")
+        );
+        assert_eq!(
+            ugly(&interface, "transfer", false).unwrap(),
+            format!("{peer1}\t1536\t100\n{peer2}\t0\t0\n")
+        );
+        assert_eq!(
+            ugly(&interface, "public-key", true).unwrap(),
+            format!("wg0\t{PUBLIC}\n")
+        );
+        assert_eq!(ugly(&interface, "fwmark", false).unwrap(), "off\n");
+        assert_eq!(ugly(&interface, "bogus", false), None);
+
+        // boringtun only reports its public key
+        let own = parse(
+            "wg0",
+            &format!("own_public_key={}\nerrno=0\n", "04".repeat(32)),
+        )
+        .unwrap();
+        assert_eq!(
+            ugly(&own, "dump", false).unwrap(),
+            format!("(none)\t{}\t0\toff\n", encode_base64(&[4; 32]))
+        );
+
+        // Its handshakes are reported as their age
+        let response = format!(
+            "own_public_key={}\npublic_key={}\nlast_handshake_time_sec=5\n\
+             last_handshake_time_nsec=0\nerrno=0\n",
+            "04".repeat(32),
+            "01".repeat(32)
+        );
+        let own = handshakes_since_epoch(parse("wg0", &response).unwrap(), Duration::new(1000, 7));
+        assert_eq!(own.peers[0].last_handshake, (995, 7));
+        let other = handshakes_since_epoch(interface, Duration::new(2000, 0));
+        assert_eq!(other.peers[0].last_handshake, (1000, 5));
+
+        let e = parse("wg0", "errno=19\n").unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENODEV));
+    }
+}
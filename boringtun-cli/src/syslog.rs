@@ -0,0 +1,246 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Logging to syslog, for `--log-target syslog`: each event is an RFC 5424 message sent as a
+//! datagram to `/dev/log`.
+//!
+//! The severity of a message comes from the level of its event, from 3 for errors to 7 for
+//! traces, and its facility from `--syslog-facility`. The text of the message is the message of
+//! the event followed by the fields of its spans and its own, as `name=value`.
+
+use boringtun::device::dump::rfc3339;
+use std::ffi::CStr;
+use std::fmt::{self, Write};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Where syslog daemons receive the messages of local processes
+const SYSLOG_PATH: &str = "/dev/log";
+
+/// The facilities of RFC 5424, by their number
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+/// Parse the name of a facility, such as `daemon` or `local0`, into its number
+pub fn parse_facility(name: &str) -> Result<u8, String> {
+    match FACILITIES.iter().position(|facility| facility == &name) {
+        Some(facility) => Ok(facility as u8),
+        None => Err(format!(
+            "Unknown facility {}, expected one of {}",
+            name,
+            FACILITIES.join(", ")
+        )),
+    }
+}
+
+/// A layer that sends the events to syslog
+pub struct Layer {
+    sock: UnixDatagram,
+    facility: u8,
+    hostname: String,
+}
+
+/// The fields of a span, already formatted
+struct SpanFields(String);
+
+impl Layer {
+    /// Connect to the syslog daemon. Connecting up front lets the messages through after the
+    /// privileges are dropped, and in a sandbox.
+    pub fn new(facility: u8) -> io::Result<Layer> {
+        Layer::connect(SYSLOG_PATH, facility)
+    }
+
+    fn connect(path: impl AsRef<Path>, facility: u8) -> io::Result<Layer> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path)?;
+        Ok(Layer {
+            sock,
+            facility,
+            hostname: hostname(),
+        })
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span was just created");
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(SpanFields(fields.fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("The span exists");
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut visitor = FieldVisitor {
+                fields: std::mem::take(fields),
+                ..Default::default()
+            };
+            values.record(&mut visitor);
+            *fields = visitor.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let mut text = visitor.message;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    text.push_str(fields);
+                }
+            }
+        }
+        text.push_str(&visitor.fields);
+
+        let message = format_message(
+            self.facility,
+            event.metadata().level(),
+            &rfc3339(std::time::SystemTime::now(), true),
+            &self.hostname,
+            std::process::id(),
+            text.trim_start(),
+        );
+        // There is nowhere left to report the failure
+        let _ = self.sock.send(message.as_bytes());
+    }
+}
+
+/// The severity of the messages of `level`
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 5,
+        Level::DEBUG => 6,
+        Level::TRACE => 7,
+    }
+}
+
+/// An RFC 5424 message from boringtun, without a message id or structured data
+fn format_message(
+    facility: u8,
+    level: &Level,
+    timestamp: &str,
+    hostname: &str,
+    pid: u32,
+    text: &str,
+) -> String {
+    format!(
+        "<{}>1 {} {} boringtun {} - - {}",
+        u16::from(facility) * 8 + u16::from(severity(level)),
+        timestamp,
+        hostname,
+        pid,
+        text
+    )
+}
+
+/// The name of the host, `-` when it is unknown as RFC 5424 wants
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    if -1 == unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } {
+        return "-".to_owned();
+    }
+    match CStr::from_bytes_until_nul(&name) {
+        Ok(name) if !name.is_empty() => name.to_string_lossy().into_owned(),
+        _ => "-".to_owned(),
+    }
+}
+
+/// Keeps the message apart from the other fields, formatted as ` name=value`
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_owned(),
+            name => {
+                let _ = write!(self.fields, " {}={}", name, value);
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(parse_facility("daemon"), Ok(3));
+        assert_eq!(parse_facility("local7"), Ok(23));
+        assert!(parse_facility("local8").is_err());
+
+        let message = format_message(
+            3,
+            &Level::WARN,
+            "2024-05-01T12:00:00.000000Z",
+            "host",
+            42,
+            "Handshake failed",
+        );
+        assert_eq!(
+            message,
+            "<28>1 2024-05-01T12:00:00.000000Z host boringtun 42 - - Handshake failed"
+        );
+    }
+
+    #[test]
+    fn test_syslog_layer() {
+        let path = std::env::temp_dir().join(format!("boringtun-syslog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let syslog = UnixDatagram::bind(&path).unwrap();
+        let layer = Layer::connect(&path, parse_facility("local0").unwrap()).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("peer", peer = "dGVzdGtl");
+            let _entered = span.enter();
+            tracing::error!(message = "Handshake failed", index = 7);
+        });
+
+        let mut buf = [0u8; 1024];
+        let n = syslog.recv(&mut buf).unwrap();
+        let message = String::from_utf8(buf[..n].to_vec()).unwrap();
+        // local0 is 16, errors are 3
+        assert!(message.starts_with("<131>1 "), "{}", message);
+        let pid = format!(" boringtun {} - - ", std::process::id());
+        assert!(message.contains(&pid), "{}", message);
+        assert!(
+            message.ends_with(" - - Handshake failed peer=dGVzdGtl index=7"),
+            "{}",
+            message
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
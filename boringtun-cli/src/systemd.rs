@@ -0,0 +1,199 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The `sd_notify` protocol of the systemd services with `Type=notify`: the state of the service
+//! is sent as `KEY=value` lines in datagrams to the unix socket of `NOTIFY_SOCKET`. Without that
+//! variable, when not started by systemd, nothing is sent.
+//!
+//! boringtun sends `READY=1` once the tunnel is configured and the privileges are dropped, the
+//! number of peers in `STATUS=`, `RELOADING=1` then `READY=1` around the reloads of the
+//! configuration file, with their result in `STATUS=`, and `STOPPING=1` once the device stopped.
+//! With `WatchdogSec=`, systemd passes `WATCHDOG_USEC` and `WATCHDOG=1` is sent at half that
+//! interval, as long as the event loops keep handling events.
+
+use boringtun::device::{DeviceHandle, DeviceStats, ReloadStage};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often `STATUS=` is sent when there is no watchdog, or a slower one
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The connection to the notification socket of systemd
+#[derive(Debug, Default)]
+pub struct Notifier {
+    sock: Option<UnixDatagram>,
+    /// `READY=1` was sent, the reloads of the startup don't count
+    ready: AtomicBool,
+}
+
+impl Notifier {
+    /// Connect to the socket of `NOTIFY_SOCKET`, if it is set. Connecting up front lets the
+    /// notifications through after the privileges are dropped.
+    pub fn from_env() -> Notifier {
+        match std::env::var("NOTIFY_SOCKET") {
+            Ok(path) => Notifier::connect(&path).unwrap_or_else(|e| {
+                tracing::warn!(message = "Failed to connect to the systemd socket", path, error = ?e);
+                Notifier::default()
+            }),
+            Err(_) => Notifier::default(),
+        }
+    }
+
+    /// Connect to a socket path, or to an abstract socket if it starts with `@`
+    fn connect(path: &str) -> std::io::Result<Notifier> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let sock = UnixDatagram::unbound()?;
+        sock.connect_addr(&addr)?;
+        Ok(Notifier {
+            sock: Some(sock),
+            ready: AtomicBool::new(false),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.sock.is_some()
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(sock) = &self.sock {
+            if let Err(e) = sock.send(state.as_bytes()) {
+                tracing::warn!(message = "Failed to notify systemd", state, error = ?e);
+            }
+        }
+    }
+
+    /// The startup is done
+    pub fn ready(&self, stats: &DeviceStats) {
+        self.notify(&format!("READY=1\nSTATUS={}", status(stats)));
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// For [`boringtun::device::DeviceConfig::on_reload`]
+    pub fn reload(&self, stage: ReloadStage) {
+        if !self.ready.load(Ordering::Relaxed) {
+            return;
+        }
+        match stage {
+            ReloadStage::Started => self.notify("RELOADING=1"),
+            ReloadStage::Applied(summary) => self.notify(&format!(
+                "READY=1\nSTATUS=Reloaded, {} peers added, {} removed, {} modified",
+                summary.added, summary.removed, summary.modified
+            )),
+            ReloadStage::Failed(e) => self.notify(&format!(
+                "READY=1\nSTATUS=Reload failed, the configuration is unchanged: {}",
+                e
+            )),
+        }
+    }
+
+    /// Wait for the device to stop like [`DeviceHandle::wait`], meanwhile the status is kept up
+    /// to date and the watchdog is pinged, then send `STOPPING=1`
+    pub fn supervise(&self, device: &mut DeviceHandle) {
+        if !self.is_active() {
+            return device.wait();
+        }
+
+        let watchdog = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        let interval = watchdog.map_or(STATUS_INTERVAL, |w| (w / 2).min(STATUS_INTERVAL));
+        while !device.wait_timeout(interval) {
+            if watchdog.is_some() {
+                // A stuck event loop blocks this, and systemd restarts the service once the
+                // watchdog times out
+                device.sync_event_loops();
+                self.notify("WATCHDOG=1");
+            }
+            self.notify(&format!("STATUS={}", status(&device.stats())));
+        }
+        self.notify("STOPPING=1");
+    }
+}
+
+fn status(stats: &DeviceStats) -> String {
+    format!("{} peers, {} connected", stats.peers, stats.connected_peers)
+}
+
+/// The interval of the watchdog of the service, unless it is meant for another process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    match usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boringtun::device::ReloadSummary;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("boringtun-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        let recv = || {
+            let mut buf = [0u8; 256];
+            let n = systemd.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+
+        // The reloads before the startup is done are not reported
+        systemd.set_nonblocking(true).unwrap();
+        notifier.reload(ReloadStage::Started);
+        assert!(systemd.recv(&mut [0u8; 16]).is_err());
+        systemd.set_nonblocking(false).unwrap();
+
+        let stats = DeviceStats {
+            peers: 3,
+            connected_peers: 1,
+            ..Default::default()
+        };
+        notifier.ready(&stats);
+        assert_eq!(recv(), "READY=1\nSTATUS=3 peers, 1 connected");
+        notifier.reload(ReloadStage::Started);
+        assert_eq!(recv(), "RELOADING=1");
+        let summary = ReloadSummary {
+            added: 1,
+            removed: 2,
+            modified: 0,
+        };
+        notifier.reload(ReloadStage::Applied(summary));
+        assert_eq!(
+            recv(),
+            "READY=1\nSTATUS=Reloaded, 1 peers added, 2 removed, 0 modified"
+        );
+        notifier.reload(ReloadStage::Failed("no such file".to_owned()));
+        assert_eq!(
+            recv(),
+            "READY=1\nSTATUS=Reload failed, the configuration is unchanged: no such file"
+        );
+
+        // Not running under systemd
+        assert!(!Notifier::default().is_active());
+        Notifier::default().ready(&stats);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let interval = Some(Duration::from_secs(30));
+        assert_eq!(watchdog_interval(Some("30000000"), None, 7), interval);
+        assert_eq!(watchdog_interval(Some("30000000"), Some("7"), 7), interval);
+        assert_eq!(watchdog_interval(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 7), None);
+        assert_eq!(watchdog_interval(None, Some("7"), 7), None);
+    }
+}
@@ -0,0 +1,9 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // A protoc of our own, so the build doesn't depend on one being installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/boringtun.proto")?;
+    Ok(())
+}
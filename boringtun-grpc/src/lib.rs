@@ -0,0 +1,574 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A gRPC management API for a boringtun device, the `WireGuardManager` service of
+//! `proto/boringtun.proto`, served with tonic.
+//!
+//! The server runs on a runtime of its own, and hands the calls over to the thread that serves
+//! it, which runs them on the [`DeviceHandle`] and waits for the device meanwhile:
+//!
+//! ```no_run
+//! use boringtun::device::{DeviceConfig, DeviceHandle};
+//! use boringtun_grpc::ServeGrpc;
+//!
+//! let mut device = DeviceHandle::new("utun", DeviceConfig::default()).unwrap();
+//! // Returns once the device stopped
+//! device.serve_grpc("127.0.0.1:51821".parse().unwrap()).unwrap();
+//! ```
+
+// The handlers of tonic return a Status as the error
+#![allow(clippy::result_large_err)]
+
+use boringtun::device::config::{ConfigDiff, PeerConfig, WgConfig};
+use boringtun::device::events::{DeviceEvent, PeerEvent};
+use boringtun::device::{self, DeviceHandle};
+use boringtun::keys::{decode_base64, encode_base64};
+use boringtun::x25519;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+#[cfg(feature = "tls")]
+pub use tonic::transport::{Identity, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+/// The messages and the service of `proto/boringtun.proto`, generated by tonic-build
+pub mod proto {
+    tonic::include_proto!("boringtun");
+}
+
+use proto::wire_guard_manager_server::{WireGuardManager, WireGuardManagerServer};
+
+/// How often the thread that serves the calls checks whether the device stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// The events a call of `StreamEvents` buffers on top of those of the stream of the device
+const EVENT_BUFFER: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("transport: {0}")]
+    Transport(#[from] tonic::transport::Error),
+}
+
+/// Serve the gRPC API of a device, see the [crate documentation](crate)
+pub trait ServeGrpc {
+    /// Serve the API on `addr` until the device stops, then wait for it like
+    /// [`DeviceHandle::wait`]. Fails if `addr` can't be listened on.
+    fn serve_grpc(&mut self, addr: SocketAddr) -> Result<(), Error>;
+
+    /// [`ServeGrpc::serve_grpc`] over TLS, terminated with rustls
+    #[cfg(feature = "tls")]
+    fn serve_grpc_tls(&mut self, addr: SocketAddr, tls: ServerTlsConfig) -> Result<(), Error>;
+}
+
+impl ServeGrpc for DeviceHandle {
+    fn serve_grpc(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        serve(self, addr, Server::builder())
+    }
+
+    #[cfg(feature = "tls")]
+    fn serve_grpc_tls(&mut self, addr: SocketAddr, tls: ServerTlsConfig) -> Result<(), Error> {
+        serve(self, addr, Server::builder().tls_config(tls)?)
+    }
+}
+
+/// A call to run on the [`DeviceHandle`], by the thread that serves the API
+type Job = Box<dyn FnOnce(&DeviceHandle) + Send>;
+
+fn serve(device: &mut DeviceHandle, addr: SocketAddr, mut server: Server) -> Result<(), Error> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    let listener = runtime.block_on(tokio::net::TcpListener::bind(addr))?;
+    tracing::info!(message = "Serving the gRPC API", addr = %listener.local_addr()?);
+
+    let (jobs, pending) = mpsc::channel();
+    let (stop, stopping) = watch::channel(false);
+    let service = WireGuardManagerServer::new(Manager {
+        jobs,
+        stopping: stopping.clone(),
+    });
+    let mut shutdown = stopping;
+    let server = runtime.spawn(server.add_service(service).serve_with_incoming_shutdown(
+        TcpListenerStream::new(listener),
+        async move {
+            shutdown.changed().await.ok();
+        },
+    ));
+
+    while !device.wait_timeout(Duration::ZERO) {
+        if let Ok(job) = pending.recv_timeout(STOP_CHECK_INTERVAL) {
+            job(device);
+        }
+    }
+
+    // The calls still pending fail once their jobs are dropped
+    drop(pending);
+    stop.send(true).ok();
+    match runtime.block_on(server) {
+        Ok(result) => result.map_err(Error::Transport),
+        Err(e) => Err(Error::Io(e.into())),
+    }
+}
+
+/// The implementation of the service, which hands the calls over to the thread of [`serve`]
+struct Manager {
+    jobs: mpsc::Sender<Job>,
+    /// Set once the device stopped, to end the streams of events
+    stopping: watch::Receiver<bool>,
+}
+
+impl Manager {
+    /// Run `f` on the device, fails if the device stopped
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&DeviceHandle) -> T + Send + 'static,
+    ) -> Result<T, Status> {
+        let (result, received) = oneshot::channel();
+        let job: Job = Box::new(move |device| {
+            result.send(f(device)).ok();
+        });
+        let stopped = || Status::unavailable("The device stopped");
+        self.jobs.send(job).map_err(|_| stopped())?;
+        received.await.map_err(|_| stopped())
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl WireGuardManager for Manager {
+    async fn get_device(
+        &self,
+        _request: Request<proto::GetDeviceRequest>,
+    ) -> Result<Response<proto::Device>, Status> {
+        let device = self
+            .call(|device| {
+                let dump = device.dump();
+                let stats = device.stats();
+                proto::Device {
+                    public_key: dump.public_key.unwrap_or_default(),
+                    listen_port: dump.listen_port.into(),
+                    fwmark: dump.fwmark,
+                    peers: stats.peers as u64,
+                    connected_peers: stats.connected_peers as u64,
+                    allowed_ips: stats.allowed_ips as u64,
+                    dropped_handshakes: stats.dropped_handshakes,
+                }
+            })
+            .await?;
+        Ok(Response::new(device))
+    }
+
+    async fn set_device(
+        &self,
+        request: Request<proto::SetDeviceRequest>,
+    ) -> Result<Response<proto::SetDeviceResponse>, Status> {
+        let request = request.into_inner();
+        let private_key = match request.private_key {
+            Some(key) => Some(x25519::StaticSecret::from(parse_key(&key)?)),
+            None => None,
+        };
+        let listen_port = match request.listen_port {
+            Some(port) => Some(
+                u16::try_from(port).map_err(|_| Status::invalid_argument("Invalid listen port"))?,
+            ),
+            None => None,
+        };
+        let config = WgConfig {
+            private_key,
+            listen_port,
+            fwmark: request.fwmark,
+            peers: request
+                .peers
+                .into_iter()
+                .map(peer_config)
+                .collect::<Result<_, _>>()?,
+            ..Default::default()
+        };
+        self.call(move |device| device.apply_config(config))
+            .await?
+            .map_err(device_error)?;
+        Ok(Response::new(proto::SetDeviceResponse {}))
+    }
+
+    async fn list_peers(
+        &self,
+        _request: Request<proto::ListPeersRequest>,
+    ) -> Result<Response<proto::ListPeersResponse>, Status> {
+        let peers = self
+            .call(|device| {
+                let dump = device.dump();
+                dump.peers
+                    .into_iter()
+                    .map(|peer| {
+                        let stats = decode_base64(&peer.public_key)
+                            .ok()
+                            .and_then(|key| device.peer_stats(&x25519::PublicKey::from(key)));
+                        proto::Peer {
+                            public_key: peer.public_key,
+                            endpoint: peer.endpoint_host.or(peer.endpoint.map(|e| e.to_string())),
+                            allowed_ips: peer.allowed_ips,
+                            persistent_keepalive: peer.persistent_keepalive.map(u32::from),
+                            stats: stats.map(peer_stats),
+                        }
+                    })
+                    .collect()
+            })
+            .await?;
+        Ok(Response::new(proto::ListPeersResponse { peers }))
+    }
+
+    async fn add_peer(
+        &self,
+        request: Request<proto::AddPeerRequest>,
+    ) -> Result<Response<proto::AddPeerResponse>, Status> {
+        let peer = request
+            .into_inner()
+            .peer
+            .ok_or_else(|| Status::invalid_argument("Missing peer"))?;
+        let peer = peer_config(peer)?;
+        self.call(move |device| {
+            if device.peer_stats(&peer.public_key).is_some() {
+                return Err(Status::already_exists("The peer exists already"));
+            }
+            let diff = ConfigDiff {
+                added: vec![peer],
+                ..Default::default()
+            };
+            device.apply_diff(diff).map_err(device_error)
+        })
+        .await??;
+        Ok(Response::new(proto::AddPeerResponse {}))
+    }
+
+    async fn remove_peer(
+        &self,
+        request: Request<proto::RemovePeerRequest>,
+    ) -> Result<Response<proto::RemovePeerResponse>, Status> {
+        let public_key = parse_public_key(&request.into_inner().public_key)?;
+        self.call(move |device| {
+            if device.peer_stats(&public_key).is_none() {
+                return Err(Status::not_found("Unknown peer"));
+            }
+            let diff = ConfigDiff {
+                removed: vec![public_key],
+                ..Default::default()
+            };
+            device.apply_diff(diff).map_err(device_error)
+        })
+        .await??;
+        Ok(Response::new(proto::RemovePeerResponse {}))
+    }
+
+    async fn get_peer_stats(
+        &self,
+        request: Request<proto::GetPeerStatsRequest>,
+    ) -> Result<Response<proto::PeerStats>, Status> {
+        let public_key = parse_public_key(&request.into_inner().public_key)?;
+        let stats = self
+            .call(move |device| device.peer_stats(&public_key))
+            .await?
+            .ok_or_else(|| Status::not_found("Unknown peer"))?;
+        Ok(Response::new(peer_stats(stats)))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    async fn stream_events(
+        &self,
+        _request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let mut events = self.call(|device| device.events()).await?;
+        let mut stopping = self.stopping.clone();
+        let (sender, receiver) = tokio::sync::mpsc::channel(EVENT_BUFFER);
+
+        // The streams end with the device, so they don't hold up the shutdown of the server
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    _ = stopping.changed() => None,
+                };
+                let sent = match event {
+                    Some(event) => sender.send(Ok(device_event(event))).await.is_ok(),
+                    None => false,
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+fn parse_key(key: &str) -> Result<[u8; 32], Status> {
+    decode_base64(key).map_err(|e| Status::invalid_argument(format!("Invalid key: {}", e)))
+}
+
+fn parse_public_key(key: &str) -> Result<x25519::PublicKey, Status> {
+    parse_key(key).map(x25519::PublicKey::from)
+}
+
+fn peer_config(peer: proto::PeerConfig) -> Result<PeerConfig, Status> {
+    let mut config = PeerConfig::new(parse_public_key(&peer.public_key)?);
+    config.preshared_key = match peer.preshared_key {
+        Some(key) => Some(parse_key(&key)?),
+        None => None,
+    };
+    config.endpoint = peer.endpoint;
+    config.allowed_ips = peer
+        .allowed_ips
+        .iter()
+        .map(|ip| {
+            ip.parse()
+                .map_err(|e| Status::invalid_argument(format!("Allowed IP {}: {}", ip, e)))
+        })
+        .collect::<Result<_, _>>()?;
+    config.persistent_keepalive = match peer.persistent_keepalive {
+        Some(interval) => Some(
+            u16::try_from(interval)
+                .map_err(|_| Status::invalid_argument("Invalid persistent keepalive"))?,
+        ),
+        None => None,
+    };
+    Ok(config)
+}
+
+fn peer_stats(stats: device::peer::PeerStats) -> proto::PeerStats {
+    proto::PeerStats {
+        seconds_since_last_handshake: stats.time_since_last_handshake.map(|t| t.as_secs()),
+        rx_bytes: stats.rx_bytes as u64,
+        tx_bytes: stats.tx_bytes as u64,
+        tx_dropped: stats.tx_dropped,
+        rx_drops_auth: stats.rx_drops_auth,
+        rx_drops_replay: stats.rx_drops_replay,
+        rx_drops_allowed_ips: stats.rx_drops_allowed_ips,
+        tx_drops_no_session: stats.tx_drops_no_session,
+        replay_detected_packets: stats.replay_detected_packets,
+        mac_failure_packets: stats.mac_failure_packets,
+    }
+}
+
+fn device_event(event: DeviceEvent) -> proto::Event {
+    use proto::event::Event;
+
+    let key = |key: x25519::PublicKey| encode_base64(key.as_bytes());
+    let event = match event {
+        DeviceEvent::DeviceStarted => Event::DeviceStarted(proto::event::DeviceStarted {}),
+        DeviceEvent::PrivateKeyRotated { public_key } => {
+            Event::PrivateKeyRotated(proto::event::PrivateKeyRotated {
+                public_key: key(public_key),
+            })
+        }
+        DeviceEvent::ListenPortChanged { old, new } => {
+            Event::ListenPortChanged(proto::event::ListenPortChanged {
+                old: old.into(),
+                new: new.into(),
+            })
+        }
+        DeviceEvent::Peer(PeerEvent::EndpointChanged {
+            public_key,
+            old,
+            new,
+        }) => Event::EndpointChanged(proto::event::EndpointChanged {
+            public_key: key(public_key),
+            old: old.map(|addr| addr.to_string()),
+            new: new.to_string(),
+        }),
+    };
+    proto::Event { event: Some(event) }
+}
+
+fn device_error(e: device::Error) -> Status {
+    match e {
+        device::Error::InvalidConfig(_) | device::Error::OverlappingAllowedIps(_) => {
+            Status::invalid_argument(e.to_string())
+        }
+        device::Error::LimitExceeded(_) | device::Error::TooManyPeers(_) => {
+            Status::resource_exhausted(e.to_string())
+        }
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use boringtun::device::channel_tun::ChannelTun;
+    use boringtun::device::DeviceConfig;
+    use proto::wire_guard_manager_client::WireGuardManagerClient;
+    use std::net::TcpListener;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+    use tonic::transport::Channel;
+    use tonic::Code;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn key(key: &x25519::StaticSecret) -> String {
+        encode_base64(x25519::PublicKey::from(key).as_bytes())
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn connect(addr: SocketAddr) -> WireGuardManagerClient<Channel> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match WireGuardManagerClient::connect(format!("http://{}", addr)).await {
+                Ok(client) => return client,
+                Err(e) if Instant::now() > deadline => panic!("Can't connect: {}", e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }
+
+    async fn next_event(
+        events: &mut tonic::Streaming<proto::Event>,
+    ) -> Option<proto::event::Event> {
+        tokio::time::timeout(TIMEOUT, events.message())
+            .await
+            .unwrap()
+            .unwrap()
+            .and_then(|event| event.event)
+    }
+
+    #[test]
+    fn test_manage_device() {
+        let (tun, _tun) = ChannelTun::new("grpc", 1420).unwrap();
+        // The device stops once this stream is closed
+        let (uapi_device, uapi) = UnixStream::pair().unwrap();
+        let config = DeviceConfig {
+            uapi_fd: uapi_device.into_raw_fd(),
+            ..Default::default()
+        };
+        let mut device = DeviceHandle::with_tun(Arc::new(tun), config).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let server = thread::spawn(move || device.serve_grpc(addr));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut client = connect(addr).await;
+            let mut events = client
+                .stream_events(proto::StreamEventsRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+
+            let private_key = x25519::StaticSecret::random_from_rng(rand_core::OsRng);
+            let peer = x25519::StaticSecret::random_from_rng(rand_core::OsRng);
+            let peer_config = proto::PeerConfig {
+                public_key: key(&peer),
+                endpoint: Some("127.0.0.1:51820".into()),
+                allowed_ips: vec!["10.0.0.2/32".into()],
+                ..Default::default()
+            };
+            client
+                .set_device(proto::SetDeviceRequest {
+                    private_key: Some(encode_base64(private_key.as_bytes())),
+                    peers: vec![peer_config.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let got = client
+                .get_device(proto::GetDeviceRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(got.public_key, key(&private_key));
+            assert_eq!(got.peers, 1);
+
+            let e = client
+                .add_peer(proto::AddPeerRequest {
+                    peer: Some(peer_config),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(e.code(), Code::AlreadyExists);
+
+            let other = x25519::StaticSecret::random_from_rng(rand_core::OsRng);
+            client
+                .add_peer(proto::AddPeerRequest {
+                    peer: Some(proto::PeerConfig {
+                        public_key: key(&other),
+                        allowed_ips: vec!["10.0.0.3/32".into()],
+                        ..Default::default()
+                    }),
+                })
+                .await
+                .unwrap();
+            let mut peers = client
+                .list_peers(proto::ListPeersRequest {})
+                .await
+                .unwrap()
+                .into_inner()
+                .peers;
+            peers.sort_by_key(|p| p.allowed_ips.clone());
+            assert_eq!(peers.len(), 2);
+            assert_eq!(peers[0].public_key, key(&peer));
+            assert_eq!(peers[0].endpoint.as_deref(), Some("127.0.0.1:51820"));
+            assert_eq!(peers[1].public_key, key(&other));
+
+            let stats = client
+                .get_peer_stats(proto::GetPeerStatsRequest {
+                    public_key: key(&peer),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(stats.seconds_since_last_handshake, None);
+
+            client
+                .remove_peer(proto::RemovePeerRequest {
+                    public_key: key(&other),
+                })
+                .await
+                .unwrap();
+            let e = client
+                .remove_peer(proto::RemovePeerRequest {
+                    public_key: key(&other),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(e.code(), Code::NotFound);
+            let e = client
+                .get_peer_stats(proto::GetPeerStatsRequest {
+                    public_key: "not a key".into(),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(e.code(), Code::InvalidArgument);
+
+            assert!(matches!(
+                next_event(&mut events).await,
+                Some(proto::event::Event::DeviceStarted(_))
+            ));
+            assert!(matches!(
+                next_event(&mut events).await,
+                Some(proto::event::Event::PrivateKeyRotated(e)) if e.public_key == key(&private_key)
+            ));
+        });
+
+        drop(uapi);
+        server.join().unwrap().unwrap();
+    }
+}
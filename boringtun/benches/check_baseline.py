@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Compare the results of the tunnel benchmarks against the committed baseline.
+
+Usage: check_baseline.py [--update] [--threshold PERCENT] [CRITERION_DIR]
+
+Reads the mean time of every benchmark from the estimates Criterion left in CRITERION_DIR
+(target/criterion by default), and fails if one of them is slower than in baseline.json by more
+than the threshold, or is missing. With --update, baseline.json is replaced by the results, to be
+committed along with a change that is expected to move them.
+"""
+
+import argparse
+import json
+import pathlib
+import sys
+
+BASELINE = pathlib.Path(__file__).with_name("baseline.json")
+
+
+def read_results(criterion_dir):
+    """The mean time in nanoseconds of every benchmark, by its Criterion ID"""
+    results = {}
+    for benchmark in criterion_dir.glob("**/new/benchmark.json"):
+        estimates = benchmark.with_name("estimates.json")
+        full_id = json.loads(benchmark.read_text())["full_id"]
+        results[full_id] = json.loads(estimates.read_text())["mean"]["point_estimate"]
+    return results
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--update", action="store_true")
+    parser.add_argument("--threshold", type=float, default=25.0)
+    parser.add_argument("criterion_dir", nargs="?", default="target/criterion")
+    args = parser.parse_args()
+
+    results = read_results(pathlib.Path(args.criterion_dir))
+    if args.update:
+        baseline = {k: round(v, 1) for k, v in sorted(results.items())}
+        BASELINE.write_text(json.dumps(baseline, indent=2) + "\n")
+        return 0
+
+    failed = False
+    for name, expected in json.loads(BASELINE.read_text()).items():
+        if name not in results:
+            print(f"{name}: missing from the results")
+            failed = True
+            continue
+        change = (results[name] - expected) / expected * 100
+        regressed = change > args.threshold
+        failed |= regressed
+        mark = "REGRESSED" if regressed else "ok"
+        print(f"{name}: {results[name]:.1f} ns, {change:+.1f}% from {expected:.1f} ns, {mark}")
+    return 1 if failed else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,27 @@
+use boringtun::device::allowed_ips::AllowedIps;
+use criterion::{BenchmarkId, Criterion};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A peer table with one /32 per peer, the way a hub is usually configured
+fn peer_table(peers: u32) -> AllowedIps<u32> {
+    let mut table = AllowedIps::new();
+    for i in 0..peers {
+        table.insert(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)), 32, i);
+    }
+    table
+}
+
+pub fn bench_allowed_ips_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("peer_lookup");
+
+    for peers in [1, 100, 10000] {
+        group.bench_with_input(BenchmarkId::from_parameter(peers), &peers, |b, &peers| {
+            let table = peer_table(peers);
+            let addr = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + peers - 1));
+
+            b.iter(|| table.find(addr));
+        });
+    }
+
+    group.finish();
+}
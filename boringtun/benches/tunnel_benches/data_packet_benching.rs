@@ -0,0 +1,77 @@
+use boringtun::noise::{Tunn, TunnResultRaw};
+use boringtun::test_utils::{VirtualConfig, VirtualEndpoint, VirtualNetwork};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+
+const PAYLOAD_SIZES: [usize; 4] = [64, 576, 1280, 65000];
+
+/// An IPv4 packet of `size` bytes in total, the tunnel doesn't look past the IP header
+fn ipv4_packet(size: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; size];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+    packet[12..16].copy_from_slice(&[192, 168, 1, 2]);
+    packet[16..20].copy_from_slice(&[192, 168, 1, 3]);
+    packet
+}
+
+fn connected_pair() -> (VirtualEndpoint, VirtualEndpoint) {
+    let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+    VirtualNetwork::handshake(&mut a, &mut b).unwrap();
+    (a, b)
+}
+
+fn encapsulate(tunn: &mut Tunn, packet: &[u8], dst: &mut [u8]) -> usize {
+    match tunn.encapsulate(packet, dst) {
+        TunnResultRaw::WriteToNetwork(data) => data.len(),
+        _ => panic!("Unexpected result from encapsulate"),
+    }
+}
+
+pub fn bench_encapsulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encapsulate");
+
+    for size in PAYLOAD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let (mut a, _b) = connected_pair();
+            let packet = ipv4_packet(size);
+            let mut dst = vec![0u8; size + 32];
+
+            b.iter(|| encapsulate(a.tunn_mut(), &packet, &mut dst));
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_decapsulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decapsulate");
+
+    for size in PAYLOAD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let (mut a, mut b_end) = connected_pair();
+            let packet = ipv4_packet(size);
+            let mut dst = vec![0u8; size + 32];
+
+            // Every iteration needs a fresh packet, or it would be rejected as a replay
+            b.iter_batched(
+                || {
+                    let mut datagram = vec![0u8; size + 32];
+                    let n = encapsulate(a.tunn_mut(), &packet, &mut datagram);
+                    datagram.truncate(n);
+                    datagram
+                },
+                |datagram| match b_end.tunn_mut().decapsulate(None, &datagram, &mut dst) {
+                    TunnResultRaw::WriteToTunnelV4(packet, _) => packet.len(),
+                    _ => panic!("Unexpected result from decapsulate"),
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
@@ -0,0 +1,43 @@
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Tunn, TunnResultRaw};
+use boringtun::test_utils::{VirtualConfig, VirtualNetwork};
+use boringtun::x25519;
+use criterion::Criterion;
+use std::sync::Arc;
+
+/// A config with a rate limiter that never kicks in, so every iteration performs a full handshake
+/// instead of a cookie exchange
+fn unlimited_config(index: u32) -> VirtualConfig {
+    let mut config = VirtualConfig::new(index);
+    let public_key = x25519::PublicKey::from(&config.private_key);
+    config.rate_limiter = Some(Arc::new(RateLimiter::new(&public_key, u64::MAX)));
+    config
+}
+
+fn handshake_round_trip(initiator: &mut Tunn, responder: &mut Tunn, buf: &mut [u8]) {
+    let mut init = [0u8; 148];
+    match initiator.format_handshake_initiation(&mut init, true) {
+        TunnResultRaw::WriteToNetwork(_) => {}
+        _ => panic!("Unexpected result from format_handshake_initiation"),
+    }
+
+    let mut resp = [0u8; 92];
+    match responder.decapsulate(None, &init, &mut resp) {
+        TunnResultRaw::WriteToNetwork(_) => {}
+        _ => panic!("Unexpected result from handshake initiation"),
+    }
+
+    match initiator.decapsulate(None, &resp, buf) {
+        TunnResultRaw::WriteToNetwork(_) => {} // The keepalive
+        _ => panic!("Unexpected result from handshake response"),
+    }
+}
+
+pub fn bench_handshake(c: &mut Criterion) {
+    c.bench_function("handshake_round_trip", |b| {
+        let (mut a, mut b_end) = VirtualNetwork::new(unlimited_config(1), unlimited_config(2));
+        let mut buf = vec![0u8; 256];
+
+        b.iter(|| handshake_round_trip(a.tunn_mut(), b_end.tunn_mut(), &mut buf));
+    });
+}
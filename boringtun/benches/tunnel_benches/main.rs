@@ -0,0 +1,17 @@
+use allowed_ips_benching::bench_allowed_ips_lookup;
+use data_packet_benching::{bench_decapsulate, bench_encapsulate};
+use handshake_benching::bench_handshake;
+
+mod allowed_ips_benching;
+mod data_packet_benching;
+mod handshake_benching;
+
+criterion::criterion_group!(
+    tunnel_benches,
+    bench_encapsulate,
+    bench_decapsulate,
+    bench_handshake,
+    bench_allowed_ips_lookup
+);
+
+criterion::criterion_main!(tunnel_benches);
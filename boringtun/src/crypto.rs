@@ -0,0 +1,165 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The AEAD used to encrypt and decrypt transport data packets, which can be replaced with a
+//! hardware or HSM backed implementation with [`crate::noise::Tunn::set_crypto_provider`].
+//! Handshake messages always use the built-in implementation.
+
+use aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use std::fmt;
+
+/// The size of the authentication tag appended to the ciphertext
+pub const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The output buffer can't hold the result
+    BufferTooSmall,
+    /// The ciphertext or its authentication tag are invalid
+    InvalidTag,
+    /// The backend failed for a reason of its own
+    Backend,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// An implementation of ChaCha20-Poly1305 for transport data packets.
+///
+/// `nonce` is the counter of the packet, the 96 bit nonce is made of 32 zero bits followed by the
+/// counter in little endian, as required by WireGuard.
+pub trait CryptoProvider: fmt::Debug + Send + Sync {
+    /// Encrypt `plaintext` into the first `plaintext.len() + TAG_LEN` bytes of `out`, with the
+    /// tag last
+    fn chacha20poly1305_seal(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError>;
+
+    /// Verify and decrypt `ciphertext`, tag included, into the first
+    /// `ciphertext.len() - TAG_LEN` bytes of `out`
+    fn chacha20poly1305_open(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError>;
+}
+
+/// The software implementation of the `chacha20poly1305` crate
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SoftwareCryptoProvider;
+
+fn nonce_bytes(nonce: u64) -> chacha20poly1305::Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..12].copy_from_slice(&nonce.to_le_bytes());
+    bytes.into()
+}
+
+impl CryptoProvider for SoftwareCryptoProvider {
+    fn chacha20poly1305_seal(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        let len = plaintext.len();
+        if out.len() < len + TAG_LEN {
+            return Err(CryptoError::BufferTooSmall);
+        }
+
+        let (data, rest) = out.split_at_mut(len);
+        data.copy_from_slice(plaintext);
+        let tag = ChaCha20Poly1305::new(key.into())
+            .encrypt_in_place_detached(&nonce_bytes(nonce), aad, data)
+            .map_err(|_| CryptoError::Backend)?;
+        rest[..TAG_LEN].copy_from_slice(&tag);
+        Ok(())
+    }
+
+    fn chacha20poly1305_open(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        let len = ciphertext
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(CryptoError::InvalidTag)?;
+        if out.len() < len {
+            return Err(CryptoError::BufferTooSmall);
+        }
+
+        let (data, tag) = ciphertext.split_at(len);
+        out[..len].copy_from_slice(data);
+        ChaCha20Poly1305::new(key.into())
+            .decrypt_in_place_detached(&nonce_bytes(nonce), aad, &mut out[..len], tag.into())
+            .map_err(|_| CryptoError::InvalidTag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+    #[test]
+    fn test_software_provider_matches_ring() {
+        let key = [7u8; 32];
+        let ring_key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap());
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&42u64.to_le_bytes());
+        let mut expected = b"hello".to_vec();
+        ring_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(b"aad"),
+                &mut expected,
+            )
+            .unwrap();
+
+        let provider = SoftwareCryptoProvider;
+        let mut sealed = [0u8; 5 + TAG_LEN];
+        provider
+            .chacha20poly1305_seal(&key, 42, b"aad", b"hello", &mut sealed)
+            .unwrap();
+        assert_eq!(&sealed[..], &expected[..]);
+
+        let mut opened = [0u8; 5];
+        provider
+            .chacha20poly1305_open(&key, 42, b"aad", &sealed, &mut opened)
+            .unwrap();
+        assert_eq!(&opened, b"hello");
+
+        sealed[0] ^= 1;
+        assert_eq!(
+            provider.chacha20poly1305_open(&key, 42, b"aad", &sealed, &mut opened),
+            Err(CryptoError::InvalidTag)
+        );
+        assert_eq!(
+            provider.chacha20poly1305_open(&key, 42, b"aad", &sealed[..4], &mut opened),
+            Err(CryptoError::InvalidTag)
+        );
+        assert_eq!(
+            provider.chacha20poly1305_seal(&key, 42, b"aad", b"hello", &mut opened),
+            Err(CryptoError::BufferTooSmall)
+        );
+    }
+}
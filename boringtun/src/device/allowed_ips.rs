@@ -48,6 +48,16 @@ impl<D> AllowedIps<D> {
         )
     }
 
+    /// The number of entries, IPv4 and IPv6 together
+    pub fn len(&self) -> usize {
+        let (v4, v6) = self.ips.len();
+        v4 + v6
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty()
+    }
+
     pub fn find(&self, key: IpAddr) -> Option<&D> {
         self.ips.longest_match(key).map(|(_net, data)| data)
     }
@@ -56,7 +66,19 @@ impl<D> AllowedIps<D> {
         self.ips.retain(|_, v| !predicate(v));
     }
 
-    pub fn iter(&self) -> Iter<D> {
+    /// The entries that contain `key/cidr` or are contained in it, looked up one by one
+    pub fn overlapping(&self, key: IpAddr, cidr: u32) -> impl Iterator<Item = (&D, IpAddr, u8)> {
+        let network = IpNetwork::new_truncate(key, cidr as u8).expect("cidr is valid length");
+        self.ips
+            .iter()
+            .filter(move |(other, _)| {
+                other.contains(network.network_address())
+                    || network.contains(other.network_address())
+            })
+            .map(|(other, d)| (d, other.network_address(), other.netmask()))
+    }
+
+    pub fn iter(&self) -> Iter<'_, D> {
         Iter(
             self.ips
                 .iter()
@@ -172,6 +194,22 @@ mod tests {
         assert_eq!(map_iter.next(), None);
     }
 
+    #[test]
+    fn test_allowed_ips_overlapping() {
+        let map = build_allowed_ips();
+        let overlapping = |addr: [u8; 4], cidr| {
+            let mut found: Vec<_> = map.overlapping(IpAddr::from(addr), cidr).collect();
+            found.sort();
+            found.into_iter().map(|(d, _, _)| *d).collect::<String>()
+        };
+        // Contained in 127.0.0.0/16, which contains 127.0.0.1/32
+        assert_eq!(overlapping([127, 0, 0, 0], 24), "12");
+        // Contains 127.0.0.0/16 and 127.1.15.0/24
+        assert_eq!(overlapping([127, 0, 0, 0], 8), "123");
+        assert_eq!(overlapping([127, 2, 0, 0], 16), "");
+        assert_eq!(overlapping([0, 0, 0, 0], 0), "123456");
+    }
+
     #[test]
     fn test_allowed_ips_v4_kernel_compatibility() {
         // Test case from wireguard-go
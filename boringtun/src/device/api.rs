@@ -1,22 +1,218 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+//! The [cross-platform userspace API](https://www.wireguard.com/xplatform/) of WireGuard, on a
+//! unix socket, or a named pipe on Windows.
+//!
+//! Beside the standard keys, a `get` reports keys specific to boringtun, prefixed with `bt_`.
+//! Readers ignore the keys they don't know, `wg` included. The drop counters below only grow,
+//! and are absent while they are zero, but for `rx_replay_errors` and `rx_mac_errors`, which are
+//! always given. They can be left out with [`super::DeviceConfig::uapi_drop_counters`].
+//!
+//! | Key | |
+//! |-----|-|
+//! | `bt_handshake_rate_limited` | Handshake messages the device answered with a cookie reply, or dropped, while under load |
+//!
+//! And after the standard keys of each peer:
+//!
+//! | Key | |
+//! |-----|-|
+//! | `bt_rx_drops_auth` | Data packets from the peer that failed to decrypt |
+//! | `bt_rx_drops_replay` | Data packets from the peer with a counter that was already received, or too old |
+//! | `bt_rx_drops_allowed_ips` | Packets from the peer with a source address it is not allowed |
+//! | `bt_tx_drops_no_session` | Packets for the peer dropped while waiting for a session |
+//! | `rx_replay_errors` | `bt_rx_drops_replay`, given even while it is zero |
+//! | `rx_mac_errors` | `bt_rx_drops_auth`, given even while it is zero |
+
 use super::dev_lock::LockReadGuard;
+#[cfg(unix)]
 use super::drop_privileges::get_saved_ids;
-use super::{AllowedIP, Device, Error, SocketAddr};
+#[cfg(windows)]
+use super::named_pipe::PipeListener;
+#[cfg(windows)]
+use super::poll::AsRawFd;
+use super::resolver::{resolve_endpoints, Preresolved};
+use super::shaper::BandwidthLimit;
+use super::{in_netns, AllowedIP, Device, Error, Obfuscation, ReplacedPeers, Resolver, SocketAddr};
 use crate::device::Action;
 use crate::serialization::KeyBytes;
 use crate::x25519;
 use hex::encode as encode_hex;
 use libc::*;
+use std::fmt::Display;
+#[cfg(unix)]
 use std::fs::{create_dir, remove_file};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+#[cfg(unix)]
 use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Where the sockets of the interfaces are, `NAME.sock`, as `wg` expects them
+#[cfg(unix)]
+pub const SOCK_DIR: &str = "/var/run/wireguard/";
+
+/// Where the Windows build of `wg` looks for the named pipes of the interfaces
+#[cfg(windows)]
+const PIPE_DIR: &str = r"\\.\pipe\ProtectedPrefix\Administrators\WireGuard\";
+
+/// How often the UAPI socket is checked for removal, and the MTU of the interface read again. It
+/// is the one timer of an idle device, a `stat` and an `ioctl` whatever its number of peers, as
+/// neither the removal of a file nor the MTU of an interface can be waited for with the poll of
+/// the event loops on every system.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest `set` request that is read, enough for some thirty thousand peers with a few
+/// allowed IPs each. Larger configurations are sent in several requests.
+const MAX_SET_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// The non-zero `errno` a UAPI request failed with, such as `EINVAL` for a malformed key, or
+/// `EADDRINUSE` for a listen port that is taken. Its message is that of `strerror`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UapiError(pub i32);
+
+impl UapiError {
+    /// An `Err` for a non-zero `errno`
+    pub fn check(errno: i32) -> Result<(), UapiError> {
+        match errno {
+            0 => Ok(()),
+            errno => Err(UapiError(errno)),
+        }
+    }
+
+    /// Parse the `errno=N` line that ends a response, `None` for any other line. An `errno` that
+    /// is not a number is reported as `EPROTO`.
+    pub fn parse_line(line: &str) -> Option<Result<(), UapiError>> {
+        let errno = line.trim_end().strip_prefix("errno=")?;
+        Some(match errno.parse() {
+            Ok(errno) => UapiError::check(errno),
+            Err(_) => Err(UapiError(EPROTO)),
+        })
+    }
+}
+
+impl Display for UapiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = unsafe { std::ffi::CStr::from_ptr(strerror(self.0)) };
+        write!(f, "{} (errno {})", message.to_string_lossy(), self.0)
+    }
+}
+
+impl std::error::Error for UapiError {}
+
+/// An extension to the UAPI protocol, used to carry implementation specific keys.
+///
+/// An extension is registered with a key prefix, using [`Device::register_uapi_extension`].
+/// During a `set` operation, any key that is not handled by the core protocol and starts with
+/// the prefix is passed to the extension. Keys that are claimed by neither the core nor an
+/// extension are still rejected with `EINVAL`. During a `get` operation the extension may append
+/// lines after the device keys and after the keys of each peer.
+///
+/// Extensions never write to the socket directly, so they can't break the protocol framing:
+/// the terminating blank line and the `errno=` line are always written by the core.
+pub trait UapiExt: Send + Sync {
+    /// Handle a device level `key=value` pair. Returns an errno value on failure.
+    fn set_device(&self, key: &str, value: &str) -> Result<(), i32> {
+        let _ = (key, value);
+        Err(EINVAL)
+    }
+
+    /// Handle a `key=value` pair that appeared in the section of the given peer. The pair is
+    /// passed on as soon as it is read, before the peer section is applied. Returns an errno
+    /// value on failure.
+    fn set_peer(&self, public_key: &x25519::PublicKey, key: &str, value: &str) -> Result<(), i32> {
+        let _ = (public_key, key, value);
+        Err(EINVAL)
+    }
+
+    /// Append device level lines to a `get` response.
+    fn get_device(&self, out: &mut UapiExtWriter) {
+        let _ = out;
+    }
+
+    /// Append lines to the section of the given peer in a `get` response.
+    fn get_peer(&self, public_key: &x25519::PublicKey, out: &mut UapiExtWriter) {
+        let _ = (public_key, out);
+    }
+}
+
+/// Writes the `key=value` lines produced by a [`UapiExt`]. Lines that would break the framing of
+/// the protocol, or whose key does not start with the prefix of the extension, are discarded.
+pub struct UapiExtWriter<'a> {
+    prefix: &'a str,
+    writer: &'a mut dyn Write,
+}
+
+impl<'a> UapiExtWriter<'a> {
+    fn new(prefix: &'a str, writer: &'a mut dyn Write) -> Self {
+        UapiExtWriter { prefix, writer }
+    }
+
+    /// Write a single `key=value` line. Returns false if the line was discarded.
+    pub fn write(&mut self, key: &str, value: impl Display) -> bool {
+        let value = value.to_string();
+        if !key.starts_with(self.prefix)
+            || key.contains(['=', '\n'])
+            || value.contains('\n')
+            || value.is_empty()
+        {
+            tracing::warn!(message = "Discarding malformed UAPI extension line", key);
+            return false;
+        }
+
+        writeln!(self.writer, "{}={}", key, value).is_ok()
+    }
+}
+
+pub(crate) struct UapiExtension {
+    prefix: String,
+    handler: Box<dyn UapiExt>,
+}
+
+fn find_extension<'a>(extensions: &'a [UapiExtension], key: &str) -> Option<&'a UapiExtension> {
+    extensions.iter().find(|ext| key.starts_with(&ext.prefix))
+}
 
-const SOCK_DIR: &str = "/var/run/wireguard/";
+/// An example [`UapiExt`] that lets a controller attach a free form label to each peer, using the
+/// `bt_label` key: `bt_label=office router`. The label is reported back in `get` response.
+#[derive(Default)]
+pub struct PeerLabels {
+    labels: parking_lot::Mutex<std::collections::HashMap<x25519::PublicKey, String>>,
+}
+
+impl PeerLabels {
+    pub const PREFIX: &'static str = "bt_label";
+
+    pub fn label(&self, public_key: &x25519::PublicKey) -> Option<String> {
+        self.labels.lock().get(public_key).cloned()
+    }
+}
+
+impl UapiExt for PeerLabels {
+    fn set_peer(&self, public_key: &x25519::PublicKey, key: &str, value: &str) -> Result<(), i32> {
+        if key != Self::PREFIX {
+            return Err(EINVAL);
+        }
 
+        let mut labels = self.labels.lock();
+        if value.is_empty() {
+            labels.remove(public_key);
+        } else {
+            labels.insert(*public_key, value.to_owned());
+        }
+        Ok(())
+    }
+
+    fn get_peer(&self, public_key: &x25519::PublicKey, out: &mut UapiExtWriter) {
+        if let Some(label) = self.labels.lock().get(public_key) {
+            out.write(Self::PREFIX, label);
+        }
+    }
+}
+
+#[cfg(unix)]
 fn create_sock_dir() {
     let _ = create_dir(SOCK_DIR); // Create the directory if it does not exist
 
@@ -35,19 +231,66 @@ fn create_sock_dir() {
 }
 
 impl Device {
+    /// Register an extension that handles UAPI keys starting with `prefix`. Keys of the core
+    /// protocol always take priority over extensions, and the first registered extension with a
+    /// matching prefix wins.
+    pub fn register_uapi_extension(&mut self, prefix: &str, handler: Box<dyn UapiExt>) {
+        self.uapi_extensions.push(UapiExtension {
+            prefix: prefix.to_owned(),
+            handler,
+        });
+    }
+
     /// Register the api handler for this Device. The api handler receives stream connections on a Unix socket
     /// with a known path: /var/run/wireguard/{tun_name}.sock.
+    #[cfg(unix)]
     pub fn register_api_handler(&mut self) -> Result<(), Error> {
         let path = format!("{}/{}.sock", SOCK_DIR, self.iface.name()?);
 
         create_sock_dir();
 
+        // A socket someone listens on belongs to another instance for the same interface, which
+        // a multi-queue tunnel would let this one share
+        if UnixStream::connect(&path).is_ok() {
+            return Err(Error::ApiSocket(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is in use, the interface is already running", path),
+            )));
+        }
+
         let _ = remove_file(&path); // Attempt to remove the socket if already exists
 
         let api_listener = UnixListener::bind(&path).map_err(Error::ApiSocket)?; // Bind a new socket to the path
 
         self.cleanup_paths.push(path.clone());
+        self.uapi_path = Some(path.clone());
+        self.register_api_listener(api_listener, Some(path))
+    }
+
+    /// Give the UAPI socket the device created, and its directory, to `uid` and `gid`, with read
+    /// and write access for both
+    #[cfg(unix)]
+    pub(crate) fn chown_api_socket(&self, uid: uid_t, gid: gid_t) -> Result<(), Error> {
+        use std::os::unix::fs::{chown, PermissionsExt};
+
+        let path = match &self.uapi_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        chown(SOCK_DIR, Some(uid), Some(gid)).map_err(Error::ApiSocket)?;
+        chown(path, Some(uid), Some(gid)).map_err(Error::ApiSocket)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))
+            .map_err(Error::ApiSocket)
+    }
 
+    /// Serve the API on a socket that listens already, such as one passed by systemd. The device
+    /// exits when `path` is removed, if it is given.
+    #[cfg(unix)]
+    pub fn register_api_listener(
+        &mut self,
+        api_listener: UnixListener,
+        path: Option<String>,
+    ) -> Result<(), Error> {
         self.queue.new_event(
             api_listener.as_raw_fd(),
             Box::new(move |d, _| {
@@ -60,9 +303,10 @@ impl Device {
                 let mut reader = BufReader::new(&api_conn);
                 let mut writer = BufWriter::new(&api_conn);
                 let mut cmd = String::new();
-                if reader.read_line(&mut cmd).is_ok() {
-                    handle_api(&cmd, &mut reader, &mut writer, d);
-                }
+                // A command that can't be read, such as one that is not UTF-8, is answered with
+                // an error too
+                let _ = reader.read_line(&mut cmd);
+                handle_api(&cmd, &mut reader, &mut writer, d);
                 Action::Continue // Indicates the worker thread should continue as normal
             }),
         )?;
@@ -71,6 +315,43 @@ impl Device {
         self.register_api_signal_handlers()
     }
 
+    /// Register the api handler for this Device. The api handler receives connections on a named
+    /// pipe with a known path: `\\.\pipe\ProtectedPrefix\Administrators\WireGuard\{tun_name}`.
+    /// Only processes running as SYSTEM can create it.
+    #[cfg(windows)]
+    pub fn register_api_handler(&mut self) -> Result<(), Error> {
+        let path = format!("{}{}", PIPE_DIR, self.iface.name()?);
+
+        let api_listener = PipeListener::bind(&path).map_err(Error::ApiSocket)?;
+
+        self.queue.new_event(
+            api_listener.as_raw_fd(),
+            Box::new(move |d, _| {
+                // This is the closure that listens on the api named pipe
+                let api_conn = match api_listener.accept() {
+                    Some(conn) => conn,
+                    None => return Action::Continue,
+                };
+
+                let mut reader = BufReader::new(&api_conn);
+                let mut writer = BufWriter::new(&api_conn);
+                let mut cmd = String::new();
+                // A command that can't be read, such as one that is not UTF-8, is answered with
+                // an error too
+                let _ = reader.read_line(&mut cmd);
+                handle_api(&cmd, &mut reader, &mut writer, d);
+                drop(writer);
+                // The client loses what it didn't read yet once the pipe is closed
+                let _ = api_conn.sync_all();
+                Action::Continue // Indicates the worker thread should continue as normal
+            }),
+        )?;
+
+        // The pipe goes away with the process, there is nothing to monitor
+        self.register_monitor(None)
+    }
+
+    #[cfg(unix)]
     pub fn register_api_fd(&mut self, fd: i32) -> Result<(), Error> {
         let io_file = unsafe { UnixStream::from_raw_fd(fd) };
 
@@ -97,35 +378,37 @@ impl Device {
         Ok(())
     }
 
-    fn register_monitor(&self, path: String) -> Result<(), Error> {
+    fn register_monitor(&self, path: Option<String>) -> Result<(), Error> {
         self.queue.new_periodic_event(
             Box::new(move |d, _| {
                 // This is not a very nice hack to detect if the control socket was removed
-                // and exiting nicely as a result. We check every 3 seconds in a loop if the
+                // and exiting nicely as a result. We check every MONITOR_INTERVAL if the
                 // file was deleted by stating it.
                 // The problem is that on linux inotify can be used quite beautifully to detect
                 // deletion, and kqueue EVFILT_VNODE can be used for the same purpose, but that
                 // will require introducing new events, for no measurable benefit.
                 // TODO: Could this be an issue if we restart the service too quickly?
-                let path = std::path::Path::new(&path);
-                if !path.exists() {
-                    d.trigger_exit();
-                    return Action::Exit;
+                if let Some(path) = &path {
+                    if !std::path::Path::new(path).exists() {
+                        d.trigger_exit();
+                        return Action::Exit;
+                    }
                 }
 
                 // Periodically read the mtu of the interface in case it changes
-                if let Ok(mtu) = d.iface.mtu() {
+                if let Ok(mtu) = in_netns(&d.config, || d.iface.mtu()) {
                     d.mtu.store(mtu, Ordering::Relaxed);
                 }
 
                 Action::Continue
             }),
-            std::time::Duration::from_millis(1000),
+            MONITOR_INTERVAL,
         )?;
 
         Ok(())
     }
 
+    #[cfg(unix)]
     fn register_api_signal_handlers(&self) -> Result<(), Error> {
         self.queue
             .new_signal_event(SIGINT, Box::new(move |_, _| Action::Exit))?;
@@ -139,13 +422,17 @@ impl Device {
 
 fn handle_api(
     cmd: &str,
-    reader: &mut BufReader<&UnixStream>,
-    writer: &mut BufWriter<&UnixStream>,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
     d: &mut LockReadGuard<Device>,
 ) {
+    // An operator looking at an idle device doesn't race its exit
+    d.mark_active();
     let status = match cmd {
-        // Only two commands are legal according to the protocol, get=1 and set=1.
+        // Only two commands are legal according to the protocol, get=1 and set=1. get=2 is
+        // specific to boringtun, see `dump`.
         "get=1\n" => api_get(writer, d),
+        "get=2\n" => api_get_json(writer, d),
         "set=1\n" => api_set(reader, d),
         _ => EIO,
     };
@@ -153,8 +440,15 @@ fn handle_api(
     writeln!(writer, "errno={}\n", status).ok();
 }
 
+fn api_get_json(writer: &mut impl Write, d: &Device) -> i32 {
+    match serde_json::to_writer(&mut *writer, &d.dump()) {
+        Ok(()) if writeln!(writer).is_ok() => 0,
+        _ => EIO,
+    }
+}
+
 #[allow(unused_must_use)]
-fn api_get(writer: &mut BufWriter<&UnixStream>, d: &Device) -> i32 {
+fn api_get(writer: &mut impl Write, d: &Device) -> i32 {
     // get command requires an empty line, but there is no reason to be religious about it
     if let Some(ref k) = d.key_pair {
         writeln!(writer, "own_public_key={}", encode_hex(k.1.as_bytes()));
@@ -168,6 +462,30 @@ fn api_get(writer: &mut BufWriter<&UnixStream>, d: &Device) -> i32 {
         writeln!(writer, "fwmark={}", fwmark);
     }
 
+    let obfuscation = &d.obfuscation;
+    if !obfuscation.is_standard() {
+        writeln!(writer, "jc={}", obfuscation.junk_packet_count);
+        writeln!(writer, "jmin={}", obfuscation.junk_packet_min_size);
+        writeln!(writer, "jmax={}", obfuscation.junk_packet_max_size);
+        writeln!(writer, "s1={}", obfuscation.init_packet_junk_size);
+        writeln!(writer, "s2={}", obfuscation.response_packet_junk_size);
+        for (i, msg_type) in obfuscation.message_types.iter().enumerate() {
+            writeln!(writer, "h{}={}", i + 1, msg_type);
+        }
+    }
+
+    if d.config.uapi_drop_counters {
+        let rate_limited = d.rate_limiter.as_ref().map_or(0, |r| r.rate_limited());
+        if rate_limited > 0 {
+            writeln!(writer, "bt_handshake_rate_limited={}", rate_limited);
+        }
+    }
+
+    for ext in &d.uapi_extensions {
+        ext.handler
+            .get_device(&mut UapiExtWriter::new(&ext.prefix, writer));
+    }
+
     for (k, p) in d.peers.iter() {
         let p = p.lock();
         writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
@@ -197,118 +515,444 @@ fn api_get(writer: &mut BufWriter<&UnixStream>, d: &Device) -> i32 {
 
         writeln!(writer, "rx_bytes={}", rx_bytes);
         writeln!(writer, "tx_bytes={}", tx_bytes);
+
+        if let Some(limit) = p.bandwidth_limit() {
+            writeln!(writer, "bt_tx_rate={}", limit.bytes_per_sec);
+            writeln!(writer, "bt_tx_burst={}", limit.burst);
+        }
+        if p.tx_dropped() > 0 {
+            writeln!(writer, "bt_tx_dropped={}", p.tx_dropped());
+        }
+        if p.filtered_packets() > 0 {
+            writeln!(writer, "bt_filtered_packets={}", p.filtered_packets());
+        }
+        if let Some(mtu) = p.path_mtu() {
+            writeln!(writer, "bt_path_mtu={}", mtu);
+        }
+        if let Some(index) = p.stats().active_endpoint {
+            writeln!(writer, "bt_active_endpoint={}", index);
+        }
+        if p.is_unreachable() {
+            writeln!(writer, "bt_unreachable=true");
+        }
+        #[cfg(feature = "mdns")]
+        if p.mdns_discovery() {
+            writeln!(writer, "bt_mdns_discovery=true");
+        }
+        if !p.pcap_capture() {
+            writeln!(writer, "bt_pcap_capture=false");
+        }
+        if d.config.uapi_drop_counters {
+            let stats = p.stats();
+            let counters = [
+                ("bt_rx_drops_auth", stats.rx_drops_auth),
+                ("bt_rx_drops_replay", stats.rx_drops_replay),
+                ("bt_rx_drops_allowed_ips", stats.rx_drops_allowed_ips),
+                ("bt_tx_drops_no_session", stats.tx_drops_no_session),
+            ];
+            for (key, count) in counters.iter().filter(|(_, count)| *count > 0) {
+                writeln!(writer, "{}={}", key, count);
+            }
+            // The same counts as `bt_rx_drops_replay` and `bt_rx_drops_auth`, under the names
+            // readers expect them
+            writeln!(writer, "rx_replay_errors={}", stats.rx_drops_replay);
+            writeln!(writer, "rx_mac_errors={}", stats.rx_drops_auth);
+        }
+
+        for ext in &d.uapi_extensions {
+            ext.handler
+                .get_peer(k, &mut UapiExtWriter::new(&ext.prefix, writer));
+        }
     }
     0
 }
 
-fn api_set(reader: &mut BufReader<&UnixStream>, d: &mut LockReadGuard<Device>) -> i32 {
+fn api_set(reader: &mut impl BufRead, d: &mut LockReadGuard<Device>) -> i32 {
+    // The request is read, and its host names resolved, before the device is locked for writing,
+    // so neither a slow client nor a slow resolver stalls the event loops
+    let request = match read_set_request(reader) {
+        Ok(request) => request,
+        Err(errno) => return errno,
+    };
+    let mut resolved = Preresolved::default();
+    for endpoints in request.lines().filter_map(|l| l.strip_prefix("endpoint=")) {
+        resolved.resolve_ahead(d.resolver.as_ref(), endpoints);
+    }
+
     d.try_writeable(
         |device| device.trigger_yield(),
         |device| {
             device.cancel_yield();
 
-            let mut cmd = String::new();
+            let mut replaced = None;
+            let mut obfuscation = device.obfuscation;
+            let mut errno = api_set_device(
+                &mut request.as_bytes(),
+                device,
+                &resolved,
+                &mut replaced,
+                &mut obfuscation,
+            );
+            if let Some(replaced) = replaced {
+                device.finish_replace_peers(replaced);
+            }
+            if errno == 0 {
+                errno = match device.set_obfuscation(obfuscation) {
+                    Ok(()) => 0,
+                    Err(Error::InvalidConfig(_)) => EINVAL,
+                    Err(_) => EADDRINUSE,
+                };
+            }
+            errno
+        },
+    )
+    .unwrap_or(EIO)
+}
+
+/// Read the keys of a `set` operation, up to and including the blank line that ends it. Nothing
+/// of a request that fails to be read is applied. A request larger than
+/// [`MAX_SET_REQUEST_SIZE`] is skipped and answered with `E2BIG`.
+fn read_set_request(reader: &mut impl BufRead) -> Result<String, i32> {
+    let mut request = String::new();
+    loop {
+        let start = request.len();
+        let room = MAX_SET_REQUEST_SIZE - start;
+        if room == 0 {
+            skip_set_request(reader, request.ends_with('\n'));
+            return Err(E2BIG);
+        }
+        let read = Read::take(&mut *reader, room as u64)
+            .read_line(&mut request)
+            .map_err(|_| EIO)?;
+        if read == 0 || &request[start..] == "\n" {
+            return Ok(request);
+        }
+    }
+}
 
-            while reader.read_line(&mut cmd).is_ok() {
-                let end = cmd.pop(); // remove newline if any
-                if let Some(end) = end {
-                    if end != '\n' {
-                        return EPROTO;
+/// Skip what is left of a `set` request, up to and including the blank line that ends it, so
+/// the next command on the stream is read from its start. `line_start` tells whether the request
+/// was cut at the start of a line.
+fn skip_set_request(reader: &mut impl BufRead, mut line_start: bool) {
+    loop {
+        let (used, done) = match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => {
+                let mut end = None;
+                for (i, &b) in buf.iter().enumerate() {
+                    if b != b'\n' {
+                        line_start = false;
+                    } else if line_start {
+                        end = Some(i + 1);
+                        break;
+                    } else {
+                        line_start = true;
                     }
                 }
-                if cmd.is_empty() {
-                    return 0; // Done
-                }
-                {
-                    let parsed_cmd: Vec<&str> = cmd.split('=').collect();
-                    if parsed_cmd.len() != 2 {
-                        return EPROTO;
-                    }
+                (end.unwrap_or(buf.len()), end.is_some())
+            }
+            _ => return,
+        };
+        reader.consume(used);
+        if done {
+            return;
+        }
+    }
+}
+
+/// Apply the keys of a `set` operation, with the host names of the endpoints looked up in
+/// `resolver`. The peers set aside by `replace_peers` are left in
+/// `replaced`. The obfuscation parameters are collected in `obfuscation`, to be applied together
+/// once they are all known.
+fn api_set_device(
+    reader: &mut impl BufRead,
+    device: &mut Device,
+    resolver: &dyn Resolver,
+    replaced: &mut Option<ReplacedPeers>,
+    obfuscation: &mut Obfuscation,
+) -> i32 {
+    let mut cmd = String::new();
 
-                    let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
-
-                    match key {
-                        "private_key" => match val.parse::<KeyBytes>() {
-                            Ok(key_bytes) => {
-                                device.set_key(x25519::StaticSecret::from(key_bytes.0))
-                            }
-                            Err(_) => return EINVAL,
-                        },
-                        "listen_port" => match val.parse::<u16>() {
-                            Ok(port) => match device.open_listen_socket(port) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        #[cfg(any(
+    loop {
+        if reader.read_line(&mut cmd).is_err() {
+            return EIO;
+        }
+        let end = cmd.pop(); // remove newline if any
+        if let Some(end) = end {
+            if end != '\n' {
+                return EPROTO;
+            }
+        }
+        if cmd.is_empty() {
+            return 0; // Done
+        }
+        {
+            let parsed_cmd: Vec<&str> = cmd.split('=').collect();
+            if parsed_cmd.len() != 2 {
+                return EPROTO;
+            }
+
+            let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
+
+            match key {
+                "private_key" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => device.set_key(x25519::StaticSecret::from(key_bytes.0)),
+                    Err(_) => return EINVAL,
+                },
+                "listen_port" => match val.parse::<u16>() {
+                    Ok(port) => match device.open_listen_socket(port) {
+                        Ok(()) => {}
+                        Err(_) => return EADDRINUSE,
+                    },
+                    Err(_) => return EINVAL,
+                },
+                "fwmark" => match val.parse::<u32>() {
+                    Ok(mark) => match device.set_fwmark(mark) {
+                        Ok(()) => {}
+                        #[cfg(not(any(
                             target_os = "android",
                             target_os = "fuchsia",
                             target_os = "linux"
-                        ))]
-                        "fwmark" => match val.parse::<u32>() {
-                            Ok(mark) => match device.set_fwmark(mark) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        "replace_peers" => match val.parse::<bool>() {
-                            Ok(true) => device.clear_peers(),
-                            Ok(false) => {}
-                            Err(_) => return EINVAL,
-                        },
-                        "public_key" => match val.parse::<KeyBytes>() {
-                            // Indicates a new peer section
-                            Ok(key_bytes) => {
-                                return api_set_peer(
-                                    reader,
-                                    device,
-                                    x25519::PublicKey::from(key_bytes.0),
-                                )
-                            }
-                            Err(_) => return EINVAL,
-                        },
-                        _ => return EINVAL,
+                        )))]
+                        Err(Error::FwmarkUnsupported) => return EOPNOTSUPP,
+                        Err(_) => return EADDRINUSE,
+                    },
+                    Err(_) => return EINVAL,
+                },
+                "jc" | "jmin" | "jmax" | "s1" | "s2" | "h1" | "h2" | "h3" | "h4" => {
+                    if set_obfuscation_key(obfuscation, key, val).is_err() {
+                        return EINVAL;
                     }
                 }
-                cmd.clear();
+                "replace_peers" => match val.parse::<bool>() {
+                    Ok(true) => {
+                        let previous = device.begin_replace_peers();
+                        match replaced {
+                            // The peers added since the first one are set aside too
+                            Some(replaced) => replaced.0.extend(previous.0),
+                            None => *replaced = Some(previous),
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(_) => return EINVAL,
+                },
+                "public_key" => match val.parse::<KeyBytes>() {
+                    // Indicates a new peer section
+                    Ok(key_bytes) => {
+                        return api_set_peer(
+                            reader,
+                            device,
+                            resolver,
+                            x25519::PublicKey::from(key_bytes.0),
+                            replaced.as_mut(),
+                        )
+                    }
+                    Err(_) => return EINVAL,
+                },
+                _ => match find_extension(&device.uapi_extensions, key) {
+                    Some(ext) => match ext.handler.set_device(key, val) {
+                        Ok(()) => {}
+                        Err(errno) => return errno,
+                    },
+                    None => return EINVAL,
+                },
             }
+        }
+        cmd.clear();
+    }
+}
 
-            0
-        },
-    )
-    .unwrap_or(EIO)
+/// Set the obfuscation parameter of one of the keys of AmneziaWG
+fn set_obfuscation_key(
+    obfuscation: &mut Obfuscation,
+    key: &str,
+    val: &str,
+) -> Result<(), std::num::ParseIntError> {
+    match key {
+        "jc" => obfuscation.junk_packet_count = val.parse()?,
+        "jmin" => obfuscation.junk_packet_min_size = val.parse()?,
+        "jmax" => obfuscation.junk_packet_max_size = val.parse()?,
+        "s1" => obfuscation.init_packet_junk_size = val.parse()?,
+        "s2" => obfuscation.response_packet_junk_size = val.parse()?,
+        "h1" => obfuscation.message_types[0] = val.parse()?,
+        "h2" => obfuscation.message_types[1] = val.parse()?,
+        "h3" => obfuscation.message_types[2] = val.parse()?,
+        "h4" => obfuscation.message_types[3] = val.parse()?,
+        _ => unreachable!("Not an obfuscation key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of an `endpoint` key, a comma separated list of endpoints to fail over
+/// between, see [`resolve_endpoints`]
+fn parse_endpoint(
+    resolver: &dyn Resolver,
+    prefer_ipv4: bool,
+    val: &str,
+) -> Result<(Vec<SocketAddr>, Option<String>), i32> {
+    resolve_endpoints(resolver, prefer_ipv4, val).map_err(|e| match e.kind() {
+        std::io::ErrorKind::InvalidInput => EINVAL,
+        _ => {
+            tracing::warn!(message = "Failed to resolve endpoint", host = val, error = ?e);
+            EADDRNOTAVAIL
+        }
+    })
+}
+
+/// The attributes of a peer, collected from its section of a `set` operation
+struct PeerSection {
+    public_key: x25519::PublicKey,
+    remove: bool,
+    replace_ips: bool,
+    endpoint: Option<SocketAddr>,
+    endpoint_host: Option<String>,
+    failover_endpoints: Vec<SocketAddr>,
+    keepalive: Option<u16>,
+    preshared_key: Option<[u8; 32]>,
+    allowed_ips: Vec<AllowedIP>,
+    tx_rate: Option<u64>,
+    tx_burst: Option<u64>,
+    #[cfg(feature = "mdns")]
+    mdns_discovery: Option<bool>,
+    pcap_capture: Option<bool>,
+}
+
+impl PeerSection {
+    fn new(public_key: x25519::PublicKey) -> PeerSection {
+        PeerSection {
+            public_key,
+            remove: false,
+            replace_ips: false,
+            endpoint: None,
+            endpoint_host: None,
+            failover_endpoints: vec![],
+            keepalive: None,
+            preshared_key: None,
+            allowed_ips: vec![],
+            tx_rate: None,
+            tx_burst: None,
+            #[cfg(feature = "mdns")]
+            mdns_discovery: None,
+            pcap_capture: None,
+        }
+    }
+
+    /// True if the section only has boringtun specific keys
+    fn only_extensions(&self) -> bool {
+        !self.remove
+            && !self.replace_ips
+            && self.endpoint.is_none()
+            && self.keepalive.is_none()
+            && self.preshared_key.is_none()
+            && self.allowed_ips.is_empty()
+    }
+
+    /// Apply the section to the device, returns an errno value on failure. A peer that was set
+    /// aside by `replace_peers` is added back with its sessions, unless its preshared key changed.
+    fn commit(self, d: &mut Device, replaced: Option<&mut ReplacedPeers>) -> Result<(), i32> {
+        let errno = |e| {
+            tracing::warn!(message = "Failed to set a peer", error = %e);
+            match e {
+                Error::LimitExceeded(_) => E2BIG,
+                Error::TooManyPeers(_) => ENOMEM,
+                Error::OverlappingAllowedIps(_) => EEXIST,
+                _ => EINVAL,
+            }
+        };
+
+        if !self.remove {
+            d.check_allowed_ips_overlap(&self.public_key, &self.allowed_ips)
+                .map_err(errno)?;
+        }
+
+        let kept = replaced
+            .filter(|_| !self.remove)
+            .and_then(|r| r.take_unchanged(&self.public_key, self.preshared_key));
+        if let Some(peer) = kept {
+            d.restore_peer(
+                self.public_key,
+                peer,
+                self.endpoint,
+                self.endpoint_host.as_deref(),
+                self.allowed_ips.as_slice(),
+                self.keepalive,
+            )
+            .map_err(errno)?;
+        } else if !(self.only_extensions() && d.peers.contains_key(&self.public_key)) {
+            d.update_peer(
+                self.public_key,
+                self.remove,
+                self.replace_ips,
+                self.endpoint,
+                self.endpoint_host.as_deref(),
+                self.allowed_ips.as_slice(),
+                self.keepalive,
+                self.preshared_key,
+            )
+            .map_err(errno)?;
+        }
+
+        let peer = match d.peers.get(&self.public_key) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let mut peer = peer.lock();
+        if self.endpoint.is_some() {
+            peer.set_failover_endpoints(self.failover_endpoints);
+        }
+
+        if self.tx_rate.is_some() || self.tx_burst.is_some() {
+            let current = peer.bandwidth_limit();
+            let bytes_per_sec = self
+                .tx_rate
+                .or_else(|| current.map(|l| l.bytes_per_sec))
+                .unwrap_or(0);
+            let burst = self
+                .tx_burst
+                .or_else(|| current.map(|l| l.burst))
+                .unwrap_or(bytes_per_sec);
+            peer.set_bandwidth_limit(Some(BandwidthLimit {
+                bytes_per_sec,
+                burst,
+            }));
+        }
+        #[cfg(feature = "mdns")]
+        if let Some(mdns_discovery) = self.mdns_discovery {
+            peer.set_mdns_discovery(mdns_discovery);
+            d.follow_mdns_endpoint(&mut peer, d.mdns_endpoint(&self.public_key));
+        }
+        if let Some(pcap_capture) = self.pcap_capture {
+            peer.set_pcap_capture(pcap_capture);
+        }
+        d.schedule_timers(&mut peer);
+        Ok(())
+    }
 }
 
 fn api_set_peer(
-    reader: &mut BufReader<&UnixStream>,
+    reader: &mut impl BufRead,
     d: &mut Device,
+    resolver: &dyn Resolver,
     pub_key: x25519::PublicKey,
+    mut replaced: Option<&mut ReplacedPeers>,
 ) -> i32 {
     let mut cmd = String::new();
 
-    let mut remove = false;
-    let mut replace_ips = false;
-    let mut endpoint = None;
-    let mut keepalive = None;
-    let mut public_key = pub_key;
-    let mut preshared_key = None;
-    let mut allowed_ips: Vec<AllowedIP> = vec![];
-    while reader.read_line(&mut cmd).is_ok() {
-        cmd.pop(); // remove newline if any
+    let mut section = PeerSection::new(pub_key);
+    loop {
+        if reader.read_line(&mut cmd).is_err() {
+            return EIO;
+        }
+        let end = cmd.pop(); // remove newline if any
+        if let Some(end) = end {
+            if end != '\n' {
+                return EPROTO;
+            }
+        }
         if cmd.is_empty() {
-            d.update_peer(
-                public_key,
-                remove,
-                replace_ips,
-                endpoint,
-                allowed_ips.as_slice(),
-                keepalive,
-                preshared_key,
-            );
-            allowed_ips.clear(); //clear the vector content after update
-            return 0; // Done
+            return match section.commit(d, replaced) {
+                Ok(()) => 0, // Done
+                Err(errno) => errno,
+            };
         }
         {
             let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
@@ -318,56 +962,265 @@ fn api_set_peer(
             let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
             match key {
                 "remove" => match val.parse::<bool>() {
-                    Ok(true) => remove = true,
-                    Ok(false) => remove = false,
+                    Ok(remove) => section.remove = remove,
                     Err(_) => return EINVAL,
                 },
                 "preshared_key" => match val.parse::<KeyBytes>() {
-                    Ok(key_bytes) => preshared_key = Some(key_bytes.0),
+                    Ok(key_bytes) => section.preshared_key = Some(key_bytes.0),
                     Err(_) => return EINVAL,
                 },
-                "endpoint" => match val.parse::<SocketAddr>() {
-                    Ok(addr) => endpoint = Some(addr),
-                    Err(_) => return EINVAL,
+                "endpoint" => match parse_endpoint(resolver, d.prefers_ipv4(), val) {
+                    Ok((addrs, host)) => {
+                        section.endpoint = addrs.first().copied();
+                        section.endpoint_host = host;
+                        section.failover_endpoints = addrs;
+                    }
+                    Err(errno) => return errno,
                 },
                 "persistent_keepalive_interval" => match val.parse::<u16>() {
-                    Ok(interval) => keepalive = Some(interval),
+                    Ok(interval) => section.keepalive = Some(interval),
                     Err(_) => return EINVAL,
                 },
                 "replace_allowed_ips" => match val.parse::<bool>() {
-                    Ok(true) => replace_ips = true,
-                    Ok(false) => replace_ips = false,
+                    Ok(replace_ips) => section.replace_ips = replace_ips,
+                    Err(_) => return EINVAL,
+                },
+                "allowed_ip" => match AllowedIP::parse_address(val) {
+                    // Don't buffer an unbounded number of entries before the peer is committed
+                    Ok(_)
+                        if d.config
+                            .limits
+                            .max_allowed_ips_per_peer
+                            .is_some_and(|max| section.allowed_ips.len() >= max) =>
+                    {
+                        return E2BIG
+                    }
+                    Ok(ip) => section.allowed_ips.push(ip),
                     Err(_) => return EINVAL,
                 },
-                "allowed_ip" => match val.parse::<AllowedIP>() {
-                    Ok(ip) => allowed_ips.push(ip),
+                // Bytes per second of traffic sent to the peer, 0 disables the limit
+                "bt_tx_rate" => match val.parse::<u64>() {
+                    Ok(rate) => section.tx_rate = Some(rate),
+                    Err(_) => return EINVAL,
+                },
+                // The burst size of the limit, one second worth of traffic by default
+                "bt_tx_burst" => match val.parse::<u64>() {
+                    Ok(burst) => section.tx_burst = Some(burst),
+                    Err(_) => return EINVAL,
+                },
+                // Follow the endpoint the peer advertises over mDNS
+                #[cfg(feature = "mdns")]
+                "bt_mdns_discovery" => match val.parse::<bool>() {
+                    Ok(mdns_discovery) => section.mdns_discovery = Some(mdns_discovery),
+                    Err(_) => return EINVAL,
+                },
+                // Leave the packets of the peer out of the capture of the device
+                "bt_pcap_capture" => match val.parse::<bool>() {
+                    Ok(pcap_capture) => section.pcap_capture = Some(pcap_capture),
                     Err(_) => return EINVAL,
                 },
                 "public_key" => {
                     // Indicates a new peer section. Commit changes for current peer, and continue to next peer
-                    d.update_peer(
-                        public_key,
-                        remove,
-                        replace_ips,
-                        endpoint,
-                        allowed_ips.as_slice(),
-                        keepalive,
-                        preshared_key,
-                    );
-                    allowed_ips.clear(); //clear the vector content after update
-                    match val.parse::<KeyBytes>() {
-                        Ok(key_bytes) => public_key = key_bytes.0.into(),
+                    let public_key = match val.parse::<KeyBytes>() {
+                        Ok(key_bytes) => key_bytes.0.into(),
                         Err(_) => return EINVAL,
+                    };
+                    let previous = std::mem::replace(&mut section, PeerSection::new(public_key));
+                    if let Err(errno) = previous.commit(d, replaced.as_deref_mut()) {
+                        return errno;
                     }
                 }
                 "protocol_version" => match val.parse::<u32>() {
                     Ok(1) => {} // Only version 1 is legal
                     _ => return EINVAL,
                 },
-                _ => return EINVAL,
+                _ => match find_extension(&d.uapi_extensions, key) {
+                    Some(ext) => match ext.handler.set_peer(&section.public_key, key, val) {
+                        Ok(()) => {}
+                        Err(errno) => return errno,
+                    },
+                    None => return EINVAL,
+                },
             }
         }
         cmd.clear();
     }
-    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ext_lines(prefix: &str, f: impl FnOnce(&mut UapiExtWriter)) -> String {
+        let mut buf = vec![];
+        f(&mut UapiExtWriter::new(prefix, &mut buf));
+        String::from_utf8(buf).unwrap()
+    }
+
+    struct FixedResolver(Vec<SocketAddr>);
+
+    impl Resolver for FixedResolver {
+        fn resolve(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+            assert_eq!(host, "peer.example.com:51820");
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let resolver = FixedResolver(vec![v6, v4]);
+
+        assert_eq!(
+            parse_endpoint(&resolver, true, "192.0.2.7:1234"),
+            Ok((vec!["192.0.2.7:1234".parse().unwrap()], None))
+        );
+        assert_eq!(
+            parse_endpoint(&resolver, true, "[2001:db8::7]:1234"),
+            Ok((vec!["[2001:db8::7]:1234".parse().unwrap()], None))
+        );
+        assert_eq!(
+            parse_endpoint(&resolver, true, "192.0.2.7:1234,[2001:db8::7]:1234"),
+            Ok((
+                vec![
+                    "192.0.2.7:1234".parse().unwrap(),
+                    "[2001:db8::7]:1234".parse().unwrap()
+                ],
+                None
+            ))
+        );
+
+        let host = Some("peer.example.com:51820".to_owned());
+        assert_eq!(
+            parse_endpoint(&resolver, true, "peer.example.com:51820"),
+            Ok((vec![v4], host.clone()))
+        );
+        assert_eq!(
+            parse_endpoint(&resolver, false, "peer.example.com:51820"),
+            Ok((vec![v6], host))
+        );
+
+        assert_eq!(
+            parse_endpoint(&resolver, true, "peer.example.com"),
+            Err(EINVAL)
+        );
+        assert_eq!(
+            parse_endpoint(&resolver, true, "peer.example.com:x"),
+            Err(EINVAL)
+        );
+        assert_eq!(parse_endpoint(&resolver, true, ":51820"), Err(EINVAL));
+        assert_eq!(
+            parse_endpoint(&resolver, true, "2001:db8::1:51820"),
+            Err(EINVAL)
+        );
+        assert_eq!(
+            parse_endpoint(&resolver, true, "[2001:db8::1]"),
+            Err(EINVAL)
+        );
+        assert_eq!(
+            parse_endpoint(&FixedResolver(vec![]), true, "peer.example.com:51820"),
+            Err(EADDRNOTAVAIL)
+        );
+    }
+
+    #[test]
+    fn test_uapi_error() {
+        assert_eq!(UapiError::parse_line("errno=0\n"), Some(Ok(())));
+        assert_eq!(
+            UapiError::parse_line("errno=22"),
+            Some(Err(UapiError(EINVAL)))
+        );
+        assert_eq!(
+            UapiError::parse_line("errno=x"),
+            Some(Err(UapiError(EPROTO)))
+        );
+        assert_eq!(UapiError::parse_line("listen_port=51820\n"), None);
+        assert_eq!(UapiError::check(0), Ok(()));
+        assert_eq!(UapiError(EINVAL).to_string(), "Invalid argument (errno 22)");
+    }
+
+    #[test]
+    fn test_read_set_request_size() {
+        let request = "listen_port=51820\nfwmark=1\n\nget=1\n";
+        let mut reader = request.as_bytes();
+        assert_eq!(
+            read_set_request(&mut reader),
+            Ok("listen_port=51820\nfwmark=1\n\n".to_owned())
+        );
+        assert_eq!(reader, b"get=1\n");
+
+        // The rest of a request that is too large is skipped, lines that are too long included
+        let long_line = format!("description={}\n", "x".repeat(MAX_SET_REQUEST_SIZE));
+        let short_lines = "fwmark=1\n".repeat(MAX_SET_REQUEST_SIZE / 8);
+        for request in [long_line, short_lines] {
+            let request = format!("{}fwmark=2\n\nget=1\n", request);
+            let mut reader = std::io::BufReader::with_capacity(1000, request.as_bytes());
+            assert_eq!(read_set_request(&mut reader), Err(E2BIG));
+            let mut next = String::new();
+            reader.read_line(&mut next).unwrap();
+            assert_eq!(next, "get=1\n");
+        }
+    }
+
+    #[test]
+    fn test_uapi_ext_writer_framing() {
+        let out = ext_lines("bt_", |w| {
+            assert!(w.write("bt_drops", 5));
+            assert!(!w.write("drops", 5)); // Wrong prefix
+            assert!(!w.write("bt_x=y", 1)); // Would split the key
+            assert!(!w.write("bt_x\n", 1));
+            assert!(!w.write("bt_x", "a\n\nerrno=0")); // Would inject a fake terminator
+            assert!(!w.write("bt_x", "")); // Would look like the end of the response
+        });
+        assert_eq!(out, "bt_drops=5\n");
+    }
+
+    #[test]
+    fn test_uapi_ext_prefix_lookup() {
+        let extensions = vec![
+            UapiExtension {
+                prefix: "bt_label".to_owned(),
+                handler: Box::<PeerLabels>::default(),
+            },
+            UapiExtension {
+                prefix: "bt_".to_owned(),
+                handler: Box::<PeerLabels>::default(),
+            },
+        ];
+
+        assert!(std::ptr::eq(
+            find_extension(&extensions, "bt_label").unwrap(),
+            &extensions[0]
+        ));
+        assert!(std::ptr::eq(
+            find_extension(&extensions, "bt_other").unwrap(),
+            &extensions[1]
+        ));
+        assert!(find_extension(&extensions, "label").is_none());
+    }
+
+    #[test]
+    fn test_peer_labels() {
+        let labels = PeerLabels::default();
+        let key = x25519::PublicKey::from([1u8; 32]);
+
+        assert_eq!(labels.set_device(PeerLabels::PREFIX, "x"), Err(EINVAL));
+        assert_eq!(labels.set_peer(&key, "bt_label_x", "x"), Err(EINVAL));
+        assert_eq!(
+            labels.set_peer(&key, PeerLabels::PREFIX, "office router"),
+            Ok(())
+        );
+        assert_eq!(labels.label(&key).as_deref(), Some("office router"));
+
+        let out = ext_lines(PeerLabels::PREFIX, |w| labels.get_peer(&key, w));
+        assert_eq!(out, "bt_label=office router\n");
+
+        assert_eq!(labels.set_peer(&key, PeerLabels::PREFIX, ""), Ok(()));
+        assert_eq!(labels.label(&key), None);
+        assert_eq!(
+            ext_lines(PeerLabels::PREFIX, |w| labels.get_peer(&key, w)),
+            ""
+        );
+    }
 }
@@ -0,0 +1,242 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A [`Tun`] that exchanges packets with the application over in-memory queues, for userspace
+//! network stacks and tests.
+
+use crate::device::iface::Tun;
+#[cfg(windows)]
+use crate::device::poll::{AsRawFd, RawFd};
+use crate::device::Error;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc};
+
+/// The packets sent to the device, along with what wakes up the event loop. It is ready exactly
+/// when the queue is not empty, which is maintained under the lock of the queue.
+struct Inbound {
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    ready: Ready,
+}
+
+/// A socket pair, that holds a single byte when it is ready
+#[cfg(unix)]
+struct Ready {
+    rx: UnixStream,
+    tx: UnixStream,
+}
+
+#[cfg(unix)]
+impl Ready {
+    fn new() -> io::Result<Ready> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        Ok(Ready { rx, tx })
+    }
+
+    fn set(&self) {
+        let _ = (&self.tx).write(&[1]);
+    }
+
+    fn clear(&self) {
+        let _ = (&self.rx).read(&mut [0u8]);
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.rx.as_raw_fd()
+    }
+}
+
+/// A manual reset event, that is set when it is ready
+#[cfg(windows)]
+struct Ready(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl Ready {
+    fn new() -> io::Result<Ready> {
+        use windows_sys::Win32::System::Threading::CreateEventW;
+
+        match unsafe { CreateEventW(std::ptr::null(), 1, 0, std::ptr::null()) } {
+            0 => Err(io::Error::last_os_error()),
+            event => Ok(Ready(event)),
+        }
+    }
+
+    fn set(&self) {
+        unsafe { windows_sys::Win32::System::Threading::SetEvent(self.0) };
+    }
+
+    fn clear(&self) {
+        unsafe { windows_sys::Win32::System::Threading::ResetEvent(self.0) };
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Handle(self.0)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Ready {
+    fn drop(&mut self) {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.0) };
+    }
+}
+
+/// The device side of an in-memory TUN device, created with [`ChannelTun::new`]
+pub struct ChannelTun {
+    name: String,
+    mtu: usize,
+    inbound: Arc<Inbound>,
+    outbound: mpsc::Sender<Vec<u8>>,
+}
+
+/// The application side of a [`ChannelTun`]
+pub struct ChannelTunHandle {
+    inbound: Arc<Inbound>,
+    outbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ChannelTun {
+    /// Create a TUN device called `name`, that reads packets of up to `mtu` bytes
+    pub fn new(name: &str, mtu: usize) -> io::Result<(ChannelTun, ChannelTunHandle)> {
+        let inbound = Arc::new(Inbound {
+            queue: Default::default(),
+            ready: Ready::new()?,
+        });
+        let (sender, receiver) = mpsc::channel();
+
+        let tun = ChannelTun {
+            name: name.to_owned(),
+            mtu,
+            inbound: Arc::clone(&inbound),
+            outbound: sender,
+        };
+        let handle = ChannelTunHandle {
+            inbound,
+            outbound: receiver,
+        };
+        Ok((tun, handle))
+    }
+
+    fn write(&self, src: &[u8]) -> usize {
+        match self.outbound.send(src.to_vec()) {
+            Ok(()) => src.len(),
+            Err(_) => 0,
+        }
+    }
+}
+
+impl AsRawFd for ChannelTun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inbound.ready.as_raw_fd()
+    }
+}
+
+impl Tun for ChannelTun {
+    fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        let mut queue = self.inbound.queue.lock();
+        let packet = match queue.pop_front() {
+            Some(packet) => packet,
+            None => return Err(Error::IfaceRead(io::ErrorKind::WouldBlock.into())),
+        };
+        if queue.is_empty() {
+            self.inbound.ready.clear();
+        }
+
+        // Longer packets are truncated, like a read from a TUN device into a short buffer
+        let len = packet.len().min(dst.len());
+        dst[..len].copy_from_slice(&packet[..len]);
+        Ok(&mut dst[..len])
+    }
+
+    fn write4(&self, src: &[u8]) -> usize {
+        self.write(src)
+    }
+
+    fn write6(&self, src: &[u8]) -> usize {
+        self.write(src)
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        Ok(self.mtu)
+    }
+
+    fn name(&self) -> Result<String, Error> {
+        Ok(self.name.clone())
+    }
+}
+
+impl ChannelTunHandle {
+    /// Send an IP packet to the device, as if it was read from a TUN device
+    pub fn send(&self, packet: Vec<u8>) {
+        let mut queue = self.inbound.queue.lock();
+        if queue.is_empty() {
+            self.inbound.ready.set();
+        }
+        queue.push_back(packet);
+    }
+
+    /// The IP packets the device wrote to the TUN device, in order
+    pub fn receiver(&self) -> &mpsc::Receiver<Vec<u8>> {
+        &self.outbound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn is_readable(tun: &ChannelTun) -> bool {
+        let mut fd = libc::pollfd {
+            fd: tun.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fd, 1, 0) == 1 }
+    }
+
+    #[cfg(windows)]
+    fn is_readable(tun: &ChannelTun) -> bool {
+        use windows_sys::Win32::Foundation::WAIT_OBJECT_0;
+        use windows_sys::Win32::System::Threading::WaitForSingleObject;
+
+        match tun.as_raw_fd() {
+            RawFd::Handle(event) => unsafe { WaitForSingleObject(event, 0) == WAIT_OBJECT_0 },
+            RawFd::Socket(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_channel_tun() {
+        let (tun, handle) = ChannelTun::new("chan0", 4).unwrap();
+        assert!(!is_readable(&tun));
+
+        handle.send(vec![1, 2]);
+        handle.send(vec![3, 4, 5, 6, 7]);
+        assert!(is_readable(&tun));
+
+        let mut buf = [0u8; 4];
+        assert_eq!(tun.read(&mut buf).unwrap(), &[1, 2]);
+        assert!(is_readable(&tun));
+        assert_eq!(tun.read(&mut buf).unwrap(), &[3, 4, 5, 6]);
+        assert!(!is_readable(&tun));
+        assert!(matches!(
+            tun.read(&mut buf),
+            Err(Error::IfaceRead(e)) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        assert_eq!(tun.write4(&[8, 9]), 2);
+        assert_eq!(handle.receiver().try_recv(), Ok(vec![8, 9]));
+        drop(handle);
+        assert_eq!(tun.write6(&[8, 9]), 0);
+    }
+}
@@ -0,0 +1,620 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The configuration file format of `wg setconf` and `wg showconf`, and the files of `wg-quick`,
+//! whose `Address`, `MTU` and hooks are read by [`parse_wg_config`], and whose other additional
+//! keys such as `DNS` are ignored.
+
+use super::peer::AllowedIP;
+use super::shaper::BandwidthLimit;
+use super::Error;
+use crate::noise::ReconnectPolicy;
+use crate::serialization::KeyBytes;
+use crate::x25519;
+use std::fs::File;
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The full configuration of a device, as accepted by [`super::DeviceHandle::apply_config`]
+#[derive(Default, Clone)]
+pub struct WgConfig {
+    pub private_key: Option<x25519::StaticSecret>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub peers: Vec<PeerConfig>,
+    /// The `Address` entries of a `wg-quick` file, for the interface. Only [`parse_wg_config`]
+    /// reads them, and [`super::Device::apply_config`] leaves them to whoever creates the
+    /// interface, see [`super::DeviceConfig::address`].
+    pub address: Vec<AllowedIP>,
+    /// The `MTU` of a `wg-quick` file, of the interface itself rather than of the network
+    /// towards the peers like [`super::DeviceConfig::mtu`]. Read like `address`.
+    pub mtu: Option<u16>,
+    /// The `PreUp`, `PostUp`, `PreDown` and `PostDown` commands of a `wg-quick` file, in the
+    /// order of the file, with `%i` left for the name of the interface. Read like `address`, and
+    /// never run by the device itself.
+    pub hooks: Hooks,
+}
+
+/// The shell commands `wg-quick` runs around the life of an interface, see [`WgConfig::hooks`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Hooks {
+    /// Before the interface is created
+    pub pre_up: Vec<String>,
+    /// Once the interface is configured
+    pub post_up: Vec<String>,
+    /// Before the interface is removed
+    pub pre_down: Vec<String>,
+    /// Once the interface is gone
+    pub post_down: Vec<String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_up.is_empty()
+            && self.post_up.is_empty()
+            && self.pre_down.is_empty()
+            && self.post_down.is_empty()
+    }
+}
+
+impl WgConfig {
+    /// Read a configuration file in the `wg setconf` format, or a `wg-quick` one, see
+    /// [`parse_wg_config`]
+    pub fn from_file(path: &Path) -> Result<WgConfig, Error> {
+        let err = |e: String| Error::InvalidConfig(format!("{}: {}", path.display(), e));
+        let text = std::fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+        parse(&text, false).map_err(err)
+    }
+}
+
+/// Parse a configuration in the format of `wg setconf`, like [`WgConfig::from_str`], except that
+/// unknown keys are logged and ignored rather than rejected. The files of `wg-quick` can be read
+/// this way, with their `Address`, `MTU` and hook keys, without the other keys only it knows.
+pub fn parse_wg_config(s: &str) -> Result<WgConfig, Error> {
+    parse(s, false).map_err(Error::InvalidConfig)
+}
+
+/// The configuration of a single peer, as found in a `[Peer]` section
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: x25519::PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    /// Either a literal socket address or a host name, in the `host:port` form
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<AllowedIP>,
+    pub persistent_keepalive: Option<u16>,
+    /// Not part of the `wg` configuration format, it is always `None` when parsed
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    /// Not part of the `wg` configuration format, it is always the default when parsed
+    pub reconnect_policy: ReconnectPolicy,
+    /// Follow the endpoint the peer advertises on the local network when the device browses
+    /// with mDNS, see [`super::mdns`]. Not part of the `wg` configuration format, it is always
+    /// `false` when parsed.
+    #[cfg(feature = "mdns")]
+    pub mdns_discovery: bool,
+    /// Whether the packets of the peer are captured, when the device captures packets with
+    /// [`super::DeviceConfig::pcap_path`]. Set it to `false` on the other peers to capture the
+    /// packets of some peers only. Not part of the `wg` configuration format, it is always `true`
+    /// when parsed.
+    pub pcap_capture: bool,
+}
+
+impl PeerConfig {
+    pub fn new(public_key: x25519::PublicKey) -> PeerConfig {
+        PeerConfig {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            allowed_ips: vec![],
+            persistent_keepalive: None,
+            bandwidth_limit: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            #[cfg(feature = "mdns")]
+            mdns_discovery: false,
+            pcap_capture: true,
+        }
+    }
+}
+
+/// The changes to a peer that is in both configurations of a [`ConfigDiff`]. Attributes that
+/// are `None` didn't change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerChanges {
+    pub preshared_key: Option<Option<[u8; 32]>>,
+    /// An endpoint can't be removed, as with `wg syncconf`, so this is only the new one
+    pub endpoint: Option<String>,
+    pub allowed_ips: Option<Vec<AllowedIP>>,
+    pub persistent_keepalive: Option<Option<u16>>,
+    pub bandwidth_limit: Option<Option<BandwidthLimit>>,
+    pub reconnect_policy: Option<ReconnectPolicy>,
+    #[cfg(feature = "mdns")]
+    pub mdns_discovery: Option<bool>,
+    pub pcap_capture: Option<bool>,
+}
+
+impl PeerChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == PeerChanges::default()
+    }
+}
+
+impl From<PeerConfig> for PeerChanges {
+    /// Set every attribute of a peer to its value in `config`
+    fn from(config: PeerConfig) -> PeerChanges {
+        PeerChanges {
+            preshared_key: Some(config.preshared_key),
+            endpoint: config.endpoint,
+            allowed_ips: Some(config.allowed_ips),
+            persistent_keepalive: Some(config.persistent_keepalive),
+            bandwidth_limit: Some(config.bandwidth_limit),
+            reconnect_policy: Some(config.reconnect_policy),
+            #[cfg(feature = "mdns")]
+            mdns_discovery: Some(config.mdns_discovery),
+            pcap_capture: Some(config.pcap_capture),
+        }
+    }
+}
+
+/// The difference between the peers of two configurations, see [`diff`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added: Vec<PeerConfig>,
+    pub removed: Vec<x25519::PublicKey>,
+    pub modified: Vec<(x25519::PublicKey, PeerChanges)>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Compare the peers of two configurations, for [`super::DeviceHandle::apply_diff`] to only touch
+/// the peers that changed. The order of the peers and of their allowed IPs doesn't matter, and
+/// the settings of the interface are not compared.
+pub fn diff(old: &WgConfig, new: &WgConfig) -> ConfigDiff {
+    fn changed<T: PartialEq>(old: T, new: T) -> Option<T> {
+        Some(new).filter(|new| *new != old)
+    }
+    let find = |config: &WgConfig, key: &x25519::PublicKey| {
+        config.peers.iter().find(|p| p.public_key == *key).cloned()
+    };
+    let sorted = |ips: &[AllowedIP]| {
+        let mut ips = ips.to_vec();
+        ips.sort();
+        ips
+    };
+
+    let mut diff = ConfigDiff {
+        removed: old
+            .peers
+            .iter()
+            .map(|p| p.public_key)
+            .filter(|key| find(new, key).is_none())
+            .collect(),
+        ..Default::default()
+    };
+
+    for new_peer in &new.peers {
+        let old_peer = match find(old, &new_peer.public_key) {
+            Some(peer) => peer,
+            None => {
+                diff.added.push(new_peer.clone());
+                continue;
+            }
+        };
+
+        let changes = PeerChanges {
+            preshared_key: changed(old_peer.preshared_key, new_peer.preshared_key),
+            endpoint: new_peer
+                .endpoint
+                .clone()
+                .filter(|endpoint| old_peer.endpoint.as_ref() != Some(endpoint)),
+            allowed_ips: Some(new_peer.allowed_ips.clone())
+                .filter(|ips| sorted(ips) != sorted(&old_peer.allowed_ips)),
+            persistent_keepalive: changed(
+                old_peer.persistent_keepalive,
+                new_peer.persistent_keepalive,
+            ),
+            bandwidth_limit: changed(old_peer.bandwidth_limit, new_peer.bandwidth_limit),
+            reconnect_policy: changed(old_peer.reconnect_policy, new_peer.reconnect_policy),
+            #[cfg(feature = "mdns")]
+            mdns_discovery: changed(old_peer.mdns_discovery, new_peer.mdns_discovery),
+            pcap_capture: changed(old_peer.pcap_capture, new_peer.pcap_capture),
+        };
+        if !changes.is_empty() {
+            diff.modified.push((new_peer.public_key, changes));
+        }
+    }
+
+    diff
+}
+
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+/// Read a private key from a file holding it in base64 or hex, like the one given to
+/// `wg set private-key`.
+///
+/// Unless `check_permissions` is false, the file must be owned by the effective user of the
+/// process and must not be accessible by anyone else, i.e. have no permissions wider than `0600`.
+/// The ACLs of the file are not checked on Windows.
+pub fn read_private_key_file(
+    path: &Path,
+    check_permissions: bool,
+) -> Result<x25519::StaticSecret, Error> {
+    let err = |e: String| Error::InvalidConfig(format!("{}: {}", path.display(), e));
+
+    // Use the metadata of the opened file, in case it is replaced between the check and the read
+    let file = File::open(path).map_err(|e| err(e.to_string()))?;
+
+    #[cfg(unix)]
+    if check_permissions {
+        let metadata = file.metadata().map_err(|e| err(e.to_string()))?;
+        let euid = unsafe { libc::geteuid() };
+        if metadata.uid() != euid {
+            return Err(err(format!(
+                "owned by uid {}, expected {}",
+                metadata.uid(),
+                euid
+            )));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            return Err(err(format!(
+                "permissions {:04o} are too open, expected 0600 or stricter",
+                metadata.mode() & 0o7777
+            )));
+        }
+    }
+    #[cfg(windows)]
+    let _ = check_permissions;
+
+    read_key(file).map_err(err)
+}
+
+/// Read a private key in base64 or hex from `reader`, such as the standard input, where a secret
+/// manager passes it, until its end. The whitespace around the key is ignored.
+pub fn read_private_key(reader: impl Read) -> Result<x25519::StaticSecret, Error> {
+    read_key(reader).map_err(|e| Error::InvalidConfig(format!("private key: {}", e)))
+}
+
+fn read_key(mut reader: impl Read) -> Result<x25519::StaticSecret, String> {
+    let mut key = String::new();
+    reader.read_to_string(&mut key).map_err(|e| e.to_string())?;
+    parse_key(key.trim()).map(x25519::StaticSecret::from)
+}
+
+fn parse_key(val: &str) -> Result<[u8; 32], String> {
+    val.parse::<KeyBytes>()
+        .map(|k| k.0)
+        .map_err(|e| e.to_owned())
+}
+
+/// `off` is accepted in place of zero, like `wg` does
+fn parse_off_or<T: FromStr + Default>(val: &str) -> Result<T, String> {
+    if val.eq_ignore_ascii_case("off") {
+        return Ok(T::default());
+    }
+    val.parse::<T>()
+        .map_err(|_| format!("Invalid value {}", val))
+}
+
+fn parse_fwmark(val: &str) -> Result<u32, String> {
+    match val.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid fwmark {}", val)),
+        None => parse_off_or(val),
+    }
+}
+
+impl FromStr for WgConfig {
+    type Err = String;
+
+    /// Parse a configuration in the INI-like format used by `wg setconf`. Keys are case
+    /// insensitive, and everything after a `#` is a comment.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s, true)
+    }
+}
+
+/// Parse a configuration, unknown keys are rejected when `strict` and logged otherwise
+fn parse(s: &str, strict: bool) -> Result<WgConfig, String> {
+    let mut config = WgConfig::default();
+    let mut section = Section::None;
+
+    for (n, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |e: String| format!("Line {}: {}", n + 1, e);
+        let unknown_key = |key: &str| match strict {
+            true => Err(format!("Unknown key {}", key)),
+            false => {
+                tracing::warn!(message = "Ignoring an unknown key", line = n + 1, key);
+                Ok(())
+            }
+        };
+
+        if line.starts_with('[') {
+            section = match line.to_ascii_lowercase().as_str() {
+                "[interface]" => Section::Interface,
+                "[peer]" => Section::Peer,
+                _ => return Err(err(format!("Unknown section {}", line))),
+            };
+            continue;
+        }
+
+        let (key, val) = match line.split_once('=') {
+            Some((key, val)) => (key.trim().to_ascii_lowercase(), val.trim()),
+            None => return Err(err("Expected a key = value pair".to_owned())),
+        };
+
+        match section {
+            Section::None => return Err(err("Key outside of a section".to_owned())),
+            Section::Interface => match key.as_str() {
+                "privatekey" => {
+                    let key = parse_key(val).map_err(err)?;
+                    config.private_key = Some(x25519::StaticSecret::from(key));
+                }
+                "listenport" => config.listen_port = Some(parse_off_or(val).map_err(err)?),
+                "fwmark" => config.fwmark = Some(parse_fwmark(val).map_err(err)?),
+                "address" if !strict => {
+                    for ip in val.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                        let ip = AllowedIP::parse_address(ip).map_err(|e| err(e.to_string()))?;
+                        config.address.push(ip);
+                    }
+                }
+                "mtu" if !strict => {
+                    config.mtu = Some(
+                        val.parse()
+                            .map_err(|_| err(format!("Invalid MTU {}", val)))?,
+                    )
+                }
+                "preup" if !strict => config.hooks.pre_up.push(val.to_owned()),
+                "postup" if !strict => config.hooks.post_up.push(val.to_owned()),
+                "predown" if !strict => config.hooks.pre_down.push(val.to_owned()),
+                "postdown" if !strict => config.hooks.post_down.push(val.to_owned()),
+                _ => unknown_key(&key).map_err(err)?,
+            },
+            Section::Peer => {
+                if key == "publickey" {
+                    let key = parse_key(val).map_err(err)?;
+                    config.peers.push(PeerConfig::new(key.into()));
+                    continue;
+                }
+
+                let peer = match config.peers.last_mut() {
+                    Some(peer) => peer,
+                    None => return Err(err("The peer is missing a PublicKey".to_owned())),
+                };
+
+                match key.as_str() {
+                    "presharedkey" => peer.preshared_key = Some(parse_key(val).map_err(err)?),
+                    "endpoint" => peer.endpoint = Some(val.to_owned()),
+                    "allowedips" => {
+                        for ip in val.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                            let ip =
+                                AllowedIP::parse_address(ip).map_err(|e| err(e.to_string()))?;
+                            peer.allowed_ips.push(ip);
+                        }
+                    }
+                    "persistentkeepalive" => {
+                        peer.persistent_keepalive =
+                            Some(parse_off_or(val).map_err(err)?).filter(|&k| k != 0)
+                    }
+                    _ => unknown_key(&key).map_err(err)?,
+                }
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ListenPort = 51820
+FwMark = 0x1f
+
+# The first peer
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = 192.95.5.67:1234
+AllowedIPs = 10.192.122.3/32, 10.192.124.1/24
+
+[peer]
+publickey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+PresharedKey = /UwcSPg38hW/D9Y3tcS1FOV0K1wuURMbS0sesJEP5ak=
+Endpoint = peer.example.com:51820 # Resolved later
+AllowedIPs = 10.192.122.4/32,
+PersistentKeepalive = 25
+";
+
+    #[test]
+    fn test_parse_config() {
+        let config: WgConfig = CONFIG.parse().unwrap();
+
+        assert_eq!(
+            base64::encode(config.private_key.unwrap().to_bytes()),
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+        );
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.fwmark, Some(0x1f));
+        assert_eq!(config.peers.len(), 2);
+
+        let peer = &config.peers[0];
+        assert_eq!(
+            base64::encode(peer.public_key.as_bytes()),
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        );
+        assert_eq!(peer.endpoint.as_deref(), Some("192.95.5.67:1234"));
+        assert_eq!(
+            peer.allowed_ips,
+            vec![
+                "10.192.122.3/32".parse().unwrap(),
+                AllowedIP::parse_address("10.192.124.1/24").unwrap()
+            ]
+        );
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(peer.persistent_keepalive, None);
+
+        let peer = &config.peers[1];
+        assert_eq!(peer.endpoint.as_deref(), Some("peer.example.com:51820"));
+        assert_eq!(peer.allowed_ips.len(), 1);
+        assert!(peer.preshared_key.is_some());
+        assert_eq!(peer.persistent_keepalive, Some(25));
+    }
+
+    #[test]
+    fn test_parse_config_off() {
+        let config: WgConfig = "[Interface]\nFwMark = off\n[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nPersistentKeepalive = off"
+            .parse()
+            .unwrap();
+        assert_eq!(config.fwmark, Some(0));
+        assert_eq!(config.peers[0].persistent_keepalive, None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_private_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("boringtun-key-{}", std::process::id()));
+        std::fs::write(&path, "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let key = read_private_key_file(&path, true).unwrap();
+        assert_eq!(
+            base64::encode(key.to_bytes()),
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+        );
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            read_private_key_file(&path, true),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(read_private_key_file(&path, false).is_ok());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(read_private_key_file(&path, false).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+
+        let key =
+            read_private_key(&b"yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\r\n"[..]).unwrap();
+        assert_eq!(
+            base64::encode(key.to_bytes()),
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+        );
+        assert!(read_private_key(&b""[..]).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: WgConfig = CONFIG.parse().unwrap();
+        assert!(diff(&old, &old).is_empty());
+
+        // The first peer is removed, the second one changes, and a third one is added
+        let mut new = WgConfig {
+            peers: old.peers[1..].to_vec(),
+            ..Default::default()
+        };
+        new.peers[0].persistent_keepalive = None;
+        new.peers[0]
+            .allowed_ips
+            .push("10.192.125.0/24".parse().unwrap());
+        let added = PeerConfig::new(x25519::PublicKey::from([1; 32]));
+        new.peers.push(added.clone());
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.added, vec![added]);
+        assert_eq!(changes.removed, vec![old.peers[0].public_key]);
+        assert_eq!(
+            changes.modified,
+            vec![(
+                old.peers[1].public_key,
+                PeerChanges {
+                    allowed_ips: Some(new.peers[0].allowed_ips.clone()),
+                    persistent_keepalive: Some(None),
+                    ..Default::default()
+                }
+            )]
+        );
+
+        // Neither the order of the allowed IPs nor a missing endpoint is a change
+        let mut reordered = old.clone();
+        reordered.peers[0].allowed_ips.reverse();
+        reordered.peers[0].endpoint = None;
+        assert!(diff(&old, &reordered).is_empty());
+    }
+
+    #[test]
+    fn test_parse_wg_quick_config() {
+        let text = "
+[Interface]
+Address = 10.192.122.1/24
+DNS = 10.192.122.2
+MTU = 1380
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE
+PreDown = iptables -D FORWARD -i %i -j ACCEPT
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 0.0.0.0/0
+";
+        assert!(text.parse::<WgConfig>().is_err());
+        let config = parse_wg_config(text).unwrap();
+        assert!(config.private_key.is_some());
+        let address = AllowedIP::parse_address("10.192.122.1/24").unwrap();
+        assert_eq!(config.address, [address]);
+        assert_eq!(config.mtu, Some(1380));
+        assert_eq!(
+            config.hooks.post_up,
+            [
+                "iptables -A FORWARD -i %i -j ACCEPT",
+                "iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE"
+            ]
+        );
+        assert_eq!(
+            config.hooks.pre_down,
+            ["iptables -D FORWARD -i %i -j ACCEPT"]
+        );
+        assert!(config.hooks.pre_up.is_empty() && config.hooks.post_down.is_empty());
+        assert_eq!(config.peers.len(), 1);
+        assert_eq!(config.peers[0].allowed_ips.len(), 1);
+
+        // Only the keys are lenient
+        assert!(parse_wg_config("[Interface]\nListenPort = x").is_err());
+        assert!(parse_wg_config("[Tunnel]").is_err());
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        let err = "[Interface]\nAddress = 10.0.0.1/24".parse::<WgConfig>();
+        assert_eq!(err.err().unwrap(), "Line 2: Unknown key address");
+
+        assert!("ListenPort = 1".parse::<WgConfig>().is_err());
+        assert!("[Interface]\nListenPort = x".parse::<WgConfig>().is_err());
+        assert!("[Interface]\nListenPort".parse::<WgConfig>().is_err());
+        assert!("[Peer]\nAllowedIPs = 10.0.0.1/32"
+            .parse::<WgConfig>()
+            .is_err());
+        assert!("[Peer]\nPublicKey = abc".parse::<WgConfig>().is_err());
+        assert!("[Tunnel]".parse::<WgConfig>().is_err());
+    }
+}
@@ -25,7 +25,7 @@ impl<T> Lock<T> {
 
 impl<T: ?Sized> Lock<T> {
     /// Acquire a read lock
-    pub fn read(&self) -> LockReadGuard<T> {
+    pub fn read(&self) -> LockReadGuard<'_, T> {
         let (lock, cvar) = &self.wants_write;
         let mut wants_write = lock.lock();
         while *wants_write {
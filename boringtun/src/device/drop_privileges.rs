@@ -1,12 +1,142 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use crate::device::sandbox::enter_sandbox;
 use crate::device::Error;
 use libc::{gid_t, setgid, setuid, uid_t};
+use nix::unistd::{Group, Uid, User};
+use std::ffi::CString;
 use std::io;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::str::FromStr;
 
-#[cfg(target_os = "macos")]
-use nix::unistd::User;
+/// The account the process switches to when it drops its privileges
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DropTarget {
+    /// The user, by default the one who started the process, e.g. through sudo
+    pub uid: Option<uid_t>,
+    /// The group, by default the primary group of the user
+    pub gid: Option<gid_t>,
+    /// Capabilities that are kept once the process runs as the user, for instance
+    /// `CAP_NET_ADMIN` to still set the fwmark of the sockets
+    #[cfg(target_os = "linux")]
+    pub keep_caps: Vec<Capability>,
+}
+
+impl DropTarget {
+    /// Look up a user and a group given by name or by numeric id, failing if they don't exist
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<DropTarget, Error> {
+        let uid = match user {
+            Some(user) => Some(lookup_user(user)?.uid.as_raw()),
+            None => None,
+        };
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => None,
+        };
+        Ok(DropTarget {
+            uid,
+            gid,
+            #[cfg(target_os = "linux")]
+            keep_caps: Vec::new(),
+        })
+    }
+
+    /// Whether the user or the group were chosen, rather than being those who started the process
+    pub fn is_explicit(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some()
+    }
+
+    /// The user and group the process switches to
+    pub fn resolve(&self) -> Result<(uid_t, gid_t), Error> {
+        match self.uid {
+            Some(uid) => {
+                let primary_gid = User::from_uid(Uid::from_raw(uid))
+                    .ok()
+                    .flatten()
+                    .map(|user| user.gid.as_raw());
+                match self.gid.or(primary_gid) {
+                    Some(gid) => Ok((uid, gid)),
+                    None => Err(Error::DropPrivileges(format!(
+                        "User {} has no primary group, a group must be given",
+                        uid
+                    ))),
+                }
+            }
+            None => {
+                let (saved_uid, saved_gid) = get_saved_ids()?;
+                Ok((saved_uid, self.gid.unwrap_or(saved_gid)))
+            }
+        }
+    }
+}
+
+/// A capability that can be kept after dropping privileges, Linux only
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    NetAdmin,
+    NetRaw,
+}
+
+#[cfg(target_os = "linux")]
+impl Capability {
+    // See linux/capability.h
+    fn number(self) -> u32 {
+        match self {
+            Capability::NetAdmin => 12,
+            Capability::NetRaw => 13,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl FromStr for Capability {
+    type Err = String;
+
+    /// Parse `CAP_NET_ADMIN` or `net_admin`, in any case
+    fn from_str(s: &str) -> Result<Capability, String> {
+        let name = s.to_ascii_uppercase();
+        match name.strip_prefix("CAP_").unwrap_or(&name) {
+            "NET_ADMIN" => Ok(Capability::NetAdmin),
+            "NET_RAW" => Ok(Capability::NetRaw),
+            _ => Err(format!(
+                "Unsupported capability {}, only CAP_NET_ADMIN and CAP_NET_RAW can be kept",
+                s
+            )),
+        }
+    }
+}
+
+fn lookup_user(user: &str) -> Result<User, Error> {
+    let found = match user.parse::<uid_t>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    };
+    match found {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(Error::DropPrivileges(format!("Unknown user {}", user))),
+        Err(e) => Err(Error::DropPrivileges(format!(
+            "Failed to look up user {}: {}",
+            user, e
+        ))),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<gid_t, Error> {
+    if let Ok(gid) = group.parse::<gid_t>() {
+        return Ok(gid);
+    }
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        Ok(None) => Err(Error::DropPrivileges(format!("Unknown group {}", group))),
+        Err(e) => Err(Error::DropPrivileges(format!(
+            "Failed to look up group {}: {}",
+            group, e
+        ))),
+    }
+}
 
 pub fn get_saved_ids() -> Result<(uid_t, gid_t), Error> {
     // Get the user name of the sudoer
@@ -47,25 +177,59 @@ pub fn get_saved_ids() -> Result<(uid_t, gid_t), Error> {
     }
 }
 
+/// Switch to the user who started the process, see [`drop_privileges_to`]
 pub fn drop_privileges() -> Result<(), Error> {
-    let (saved_uid, saved_gid) = get_saved_ids()?;
+    drop_privileges_to(&DropTarget::default())
+}
 
-    if -1 == unsafe { setgid(saved_gid) } {
+/// Switch to the user and group of `target`, with the supplementary groups of the user, keeping
+/// only the capabilities of `target`
+pub fn drop_privileges_to(target: &DropTarget) -> Result<(), Error> {
+    drop_privileges_in(target, None)
+}
+
+/// Like [`drop_privileges_to`], and enter the sandbox in `sandbox` once the user database was read,
+/// before the user changes
+pub(crate) fn drop_privileges_in(target: &DropTarget, sandbox: Option<&Path>) -> Result<(), Error> {
+    let (uid, gid) = target.resolve()?;
+
+    set_groups(uid, gid)?;
+
+    if let Some(dir) = sandbox {
+        enter_sandbox(dir)?;
+    }
+
+    if -1 == unsafe { setgid(gid) } {
         // Set real and effective group ID
         return Err(Error::DropPrivileges(
             io::Error::last_os_error().to_string(),
         ));
     }
 
-    if -1 == unsafe { setuid(saved_uid) } {
+    // The capabilities are lost when the user changes, unless they are kept explicitly
+    #[cfg(target_os = "linux")]
+    if !target.keep_caps.is_empty() && -1 == unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1) } {
+        return Err(Error::DropPrivileges(format!(
+            "Failed to keep the capabilities: {}",
+            io::Error::last_os_error()
+        )));
+    }
+
+    if -1 == unsafe { setuid(uid) } {
         // Set  real and effective user ID
         return Err(Error::DropPrivileges(
             io::Error::last_os_error().to_string(),
         ));
     }
 
+    #[cfg(target_os = "linux")]
+    if !target.keep_caps.is_empty() {
+        set_capabilities(&target.keep_caps)?;
+        unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0) };
+    }
+
     // Validated we can't get sudo back again
-    if unsafe { (setgid(0) != -1) || (setuid(0) != -1) } {
+    if uid != 0 && unsafe { (setgid(0) != -1) || (setuid(0) != -1) } {
         Err(Error::DropPrivileges(
             "Failed to permanently drop privileges".to_owned(),
         ))
@@ -73,3 +237,98 @@ pub fn drop_privileges() -> Result<(), Error> {
         Ok(())
     }
 }
+
+/// Replace the supplementary groups with those of the user, or with `gid` only if the user has
+/// no entry in the user database
+fn set_groups(uid: uid_t, gid: gid_t) -> Result<(), Error> {
+    let name = User::from_uid(Uid::from_raw(uid))
+        .ok()
+        .flatten()
+        .and_then(|user| CString::new(user.name).ok());
+    let result = match name {
+        Some(name) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+        None => unsafe { libc::setgroups(1, &gid) },
+    };
+    match result {
+        -1 => Err(Error::DropPrivileges(format!(
+            "Failed to set the supplementary groups: {}",
+            io::Error::last_os_error()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Make `caps` the only permitted and effective capabilities, see capset(2)
+#[cfg(target_os = "linux")]
+fn set_capabilities(caps: &[Capability]) -> Result<(), Error> {
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // Version 3 splits the 64 bits of the sets in two
+    let mut data = [CapData::default(); 2];
+    for cap in caps {
+        let n = cap.number();
+        let set = &mut data[(n / 32) as usize];
+        set.effective |= 1 << (n % 32);
+        set.permitted |= 1 << (n % 32);
+    }
+
+    if -1 == unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) } {
+        return Err(Error::DropPrivileges(format!(
+            "Failed to set the capabilities: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let target = DropTarget::lookup(Some("root"), Some("0")).unwrap();
+        assert_eq!(target.uid, Some(0));
+        assert_eq!(target.gid, Some(0));
+        assert_eq!(DropTarget::lookup(Some("0"), None).unwrap().uid, Some(0));
+
+        assert!(DropTarget::lookup(Some("no-such-user-boringtun"), None).is_err());
+        assert!(DropTarget::lookup(None, Some("no-such-group-boringtun")).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = DropTarget::lookup(Some("root"), None).unwrap();
+        assert!(root.is_explicit());
+        assert_eq!(root.resolve().unwrap(), (0, 0));
+        let target = DropTarget::lookup(Some("root"), Some("1")).unwrap();
+        assert_eq!(target.resolve().unwrap(), (0, 1));
+        assert!(!DropTarget::default().is_explicit());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_capability() {
+        assert_eq!("CAP_NET_ADMIN".parse(), Ok(Capability::NetAdmin));
+        assert_eq!("net_raw".parse(), Ok(Capability::NetRaw));
+        assert!("CAP_SYS_ADMIN".parse::<Capability>().is_err());
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A typed snapshot of the state of a device, for tooling that would rather parse JSON than the
+//! `key=value` lines of a UAPI `get`. Read it with [`super::DeviceHandle::dump_json`], or over the
+//! UAPI socket with the boringtun-specific `get=2` command, answered with the document on a single
+//! line before the usual `errno` line.
+//!
+//! Keys are base64 encoded, as in configuration files. The private key and the preshared keys of
+//! the peers are never part of it.
+
+use super::{Device, Limits};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The state of a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceDump {
+    pub public_key: Option<String>,
+    pub listen_port: u16,
+    pub fwmark: Option<u32>,
+    /// The number of handshakes dropped because too many were waiting to be processed
+    pub dropped_handshakes: u64,
+    /// See [`crate::noise::rate_limiter::RateLimiter::rate_limited`]
+    pub handshake_rate_limited: u64,
+    pub limits: LimitsDump,
+    pub peers: Vec<PeerDump>,
+}
+
+/// See [`Limits`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LimitsDump {
+    pub max_peers: Option<usize>,
+    pub max_allowed_ips_per_peer: Option<usize>,
+    pub max_allowed_ips: Option<usize>,
+}
+
+/// The state of a peer, the counters are those of [`super::peer::PeerStats`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerDump {
+    pub public_key: String,
+    pub has_preshared_key: bool,
+    pub endpoint: Option<SocketAddr>,
+    /// The host name the endpoint was resolved from, if it was configured with one
+    pub endpoint_host: Option<String>,
+    /// In CIDR notation, such as `10.0.0.0/8`
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u16>,
+    /// In RFC 3339 format, in UTC
+    pub last_handshake: Option<String>,
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    pub tx_dropped: u64,
+    pub filtered_packets: u64,
+    pub rx_drops_auth: u64,
+    pub rx_drops_replay: u64,
+    pub rx_drops_allowed_ips: u64,
+    pub tx_drops_no_session: u64,
+    /// The bandwidth limit, in bytes per second, and its burst in bytes
+    pub tx_rate: Option<u64>,
+    pub tx_burst: Option<u64>,
+    pub path_mtu: Option<usize>,
+    pub active_endpoint: Option<usize>,
+    pub unreachable: bool,
+}
+
+impl From<Limits> for LimitsDump {
+    fn from(limits: Limits) -> LimitsDump {
+        LimitsDump {
+            max_peers: limits.max_peers,
+            max_allowed_ips_per_peer: limits.max_allowed_ips_per_peer,
+            max_allowed_ips: limits.max_allowed_ips,
+        }
+    }
+}
+
+/// A time in RFC 3339 format in UTC, to the second, such as `2023-11-14T22:13:20Z`, or to the
+/// microsecond with `micros`, such as `2023-11-14T22:13:20.123456Z`
+pub fn rfc3339(time: SystemTime, micros: bool) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // The civil date of a number of days since the epoch, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    let date_time = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    match micros {
+        true => format!("{}.{:06}Z", date_time, since_epoch.subsec_micros()),
+        false => date_time + "Z",
+    }
+}
+
+fn last_handshake(time_since_last_handshake: Duration) -> Option<String> {
+    SystemTime::now()
+        .checked_sub(time_since_last_handshake)
+        .map(|time| rfc3339(time, false))
+}
+
+impl Device {
+    pub(super) fn dump(&self) -> DeviceDump {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| {
+                let peer = peer.lock();
+                let stats = peer.stats();
+                let limit = peer.bandwidth_limit();
+                let endpoint = peer.endpoint().addr;
+                PeerDump {
+                    public_key: base64::encode(public_key.as_bytes()),
+                    has_preshared_key: peer.preshared_key().is_some(),
+                    endpoint,
+                    endpoint_host: peer.endpoint_host().map(str::to_owned),
+                    allowed_ips: peer
+                        .allowed_ips()
+                        .map(|(ip, cidr)| format!("{}/{}", ip, cidr))
+                        .collect(),
+                    persistent_keepalive: peer.persistent_keepalive(),
+                    last_handshake: stats.time_since_last_handshake.and_then(last_handshake),
+                    rx_bytes: stats.rx_bytes,
+                    tx_bytes: stats.tx_bytes,
+                    tx_dropped: stats.tx_dropped,
+                    filtered_packets: stats.filtered_packets,
+                    rx_drops_auth: stats.rx_drops_auth,
+                    rx_drops_replay: stats.rx_drops_replay,
+                    rx_drops_allowed_ips: stats.rx_drops_allowed_ips,
+                    tx_drops_no_session: stats.tx_drops_no_session,
+                    tx_rate: limit.map(|limit| limit.bytes_per_sec),
+                    tx_burst: limit.map(|limit| limit.burst),
+                    path_mtu: stats.path_mtu,
+                    active_endpoint: stats.active_endpoint,
+                    unreachable: stats.unreachable,
+                }
+            })
+            .collect();
+        // The peers are in a hash map, sorted so that the same state gives the same dump
+        peers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+
+        DeviceDump {
+            public_key: self
+                .key_pair
+                .as_ref()
+                .map(|(_, public_key)| base64::encode(public_key.as_bytes())),
+            listen_port: self.listen_port,
+            fwmark: self.fwmark,
+            dropped_handshakes: self.stats().dropped_handshakes,
+            handshake_rate_limited: self.rate_limiter.as_ref().map_or(0, |r| r.rate_limited()),
+            limits: self.config.limits.into(),
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH, false), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(rfc3339(time, false), "2023-11-14T22:13:20Z");
+        // A leap day, and the last second of a leap year
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(rfc3339(time, false), "2000-02-29T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_735_689_599);
+        assert_eq!(rfc3339(time, false), "2024-12-31T23:59:59Z");
+
+        let time = UNIX_EPOCH + Duration::from_micros(1_709_251_199_123_456);
+        assert_eq!(rfc3339(time, true), "2024-02-29T23:59:59.123456Z");
+        assert_eq!(rfc3339(UNIX_EPOCH, true), "1970-01-01T00:00:00.000000Z");
+    }
+}
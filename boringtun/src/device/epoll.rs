@@ -161,6 +161,52 @@ impl<H: Sync + Send> EventPoll<H> {
         self.register_event(ev)
     }
 
+    /// Add a new timer event with the factory. It is not triggered until it is armed with
+    /// set_timer, and it is then triggered once, after the given delay.
+    pub fn new_timer_event(&self, handler: H) -> Result<EventRef, Error> {
+        let tfd = match unsafe { timerfd_create(CLOCK_BOOTTIME, TFD_NONBLOCK) } {
+            -1 => match unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK) } {
+                -1 => return Err(Error::Timer(io::Error::last_os_error())),
+                efd => efd,
+            },
+            efd => efd,
+        };
+
+        let ev = Event {
+            event: epoll_event {
+                events: (EPOLLIN | EPOLLONESHOT) as _,
+                u64: 0,
+            },
+            fd: tfd,
+            handler,
+            notifier: false,
+            // Arming the timer resets it, reading it when the guard is released could drop an
+            // expiration of the new delay
+            needs_read: false,
+        };
+
+        self.register_event(ev)
+    }
+
+    /// Arm a timer event to be triggered once after `delay`, replacing the previous delay, or
+    /// disarm it with `None`. It can be called from the handler of the timer, the handler must
+    /// call it every time it is triggered.
+    pub fn set_timer(&self, timer_event: &EventRef, delay: Option<Duration>) {
+        // A zero it_value disarms the timer
+        let delay = delay.map_or(Duration::ZERO, |d| d.max(Duration::from_nanos(1)));
+        let spec = itimerspec {
+            it_value: timespec {
+                tv_sec: delay.as_secs() as _,
+                tv_nsec: i64::from(delay.subsec_nanos()) as _,
+            },
+            it_interval: timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+        };
+        unsafe { timerfd_settime(timer_event.trigger, 0, &spec, std::ptr::null_mut()) };
+    }
+
     /// Add and enable a new notification event with the factory.
     /// The event can only be triggered manually, using the trigger_notification method.
     /// The event will remain in a triggered state until the stop_notification method is
@@ -301,7 +347,7 @@ impl<H: Sync + Send> EventPoll<H> {
         unsafe {
             write(
                 notification_event.trigger,
-                &(std::u64::MAX - 1).to_ne_bytes()[0] as *const u8 as _,
+                &(u64::MAX - 1).to_ne_bytes()[0] as *const u8 as _,
                 8,
             )
         };
@@ -0,0 +1,257 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Notifications about changes of the state of peers, see [`super::DeviceHandle::peer_events`],
+//! and of the device, as an asynchronous stream with the `async-events` feature, see
+//! `DeviceHandle::events`.
+//!
+//! The stream works with any executor. Each has a queue of [`EVENT_QUEUE_CAPACITY`] events, once
+//! it is full the oldest event is dropped for the new one, and counted in
+//! [`super::DeviceStats::dropped_events`], so a slow consumer never holds up the event loop.
+
+use crate::x25519;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+#[cfg(feature = "async-events")]
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll, Waker},
+};
+
+/// The events a stream holds before it drops the oldest ones
+pub const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// A change of the state of a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The endpoint of the peer changed, because its host name now resolves to a different
+    /// address, or because a handshake timed out and the next failover endpoint is tried
+    EndpointChanged {
+        public_key: x25519::PublicKey,
+        old: Option<SocketAddr>,
+        new: SocketAddr,
+    },
+}
+
+/// A change of the state of the device, or of one of its peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// The threads of the device run. As the device starts before it can be subscribed to, this
+    /// is the first event of every stream.
+    DeviceStarted,
+    /// The private key of the device changed, to the one of `public_key`
+    PrivateKeyRotated { public_key: x25519::PublicKey },
+    /// The device listens on another port
+    ListenPortChanged { old: u16, new: u16 },
+    /// An event of a peer
+    Peer(PeerEvent),
+}
+
+impl From<PeerEvent> for DeviceEvent {
+    fn from(event: PeerEvent) -> Self {
+        DeviceEvent::Peer(event)
+    }
+}
+
+/// The receivers of the events of a device. Events are sent from the event loop, so sending
+/// never blocks, and a receiver that was dropped is only removed on the next event.
+#[derive(Default)]
+pub(crate) struct EventSubscribers {
+    senders: Mutex<Vec<mpsc::Sender<PeerEvent>>>,
+    #[cfg(feature = "async-events")]
+    streams: Mutex<Vec<Weak<Mutex<Queue>>>>,
+    /// The events dropped from the queues of the streams that were full
+    dropped: AtomicU64,
+}
+
+impl EventSubscribers {
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<PeerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: PeerEvent) {
+        self.senders
+            .lock()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+        self.emit_device(DeviceEvent::Peer(event));
+    }
+
+    /// Send a device event to the streams, there are none without the `async-events` feature
+    pub(crate) fn emit_device(&self, event: DeviceEvent) {
+        #[cfg(feature = "async-events")]
+        self.streams.lock().retain(|queue| match queue.upgrade() {
+            Some(queue) => {
+                self.push(&queue, event.clone());
+                true
+            }
+            None => false,
+        });
+        #[cfg(not(feature = "async-events"))]
+        let _ = event;
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// A stream of the events emitted from now on, which starts with `first`
+    #[cfg(feature = "async-events")]
+    pub(crate) fn stream(&self, first: DeviceEvent) -> EventStream {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        self.push(&queue, first);
+        self.streams.lock().push(Arc::downgrade(&queue));
+        EventStream(queue)
+    }
+
+    #[cfg(feature = "async-events")]
+    fn push(&self, queue: &Mutex<Queue>, event: DeviceEvent) {
+        let waker = {
+            let mut queue = queue.lock();
+            if queue.events.len() >= EVENT_QUEUE_CAPACITY {
+                queue.events.pop_front();
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            queue.events.push_back(event);
+            queue.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The streams end once the device is dropped
+#[cfg(feature = "async-events")]
+impl Drop for EventSubscribers {
+    fn drop(&mut self) {
+        for queue in self.streams.get_mut().iter().filter_map(Weak::upgrade) {
+            let mut queue = queue.lock();
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async-events")]
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<DeviceEvent>,
+    /// The task waiting for the next event
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The events of a device, see `DeviceHandle::events`. Dropping the stream unsubscribes from
+/// them.
+#[cfg(feature = "async-events")]
+pub struct EventStream(Arc<Mutex<Queue>>);
+
+#[cfg(feature = "async-events")]
+impl futures_core::Stream for EventStream {
+    type Item = DeviceEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DeviceEvent>> {
+        let mut queue = self.0.lock();
+        match queue.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if queue.closed => Poll::Ready(None),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_to_subscribers() {
+        let subscribers = EventSubscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+
+        let event = PeerEvent::EndpointChanged {
+            public_key: x25519::PublicKey::from([1u8; 32]),
+            old: None,
+            new: "192.0.2.1:51820".parse().unwrap(),
+        };
+        subscribers.emit(event.clone());
+        assert_eq!(first.try_recv(), Ok(event.clone()));
+        assert_eq!(second.try_recv(), Ok(event.clone()));
+
+        drop(second);
+        subscribers.emit(event.clone());
+        assert_eq!(subscribers.senders.lock().len(), 1);
+        assert_eq!(first.try_recv(), Ok(event));
+    }
+
+    #[cfg(feature = "async-events")]
+    #[test]
+    fn test_event_stream() {
+        use futures_core::Stream;
+        use std::sync::atomic::AtomicUsize;
+        use std::task::Wake;
+
+        #[derive(Default)]
+        struct CountingWaker(AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let woken = Arc::new(CountingWaker::default());
+        let waker = Waker::from(Arc::clone(&woken));
+        let mut cx = Context::from_waker(&waker);
+        let subscribers = EventSubscribers::default();
+        let mut stream = subscribers.stream(DeviceEvent::DeviceStarted);
+        let mut next = || Pin::new(&mut stream).poll_next(&mut cx);
+
+        assert_eq!(next(), Poll::Ready(Some(DeviceEvent::DeviceStarted)));
+        assert_eq!(next(), Poll::Pending);
+        let event = PeerEvent::EndpointChanged {
+            public_key: x25519::PublicKey::from([1u8; 32]),
+            old: None,
+            new: "192.0.2.1:51820".parse().unwrap(),
+        };
+        subscribers.emit(event.clone());
+        assert_eq!(woken.0.load(Ordering::Relaxed), 1);
+        assert_eq!(next(), Poll::Ready(Some(DeviceEvent::Peer(event))));
+
+        // The oldest events make room for the new ones
+        for port in 0..EVENT_QUEUE_CAPACITY as u16 + 2 {
+            subscribers.emit_device(DeviceEvent::ListenPortChanged {
+                old: port,
+                new: port + 1,
+            });
+        }
+        assert_eq!(subscribers.dropped(), 2);
+        assert_eq!(
+            next(),
+            Poll::Ready(Some(DeviceEvent::ListenPortChanged { old: 2, new: 3 }))
+        );
+
+        // A dropped stream unsubscribes, the others end with the device
+        let other = subscribers.stream(DeviceEvent::DeviceStarted);
+        drop(other);
+        subscribers.emit_device(DeviceEvent::DeviceStarted);
+        assert_eq!(subscribers.streams.lock().len(), 1);
+        drop(subscribers);
+        for _ in 0..EVENT_QUEUE_CAPACITY {
+            assert!(matches!(next(), Poll::Ready(Some(_))));
+        }
+        assert_eq!(next(), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A hook to inspect and drop the packets going through the tunnel, for firewalling or logging
+//! in the application, see [`super::DeviceConfig::packet_filter`].
+
+use crate::x25519;
+use std::fmt::Debug;
+
+/// What happens to a packet seen by a [`PacketFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterVerdict {
+    Allow,
+    /// The packet is dropped, and counted in [`super::peer::PeerStats::filtered_packets`]
+    Drop,
+}
+
+/// Sees the IP packets exchanged with each peer, in plain text. The filter runs on the threads
+/// that move packets, while the peer is locked, so it needs to be quick and must not block.
+pub trait PacketFilter: Debug {
+    /// A packet read from the tunnel, before it is encrypted and sent to `peer`
+    fn filter_outbound(&self, peer: &x25519::PublicKey, packet: &[u8]) -> FilterVerdict;
+
+    /// A packet from `peer` after it was decrypted, before it is written to the tunnel
+    fn filter_inbound(&self, peer: &x25519::PublicKey, packet: &[u8]) -> FilterVerdict;
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::device::test_support::{DevicePair, DevicePairConfig, TestDevice};
+    use crate::device::DeviceConfig;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Drops the packets sent with a payload of "out", and those received with "in"
+    #[derive(Debug)]
+    struct PayloadFilter;
+
+    impl PacketFilter for PayloadFilter {
+        fn filter_outbound(&self, _: &x25519::PublicKey, packet: &[u8]) -> FilterVerdict {
+            match packet.ends_with(b"out") {
+                true => FilterVerdict::Drop,
+                false => FilterVerdict::Allow,
+            }
+        }
+
+        fn filter_inbound(&self, _: &x25519::PublicKey, packet: &[u8]) -> FilterVerdict {
+            match packet.ends_with(b"in") {
+                true => FilterVerdict::Drop,
+                false => FilterVerdict::Allow,
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_filter() {
+        let pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                packet_filter: Some(Arc::new(PayloadFilter)),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let timeout = Duration::from_millis(500);
+        let filtered = |from: &TestDevice, to: &TestDevice| {
+            let stats = from.handle.peer_stats(&to.public_key()).unwrap();
+            stats.filtered_packets
+        };
+
+        pair.a.send_to(&pair.b, b"allowed");
+        assert!(pair.b.recv_timeout(timeout).is_some());
+
+        pair.a.send_to(&pair.b, b"out");
+        assert!(pair.b.recv_timeout(timeout).is_none());
+        assert_eq!(filtered(&pair.a, &pair.b), 1);
+
+        pair.a.send_to(&pair.b, b"in");
+        assert!(pair.b.recv_timeout(timeout).is_none());
+        assert_eq!(filtered(&pair.a, &pair.b), 1);
+        assert_eq!(filtered(&pair.b, &pair.a), 1);
+
+        pair.b.send_to(&pair.a, b"allowed");
+        assert!(pair.a.recv_timeout(timeout).is_some());
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The handshakes received from the network are processed on dedicated threads, so a burst of
+//! them, such as peers reconnecting after an outage, doesn't delay the data of the established
+//! sessions. See [`super::DeviceConfig::handshake_threads`].
+//!
+//! The I/O threads push the handshakes that passed the mac1 check to a bounded queue. The
+//! handshake threads process them, and hand the replies back to the I/O threads to be sent.
+
+use super::transport::tcp::TcpConnection;
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of handshakes that can wait to be processed
+pub(crate) const HANDSHAKE_QUEUE_CAPACITY: usize = 1024;
+
+/// Where a packet was received from, and where its replies are sent
+#[derive(Clone)]
+pub(crate) enum PacketSource {
+    Udp(SocketAddr),
+    Tcp(Arc<TcpConnection>),
+}
+
+impl PacketSource {
+    pub(crate) fn ip(&self) -> IpAddr {
+        match self {
+            PacketSource::Udp(addr) => addr.ip(),
+            PacketSource::Tcp(conn) => conn.peer_addr().ip(),
+        }
+    }
+}
+
+/// A handshake initiation or response waiting to be processed
+pub(crate) struct HandshakeJob {
+    pub(crate) packet: Vec<u8>,
+    pub(crate) source: PacketSource,
+    /// Initiations are dropped first when the queue is full, responses answer our own
+    /// initiations and are kept
+    pub(crate) initiation: bool,
+}
+
+#[derive(Default)]
+struct Jobs {
+    pending: VecDeque<HandshakeJob>,
+    closed: bool,
+}
+
+pub(crate) struct HandshakeQueue {
+    jobs: Mutex<Jobs>,
+    available: Condvar,
+    capacity: usize,
+    /// The number of handshakes dropped because the queue was full
+    dropped: AtomicU64,
+    /// The packets the handshake threads produced, for the I/O threads to send
+    replies: Mutex<Vec<(PacketSource, Vec<u8>)>>,
+}
+
+impl HandshakeQueue {
+    pub(crate) fn new(capacity: usize) -> HandshakeQueue {
+        HandshakeQueue {
+            jobs: Default::default(),
+            available: Condvar::new(),
+            capacity,
+            dropped: AtomicU64::new(0),
+            replies: Default::default(),
+        }
+    }
+
+    /// Queue a handshake. When the queue is full the oldest initiation is dropped to make room,
+    /// or `job` itself if there is none.
+    pub(crate) fn push(&self, job: HandshakeJob) {
+        let mut jobs = self.jobs.lock();
+        if jobs.closed {
+            return;
+        }
+        if jobs.pending.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match jobs.pending.iter().position(|job| job.initiation) {
+                Some(oldest) => {
+                    jobs.pending.remove(oldest);
+                }
+                None => return,
+            }
+        }
+        jobs.pending.push_back(job);
+        self.available.notify_one();
+    }
+
+    /// The next handshake to process, waiting for one if needed. Returns `None` once the queue
+    /// is closed.
+    pub(crate) fn pop(&self) -> Option<HandshakeJob> {
+        let mut jobs = self.jobs.lock();
+        loop {
+            if jobs.closed {
+                return None;
+            }
+            if let Some(job) = jobs.pending.pop_front() {
+                return Some(job);
+            }
+            self.available.wait(&mut jobs);
+        }
+    }
+
+    /// Stop the handshake threads, the handshakes still queued are dropped
+    pub(crate) fn close(&self) {
+        let mut jobs = self.jobs.lock();
+        jobs.closed = true;
+        jobs.pending.clear();
+        self.available.notify_all();
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn push_replies(&self, source: &PacketSource, packets: Vec<Vec<u8>>) {
+        let mut replies = self.replies.lock();
+        replies.extend(packets.into_iter().map(|p| (source.clone(), p)));
+    }
+
+    pub(crate) fn take_replies(&self) -> Vec<(PacketSource, Vec<u8>)> {
+        std::mem::take(&mut *self.replies.lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(n: u8, initiation: bool) -> HandshakeJob {
+        HandshakeJob {
+            packet: vec![n],
+            source: PacketSource::Udp(([127, 0, 0, 1], 51820).into()),
+            initiation,
+        }
+    }
+
+    #[test]
+    fn test_handshake_queue_overflow() {
+        let queue = HandshakeQueue::new(3);
+        queue.push(job(1, false));
+        queue.push(job(2, true));
+        queue.push(job(3, true));
+
+        // The oldest initiation makes room, the response is kept
+        queue.push(job(4, false));
+        assert_eq!(queue.dropped(), 1);
+
+        queue.push(job(5, true));
+        queue.push(job(6, false));
+        assert_eq!(queue.dropped(), 3);
+
+        // Without initiations left to drop, the new handshake is dropped
+        queue.push(job(7, false));
+        assert_eq!(queue.dropped(), 4);
+
+        let popped: Vec<u8> = (0..3).map(|_| queue.pop().unwrap().packet[0]).collect();
+        assert_eq!(popped, vec![1, 4, 6]);
+
+        queue.push(job(8, true));
+        queue.close();
+        assert!(queue.pop().is_none());
+    }
+}
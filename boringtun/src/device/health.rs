@@ -0,0 +1,35 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A minimal HTTP/1.1 endpoint for the health checks of load balancers, such as Kubernetes
+//! readiness probes or AWS NLB target groups. Every request is answered with `200 OK` while a peer
+//! had a handshake in the last [`HEALTHY_HANDSHAKE_AGE`], and `503 Service Unavailable` otherwise.
+
+use super::http::Response;
+use super::{Device, Error};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The device is healthy while it had a handshake with a peer this recently
+pub const HEALTHY_HANDSHAKE_AGE: Duration = Duration::from_secs(180);
+
+impl Device {
+    pub(super) fn register_health_check_handler(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.register_http_handler(addr, |d, _| {
+            let healthy = d.peers.values().any(|peer| {
+                peer.lock()
+                    .time_since_last_handshake()
+                    .is_some_and(|age| age < HEALTHY_HANDSHAKE_AGE)
+            });
+            let (status, body) = match healthy {
+                true => ("200 OK", r#"{"status":"healthy"}"#),
+                false => ("503 Service Unavailable", r#"{"status":"unhealthy"}"#),
+            };
+            Response {
+                status,
+                content_type: "application/json",
+                body: body.to_owned(),
+            }
+        })
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A minimal HTTP/1.1 server on the event loop, for the endpoints of [`super::health`] and
+//! [`super::metrics`]. Each connection gets a single response and is closed.
+
+#[cfg(windows)]
+use super::poll::AsRawFd;
+use super::{Action, Device, Error};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// How long a client may take to send its request, it holds a thread of the event loop meanwhile
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// The answer to a request
+pub(super) struct Response {
+    pub(super) status: &'static str,
+    pub(super) content_type: &'static str,
+    pub(super) body: String,
+}
+
+impl Device {
+    /// Serve HTTP on `addr`, `respond` gets the path of each request
+    pub(super) fn register_http_handler(
+        &self,
+        addr: SocketAddr,
+        respond: fn(&Device, &str) -> Response,
+    ) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = socket2::Socket::from(listener);
+
+        self.queue.new_event(
+            listener.as_raw_fd(),
+            Box::new(move |d, _| {
+                let conn = match listener.accept() {
+                    Ok((conn, _)) => TcpStream::from(conn),
+                    _ => return Action::Continue,
+                };
+                if let Err(e) = serve(conn, |path| respond(d, path)) {
+                    tracing::debug!(message = "Failed to answer an HTTP request", error = ?e);
+                }
+                Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
+}
+
+fn serve(mut conn: TcpStream, respond: impl FnOnce(&str) -> Response) -> io::Result<()> {
+    conn.set_nonblocking(false)?;
+    conn.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    conn.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // The request is read up to its end, the response could be lost if the connection was closed
+    // with unread data
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        match conn.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    // Only the path of the request line matters, such as `/metrics` in `GET /metrics HTTP/1.1`
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split(' ').nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+
+    let response = respond(path);
+    write!(
+        conn,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    conn.flush()
+}
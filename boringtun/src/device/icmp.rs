@@ -0,0 +1,370 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! ICMP errors about the packets from the tunnel, sent back through the tunnel to their sender.
+//!
+//! Besides the errors of [`super::pmtu`], a "destination unreachable" error is sent back when
+//! the datagram carrying a packet can't be sent to the endpoint of the peer, because the kernel
+//! reported that the endpoint refused it or is unreachable. The sender then gives up right away,
+//! rather than waiting for its own timeouts.
+//!
+//! With [`super::DeviceConfig::decrement_inner_ttl`], the device also counts as a hop: the TTL or
+//! hop limit of a packet from the tunnel is decremented before it is encrypted, and a "time
+//! exceeded" error is sent back for those that reach zero.
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub(crate) const IPV4_HEADER_LEN: usize = 20;
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+const ICMP_HEADER_LEN: usize = 8;
+
+/// ICMP errors should not be larger than this, RFC 1812 for IPv4 and RFC 4443 for IPv6
+pub(crate) const IPV4_MAX_ICMP_ERROR_LEN: usize = 576;
+pub(crate) const IPV6_MAX_ICMP_ERROR_LEN: usize = 1280;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+pub(crate) const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_NET_UNREACH: u8 = 0;
+const ICMP_HOST_UNREACH: u8 = 1;
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_NO_ROUTE: u8 = 0;
+const ICMPV6_ADDR_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/// Whether `packet` is an ICMP error, which never triggers another error. Extension headers of
+/// IPv6 are not followed.
+pub(crate) fn is_icmp_error(packet: &[u8]) -> bool {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_HEADER_LEN => {
+            let ihl = usize::from(packet[0] & 0x0f) * 4;
+            packet[9] == IPPROTO_ICMP
+                && packet
+                    .get(ihl)
+                    .is_some_and(|&t| !matches!(t, 0 | 8 | 13 | 14 | 15 | 16 | 17 | 18))
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => {
+            // Error messages are the ones with a type under 128
+            packet[6] == IPPROTO_ICMPV6 && packet.get(IPV6_HEADER_LEN).is_some_and(|&t| t < 128)
+        }
+        _ => false,
+    }
+}
+
+/// The Internet checksum of the concatenation of `parts`, which must all have an even length
+/// except for the last one
+pub(crate) fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match *word {
+                [hi, lo] => u16::from_be_bytes([hi, lo]),
+                [hi] => u16::from_be_bytes([hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Write into `dst` an ICMP error about `packet`, that starts with `v4_header` if `packet` is an
+/// IPv4 packet, or `v6_header` if it is an IPv6 packet, checksum excluded. The error appears to
+/// come from the destination of `packet`, and quotes as much of it as allowed. Returns `None` if
+/// `packet` is not a valid IP packet or `dst` is too small.
+pub(crate) fn icmp_error<'a>(
+    packet: &[u8],
+    v4_header: [u8; ICMP_HEADER_LEN],
+    v6_header: [u8; ICMP_HEADER_LEN],
+    dst: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    icmp_error_from(None, packet, v4_header, v6_header, dst)
+}
+
+/// Like [`icmp_error`], with the error coming from `from` instead when it is of the family of
+/// `packet`
+fn icmp_error_from<'a>(
+    from: Option<IpAddr>,
+    packet: &[u8],
+    v4_header: [u8; ICMP_HEADER_LEN],
+    v6_header: [u8; ICMP_HEADER_LEN],
+    dst: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= IPV4_HEADER_LEN => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?);
+            let dest = match from {
+                Some(IpAddr::V4(from)) => from,
+                _ => Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?),
+            };
+            let quoted = packet
+                .len()
+                .min(IPV4_MAX_ICMP_ERROR_LEN - IPV4_HEADER_LEN - ICMP_HEADER_LEN);
+            let len = IPV4_HEADER_LEN + ICMP_HEADER_LEN + quoted;
+            let dst = dst.get_mut(..len)?;
+
+            let (ip, icmp) = dst.split_at_mut(IPV4_HEADER_LEN);
+            ip.copy_from_slice(&[
+                0x45,
+                0,
+                0,
+                0, // Version, IHL, DSCP, total length
+                0,
+                0,
+                0,
+                0, // Identification, flags, fragment offset
+                64,
+                IPPROTO_ICMP,
+                0,
+                0, // TTL, protocol, checksum
+                0,
+                0,
+                0,
+                0, // Source address
+                0,
+                0,
+                0,
+                0, // Destination address
+            ]);
+            ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            ip[12..16].copy_from_slice(&dest.octets());
+            ip[16..20].copy_from_slice(&src.octets());
+            let ip_checksum = checksum(&[ip]);
+            ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+            icmp[..ICMP_HEADER_LEN].copy_from_slice(&v4_header);
+            icmp[ICMP_HEADER_LEN..].copy_from_slice(&packet[..quoted]);
+            let icmp_checksum = checksum(&[icmp]);
+            icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+            Some(dst)
+        }
+        6 if packet.len() >= IPV6_HEADER_LEN => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+            let dest = match from {
+                Some(IpAddr::V6(from)) => from,
+                _ => Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?),
+            };
+            let quoted = packet
+                .len()
+                .min(IPV6_MAX_ICMP_ERROR_LEN - IPV6_HEADER_LEN - ICMP_HEADER_LEN);
+            let payload_len = ICMP_HEADER_LEN + quoted;
+            let dst = dst.get_mut(..IPV6_HEADER_LEN + payload_len)?;
+
+            let (ip, icmp) = dst.split_at_mut(IPV6_HEADER_LEN);
+            ip[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 0, IPPROTO_ICMPV6, 64]);
+            ip[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+            ip[8..24].copy_from_slice(&dest.octets());
+            ip[24..40].copy_from_slice(&src.octets());
+
+            icmp[..ICMP_HEADER_LEN].copy_from_slice(&v6_header);
+            icmp[ICMP_HEADER_LEN..].copy_from_slice(&packet[..quoted]);
+
+            // The checksum covers a pseudo-header with the addresses, length and next header
+            let pseudo_header = [
+                &(payload_len as u32).to_be_bytes()[..],
+                &[0, 0, 0, IPPROTO_ICMPV6],
+            ]
+            .concat();
+            let icmp_checksum = checksum(&[&ip[8..40], &pseudo_header, icmp]);
+            icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+            Some(dst)
+        }
+        _ => None,
+    }
+}
+
+/// Write into `dst` the "destination unreachable" error telling the sender of `packet` that it
+/// couldn't be sent to the peer because of `error`. Returns `None` if the error doesn't say the
+/// endpoint is unreachable, if `packet` is itself an ICMP error or is not a valid IP packet.
+///
+/// A refused datagram, where the endpoint doesn't run WireGuard, makes the whole tunnel
+/// unusable, it is reported as the network being unreachable. An unreachable endpoint is
+/// reported as the host being unreachable.
+pub(crate) fn destination_unreachable<'a>(
+    packet: &[u8],
+    error: &io::Error,
+    dst: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    let (v4_code, v6_code) = match error.kind() {
+        io::ErrorKind::ConnectionRefused => (ICMP_NET_UNREACH, ICMPV6_NO_ROUTE),
+        io::ErrorKind::HostUnreachable => (ICMP_HOST_UNREACH, ICMPV6_ADDR_UNREACH),
+        _ => return None,
+    };
+    if is_icmp_error(packet) {
+        return None;
+    }
+    icmp_error(
+        packet,
+        [ICMP_DEST_UNREACH, v4_code, 0, 0, 0, 0, 0, 0],
+        [ICMPV6_DEST_UNREACH, v6_code, 0, 0, 0, 0, 0, 0],
+        dst,
+    )
+}
+
+/// Decrement the TTL of an IPv4 `packet`, updating the checksum of its header, or the hop limit
+/// of an IPv6 one. Returns false, leaving `packet` as it is, if it would reach zero or `packet` is
+/// not a valid IP packet.
+pub(crate) fn decrement_ttl(packet: &mut [u8]) -> bool {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_HEADER_LEN => {
+            let ihl = usize::from(packet[0] & 0x0f) * 4;
+            if packet[8] <= 1 || ihl < IPV4_HEADER_LEN || ihl > packet.len() {
+                return false;
+            }
+            packet[8] -= 1;
+            packet[10..12].fill(0);
+            let ip_checksum = checksum(&[&packet[..ihl]]);
+            packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+            true
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => {
+            if packet[7] <= 1 {
+                return false;
+            }
+            packet[7] -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Write into `dst` the "time exceeded in transit" error about `packet`, whose TTL or hop limit
+/// reached zero in the tunnel, from `from` when it is of the family of `packet` or from its
+/// destination otherwise. Returns `None` if `packet` is itself an ICMP error or is not a valid IP
+/// packet.
+pub(crate) fn time_exceeded<'a>(
+    packet: &[u8],
+    from: Option<IpAddr>,
+    dst: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    if is_icmp_error(packet) {
+        return None;
+    }
+    icmp_error_from(
+        from,
+        packet,
+        [ICMP_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0],
+        [ICMPV6_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0],
+        dst,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TCP SYN from 10.0.0.2:40000 to 10.0.0.1:80
+    const TCP_SYN_V4: &[u8] = &[
+        0x45, 0x00, 0x00, 0x28, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x14, 0x9a, 0x0a, 0x00, 0x00,
+        0x02, 0x0a, 0x00, 0x00, 0x01, 0x9c, 0x40, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x50, 0x02, 0xff, 0xff, 0xff, 0x4e, 0x00, 0x00,
+    ];
+
+    /// A UDP datagram with a 4 byte payload from [fd00::2]:5000 to [fd00::1]:53
+    const UDP_V6: &[u8] = &[
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x11, 0x40, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x13, 0x88, 0x00, 0x35, 0x00,
+        0x0c, 0x54, 0x77, 0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    #[test]
+    fn test_destination_unreachable_v4() {
+        let mut dst = [0u8; 1500];
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let error = destination_unreachable(TCP_SYN_V4, &refused, &mut dst).unwrap();
+        assert_eq!(
+            error.len(),
+            IPV4_HEADER_LEN + ICMP_HEADER_LEN + TCP_SYN_V4.len()
+        );
+        assert_eq!(checksum(&[&error[..IPV4_HEADER_LEN]]), 0);
+        // From the destination of the packet to its source
+        assert_eq!(&error[12..16], &TCP_SYN_V4[16..20]);
+        assert_eq!(&error[16..20], &TCP_SYN_V4[12..16]);
+
+        let icmp = &error[IPV4_HEADER_LEN..];
+        assert_eq!(&icmp[..2], &[ICMP_DEST_UNREACH, ICMP_NET_UNREACH]);
+        assert_eq!(checksum(&[icmp]), 0);
+        assert_eq!(&icmp[ICMP_HEADER_LEN..], TCP_SYN_V4);
+
+        // Which is an error, that doesn't get another one
+        let unreachable = io::Error::from(io::ErrorKind::HostUnreachable);
+        assert!(is_icmp_error(error));
+        let error = error.to_vec();
+        assert!(destination_unreachable(&error, &unreachable, &mut dst).is_none());
+
+        let error = destination_unreachable(TCP_SYN_V4, &unreachable, &mut dst).unwrap();
+        assert_eq!(error[IPV4_HEADER_LEN + 1], ICMP_HOST_UNREACH);
+    }
+
+    #[test]
+    fn test_destination_unreachable_v6() {
+        let mut dst = [0u8; 1500];
+        let unreachable = io::Error::from(io::ErrorKind::HostUnreachable);
+        let error = destination_unreachable(UDP_V6, &unreachable, &mut dst).unwrap();
+        assert_eq!(&error[8..24], &UDP_V6[24..40]);
+        assert_eq!(&error[24..40], &UDP_V6[8..24]);
+
+        let icmp = &error[IPV6_HEADER_LEN..];
+        assert_eq!(&icmp[..2], &[ICMPV6_DEST_UNREACH, ICMPV6_ADDR_UNREACH]);
+        let pseudo_header = [&(icmp.len() as u32).to_be_bytes()[..], &[0, 0, 0, 58]].concat();
+        assert_eq!(checksum(&[&error[8..40], &pseudo_header, icmp]), 0);
+        assert_eq!(&icmp[ICMP_HEADER_LEN..], UDP_V6);
+        assert!(is_icmp_error(error));
+
+        // Other errors don't say anything about the endpoint
+        let other = io::Error::from(io::ErrorKind::WouldBlock);
+        assert!(destination_unreachable(UDP_V6, &other, &mut dst).is_none());
+        assert!(destination_unreachable(&[], &unreachable, &mut dst).is_none());
+    }
+
+    #[test]
+    fn test_decrement_ttl() {
+        let mut packet = TCP_SYN_V4.to_vec();
+        assert!(decrement_ttl(&mut packet));
+        assert_eq!(packet[8], 0x3f);
+        assert_eq!(checksum(&[&packet[..IPV4_HEADER_LEN]]), 0);
+        assert_eq!(&packet[IPV4_HEADER_LEN..], &TCP_SYN_V4[IPV4_HEADER_LEN..]);
+
+        // The packet is left as it is once the TTL would reach zero
+        packet[8] = 1;
+        let expired = packet.clone();
+        assert!(!decrement_ttl(&mut packet));
+        assert_eq!(packet, expired);
+
+        let mut packet = UDP_V6.to_vec();
+        assert!(decrement_ttl(&mut packet));
+        assert_eq!(packet[7], 0x3f);
+        packet[7] = 1;
+        assert!(!decrement_ttl(&mut packet));
+        assert!(!decrement_ttl(&mut []));
+    }
+
+    #[test]
+    fn test_time_exceeded() {
+        let mut dst = [0u8; 1500];
+        let from = "10.0.0.254".parse().ok();
+        let error = time_exceeded(TCP_SYN_V4, from, &mut dst).unwrap();
+        assert_eq!(&error[12..16], &[10, 0, 0, 254]);
+        assert_eq!(&error[16..20], &TCP_SYN_V4[12..16]);
+        let icmp = &error[IPV4_HEADER_LEN..];
+        assert_eq!(&icmp[..2], &[ICMP_TIME_EXCEEDED, 0]);
+        assert_eq!(checksum(&[icmp]), 0);
+        assert!(is_icmp_error(error));
+        let error = error.to_vec();
+        assert!(time_exceeded(&error, from, &mut dst).is_none());
+
+        // An address of another family is not used
+        let error = time_exceeded(UDP_V6, from, &mut dst).unwrap();
+        assert_eq!(&error[8..24], &UDP_V6[24..40]);
+        assert_eq!(error[IPV6_HEADER_LEN], ICMPV6_TIME_EXCEEDED);
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The packet I/O of the tunnel side of a device. The device uses the TUN device of the
+//! platform by default, see [`super::DeviceHandle::new`], applications can supply their own
+//! implementation, e.g. a userspace network stack, with [`super::DeviceHandle::with_tun`].
+
+use crate::device::peer::AllowedIP;
+#[cfg(windows)]
+use crate::device::poll::AsRawFd;
+use crate::device::tun::TunSocket;
+use crate::device::Error;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+/// A source and sink of IP packets for the tunnel side of a device.
+///
+/// The event loop polls the file descriptor returned by [`AsRawFd::as_raw_fd`] for readability,
+/// and then calls [`Tun::read`] until it fails with [`std::io::ErrorKind::WouldBlock`]. The
+/// descriptor must stay readable for as long as packets can be read, an implementation that is
+/// not backed by a file descriptor can use an eventfd or a socket pair to wake the event loop,
+/// like [`super::channel_tun::ChannelTun`] does. On Windows the event loop waits on a handle
+/// that must stay signaled in the same way, e.g. a manual reset event.
+pub trait Tun: AsRawFd + Send + Sync {
+    /// Read a single packet into `dst`, without blocking. Fails with [`Error::IfaceRead`] when
+    /// there is no packet to read.
+    fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error>;
+
+    /// Write an IPv4 packet, returns the number of bytes written
+    fn write4(&self, src: &[u8]) -> usize;
+
+    /// Write an IPv6 packet, returns the number of bytes written
+    fn write6(&self, src: &[u8]) -> usize;
+
+    /// The largest packet that can be read
+    fn mtu(&self) -> Result<usize, Error>;
+
+    fn name(&self) -> Result<String, Error>;
+
+    /// Add `addresses` to the interface, set its MTU, and bring it up if `bring_up` is set
+    fn configure(
+        &self,
+        _addresses: &[AllowedIP],
+        _mtu: Option<u32>,
+        _bring_up: bool,
+    ) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "the interface can't be configured".to_owned(),
+        ))
+    }
+
+    /// Route `routes` through the interface, in the routing `table`
+    #[cfg(target_os = "linux")]
+    fn add_routes(&self, _routes: &[AllowedIP], _table: u32) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "routes can't be added through the interface".to_owned(),
+        ))
+    }
+
+    /// Let the interface hold up to `depth` packets waiting to be read, in each of its queues
+    #[cfg(target_os = "linux")]
+    fn set_queue_depth(&self, _depth: u32) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "the queue depth of the interface can't be set".to_owned(),
+        ))
+    }
+
+    /// Open another queue of the same interface, for a thread of its own. Threads share
+    /// the original queue when `None` is returned.
+    fn new_queue(&self) -> Result<Option<Arc<dyn Tun>>, Error> {
+        Ok(None)
+    }
+}
+
+impl Tun for TunSocket {
+    fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        TunSocket::read(self, dst)
+    }
+
+    fn write4(&self, src: &[u8]) -> usize {
+        TunSocket::write4(self, src)
+    }
+
+    fn write6(&self, src: &[u8]) -> usize {
+        TunSocket::write6(self, src)
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        TunSocket::mtu(self)
+    }
+
+    fn name(&self) -> Result<String, Error> {
+        TunSocket::name(self)
+    }
+
+    fn configure(
+        &self,
+        addresses: &[AllowedIP],
+        mtu: Option<u32>,
+        bring_up: bool,
+    ) -> Result<(), Error> {
+        TunSocket::configure(self, addresses, mtu, bring_up)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn add_routes(&self, routes: &[AllowedIP], table: u32) -> Result<(), Error> {
+        TunSocket::add_routes(self, routes, table)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_queue_depth(&self, depth: u32) -> Result<(), Error> {
+        TunSocket::set_queue_depth(self, depth)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn new_queue(&self) -> Result<Option<Arc<dyn Tun>>, Error> {
+        let queue = TunSocket::new(&self.name()?)?.set_non_blocking()?;
+        Ok(Some(Arc::new(queue)))
+    }
+}
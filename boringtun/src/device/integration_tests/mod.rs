@@ -2,10 +2,11 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 // This module contains some integration tests for boringtun
-// Those tests require docker and sudo privileges to run
-#[cfg(all(test, not(target_os = "macos")))]
+// Those tests require docker and sudo privileges to run, they are not run on Windows
+#[cfg(all(test, unix, not(target_os = "macos")))]
 mod tests {
-    use crate::device::{DeviceConfig, DeviceHandle};
+    use crate::device::config::WgConfig;
+    use crate::device::{DeviceConfig, DeviceHandle, DeviceStats, Limits};
     use crate::x25519::{PublicKey, StaticSecret};
     use base64::encode as base64encode;
     use hex::encode;
@@ -21,6 +22,10 @@ mod tests {
     use std::thread;
 
     static NEXT_IFACE_IDX: AtomicUsize = AtomicUsize::new(100); // utun 100+ should be vacant during testing on CI
+    #[cfg(not(target_os = "openbsd"))]
+    const IFACE_PREFIX: &str = "utun";
+    #[cfg(target_os = "openbsd")]
+    const IFACE_PREFIX: &str = "tun"; // OpenBSD only has tunN interfaces
     static NEXT_PORT: AtomicUsize = AtomicUsize::new(61111); // Use ports starting with 61111, hoping we don't run into a taken port 🤷
     static NEXT_IP: AtomicUsize = AtomicUsize::new(0xc0000200); // Use 192.0.2.0/24 for those tests, we might use more than 256 addresses though, usize must be >=32 bits on all supported platforms
     static NEXT_IP_V6: AtomicUsize = AtomicUsize::new(0); // Use the 2001:db8:: address space, append this atomic counter for bottom 32 bits
@@ -266,14 +271,19 @@ mod tests {
                     use_multi_queue: true,
                     #[cfg(target_os = "linux")]
                     uapi_fd: -1,
+                    ..Default::default()
                 },
             )
         }
 
         /// Create a new interface for the tunnel with the given address
         fn init_with_config(addr_v4: IpAddr, addr_v6: IpAddr, config: DeviceConfig) -> WGHandle {
-            // Generate a new name, utun100+ should work on macOS and Linux
-            let name = format!("utun{}", NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed));
+            // Generate a new name, utun100+ should work on macOS, FreeBSD and Linux
+            let name = format!(
+                "{}{}",
+                IFACE_PREFIX,
+                NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+            );
             let _device = DeviceHandle::new(&name, config).unwrap();
             WGHandle {
                 _device,
@@ -285,12 +295,12 @@ mod tests {
             }
         }
 
-        #[cfg(target_os = "macos")]
+        #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
         /// Starts the tunnel
         fn start(&mut self) {
             // Assign the ipv4 address to the interface
             Command::new("ifconfig")
-                .args(&[
+                .args([
                     &self.name,
                     &self.addr_v4.to_string(),
                     &self.addr_v4.to_string(),
@@ -301,7 +311,7 @@ mod tests {
 
             // Assign the ipv6 address to the interface
             Command::new("ifconfig")
-                .args(&[
+                .args([
                     &self.name,
                     "inet6",
                     &self.addr_v6.to_string(),
@@ -314,7 +324,7 @@ mod tests {
 
             // Start the tunnel
             Command::new("ifconfig")
-                .args(&[&self.name, "up"])
+                .args([&self.name, "up"])
                 .status()
                 .expect("failed to start the tunnel");
 
@@ -329,7 +339,7 @@ mod tests {
                     };
 
                     Command::new("route")
-                        .args(&[
+                        .args([
                             "-q",
                             "-n",
                             "add",
@@ -538,6 +548,112 @@ mod tests {
         );
     }
 
+    #[test]
+    #[ignore]
+    /// Test that the bandwidth limit of a peer can be set on an existing peer, and is reported
+    fn test_wireguard_set_bandwidth_limit() {
+        let wg = WGHandle::init("192.0.2.0".parse().unwrap(), "::2".parse().unwrap());
+        assert_eq!(
+            wg.wg_set_key(StaticSecret::random_from_rng(OsRng)),
+            "errno=0\n\n"
+        );
+
+        let peer_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let endpoint = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 0, 0, 1)), 50001);
+        assert_eq!(wg.wg_set_peer(&peer_key, &endpoint, &[]), "errno=0\n\n");
+
+        let set_peer = |keys: &str| {
+            wg.wg_set(&format!(
+                "public_key={}\n{}",
+                encode(peer_key.as_bytes()),
+                keys
+            ))
+        };
+
+        assert_eq!(set_peer("bt_tx_rate=125000"), "errno=0\n\n");
+        assert!(wg
+            .wg_get()
+            .contains("tx_bytes=0\nbt_tx_rate=125000\nbt_tx_burst=125000\n"));
+
+        assert_eq!(set_peer("bt_tx_burst=1500"), "errno=0\n\n");
+        assert!(wg
+            .wg_get()
+            .contains("bt_tx_rate=125000\nbt_tx_burst=1500\n"));
+
+        assert_eq!(set_peer("bt_tx_rate=0"), "errno=0\n\n");
+        assert!(!wg.wg_get().contains("bt_tx_rate"));
+
+        assert_eq!(set_peer("bt_tx_rate=fast"), "errno=22\n\n");
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that peers and allowed IPs over the limits are rejected, and that existing ones are
+    /// kept when the limits are lowered
+    fn test_wireguard_set_limits() {
+        let limits = Limits {
+            max_peers: Some(2),
+            max_allowed_ips_per_peer: Some(2),
+            max_allowed_ips: Some(3),
+        };
+        let wg = WGHandle::init_with_config(
+            "192.0.2.0".parse().unwrap(),
+            "::2".parse().unwrap(),
+            DeviceConfig {
+                limits,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            wg.wg_set_key(StaticSecret::random_from_rng(OsRng)),
+            "errno=0\n\n"
+        );
+
+        let endpoint = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 0, 0, 1)), 50001);
+        let allowed_ips = |n: usize| -> Vec<AllowedIp> {
+            (0..n)
+                .map(|_| AllowedIp {
+                    ip: next_ip(),
+                    cidr: 32,
+                })
+                .collect()
+        };
+        let add_peer = |n_ips| {
+            let key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+            wg.wg_set_peer(&key, &endpoint, &allowed_ips(n_ips))
+        };
+
+        assert_eq!(add_peer(3), "errno=7\n\n"); // E2BIG
+        assert_eq!(add_peer(2), "errno=0\n\n");
+        assert_eq!(add_peer(2), "errno=7\n\n");
+        assert_eq!(add_peer(1), "errno=0\n\n");
+        let stats = wg._device.stats();
+        assert_eq!(
+            stats,
+            DeviceStats {
+                peers: 2,
+                allowed_ips: 3,
+                dropped_handshakes: 0,
+                ..stats
+            }
+        );
+
+        // Raising the global limits lets the peer limit kick in
+        wg._device.set_limits(Limits {
+            max_allowed_ips: None,
+            ..limits
+        });
+        assert_eq!(add_peer(1), "errno=12\n\n"); // ENOMEM
+
+        // Lowering them keeps the existing peers
+        wg._device.set_limits(Limits {
+            max_peers: Some(1),
+            ..limits
+        });
+        assert_eq!(wg._device.limits().max_peers, Some(1));
+        assert_eq!(wg._device.stats().peers, 2);
+    }
+
     /// Test if wireguard can handle simple ipv4 connections, don't use a connected socket
     #[test]
     #[ignore]
@@ -558,6 +674,7 @@ mod tests {
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                ..Default::default()
             },
         );
 
@@ -716,6 +833,7 @@ mod tests {
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                ..Default::default()
             },
         );
 
@@ -846,4 +964,870 @@ mod tests {
             t.join().unwrap();
         }
     }
+
+    /// Return the index of the only peer of the device, and the time since its last handshake
+    fn peer_session(device: &DeviceHandle) -> (u32, Option<std::time::Duration>) {
+        let device = device.device.read();
+        let peer = device.peers.values().next().expect("No peer").lock();
+        (peer.index(), peer.time_since_last_handshake())
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that applying the same config again leaves the sessions of the peers alone
+    fn test_apply_config_keeps_sessions() {
+        let (port_a, port_b) = (next_port(), next_port());
+        let key_a = StaticSecret::random_from_rng(OsRng);
+        let key_b = StaticSecret::random_from_rng(OsRng);
+
+        let config = |own_key: &StaticSecret, port, peer_key: &StaticSecret, peer_port| {
+            format!(
+                "[Interface]\nPrivateKey = {}\nListenPort = {}\n\n\
+                 [Peer]\nPublicKey = {}\nEndpoint = 127.0.0.1:{}\n\
+                 AllowedIPs = {}/32\nPersistentKeepalive = 1\n",
+                base64encode(own_key.to_bytes()),
+                port,
+                base64encode(PublicKey::from(peer_key).as_bytes()),
+                peer_port,
+                next_ip(),
+            )
+            .parse::<WgConfig>()
+            .unwrap()
+        };
+        let config_a = config(&key_a, port_a, &key_b, port_b);
+        let config_b = config(&key_b, port_b, &key_a, port_a);
+
+        let new_device = || {
+            let name = format!(
+                "{}{}",
+                IFACE_PREFIX,
+                NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+            );
+            DeviceHandle::new(&name, DeviceConfig::default()).unwrap()
+        };
+        let device_a = new_device();
+        let device_b = new_device();
+        device_a.apply_config(config_a.clone()).unwrap();
+        device_b.apply_config(config_b.clone()).unwrap();
+
+        // The persistent keepalive triggers a handshake
+        for _ in 0..100 {
+            if peer_session(&device_a).1.is_some() && peer_session(&device_b).1.is_some() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        let (index_a, handshake_a) = peer_session(&device_a);
+        let (index_b, handshake_b) = peer_session(&device_b);
+        let (handshake_a, handshake_b) = (handshake_a.unwrap(), handshake_b.unwrap());
+
+        device_a.apply_config(config_a).unwrap();
+        device_b.apply_config(config_b).unwrap();
+        thread::sleep(std::time::Duration::from_secs(2));
+
+        // Same peers, and no new handshake took place
+        let (new_index_a, new_handshake_a) = peer_session(&device_a);
+        let (new_index_b, new_handshake_b) = peer_session(&device_b);
+        assert_eq!(index_a, new_index_a);
+        assert_eq!(index_b, new_index_b);
+        assert!(new_handshake_a.unwrap() > handshake_a);
+        assert!(new_handshake_b.unwrap() > handshake_b);
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that the routing lookups agree with the allowed IPs of the peers
+    fn test_peer_for_ip() {
+        use crate::device::peer::AllowedIP;
+
+        let peer_key = |i: u8| PublicKey::from(&StaticSecret::from([i; 32]));
+        let (wide, narrow) = (peer_key(1), peer_key(2));
+        let config = format!(
+            "[Interface]\nPrivateKey = {}\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.0/8, fd00::/8\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.1.0.0/16\n",
+            base64encode(StaticSecret::random_from_rng(OsRng).to_bytes()),
+            base64encode(wide.as_bytes()),
+            base64encode(narrow.as_bytes()),
+        );
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let device = DeviceHandle::new(&name, DeviceConfig::default()).unwrap();
+        device.apply_config(config.parse().unwrap()).unwrap();
+
+        assert_eq!(
+            device.peer_for_ip("10.1.2.3".parse().unwrap()),
+            Some(narrow)
+        );
+        assert_eq!(device.peer_for_ip("10.2.0.1".parse().unwrap()), Some(wide));
+        assert_eq!(device.peer_for_ip("fd00::1".parse().unwrap()), Some(wide));
+        assert_eq!(device.peer_for_ip("192.0.2.1".parse().unwrap()), None);
+
+        let mut allowed_ips = device.allowed_ips_of(&wide);
+        allowed_ips.sort();
+        assert_eq!(
+            allowed_ips,
+            ["10.0.0.0/8", "fd00::/8"]
+                .iter()
+                .map(|ip| ip.parse::<AllowedIP>().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(device.allowed_ips_of(&peer_key(3)).is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that the host name of an endpoint is resolved again periodically
+    fn test_dns_recheck() {
+        use crate::device::events::PeerEvent;
+        use crate::device::Resolver;
+        use parking_lot::Mutex;
+        use std::time::Duration;
+
+        struct MovingResolver(Mutex<SocketAddr>);
+
+        impl Resolver for MovingResolver {
+            fn resolve(&self, _host: &str) -> std::io::Result<Vec<SocketAddr>> {
+                Ok(vec![*self.0.lock()])
+            }
+        }
+
+        let old: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let new: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let resolver = Arc::new(MovingResolver(Mutex::new(old)));
+        let peer_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = DeviceConfig {
+            dns_recheck_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, config).unwrap();
+        device.set_resolver(Arc::clone(&resolver) as _);
+        let events = device.peer_events();
+
+        let config = format!(
+            "[Interface]\nPrivateKey = {}\n\n\
+             [Peer]\nPublicKey = {}\nEndpoint = peer.example.com:51820\nAllowedIPs = {}/32\n",
+            base64encode(StaticSecret::random_from_rng(OsRng).to_bytes()),
+            base64encode(peer_key.as_bytes()),
+            next_ip(),
+        );
+        device.apply_config(config.parse().unwrap()).unwrap();
+        let endpoint = || device.device.read().peers[&peer_key].lock().endpoint().addr;
+        assert_eq!(endpoint(), Some(old));
+
+        *resolver.0.lock() = new;
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            event,
+            PeerEvent::EndpointChanged {
+                public_key: peer_key,
+                old: Some(old),
+                new,
+            }
+        );
+        assert_eq!(endpoint(), Some(new));
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that reloading the configuration file adds, updates and removes peers
+    fn test_reload_config_file() {
+        let path = temp_path();
+        let peer_key = |i: u8| PublicKey::from(&StaticSecret::from([i; 32]));
+        let interface = format!(
+            "[Interface]\nPrivateKey = {}\n",
+            base64encode(StaticSecret::random_from_rng(OsRng).to_bytes())
+        );
+        let peer = |i: u8, allowed_ip: &str| {
+            format!(
+                "[Peer]\nPublicKey = {}\nAllowedIPs = {}\n",
+                base64encode(peer_key(i).as_bytes()),
+                allowed_ip
+            )
+        };
+
+        std::fs::write(
+            &path,
+            interface.clone() + &peer(1, "10.0.1.0/24") + &peer(2, "10.0.2.0/24"),
+        )
+        .unwrap();
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = DeviceConfig {
+            config_file: Some(path.clone().into()),
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, config).unwrap();
+        device.trigger_reload().unwrap();
+        assert_eq!(device.stats().peers, 2);
+        let index = device.device.read().peers[&peer_key(1)].lock().index();
+
+        std::fs::write(
+            &path,
+            interface + &peer(1, "10.0.1.0/24") + &peer(3, "10.0.3.0/24, 10.0.4.0/24"),
+        )
+        .unwrap();
+        device.trigger_reload().unwrap();
+        let stats = device.stats();
+        assert_eq!(
+            stats,
+            DeviceStats {
+                peers: 2,
+                allowed_ips: 3,
+                dropped_handshakes: 0,
+                ..stats
+            }
+        );
+        assert_eq!(
+            device.device.read().peers[&peer_key(1)].lock().index(),
+            index
+        );
+        assert!(!device.device.read().peers.contains_key(&peer_key(2)));
+
+        // A broken file leaves the device alone
+        std::fs::write(&path, "[Peer]\nPublicKey = nope\n").unwrap();
+        assert!(device.trigger_reload().is_err());
+        assert_eq!(device.stats().peers, 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the address, MTU and link state of the interface are set from the config
+    fn test_configure_interface() {
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        use crate::device::peer::AllowedIP;
+
+        let address = vec![
+            AllowedIP::parse_address("192.0.2.1/24").unwrap(),
+            AllowedIP::parse_address("2001:db8::1/64").unwrap(),
+        ];
+        let config = DeviceConfig {
+            address: address.clone(),
+            mtu: Some(1460),
+            bring_up: true,
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, config).unwrap();
+
+        // The encapsulation over IPv6 takes 80 bytes
+        let output = Command::new("ip")
+            .args(["address", "show", "dev", &name])
+            .output()
+            .unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert!(output.contains("mtu 1380"), "{}", output);
+        assert!(output.contains(",UP"), "{}", output);
+        assert!(output.contains("inet 192.0.2.1/24"), "{}", output);
+        assert!(output.contains("inet6 2001:db8::1/64"), "{}", output);
+
+        let iface = Arc::clone(&device.device.read().iface);
+        let err = iface.configure(&address[..1], None, false).unwrap_err();
+        assert!(err.to_string().contains("already assigned"), "{}", err);
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the queue length of the interface is set from the config
+    fn test_tun_queue_depth() {
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = DeviceConfig {
+            tun_queue_depth: Some(2048),
+            ..Default::default()
+        };
+        let _device = DeviceHandle::new(&name, config).unwrap();
+
+        let path = format!("/sys/class/net/{}/tx_queue_len", name);
+        assert_eq!(std::fs::read_to_string(path).unwrap().trim(), "2048");
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the device starts on queues opened beforehand, and rejects descriptors that are
+    /// not queues of the interface
+    fn test_tun_fds() {
+        use crate::device::tun::TunSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let next_name = || {
+            format!(
+                "{}{}",
+                IFACE_PREFIX,
+                NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+            )
+        };
+        // The device takes ownership of the descriptors
+        let open_queue = |name: &str| {
+            let queue = TunSocket::new(name).unwrap();
+            let fd = queue.as_raw_fd();
+            std::mem::forget(queue);
+            fd
+        };
+
+        // More queues than threads, they are shared
+        let name = next_name();
+        let config = DeviceConfig {
+            n_threads: 2,
+            tun_fds: (0..3).map(|_| open_queue(&name)).collect(),
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, config).unwrap();
+        assert_eq!(device.interface_name().unwrap(), name);
+
+        let start = |tun_fds| {
+            let config = DeviceConfig {
+                tun_fds,
+                ..Default::default()
+            };
+            DeviceHandle::new(&name, config).err().unwrap().to_string()
+        };
+        let other = next_name();
+        let err = start(vec![open_queue(&name), open_queue(&other)]);
+        assert!(err.contains(&format!("is a queue of {}", other)), "{}", err);
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let err = start(vec![pipe[0]]);
+        assert!(err.contains("is not a TUN device"), "{}", err);
+        let err = start(vec![pipe[1], pipe[1]]);
+        assert!(err.contains("is given twice"), "{}", err);
+        unsafe { libc::close(pipe[1]) };
+    }
+
+    #[cfg(target_os = "linux")]
+    fn socket_mark(fd: std::os::unix::io::RawFd) -> u32 {
+        let mut mark = 0u32;
+        let mut len = std::mem::size_of::<u32>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_MARK,
+                &mut mark as *mut u32 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        mark
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the fwmark is set on the listeners and on connected sockets, and follows changes
+    fn test_fwmark_on_all_sockets() {
+        let (port_a, port_b) = (next_port(), next_port());
+        let key_a = StaticSecret::random_from_rng(OsRng);
+        let key_b = StaticSecret::random_from_rng(OsRng);
+
+        let config = |own_key: &StaticSecret, port, peer_key: &StaticSecret, peer_port| {
+            format!(
+                "[Interface]\nPrivateKey = {}\nListenPort = {}\nFwMark = 0x51\n\n\
+                 [Peer]\nPublicKey = {}\nEndpoint = 127.0.0.1:{}\n\
+                 AllowedIPs = {}/32\nPersistentKeepalive = 1\n",
+                base64encode(own_key.to_bytes()),
+                port,
+                base64encode(PublicKey::from(peer_key).as_bytes()),
+                peer_port,
+                next_ip(),
+            )
+            .parse::<WgConfig>()
+            .unwrap()
+        };
+
+        let new_device = || {
+            let name = format!(
+                "{}{}",
+                IFACE_PREFIX,
+                NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+            );
+            DeviceHandle::new(&name, DeviceConfig::default()).unwrap()
+        };
+        let device_a = new_device();
+        let device_b = new_device();
+        device_a
+            .apply_config(config(&key_a, port_a, &key_b, port_b))
+            .unwrap();
+        device_b
+            .apply_config(config(&key_b, port_b, &key_a, port_a))
+            .unwrap();
+
+        let connected_mark = |device: &DeviceHandle| {
+            let device = device.device.read();
+            let peer = device.peers.values().next().expect("No peer").lock();
+            let endpoint = peer.endpoint();
+            endpoint
+                .conn
+                .as_ref()
+                .map(|conn| socket_mark(conn.as_raw_fd()))
+        };
+
+        // The keepalives from the peer make the device connect a socket to it
+        for _ in 0..100 {
+            if connected_mark(&device_a).is_some() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(connected_mark(&device_a), Some(0x51));
+
+        {
+            let device = device_a.device.read();
+            assert_eq!(device.fwmark, Some(0x51));
+            let outer = device.outer.as_ref().unwrap();
+            assert_eq!(socket_mark(outer.v4.as_raw_fd()), 0x51);
+            assert_eq!(socket_mark(outer.v6.as_raw_fd()), 0x51);
+        }
+
+        let with_device = |f: &dyn Fn(&mut crate::device::Device)| {
+            device_a
+                .device
+                .read()
+                .try_writeable(
+                    |d| d.trigger_yield(),
+                    |d| {
+                        d.cancel_yield();
+                        f(d)
+                    },
+                )
+                .unwrap()
+        };
+
+        // Changing the mark closes the connected socket, to be connected again with the new mark
+        with_device(&|d| d.set_fwmark(0x52).unwrap());
+        // New listeners keep the mark
+        with_device(&|d| {
+            d.open_listen_socket(port_a).unwrap();
+            let outer = d.outer.as_ref().unwrap();
+            assert_eq!(socket_mark(outer.v4.as_raw_fd()), 0x52);
+            assert_eq!(socket_mark(outer.v6.as_raw_fd()), 0x52);
+        });
+
+        for _ in 0..100 {
+            if connected_mark(&device_a) == Some(0x52) {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(connected_mark(&device_a), Some(0x52));
+
+        with_device(&|d| d.set_fwmark(0).unwrap());
+        assert_eq!(device_a.device.read().fwmark, None);
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the TUN device is created in the network namespace given in the config
+    fn test_device_in_netns() {
+        use crate::device::netns::run_in_netns;
+        use std::ffi::CString;
+        use std::fs::File;
+        use std::os::unix::io::AsRawFd;
+
+        // A thread that moves to a new namespace, and keeps it alive through the file
+        let netns = thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+            File::open("/proc/thread-self/ns/net").unwrap()
+        })
+        .join()
+        .unwrap();
+
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = DeviceConfig {
+            netns_fd: Some(netns.as_raw_fd()),
+            ..Default::default()
+        };
+        let _device = DeviceHandle::new(&name, config).unwrap();
+
+        let if_index = || {
+            let name = CString::new(name.as_str()).unwrap();
+            Ok(unsafe { libc::if_nametoindex(name.as_ptr()) })
+        };
+        assert_eq!(if_index().unwrap(), 0);
+        assert_ne!(run_in_netns(Some(netns.as_raw_fd()), if_index).unwrap(), 0);
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the routes and the rules of a default route are added, and the rules deleted
+    /// with the device, in a namespace of its own so that the host keeps its routes
+    fn test_add_routes() {
+        use crate::device::netns::run_in_netns;
+        use std::fs::File;
+        use std::os::unix::io::AsRawFd;
+
+        let netns = thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+            File::open("/proc/thread-self/ns/net").unwrap()
+        })
+        .join()
+        .unwrap();
+        let ip = |args: &[&str]| {
+            let output = run_in_netns(Some(netns.as_raw_fd()), || {
+                Ok(Command::new("ip").args(args).output().unwrap())
+            })
+            .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = DeviceConfig {
+            netns_fd: Some(netns.as_raw_fd()),
+            address: vec![crate::device::AllowedIP::parse_address("10.9.0.1/24").unwrap()],
+            bring_up: true,
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, config).unwrap();
+        let routes = [
+            "10.10.0.0/16".parse().unwrap(),
+            "0.0.0.0/0".parse().unwrap(),
+        ];
+        device.add_routes(&routes).unwrap();
+
+        assert!(ip(&["route", "show", "dev", &name]).contains("10.10.0.0/16"));
+        assert!(ip(&["route", "show", "table", "51820"]).contains("default"));
+        let rules = ip(&["rule"]);
+        assert!(rules.contains("not from all fwmark 0xca6c lookup 51820"));
+        assert!(rules.contains("lookup main suppress_prefixlength 0"));
+        assert_eq!(device.device.read().fwmark, Some(51820));
+
+        let err = device.add_routes(&routes[..1]).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+
+        drop(device);
+        assert!(!ip(&["rule"]).contains("51820"));
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that a graceful shutdown stops the threads, and sends a last keepalive to the peers
+    fn test_shutdown_sends_keepalive() {
+        use crate::noise::{Tunn, TunnAction};
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let device_key = StaticSecret::random_from_rng(OsRng);
+        let peer_key = StaticSecret::random_from_rng(OsRng);
+        let peer_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer_socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let port = next_port();
+        let config = format!(
+            "[Interface]\nPrivateKey = {}\nListenPort = {}\n\n\
+             [Peer]\nPublicKey = {}\nEndpoint = {}\nAllowedIPs = {}/32\n",
+            base64encode(device_key.to_bytes()),
+            port,
+            base64encode(PublicKey::from(&peer_key).as_bytes()),
+            peer_socket.local_addr().unwrap(),
+            next_ip(),
+        );
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut device = DeviceHandle::new(&name, DeviceConfig::default()).unwrap();
+        device.apply_config(config.parse().unwrap()).unwrap();
+
+        // Complete a handshake with the device, the keepalive confirms the session
+        let mut peer = Tunn::new(
+            peer_key,
+            PublicKey::from(&device_key),
+            None,
+            None,
+            1,
+            None,
+            None,
+        )
+        .unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        match peer.try_format_handshake_initiation(&mut dst, false) {
+            Ok(TunnAction::WriteToNetwork(packet)) => peer_socket.send_to(packet, device_addr),
+            other => panic!("Unexpected handshake initiation {:?}", other),
+        }
+        .unwrap();
+        let n = peer_socket.recv(&mut buf).unwrap();
+        match peer.try_decapsulate(None, &buf[..n], &mut dst) {
+            Ok(TunnAction::WriteToNetwork(packet)) => peer_socket.send_to(packet, device_addr),
+            other => panic!("Unexpected handshake response {:?}", other),
+        }
+        .unwrap();
+        while peer_session(&device).1.is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        device.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert!(device.threads.is_empty());
+
+        // A keepalive carries no packet for the tunnel
+        let n = peer_socket.recv(&mut buf).unwrap();
+        assert_eq!(
+            peer.try_decapsulate(None, &buf[..n], &mut dst),
+            Ok(TunnAction::Noop)
+        );
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that a handshake that gets no response fails over to the next endpoint
+    fn test_endpoint_failover() {
+        use crate::device::events::PeerEvent;
+        use crate::noise::{Tunn, TunnAction};
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let device_key = StaticSecret::random_from_rng(OsRng);
+        let peer_key = StaticSecret::random_from_rng(OsRng);
+        let peer_public = PublicKey::from(&peer_key);
+        let dead_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer_socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (dead, alive) = (
+            dead_socket.local_addr().unwrap(),
+            peer_socket.local_addr().unwrap(),
+        );
+
+        let port = next_port();
+        let config = format!(
+            "[Interface]\nPrivateKey = {}\nListenPort = {}\n\n\
+             [Peer]\nPublicKey = {}\nEndpoint = {},{}\nAllowedIPs = {}/32\n\
+             PersistentKeepalive = 1\n",
+            base64encode(device_key.to_bytes()),
+            port,
+            base64encode(peer_public.as_bytes()),
+            dead,
+            alive,
+            next_ip(),
+        );
+        let name = format!(
+            "{}{}",
+            IFACE_PREFIX,
+            NEXT_IFACE_IDX.fetch_add(1, Ordering::Relaxed)
+        );
+        let device_config = DeviceConfig {
+            handshake_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let device = DeviceHandle::new(&name, device_config).unwrap();
+        let events = device.peer_events();
+        device.apply_config(config.parse().unwrap()).unwrap();
+        assert_eq!(
+            device.peer_stats(&peer_public).unwrap().active_endpoint,
+            Some(0)
+        );
+
+        // The retransmitted initiation goes to the second endpoint
+        let mut peer = Tunn::new(
+            peer_key,
+            PublicKey::from(&device_key),
+            None,
+            None,
+            1,
+            None,
+            None,
+        )
+        .unwrap();
+        let device_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let n = peer_socket.recv(&mut buf).unwrap();
+        match peer.try_decapsulate(None, &buf[..n], &mut dst) {
+            Ok(TunnAction::WriteToNetwork(packet)) => peer_socket.send_to(packet, device_addr),
+            other => panic!("Unexpected handshake initiation {:?}", other),
+        }
+        .unwrap();
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(PeerEvent::EndpointChanged {
+                public_key: peer_public,
+                old: Some(dead),
+                new: alive,
+            })
+        );
+        for _ in 0..100 {
+            if device
+                .peer_stats(&peer_public)
+                .unwrap()
+                .time_since_last_handshake
+                .is_some()
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The endpoint that completed the handshake stays in use
+        let stats = device.peer_stats(&peer_public).unwrap();
+        assert!(stats.time_since_last_handshake.is_some());
+        assert_eq!(stats.active_endpoint, Some(1));
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(
+            device.peer_stats(&peer_public).unwrap().active_endpoint,
+            Some(1)
+        );
+
+        // The first endpoint was tried before
+        dead_socket.set_nonblocking(true).unwrap();
+        assert!(dead_socket.recv(&mut buf).is_ok());
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that a device still completes a handshake and forwards packets once it is sandboxed,
+    /// running as nobody with the seccomp filter. The sandbox applies to the whole process, so the
+    /// test runs again in a child process that enters it.
+    fn test_sandboxed_device() {
+        use crate::device::drop_privileges::DropTarget;
+        use crate::device::test_support::{ipv4_packet, DevicePair, DevicePairConfig};
+        use std::path::Path;
+        use std::time::Duration;
+
+        const SANDBOX_ENV: &str = "BORINGTUN_TEST_SANDBOX";
+        let sandbox = match std::env::var(SANDBOX_ENV) {
+            Ok(sandbox) => sandbox,
+            Err(_) => {
+                let test_name = concat!(module_path!(), "::test_sandboxed_device");
+                let sandbox = temp_path();
+                let status = Command::new(std::env::current_exe().unwrap())
+                    .args([test_name.strip_prefix("boringtun::").unwrap(), "--exact"])
+                    .args(["--ignored", "--nocapture"])
+                    .env(SANDBOX_ENV, &sandbox)
+                    .status()
+                    .unwrap();
+                let _ = std::fs::remove_dir(sandbox);
+                assert!(status.success());
+                return;
+            }
+        };
+
+        let pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                enable_seccomp: true,
+                sandbox: Some(sandbox.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let nobody = DropTarget::lookup(Some("nobody"), None).unwrap();
+        pair.a.handle.drop_privileges_to(&nobody).unwrap();
+        assert!(!Path::new("/etc/passwd").exists());
+
+        // The handshake connects new sockets to the peer
+        for payload in [&b"ping"[..], b"pong"] {
+            pair.a.send_to(&pair.b, payload);
+            let packet = pair.b.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, payload));
+            pair.b.send_to(&pair.a, payload);
+            let packet = pair.a.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, payload));
+        }
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    /// Test that the UAPI socket is handed over to the account privileges are dropped to, which
+    /// can then configure the device. This runs in a child process, like the sandbox.
+    fn test_drop_privileges_chowns_uapi_socket() {
+        use crate::device::channel_tun::ChannelTun;
+        use crate::device::drop_privileges::DropTarget;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        const CHILD_ENV: &str = "BORINGTUN_TEST_CHOWN";
+        const SOCK_DIR: &str = "/var/run/wireguard";
+        if std::env::var(CHILD_ENV).is_err() {
+            let test_name = concat!(module_path!(), "::test_drop_privileges_chowns_uapi_socket");
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([test_name.strip_prefix("boringtun::").unwrap(), "--exact"])
+                .args(["--ignored", "--nocapture"])
+                .env(CHILD_ENV, "1")
+                .status()
+                .unwrap();
+            // The directory is shared by all interfaces
+            std::os::unix::fs::chown(SOCK_DIR, Some(0), Some(0)).unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let (tun, _tun) = ChannelTun::new("chowntest", 1420).unwrap();
+        let device = DeviceHandle::with_tun(Arc::new(tun), DeviceConfig::default()).unwrap();
+        let nobody = DropTarget::lookup(Some("nobody"), None).unwrap();
+        let (uid, gid) = nobody.resolve().unwrap();
+        device.drop_privileges_to(&nobody).unwrap();
+
+        let path = format!("{}/chowntest.sock", SOCK_DIR);
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"get=1\n\n").unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        assert!(response.starts_with("listen_port="), "{}", response);
+    }
+
+    #[test]
+    #[ignore]
+    /// Test that a second device for an interface whose UAPI socket is listened on fails to start,
+    /// and that the socket is taken over once the first one is gone
+    fn test_uapi_socket_in_use() {
+        use crate::device::channel_tun::ChannelTun;
+
+        let (tun, _tun) = ChannelTun::new("inusetest", 1420).unwrap();
+        let first = DeviceHandle::with_tun(Arc::new(tun), DeviceConfig::default()).unwrap();
+
+        let (tun, _tun) = ChannelTun::new("inusetest", 1420).unwrap();
+        let e = DeviceHandle::with_tun(Arc::new(tun), DeviceConfig::default())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("already running"), "{}", e);
+
+        drop(first);
+        let (tun, _tun) = ChannelTun::new("inusetest", 1420).unwrap();
+        DeviceHandle::with_tun(Arc::new(tun), DeviceConfig::default()).unwrap();
+    }
 }
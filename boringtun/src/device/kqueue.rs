@@ -10,6 +10,31 @@ use std::os::unix::io::RawFd;
 use std::ptr::{null, null_mut};
 use std::time::Duration;
 
+// OpenBSD has no NOTE_NSECONDS, its timers are always in milliseconds
+#[cfg(not(target_os = "openbsd"))]
+const TIMER_UNIT: u32 = NOTE_NSECONDS;
+#[cfg(target_os = "openbsd")]
+const TIMER_UNIT: u32 = 0;
+
+#[cfg(not(target_os = "openbsd"))]
+fn timer_data(period: Duration) -> u64 {
+    period
+        .as_secs()
+        .checked_mul(1_000_000_000)
+        .unwrap()
+        .checked_add(u64::from(period.subsec_nanos()))
+        .unwrap()
+}
+
+#[cfg(target_os = "openbsd")]
+fn timer_data(period: Duration) -> u64 {
+    period.as_millis().max(1) as u64
+}
+
+/// The delay of a timer that is not armed. A kqueue timer is periodic, and releasing its guard
+/// enables it again, so it is pushed far out instead of being disabled.
+const DISARMED_TIMER: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// A return type for the EventPoll::wait() function
 pub enum WaitResult<'a, H> {
     /// Event triggered normally
@@ -106,6 +131,8 @@ impl<H: Send + Sync> EventPoll<H> {
                 fflags: 0,
                 data: 0,
                 udata: null_mut(),
+                #[cfg(target_os = "freebsd")]
+                ext: [0; 4],
             },
             handler,
             kind: EventKind::FD,
@@ -121,14 +148,11 @@ impl<H: Send + Sync> EventPoll<H> {
                 ident: 0,
                 filter: EVFILT_TIMER,
                 flags: EV_ENABLE | EV_DISPATCH,
-                fflags: NOTE_NSECONDS,
-                data: period
-                    .as_secs()
-                    .checked_mul(1_000_000_000)
-                    .unwrap()
-                    .checked_add(u64::from(period.subsec_nanos()))
-                    .unwrap() as _,
+                fflags: TIMER_UNIT,
+                data: timer_data(period) as _,
                 udata: null_mut(),
+                #[cfg(target_os = "freebsd")]
+                ext: [0; 4],
             },
             handler,
             kind: EventKind::Timer,
@@ -137,6 +161,50 @@ impl<H: Send + Sync> EventPoll<H> {
         self.register_event(ev)
     }
 
+    /// Add a new timer event with the factory. It is not triggered until it is armed with
+    /// set_timer, and it is then triggered once, after the given delay.
+    pub fn new_timer_event(&self, handler: H) -> Result<EventRef, Error> {
+        let ev = Event {
+            event: kevent {
+                ident: 0,
+                filter: EVFILT_TIMER,
+                flags: EV_ENABLE | EV_DISPATCH,
+                fflags: TIMER_UNIT,
+                data: timer_data(DISARMED_TIMER) as _,
+                udata: null_mut(),
+                #[cfg(target_os = "freebsd")]
+                ext: [0; 4],
+            },
+            handler,
+            kind: EventKind::Timer,
+        };
+
+        self.register_event(ev)
+    }
+
+    /// Arm a timer event to be triggered once after `delay`, replacing the previous delay, or
+    /// disarm it with `None`. It can be called from the handler of the timer, the handler must
+    /// call it every time it is triggered.
+    pub fn set_timer(&self, timer_event: &EventRef, delay: Option<Duration>) {
+        let events = self.custom.lock();
+        let ev_index = -timer_event.trigger - 1; // Custom events have negative index from -1
+
+        let event_ref = &(*events)[ev_index as usize];
+        let event_data = event_ref.as_ref().expect("Expected an event");
+
+        if event_data.kind != EventKind::Timer {
+            panic!("Can only set a timer event");
+        }
+
+        // Adding the timer again restarts it. Without EV_ENABLE it stays disabled while its
+        // handler runs, the guard enables it again.
+        let mut kev = event_data.event;
+        kev.flags = EV_ADD | EV_DISPATCH;
+        kev.data = timer_data(delay.unwrap_or(DISARMED_TIMER)) as _;
+
+        unsafe { kevent(self.kqueue, &kev, 1, null_mut(), 0, null()) };
+    }
+
     pub fn new_notifier(&self, handler: H) -> Result<EventRef, Error> {
         // The notifier in BSD uses EVFILT_USER for notifications.
         let ev = Event {
@@ -147,6 +215,8 @@ impl<H: Send + Sync> EventPoll<H> {
                 fflags: 0,
                 data: 0,
                 udata: null_mut(),
+                #[cfg(target_os = "freebsd")]
+                ext: [0; 4],
             },
             handler,
             kind: EventKind::Notifier,
@@ -165,6 +235,8 @@ impl<H: Send + Sync> EventPoll<H> {
                 fflags: 0,
                 data: 0,
                 udata: null_mut(),
+                #[cfg(target_os = "freebsd")]
+                ext: [0; 4],
             },
             handler,
             kind: EventKind::Signal,
@@ -185,6 +257,8 @@ impl<H: Send + Sync> EventPoll<H> {
             fflags: 0,
             data: 0,
             udata: null_mut(),
+            #[cfg(target_os = "freebsd")]
+            ext: [0; 4],
         };
 
         if unsafe { kevent(self.kqueue, null(), 0, &mut event, 1, null()) } == -1 {
@@ -291,7 +365,13 @@ impl<H: Send + Sync> EventPoll<H> {
 }
 
 impl<H> EventPoll<H> {
-    // This function is only safe to call when the event loop is not running
+    /// Disable and remove the event and associated handler, using the fd that
+    /// was used to register it.
+    ///
+    /// # Safety
+    ///
+    /// This function is only safe to call when the event loop is not running,
+    /// otherwise the memory of the handler may get freed while in use.
     pub unsafe fn clear_event_by_fd(&self, index: RawFd) {
         let (mut events, index) = if index >= 0 {
             (self.events.lock(), index as usize)
@@ -0,0 +1,296 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Discovery of the endpoints of peers on the local network, with mDNS-SD.
+//!
+//! With [`super::DeviceConfig::mdns`], the device advertises its listen port as a
+//! [`SERVICE_TYPE`] service on the addresses of the host, with its public key in the
+//! [`PUBLIC_KEY_PROPERTY`] of the TXT record. It browses the services of the other devices as
+//! well, and the peers configured with [`super::config::PeerConfig::mdns_discovery`] follow the
+//! endpoint advertised with their public key, so they need no static address.
+
+use super::{Action, Device, Error};
+use crate::serialization::KeyBytes;
+use crate::x25519;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The type of the service advertised by each device
+pub const SERVICE_TYPE: &str = "_wireguard._udp.local.";
+
+/// The property of the TXT record that holds the base64 public key of the device
+pub const PUBLIC_KEY_PROPERTY: &str = "pk";
+
+/// How often the peers are updated with the endpoints discovered meanwhile
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The services discovered on the local network
+#[derive(Debug, Default)]
+struct Cache {
+    endpoints: HashMap<x25519::PublicKey, SocketAddr>,
+    /// The public key of each service by full name, to forget its endpoint once it is removed
+    services: HashMap<String, x25519::PublicKey>,
+    /// The public keys whose endpoint changed since the peers were last updated
+    changed: HashSet<x25519::PublicKey>,
+}
+
+impl Cache {
+    fn handle(&mut self, event: ServiceEvent) {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let public_key = match info
+                    .get_property_val_str(PUBLIC_KEY_PROPERTY)
+                    .and_then(|key| key.parse::<KeyBytes>().ok())
+                {
+                    Some(KeyBytes(key)) => x25519::PublicKey::from(key),
+                    None => return,
+                };
+                let ip = match preferred_addr(info.get_addresses()) {
+                    Some(ip) => ip,
+                    None => return,
+                };
+                let addr = SocketAddr::new(ip, info.get_port());
+
+                self.services
+                    .insert(info.get_fullname().to_owned(), public_key);
+                if self.endpoints.insert(public_key, addr) != Some(addr) {
+                    self.changed.insert(public_key);
+                }
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                if let Some(public_key) = self.services.remove(&fullname) {
+                    // The peers stay on the endpoint until it is advertised again
+                    self.endpoints.remove(&public_key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The address to reach a service at: an IPv4 address if there is one, as a LAN usually has
+/// one per host. Link-local IPv6 addresses are skipped, they can't be used without knowing the
+/// interface they are on.
+fn preferred_addr(addrs: &HashSet<IpAddr>) -> Option<IpAddr> {
+    let ipv4 = addrs.iter().filter(|ip| ip.is_ipv4()).min();
+    let ipv6 = addrs
+        .iter()
+        .filter(|ip| matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 != 0xfe80))
+        .min();
+    ipv4.or(ipv6).copied()
+}
+
+/// Advertises the endpoint of the device and browses those of the other devices
+pub(crate) struct Mdns {
+    daemon: ServiceDaemon,
+    /// The full name of the service advertised, if any
+    advertised: Option<String>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+fn mdns_error(e: mdns_sd::Error) -> Error {
+    Error::Mdns(e.to_string())
+}
+
+impl Mdns {
+    pub(crate) fn new() -> Result<Mdns, Error> {
+        let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+        let events = daemon.browse(SERVICE_TYPE).map_err(mdns_error)?;
+
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        let browsed = Arc::clone(&cache);
+        thread::Builder::new()
+            .name("mdns".to_owned())
+            .spawn(move || {
+                // Ends once the daemon shuts down
+                while let Ok(event) = events.recv() {
+                    browsed.lock().handle(event);
+                }
+            })?;
+
+        Ok(Mdns {
+            daemon,
+            advertised: None,
+            cache,
+        })
+    }
+
+    /// Advertise `port` with `public_key`, in place of the previous advertisement
+    pub(crate) fn advertise(
+        &mut self,
+        public_key: &x25519::PublicKey,
+        port: u16,
+    ) -> Result<(), Error> {
+        if let Some(fullname) = self.advertised.take() {
+            self.daemon.unregister(&fullname).map_err(mdns_error)?;
+        }
+
+        // Instance and host names are labels of at most 63 bytes, a prefix of the key in hex
+        // tells the devices apart
+        let instance: String = public_key.as_bytes()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let instance = format!("wg-{}", instance);
+        let key = base64::encode(public_key.as_bytes());
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &format!("{}.local.", instance),
+            (),
+            port,
+            &[(PUBLIC_KEY_PROPERTY, key.as_str())][..],
+        )
+        .map_err(mdns_error)?
+        .enable_addr_auto();
+
+        let fullname = service.get_fullname().to_owned();
+        self.daemon.register(service).map_err(mdns_error)?;
+        self.advertised = Some(fullname);
+        Ok(())
+    }
+
+    /// The endpoint advertised with `public_key`, if any
+    pub(crate) fn endpoint(&self, public_key: &x25519::PublicKey) -> Option<SocketAddr> {
+        self.cache.lock().endpoints.get(public_key).copied()
+    }
+
+    /// The endpoints that changed since the last call
+    fn take_changes(&self) -> Vec<(x25519::PublicKey, SocketAddr)> {
+        let mut cache = self.cache.lock();
+        let changed = std::mem::take(&mut cache.changed);
+        changed
+            .into_iter()
+            .filter_map(|key| Some((key, *cache.endpoints.get(&key)?)))
+            .collect()
+    }
+}
+
+impl Drop for Mdns {
+    fn drop(&mut self) {
+        let _: Result<_, _> = self.daemon.shutdown();
+    }
+}
+
+impl Device {
+    pub(super) fn register_mdns_handler(&self) -> Result<(), Error> {
+        self.queue.new_periodic_event(
+            Box::new(|d, _| {
+                let changes = match &d.mdns {
+                    Some(mdns) => mdns.take_changes(),
+                    None => return Action::Continue,
+                };
+                for (public_key, addr) in changes {
+                    if let Some(peer) = d.peers.get(&public_key) {
+                        d.follow_mdns_endpoint(&mut peer.lock(), Some(addr));
+                    }
+                }
+                Action::Continue
+            }),
+            UPDATE_INTERVAL,
+        )?;
+        Ok(())
+    }
+
+    /// Advertise the listen port with the public key of the device, once both are known
+    pub(super) fn advertise_mdns(&mut self) {
+        let public_key = match &self.key_pair {
+            Some((_, public_key)) => *public_key,
+            None => return,
+        };
+        let port = self.listen_port;
+        if let Some(mdns) = self.mdns.as_mut().filter(|_| port != 0) {
+            if let Err(e) = mdns.advertise(&public_key, port) {
+                tracing::warn!(message = "Failed to advertise over mDNS", error = ?e);
+            }
+        }
+    }
+
+    /// The endpoint advertised with the public key of a peer, if any
+    pub(super) fn mdns_endpoint(&self, public_key: &x25519::PublicKey) -> Option<SocketAddr> {
+        self.mdns.as_ref()?.endpoint(public_key)
+    }
+
+    /// Move `peer` to `addr` if it follows the endpoint it advertises
+    pub(super) fn follow_mdns_endpoint(
+        &self,
+        peer: &mut super::peer::Peer,
+        addr: Option<SocketAddr>,
+    ) {
+        let old = peer.endpoint().addr;
+        let new = match addr {
+            Some(addr) if peer.mdns_discovery() && old != Some(addr) => addr,
+            _ => return,
+        };
+        let _span = peer.span().clone().entered();
+        peer.set_endpoint(new);
+
+        let public_key = peer.tunnel.peer_static_public();
+        tracing::info!(message = "Endpoint discovered over mDNS", endpoint = ?new);
+        self.events.emit(super::events::PeerEvent::EndpointChanged {
+            public_key,
+            old,
+            new,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(fullname: &str, key: &str, addrs: &str) -> ServiceEvent {
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            fullname,
+            "host.local.",
+            addrs,
+            51820,
+            &[(PUBLIC_KEY_PROPERTY, key)][..],
+        )
+        .unwrap();
+        ServiceEvent::ServiceResolved(info)
+    }
+
+    #[test]
+    fn test_cache() {
+        let public_key = x25519::PublicKey::from([7u8; 32]);
+        let key = base64::encode(public_key.as_bytes());
+        let mut cache = Cache::default();
+
+        cache.handle(resolved("a", &key, "fe80::1,2001:db8::1,192.168.1.2"));
+        let addr: SocketAddr = "192.168.1.2:51820".parse().unwrap();
+        assert_eq!(cache.endpoints.get(&public_key), Some(&addr));
+        assert!(cache.changed.contains(&public_key));
+
+        // Resolving the same endpoint again is not a change
+        cache.changed.clear();
+        cache.handle(resolved("a", &key, "192.168.1.2"));
+        assert!(cache.changed.is_empty());
+
+        cache.handle(resolved("a", &key, "fe80::1,2001:db8::1"));
+        let addr: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert_eq!(cache.endpoints.get(&public_key), Some(&addr));
+
+        cache.handle(ServiceEvent::ServiceRemoved(
+            SERVICE_TYPE.to_owned(),
+            format!("a.{}", SERVICE_TYPE),
+        ));
+        assert!(cache.endpoints.is_empty());
+    }
+
+    #[test]
+    fn test_cache_skips_unusable_services() {
+        let mut cache = Cache::default();
+        cache.handle(resolved("a", "not a key", "192.168.1.2"));
+        let key = base64::encode([7u8; 32]);
+        cache.handle(resolved("b", &key, "fe80::1"));
+        assert!(cache.endpoints.is_empty());
+        assert!(cache.services.is_empty());
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Counters of the activity of the device and its peers, served over HTTP in the Prometheus
+//! exposition format on `/metrics`, see [`super::DeviceConfig::metrics_listen`].
+//!
+//! The counters of the hot path are relaxed atomics, updating them takes no lock and formats
+//! nothing. The text is only written when the metrics are scraped, the peers are locked then, as
+//! for a UAPI `get`.
+
+use super::http::Response;
+use super::{peer_fingerprint, Device, Error};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why the device dropped a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DropReason {
+    /// From the tunnel, to an address no peer is allowed
+    NoRoute,
+    /// Rejected by the packet filter, see [`super::filter`]
+    Filtered,
+    /// Over the bandwidth limit of the peer, see [`super::shaper`]
+    BandwidthLimit,
+    /// Over the path MTU to the endpoint of the peer, the sender is told to send smaller ones
+    TooBig,
+    /// Not a valid message of a peer
+    Invalid,
+    /// From a peer, with a source address the peer is not allowed
+    SourceNotAllowed,
+    /// From the tunnel, with a TTL or hop limit that reached zero, see
+    /// [`super::DeviceConfig::decrement_inner_ttl`]
+    TtlExceeded,
+}
+
+impl DropReason {
+    const ALL: [DropReason; 7] = [
+        DropReason::NoRoute,
+        DropReason::Filtered,
+        DropReason::BandwidthLimit,
+        DropReason::TooBig,
+        DropReason::Invalid,
+        DropReason::SourceNotAllowed,
+        DropReason::TtlExceeded,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            DropReason::NoRoute => "no_route",
+            DropReason::Filtered => "filtered",
+            DropReason::BandwidthLimit => "bandwidth_limit",
+            DropReason::TooBig => "too_big",
+            DropReason::Invalid => "invalid",
+            DropReason::SourceNotAllowed => "source_not_allowed",
+            DropReason::TtlExceeded => "ttl_exceeded",
+        }
+    }
+}
+
+/// The counters of the device
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    loop_iterations: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn count_drop(&self, reason: DropReason) {
+        self.dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event handled by a thread of the event loop
+    pub(crate) fn count_loop_iteration(&self) {
+        self.loop_iterations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters of a peer, in addition to those of its tunnel
+#[derive(Debug, Default)]
+pub(crate) struct PeerMetrics {
+    rx_packets: AtomicU64,
+    tx_packets: AtomicU64,
+}
+
+impl PeerMetrics {
+    /// Count a data packet from the peer, written to the tunnel
+    pub(crate) fn count_rx(&self) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a packet from the tunnel for the peer, sent or queued until a handshake completes
+    pub(crate) fn count_tx(&self) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Text in the exposition format of Prometheus
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.0.push_str(name);
+        for (i, (label, value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { '{' } else { ',' };
+            let _ = write!(self.0, "{}{}=\"{}\"", separator, label, escape(value));
+        }
+        if !labels.is_empty() {
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+impl Device {
+    pub(super) fn register_metrics_handler(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.register_http_handler(addr, |d, path| match path {
+            "/metrics" => Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: d.render_metrics(),
+            },
+            _ => Response {
+                status: "404 Not Found",
+                content_type: "text/plain",
+                body: String::new(),
+            },
+        })
+    }
+
+    /// The metrics of the device and its peers, in the exposition format of Prometheus
+    pub(super) fn render_metrics(&self) -> String {
+        let interface = self.iface.name().unwrap_or_default();
+        let device = [("interface", interface.as_str())];
+        let mut text = Exposition(String::new());
+
+        text.family("boringtun_peers", "gauge", "The number of peers");
+        text.sample("boringtun_peers", &device, self.peers.len() as u64);
+
+        let name = "boringtun_dropped_packets_total";
+        text.family(name, "counter", "Packets dropped by the device, by reason");
+        for reason in DropReason::ALL {
+            let count = self.metrics.dropped[reason as usize].load(Ordering::Relaxed);
+            let labels = [device[0], ("reason", reason.label())];
+            text.sample(name, &labels, count);
+        }
+        // Counted by the queue itself, see `DeviceStats::dropped_handshakes`
+        let dropped = self.handshakes.as_ref().map_or(0, |h| h.dropped());
+        text.sample(name, &[device[0], ("reason", "handshake_queue")], dropped);
+
+        let name = "boringtun_rate_limiter_under_load_seconds_total";
+        let help = "Seconds the rate limiter of handshakes answered with cookie replies";
+        text.family(name, "counter", help);
+        let under_load = self
+            .rate_limiter
+            .as_ref()
+            .map_or(0, |r| r.under_load_seconds());
+        text.sample(name, &device, under_load);
+
+        let name = "boringtun_loop_iterations_total";
+        text.family(name, "counter", "Events handled by the event loop");
+        let iterations = self.metrics.loop_iterations.load(Ordering::Relaxed);
+        text.sample(name, &device, iterations);
+
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| {
+                let peer = peer.lock();
+                let (_, tx_bytes, rx_bytes, ..) = peer.tunnel.stats();
+                let (completed, failed) = peer.tunnel.handshake_stats();
+                let values = [
+                    completed,
+                    failed,
+                    rx_bytes as u64,
+                    tx_bytes as u64,
+                    peer.metrics.rx_packets.load(Ordering::Relaxed),
+                    peer.metrics.tx_packets.load(Ordering::Relaxed),
+                ];
+                (peer_fingerprint(public_key), values)
+            })
+            .collect();
+        peers.sort();
+
+        let families = [
+            ("handshakes_completed", "Handshakes completed with the peer"),
+            (
+                "handshake_failures",
+                "Rejected handshake messages and unanswered initiations",
+            ),
+            ("rx_bytes", "Data received from the peer, in bytes"),
+            ("tx_bytes", "Data sent to the peer, in bytes"),
+            ("rx_packets", "Packets from the peer written to the tunnel"),
+            ("tx_packets", "Packets from the tunnel for the peer"),
+        ];
+        for (i, (name, help)) in families.iter().enumerate() {
+            let name = format!("boringtun_{}_total", name);
+            text.family(&name, "counter", help);
+            for (peer, values) in &peers {
+                let labels = [device[0], ("peer", peer.as_str())];
+                text.sample(&name, &labels, values[i]);
+            }
+        }
+        text.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposition() {
+        let mut text = Exposition(String::new());
+        text.family("boringtun_peers", "gauge", "The number of peers");
+        text.sample("boringtun_peers", &[("interface", "wg\"0\\\n")], 2);
+        text.sample("boringtun_up", &[], 1);
+        assert_eq!(
+            text.0,
+            "# HELP boringtun_peers The number of peers\n\
+             # TYPE boringtun_peers gauge\n\
+             boringtun_peers{interface=\"wg\\\"0\\\\\\n\"} 2\n\
+             boringtun_up 1\n"
+        );
+    }
+
+    #[test]
+    fn test_count_drop() {
+        let metrics = Metrics::default();
+        metrics.count_drop(DropReason::Invalid);
+        metrics.count_drop(DropReason::Invalid);
+        metrics.count_drop(DropReason::NoRoute);
+        let counts: Vec<_> = DropReason::ALL
+            .iter()
+            .map(|&reason| metrics.dropped[reason as usize].load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(counts, [1, 0, 0, 0, 2, 0, 0]);
+    }
+}
@@ -3,13 +3,61 @@
 
 pub mod allowed_ips;
 pub mod api;
+pub mod channel_tun;
+pub mod config;
 mod dev_lock;
+#[cfg(unix)]
 pub mod drop_privileges;
+pub mod dump;
+pub mod events;
+pub mod filter;
+mod handshake_pool;
+#[cfg(feature = "http-health")]
+pub mod health;
+#[cfg(any(feature = "http-health", feature = "metrics"))]
+mod http;
+mod icmp;
+pub mod iface;
 #[cfg(test)]
 mod integration_tests;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multipath;
+#[cfg(windows)]
+mod named_pipe;
+#[cfg(target_os = "linux")]
+mod netns;
+mod padding;
+mod pcap;
 pub mod peer;
-
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod peer_state;
+mod pmtu;
+mod resolver;
+#[cfg(target_os = "linux")]
+pub mod routes;
+#[cfg(unix)]
+pub mod sandbox;
+#[cfg(target_os = "linux")]
+pub mod seccomp;
+pub mod shaper;
+#[cfg(target_os = "linux")]
+pub mod socket_activation;
+pub mod stats_log;
+#[cfg(all(target_os = "linux", any(test, feature = "test-support")))]
+pub mod test_support;
+mod timer_queue;
+mod transport;
+#[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+mod txtime;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd"
+))]
 #[path = "kqueue.rs"]
 pub mod poll;
 
@@ -17,43 +65,108 @@ pub mod poll;
 #[path = "epoll.rs"]
 pub mod poll;
 
+#[cfg(windows)]
+#[path = "poll_windows.rs"]
+pub mod poll;
+
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 #[path = "tun_darwin.rs"]
 pub mod tun;
 
+#[cfg(target_os = "freebsd")]
+#[path = "tun_freebsd.rs"]
+pub mod tun;
+
+#[cfg(target_os = "openbsd")]
+#[path = "tun_openbsd.rs"]
+pub mod tun;
+
 #[cfg(target_os = "linux")]
 #[path = "tun_linux.rs"]
 pub mod tun;
 
+#[cfg(windows)]
+#[path = "tun_wintun.rs"]
+pub mod tun;
+
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::{self, Write as _};
-use std::mem::MaybeUninit;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::ops::RangeInclusive;
+#[cfg(unix)]
 use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Weak};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crate::noise::errors::WireGuardError;
+use crate::crypto::CryptoProvider;
+use crate::keys::PrivateKey;
 use crate::noise::handshake::parse_handshake_anon;
 use crate::noise::rate_limiter::RateLimiter;
-use crate::noise::{Packet, Tunn, TunnResult};
+use crate::noise::{Packet, Tunn, TunnAction, TunnError, REJECT_AFTER_TIME};
 use crate::x25519;
 use allowed_ips::AllowedIps;
-use parking_lot::Mutex;
-use peer::{AllowedIP, Peer};
+use api::{UapiExt, UapiExtension};
+use config::{ConfigDiff, PeerChanges, PeerConfig, WgConfig};
+use filter::PacketFilter;
+use handshake_pool::{HandshakeJob, HandshakeQueue, PacketSource, HANDSHAKE_QUEUE_CAPACITY};
+pub use iface::Tun;
+#[cfg(feature = "metrics")]
+use metrics::DropReason;
+pub use padding::PaddingMode;
+use parking_lot::{Mutex, MutexGuard};
+use peer::{AllowedIP, Peer, PeerStats};
+#[cfg(windows)]
+use poll::{AsRawFd, RawFd};
 use poll::{EventPoll, EventRef, WaitResult};
 use rand_core::{OsRng, RngCore};
-use socket2::{Domain, Protocol, Type};
+#[cfg(feature = "hickory-dns")]
+pub use resolver::HickoryResolver;
+use resolver::{Preresolved, ResolvedEndpoints};
+pub use resolver::{Resolver, SystemResolver};
+use shaper::BandwidthLimit;
+use timer_queue::TimerQueue;
+use transport::tcp::{Connector, TcpConnection, TcpLink};
+use transport::udp::{BufferSizes, UdpFactory};
+pub use transport::{
+    ConnectedTransport, Obfuscation, OuterTransport, OuterTransportFactory, OuterTransports,
+    Socks5, Socks5Credentials, TcpFraming, Transport,
+};
 use tun::TunSocket;
 
 use dev_lock::{Lock, LockReadGuard};
+use dump::DeviceDump;
+use events::{DeviceEvent, EventSubscribers, PeerEvent};
 
 const HANDSHAKE_RATE_LIMIT: u64 = 100; // The number of handshakes per second we can tolerate before using cookies
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 const MAX_ITR: usize = 100; // Number of packets to handle per handler call
+/// The packets each thread still reads from the tun device when the device shuts down, so that
+/// the shutdown ends even if the packets keep coming
+const SHUTDOWN_DRAIN_PACKETS: usize = 10 * MAX_ITR;
+/// Added to the deadlines of the timers of the tunnels, so they have passed when the timer fires
+const TIMER_SLACK: Duration = Duration::from_millis(10);
+/// How often the activity of the device is checked at most, see [`DeviceConfig::exit_on_idle`]
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The smallest MTU accepted in [`DeviceConfig::mtu`], the minimum of IPv6
+pub const MIN_MTU: u16 = 1280;
+
+/// The smallest stack accepted in [`DeviceConfig::thread_stack_size`]
+pub const MIN_THREAD_STACK_SIZE: usize = 64 * 1024;
+
+/// The deepest queue accepted in [`DeviceConfig::tun_queue_depth`]
+#[cfg(target_os = "linux")]
+pub const MAX_TUN_QUEUE_DEPTH: usize = 4096;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -80,7 +193,7 @@ pub enum Error {
     GetSockOpt(io::Error),
     #[error("{0}")]
     GetSockName(String),
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", windows))]
     #[error("{0}")]
     Timer(io::Error),
     #[error("iface read: {0}")]
@@ -89,6 +202,51 @@ pub enum Error {
     DropPrivileges(String),
     #[error("API socket error: {0}")]
     ApiSocket(io::Error),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("interface configuration: {0}")]
+    InterfaceConfig(String),
+    #[error("limit exceeded: {0}")]
+    LimitExceeded(String),
+    /// The device already has [`Limits::max_peers`] peers
+    #[error("too many peers, the limit is {0}")]
+    TooManyPeers(usize),
+    #[error("overlapping allowed IPs: {0}")]
+    OverlappingAllowedIps(String),
+    #[error("UAPI: {0}")]
+    Uapi(api::UapiError),
+    #[cfg(feature = "mdns")]
+    #[error("mdns: {0}")]
+    Mdns(String),
+    #[cfg(unix)]
+    #[error("sandbox: {0}")]
+    Sandbox(String),
+    #[cfg(target_os = "linux")]
+    #[error("seccomp: {0}")]
+    Seccomp(String),
+    #[cfg(target_os = "linux")]
+    #[error("network namespace: {0}")]
+    Netns(io::Error),
+    #[cfg(windows)]
+    #[error("wintun: {0}")]
+    Wintun(String),
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    #[error("fwmark is not supported on this platform")]
+    FwmarkUnsupported,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShutdownError {
+    #[error("{0} threads did not exit in time")]
+    Timeout(usize),
+    #[error("{0} threads panicked")]
+    Panicked(usize),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyReloadError {
+    #[error("the private key is already in use")]
+    KeyUnchanged,
 }
 
 // What the event loop should do after a handler returns
@@ -96,35 +254,456 @@ enum Action {
     Continue, // Continue the loop
     Yield,    // Yield the read lock and acquire it again
     Exit,     // Stop the loop
+    Leave,    // Stop the loop of this thread only
 }
 
 // Event handler function
 type Handler = Box<dyn Fn(&mut LockReadGuard<Device>, &mut ThreadData) -> Action + Send + Sync>;
 
+/// Create sockets and devices in the network namespace of the device, see [`netns`]
+#[cfg(target_os = "linux")]
+pub(crate) fn in_netns<T: Send>(
+    config: &DeviceConfig,
+    f: impl FnOnce() -> Result<T, Error> + Send,
+) -> Result<T, Error> {
+    netns::run_in_netns(config.netns_fd, f)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn in_netns<T>(
+    _: &DeviceConfig,
+    f: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    f()
+}
+
+/// Called on every UDP socket of a device right after it is created, see
+/// [`DeviceConfig::on_socket_created`]
+#[derive(Clone)]
+pub struct SocketHook(Arc<dyn Fn(RawFd) -> io::Result<()> + Send + Sync>);
+
+impl SocketHook {
+    pub fn new(hook: impl Fn(RawFd) -> io::Result<()> + Send + Sync + 'static) -> SocketHook {
+        SocketHook(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for SocketHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SocketHook")
+    }
+}
+
+/// The peers a reload of the configuration file added, removed and modified, see
+/// [`Device::reload_config`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+/// Where a reload of the configuration file of a device is, see [`DeviceConfig::on_reload`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadStage {
+    Started,
+    Applied(ReloadSummary),
+    /// The previous configuration is left as it was, with the reason
+    Failed(String),
+}
+
+/// Called when a device starts and finishes reloading its configuration file, see
+/// [`DeviceConfig::on_reload`]
+#[derive(Clone)]
+pub struct ReloadHook(Arc<dyn Fn(ReloadStage) + Send + Sync>);
+
+impl ReloadHook {
+    pub fn new(hook: impl Fn(ReloadStage) + Send + Sync + 'static) -> ReloadHook {
+        ReloadHook(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for ReloadHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReloadHook")
+    }
+}
+
+fn write_to_tunnel(iface: &dyn Tun, packet: &[u8], src: IpAddr) {
+    match src {
+        IpAddr::V4(_) => iface.write4(packet),
+        IpAddr::V6(_) => iface.write6(packet),
+    };
+}
+
+/// A short form of the public key of a peer, to tell peers apart in logs, see
+/// [`crate::keys::PublicKey::fingerprint`]
+fn peer_fingerprint(public_key: &x25519::PublicKey) -> String {
+    crate::keys::PublicKey::from(*public_key).fingerprint()
+}
+
+/// Send `packet` to the endpoint of `peer`, for it to leave once `delay` elapsed, see [`shaper`].
+/// A packet that may be fragmented skips the connected socket, which never fragments, see
+/// [`pmtu`]. Returns the error of the send, if any, such as the endpoint refusing the packets,
+/// see [`icmp`].
+fn send_to_endpoint(
+    peer: &Peer,
+    outer: &OuterTransports,
+    packet: &[u8],
+    fragment: bool,
+    delay: Duration,
+    flow: Option<u64>,
+) -> io::Result<()> {
+    if let Some(link) = peer.tcp_link() {
+        return link.send_packet(peer.endpoint().addr, packet).map(|_| ());
+    }
+
+    let mut endpoint = peer.endpoint_mut();
+    let endpoint = &mut *endpoint;
+    let sent = if let Some(conn) = endpoint.conn.as_ref().filter(|_| !fragment) {
+        // Prefer to send using the connected socket
+        if let Some(kind) = endpoint.send_error.take() {
+            return Err(kind.into());
+        }
+        let sent = conn.send_delayed(packet, delay);
+        if let Err(e) = &sent {
+            if e.raw_os_error() == Some(libc::EMSGSIZE) {
+                // The kernel learned a smaller path MTU, the next packets are checked against it
+                let mtu = conn.path_mtu();
+                tracing::debug!(message = "Packet too big for the path", path_mtu = ?mtu);
+                endpoint.path_mtu = mtu.map(|mtu| (mtu, Instant::now()));
+            }
+        }
+        sent
+    } else if let Some(addr) = endpoint.addr {
+        let transport = match flow {
+            Some(flow) => outer.for_flow(&addr, flow),
+            None => outer.for_addr(&addr),
+        };
+        transport.send_to_delayed(packet, addr, delay)
+    } else {
+        tracing::error!("No endpoint");
+        return Ok(());
+    };
+    sent.map(|_| ())
+}
+
 pub struct DeviceHandle {
     device: Arc<Lock<Device>>, // The interface this handle owns
     threads: Vec<JoinHandle<()>>,
+    handshake_threads: Vec<JoinHandle<()>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The settings of a device, see [`DeviceHandle::new`]. It used to be `Copy`, and is only
+/// `Clone` since it holds paths, such as [`DeviceConfig::peer_state_file`], and hooks: a
+/// configuration that is used again after being passed to a device has to be cloned first.
+#[derive(Debug, Clone)]
 pub struct DeviceConfig {
     pub n_threads: usize,
+    /// The number of threads that process the handshakes received from the network, apart from
+    /// the `n_threads` that forward the data, through a bounded queue that drops handshakes when
+    /// they arrive faster than they are processed. With 0, or with a single thread in total,
+    /// handshakes are processed inline instead.
+    pub handshake_threads: usize,
+    /// The size of the stack of the threads of the device, the event loops and the handshake
+    /// threads, instead of the default of Rust, 2 MiB unless `RUST_MIN_STACK` says otherwise. At
+    /// least [`MIN_THREAD_STACK_SIZE`], which is enough for an optimized build, the buffers of the
+    /// threads are on the heap. A smaller stack saves memory on embedded systems, a larger one
+    /// leaves room for a deep [`PacketFilter`] or [`CryptoProvider`].
+    pub thread_stack_size: Option<usize>,
+    /// Receive the packets of each peer on a UDP socket connected to its endpoint. Not supported
+    /// on Windows, where it must be false.
     pub use_connected_socket: bool,
     #[cfg(target_os = "linux")]
     pub use_multi_queue: bool,
+    /// The number of packets each queue of the TUN device holds until a thread reads them, its
+    /// `txqueuelen`, from 1 to [`MAX_TUN_QUEUE_DEPTH`]. Left at the default of 500 when `None`.
+    /// A deeper queue loses fewer packets in bursts from the tunnel, at the cost of up to about
+    /// `depth * MTU` bytes of kernel memory per queue, with a queue per thread under
+    /// `use_multi_queue`.
+    #[cfg(target_os = "linux")]
+    pub tun_queue_depth: Option<usize>,
     #[cfg(target_os = "linux")]
     pub uapi_fd: i32,
+    /// A listening unix stream socket to serve the UAPI on, instead of creating
+    /// `/var/run/wireguard/<iface>.sock`. The device takes ownership of it, and leaves its path in
+    /// place. When it is `None`, [`DeviceHandle::new`] uses the one systemd passed for the
+    /// interface if there is one, see [`socket_activation`].
+    #[cfg(target_os = "linux")]
+    pub uapi_listen_fd: Option<RawFd>,
+    /// Install a seccomp-BPF allowlist after dropping privileges, see [`seccomp`]
+    #[cfg(target_os = "linux")]
+    pub enable_seccomp: bool,
+    /// Chroot to this empty directory when privileges are dropped, see [`sandbox`]. The
+    /// configuration file and the peer state file can't be used with it.
+    #[cfg(unix)]
+    pub sandbox: Option<PathBuf>,
+    /// A network namespace to create the TUN device and the UDP sockets in, instead of the one of
+    /// the process. The threads of the device stay in their own namespace.
+    #[cfg(target_os = "linux")]
+    pub netns_fd: Option<RawFd>,
+    /// A utun descriptor to use instead of creating an interface, such as the one of the packet
+    /// flow of a Network Extension. The device takes ownership of it, and the name given to
+    /// [`DeviceHandle::new`] is only used as the name of the interface. No ioctl is made on it, so
+    /// it can't be configured and its MTU is assumed to be 1500. Inside the sandbox the UAPI socket
+    /// can't be created either, the device is then configured with [`DeviceHandle::apply_config`].
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub tun_fd: Option<RawFd>,
+    /// Queues of the TUN device to use instead of opening them, such as those a privileged
+    /// launcher opened, see [`tun::TunSocket::from_fds`]. The device takes ownership of them. The
+    /// packets of every queue are read, and the threads take turns writing to them, whether there
+    /// are more threads or more queues. No other queue is opened, whatever `use_multi_queue` is.
+    #[cfg(target_os = "linux")]
+    pub tun_fds: Vec<RawFd>,
+    /// Whether the packets of `tun_fd` start with the 4 byte address family, as on the utun
+    /// devices of the kernel
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub tun_af_prefix: bool,
+    /// Addresses to add to the interface once it is created
+    pub address: Vec<AllowedIP>,
+    /// The MTU of the network towards the peers, instead of the default of the system. The MTU of
+    /// the interface is set to it minus the encapsulation overhead, and it is used as the path
+    /// MTU of peers until a smaller one is learned. It must be at least [`MIN_MTU`].
+    pub mtu: Option<u16>,
+    /// Bring the interface up once it is created
+    pub bring_up: bool,
+    /// Pad the packets of the tunnel before encrypting them, without exceeding the MTU of the
+    /// interface or the path MTU of the peer, see [`PaddingMode`]
+    pub padding_mode: PaddingMode,
+    /// Count the device as a hop of the packets of the tunnel, so it shows in `traceroute`: their
+    /// TTL or hop limit is decremented before they are encrypted, and those where it reaches zero
+    /// are dropped with an ICMP "time exceeded" sent back, from the first address of the family
+    /// of the packet in `address` or from its destination otherwise
+    pub decrement_inner_ttl: bool,
+    /// How the packets of the peers are carried, see [`Transport`]
+    pub transport: Transport,
+    /// The private key the device starts with, before the API serves any request. It wins over
+    /// the `PrivateKey` of `config_file`, which is ignored with a warning if it differs.
+    pub private_key: Option<PrivateKey>,
+    /// The port the device listens on from the start, instead of a random one. It wins over the
+    /// `ListenPort` of `config_file`, which is ignored with a warning if it differs.
+    pub listen_port: Option<u16>,
+    /// A configuration file in the `wg setconf` format, applied again with the semantics of
+    /// `wg syncconf` on `SIGHUP` and by [`DeviceHandle::trigger_reload`]. Windows has no
+    /// `SIGHUP`, only the latter applies. Without a file, `SIGHUP` is ignored.
+    pub config_file: Option<PathBuf>,
+    /// How often the host names of the endpoints of peers are resolved again, to follow changes
+    /// of their addresses. When `None`, they are only resolved again when a peer stops responding.
+    pub dns_recheck_interval: Option<Duration>,
+    /// How long a handshake waits for a response before peers with several endpoints fail over
+    /// to the next one, see [`Peer::set_failover_endpoints`]
+    pub handshake_timeout: Duration,
+    /// Shut the device down gracefully, as [`DeviceHandle::initiate_shutdown`] does, once no data
+    /// packet was sent or received with any peer for this long. Keepalives and handshakes don't
+    /// count, and neither do UAPI connections, which start the period over. For devices started on
+    /// demand, such as by the socket activation of systemd.
+    pub exit_on_idle: Option<Duration>,
+    /// Initiate a new handshake this long before the current session of a peer expires if it
+    /// carried data, whichever side initiated the session, so the traffic never waits for a new
+    /// session. See [`Tunn::set_proactive_rekey_lead_time`].
+    pub proactive_rekey_lead_time: Option<Duration>,
+    /// A file where the last known endpoints of peers are saved periodically and on shutdown.
+    /// Peers that are added without an endpoint use the one from this file.
+    pub peer_state_file: Option<PathBuf>,
+    /// Entries of the peer state file from peers that didn't have a handshake for longer are
+    /// ignored
+    pub peer_state_max_age: Duration,
+    /// A pcap file where the packets of the tunnel are captured, such as for Wireshark: the raw IP
+    /// packets received once decrypted, and those sent before they are encrypted. The file is
+    /// truncated when the device starts. Only the packets of the peers with
+    /// [`PeerConfig::pcap_capture`] are captured.
+    pub pcap_path: Option<PathBuf>,
+    /// Move the capture file of `pcap_path` to `<pcap_path>.1` and start a new one before it
+    /// grows past this size, in bytes. By default it grows without bound.
+    pub pcap_max_bytes: Option<u64>,
+    /// Inspects the packets exchanged with the peers, and drops those it rejects
+    pub packet_filter: Option<Arc<dyn PacketFilter + Send + Sync>>,
+    /// Encrypts and decrypts the transport data of all peers, instead of the built-in
+    /// implementation, see [`crate::crypto`]
+    pub crypto_provider: Option<Arc<dyn CryptoProvider>>,
+    /// Ports to try in order when the device binds without a listen port, at startup or when it
+    /// is set to 0, instead of a random one. Binding fails if none of them is free.
+    pub listen_port_range: Option<RangeInclusive<u16>>,
+    /// Bounds on the number of peers and allowed IPs, they can be changed later with
+    /// [`DeviceHandle::set_limits`]
+    pub limits: Limits,
+    /// Let a `set` operation of the UAPI give a peer allowed IPs that contain, or are contained
+    /// in, those of another peer, which is then only routed the addresses its allowed IPs match
+    /// best. By default the operation fails with `EEXIST` instead.
+    pub allow_overlapping_ips: bool,
+    /// On top of the allowed IPs of the peer, which packets from other source addresses are
+    /// always dropped for, drop the packets from an address the device routes to another peer,
+    /// counted in [`PeerStats::rx_drops_allowed_ips`] as well. Only matters with
+    /// [`DeviceConfig::allow_overlapping_ips`], where a hub peer allowed `10.0.0.0/8` could
+    /// otherwise send from the `10.1.0.0/16` of a more specific peer.
+    pub strict_source_check: bool,
+    /// The handshake initiations each source IP may send per second, the others are answered with
+    /// cookie replies even when the device is not under load. 0 means unlimited.
+    pub max_handshake_initiations_per_second: u32,
+    /// Carries the datagrams of the peers, UDP sockets when `None`, see [`OuterTransport`]
+    pub outer_transport: Option<Arc<dyn OuterTransportFactory>>,
+    /// Set `SO_REUSEPORT` on the UDP sockets, so several boringtun processes can listen on the
+    /// same port and the kernel spreads the datagrams between them. Each datagram of a peer must
+    /// reach the process that holds its sessions, the kernel hashes the addresses of the datagram
+    /// to pick a socket. Not supported on Windows, and ignored with an `outer_transport`.
+    ///
+    /// The kernel implementation of WireGuard and boringtun shouldn't share a port this way,
+    /// unless a kernel module coordinates their sessions: each would drop the datagrams of the
+    /// sessions of the other.
+    pub reuse_port: bool,
+    /// The local addresses of the UDP sockets, one socket per address, their port is the listen
+    /// port when 0. By default a socket of each family is bound to the unspecified address. With
+    /// several addresses of a family, to bond the links of several NICs or ISPs, the packets from
+    /// the tunnel to an endpoint of that family are spread over their sockets by a hash of their
+    /// 5-tuple, so a connection stays on the same path. The peers then see several endpoints for
+    /// the device, and reply to the one they last heard from. Datagrams are received on all the
+    /// sockets, and connected sockets are not used. Ignored with an `outer_transport`.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// The size of the receive buffer of the UDP sockets, `SO_RCVBUF`, left to the system when
+    /// `None`. Bursts of traffic over a few Gbps overflow the usual default of around 200 KiB.
+    /// The kernel caps the size, at `net.core.rmem_max` on Linux unless the process still has
+    /// `CAP_NET_ADMIN` to set `SO_RCVBUFFORCE`. The size it applied is logged at the debug level
+    /// and reported by [`DeviceHandle::stats`]. Ignored with an `outer_transport`.
+    pub udp_recv_buffer_size: Option<usize>,
+    /// The size of the send buffer of the UDP sockets, `SO_SNDBUF`, left to the system when
+    /// `None`. While the network towards a peer is congested, the packets queue in this buffer,
+    /// once it is full the sends fail and the packets are lost. Capped at `net.core.wmem_max` on
+    /// Linux without `CAP_NET_ADMIN`, like [`DeviceConfig::udp_recv_buffer_size`].
+    pub udp_send_buffer_size: Option<usize>,
+    /// Disguise the datagrams of the outer transport in the manner of AmneziaWG, see
+    /// [`Obfuscation`]. The default sends standard WireGuard datagrams.
+    pub obfuscation: Obfuscation,
+    /// Log the public keys of the peers in full. By default the logs only have their
+    /// [`crate::keys::PublicKey::fingerprint`], enough to tell the peers apart without
+    /// identifying them.
+    pub log_public_keys: bool,
+    /// Report the drop counters of the device and its peers in the response to a UAPI `get`, as
+    /// the `bt_` keys described in [`api`]
+    pub uapi_drop_counters: bool,
+    /// Called on every UDP socket of the device right after it is created, before any packet is
+    /// sent, including the sockets opened again when the listen port changes. On Android this is
+    /// where sockets are passed to `VpnService.protect()`, so their traffic bypasses the tunnel.
+    /// The socket is not used if it returns an error.
+    pub on_socket_created: Option<SocketHook>,
+    /// Called before and after the configuration file is reloaded, on `SIGHUP` or with
+    /// [`DeviceHandle::trigger_reload`]. It runs on the thread that reloads, outside of the lock of
+    /// the write lock of the device.
+    pub on_reload: Option<ReloadHook>,
+    /// Log a snapshot of the statistics of the device and its peers on `SIGUSR1`, see
+    /// [`stats_log`]
+    #[cfg(unix)]
+    pub log_stats_on_sigusr1: bool,
+    /// Serve HTTP health checks on this address, see [`health`]
+    #[cfg(feature = "http-health")]
+    pub health_check_addr: Option<SocketAddr>,
+    /// Advertise the endpoint of the device with mDNS, and let the peers that opt in follow the
+    /// endpoints advertised on the local network, see [`mdns`]
+    #[cfg(feature = "mdns")]
+    pub mdns: bool,
+    /// Serve the counters of the device and its peers on `/metrics` at this address, in the
+    /// exposition format of Prometheus
+    #[cfg(feature = "metrics")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+/// Bounds on the size of the tables of a device, `None` means unlimited. A limit only prevents
+/// new entries from being added, lowering it below the current number of entries doesn't remove
+/// any of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_peers: Option<usize>,
+    pub max_allowed_ips_per_peer: Option<usize>,
+    /// The number of allowed IP entries across all peers
+    pub max_allowed_ips: Option<usize>,
+}
+
+/// The current size of the tables of a device, to compare with its [`Limits`], and the state of
+/// its sockets
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub peers: usize,
+    /// The peers that had a handshake recently enough for their session to still be valid
+    pub connected_peers: usize,
+    pub allowed_ips: usize,
+    /// The number of handshakes dropped because too many were waiting to be processed
+    pub dropped_handshakes: u64,
+    /// The number of events dropped because a stream of `DeviceHandle::events` was full
+    pub dropped_events: u64,
+    /// The sizes of the receive and send buffers the kernel gave the IPv4 UDP socket, see
+    /// [`DeviceConfig::udp_recv_buffer_size`]. `None` with an `outer_transport`.
+    pub udp_recv_buffer_size: Option<usize>,
+    pub udp_send_buffer_size: Option<usize>,
 }
 
 impl Default for DeviceConfig {
     fn default() -> Self {
         DeviceConfig {
             n_threads: 4,
-            use_connected_socket: true,
+            thread_stack_size: None,
+            handshake_threads: 1,
+            use_connected_socket: cfg!(not(windows)),
             #[cfg(target_os = "linux")]
             use_multi_queue: true,
             #[cfg(target_os = "linux")]
+            tun_queue_depth: None,
+            #[cfg(target_os = "linux")]
             uapi_fd: -1,
+            #[cfg(target_os = "linux")]
+            uapi_listen_fd: None,
+            #[cfg(target_os = "linux")]
+            enable_seccomp: false,
+            #[cfg(unix)]
+            sandbox: None,
+            #[cfg(target_os = "linux")]
+            netns_fd: None,
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            tun_fd: None,
+            #[cfg(target_os = "linux")]
+            tun_fds: vec![],
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            tun_af_prefix: true,
+            address: vec![],
+            mtu: None,
+            bring_up: false,
+            padding_mode: PaddingMode::None,
+            decrement_inner_ttl: false,
+            transport: Transport::Udp,
+            private_key: None,
+            listen_port: None,
+            config_file: None,
+            dns_recheck_interval: Some(Duration::from_secs(60)),
+            handshake_timeout: Duration::from_secs(5),
+            exit_on_idle: None,
+            proactive_rekey_lead_time: Some(Duration::from_secs(10)),
+            peer_state_file: None,
+            peer_state_max_age: Duration::from_secs(24 * 60 * 60),
+            pcap_path: None,
+            pcap_max_bytes: None,
+            packet_filter: None,
+            crypto_provider: None,
+            listen_port_range: None,
+            limits: Limits::default(),
+            allow_overlapping_ips: false,
+            strict_source_check: false,
+            max_handshake_initiations_per_second: 10,
+            outer_transport: None,
+            reuse_port: false,
+            bind_addrs: vec![],
+            udp_recv_buffer_size: None,
+            udp_send_buffer_size: None,
+            obfuscation: Obfuscation::default(),
+            log_public_keys: false,
+            uapi_drop_counters: true,
+            on_socket_created: None,
+            on_reload: None,
+            #[cfg(unix)]
+            log_stats_on_sigusr1: false,
+            #[cfg(feature = "http-health")]
+            health_check_addr: None,
+            #[cfg(feature = "mdns")]
+            mdns: false,
+            #[cfg(feature = "metrics")]
+            metrics_listen: None,
         }
     }
 }
@@ -136,12 +715,34 @@ pub struct Device {
     listen_port: u16,
     fwmark: Option<u32>,
 
-    iface: Arc<TunSocket>,
-    udp4: Option<socket2::Socket>,
-    udp6: Option<socket2::Socket>,
+    iface: Arc<dyn Tun>,
+    /// The queues of [`DeviceConfig::tun_fds`] after the first one, which is `iface`
+    #[cfg(target_os = "linux")]
+    tun_queues: Vec<Arc<dyn Tun>>,
+    outer: Option<OuterTransports>,
+    /// The sizes the kernel gave the buffers of the UDP sockets, see [`DeviceStats`]
+    udp_buffer_sizes: BufferSizes,
+    /// The obfuscation of the outer transports, they are opened again when it changes
+    obfuscation: Obfuscation,
 
     yield_notice: Option<EventRef>,
     exit_notice: Option<EventRef>,
+    shutdown_notice: Option<EventRef>,
+    /// Triggered when the handshake threads have replies to send
+    handshake_notice: Option<EventRef>,
+    /// Fires at the earliest deadline in `timers`
+    timer_event: Option<EventRef>,
+    timers: Mutex<TimerQueue>,
+    /// The number of times the timer event fired, never while no timer of a peer is due
+    #[cfg(test)]
+    timer_wakeups: AtomicUsize,
+
+    shutting_down: AtomicBool,
+    /// Set by the data packets and the UAPI connections, cleared by the idle check, see
+    /// [`DeviceConfig::exit_on_idle`]
+    active: AtomicBool,
+    /// The number of threads that drained their queue of packets during the shutdown
+    drained_threads: AtomicUsize,
 
     peers: HashMap<x25519::PublicKey, Arc<Mutex<Peer>>>,
     peers_by_ip: AllowedIps<Arc<Mutex<Peer>>>,
@@ -151,42 +752,143 @@ pub struct Device {
     config: DeviceConfig,
 
     cleanup_paths: Vec<String>,
+    /// The path of the UAPI socket, when the device created it
+    #[cfg(unix)]
+    uapi_path: Option<String>,
+    /// The rules added for default routes, deleted when the device is dropped, see [`routes`]
+    #[cfg(target_os = "linux")]
+    policy_rules: Mutex<Vec<routes::PolicyRule>>,
 
     mtu: AtomicUsize,
 
     rate_limiter: Option<Arc<RateLimiter>>,
+    /// The handshakes waiting for the handshake threads, `None` when they are processed inline
+    handshakes: Option<Arc<HandshakeQueue>>,
+    /// The event loops and handshake threads that are running
+    running: stats_log::RunningThreads,
+
+    uapi_extensions: Vec<UapiExtension>,
+
+    resolver: Arc<dyn Resolver>,
+
+    events: EventSubscribers,
+
+    /// Endpoints loaded from the peer state file, for peers that were not configured yet
+    restored_endpoints: HashMap<x25519::PublicKey, SocketAddr>,
+    /// Writes the peer state file, when there is one
+    peer_state: Option<Arc<peer_state::PeerStateSaver>>,
+
+    /// Set when the packets of the tunnel are captured, see [`DeviceConfig::pcap_path`]
+    pcap: Option<Mutex<pcap::PcapWriter>>,
+
+    /// Set when the device advertises and discovers endpoints, see [`DeviceConfig::mdns`]
+    #[cfg(feature = "mdns")]
+    mdns: Option<mdns::Mdns>,
+
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
 
     #[cfg(target_os = "linux")]
     uapi_fd: i32,
 }
 
+/// The peers set aside by a `replace_peers` set, see [`Device::begin_replace_peers`]
+struct ReplacedPeers(HashMap<x25519::PublicKey, Arc<Mutex<Peer>>>);
+
+impl ReplacedPeers {
+    /// Take the peer with `pub_key` back for the new peer set, if its preshared key didn't
+    /// change. Otherwise it is added again with new sessions.
+    fn take_unchanged(
+        &mut self,
+        pub_key: &x25519::PublicKey,
+        preshared_key: Option<[u8; 32]>,
+    ) -> Option<Arc<Mutex<Peer>>> {
+        let peer = self.0.get(pub_key)?;
+        if peer.lock().preshared_key() != preshared_key.as_ref() {
+            return None;
+        }
+        self.0.remove(pub_key)
+    }
+}
+
 struct ThreadData {
-    iface: Arc<TunSocket>,
-    src_buf: [u8; MAX_UDP_SIZE],
-    dst_buf: [u8; MAX_UDP_SIZE],
+    iface: Arc<dyn Tun>,
+    src_buf: Box<[u8]>,
+    dst_buf: Box<[u8]>,
 }
 
 impl DeviceHandle {
     pub fn new(name: &str, config: DeviceConfig) -> Result<DeviceHandle, Error> {
-        let n_threads = config.n_threads;
-        let mut wg_interface = Device::new(name, config)?;
-        wg_interface.open_listen_socket(0)?; // Start listening on a random port
+        let wg_interface = Device::new(name, config)?;
+        DeviceHandle::start(wg_interface)
+    }
+
+    /// Create a device that exchanges the packets of the tunnel with `iface` instead of a TUN
+    /// device of the platform, see [`Tun`]
+    pub fn with_tun(iface: Arc<dyn Tun>, config: DeviceConfig) -> Result<DeviceHandle, Error> {
+        let wg_interface = Device::with_tun(iface, config)?;
+        DeviceHandle::start(wg_interface)
+    }
+
+    fn start(mut wg_interface: Device) -> Result<DeviceHandle, Error> {
+        let n_threads = wg_interface.config.n_threads;
+        let stack_size = wg_interface.config.thread_stack_size;
+        if let Some(private_key) = wg_interface.config.private_key.clone() {
+            wg_interface.set_key(private_key.into());
+        }
+        // Start listening on the port of the configuration, or on a random one
+        wg_interface.open_listen_socket(wg_interface.config.listen_port.unwrap_or(0))?;
 
         let interface_lock = Arc::new(Lock::new(wg_interface));
+        let spawn = |name: String, f: Box<dyn FnOnce() + Send>| {
+            let builder = thread::Builder::new().name(name);
+            match stack_size {
+                Some(size) => builder.stack_size(size),
+                None => builder,
+            }
+            .spawn(f)
+            .map_err(Error::IoError)
+        };
 
-        let mut threads = vec![];
+        // Dropped on failure, which stops the threads that were spawned already
+        let mut handle = DeviceHandle {
+            device: Arc::clone(&interface_lock),
+            threads: vec![],
+            handshake_threads: vec![],
+        };
 
+        let running = Arc::clone(&interface_lock.read().running.event_loops);
         for i in 0..n_threads {
-            threads.push({
-                let dev = Arc::clone(&interface_lock);
-                thread::spawn(move || DeviceHandle::event_loop(i, &dev))
-            });
+            let dev = Arc::clone(&interface_lock);
+            let running = Arc::clone(&running);
+            let thread = spawn(
+                format!("event-loop-{}", i),
+                Box::new(move || {
+                    let _running = stats_log::RunningThread::new(&running);
+                    DeviceHandle::event_loop(i, &dev)
+                }),
+            )?;
+            handle.threads.push(thread);
         }
 
-        Ok(DeviceHandle {
-            device: interface_lock,
-            threads,
-        })
+        if let Some(handshakes) = interface_lock.read().handshakes.clone() {
+            let running = Arc::clone(&interface_lock.read().running.handshakes);
+            for i in 0..interface_lock.read().config.handshake_threads {
+                let dev = Arc::downgrade(&interface_lock);
+                let handshakes = Arc::clone(&handshakes);
+                let running = Arc::clone(&running);
+                let thread = spawn(
+                    format!("handshake-{}", i),
+                    Box::new(move || {
+                        let _running = stats_log::RunningThread::new(&running);
+                        DeviceHandle::handshake_loop(&dev, &handshakes)
+                    }),
+                )?;
+                handle.handshake_threads.push(thread);
+            }
+        }
+
+        Ok(handle)
     }
 
     pub fn wait(&mut self) {
@@ -195,6 +897,340 @@ impl DeviceHandle {
         }
     }
 
+    /// Wait up to `timeout` for the device to stop, like [`DeviceHandle::wait`]. Returns whether
+    /// all the event loops exited.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.threads.iter().any(|t| !t.is_finished()) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.wait();
+        true
+    }
+
+    /// Return once every event loop finished the event it was handling and yielded, which tells
+    /// that none of them is stuck. This blocks for as long as one is.
+    pub fn sync_event_loops(&self) {
+        self.device.read().try_writeable(
+            |device| device.trigger_yield(),
+            |device| device.cancel_yield(),
+        );
+    }
+
+    /// Ask the device to shut down, without waiting for it. Each thread sends the packets that
+    /// are still queued on the tun device, up to a thousand, then a keepalive is sent to every
+    /// peer with a session, and the threads exit, which is when [`DeviceHandle::wait`] returns.
+    pub fn initiate_shutdown(&self) {
+        self.device.read().trigger_shutdown();
+    }
+
+    /// Shut the device down gracefully, see [`DeviceHandle::initiate_shutdown`], and wait up to
+    /// `timeout` for all threads to exit, the handshake threads included once the event loops
+    /// are done. The threads that are still running on timeout are kept, and can be waited for
+    /// again.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> Result<(), ShutdownError> {
+        self.initiate_shutdown();
+
+        let deadline = Instant::now() + timeout;
+        let mut closed = false;
+        loop {
+            let event_loops = self.threads.iter().filter(|t| !t.is_finished()).count();
+            // The event loops queue no more handshakes once they exited
+            if event_loops == 0 && !closed {
+                if let Some(handshakes) = &self.device.read().handshakes {
+                    handshakes.close();
+                }
+                closed = true;
+            }
+            let handshakes = self.handshake_threads.iter();
+            let running = event_loops + handshakes.filter(|t| !t.is_finished()).count();
+            if running == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(ShutdownError::Timeout(running));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let panicked = self
+            .threads
+            .drain(..)
+            .chain(self.handshake_threads.drain(..))
+            .filter_map(|t| t.join().err())
+            .count();
+        match panicked {
+            0 => Ok(()),
+            n => Err(ShutdownError::Panicked(n)),
+        }
+    }
+
+    /// Drop the privileges of the process, entering the sandbox on the way, then install the
+    /// seccomp filter if they are enabled in the config
+    #[cfg(unix)]
+    pub fn drop_privileges(&self) -> Result<(), Error> {
+        self.drop_privileges_to(&Default::default())
+    }
+
+    /// Like [`DeviceHandle::drop_privileges`], but switch to the account of `target` and keep its
+    /// capabilities. When the user or the group of `target` is given, the UAPI socket created by
+    /// the device is handed over to them first, for the members of the group to configure it.
+    #[cfg(unix)]
+    pub fn drop_privileges_to(&self, target: &drop_privileges::DropTarget) -> Result<(), Error> {
+        // Once the process switches to them, the account can still configure the device and
+        // remove the socket on exit
+        if target.is_explicit() {
+            let (uid, gid) = target.resolve()?;
+            self.device.read().chown_api_socket(uid, gid)?;
+        }
+
+        let sandbox = self.device.read().config.sandbox.clone();
+        drop_privileges::drop_privileges_in(target, sandbox.as_deref())?;
+
+        #[cfg(target_os = "linux")]
+        if self.device.read().config.enable_seccomp {
+            seccomp::apply_seccomp_filter()?;
+        }
+
+        Ok(())
+    }
+
+    /// Register an extension that handles UAPI keys starting with `prefix`, see
+    /// [`Device::register_uapi_extension`].
+    pub fn register_uapi_extension(&self, prefix: &str, handler: Box<dyn UapiExt>) {
+        self.device.read().try_writeable(
+            |device| device.trigger_yield(),
+            |device| {
+                device.cancel_yield();
+                device.register_uapi_extension(prefix, handler)
+            },
+        );
+    }
+
+    /// The UDP port the device listens on, the one the system picked when it was set to 0
+    pub fn listen_port(&self) -> u16 {
+        self.device.read().listen_port
+    }
+
+    /// The public key of the device, `None` until a private key is set
+    pub fn local_public_key(&self) -> Option<x25519::PublicKey> {
+        self.device
+            .read()
+            .key_pair
+            .as_ref()
+            .map(|(_, public_key)| *public_key)
+    }
+
+    /// The public key of the device in the short form of
+    /// [`crate::keys::PublicKey::fingerprint`], the one the peers have in the logs, to tell the
+    /// key of the device apart without showing it in full. `(none)` until a private key is set,
+    /// as `wg show` puts it.
+    pub fn private_key_fingerprint(&self) -> String {
+        match self.local_public_key() {
+            Some(public_key) => peer_fingerprint(&public_key),
+            None => "(none)".to_owned(),
+        }
+    }
+
+    /// The name of the interface, the one the system picked if it was given a pattern such as
+    /// `utun`
+    pub fn interface_name(&self) -> Result<String, Error> {
+        self.device.read().iface.name()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.device.read().config.limits
+    }
+
+    /// Replace the limits of the device, existing entries over the new limits are kept
+    pub fn set_limits(&self, limits: Limits) {
+        self.device.read().try_writeable(
+            |device| device.trigger_yield(),
+            |device| {
+                device.cancel_yield();
+                device.config.limits = limits
+            },
+        );
+    }
+
+    pub fn stats(&self) -> DeviceStats {
+        self.device.read().stats()
+    }
+
+    /// A snapshot of the state of the device and its peers, see [`dump`]
+    pub fn dump(&self) -> DeviceDump {
+        self.device.read().dump()
+    }
+
+    /// [`DeviceHandle::dump`] as a JSON document
+    pub fn dump_json(&self) -> String {
+        serde_json::to_string(&self.dump()).expect("The dump serializes to JSON")
+    }
+
+    /// The peer that packets sent to `addr` are routed to, by longest prefix match over the
+    /// allowed IPs of the peers, like the tun handler does
+    pub fn peer_for_ip(&self, addr: IpAddr) -> Option<x25519::PublicKey> {
+        let device = self.device.read();
+        let peer = device.peers_by_ip.find(addr)?;
+        let public_key = peer.lock().tunnel.peer_static_public();
+        Some(public_key)
+    }
+
+    /// A snapshot of the statistics of a peer, `None` if the peer is unknown
+    pub fn peer_stats(&self, public_key: &x25519::PublicKey) -> Option<PeerStats> {
+        let device = self.device.read();
+        let stats = device.peers.get(public_key)?.lock().stats();
+        Some(stats)
+    }
+
+    /// The allowed IPs of a peer, empty if the peer is unknown
+    pub fn allowed_ips_of(&self, public_key: &x25519::PublicKey) -> Vec<AllowedIP> {
+        match self.device.read().peers.get(public_key) {
+            Some(peer) => peer
+                .lock()
+                .allowed_ips()
+                .map(|(addr, cidr)| AllowedIP { addr, cidr })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Limit the rate of the traffic sent to a peer, see [`Peer::set_bandwidth_limit`]
+    pub fn set_bandwidth_limit(
+        &self,
+        public_key: &x25519::PublicKey,
+        limit: Option<BandwidthLimit>,
+    ) -> Result<(), Error> {
+        match self.device.read().peers.get(public_key) {
+            Some(peer) => {
+                peer.lock().set_bandwidth_limit(limit);
+                Ok(())
+            }
+            None => Err(Error::InvalidConfig("Unknown peer".to_owned())),
+        }
+    }
+
+    /// Subscribe to the events of the peers of the device. Events are queued until they are
+    /// received, and are no longer sent once the receiver is dropped.
+    pub fn peer_events(&self) -> mpsc::Receiver<PeerEvent> {
+        self.device.read().events.subscribe()
+    }
+
+    /// Subscribe to the events of the device and of its peers, as a stream that starts with
+    /// [`DeviceEvent::DeviceStarted`] and ends when the device is dropped. A stream that is not
+    /// polled fast enough drops its oldest events, see [`events`]. Dropping the stream
+    /// unsubscribes from the events.
+    #[cfg(feature = "async-events")]
+    pub fn events(&self) -> events::EventStream {
+        self.device.read().events.stream(DeviceEvent::DeviceStarted)
+    }
+
+    /// Replace the resolver used for endpoints that are configured with a host name
+    pub fn set_resolver(&self, resolver: Arc<dyn Resolver>) {
+        self.device.read().try_writeable(
+            |device| device.trigger_yield(),
+            |device| {
+                device.cancel_yield();
+                device.resolver = resolver
+            },
+        );
+    }
+
+    /// Route `routes` through the interface, as `wg-quick` does for the allowed IPs of the
+    /// peers: a default route gets a table and policy rules of its own, see [`routes`], and the
+    /// device gets the fwmark [`routes::DEFAULT_ROUTE_FWMARK`] if it had none. The interface must
+    /// be up. A route or a rule that exists already fails with an error naming it, the routes
+    /// added until then are kept.
+    #[cfg(target_os = "linux")]
+    pub fn add_routes(&self, routes: &[AllowedIP]) -> Result<(), Error> {
+        self.device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.add_routes(routes)
+                },
+            )
+            .expect("Write access is always eventually granted")
+    }
+
+    /// Replace the private key of the running device, to rotate it without a restart. The
+    /// sessions of all the peers are dropped, WireGuard has no message to tear them down, and
+    /// handshakes under the new key are initiated right away with the peers that had a session
+    /// or have a persistent keepalive. The peers must know the new public key by then, or they
+    /// reject the handshakes.
+    pub fn reload_private_key(&self, new_key: &[u8; 32]) -> Result<(), KeyReloadError> {
+        let private_key = x25519::StaticSecret::from(*new_key);
+        self.device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.reload_private_key(private_key)
+                },
+            )
+            .expect("Write access is always eventually granted")
+    }
+
+    /// Bring the device to the state described by `config`, with the semantics of `wg syncconf`.
+    /// See [`Device::apply_config`]. The host names of the endpoints are resolved before the
+    /// device is locked for writing.
+    pub fn apply_config(&self, config: WgConfig) -> Result<(), Error> {
+        let mut device = self.device.read();
+        let resolved = device.resolve_ahead(
+            config
+                .peers
+                .iter()
+                .map(|p| (&p.public_key, p.endpoint.as_deref())),
+        );
+        device
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.sync_config(config, &resolved).map(|_| ())
+                },
+            )
+            .expect("Write access is always eventually granted")
+    }
+
+    /// Apply the changes of a [`ConfigDiff`] to the peers, under a single write lock. See
+    /// [`Device::apply_diff`]. The host names of the endpoints are resolved before the device is
+    /// locked for writing.
+    pub fn apply_diff(&self, diff: ConfigDiff) -> Result<(), Error> {
+        let mut device = self.device.read();
+        let resolved = device.resolve_ahead(
+            diff.added
+                .iter()
+                .map(|p| (&p.public_key, p.endpoint.as_deref()))
+                .chain(
+                    diff.modified
+                        .iter()
+                        .map(|(key, changes)| (key, changes.endpoint.as_deref())),
+                ),
+        );
+        device
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    device.apply_diff_with(diff, &resolved)
+                },
+            )
+            .expect("Write access is always eventually granted")
+    }
+
+    /// Read the configuration file of the device again and apply it, like on `SIGHUP`. See
+    /// [`Device::reload_config`].
+    pub fn trigger_reload(&self) -> Result<(), Error> {
+        Device::reload(&mut self.device.read())
+    }
+
     pub fn clean(&mut self) {
         for path in &self.device.read().cleanup_paths {
             // attempt to remove any file we created in the work dir
@@ -205,38 +1241,38 @@ impl DeviceHandle {
     fn event_loop(_i: usize, device: &Lock<Device>) {
         #[cfg(target_os = "linux")]
         let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: if _i == 0 || !device.read().config.use_multi_queue {
+            src_buf: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
+            dst_buf: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
+            iface: if !device.read().tun_queues.is_empty() {
+                // Take turns with the queues that were passed in, the first one is the original
+                let device = device.read();
+                match _i % (device.tun_queues.len() + 1) {
+                    0 => Arc::clone(&device.iface),
+                    n => Arc::clone(&device.tun_queues[n - 1]),
+                }
+            } else if _i == 0 || !device.read().config.use_multi_queue {
                 // For the first thread use the original iface
                 Arc::clone(&device.read().iface)
             } else {
-                // For for the rest create a new iface queue
-                let iface_local = Arc::new(
-                    TunSocket::new(&device.read().iface.name().unwrap())
-                        .unwrap()
-                        .set_non_blocking()
-                        .unwrap(),
-                );
-
-                device
-                    .read()
-                    .register_iface_handler(Arc::clone(&iface_local))
-                    .ok();
-
-                iface_local
+                // For for the rest create a new iface queue, if the iface has several
+                let device = device.read();
+                match in_netns(&device.config, || device.iface.new_queue()).unwrap() {
+                    Some(iface_local) => {
+                        device.register_iface_handler(Arc::clone(&iface_local)).ok();
+                        iface_local
+                    }
+                    None => Arc::clone(&device.iface),
+                }
             },
         };
 
         #[cfg(not(target_os = "linux"))]
         let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
+            src_buf: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
+            dst_buf: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
             iface: Arc::clone(&device.read().iface),
         };
 
-        #[cfg(not(target_os = "linux"))]
-        let uapi_fd = -1;
         #[cfg(target_os = "linux")]
         let uapi_fd = device.read().uapi_fd;
 
@@ -248,6 +1284,8 @@ impl DeviceHandle {
             loop {
                 match queue.wait() {
                     WaitResult::Ok(handler) => {
+                        #[cfg(feature = "metrics")]
+                        device_lock.metrics.count_loop_iteration();
                         let action = (*handler)(&mut device_lock, &mut thread_local);
                         match action {
                             Action::Continue => {}
@@ -256,9 +1294,11 @@ impl DeviceHandle {
                                 device_lock.trigger_exit();
                                 return;
                             }
+                            Action::Leave => return,
                         }
                     }
                     WaitResult::EoF(handler) => {
+                        #[cfg(target_os = "linux")]
                         if uapi_fd >= 0 && uapi_fd == handler.fd() {
                             device_lock.trigger_exit();
                             return;
@@ -270,11 +1310,34 @@ impl DeviceHandle {
             }
         }
     }
+
+    /// Process the handshakes queued by the event loops until the queue is closed. The device is
+    /// only locked while a handshake is processed, so writers are never kept waiting.
+    fn handshake_loop(device: &Weak<Lock<Device>>, handshakes: &HandshakeQueue) {
+        let mut dst_buf = vec![0u8; MAX_UDP_SIZE];
+        while let Some(job) = handshakes.pop() {
+            let device = match device.upgrade() {
+                Some(device) => device,
+                None => return,
+            };
+            let device = device.read();
+            device.process_handshake(job, &mut dst_buf);
+        }
+    }
 }
 
 impl Drop for DeviceHandle {
     fn drop(&mut self) {
+        self.device.read().save_peer_state(false);
+        #[cfg(target_os = "linux")]
+        self.device.read().delete_policy_rules();
         self.device.read().trigger_exit();
+        if let Some(handshakes) = &self.device.read().handshakes {
+            handshakes.close();
+        }
+        for thread in self.handshake_threads.drain(..) {
+            let _ = thread.join();
+        }
         self.clean();
     }
 }
@@ -295,7 +1358,7 @@ impl Device {
             self.peers_by_ip
                 .remove(&|p: &Arc<Mutex<Peer>>| Arc::ptr_eq(&peer, p));
 
-            tracing::info!("Peer removed");
+            tracing::info!(message = "Peer removed", peer = self.peer_log_id(pub_key));
         }
     }
 
@@ -306,38 +1369,66 @@ impl Device {
         remove: bool,
         _replace_ips: bool,
         endpoint: Option<SocketAddr>,
+        endpoint_host: Option<&str>,
         allowed_ips: &[AllowedIP],
         keepalive: Option<u16>,
         preshared_key: Option<[u8; 32]>,
-    ) {
+    ) -> Result<(), Error> {
         if remove {
             // Completely remove a peer
-            return self.remove_peer(&pub_key);
+            self.remove_peer(&pub_key);
+            return Ok(());
         }
 
         // Update an existing peer
-        if self.peers.get(&pub_key).is_some() {
+        if self.peers.contains_key(&pub_key) {
             // We already have a peer, we need to merge the existing config into the newly created one
-            panic!("Modifying existing peers is not yet supported. Remove and add again instead.");
+            return Err(Error::InvalidConfig(
+                "Modifying existing peers is not yet supported. Remove and add again instead."
+                    .to_owned(),
+            ));
+        }
+
+        let limits = self.config.limits;
+        if let Some(max) = limits.max_peers.filter(|&max| self.peers.len() >= max) {
+            return Err(Error::TooManyPeers(max));
         }
+        self.check_allowed_ips_limits(0, allowed_ips.len())?;
 
         let next_index = self.next_index();
         let device_key_pair = self
             .key_pair
             .as_ref()
-            .expect("Private key must be set first");
+            .ok_or_else(|| Error::InvalidConfig("Private key must be set first".to_owned()))?;
 
-        let tunn = Tunn::new(
+        let mut tunn = Tunn::new(
             device_key_pair.0.clone(),
             pub_key,
             preshared_key,
             keepalive,
             next_index,
             None,
+            None,
         )
-        .unwrap();
+        .map_err(|e| Error::InvalidConfig(e.to_owned()))?;
+        tunn.set_crypto_provider(self.config.crypto_provider.clone());
+        tunn.set_proactive_rekey_lead_time(self.config.proactive_rekey_lead_time);
 
-        let peer = Peer::new(tunn, next_index, endpoint, allowed_ips, preshared_key);
+        let restored_endpoint = self.restored_endpoints.remove(&pub_key);
+        let endpoint = endpoint.or(restored_endpoint);
+
+        let mut peer = Peer::new(tunn, next_index, endpoint, allowed_ips, preshared_key);
+        peer.set_span(self.peer_span(&pub_key));
+        if let Some(host) = endpoint_host {
+            peer.set_endpoint_host(host, Arc::clone(&self.resolver));
+        }
+        if let Transport::Tcp { framing, .. } = self.config.transport {
+            let connector = Arc::new(DeviceConnector {
+                queue: Arc::clone(&self.queue),
+                config: self.config.clone(),
+            });
+            peer.set_tcp_link(TcpLink::new(framing, connector));
+        }
 
         let peer = Arc::new(Mutex::new(peer));
         self.peers.insert(pub_key, Arc::clone(&peer));
@@ -347,58 +1438,139 @@ impl Device {
             self.peers_by_ip
                 .insert(*addr, *cidr as _, Arc::clone(&peer));
         }
+        self.schedule_timers(&mut peer.lock());
 
-        tracing::info!("Peer added");
+        tracing::info!(message = "Peer added", peer = self.peer_log_id(&pub_key));
+        Ok(())
     }
 
-    pub fn new(name: &str, config: DeviceConfig) -> Result<Device, Error> {
-        let poll = EventPoll::<Handler>::new()?;
-
-        // Create a tunnel device
-        let iface = Arc::new(TunSocket::new(name)?.set_non_blocking()?);
-        let mtu = iface.mtu()?;
+    /// How a peer is named in logs, its public key in full or its fingerprint, see
+    /// [`DeviceConfig::log_public_keys`]
+    fn peer_log_id(&self, public_key: &x25519::PublicKey) -> String {
+        match self.config.log_public_keys {
+            true => base64::encode(public_key.as_bytes()),
+            false => peer_fingerprint(public_key),
+        }
+    }
 
-        #[cfg(not(target_os = "linux"))]
-        let uapi_fd = -1;
-        #[cfg(target_os = "linux")]
-        let uapi_fd = config.uapi_fd;
+    /// The span of the events of a peer, see [`Peer::span`]
+    fn peer_span(&self, public_key: &x25519::PublicKey) -> tracing::Span {
+        tracing::info_span!(
+            "peer",
+            interface = %self.iface.name().unwrap_or_default(),
+            peer = %self.peer_log_id(public_key)
+        )
+    }
 
-        let mut device = Device {
-            queue: Arc::new(poll),
-            iface,
-            config,
-            exit_notice: Default::default(),
-            yield_notice: Default::default(),
-            fwmark: Default::default(),
-            key_pair: Default::default(),
-            listen_port: Default::default(),
-            next_index: Default::default(),
-            peers: Default::default(),
-            peers_by_idx: Default::default(),
-            peers_by_ip: AllowedIps::new(),
-            udp4: Default::default(),
-            udp6: Default::default(),
-            cleanup_paths: Default::default(),
-            mtu: AtomicUsize::new(mtu),
-            rate_limiter: None,
-            #[cfg(target_os = "linux")]
-            uapi_fd,
-        };
+    fn stats(&self) -> DeviceStats {
+        DeviceStats {
+            peers: self.peers.len(),
+            connected_peers: self
+                .peers
+                .values()
+                .filter(|peer| {
+                    peer.lock()
+                        .time_since_last_handshake()
+                        .is_some_and(|age| age < REJECT_AFTER_TIME)
+                })
+                .count(),
+            allowed_ips: self.peers_by_ip.len(),
+            dropped_handshakes: self.handshakes.as_ref().map_or(0, |h| h.dropped()),
+            dropped_events: self.events.dropped(),
+            udp_recv_buffer_size: self.udp_buffer_sizes.recv,
+            udp_send_buffer_size: self.udp_buffer_sizes.send,
+        }
+    }
 
-        if uapi_fd >= 0 {
-            device.register_api_fd(uapi_fd)?;
-        } else {
-            device.register_api_handler()?;
+    /// Check that a peer can go from `current` to `new` allowed IPs, shrinking is always allowed
+    fn check_allowed_ips_limits(&self, current: usize, new: usize) -> Result<(), Error> {
+        if new <= current {
+            return Ok(());
         }
-        device.register_iface_handler(Arc::clone(&device.iface))?;
-        device.register_notifiers()?;
-        device.register_timers()?;
 
-        #[cfg(target_os = "macos")]
+        let limits = self.config.limits;
+        if limits.max_allowed_ips_per_peer.is_some_and(|max| new > max) {
+            return Err(Error::LimitExceeded(
+                "Too many allowed IPs for the peer".to_owned(),
+            ));
+        }
+        if limits
+            .max_allowed_ips
+            .is_some_and(|max| self.peers_by_ip.len() + new - current > max)
+        {
+            return Err(Error::LimitExceeded("Too many allowed IPs".to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// Check that `allowed_ips` don't overlap those of the peers other than `pub_key`, unless
+    /// [`DeviceConfig::allow_overlapping_ips`] says they can
+    fn check_allowed_ips_overlap(
+        &self,
+        pub_key: &x25519::PublicKey,
+        allowed_ips: &[AllowedIP],
+    ) -> Result<(), Error> {
+        if self.config.allow_overlapping_ips {
+            return Ok(());
+        }
+
+        let own = self.peers.get(pub_key);
+        for ip in allowed_ips {
+            let other = self
+                .peers_by_ip
+                .overlapping(ip.addr, ip.cidr.into())
+                .find(|(peer, _, _)| own.is_none_or(|own| !Arc::ptr_eq(own, peer)));
+            if let Some((_, addr, cidr)) = other {
+                return Err(Error::OverlappingAllowedIps(format!(
+                    "{} overlaps {}/{} of another peer",
+                    ip, addr, cidr
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new(name: &str, config: DeviceConfig) -> Result<Device, Error> {
+        // Create a tunnel device, or use the one that was passed in
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let tun = match config.tun_fd {
+            Some(fd) => TunSocket::from_fd(fd, name, config.tun_af_prefix),
+            None => TunSocket::new(name),
+        }?;
+        #[cfg(target_os = "linux")]
+        let config = match config.uapi_listen_fd {
+            None if config.uapi_fd < 0 => DeviceConfig {
+                uapi_listen_fd: socket_activation::find_uapi_listener(name)?,
+                ..config
+            },
+            _ => config,
+        };
+        #[cfg(target_os = "linux")]
+        if !config.tun_fds.is_empty() {
+            let mut queues = TunSocket::from_fds(&config.tun_fds, name)?
+                .into_iter()
+                .map(|queue| Ok(Arc::new(queue.set_non_blocking()?) as Arc<dyn Tun>))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let mut device = Device::with_tun(queues.remove(0), config)?;
+            for queue in &queues {
+                device.register_iface_handler(Arc::clone(queue))?;
+            }
+            device.tun_queues = queues;
+            return Ok(device);
+        }
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        let tun = in_netns(&config, || TunSocket::new(name))?;
+        let iface = Arc::new(tun.set_non_blocking()?);
+        let device = Device::with_tun(iface, config)?;
+
+        #[cfg(target_os = "macos")]
+        let mut device = device;
+        #[cfg(target_os = "macos")]
         {
             // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
             if let Ok(name_file) = std::env::var("WG_TUN_NAME_FILE") {
-                if name == "utun" {
+                if name == "utun" && device.config.tun_fd.is_none() {
                     std::fs::write(&name_file, device.iface.name().unwrap().as_bytes()).unwrap();
                     device.cleanup_paths.push(name_file);
                 }
@@ -408,46 +1580,263 @@ impl Device {
         Ok(device)
     }
 
+    /// Create a device on top of `iface`, see [`DeviceHandle::with_tun`]
+    pub fn with_tun(iface: Arc<dyn Tun>, config: DeviceConfig) -> Result<Device, Error> {
+        let poll = EventPoll::<Handler>::new()?;
+
+        #[cfg(windows)]
+        if config.use_connected_socket {
+            return Err(Error::InvalidConfig(
+                "connected sockets are not supported on Windows".to_owned(),
+            ));
+        }
+        #[cfg(windows)]
+        if config.reuse_port {
+            return Err(Error::InvalidConfig(
+                "SO_REUSEPORT is not supported on Windows".to_owned(),
+            ));
+        }
+
+        #[cfg(unix)]
+        sandbox::check_config(&config)?;
+
+        config.obfuscation.validate()?;
+        let obfuscation = config.obfuscation;
+
+        if let Some(mtu) = config.mtu {
+            if mtu < MIN_MTU {
+                return Err(Error::InterfaceConfig(format!(
+                    "the MTU must be between {} and {}",
+                    MIN_MTU,
+                    u16::MAX
+                )));
+            }
+        }
+        if let Some(size) = config.thread_stack_size {
+            if size < MIN_THREAD_STACK_SIZE {
+                return Err(Error::InvalidConfig(format!(
+                    "the thread stack size must be at least {} bytes",
+                    MIN_THREAD_STACK_SIZE
+                )));
+            }
+        }
+        if config.exit_on_idle == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig(
+                "the idle period before exiting must not be zero".to_owned(),
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(depth) = config.tun_queue_depth {
+            if !(1..=MAX_TUN_QUEUE_DEPTH).contains(&depth) {
+                return Err(Error::InvalidConfig(format!(
+                    "the TUN queue depth must be between 1 and {}",
+                    MAX_TUN_QUEUE_DEPTH
+                )));
+            }
+            in_netns(&config, || iface.set_queue_depth(depth as u32))?;
+        }
+        if !config.address.is_empty() || config.mtu.is_some() || config.bring_up {
+            let inner_mtu = config.mtu.map(pmtu::inner_mtu);
+            in_netns(&config, || {
+                iface.configure(&config.address, inner_mtu, config.bring_up)
+            })?;
+        }
+        let mtu = in_netns(&config, || iface.mtu())?;
+
+        #[cfg(target_os = "linux")]
+        let uapi_fd = config.uapi_fd;
+
+        let pcap = match &config.pcap_path {
+            Some(path) => Some(Mutex::new(
+                pcap::PcapWriter::create(path, config.pcap_max_bytes).map_err(|e| {
+                    Error::InvalidConfig(format!("pcap file {}: {}", path.display(), e))
+                })?,
+            )),
+            None => None,
+        };
+
+        let restored_endpoints = match &config.peer_state_file {
+            Some(path) => peer_state::load(path, config.peer_state_max_age),
+            None => Default::default(),
+        };
+        let peer_state = config
+            .peer_state_file
+            .clone()
+            .map(peer_state::PeerStateSaver::new);
+
+        let handshakes = (config.n_threads > 1 && config.handshake_threads > 0)
+            .then(|| Arc::new(HandshakeQueue::new(HANDSHAKE_QUEUE_CAPACITY)));
+
+        let mut device = Device {
+            queue: Arc::new(poll),
+            iface,
+            #[cfg(target_os = "linux")]
+            tun_queues: vec![],
+            config,
+            exit_notice: Default::default(),
+            shutdown_notice: Default::default(),
+            handshake_notice: Default::default(),
+            timer_event: Default::default(),
+            timers: Default::default(),
+            #[cfg(test)]
+            timer_wakeups: Default::default(),
+            shutting_down: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            drained_threads: AtomicUsize::new(0),
+            yield_notice: Default::default(),
+            fwmark: Default::default(),
+            key_pair: Default::default(),
+            listen_port: Default::default(),
+            next_index: Default::default(),
+            peers: Default::default(),
+            peers_by_idx: Default::default(),
+            peers_by_ip: AllowedIps::new(),
+            outer: None,
+            udp_buffer_sizes: Default::default(),
+            obfuscation,
+            cleanup_paths: Default::default(),
+            #[cfg(unix)]
+            uapi_path: None,
+            #[cfg(target_os = "linux")]
+            policy_rules: Default::default(),
+            mtu: AtomicUsize::new(mtu),
+            rate_limiter: None,
+            handshakes,
+            running: Default::default(),
+            uapi_extensions: Default::default(),
+            resolver: resolver::default_resolver(),
+            events: Default::default(),
+            restored_endpoints,
+            peer_state,
+            pcap,
+            #[cfg(feature = "mdns")]
+            mdns: None,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            #[cfg(target_os = "linux")]
+            uapi_fd,
+        };
+
+        #[cfg(target_os = "linux")]
+        if uapi_fd >= 0 {
+            device.register_api_fd(uapi_fd)?;
+        } else if let Some(fd) = device.config.uapi_listen_fd {
+            socket_activation::check_unix_listener(fd)?;
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            device.register_api_listener(listener, None)?;
+        } else {
+            device.register_api_handler()?;
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+        device.register_api_handler()?;
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        match device.register_api_handler() {
+            // A Network Extension can't create the socket, and configures the device directly
+            Err(e) if device.config.tun_fd.is_some() => {
+                tracing::warn!(message = "The UAPI is not available", error = ?e);
+            }
+            result => result?,
+        }
+        device.register_iface_handler(Arc::clone(&device.iface))?;
+        if let Transport::Tcp { addr, framing } = device.config.transport {
+            device.register_tcp_listener(addr, framing)?;
+        }
+        device.register_notifiers()?;
+        device.register_timers()?;
+        #[cfg(unix)]
+        device.register_reload_handler()?;
+        #[cfg(unix)]
+        if device.config.log_stats_on_sigusr1 {
+            device.register_stats_log_handler()?;
+        }
+        #[cfg(feature = "http-health")]
+        if let Some(addr) = device.config.health_check_addr {
+            device.register_health_check_handler(addr)?;
+        }
+        #[cfg(feature = "mdns")]
+        if device.config.mdns {
+            device.mdns = Some(mdns::Mdns::new()?);
+            device.register_mdns_handler()?;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = device.config.metrics_listen {
+            device.register_metrics_handler(addr)?;
+        }
+
+        Ok(device)
+    }
+
     fn open_listen_socket(&mut self, mut port: u16) -> Result<(), Error> {
         // Binds the network facing interfaces
         // First close any existing open socket, and remove them from the event loop
-        if let Some(s) = self.udp4.take() {
-            unsafe {
-                // This is safe because the event loop is not running yet
-                self.queue.clear_event_by_fd(s.as_raw_fd())
+        if let Some(outer) = self.outer.take() {
+            for transport in outer.distinct() {
+                unsafe {
+                    // This is safe because the event loop is not running yet
+                    self.queue.clear_event_by_fd(transport.as_raw_fd())
+                }
             }
-        };
-
-        if let Some(s) = self.udp6.take() {
-            unsafe { self.queue.clear_event_by_fd(s.as_raw_fd()) };
         }
 
         for peer in self.peers.values() {
             peer.lock().shutdown_endpoint();
         }
 
-        // Then open new sockets and bind to the port
-        let udp_sock4 = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock4.set_reuse_address(true)?;
-        udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
-        udp_sock4.set_nonblocking(true)?;
+        // Then open new sockets and bind to the port, or to the first free one of the range when
+        // any port will do
+        let factory = self.config.outer_transport.clone();
+        let udp = UdpFactory {
+            reuse_port: self.config.reuse_port,
+            bind_addrs: self.config.bind_addrs.clone(),
+            buffer_sizes: BufferSizes {
+                recv: self.config.udp_recv_buffer_size,
+                send: self.config.udp_send_buffer_size,
+            },
+            ..Default::default()
+        };
+        let factory = factory.as_deref().unwrap_or(&udp);
+        let hook = self.config.on_socket_created.as_ref();
+        let outer = match self.config.listen_port_range.clone() {
+            Some(range) if port == 0 => {
+                let mut result = Err(Error::InvalidConfig(format!(
+                    "the listen port range {:?} is empty",
+                    range
+                )));
+                for candidate in range {
+                    result = in_netns(&self.config, || factory.bind(candidate, hook));
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result?
+            }
+            _ => in_netns(&self.config, || factory.bind(port, hook))?,
+        };
+        let outer = match self.obfuscation.is_standard() {
+            true => outer,
+            false => self.obfuscation.wrap(outer),
+        };
+        port = outer.v4.local_addr()?.port();
+        self.udp_buffer_sizes = *udp.effective.lock();
 
-        if port == 0 {
-            // Random port was assigned
-            port = udp_sock4.local_addr()?.as_socket().unwrap().port();
+        for transport in outer.distinct() {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            if let Some(mark) = self.fwmark {
+                transport.set_fwmark(mark)?;
+            }
+            self.register_udp_handler(Arc::clone(transport))?;
         }
+        self.outer = Some(outer);
 
-        let udp_sock6 = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock6.set_reuse_address(true)?;
-        udp_sock6.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
-        udp_sock6.set_nonblocking(true)?;
-
-        self.register_udp_handler(udp_sock4.try_clone().unwrap())?;
-        self.register_udp_handler(udp_sock6.try_clone().unwrap())?;
-        self.udp4 = Some(udp_sock4);
-        self.udp6 = Some(udp_sock6);
-
+        if port != self.listen_port {
+            self.events.emit_device(DeviceEvent::ListenPortChanged {
+                old: self.listen_port,
+                new: port,
+            });
+        }
         self.listen_port = port;
+        #[cfg(feature = "mdns")]
+        self.advertise_mdns();
 
         Ok(())
     }
@@ -464,7 +1853,9 @@ impl Device {
             return;
         }
 
-        let rate_limiter = Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT));
+        let mut rate_limiter = RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT);
+        rate_limiter.set_source_limit(self.config.max_handshake_initiations_per_second.into());
+        let rate_limiter = Arc::new(rate_limiter);
 
         for peer in self.peers.values_mut() {
             let mut peer_mut = peer.lock();
@@ -486,40 +1877,681 @@ impl Device {
 
         self.key_pair = key_pair;
         self.rate_limiter = Some(rate_limiter);
+        #[cfg(feature = "mdns")]
+        self.advertise_mdns();
+        self.events
+            .emit_device(DeviceEvent::PrivateKeyRotated { public_key });
 
         // Remove all the bad peers
-        for _ in bad_peers {
+        if !bad_peers.is_empty() {
             unimplemented!();
         }
     }
 
+    /// Replace the private key and reconnect, see [`DeviceHandle::reload_private_key`]
+    fn reload_private_key(
+        &mut self,
+        private_key: x25519::StaticSecret,
+    ) -> Result<(), KeyReloadError> {
+        let public_key = x25519::PublicKey::from(&private_key);
+        if Some(&public_key) == self.key_pair.as_ref().map(|p| &p.1) {
+            return Err(KeyReloadError::KeyUnchanged);
+        }
+
+        // Picked before the sessions are dropped along with the previous key
+        let reconnect: Vec<_> = self
+            .peers
+            .values()
+            .filter(|peer| {
+                let peer = peer.lock();
+                peer.persistent_keepalive().is_some() || peer.tunnel.has_session()
+            })
+            .cloned()
+            .collect();
+        self.set_key(private_key);
+
+        let outer = match self.outer.as_ref() {
+            Some(outer) => outer,
+            None => return Ok(()),
+        };
+        let mut dst = [0u8; 256];
+        for peer in reconnect {
+            let mut peer = peer.lock();
+            let _span = peer.span().clone().entered();
+            if peer.endpoint().addr.is_none() && peer.tcp_link().is_none() {
+                continue;
+            }
+            if let Ok(TunnAction::WriteToNetwork(packet)) =
+                peer.tunnel.try_format_handshake_initiation(&mut dst, true)
+            {
+                let sent = send_to_endpoint(&peer, outer, packet, false, Duration::ZERO, None);
+                peer.set_unreachable(sent.is_err());
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the obfuscation of the datagrams. The outer transports are opened again on the same
+    /// port, and the peers reconnect when they next send.
+    fn set_obfuscation(&mut self, obfuscation: Obfuscation) -> Result<(), Error> {
+        obfuscation.validate()?;
+        if obfuscation == self.obfuscation {
+            return Ok(());
+        }
+        self.obfuscation = obfuscation;
+        match self.outer {
+            Some(_) => self.open_listen_socket(self.listen_port),
+            None => Ok(()),
+        }
+    }
+
+    /// Set the mark of every socket the device sends from, a mark of zero removes it
     #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
     fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
-        self.fwmark = Some(mark);
-
         // First set fwmark on listeners
-        if let Some(ref sock) = self.udp4 {
-            sock.set_mark(mark)?;
+        for transport in self.outer.iter().flat_map(OuterTransports::distinct) {
+            transport.set_fwmark(mark)?;
+        }
+
+        self.fwmark = Some(mark).filter(|&m| m != 0);
+
+        // Then close the connected sockets, the kernel may still hold a route looked up with the
+        // old mark for them. They are connected again with the new mark on the next packet from
+        // their peer, until then the listeners are used.
+        for peer in self.peers.values() {
+            peer.lock().shutdown_endpoint();
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
+        match mark {
+            0 => Ok(()),
+            _ => Err(Error::FwmarkUnsupported),
+        }
+    }
+
+    /// Set the peers aside for a `replace_peers` set, which adds the new peers to the empty
+    /// tables. The I/O threads are parked by the write lock, so they never see the tables until
+    /// they are complete. See [`Device::finish_replace_peers`].
+    fn begin_replace_peers(&mut self) -> ReplacedPeers {
+        self.peers_by_idx.clear();
+        self.peers_by_ip.clear();
+        ReplacedPeers(std::mem::take(&mut self.peers))
+    }
+
+    /// Add a peer that was set aside by `replace_peers` with the attributes of a new peer, so it
+    /// keeps its sessions. `None` attributes are left as they were.
+    #[allow(clippy::too_many_arguments)]
+    fn restore_peer(
+        &mut self,
+        pub_key: x25519::PublicKey,
+        peer: Arc<Mutex<Peer>>,
+        endpoint: Option<SocketAddr>,
+        endpoint_host: Option<&str>,
+        allowed_ips: &[AllowedIP],
+        keepalive: Option<u16>,
+    ) -> Result<(), Error> {
+        let limits = self.config.limits;
+        if let Some(max) = limits.max_peers.filter(|&max| self.peers.len() >= max) {
+            return Err(Error::TooManyPeers(max));
+        }
+        self.check_allowed_ips_limits(0, allowed_ips.len())?;
+
+        let mut p = peer.lock();
+        if let Some(endpoint) = endpoint {
+            p.set_failover_endpoints(vec![]);
+            p.set_endpoint(endpoint);
+            match endpoint_host {
+                Some(host) => p.set_endpoint_host(host, Arc::clone(&self.resolver)),
+                None => p.clear_endpoint_host(),
+            }
+        }
+        p.set_persistent_keepalive(keepalive);
+        p.set_bandwidth_limit(None);
+        p.set_allowed_ips(allowed_ips);
+
+        self.peers.insert(pub_key, Arc::clone(&peer));
+        self.peers_by_idx.insert(p.index(), Arc::clone(&peer));
+        for AllowedIP { addr, cidr } in allowed_ips {
+            self.peers_by_ip
+                .insert(*addr, *cidr as _, Arc::clone(&peer));
+        }
+        self.schedule_timers(&mut p);
+
+        tracing::info!(message = "Peer kept", peer = self.peer_log_id(&pub_key));
+        Ok(())
+    }
+
+    /// Clean up the peers left out of a `replace_peers` set
+    fn finish_replace_peers(&mut self, replaced: ReplacedPeers) {
+        for (pub_key, peer) in replaced.0 {
+            peer.lock().shutdown_endpoint();
+            tracing::info!(message = "Peer removed", peer = self.peer_log_id(&pub_key));
+        }
+    }
+
+    /// Write the endpoints of the peers we had a handshake with to the peer state file, if any.
+    /// The file is written on a thread of its own `in_background`, otherwise before returning.
+    fn save_peer_state(&self, in_background: bool) {
+        let saver = match &self.peer_state {
+            Some(saver) => saver,
+            None => return,
+        };
+
+        let peers = self
+            .peers
+            .iter()
+            .filter_map(|(pub_key, peer)| {
+                let p = peer.lock();
+                let endpoint = p.endpoint().addr?;
+                let since_handshake = p.time_since_last_handshake()?;
+                Some(peer_state::PeerState::new(
+                    pub_key,
+                    endpoint,
+                    since_handshake,
+                ))
+            })
+            .collect();
+
+        match in_background {
+            true => saver.save_in_background(peers),
+            false => saver.save_now(peers),
+        }
+    }
+
+    /// Host names are resolved to an address of the family we are listening on, if possible.
+    /// The outer transports always cover both families.
+    fn prefers_ipv4(&self) -> bool {
+        true
+    }
+
+    /// Bring the device to the state described by `config`, the way `wg syncconf` does: peers that
+    /// are missing from `config` are removed, new peers are added, and peers that already exist are
+    /// updated in place, so they keep their sessions. Settings of the interface that are not set in
+    /// `config` are left untouched, and so are the address and the MTU of the interface.
+    pub fn apply_config(&mut self, config: WgConfig) -> Result<(), Error> {
+        let resolver = Arc::clone(&self.resolver);
+        self.sync_config(config, resolver.as_ref()).map(|_| ())
+    }
+
+    /// [`Device::apply_config`], which checks the limits and resolves the endpoints of the peers
+    /// with `resolver` before anything is changed, so a configuration that fails leaves the
+    /// device as it was. The fwmark is set before the listen port, and put back when the system
+    /// refuses the port.
+    fn sync_config(
+        &mut self,
+        config: WgConfig,
+        resolver: &dyn Resolver,
+    ) -> Result<ReloadSummary, Error> {
+        let WgConfig {
+            private_key,
+            listen_port,
+            fwmark,
+            peers,
+            ..
+        } = config;
+
+        if private_key.is_none() && self.key_pair.is_none() && !peers.is_empty() {
+            return Err(Error::InvalidConfig(
+                "Private key must be set to add peers".to_owned(),
+            ));
+        }
+        self.check_config_limits(&peers)?;
+        let endpoints = peers
+            .iter()
+            .map(|p| self.resolve_endpoint_of(resolver, &p.public_key, p.endpoint.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The mark goes first, so the sockets of a new port are opened with it. A port the system
+        // refuses puts the mark and the sockets of the old port back.
+        let old_mark = self.fwmark.unwrap_or(0);
+        let mark = fwmark.filter(|&mark| mark != old_mark);
+        if let Some(mark) = mark {
+            self.set_fwmark(mark)?;
+        }
+
+        if let Some(port) = listen_port {
+            if port != self.listen_port || self.outer.is_none() {
+                let old_port = self.outer.is_some().then_some(self.listen_port);
+                if let Err(e) = self.open_listen_socket(port) {
+                    if mark.is_some() {
+                        self.set_fwmark(old_mark).ok();
+                    }
+                    if let Some(old_port) = old_port {
+                        if let Err(e) = self.open_listen_socket(old_port) {
+                            tracing::error!(message = "Failed to reopen the listen port", port = old_port, error = ?e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(private_key) = private_key {
+            self.set_key(private_key);
+        }
+
+        let removed: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|k| !peers.iter().any(|p| p.public_key == **k))
+            .copied()
+            .collect();
+        let mut summary = ReloadSummary {
+            removed: removed.len(),
+            ..Default::default()
+        };
+        for pub_key in removed {
+            self.remove_peer(&pub_key);
+        }
+
+        // The peers that lose allowed IPs go first, so the total never goes past the limit the
+        // configuration was checked against
+        let mut peers: Vec<_> = peers.into_iter().zip(endpoints).collect();
+        peers.sort_by_key(|(peer_config, _)| {
+            let current = self
+                .peers
+                .get(&peer_config.public_key)
+                .map_or(0, |peer| peer.lock().allowed_ips().count());
+            peer_config.allowed_ips.len() as isize - current as isize
+        });
+        for (peer_config, endpoint) in peers {
+            match self.peers.get(&peer_config.public_key) {
+                Some(peer) => {
+                    let peer = Arc::clone(peer);
+                    let public_key = peer_config.public_key;
+                    if self.update_existing_peer(
+                        &peer,
+                        &public_key,
+                        peer_config.into(),
+                        endpoint,
+                    )? {
+                        summary.modified += 1;
+                    }
+                }
+                None => {
+                    self.add_peer(peer_config, endpoint)?;
+                    summary.added += 1;
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Check that `peers` fit in the [`Limits`] of the device, once they replace the current ones
+    fn check_config_limits(&self, peers: &[PeerConfig]) -> Result<(), Error> {
+        let limits = self.config.limits;
+        if let Some(max) = limits.max_peers.filter(|&max| peers.len() > max) {
+            return Err(Error::TooManyPeers(max));
+        }
+        let most = peers.iter().map(|p| p.allowed_ips.len()).max().unwrap_or(0);
+        if limits
+            .max_allowed_ips_per_peer
+            .is_some_and(|max| most > max)
+        {
+            return Err(Error::LimitExceeded(
+                "Too many allowed IPs for the peer".to_owned(),
+            ));
+        }
+        let total: usize = peers.iter().map(|p| p.allowed_ips.len()).sum();
+        if limits.max_allowed_ips.is_some_and(|max| total > max) {
+            return Err(Error::LimitExceeded("Too many allowed IPs".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Apply the changes of a [`ConfigDiff`] to the peers, leaving the other peers and the
+    /// settings of the interface alone. A diff that doesn't match the current peers is rejected
+    /// before anything is changed. The peers that are in the diff but didn't change keep their
+    /// sessions.
+    pub fn apply_diff(&mut self, diff: ConfigDiff) -> Result<(), Error> {
+        let resolver = Arc::clone(&self.resolver);
+        self.apply_diff_with(diff, resolver.as_ref())
+    }
+
+    /// [`Device::apply_diff`], which resolves the endpoints of the added and modified peers with
+    /// `resolver`
+    fn apply_diff_with(&mut self, diff: ConfigDiff, resolver: &dyn Resolver) -> Result<(), Error> {
+        let ConfigDiff {
+            added,
+            removed,
+            modified,
+        } = diff;
+
+        if self.key_pair.is_none() && !added.is_empty() {
+            return Err(Error::InvalidConfig(
+                "Private key must be set to add peers".to_owned(),
+            ));
+        }
+        if let Some(peer) = added
+            .iter()
+            .find(|p| self.peers.contains_key(&p.public_key))
+        {
+            return Err(Error::InvalidConfig(format!(
+                "Peer {} already exists",
+                peer_fingerprint(&peer.public_key)
+            )));
+        }
+        let changed = removed.iter().chain(modified.iter().map(|(key, _)| key));
+        if let Some(key) = changed.clone().find(|key| !self.peers.contains_key(*key)) {
+            return Err(Error::InvalidConfig(format!(
+                "Unknown peer {}",
+                peer_fingerprint(key)
+            )));
+        }
+        let modified = modified
+            .into_iter()
+            .map(|(key, changes)| {
+                let endpoint =
+                    self.resolve_endpoint_of(resolver, &key, changes.endpoint.as_deref())?;
+                Ok((key, changes, endpoint))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let added = added
+            .into_iter()
+            .map(|p| {
+                let endpoint =
+                    self.resolve_endpoint_of(resolver, &p.public_key, p.endpoint.as_deref())?;
+                Ok((p, endpoint))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        for pub_key in removed {
+            self.remove_peer(&pub_key);
+        }
+
+        for (public_key, changes, endpoint) in modified {
+            let peer = Arc::clone(&self.peers[&public_key]);
+            self.update_existing_peer(&peer, &public_key, changes, endpoint)?;
+        }
+
+        for (peer_config, endpoint) in added {
+            self.add_peer(peer_config, endpoint)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a peer, with its endpoint resolved by [`Device::resolve_endpoint_of`]
+    fn add_peer(
+        &mut self,
+        peer_config: PeerConfig,
+        endpoint: Option<ResolvedEndpoints>,
+    ) -> Result<(), Error> {
+        let (addrs, host) = endpoint.unwrap_or_default();
+
+        self.update_peer(
+            peer_config.public_key,
+            false,
+            false,
+            addrs.first().copied(),
+            host.as_deref(),
+            &peer_config.allowed_ips,
+            peer_config.persistent_keepalive,
+            peer_config.preshared_key,
+        )?;
+        if let Some(peer) = self.peers.get(&peer_config.public_key) {
+            let mut peer = peer.lock();
+            peer.set_failover_endpoints(addrs);
+            peer.set_bandwidth_limit(peer_config.bandwidth_limit);
+            peer.set_reconnect_policy(peer_config.reconnect_policy);
+            peer.set_pcap_capture(peer_config.pcap_capture);
+            #[cfg(feature = "mdns")]
+            {
+                peer.set_mdns_discovery(peer_config.mdns_discovery);
+                self.follow_mdns_endpoint(&mut peer, self.mdns_endpoint(&peer_config.public_key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the configuration file of the device again, see [`DeviceConfig::config_file`].
+    /// Peers that are in the file and didn't change keep their sessions.
+    /// A file that fails to be read or applied leaves the configuration as it was, see
+    /// [`Device::apply_config`].
+    pub fn reload_config(&mut self) -> Result<ReloadSummary, Error> {
+        let file = self.read_config_file()?;
+        let resolver = Arc::clone(&self.resolver);
+        self.apply_config_file(file, resolver.as_ref())
+    }
+
+    /// Read the configuration file of the device, with the private key and the listen port the
+    /// device was started with, see [`Device::keep_configured_key_and_port`]
+    fn read_config_file(&self) -> Result<WgConfig, Error> {
+        let path = match &self.config.config_file {
+            Some(path) => path,
+            None => return Err(Error::InvalidConfig("No configuration file".to_owned())),
+        };
+
+        tracing::info!(message = "Reloading configuration", path = ?path);
+        let mut file = WgConfig::from_file(path)?;
+        self.keep_configured_key_and_port(&mut file);
+        Ok(file)
+    }
+
+    /// Apply a configuration file read by [`Device::read_config_file`], with the endpoints
+    /// resolved by `resolver`
+    fn apply_config_file(
+        &mut self,
+        file: WgConfig,
+        resolver: &dyn Resolver,
+    ) -> Result<ReloadSummary, Error> {
+        let summary = self.sync_config(file, resolver)?;
+        tracing::info!(
+            message = "Configuration reloaded",
+            added = summary.added,
+            removed = summary.removed,
+            modified = summary.modified
+        );
+        Ok(summary)
+    }
+
+    /// Replace the private key and the listen port of a configuration file by those of
+    /// [`DeviceConfig::private_key`] and [`DeviceConfig::listen_port`], when they are set, with a
+    /// warning for those that differ
+    fn keep_configured_key_and_port(&self, file: &mut WgConfig) {
+        if let Some(private_key) = &self.config.private_key {
+            let public_key = |key: &x25519::StaticSecret| x25519::PublicKey::from(key);
+            let configured = x25519::StaticSecret::from(private_key.clone());
+            if file
+                .private_key
+                .as_ref()
+                .is_some_and(|key| public_key(key) != public_key(&configured))
+            {
+                tracing::warn!(
+                    "The private key of the configuration file differs from the one the device \
+                     was started with, which is kept"
+                );
+            }
+            file.private_key = Some(configured);
+        }
+        if let Some(port) = self.config.listen_port {
+            if let Some(file_port) = file.listen_port.filter(|file_port| *file_port != port) {
+                tracing::warn!(
+                    message = "The listen port of the configuration file differs from the one \
+                               the device was started with, which is kept",
+                    port,
+                    file_port
+                );
+            }
+            file.listen_port = Some(port);
+        }
+    }
+
+    /// Resolve the endpoint of a peer, see [`Device::resolve_peer_endpoint`], the peer being new
+    /// or not
+    fn resolve_endpoint_of(
+        &self,
+        resolver: &dyn Resolver,
+        public_key: &x25519::PublicKey,
+        endpoint: Option<&str>,
+    ) -> Result<Option<ResolvedEndpoints>, Error> {
+        self.resolve_peer_endpoint(
+            resolver,
+            self.endpoint_host_of(public_key).as_deref(),
+            endpoint,
+        )
+    }
+
+    /// The host name the endpoint of a peer was configured with, if any
+    fn endpoint_host_of(&self, public_key: &x25519::PublicKey) -> Option<String> {
+        self.peers
+            .get(public_key)
+            .and_then(|peer| peer.lock().endpoint_host().map(str::to_owned))
+    }
+
+    /// Resolve the host names among the endpoints of `peers` with the resolver of the device,
+    /// for [`Device::resolve_endpoint_of`] to find them once the device is locked for writing.
+    /// The host names the peers already have are skipped, like it skips them.
+    fn resolve_ahead<'a>(
+        &self,
+        peers: impl IntoIterator<Item = (&'a x25519::PublicKey, Option<&'a str>)>,
+    ) -> Preresolved {
+        let mut resolved = Preresolved::default();
+        for (public_key, endpoint) in peers {
+            if let Some(endpoint) = endpoint {
+                if self.endpoint_host_of(public_key).as_deref() != Some(endpoint) {
+                    resolved.resolve_ahead(self.resolver.as_ref(), endpoint);
+                }
+            }
+        }
+        resolved
+    }
+
+    /// Resolve the endpoints of a peer with `resolver`, unless it was configured with the same
+    /// host name as `current_host`, in which case `None` is returned as well
+    fn resolve_peer_endpoint(
+        &self,
+        resolver: &dyn Resolver,
+        current_host: Option<&str>,
+        endpoint: Option<&str>,
+    ) -> Result<Option<ResolvedEndpoints>, Error> {
+        match endpoint {
+            Some(endpoint) if Some(endpoint) != current_host => {
+                resolver::resolve_endpoints(resolver, self.prefers_ipv4(), endpoint)
+                    .map(Some)
+                    .map_err(|e| Error::InvalidConfig(format!("Endpoint {}: {}", endpoint, e)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Update the attributes of a peer that are set in `changes` and differ, leaving its sessions
+    /// alone. `endpoint` is the one of `changes`, resolved by [`Device::resolve_endpoint_of`].
+    /// Returns whether anything changed.
+    fn update_existing_peer(
+        &mut self,
+        peer: &Arc<Mutex<Peer>>,
+        public_key: &x25519::PublicKey,
+        changes: PeerChanges,
+        endpoint: Option<ResolvedEndpoints>,
+    ) -> Result<bool, Error> {
+        let mut p = peer.lock();
+        let mut changed = false;
+
+        let current_host = p.endpoint_host().map(str::to_owned);
+        match endpoint {
+            Some((addrs, _)) if addrs.len() > 1 => {
+                // Stays on the endpoint in use if the list didn't change
+                changed |= p.failover_endpoints() != addrs.as_slice() || current_host.is_some();
+                p.set_failover_endpoints(addrs);
+                p.clear_endpoint_host();
+            }
+            Some((addrs, Some(host))) => {
+                changed |= p.endpoint().addr != Some(addrs[0])
+                    || current_host != Some(host.clone())
+                    || !p.failover_endpoints().is_empty();
+                p.set_failover_endpoints(vec![]);
+                p.set_endpoint(addrs[0]);
+                p.set_endpoint_host(&host, Arc::clone(&self.resolver));
+            }
+            Some((addrs, None)) => {
+                changed |= p.endpoint().addr != Some(addrs[0])
+                    || current_host.is_some()
+                    || !p.failover_endpoints().is_empty();
+                p.set_failover_endpoints(vec![]);
+                p.set_endpoint(addrs[0]);
+                p.clear_endpoint_host();
+            }
+            None => {}
+        }
+
+        if let Some(keepalive) = changes.persistent_keepalive {
+            if p.persistent_keepalive() != keepalive {
+                p.set_persistent_keepalive(keepalive);
+                changed = true;
+            }
+        }
+
+        if let Some(preshared_key) = changes.preshared_key {
+            if p.preshared_key() != preshared_key.as_ref() {
+                p.set_preshared_key(preshared_key);
+                changed = true;
+            }
+        }
+
+        if let Some(limit) = changes.bandwidth_limit {
+            let limit = limit.filter(|l| l.bytes_per_sec > 0);
+            if p.bandwidth_limit() != limit {
+                p.set_bandwidth_limit(limit);
+                changed = true;
+            }
+        }
+
+        if let Some(policy) = changes.reconnect_policy {
+            if p.reconnect_policy() != policy {
+                p.set_reconnect_policy(policy);
+                changed = true;
+            }
         }
 
-        if let Some(ref sock) = self.udp6 {
-            sock.set_mark(mark)?;
+        if let Some(pcap_capture) = changes.pcap_capture {
+            if p.pcap_capture() != pcap_capture {
+                p.set_pcap_capture(pcap_capture);
+                changed = true;
+            }
         }
 
-        // Then on all currently connected sockets
-        for peer in self.peers.values() {
-            if let Some(ref sock) = peer.lock().endpoint().conn {
-                sock.set_mark(mark)?
+        #[cfg(feature = "mdns")]
+        if let Some(mdns_discovery) = changes.mdns_discovery {
+            if p.mdns_discovery() != mdns_discovery {
+                p.set_mdns_discovery(mdns_discovery);
+                self.follow_mdns_endpoint(&mut p, self.mdns_endpoint(public_key));
+                changed = true;
             }
         }
 
-        Ok(())
-    }
+        if let Some(allowed_ips) = changes.allowed_ips {
+            let mut current_ips: Vec<_> = p.allowed_ips().collect();
+            let mut new_ips: Vec<_> = allowed_ips.iter().map(|ip| (ip.addr, ip.cidr)).collect();
+            current_ips.sort();
+            new_ips.sort();
 
-    fn clear_peers(&mut self) {
-        self.peers.clear();
-        self.peers_by_idx.clear();
-        self.peers_by_ip.clear();
+            if current_ips != new_ips {
+                self.check_allowed_ips_limits(current_ips.len(), new_ips.len())?;
+                p.set_allowed_ips(&allowed_ips);
+
+                self.peers_by_ip
+                    .remove(&|p: &Arc<Mutex<Peer>>| Arc::ptr_eq(peer, p));
+                for AllowedIP { addr, cidr } in &allowed_ips {
+                    self.peers_by_ip.insert(*addr, *cidr as _, Arc::clone(peer));
+                }
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.schedule_timers(&mut p);
+            tracing::info!(
+                message = "Peer updated",
+                peer = self.peer_log_id(public_key)
+            );
+        }
+        Ok(changed)
     }
 
     fn register_notifiers(&mut self) -> Result<(), Error> {
@@ -534,65 +2566,247 @@ impl Device {
             // The exit event handler simply returns Action::Exit
             .new_notifier(Box::new(|_, _| Action::Exit))?;
         self.exit_notice = Some(exit_ev);
+
+        let shutdown_ev = self.queue.new_notifier(Box::new(|d, t| {
+            // The notification stays triggered until the device exits, so every thread runs this
+            // once, and drains the packets of its own queue of the tun device
+            let iface = Arc::clone(&t.iface);
+            d.handle_iface_packets(&*iface, t, SHUTDOWN_DRAIN_PACKETS);
+
+            let drained = d.drained_threads.fetch_add(1, Ordering::AcqRel) + 1;
+            if drained < d.config.n_threads {
+                return Action::Leave;
+            }
+            d.send_final_keepalives(t);
+            Action::Exit
+        }))?;
+        self.shutdown_notice = Some(shutdown_ev);
+
+        if self.handshakes.is_some() {
+            let handshake_ev = self.queue.new_notifier(Box::new(|d, _| {
+                // Send the replies of the handshake threads
+                d.queue
+                    .stop_notification(d.handshake_notice.as_ref().unwrap());
+                let handshakes = d.handshakes.as_ref().unwrap();
+                for (source, packet) in handshakes.take_replies() {
+                    d.send_to_source(&source, &packet);
+                }
+                Action::Continue
+            }))?;
+            self.handshake_notice = Some(handshake_ev);
+        }
         Ok(())
     }
 
-    fn register_timers(&self) -> Result<(), Error> {
-        self.queue.new_periodic_event(
-            // Reset the rate limiter every second give or take
+    /// Reload the configuration file with [`Device::reload_config`], between the calls of
+    /// [`DeviceConfig::on_reload`]
+    fn reload(device: &mut LockReadGuard<Device>) -> Result<(), Error> {
+        let hook = device.config.on_reload.clone();
+        if let Some(hook) = &hook {
+            (hook.0)(ReloadStage::Started);
+        }
+        // The file is read and its host names resolved before the device is locked for writing
+        let result = device.read_config_file().and_then(|file| {
+            let resolved = device.resolve_ahead(
+                file.peers
+                    .iter()
+                    .map(|p| (&p.public_key, p.endpoint.as_deref())),
+            );
+            device
+                .try_writeable(
+                    |device| device.trigger_yield(),
+                    |device| {
+                        device.cancel_yield();
+                        device.apply_config_file(file, &resolved)
+                    },
+                )
+                .expect("Write access is always eventually granted")
+        });
+        if let Some(hook) = &hook {
+            (hook.0)(match &result {
+                Ok(summary) => ReloadStage::Applied(*summary),
+                Err(e) => ReloadStage::Failed(e.to_string()),
+            });
+        }
+        result.map(|_| ())
+    }
+
+    #[cfg(unix)]
+    fn register_reload_handler(&self) -> Result<(), Error> {
+        self.queue.new_signal_event(
+            libc::SIGHUP,
             Box::new(|d, _| {
-                if let Some(r) = d.rate_limiter.as_ref() {
-                    r.reset_count()
+                // Rather than the default action of the signal, which terminates the process
+                if d.config.config_file.is_none() {
+                    tracing::warn!("Ignoring SIGHUP, there is no configuration file to reload");
+                    return Action::Continue;
+                }
+                if let Err(e) = Device::reload(d) {
+                    tracing::error!(message = "Failed to reload the configuration", error = ?e);
                 }
                 Action::Continue
             }),
-            std::time::Duration::from_secs(1),
         )?;
+        Ok(())
+    }
 
-        self.queue.new_periodic_event(
-            // Execute the timed function of every peer in the list
-            Box::new(|d, t| {
-                let peer_map = &d.peers;
+    fn register_timers(&mut self) -> Result<(), Error> {
+        if self.config.peer_state_file.is_some() {
+            self.queue.new_periodic_event(
+                Box::new(|d, _| {
+                    d.save_peer_state(true);
+                    Action::Continue
+                }),
+                peer_state::PEER_STATE_SAVE_INTERVAL,
+            )?;
+        }
 
-                let (udp4, udp6) = match (d.udp4.as_ref(), d.udp6.as_ref()) {
-                    (Some(udp4), Some(udp6)) => (udp4, udp6),
-                    _ => return Action::Continue,
-                };
+        if let Some(idle) = self.config.exit_on_idle {
+            let deadline = Mutex::new(Instant::now() + idle);
+            self.queue.new_periodic_event(
+                Box::new(move |d, _| {
+                    let now = Instant::now();
+                    let mut deadline = deadline.lock();
+                    if d.active.swap(false, Ordering::Relaxed) {
+                        *deadline = now + idle;
+                    } else if now >= *deadline {
+                        tracing::info!(message = "Shutting down, the device is idle", idle = ?idle);
+                        d.trigger_shutdown();
+                    }
+                    Action::Continue
+                }),
+                (idle / 4).min(IDLE_CHECK_INTERVAL),
+            )?;
+        }
 
-                // Go over each peer and invoke the timer function
-                for peer in peer_map.values() {
+        let timer_ev = self.queue.new_timer_event(Box::new(|d, t| {
+            #[cfg(test)]
+            d.timer_wakeups.fetch_add(1, Ordering::Relaxed);
+            // Run the timers of the peers whose deadline arrived, and schedule them again
+            let due = d.timers.lock().pop_due(Instant::now());
+            for index in due {
+                if let Some(peer) = d.peers_by_idx.get(&index) {
                     let mut p = peer.lock();
-                    let endpoint_addr = match p.endpoint().addr {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
-
-                    match p.update_timers(&mut t.dst_buf[..]) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(WireGuardError::ConnectionExpired) => {
-                            p.shutdown_endpoint(); // close open udp socket
-                        }
-                        TunnResult::Err(e) => tracing::error!(message = "Timer error", error = ?e),
-                        TunnResult::WriteToNetwork(packet) => {
-                            match endpoint_addr {
-                                SocketAddr::V4(_) => {
-                                    udp4.send_to(packet, &endpoint_addr.into()).ok()
-                                }
-                                SocketAddr::V6(_) => {
-                                    udp6.send_to(packet, &endpoint_addr.into()).ok()
-                                }
-                            };
-                        }
-                        _ => panic!("Unexpected result from update_timers"),
-                    };
+                    p.timer_deadline = None;
+                    d.run_timers(&mut p, t);
+                    d.schedule_timers(&mut p);
                 }
-                Action::Continue
-            }),
-            std::time::Duration::from_millis(250),
-        )?;
+            }
+
+            let next = d.timers.lock().rearm();
+            let delay = next.map(|at| at.saturating_duration_since(Instant::now()));
+            d.queue.set_timer(d.timer_event.as_ref().unwrap(), delay);
+            Action::Continue
+        }))?;
+        self.timer_event = Some(timer_ev);
         Ok(())
     }
 
+    /// Make sure the timer event fires once something is due for `peer`: a timer of its tunnel,
+    /// the resolution of its endpoint host name, or a failover. Must be called whenever they may
+    /// have moved earlier, the peers are not polled.
+    pub(crate) fn schedule_timers(&self, peer: &mut Peer) {
+        let now = Instant::now();
+        let tunnel = peer
+            .tunnel
+            .time_to_next_timer()
+            .map(|delay| now + delay + TIMER_SLACK);
+        let resolution = peer.resolution_deadline(self.config.dns_recheck_interval, now);
+        let fail_over = peer.fail_over_deadline(self.config.handshake_timeout, now);
+        let at = match tunnel.into_iter().chain(resolution).chain(fail_over).min() {
+            Some(at) => at,
+            None => return,
+        };
+        // Spares locking the queue on every packet
+        if peer.timer_deadline.is_some_and(|deadline| deadline <= at) {
+            return;
+        }
+        peer.timer_deadline = Some(at);
+
+        let mut timers = self.timers.lock();
+        if let (Some(at), Some(timer_ev)) = (timers.schedule(peer.index(), at), &self.timer_event) {
+            // Armed under the lock, so a later deadline doesn't overwrite an earlier one
+            self.queue
+                .set_timer(timer_ev, Some(at.saturating_duration_since(now)));
+        }
+    }
+
+    /// Execute the timed functions of `peer`
+    fn run_timers(&self, p: &mut Peer, t: &mut ThreadData) {
+        let outer = match self.outer.as_ref() {
+            Some(outer) => outer,
+            None => return,
+        };
+        let _span = p.span().clone().entered();
+
+        let public_key = p.tunnel.peer_static_public();
+        let endpoint_changed = |old, new| {
+            self.events.emit(PeerEvent::EndpointChanged {
+                public_key,
+                old,
+                new,
+            })
+        };
+
+        if let Some(interval) = self.config.dns_recheck_interval {
+            let old = p.endpoint().addr;
+            if let Some(new) = p.recheck_endpoint(interval) {
+                endpoint_changed(old, new);
+            }
+        }
+
+        let old = p.endpoint().addr;
+        let failed_over = p.fail_over(self.config.handshake_timeout);
+        if let Some(new) = failed_over {
+            endpoint_changed(old, new);
+        }
+
+        // Over TCP, the peer may be reached over the connection it made to us
+        let endpoint_addr = p.endpoint().addr;
+        if endpoint_addr.is_none() && p.tcp_link().is_none() {
+            return;
+        }
+
+        let result = match failed_over {
+            // Retry the handshake with the new endpoint right away
+            Some(_) => p
+                .tunnel
+                .try_format_handshake_initiation(&mut t.dst_buf[..], true),
+            None => p.update_timers(&mut t.dst_buf[..]),
+        };
+        match result {
+            Ok(TunnAction::Done | TunnAction::Noop) => {}
+            Err(TunnError::ConnectionExpired) => {
+                p.shutdown_endpoint(); // close open udp socket
+
+                // The peer may have moved, if its endpoint came from a host name
+                if let Some(new) = p.reresolve_endpoint() {
+                    endpoint_changed(endpoint_addr, new);
+                }
+            }
+            Err(e) => tracing::error!(message = "Timer error", error = ?e),
+            Ok(TunnAction::WriteToNetwork(packet)) => {
+                let sent = match (p.tcp_link(), endpoint_addr) {
+                    (Some(link), _) => link.send_packet(endpoint_addr, packet),
+                    (None, Some(addr)) => outer.for_addr(&addr).send_to(packet, addr),
+                    (None, None) => return,
+                };
+                p.set_unreachable(sent.is_err());
+            }
+            Ok(TunnAction::WriteToTunnel(..)) => {
+                panic!("Unexpected result from update_timers")
+            }
+        };
+    }
+
+    /// Start the period of [`DeviceConfig::exit_on_idle`] over
+    pub(crate) fn mark_active(&self) {
+        // Spares writing to a shared cache line on every packet
+        if !self.active.load(Ordering::Relaxed) {
+            self.active.store(true, Ordering::Relaxed);
+        }
+    }
+
     pub(crate) fn trigger_yield(&self) {
         self.queue
             .trigger_notification(self.yield_notice.as_ref().unwrap())
@@ -603,110 +2817,300 @@ impl Device {
             .trigger_notification(self.exit_notice.as_ref().unwrap())
     }
 
+    /// Start a graceful shutdown, see [`DeviceHandle::initiate_shutdown`]
+    pub(crate) fn trigger_shutdown(&self) {
+        if !self.shutting_down.swap(true, Ordering::AcqRel) {
+            self.queue
+                .trigger_notification(self.shutdown_notice.as_ref().unwrap())
+        }
+    }
+
     pub(crate) fn cancel_yield(&self) {
         self.queue
             .stop_notification(self.yield_notice.as_ref().unwrap())
     }
 
-    fn register_udp_handler(&self, udp: socket2::Socket) -> Result<(), Error> {
+    fn register_udp_handler(&self, udp: Arc<dyn OuterTransport>) -> Result<(), Error> {
         self.queue.new_event(
             udp.as_raw_fd(),
             Box::new(move |d, t| {
                 // Handler that handles anonymous packets over UDP
                 let mut iter = MAX_ITR;
-                let (private_key, public_key) = d.key_pair.as_ref().expect("Key not set");
-
-                let rate_limiter = d.rate_limiter.as_ref().unwrap();
 
                 // Loop while we have packets on the anonymous connection
-
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf =
-                    unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
-                while let Ok((packet_len, addr)) = udp.recv_from(src_buf) {
+                while let Ok((packet_len, addr)) = udp.recv_from(&mut t.src_buf[..]) {
                     let packet = &t.src_buf[..packet_len];
-                    // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
-                    let parsed_packet = match rate_limiter.verify_packet(
-                        Some(addr.as_socket().unwrap().ip()),
-                        packet,
-                        &mut t.dst_buf,
-                    ) {
-                        Ok(packet) => packet,
-                        Err(TunnResult::WriteToNetwork(cookie)) => {
-                            let _: Result<_, _> = udp.send_to(cookie, &addr);
-                            continue;
-                        }
-                        Err(_) => continue,
+                    let reply = |packet: &[u8]| {
+                        let _: Result<_, _> = udp.send_to(packet, addr);
                     };
+                    let source = PacketSource::Udp(addr);
+                    d.handle_incoming_packet(packet, &source, &*t.iface, &mut t.dst_buf, &reply);
 
-                    let peer = match &parsed_packet {
-                        Packet::HandshakeInit(p) => {
-                            parse_handshake_anon(private_key, public_key, p)
-                                .ok()
-                                .and_then(|hh| {
-                                    d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
-                                })
-                        }
-                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                    };
+                    iter -= 1;
+                    if iter == 0 {
+                        break;
+                    }
+                }
+                Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
 
-                    let peer = match peer {
-                        None => continue,
-                        Some(peer) => peer,
-                    };
+    /// Process a packet received from the network from `source`, replies are sent with `reply`.
+    /// Handshakes are queued for the handshake threads if there are any.
+    fn handle_incoming_packet(
+        &self,
+        packet: &[u8],
+        source: &PacketSource,
+        iface: &dyn Tun,
+        dst: &mut [u8],
+        reply: &dyn Fn(&[u8]),
+    ) {
+        let rate_limiter = self.rate_limiter.as_ref().unwrap();
+
+        // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
+        let parsed_packet = match rate_limiter.verify_packet(Some(source.ip()), packet, dst) {
+            Ok(packet) => packet,
+            Err(result) => {
+                match result.into() {
+                    Ok(TunnAction::WriteToNetwork(cookie)) => reply(cookie),
+                    _ => {
+                        #[cfg(feature = "metrics")]
+                        self.metrics.count_drop(DropReason::Invalid);
+                    }
+                }
+                return;
+            }
+        };
 
-                    let mut p = peer.lock();
+        if let Some(handshakes) = &self.handshakes {
+            let initiation = match parsed_packet {
+                Packet::HandshakeInit(_) => Some(true),
+                Packet::HandshakeResponse(_) => Some(false),
+                _ => None,
+            };
+            if let Some(initiation) = initiation {
+                handshakes.push(HandshakeJob {
+                    packet: packet.to_vec(),
+                    source: source.clone(),
+                    initiation,
+                });
+                return;
+            }
+        }
 
-                    // We found a peer, use it to decapsulate the message+
-                    let mut flush = false; // Are there packets to send from the queue?
-                    match p
-                        .tunnel
-                        .handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
-                    {
-                        TunnResult::Done => {}
-                        TunnResult::Err(_) => continue,
-                        TunnResult::WriteToNetwork(packet) => {
-                            flush = true;
-                            let _: Result<_, _> = udp.send_to(packet, &addr);
-                        }
-                        TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                t.iface.write4(packet);
-                            }
-                        }
-                        TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                t.iface.write6(packet);
-                            }
-                        }
-                    };
+        if let Some((peer, mut p)) = self.handle_parsed_packet(parsed_packet, iface, dst, reply) {
+            self.after_valid_packet(peer, &mut p, source);
+        }
+    }
 
-                    if flush {
-                        // Flush pending queue
-                        while let TunnResult::WriteToNetwork(packet) =
-                            p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
-                        {
-                            let _: Result<_, _> = udp.send_to(packet, &addr);
-                        }
-                    }
+    /// Process a handshake on a handshake thread, its replies are sent by the event loops
+    fn process_handshake(&self, job: HandshakeJob, dst: &mut [u8]) {
+        let parsed_packet = match Tunn::parse_incoming_packet(&job.packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
 
-                    // This packet was OK, that means we want to create a connected socket for this peer
-                    let addr = addr.as_socket().unwrap();
-                    let ip_addr = addr.ip();
-                    p.set_endpoint(addr);
-                    if d.config.use_connected_socket {
-                        if let Ok(sock) = p.connect_endpoint(d.listen_port, d.fwmark) {
-                            d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
-                                .unwrap();
-                        }
+        let replies = RefCell::new(vec![]);
+        let reply = |packet: &[u8]| replies.borrow_mut().push(packet.to_vec());
+        if let Some((peer, mut p)) =
+            self.handle_parsed_packet(parsed_packet, &*self.iface, dst, &reply)
+        {
+            self.after_valid_packet(peer, &mut p, &job.source);
+        }
+
+        let replies = replies.into_inner();
+        if !replies.is_empty() {
+            self.handshakes
+                .as_ref()
+                .unwrap()
+                .push_replies(&job.source, replies);
+            self.queue
+                .trigger_notification(self.handshake_notice.as_ref().unwrap());
+        }
+    }
+
+    /// Send `packet` back to where another packet came from
+    fn send_to_source(&self, source: &PacketSource, packet: &[u8]) {
+        match source {
+            PacketSource::Udp(addr) => {
+                if let Some(outer) = &self.outer {
+                    let _: Result<_, _> = outer.for_addr(addr).send_to(packet, *addr);
+                }
+            }
+            PacketSource::Tcp(conn) => {
+                let _: Result<_, _> = conn.send_packet(packet);
+            }
+        }
+    }
+
+    /// Once a valid packet came from `source`, the peer is reached there from then on
+    fn after_valid_packet(&self, peer: &Arc<Mutex<Peer>>, p: &mut Peer, source: &PacketSource) {
+        match source {
+            PacketSource::Udp(addr) => {
+                // This packet was OK, that means we want to create a connected socket for this peer
+                if p.tcp_link().is_none() {
+                    p.set_endpoint(*addr);
+                }
+                if let Some(outer) = self.outer.as_ref().filter(|outer| {
+                    // A connected socket would take the datagrams of the peer off the paths
+                    self.config.use_connected_socket
+                        && !outer.is_multipath()
+                        && p.tcp_link().is_none()
+                        && p.endpoint().conn.is_none()
+                }) {
+                    let connected = in_netns(&self.config, || {
+                        p.connect_endpoint(
+                            outer.for_addr(addr),
+                            self.fwmark,
+                            self.config.on_socket_created.as_ref(),
+                        )
+                    });
+                    if let Ok(Some(conn)) = connected {
+                        self.register_conn_handler(Arc::clone(peer), conn, addr.ip())
+                            .unwrap();
                     }
+                }
+            }
+            PacketSource::Tcp(conn) => {
+                if let Some(link) = p.tcp_link() {
+                    link.adopt(conn);
+                }
+            }
+        }
+        self.schedule_timers(p);
+    }
 
-                    iter -= 1;
-                    if iter == 0 {
-                        break;
+    /// Process a packet that passed the checks of the rate limiter. Returns the peer the packet
+    /// came from, locked, if it was valid.
+    fn handle_parsed_packet<'a>(
+        &'a self,
+        parsed_packet: Packet<'_>,
+        iface: &dyn Tun,
+        dst: &mut [u8],
+        reply: &dyn Fn(&[u8]),
+    ) -> Option<(&'a Arc<Mutex<Peer>>, MutexGuard<'a, Peer>)> {
+        let (private_key, public_key) = self.key_pair.as_ref().expect("Key not set");
+
+        let peer = match &parsed_packet {
+            Packet::HandshakeInit(p) => parse_handshake_anon(private_key, public_key, p)
+                .ok()
+                .and_then(|hh| {
+                    self.peers
+                        .get(&x25519::PublicKey::from(hh.peer_static_public))
+                }),
+            Packet::HandshakeResponse(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+            Packet::PacketCookieReply(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+            Packet::PacketData(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => {
+                #[cfg(feature = "metrics")]
+                self.metrics.count_drop(DropReason::Invalid);
+                return None;
+            }
+        };
+
+        let mut p = peer.lock();
+        // The data packets are the common case, the span is only worth it for the others
+        let _span = match parsed_packet {
+            Packet::PacketData(_) => None,
+            _ => Some(p.span().clone().entered()),
+        };
+
+        // We found a peer, use it to decapsulate the message+
+        let mut flush = false; // Are there packets to send from the queue?
+        let result: Result<_, TunnError> =
+            p.tunnel.handle_verified_packet(parsed_packet, dst).into();
+        match result {
+            Ok(TunnAction::Done | TunnAction::Noop) => {}
+            Err(_) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.count_drop(DropReason::Invalid);
+                return None;
+            }
+            Ok(TunnAction::WriteToNetwork(packet)) => {
+                flush = true;
+                reply(packet);
+            }
+            Ok(TunnAction::WriteToTunnel(packet, src)) => {
+                self.deliver_to_tunnel(peer, &mut p, iface, packet, src);
+            }
+        };
+
+        if flush {
+            // Flush pending queue
+            while let Ok(TunnAction::WriteToNetwork(packet)) =
+                p.tunnel.try_decapsulate(None, &[], dst)
+            {
+                reply(packet);
+            }
+        }
+
+        Some((peer, p))
+    }
+
+    /// Write `packet`, decapsulated from what `peer` sent, to the tunnel, unless the peer is not
+    /// allowed its source address, see [`DeviceConfig::strict_source_check`], or the packet
+    /// filter rejects it
+    fn deliver_to_tunnel(
+        &self,
+        peer: &Arc<Mutex<Peer>>,
+        p: &mut Peer,
+        iface: &dyn Tun,
+        packet: &[u8],
+        src: IpAddr,
+    ) {
+        let routed = self.config.strict_source_check.then(|| {
+            self.peers_by_ip
+                .find(src)
+                .is_some_and(|owner| Arc::ptr_eq(owner, peer))
+        });
+        if !p.check_source(src, routed) {
+            #[cfg(feature = "metrics")]
+            self.metrics.count_drop(DropReason::SourceNotAllowed);
+            return;
+        }
+        if !p.filter_inbound(self.config.packet_filter.as_deref(), packet) {
+            #[cfg(feature = "metrics")]
+            self.metrics.count_drop(DropReason::Filtered);
+            return;
+        }
+        self.capture(p, packet);
+        self.mark_active();
+        write_to_tunnel(iface, packet, src);
+        #[cfg(feature = "metrics")]
+        p.metrics.count_rx();
+    }
+
+    /// Write `packet`, exchanged with `p`, to the capture of the device, if it has one
+    fn capture(&self, p: &Peer, packet: &[u8]) {
+        if let Some(pcap) = &self.pcap {
+            if p.pcap_capture() {
+                pcap.lock().write(packet);
+            }
+        }
+    }
+
+    fn register_tcp_listener(&self, addr: SocketAddr, framing: TcpFraming) -> Result<(), Error> {
+        let listener = in_netns(&self.config, || {
+            TcpListener::bind(addr).map_err(|e| Error::Bind(format!("TCP {}: {}", addr, e)))
+        })?;
+        listener.set_nonblocking(true)?;
+        tracing::info!(message = "Listening over TCP", addr = ?listener.local_addr()?);
+
+        self.queue.new_event(
+            listener.as_raw_fd(),
+            Box::new(move |d, _| {
+                // Accept the connections of the peers, they are identified by their first packet
+                while let Ok((stream, addr)) = listener.accept() {
+                    let registered = TcpConnection::new(stream, framing)
+                        .map_err(Error::from)
+                        .and_then(|conn| Device::register_tcp_handler(&d.queue, Arc::new(conn)));
+                    if let Err(e) = registered {
+                        tracing::warn!(message = "Failed to accept a TCP connection", peer = ?addr, error = ?e);
                     }
                 }
                 Action::Continue
@@ -715,60 +3119,93 @@ impl Device {
         Ok(())
     }
 
+    /// Handle the packets received on a TCP connection, one that a peer made to us or that we
+    /// made to a peer. Whichever peer sends a valid packet on it is reached over it from then on.
+    fn register_tcp_handler(
+        queue: &EventPoll<Handler>,
+        conn: Arc<TcpConnection>,
+    ) -> Result<(), Error> {
+        let stream = conn.stream().expect("The connection is established");
+        queue.new_event(
+            stream.as_raw_fd(),
+            Box::new(move |d, t| {
+                let reply = |packet: &[u8]| {
+                    let _: Result<_, _> = conn.send_packet(packet);
+                };
+                let source = PacketSource::Tcp(Arc::clone(&conn));
+
+                // Packets that were received already are only seen here, so all of them are
+                // handled before returning
+                while let Ok(len) = conn.recv_packet(&mut t.src_buf) {
+                    let packet = &t.src_buf[..len];
+                    d.handle_incoming_packet(packet, &source, &*t.iface, &mut t.dst_buf, &reply);
+                }
+                Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
+
     fn register_conn_handler(
         &self,
         peer: Arc<Mutex<Peer>>,
-        udp: socket2::Socket,
+        udp: Arc<dyn ConnectedTransport>,
         peer_addr: IpAddr,
     ) -> Result<(), Error> {
         self.queue.new_event(
             udp.as_raw_fd(),
-            Box::new(move |_, t| {
+            Box::new(move |d, t| {
                 // The conn_handler handles packet received from a connected UDP socket, associated
                 // with a known peer, this saves us the hustle of finding the right peer. If another
                 // peer gets the same ip, it will be ignored until the socket does not expire.
-                let iface = &t.iface;
+                let iface = &*t.iface;
                 let mut iter = MAX_ITR;
 
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf =
-                    unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
-
-                while let Ok(read_bytes) = udp.recv(src_buf) {
+                loop {
+                    let read_bytes = match udp.recv(&mut t.src_buf[..]) {
+                        Ok(read_bytes) => read_bytes,
+                        Err(e) => {
+                            // The error the endpoint got is kept for the next send
+                            if matches!(
+                                e.kind(),
+                                io::ErrorKind::ConnectionRefused | io::ErrorKind::HostUnreachable
+                            ) {
+                                peer.lock().endpoint_mut().send_error = Some(e.kind());
+                            }
+                            break;
+                        }
+                    };
                     let mut flush = false;
                     let mut p = peer.lock();
-                    match p.tunnel.decapsulate(
+                    match p.tunnel.try_decapsulate(
                         Some(peer_addr),
                         &t.src_buf[..read_bytes],
                         &mut t.dst_buf[..],
                     ) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => eprintln!("Decapsulate error {:?}", e),
-                        TunnResult::WriteToNetwork(packet) => {
+                        Ok(TunnAction::Done | TunnAction::Noop) => {}
+                        Err(e) => {
+                            #[cfg(feature = "metrics")]
+                            d.metrics.count_drop(DropReason::Invalid);
+                            eprintln!("Decapsulate error {:?}", e)
+                        }
+                        Ok(TunnAction::WriteToNetwork(packet)) => {
                             flush = true;
                             let _: Result<_, _> = udp.send(packet);
                         }
-                        TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                iface.write4(packet);
-                            }
-                        }
-                        TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                iface.write6(packet);
-                            }
+                        Ok(TunnAction::WriteToTunnel(packet, src)) => {
+                            d.deliver_to_tunnel(&peer, &mut p, iface, packet, src);
                         }
                     };
 
                     if flush {
                         // Flush pending queue
-                        while let TunnResult::WriteToNetwork(packet) =
-                            p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
+                        while let Ok(TunnAction::WriteToNetwork(packet)) =
+                            p.tunnel.try_decapsulate(None, &[], &mut t.dst_buf[..])
                         {
                             let _: Result<_, _> = udp.send(packet);
                         }
                     }
+                    d.schedule_timers(&mut p);
 
                     iter -= 1;
                     if iter == 0 {
@@ -781,74 +3218,204 @@ impl Device {
         Ok(())
     }
 
-    fn register_iface_handler(&self, iface: Arc<TunSocket>) -> Result<(), Error> {
+    fn register_iface_handler(&self, iface: Arc<dyn Tun>) -> Result<(), Error> {
         self.queue.new_event(
             iface.as_raw_fd(),
-            Box::new(move |d, t| {
-                // The iface_handler handles packets received from the WireGuard virtual network
-                // interface. The flow is as follows:
-                // * Read a packet
-                // * Determine peer based on packet destination ip
-                // * Encapsulate the packet for the given peer
-                // * Send encapsulated packet to the peer's endpoint
-                let mtu = d.mtu.load(Ordering::Relaxed);
-
-                let udp4 = d.udp4.as_ref().expect("Not connected");
-                let udp6 = d.udp6.as_ref().expect("Not connected");
-
-                let peers = &d.peers_by_ip;
-                for _ in 0..MAX_ITR {
-                    let src = match iface.read(&mut t.src_buf[..mtu]) {
-                        Ok(src) => src,
-                        Err(Error::IfaceRead(e)) => {
-                            let ek = e.kind();
-                            if ek == io::ErrorKind::Interrupted || ek == io::ErrorKind::WouldBlock {
-                                break;
-                            }
-                            eprintln!("Fatal read error on tun interface: {:?}", e);
-                            return Action::Exit;
-                        }
-                        Err(e) => {
-                            eprintln!("Unexpected error on tun interface: {:?}", e);
-                            return Action::Exit;
-                        }
-                    };
+            Box::new(move |d, t| d.handle_iface_packets(&*iface, t, MAX_ITR)),
+        )?;
+        Ok(())
+    }
 
-                    let dst_addr = match Tunn::dst_address(src) {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
+    /// Handle up to `max_packets` packets received from the WireGuard virtual network
+    /// interface. The flow is as follows:
+    /// * Read a packet
+    /// * Determine peer based on packet destination ip
+    /// * Encapsulate the packet for the given peer
+    /// * Send encapsulated packet to the peer's endpoint
+    fn handle_iface_packets(
+        &self,
+        iface: &dyn Tun,
+        t: &mut ThreadData,
+        max_packets: usize,
+    ) -> Action {
+        let mtu = self.mtu.load(Ordering::Relaxed);
+
+        let outer = self.outer.as_ref().expect("Not connected");
+
+        let peers = &self.peers_by_ip;
+        for _ in 0..max_packets {
+            let src = match iface.read(&mut t.src_buf[..mtu]) {
+                Ok(src) => src,
+                Err(Error::IfaceRead(e)) => {
+                    let ek = e.kind();
+                    if ek == io::ErrorKind::Interrupted || ek == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    eprintln!("Fatal read error on tun interface: {:?}", e);
+                    return Action::Exit;
+                }
+                Err(e) => {
+                    eprintln!("Unexpected error on tun interface: {:?}", e);
+                    return Action::Exit;
+                }
+            };
+
+            let dst_addr = match Tunn::dst_address(src) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            let mut peer = match peers.find(dst_addr) {
+                Some(peer) => peer.lock(),
+                None => {
+                    #[cfg(feature = "metrics")]
+                    self.metrics.count_drop(DropReason::NoRoute);
+                    continue;
+                }
+            };
 
-                    let mut peer = match peers.find(dst_addr) {
-                        Some(peer) => peer.lock(),
-                        None => continue,
-                    };
+            if !peer.filter_outbound(self.config.packet_filter.as_deref(), src) {
+                #[cfg(feature = "metrics")]
+                self.metrics.count_drop(DropReason::Filtered);
+                continue;
+            }
 
-                    match peer.tunnel.encapsulate(src, &mut t.dst_buf[..]) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => {
-                            tracing::error!(message = "Encapsulate error", error = ?e)
-                        }
-                        TunnResult::WriteToNetwork(packet) => {
-                            let mut endpoint = peer.endpoint_mut();
-                            if let Some(conn) = endpoint.conn.as_mut() {
-                                // Prefer to send using the connected socket
-                                let _: Result<_, _> = conn.write(packet);
-                            } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp4.send_to(packet, &addr.into());
-                            } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp6.send_to(packet, &addr.into());
-                            } else {
-                                tracing::error!("No endpoint");
-                            }
-                        }
-                        _ => panic!("Unexpected result from encapsulate"),
-                    };
+            // Like a router, before the packet is forwarded to the peer
+            let src_len = src.len();
+            if self.config.decrement_inner_ttl && !icmp::decrement_ttl(&mut t.src_buf[..src_len]) {
+                let src = &t.src_buf[..src_len];
+                let from = self
+                    .config
+                    .address
+                    .iter()
+                    .map(|address| address.addr)
+                    .find(|addr| addr.is_ipv4() == dst_addr.is_ipv4());
+                if let Some(error) = icmp::time_exceeded(src, from, &mut t.dst_buf[..]) {
+                    write_to_tunnel(iface, error, dst_addr);
                 }
-                Action::Continue
-            }),
-        )?;
-        Ok(())
+                #[cfg(feature = "metrics")]
+                self.metrics.count_drop(DropReason::TtlExceeded);
+                continue;
+            }
+            let src = &t.src_buf[..src_len];
+
+            // Tell the sender about packets that would exceed the path MTU once encapsulated,
+            // unless they may be fragmented
+            let max_size = peer.max_inner_size(self.config.mtu);
+            let oversized = max_size.is_some_and(|max| src.len() > max);
+            if oversized && pmtu::wants_packet_too_big(src) {
+                let max = max_size.unwrap_or_default();
+                if let Some(error) = pmtu::packet_too_big(src, max, &mut t.dst_buf[..]) {
+                    write_to_tunnel(iface, error, dst_addr);
+                }
+                #[cfg(feature = "metrics")]
+                self.metrics.count_drop(DropReason::TooBig);
+                continue;
+            }
+
+            // Drop packets over the bandwidth limit before spending time encrypting them
+            let delay = match peer.shape_tx(src.len()) {
+                Some(delay) => delay,
+                None => {
+                    #[cfg(feature = "metrics")]
+                    self.metrics.count_drop(DropReason::BandwidthLimit);
+                    continue;
+                }
+            };
+
+            self.capture(&peer, src);
+            self.mark_active();
+
+            // Pad with zeros after the packet, up to the path MTU at most
+            let max_len = max_size.map_or(mtu, |max| max.min(mtu));
+            let len = self.config.padding_mode.padded_len(src_len, max_len);
+            t.src_buf[src_len..len].fill(0);
+
+            // The path of the packet, with several sockets to an endpoint
+            let flow = outer
+                .is_multipath()
+                .then(|| multipath::flow_hash(&t.src_buf[..src_len]));
+            let sent = match peer
+                .tunnel
+                .try_encapsulate(&t.src_buf[..len], &mut t.dst_buf[..])
+            {
+                Ok(TunnAction::Done | TunnAction::Noop) => Ok(()),
+                Err(e) => {
+                    tracing::error!(message = "Encapsulate error", error = ?e);
+                    Ok(())
+                }
+                Ok(TunnAction::WriteToNetwork(packet)) => {
+                    #[cfg(feature = "metrics")]
+                    peer.metrics.count_tx();
+                    let sent = send_to_endpoint(&peer, outer, packet, oversized, delay, flow);
+                    peer.set_unreachable(sent.is_err());
+                    sent
+                }
+                Ok(TunnAction::WriteToTunnel(..)) => {
+                    panic!("Unexpected result from encapsulate")
+                }
+            };
+            self.schedule_timers(&mut peer);
+
+            // Tell the sender right away when the endpoint can't be reached, the packet is still
+            // in the source buffer
+            if let Err(e) = sent {
+                let src = &t.src_buf[..src_len];
+                if let Some(error) = icmp::destination_unreachable(src, &e, &mut t.dst_buf[..]) {
+                    tracing::debug!(message = "Endpoint unreachable", error = ?e);
+                    write_to_tunnel(iface, error, dst_addr);
+                }
+            }
+        }
+        Action::Continue
+    }
+
+    /// Send a keepalive to every peer with a session, so they know we were still alive up to
+    /// the shutdown
+    fn send_final_keepalives(&self, t: &mut ThreadData) {
+        let outer = match self.outer.as_ref() {
+            Some(outer) => outer,
+            None => return,
+        };
+
+        for peer in self.peers.values() {
+            let mut peer = peer.lock();
+            if peer.time_since_last_handshake().is_none() {
+                // Without a session, the keepalive would start a handshake instead
+                continue;
+            }
+            if let Ok(TunnAction::WriteToNetwork(packet)) =
+                peer.tunnel.try_encapsulate(&[], &mut t.dst_buf[..])
+            {
+                let _: Result<_, _> =
+                    send_to_endpoint(&peer, outer, packet, false, Duration::ZERO, None);
+            }
+        }
+    }
+}
+
+/// Connects the TCP links of the peers in the network namespace of the device, and registers
+/// their connections with the event loop
+struct DeviceConnector {
+    queue: Arc<EventPoll<Handler>>,
+    config: DeviceConfig,
+}
+
+impl Connector for DeviceConnector {
+    fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        in_netns(&self.config, || {
+            TcpStream::connect_timeout(&addr, timeout).map_err(Error::from)
+        })
+        .map_err(|e| match e {
+            Error::IoError(e) => e,
+            e => io::Error::other(e.to_string()),
+        })
+    }
+
+    fn connected(&self, conn: Arc<TcpConnection>) {
+        if let Err(e) = Device::register_tcp_handler(&self.queue, conn) {
+            tracing::error!(message = "Failed to register a TCP connection", error = ?e);
+        }
     }
 }
 
@@ -0,0 +1,133 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Spreading the traffic of the peers over several local UDP sockets, see
+//! [`super::DeviceConfig::bind_addrs`].
+//!
+//! Each packet from the tunnel is assigned a path by a hash of its 5-tuple: its addresses, its
+//! protocol and, for TCP, UDP, UDP-Lite and SCTP, its ports. The packets of a connection then all
+//! take the same path and arrive in order, while different connections add up the bandwidth of
+//! the paths. The hash is the same from one run to the next.
+
+/// The offset of the first byte after the fixed IPv6 header
+const IPV6_HEADER_LEN: usize = 40;
+
+/// The protocols that start with a source and a destination port
+const PROTOCOLS_WITH_PORTS: [u8; 4] = [
+    6,   // TCP
+    17,  // UDP
+    132, // SCTP
+    136, // UDP-Lite
+];
+
+/// The hash of the 5-tuple of an IP packet, or 0 for a packet too short to have one
+pub(crate) fn flow_hash(packet: &[u8]) -> u64 {
+    let (addrs, protocol, payload) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            // Only the first fragment has the ports, the flow of the others is their addresses
+            let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+            let payload = match fragmented {
+                true => &[][..],
+                false => packet.get(header_len..).unwrap_or_default(),
+            };
+            (&packet[12..20], packet[9], payload)
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => {
+            (&packet[8..40], packet[6], &packet[IPV6_HEADER_LEN..])
+        }
+        _ => return 0,
+    };
+
+    let mut hash = Fnv1a::default();
+    hash.write(addrs);
+    hash.write(&[protocol]);
+    if PROTOCOLS_WITH_PORTS.contains(&protocol) {
+        if let Some(ports) = payload.get(..4) {
+            hash.write(ports);
+        }
+    }
+    hash.finish()
+}
+
+/// The 64 bit FNV-1a hash, which is stable, unlike the hashers of the standard library. Its low
+/// bits, which pick the path, are mixed with the others once done.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    /// The hash, through the finalizer of MurmurHash3
+    fn finish(&self) -> u64 {
+        let mut hash = self.0;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A UDP packet over IPv4 between the ports of 10.0.0.1 and 10.0.0.2
+    fn udp4(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        packet[20..22].copy_from_slice(&src_port.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        // Flows differ by their ports, the packets of a flow hash the same whatever their content
+        let flow = flow_hash(&udp4(1000, 53));
+        assert_ne!(flow, 0);
+        assert_ne!(flow, flow_hash(&udp4(1001, 53)));
+        let mut packet = udp4(1000, 53);
+        packet.extend_from_slice(b"payload");
+        assert_eq!(flow_hash(&packet), flow);
+
+        // Fragments after the first are told apart by their addresses only
+        let mut fragment = udp4(1000, 53);
+        fragment[7] = 1;
+        let mut other = udp4(1001, 53);
+        other[7] = 1;
+        assert_eq!(flow_hash(&fragment), flow_hash(&other));
+
+        // Without ports, ICMP flows are told apart by their addresses
+        let mut icmp = udp4(1000, 53);
+        icmp[9] = 1;
+        let mut other = udp4(1001, 53);
+        other[9] = 1;
+        assert_eq!(flow_hash(&icmp), flow_hash(&other));
+
+        let mut ipv6 = vec![0u8; 48];
+        ipv6[0] = 0x60;
+        ipv6[6] = 6;
+        ipv6[40..42].copy_from_slice(&1000u16.to_be_bytes());
+        let flow = flow_hash(&ipv6);
+        ipv6[40..42].copy_from_slice(&1001u16.to_be_bytes());
+        assert_ne!(flow_hash(&ipv6), flow);
+
+        assert_eq!(flow_hash(&[0x45; 10]), 0);
+        assert_eq!(flow_hash(&[]), 0);
+    }
+}
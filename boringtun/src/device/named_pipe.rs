@@ -0,0 +1,216 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The listening side of a named pipe, the transport of the UAPI on Windows. Named pipes have no
+//! readiness to poll for, so a thread waits for the clients to connect, and queues the
+//! connections for the event loop, which is woken up with an event.
+
+use super::poll::{AsRawFd, RawFd};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::windows::io::FromRawHandle;
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, LocalFree, ERROR_PIPE_CONNECTED, GENERIC_READ, GENERIC_WRITE,
+    HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows_sys::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, FILE_FLAG_FIRST_PIPE_INSTANCE, OPEN_EXISTING, PIPE_ACCESS_DUPLEX,
+};
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+    PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use windows_sys::Win32::System::Threading::{CreateEventW, ResetEvent, SetEvent};
+
+/// Only SYSTEM and the administrators may connect, and the pipe is owned by SYSTEM, which `wg`
+/// checks before it trusts the pipe. The same descriptor as wireguard-go.
+const SECURITY_DESCRIPTOR: &str = "O:SYD:P(A;;GA;;;SY)(A;;GA;;;BA)S:(ML;;NWNRNX;;;HI)";
+
+const BUFFER_SIZE: u32 = 4096;
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+// The security descriptor of the instances of the pipe
+struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+// The descriptor is only read once it is created
+unsafe impl Send for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    fn new() -> io::Result<SecurityDescriptor> {
+        let sddl = wide(SECURITY_DESCRIPTOR);
+        let mut descriptor = null_mut();
+        if unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                null_mut(),
+            )
+        } == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SecurityDescriptor(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0 as _) };
+    }
+}
+
+// Create a new instance of the pipe, that a single client can connect to
+fn create_instance(
+    path: &[u16],
+    descriptor: &SecurityDescriptor,
+    first: bool,
+) -> io::Result<HANDLE> {
+    let attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor.0,
+        bInheritHandle: 0,
+    };
+    let mode = match first {
+        // Fail if the pipe is already served, e.g. by another instance of boringtun
+        true => PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+        false => PIPE_ACCESS_DUPLEX,
+    };
+
+    match unsafe {
+        CreateNamedPipeW(
+            path.as_ptr(),
+            mode,
+            PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            &attributes,
+        )
+    } {
+        INVALID_HANDLE_VALUE => Err(io::Error::last_os_error()),
+        pipe => Ok(pipe),
+    }
+}
+
+// The state shared with the thread that accepts the connections
+struct Shared {
+    connections: Mutex<VecDeque<File>>,
+    ready: HANDLE, // A manual reset event, set exactly when there are connections to accept
+    closed: AtomicBool,
+}
+
+// The event is only passed to thread safe functions
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.ready) };
+    }
+}
+
+/// A named pipe server, like a `UnixListener`
+pub struct PipeListener {
+    path: Vec<u16>,
+    shared: Arc<Shared>,
+}
+
+impl PipeListener {
+    pub fn bind(path: &str) -> io::Result<PipeListener> {
+        let path = wide(path);
+        let descriptor = SecurityDescriptor::new()?;
+        let mut pipe = create_instance(&path, &descriptor, true)?;
+
+        let ready = match unsafe { CreateEventW(null(), 1, 0, null()) } {
+            0 => {
+                let err = io::Error::last_os_error();
+                unsafe { CloseHandle(pipe) };
+                return Err(err);
+            }
+            event => event,
+        };
+        let shared = Arc::new(Shared {
+            connections: Default::default(),
+            ready,
+            closed: AtomicBool::new(false),
+        });
+
+        let thread_path = path.clone();
+        let thread_shared = Arc::clone(&shared);
+        thread::spawn(move || loop {
+            let connected = unsafe { ConnectNamedPipe(pipe, null_mut()) } != 0
+                || unsafe { GetLastError() } == ERROR_PIPE_CONNECTED;
+            let conn = unsafe { File::from_raw_handle(pipe as _) };
+            if thread_shared.closed.load(Ordering::Acquire) {
+                return;
+            }
+            if connected {
+                let mut connections = thread_shared.connections.lock();
+                connections.push_back(conn);
+                unsafe { SetEvent(thread_shared.ready) };
+            }
+
+            pipe = match create_instance(&thread_path, &descriptor, false) {
+                Ok(pipe) => pipe,
+                Err(e) => {
+                    tracing::error!(message = "Failed to create a UAPI pipe instance", error = ?e);
+                    return;
+                }
+            };
+        });
+
+        Ok(PipeListener { path, shared })
+    }
+
+    /// Take a connected client, if any
+    pub fn accept(&self) -> Option<File> {
+        let mut connections = self.shared.connections.lock();
+        let conn = connections.pop_front();
+        if connections.is_empty() {
+            unsafe { ResetEvent(self.shared.ready) };
+        }
+        conn
+    }
+}
+
+impl Drop for PipeListener {
+    fn drop(&mut self) {
+        // Connect to the pipe, so the thread stops waiting for a client and sees it must exit
+        self.shared.closed.store(true, Ordering::Release);
+        let client = unsafe {
+            CreateFileW(
+                self.path.as_ptr(),
+                GENERIC_READ | GENERIC_WRITE,
+                0,
+                null(),
+                OPEN_EXISTING,
+                0,
+                0,
+            )
+        };
+        if client != INVALID_HANDLE_VALUE {
+            unsafe { CloseHandle(client) };
+        }
+    }
+}
+
+impl AsRawFd for PipeListener {
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Handle(self.shared.ready)
+    }
+}
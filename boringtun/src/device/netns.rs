@@ -0,0 +1,66 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Creation of the TUN device and of the UDP sockets inside another network namespace.
+//!
+//! A socket or TUN device belongs to the namespace of the thread that creates it, and keeps
+//! working from any namespace after that. Interfaces are looked up by name in the namespace of
+//! the calling thread too, e.g. to read the MTU. To leave the namespace of the worker threads
+//! alone, all of that happens on a short lived setup thread, which joins the namespace and goes
+//! back to its original one when done.
+
+use crate::device::Error;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+
+fn setns(fd: RawFd) -> Result<(), Error> {
+    match unsafe { libc::setns(fd, libc::CLONE_NEWNET) } {
+        -1 => Err(Error::Netns(io::Error::last_os_error())),
+        _ => Ok(()),
+    }
+}
+
+/// Run `f` on a setup thread inside the network namespace `netns_fd`, or run it directly if
+/// there is none
+pub(crate) fn run_in_netns<T, F>(netns_fd: Option<RawFd>, f: F) -> Result<T, Error>
+where
+    T: Send,
+    F: FnOnce() -> Result<T, Error> + Send,
+{
+    let netns_fd = match netns_fd {
+        Some(fd) => fd,
+        None => return f(),
+    };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let original = File::open("/proc/thread-self/ns/net").map_err(Error::Netns)?;
+            setns(netns_fd)?;
+            let result = f();
+            setns(original.as_raw_fd())?;
+            result
+        })
+        .join()
+        .expect("The netns setup thread panicked")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_in_no_netns() {
+        let thread = thread::current().id();
+        let same_thread = run_in_netns(None, || Ok(thread::current().id() == thread));
+        assert!(same_thread.unwrap());
+    }
+
+    #[test]
+    fn test_run_in_bad_netns() {
+        let result = run_in_netns(Some(-1), || Ok(()));
+        assert!(matches!(result, Err(Error::Netns(_))));
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Padding of the packets from the tunnel before they are encrypted, so that the size of the
+//! datagrams tells less about their content.
+//!
+//! The padding is made of zeros after the IP packet. Receivers drop it after decryption, they
+//! truncate packets to the length of their IP header, so peers don't need to support padding.
+
+use rand_core::{OsRng, RngCore};
+use std::num::NonZeroU16;
+
+/// How the packets of the tunnel are padded, see [`super::DeviceConfig::padding_mode`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingMode {
+    /// Packets are sent as they are
+    #[default]
+    None,
+    /// Pad packets to the next multiple of this number of bytes
+    BlockSize(NonZeroU16),
+    /// Pad packets with a random number of bytes, from 0 up to this number
+    RandomUpTo(u16),
+}
+
+impl PaddingMode {
+    /// The length of a packet of `len` bytes once padded, which exceeds `max_len` only if the
+    /// packet already did. Keepalives, which are empty, are never padded.
+    pub(crate) fn padded_len(self, len: usize, max_len: usize) -> usize {
+        let padded = match self {
+            _ if len == 0 => return 0,
+            PaddingMode::None => return len,
+            PaddingMode::BlockSize(block) => {
+                let block = usize::from(block.get());
+                len.div_ceil(block) * block
+            }
+            PaddingMode::RandomUpTo(max) => {
+                len + (OsRng.next_u32() % (u32::from(max) + 1)) as usize
+            }
+        };
+        padded.min(max_len).max(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_len() {
+        assert_eq!(PaddingMode::None.padded_len(100, 1420), 100);
+
+        let block = PaddingMode::BlockSize(NonZeroU16::new(64).unwrap());
+        assert_eq!(block.padded_len(100, 1420), 128);
+        assert_eq!(block.padded_len(128, 1420), 128);
+        assert_eq!(block.padded_len(1410, 1420), 1420);
+        assert_eq!(block.padded_len(1500, 1420), 1500);
+        assert_eq!(block.padded_len(0, 1420), 0);
+
+        let random = PaddingMode::RandomUpTo(10);
+        for _ in 0..100 {
+            assert!((100..=110).contains(&random.padded_len(100, 1420)));
+        }
+        assert_eq!(random.padded_len(1420, 1420), 1420);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A capture of the packets of the tunnel in the pcap format, for Wireshark or tcpdump, see
+//! [`super::DeviceConfig::pcap_path`].
+//!
+//! The packets are captured as the tunnel sees them: those that are received once decrypted, just
+//! before they are written to the TUN device, and those that are sent once read from the TUN
+//! device, just before they are encrypted. They are raw IP packets, the link type of the file is
+//! `LINKTYPE_RAW`.
+//!
+//! Each packet is written at once, under a lock, so the file can be read while the device runs.
+//! The write that would take the file past [`super::DeviceConfig::pcap_max_bytes`] first moves
+//! it to `PATH.1`, replacing the previous one, and starts a new file.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The raw IPv4 and IPv6 packets of the tunnel
+const LINKTYPE_RAW: u32 = 101;
+/// The largest packet the file tells readers to expect
+const SNAPLEN: u32 = 65535;
+const HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+pub(crate) struct PcapWriter {
+    file: File,
+    path: PathBuf,
+    /// The bytes written to `file`, with its header
+    size: u64,
+    max_bytes: Option<u64>,
+    /// Set when the last write failed, so that the failure is logged once
+    failed: bool,
+}
+
+impl PcapWriter {
+    /// Create the file at `path`, truncating it if it exists
+    pub(crate) fn create(path: &Path, max_bytes: Option<u64>) -> io::Result<PcapWriter> {
+        // Absolute, the working directory changes when the process daemonizes
+        let path = std::path::absolute(path)?;
+        Ok(PcapWriter {
+            file: create_file(&path)?,
+            path,
+            size: HEADER_LEN,
+            max_bytes,
+            failed: false,
+        })
+    }
+
+    /// Append `packet`, captured now
+    pub(crate) fn write(&mut self, packet: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+
+        // The directory may not be writable anymore once the privileges are dropped, or out of
+        // reach in the sandbox. The packets then stay in the current file, which is offered to
+        // rotate again once it grew as much.
+        if self.rotate_for(record.len() as u64).is_err() {
+            self.size = HEADER_LEN;
+        }
+        match self.file.write_all(&record) {
+            Err(e) if !self.failed => {
+                self.failed = true;
+                tracing::warn!(message = "Failed to write to the pcap file", path = ?self.path, error = ?e);
+            }
+            Err(_) => {}
+            Ok(()) => {
+                self.size += record.len() as u64;
+                self.failed = false;
+            }
+        }
+    }
+
+    /// Start a new file if `len` more bytes would take the current one past its maximum size
+    fn rotate_for(&mut self, len: u64) -> io::Result<()> {
+        match self.max_bytes {
+            Some(max) if self.size > HEADER_LEN && self.size + len > max => {
+                let mut rotated = self.path.clone().into_os_string();
+                rotated.push(".1");
+                std::fs::rename(&self.path, rotated)?;
+                self.file = create_file(&self.path)?;
+                self.size = HEADER_LEN;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Create the file at `path`, with the header of a pcap file
+fn create_file(path: &Path) -> io::Result<File> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    // Microsecond timestamps, in the byte order of the magic number
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+
+    let mut file = File::create(path)?;
+    file.write_all(&header)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_writer() {
+        let path = std::env::temp_dir().join(format!("boringtun-pcap-{}", std::process::id()));
+        let rotated = path.with_extension("1");
+        let _ = std::fs::remove_file(&rotated);
+
+        // Room for the header and two packets of 4 bytes
+        let mut writer = PcapWriter::create(&path, Some(64)).unwrap();
+        writer.write(&[0x45, 0, 0, 4]);
+        writer.write(&[0x45, 0, 0, 5]);
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file.len(), 64);
+        assert_eq!(file[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(file[20..24], [101, 0, 0, 0]);
+        // The lengths and the packet after the timestamps of the record
+        assert_eq!(file[32..44], [4, 0, 0, 0, 4, 0, 0, 0, 0x45, 0, 0, 4]);
+        assert_eq!(file[60..], [0x45, 0, 0, 5]);
+
+        // The third one starts a new file
+        writer.write(&[0x45, 0, 0, 6]);
+        assert_eq!(std::fs::read(&rotated).unwrap(), file);
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file.len(), 44);
+        assert_eq!(file[40..], [0x45, 0, 0, 6]);
+
+        // A packet larger than the maximum size gets a file of its own
+        writer.write(&[0; 64]);
+        assert_eq!(std::fs::read(&path).unwrap().len(), 104);
+        assert_eq!(std::fs::read(&rotated).unwrap(), file);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
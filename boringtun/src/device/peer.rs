@@ -2,18 +2,31 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use parking_lot::RwLock;
-use socket2::{Domain, Protocol, Type};
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::device::{AllowedIps, Error};
-use crate::noise::{Tunn, TunnResult};
+use crate::device::filter::{FilterVerdict, PacketFilter};
+use crate::device::pmtu::{self, PATH_MTU_EXPIRY};
+use crate::device::resolver::{Reresolver, Resolver};
+use crate::device::shaper::{BandwidthLimit, TokenBucket};
+use crate::device::transport::tcp::TcpLink;
+use crate::device::{AllowedIps, ConnectedTransport, Error, OuterTransport, SocketHook};
+use crate::noise::{ReconnectPolicy, Tunn, TunnAction, TunnError};
 
 #[derive(Default, Debug)]
 pub struct Endpoint {
     pub addr: Option<SocketAddr>,
-    pub conn: Option<socket2::Socket>,
+    pub conn: Option<Arc<dyn ConnectedTransport>>,
+    /// The path MTU learned from the connected socket, and when it was learned
+    pub(crate) path_mtu: Option<(usize, Instant)>,
+    /// An error about the endpoint that the connected socket received while it was waiting for
+    /// packets, reported by the next send instead, as the kernel would
+    pub(crate) send_error: Option<io::ErrorKind>,
 }
 
 pub struct Peer {
@@ -24,6 +37,73 @@ pub struct Peer {
     endpoint: RwLock<Endpoint>,
     allowed_ips: AllowedIps<()>,
     preshared_key: Option<[u8; 32]>,
+    /// Set when the endpoint was configured with a host name
+    reresolver: Option<Reresolver>,
+    /// Set when the traffic sent to the peer is shaped
+    tx_shaper: Option<TokenBucket>,
+    /// The number of packets to the peer that were dropped by the shaper
+    tx_dropped: u64,
+    /// The number of packets from and to the peer that were dropped by the packet filter
+    filtered_packets: u64,
+    /// The number of packets from the peer with a source address it is not allowed
+    rx_drops_allowed_ips: u64,
+    /// The endpoints to fail over between, empty unless several were configured
+    failover: Vec<SocketAddr>,
+    /// The index in `failover` of the endpoint in use
+    active_endpoint: usize,
+    /// When the handshake in progress moved to the endpoint in use
+    failed_over_at: Option<Instant>,
+    /// The deadline the device scheduled the timers of the peer for, if any
+    pub(crate) timer_deadline: Option<Instant>,
+    /// Set when the packets of the peer are sent over TCP
+    tcp: Option<TcpLink>,
+    /// Set when the last packet sent to the endpoint failed
+    unreachable: bool,
+    /// Set when the endpoint follows the one the peer advertises over mDNS, see [`super::mdns`]
+    #[cfg(feature = "mdns")]
+    mdns_discovery: bool,
+    /// Whether the packets of the peer go to the capture of the device, if it has one
+    pcap_capture: bool,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: super::metrics::PeerMetrics,
+    /// The span of the events of the peer, entered by the device around its handshakes and
+    /// timers
+    span: tracing::Span,
+}
+
+/// A snapshot of the statistics of a peer, see [`Peer::stats`]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerStats {
+    pub time_since_last_handshake: Option<Duration>,
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+    /// See [`Peer::tx_dropped`]
+    pub tx_dropped: u64,
+    /// See [`Peer::filtered_packets`]
+    pub filtered_packets: u64,
+    /// See [`Peer::path_mtu`]
+    pub path_mtu: Option<usize>,
+    /// The index of the endpoint in use among the failover endpoints, if several were configured
+    pub active_endpoint: Option<usize>,
+    /// See [`Peer::is_unreachable`]
+    pub unreachable: bool,
+    /// How long until the handshake initiation in progress is retried, which shows how far the
+    /// [`crate::noise::ReconnectPolicy`] backed off
+    pub next_retry_in: Option<Duration>,
+    /// See [`crate::noise::DropStats::rx_auth`]
+    pub rx_drops_auth: u64,
+    /// See [`crate::noise::DropStats::rx_replay`]
+    pub rx_drops_replay: u64,
+    /// Packets from the peer with a source address it is not allowed, or that the allowed IPs of
+    /// another peer match best with [`super::DeviceConfig::strict_source_check`]
+    pub rx_drops_allowed_ips: u64,
+    /// See [`crate::noise::DropStats::tx_no_session`]
+    pub tx_drops_no_session: u64,
+    /// The same count as `rx_drops_replay`, under the name of the `rx_replay_errors` key of the
+    /// UAPI. Many of them tell of a replay attack, or of packets reordered far along the path.
+    pub replay_detected_packets: u64,
+    /// The same count as `rx_drops_auth`, under the name of the `rx_mac_errors` key of the UAPI
+    pub mac_failure_packets: u64,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -32,21 +112,62 @@ pub struct AllowedIP {
     pub cidr: u8,
 }
 
+/// Why a string is not an [`AllowedIP`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AllowedIPParseError {
+    #[error("Invalid CIDR, expected an address and a prefix length such as 10.0.0.0/8")]
+    BadCidr,
+    #[error("The address has bits set after the prefix")]
+    HostBitsSet,
+    #[error("The prefix length is too long for the address family")]
+    InvalidAddressFamily,
+}
+
+impl AllowedIP {
+    /// Parse an address and its prefix length, such as `10.0.0.1/24`, the address of an
+    /// interface on its network. Unlike [`FromStr`], the bits after the prefix may be set.
+    pub fn parse_address(s: &str) -> Result<AllowedIP, AllowedIPParseError> {
+        let (addr, cidr) = s.split_once('/').ok_or(AllowedIPParseError::BadCidr)?;
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| AllowedIPParseError::BadCidr)?;
+        let cidr = cidr
+            .parse::<u8>()
+            .map_err(|_| AllowedIPParseError::BadCidr)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if cidr > max {
+            return Err(AllowedIPParseError::InvalidAddressFamily);
+        }
+        Ok(AllowedIP { addr, cidr })
+    }
+
+    /// Whether bits of the address are set after the prefix
+    fn has_host_bits(&self) -> bool {
+        let (bits, len) = match self.addr {
+            IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => (u128::from(addr), 128),
+        };
+        let host_len = len - u32::from(self.cidr);
+        bits.checked_shl(128 - host_len).unwrap_or(0) != 0
+    }
+}
+
+/// Parse a network in CIDR notation, such as `10.0.0.0/8` or `2001:db8::/32`
 impl FromStr for AllowedIP {
-    type Err = String;
+    type Err = AllowedIPParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let ip: Vec<&str> = s.split('/').collect();
-        if ip.len() != 2 {
-            return Err("Invalid IP format".to_owned());
+        let ip = AllowedIP::parse_address(s)?;
+        if ip.has_host_bits() {
+            return Err(AllowedIPParseError::HostBitsSet);
         }
+        Ok(ip)
+    }
+}
 
-        let (addr, cidr) = (ip[0].parse::<IpAddr>(), ip[1].parse::<u8>());
-        match (addr, cidr) {
-            (Ok(addr @ IpAddr::V4(_)), Ok(cidr)) if cidr <= 32 => Ok(AllowedIP { addr, cidr }),
-            (Ok(addr @ IpAddr::V6(_)), Ok(cidr)) if cidr <= 128 => Ok(AllowedIP { addr, cidr }),
-            _ => Err("Invalid IP format".to_owned()),
-        }
+impl fmt::Display for AllowedIP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.cidr)
     }
 }
 
@@ -64,14 +185,41 @@ impl Peer {
             endpoint: RwLock::new(Endpoint {
                 addr: endpoint,
                 conn: None,
+                path_mtu: None,
+                send_error: None,
             }),
             allowed_ips: allowed_ips.iter().map(|ip| (ip, ())).collect(),
             preshared_key,
+            reresolver: None,
+            tx_shaper: None,
+            tx_dropped: 0,
+            filtered_packets: 0,
+            rx_drops_allowed_ips: 0,
+            failover: vec![],
+            active_endpoint: 0,
+            failed_over_at: None,
+            timer_deadline: None,
+            tcp: None,
+            unreachable: false,
+            #[cfg(feature = "mdns")]
+            mdns_discovery: false,
+            pcap_capture: true,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            span: tracing::Span::none(),
         }
     }
 
-    pub fn update_timers<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
-        self.tunnel.update_timers(dst)
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    pub(crate) fn set_span(&mut self, span: tracing::Span) {
+        self.span = span;
+    }
+
+    pub fn update_timers<'a>(&mut self, dst: &'a mut [u8]) -> Result<TunnAction<'a>, TunnError> {
+        self.tunnel.try_update_timers(dst)
     }
 
     pub fn endpoint(&self) -> parking_lot::RwLockReadGuard<'_, Endpoint> {
@@ -83,9 +231,11 @@ impl Peer {
     }
 
     pub fn shutdown_endpoint(&self) {
-        if let Some(conn) = self.endpoint.write().conn.take() {
-            tracing::info!("Disconnecting from endpoint");
-            conn.shutdown(Shutdown::Both).unwrap();
+        let mut endpoint = self.endpoint.write();
+        if let Some(conn) = endpoint.conn.take() {
+            let _span = self.span.enter();
+            tracing::info!(message = "Disconnecting from endpoint", endpoint = ?endpoint.addr);
+            conn.shutdown();
         }
     }
 
@@ -94,18 +244,177 @@ impl Peer {
         if endpoint.addr != Some(addr) {
             // We only need to update the endpoint if it differs from the current one
             if let Some(conn) = endpoint.conn.take() {
-                conn.shutdown(Shutdown::Both).unwrap();
+                conn.shutdown();
             }
 
             endpoint.addr = Some(addr);
+            endpoint.path_mtu = None;
+            endpoint.send_error = None;
+            let _span = self.span.enter();
+            tracing::debug!(message = "Endpoint updated", endpoint = ?addr);
+        }
+    }
+
+    /// The MTU of the path to the endpoint, if sending to it failed because a packet was too big
+    pub fn path_mtu(&self) -> Option<usize> {
+        match self.endpoint().path_mtu {
+            Some((mtu, learned)) if learned.elapsed() < PATH_MTU_EXPIRY => Some(mtu),
+            _ => None,
+        }
+    }
+
+    /// The largest packet from the tunnel that can be sent to the endpoint without exceeding
+    /// its path MTU once encapsulated, `default_mtu` is used when the path MTU isn't known
+    pub(crate) fn max_inner_size(&self, default_mtu: Option<u16>) -> Option<usize> {
+        let mtu = self.path_mtu().or(default_mtu.map(usize::from))?;
+        let ipv4 = self.endpoint().addr?.is_ipv4();
+        Some(pmtu::max_inner_size(mtu, ipv4))
+    }
+
+    /// Configure several endpoints, when a handshake gets no response within the handshake
+    /// timeout the next one is tried. The first one is used right away, unless the list didn't
+    /// change.
+    /// A single endpoint disables failover, without changing the endpoint.
+    pub fn set_failover_endpoints(&mut self, endpoints: Vec<SocketAddr>) {
+        let endpoints = if endpoints.len() > 1 {
+            endpoints
+        } else {
+            vec![]
+        };
+        if endpoints == self.failover {
+            return;
+        }
+
+        if let Some(&first) = endpoints.first() {
+            self.set_endpoint(first);
+        }
+        self.failover = endpoints;
+        self.active_endpoint = 0;
+        self.failed_over_at = None;
+    }
+
+    /// The endpoints to fail over between, empty unless several were configured
+    pub fn failover_endpoints(&self) -> &[SocketAddr] {
+        &self.failover
+    }
+
+    /// Move on to the next failover endpoint, in order, once the handshake in progress got no
+    /// response from the current one for `timeout`. The endpoint that completes the handshake
+    /// stays in use, later failovers start from it. Returns the new endpoint, the caller should
+    /// send it a handshake initiation right away.
+    pub(crate) fn fail_over(&mut self, timeout: Duration) -> Option<SocketAddr> {
+        if self.failover.is_empty() {
+            return None;
+        }
+
+        let waiting = match self.tunnel.time_since_handshake_started() {
+            Some(waiting) => waiting,
+            None => {
+                self.failed_over_at = None;
+                return None;
+            }
+        };
+        let on_current = match self.failed_over_at {
+            Some(at) => waiting.min(at.elapsed()),
+            None => waiting,
+        };
+        if on_current < timeout {
+            return None;
+        }
+
+        self.active_endpoint = (self.active_endpoint + 1) % self.failover.len();
+        self.failed_over_at = Some(Instant::now());
+        let addr = self.failover[self.active_endpoint];
+        let _span = self.span.enter();
+        tracing::info!(message = "Handshake timed out, failing over", endpoint = ?addr);
+        self.set_endpoint(addr);
+        Some(addr)
+    }
+
+    /// When [`Peer::fail_over`] moves on to the next endpoint, unless the handshake in progress
+    /// gets a response first
+    pub(crate) fn fail_over_deadline(&self, timeout: Duration, now: Instant) -> Option<Instant> {
+        if self.failover.is_empty() {
+            return None;
         }
+        let started = now.checked_sub(self.tunnel.time_since_handshake_started()?)?;
+        let on_current = self.failed_over_at.map_or(started, |at| at.max(started));
+        Some(on_current + timeout)
+    }
+
+    /// Send the packets of the peer over `link` instead of UDP, see [`super::Transport::Tcp`]
+    pub(crate) fn set_tcp_link(&mut self, link: TcpLink) {
+        self.tcp = Some(link);
     }
 
+    pub(crate) fn tcp_link(&self) -> Option<&TcpLink> {
+        self.tcp.as_ref()
+    }
+
+    /// Remember the host name the endpoint was resolved from, in the `host:port` form, so it
+    /// can be resolved again if the peer stops responding.
+    pub fn set_endpoint_host(&mut self, host: &str, resolver: Arc<dyn Resolver>) {
+        self.reresolver = Some(Reresolver::new(host.to_owned(), resolver));
+    }
+
+    /// Forget the host name of the endpoint, it is no longer re-resolved
+    pub fn clear_endpoint_host(&mut self) {
+        self.reresolver = None;
+    }
+
+    /// The host name the endpoint was resolved from, if any
+    pub fn endpoint_host(&self) -> Option<&str> {
+        self.reresolver.as_ref().map(|r| r.host())
+    }
+
+    /// Resolve the endpoint host name again, in case the peer moved to a different address.
+    /// Resolution happens in the background and is retried with an exponential backoff, the
+    /// endpoint is updated once an attempt yields a new address, which is returned.
+    pub(crate) fn reresolve_endpoint(&mut self) -> Option<SocketAddr> {
+        let current = self.endpoint().addr;
+        let new_addr = self.reresolver.as_mut()?.poll(current, Instant::now());
+        self.update_resolved_endpoint(new_addr)
+    }
+
+    /// Like [`Peer::reresolve_endpoint`], but only resolves the host name once `interval` has
+    /// passed since the last resolution.
+    pub(crate) fn recheck_endpoint(&mut self, interval: Duration) -> Option<SocketAddr> {
+        let current = self.endpoint().addr;
+        let new_addr = self
+            .reresolver
+            .as_mut()?
+            .recheck(current, Instant::now(), interval);
+        self.update_resolved_endpoint(new_addr)
+    }
+
+    /// When the endpoint host name should be resolved again, by [`Peer::reresolve_endpoint`] once
+    /// the connection expired, or by [`Peer::recheck_endpoint`] with `recheck_interval`
+    pub(crate) fn resolution_deadline(
+        &self,
+        recheck_interval: Option<Duration>,
+        now: Instant,
+    ) -> Option<Instant> {
+        let expired = self.tunnel.is_expired();
+        self.reresolver
+            .as_ref()?
+            .next_poll(expired, recheck_interval, now)
+    }
+
+    fn update_resolved_endpoint(&mut self, new_addr: Option<SocketAddr>) -> Option<SocketAddr> {
+        let addr = new_addr?;
+        let _span = self.span.enter();
+        tracing::info!(message = "Endpoint address changed", host = self.endpoint_host(), endpoint = ?addr);
+        self.set_endpoint(addr);
+        Some(addr)
+    }
+
+    /// Open a handle of `transport` to the endpoint, returns `None` if the transport has none
     pub fn connect_endpoint(
         &self,
-        port: u16,
-        #[allow(unused_variables)] fwmark: Option<u32>,
-    ) -> Result<socket2::Socket, Error> {
+        transport: &dyn OuterTransport,
+        fwmark: Option<u32>,
+        on_socket_created: Option<&SocketHook>,
+    ) -> Result<Option<Arc<dyn ConnectedTransport>>, Error> {
         let mut endpoint = self.endpoint.write();
 
         if endpoint.conn.is_some() {
@@ -116,38 +425,38 @@ impl Peer {
             .addr
             .expect("Attempt to connect to undefined endpoint");
 
-        let udp_conn =
-            socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::UDP))?;
-        udp_conn.set_reuse_address(true)?;
-        let bind_addr = if addr.is_ipv4() {
-            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()
-        } else {
-            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into()
+        let conn = match transport.connect(addr, fwmark, on_socket_created)? {
+            Some(conn) => conn,
+            None => return Ok(None),
         };
-        udp_conn.bind(&bind_addr)?;
-        udp_conn.connect(&addr.into())?;
-        udp_conn.set_nonblocking(true)?;
-
-        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-        if let Some(fwmark) = fwmark {
-            udp_conn.set_mark(fwmark)?;
-        }
 
+        let _span = self.span.enter();
         tracing::info!(
             message="Connected endpoint",
-            port=port,
+            port=transport.local_addr()?.port(),
             endpoint=?endpoint.addr.unwrap()
         );
 
-        endpoint.conn = Some(udp_conn.try_clone().unwrap());
+        endpoint.conn = Some(Arc::clone(&conn));
 
-        Ok(udp_conn)
+        Ok(Some(conn))
     }
 
     pub fn is_allowed_ip<I: Into<IpAddr>>(&self, addr: I) -> bool {
         self.allowed_ips.find(addr.into()).is_some()
     }
 
+    /// Whether the peer may send packets from `src`, the others are counted as dropped. With
+    /// `routed`, whether the device routes `src` to the peer as well, which only differs when
+    /// the allowed IPs of the peers overlap.
+    pub(crate) fn check_source(&mut self, src: IpAddr, routed: Option<bool>) -> bool {
+        let allowed = self.is_allowed_ip(src) && routed.unwrap_or(true);
+        if !allowed {
+            self.rx_drops_allowed_ips += 1;
+        }
+        allowed
+    }
+
     pub fn allowed_ips(&self) -> impl Iterator<Item = (IpAddr, u8)> + '_ {
         self.allowed_ips.iter().map(|(_, ip, cidr)| (ip, cidr))
     }
@@ -156,10 +465,53 @@ impl Peer {
         self.tunnel.time_since_last_handshake()
     }
 
+    pub fn set_persistent_keepalive(&mut self, persistent_keepalive: Option<u16>) {
+        self.tunnel.set_persistent_keepalive(persistent_keepalive);
+    }
+
+    pub fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.tunnel.set_preshared_key(preshared_key);
+        self.preshared_key = preshared_key;
+    }
+
+    /// Replace the allowed IPs of the peer. The routing table of the device must be updated
+    /// separately.
+    pub(crate) fn set_allowed_ips(&mut self, allowed_ips: &[AllowedIP]) {
+        self.allowed_ips = allowed_ips.iter().map(|ip| (ip, ())).collect();
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
         self.tunnel.persistent_keepalive()
     }
 
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.tunnel.reconnect_policy()
+    }
+
+    /// See [`Tunn::set_reconnect_policy`]
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.tunnel.set_reconnect_policy(policy);
+    }
+
+    #[cfg(feature = "mdns")]
+    pub fn mdns_discovery(&self) -> bool {
+        self.mdns_discovery
+    }
+
+    #[cfg(feature = "mdns")]
+    pub fn set_mdns_discovery(&mut self, mdns_discovery: bool) {
+        self.mdns_discovery = mdns_discovery;
+    }
+
+    /// See [`super::config::PeerConfig::pcap_capture`]
+    pub fn pcap_capture(&self) -> bool {
+        self.pcap_capture
+    }
+
+    pub fn set_pcap_capture(&mut self, pcap_capture: bool) {
+        self.pcap_capture = pcap_capture;
+    }
+
     pub fn preshared_key(&self) -> Option<&[u8; 32]> {
         self.preshared_key.as_ref()
     }
@@ -167,4 +519,256 @@ impl Peer {
     pub fn index(&self) -> u32 {
         self.index
     }
+
+    pub fn bandwidth_limit(&self) -> Option<BandwidthLimit> {
+        self.tx_shaper.as_ref().map(TokenBucket::limit)
+    }
+
+    /// Limit the rate of the traffic sent to the peer, packets over the limit are dropped. A
+    /// limit of zero bytes per second, or `None`, disables shaping.
+    pub fn set_bandwidth_limit(&mut self, limit: Option<BandwidthLimit>) {
+        if limit == self.bandwidth_limit() {
+            return;
+        }
+        self.tx_shaper = limit
+            .filter(|l| l.bytes_per_sec > 0)
+            .map(|l| TokenBucket::new(l, Instant::now()));
+    }
+
+    /// The number of packets to the peer that were dropped because of the bandwidth limit
+    pub fn tx_dropped(&self) -> u64 {
+        self.tx_dropped
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        let (time_since_last_handshake, tx_bytes, rx_bytes, ..) = self.tunnel.stats();
+        let drops = self.tunnel.drop_stats();
+        PeerStats {
+            time_since_last_handshake,
+            tx_bytes,
+            rx_bytes,
+            tx_dropped: self.tx_dropped,
+            filtered_packets: self.filtered_packets,
+            path_mtu: self.path_mtu(),
+            active_endpoint: (!self.failover.is_empty()).then_some(self.active_endpoint),
+            unreachable: self.unreachable,
+            next_retry_in: self.tunnel.next_retry_in(),
+            rx_drops_auth: drops.rx_auth,
+            rx_drops_replay: drops.rx_replay,
+            rx_drops_allowed_ips: self.rx_drops_allowed_ips,
+            tx_drops_no_session: drops.tx_no_session,
+            replay_detected_packets: drops.rx_replay,
+            mac_failure_packets: drops.rx_auth,
+        }
+    }
+
+    /// Whether the last packet sent to the endpoint failed, such as when the outer transport
+    /// can't reach it, instead of being sent and lost
+    pub fn is_unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    /// Record the outcome of sending a packet to the endpoint
+    pub(crate) fn set_unreachable(&mut self, unreachable: bool) {
+        if unreachable && !self.unreachable {
+            let _span = self.span.enter();
+            tracing::warn!(message = "Endpoint unreachable", endpoint = ?self.endpoint().addr);
+        }
+        self.unreachable = unreachable;
+    }
+
+    /// The number of packets from and to the peer that were dropped by the packet filter
+    pub fn filtered_packets(&self) -> u64 {
+        self.filtered_packets
+    }
+
+    /// Run the packet filter, if any, on a packet about to be sent to the peer. Returns false if
+    /// it must be dropped.
+    pub(crate) fn filter_outbound(
+        &mut self,
+        filter: Option<&(dyn PacketFilter + Send + Sync)>,
+        packet: &[u8],
+    ) -> bool {
+        let verdict = match filter {
+            Some(filter) => filter.filter_outbound(&self.tunnel.peer_static_public(), packet),
+            None => return true,
+        };
+        self.count_verdict(verdict)
+    }
+
+    /// Like [`Peer::filter_outbound`], for a packet received from the peer
+    pub(crate) fn filter_inbound(
+        &mut self,
+        filter: Option<&(dyn PacketFilter + Send + Sync)>,
+        packet: &[u8],
+    ) -> bool {
+        let verdict = match filter {
+            Some(filter) => filter.filter_inbound(&self.tunnel.peer_static_public(), packet),
+            None => return true,
+        };
+        self.count_verdict(verdict)
+    }
+
+    fn count_verdict(&mut self, verdict: FilterVerdict) -> bool {
+        match verdict {
+            FilterVerdict::Allow => true,
+            FilterVerdict::Drop => {
+                self.filtered_packets += 1;
+                false
+            }
+        }
+    }
+
+    /// Account for an IP packet about to be sent to the peer. Returns how long the kernel holds
+    /// it before sending it, which is zero unless the packets are paced, see [`shaper`], or `None`
+    /// if it must be dropped.
+    ///
+    /// [`shaper`]: crate::device::shaper
+    pub(crate) fn shape_tx(&mut self, len: usize) -> Option<Duration> {
+        let shaper = match self.tx_shaper.as_mut() {
+            Some(shaper) => shaper,
+            None => return Some(Duration::ZERO),
+        };
+
+        #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+        let delay = shaper.pace(len, Instant::now());
+        #[cfg(not(all(feature = "tx-pacing", target_os = "linux")))]
+        let delay = shaper
+            .try_consume(len, Instant::now())
+            .then_some(Duration::ZERO);
+
+        if delay.is_none() {
+            self.tx_dropped += 1;
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x25519;
+    use parking_lot::Mutex;
+    use rand_core::OsRng;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// The fields of an event or a span, as `name=value`
+    #[derive(Debug, Default, Clone)]
+    struct Fields(Vec<String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push(format!("{}={}", field.name(), value));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    /// Records the fields of each event, with the fields of the spans it is in
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<Fields>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            for span in ctx.event_scope(event).into_iter().flatten() {
+                fields
+                    .0
+                    .extend(span.extensions().get::<Fields>().unwrap().0.clone());
+            }
+            self.0.lock().push(fields);
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+            let public_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            let tunnel = Tunn::new(private_key, public_key, None, None, 0, None, None).unwrap();
+            let mut peer = Peer::new(tunnel, 0, None, &[], None);
+            peer.set_span(tracing::info_span!(
+                "peer",
+                interface = "wg0",
+                peer = "AAAAAAAA"
+            ));
+
+            peer.set_endpoint("192.0.2.1:51820".parse().unwrap());
+            peer.set_unreachable(true);
+            // The device enters the span around the handshakes and the timers of the tunnel
+            let _span = peer.span().clone().entered();
+            peer.tunnel
+                .format_handshake_initiation(&mut [0u8; 256], false);
+        });
+
+        let events = recorder.0.lock();
+        let fields: Vec<_> = events.iter().map(|fields| fields.0.join(" ")).collect();
+        let span = "interface=wg0 peer=AAAAAAAA";
+        assert_eq!(
+            fields,
+            [
+                format!("message=Endpoint updated endpoint=192.0.2.1:51820 {}", span),
+                format!(
+                    "message=Endpoint unreachable endpoint=Some(192.0.2.1:51820) {}",
+                    span
+                ),
+                format!(
+                    "message=Sending message msg_type=handshake_initiation {}",
+                    span
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_allowed_ip() {
+        for s in [
+            "10.0.0.0/8",
+            "192.168.1.1/32",
+            "0.0.0.0/0",
+            "2001:db8::/32",
+            "::/0",
+        ] {
+            let ip: AllowedIP = s.parse().unwrap();
+            assert_eq!(ip.to_string(), s);
+        }
+        let ip: AllowedIP = "2001:db8::/32".parse().unwrap();
+        assert_eq!(ip.addr, "2001:db8::".parse::<IpAddr>().unwrap());
+        assert_eq!(ip.cidr, 32);
+
+        let err = |s: &str| s.parse::<AllowedIP>().unwrap_err();
+        assert_eq!(err("10.0.0.0"), AllowedIPParseError::BadCidr);
+        assert_eq!(err("10.0.0/8"), AllowedIPParseError::BadCidr);
+        assert_eq!(err("10.0.0.0/x"), AllowedIPParseError::BadCidr);
+        assert_eq!(err("10.0.0.0/8/8"), AllowedIPParseError::BadCidr);
+        assert_eq!(err("10.0.0.1/8"), AllowedIPParseError::HostBitsSet);
+        assert_eq!(err("2001:db8::1/64"), AllowedIPParseError::HostBitsSet);
+        assert_eq!(
+            err("10.0.0.0/33"),
+            AllowedIPParseError::InvalidAddressFamily
+        );
+        assert_eq!(
+            err("2001:db8::/129"),
+            AllowedIPParseError::InvalidAddressFamily
+        );
+
+        // The address of an interface keeps its host bits
+        let ip = AllowedIP::parse_address("10.0.0.1/24").unwrap();
+        assert_eq!(ip.to_string(), "10.0.0.1/24");
+    }
 }
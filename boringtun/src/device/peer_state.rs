@@ -0,0 +1,263 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Persistence of the last known endpoints of peers, so peers behind a NAT remain reachable after
+//! a restart, before they send any traffic to us.
+//!
+//! The state is stored as a single JSON object:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "peers": [
+//!     { "public_key": "<base64>", "endpoint": "192.0.2.1:51820", "last_handshake": 1700000000 }
+//!   ]
+//! }
+//! ```
+//!
+//! `last_handshake` is in seconds since the Unix epoch.
+
+use crate::x25519;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PEER_STATE_VERSION: u32 = 1;
+
+/// How often the state is written to disk while the device runs
+pub(crate) const PEER_STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+struct PeerStateFile {
+    version: u32,
+    peers: Vec<PeerState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct PeerState {
+    /// Base64 encoded public key of the peer
+    public_key: String,
+    endpoint: SocketAddr,
+    /// Seconds since the Unix epoch
+    last_handshake: u64,
+}
+
+impl PeerState {
+    /// `time_since_last_handshake` is relative to now
+    pub(crate) fn new(
+        public_key: &x25519::PublicKey,
+        endpoint: SocketAddr,
+        time_since_last_handshake: Duration,
+    ) -> PeerState {
+        let last_handshake = SystemTime::now()
+            .checked_sub(time_since_last_handshake)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        PeerState {
+            public_key: base64::encode(public_key.as_bytes()),
+            endpoint,
+            last_handshake: last_handshake.as_secs(),
+        }
+    }
+}
+
+/// Saves the state file of a device. The periodic saves are written by a thread of their own,
+/// so the event loops never wait for the disk.
+pub(crate) struct PeerStateSaver {
+    path: PathBuf,
+    /// The number of states collected so far, and under the lock the last one written, so a
+    /// state that was overtaken doesn't replace a newer one
+    collected: AtomicU64,
+    written: Mutex<u64>,
+    /// Set while a thread writes, the periodic saves are skipped meanwhile rather than pile up
+    writing: AtomicBool,
+}
+
+impl PeerStateSaver {
+    pub(crate) fn new(path: PathBuf) -> Arc<PeerStateSaver> {
+        Arc::new(PeerStateSaver {
+            path,
+            collected: AtomicU64::new(0),
+            written: Mutex::new(0),
+            writing: AtomicBool::new(false),
+        })
+    }
+
+    /// Write `peers` on a new thread, unless the previous write is still going
+    pub(crate) fn save_in_background(self: &Arc<Self>, peers: Vec<PeerState>) {
+        if self.writing.swap(true, Ordering::Acquire) {
+            return;
+        }
+        let generation = self.collected.fetch_add(1, Ordering::Relaxed) + 1;
+        let saver = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name("peer-state".to_owned())
+            .spawn(move || {
+                saver.write(generation, peers);
+                saver.writing.store(false, Ordering::Release);
+            });
+        if let Err(e) = spawned {
+            self.writing.store(false, Ordering::Release);
+            tracing::error!(message = "Failed to save peer state", path = ?self.path, error = ?e);
+        }
+    }
+
+    /// Write `peers` on this thread, such as on shutdown, once the write in progress is done
+    pub(crate) fn save_now(&self, peers: Vec<PeerState>) {
+        let generation = self.collected.fetch_add(1, Ordering::Relaxed) + 1;
+        self.write(generation, peers);
+    }
+
+    fn write(&self, generation: u64, peers: Vec<PeerState>) {
+        let mut written = self.written.lock();
+        if *written > generation {
+            return;
+        }
+        if let Err(e) = save(&self.path, peers) {
+            tracing::error!(message = "Failed to save peer state", path = ?self.path, error = ?e);
+        }
+        *written = generation;
+    }
+}
+
+/// Atomically replace the state file at `path` with `peers`
+pub(crate) fn save(path: &Path, peers: Vec<PeerState>) -> io::Result<()> {
+    let state = PeerStateFile {
+        version: PEER_STATE_VERSION,
+        peers,
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Load the endpoints of the peers that had a handshake within `max_age`. A missing or corrupted
+/// file is not an error, it just has no endpoints.
+pub(crate) fn load(path: &Path, max_age: Duration) -> HashMap<x25519::PublicKey, SocketAddr> {
+    let state = match fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice::<PeerStateFile>(&data).map_err(|e| e.to_string()))
+    {
+        Ok(state) if state.version == PEER_STATE_VERSION => state,
+        Ok(state) => {
+            tracing::warn!(
+                message = "Ignoring peer state of unknown version",
+                version = state.version
+            );
+            return HashMap::new();
+        }
+        Err(e) => {
+            tracing::warn!(message = "Failed to load peer state", path = ?path, error = e);
+            return HashMap::new();
+        }
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    state
+        .peers
+        .into_iter()
+        .filter(|p| now.saturating_sub(p.last_handshake) <= max_age.as_secs())
+        .filter_map(|p| {
+            let key: [u8; 32] = base64::decode(&p.public_key).ok()?.try_into().ok()?;
+            Some((x25519::PublicKey::from(key), p.endpoint))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("boringtun-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_peer_state_round_trip() {
+        let path = temp_path("peer-state-round-trip");
+        let fresh = x25519::PublicKey::from([1u8; 32]);
+        let stale = x25519::PublicKey::from([2u8; 32]);
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+
+        save(
+            &path,
+            vec![
+                PeerState::new(&fresh, endpoint, Duration::from_secs(10)),
+                PeerState::new(&stale, endpoint, Duration::from_secs(7200)),
+            ],
+        )
+        .unwrap();
+
+        let endpoints = load(&path, Duration::from_secs(3600));
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints.get(&fresh), Some(&endpoint));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_peer_state_corrupted() {
+        let path = temp_path("peer-state-corrupted");
+        let max_age = Duration::from_secs(3600);
+
+        assert!(load(&path, max_age).is_empty()); // Missing
+
+        fs::write(&path, b"{\"version\": 1, \"peers\": [").unwrap();
+        assert!(load(&path, max_age).is_empty());
+
+        fs::write(&path, b"{\"version\": 2, \"peers\": []}").unwrap();
+        assert!(load(&path, max_age).is_empty());
+
+        // A bad key only drops that entry
+        fs::write(
+            &path,
+            b"{\"version\": 1, \"peers\": [{\"public_key\": \"AAAA\", \"endpoint\": \"192.0.2.1:1\", \"last_handshake\": 0}]}",
+        )
+        .unwrap();
+        assert!(load(&path, Duration::MAX).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_peer_state_saver_keeps_newest() {
+        let path = temp_path("peer-state-saver");
+        let (old, new) = (
+            x25519::PublicKey::from([1u8; 32]),
+            x25519::PublicKey::from([2u8; 32]),
+        );
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let state = |key| vec![PeerState::new(key, endpoint, Duration::from_secs(10))];
+
+        // The background write, whenever it runs, doesn't replace the state saved after it
+        let saver = PeerStateSaver::new(path.clone());
+        saver.save_in_background(state(&old));
+        saver.save_now(state(&new));
+        while saver.writing.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let endpoints = load(&path, Duration::from_secs(3600));
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints.get(&new), Some(&endpoint));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
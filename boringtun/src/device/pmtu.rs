@@ -0,0 +1,226 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Path MTU handling for the traffic sent to a peer.
+//!
+//! The connected socket of a peer sends with DF set, so a send fails with `EMSGSIZE` once the
+//! kernel learned that the path to the endpoint has a smaller MTU, which is then read from the
+//! socket. Packets from the tunnel that would not fit in it once encapsulated are dropped, and an
+//! ICMP "fragmentation needed" (IPv4 with DF set) or "packet too big" (IPv6) is sent back through
+//! the tunnel, so path MTU discovery of the sender works across the tunnel. Those that may be
+//! fragmented are sent from the listening socket instead, which lets the kernel fragment them.
+
+use super::icmp::{self, ICMP_DEST_UNREACH, IPV4_HEADER_LEN, IPV6_HEADER_LEN};
+use std::time::Duration;
+
+/// A learned path MTU is forgotten after a while, like the kernel does, in case the path changed
+pub(crate) const PATH_MTU_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// UDP and WireGuard data message overhead: 8 bytes of UDP header, 16 bytes of data message
+/// header and 16 bytes of authentication tag
+const ENCAPSULATION_OVERHEAD: usize = 8 + 16 + 16;
+
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+
+/// The largest packet from the tunnel that fits in `path_mtu` once encapsulated
+pub(crate) fn max_inner_size(path_mtu: usize, ipv4_endpoint: bool) -> usize {
+    let ip_header = if ipv4_endpoint {
+        IPV4_HEADER_LEN
+    } else {
+        IPV6_HEADER_LEN
+    };
+    path_mtu.saturating_sub(ip_header + ENCAPSULATION_OVERHEAD)
+}
+
+/// The MTU of the interface when the network towards the peers has `outer_mtu`, which leaves room
+/// for the encapsulation over IPv6, the larger of the two
+pub(crate) fn inner_mtu(outer_mtu: u16) -> u32 {
+    max_inner_size(usize::from(outer_mtu), false) as u32
+}
+
+/// Whether the sender of `packet` should be told it is too big rather than having it fragmented:
+/// IPv4 packets with DF set and all IPv6 packets. ICMP errors never trigger another error.
+pub(crate) fn wants_packet_too_big(packet: &[u8]) -> bool {
+    let wants_error = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_HEADER_LEN => packet[6] & 0x40 != 0, // DF set
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => true,
+        _ => false,
+    };
+    wants_error && !icmp::is_icmp_error(packet)
+}
+
+/// Write into `dst` an ICMP error telling the sender of `packet` that the largest packet that can
+/// go through is `mtu` bytes. The error appears to come from the destination of `packet`.
+/// Returns `None` if `packet` is not a valid IP packet or `dst` is too small.
+pub(crate) fn packet_too_big<'a>(packet: &[u8], mtu: usize, dst: &'a mut [u8]) -> Option<&'a [u8]> {
+    let mut v4_header = [ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED, 0, 0, 0, 0, 0, 0];
+    v4_header[6..8].copy_from_slice(&(mtu.min(usize::from(u16::MAX)) as u16).to_be_bytes());
+    let mut v6_header = [ICMPV6_PACKET_TOO_BIG, 0, 0, 0, 0, 0, 0, 0];
+    v6_header[4..8].copy_from_slice(&(mtu as u32).to_be_bytes());
+    icmp::icmp_error(packet, v4_header, v6_header, dst)
+}
+
+/// Never fragment the datagrams sent from `socket`, sends fail with `EMSGSIZE` instead
+#[cfg(target_os = "linux")]
+pub(crate) fn set_dont_fragment(socket: &socket2::Socket, ipv4: bool) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name, value) = match ipv4 {
+        true => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        false => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+    };
+    match unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as _,
+            std::mem::size_of_val(&value) as _,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// The path MTU the kernel learned for the destination of a connected socket
+#[cfg(target_os = "linux")]
+pub(crate) fn socket_path_mtu(socket: &socket2::Socket, ipv4: bool) -> Option<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = match ipv4 {
+        true => (libc::IPPROTO_IP, libc::IP_MTU),
+        false => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&mtu) as libc::socklen_t;
+    match unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut _ as _,
+            &mut len,
+        )
+    } {
+        0 if mtu > 0 => Some(mtu as usize),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn socket_path_mtu(_: &socket2::Socket, _: bool) -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::icmp::{checksum, IPV4_MAX_ICMP_ERROR_LEN, IPV6_MAX_ICMP_ERROR_LEN};
+
+    /// A TCP SYN with DF set from 10.0.0.2:40000 to 10.0.0.1:80
+    const TCP_SYN_V4: &[u8] = &[
+        0x45, 0x00, 0x00, 0x28, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x14, 0x9a, 0x0a, 0x00, 0x00,
+        0x02, 0x0a, 0x00, 0x00, 0x01, 0x9c, 0x40, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x50, 0x02, 0xff, 0xff, 0xff, 0x4e, 0x00, 0x00,
+    ];
+
+    /// The fragmentation needed error for `TCP_SYN_V4`, with a next-hop MTU of 1380
+    const FRAG_NEEDED_V4: &[u8] = &[
+        0x45, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00, 0x00, 0x40, 0x01, 0x66, 0xb7, 0x0a, 0x00, 0x00,
+        0x01, 0x0a, 0x00, 0x00, 0x02, 0x03, 0x04, 0x0b, 0xb5, 0x00, 0x00, 0x05, 0x64, 0x45, 0x00,
+        0x00, 0x28, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x14, 0x9a, 0x0a, 0x00, 0x00, 0x02, 0x0a,
+        0x00, 0x00, 0x01, 0x9c, 0x40, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x50, 0x02, 0xff, 0xff, 0xff, 0x4e, 0x00, 0x00,
+    ];
+
+    /// A UDP datagram with a 4 byte payload from [fd00::2]:5000 to [fd00::1]:53
+    const UDP_V6: &[u8] = &[
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x11, 0x40, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x13, 0x88, 0x00, 0x35, 0x00,
+        0x0c, 0x54, 0x77, 0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    /// The packet too big error for `UDP_V6`, with an MTU of 1280
+    const PACKET_TOO_BIG_V6: &[u8] = &[
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3a, 0x40, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x8d, 0x55, 0x00,
+        0x00, 0x05, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x11, 0x40, 0xfd, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xfd, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x13, 0x88,
+        0x00, 0x35, 0x00, 0x0c, 0x54, 0x77, 0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    #[test]
+    fn test_frag_needed_v4() {
+        let mut dst = [0u8; 1500];
+        assert_eq!(
+            packet_too_big(TCP_SYN_V4, 1380, &mut dst),
+            Some(FRAG_NEEDED_V4)
+        );
+    }
+
+    #[test]
+    fn test_packet_too_big_v6() {
+        let mut dst = [0u8; 1500];
+        assert_eq!(
+            packet_too_big(UDP_V6, 1280, &mut dst),
+            Some(PACKET_TOO_BIG_V6)
+        );
+    }
+
+    #[test]
+    fn test_packet_too_big_quotes_at_most_the_minimum_mtu() {
+        let mut big = vec![0u8; 1500];
+        big[..IPV4_HEADER_LEN].copy_from_slice(&TCP_SYN_V4[..IPV4_HEADER_LEN]);
+        let mut dst = [0u8; 1500];
+        let error = packet_too_big(&big, 1400, &mut dst).unwrap();
+        assert_eq!(error.len(), IPV4_MAX_ICMP_ERROR_LEN);
+        assert_eq!(checksum(&[&error[IPV4_HEADER_LEN..]]), 0);
+
+        let mut big = vec![0u8; 1500];
+        big[..IPV6_HEADER_LEN].copy_from_slice(&UDP_V6[..IPV6_HEADER_LEN]);
+        let error = packet_too_big(&big, 1400, &mut dst).unwrap();
+        assert_eq!(error.len(), IPV6_MAX_ICMP_ERROR_LEN);
+
+        assert!(packet_too_big(&big, 1400, &mut [0u8; 100]).is_none());
+    }
+
+    #[test]
+    fn test_wants_packet_too_big() {
+        assert!(wants_packet_too_big(TCP_SYN_V4));
+        assert!(wants_packet_too_big(UDP_V6));
+
+        // DF not set
+        let mut fragmentable = TCP_SYN_V4.to_vec();
+        fragmentable[6] = 0;
+        assert!(!wants_packet_too_big(&fragmentable));
+
+        // No error in response to an error
+        let mut error = FRAG_NEEDED_V4.to_vec();
+        error[6] = 0x40;
+        assert!(!wants_packet_too_big(&error));
+        assert!(!wants_packet_too_big(PACKET_TOO_BIG_V6));
+        assert!(!wants_packet_too_big(&[]));
+    }
+
+    #[test]
+    fn test_max_inner_size() {
+        assert_eq!(max_inner_size(1500, true), 1440);
+        assert_eq!(max_inner_size(1500, false), 1420);
+        assert_eq!(max_inner_size(40, true), 0);
+        assert_eq!(inner_mtu(1500), 1420);
+        assert_eq!(inner_mtu(1280), 1200);
+    }
+}
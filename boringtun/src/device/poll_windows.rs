@@ -0,0 +1,412 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::Error;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::ops::Deref;
+use std::os::windows::io::AsRawSocket;
+use std::ptr::null;
+use std::time::Duration;
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0};
+use windows_sys::Win32::Networking::WinSock::{
+    WSACloseEvent, WSACreateEvent, WSAEnumNetworkEvents, WSAEventSelect, FD_CLOSE, FD_READ, SOCKET,
+    SOCKET_ERROR, WSANETWORKEVENTS,
+};
+use windows_sys::Win32::System::Threading::{
+    CancelWaitableTimer, CreateEventW, CreateWaitableTimerW, ResetEvent, SetEvent,
+    SetWaitableTimer, WaitForMultipleObjects, INFINITE,
+};
+
+/// The most objects WaitForMultipleObjects can wait on, one of them is taken by the poll itself
+const MAXIMUM_WAIT_OBJECTS: usize = 64;
+
+/// What triggers an event. It takes the place of the file descriptors of the Unix polls, so the
+/// rest of the device is the same on every platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RawFd {
+    /// A waitable object, triggering while it is signaled, e.g. an event
+    Handle(HANDLE),
+    /// A socket, triggering while it has data to read or was closed
+    Socket(SOCKET),
+}
+
+/// The counterpart of `std::os::unix::io::AsRawFd` for the triggers of events
+pub trait AsRawFd {
+    fn as_raw_fd(&self) -> RawFd;
+}
+
+impl AsRawFd for socket2::Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Socket(self.as_raw_socket() as SOCKET)
+    }
+}
+
+impl AsRawFd for std::net::TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Socket(self.as_raw_socket() as SOCKET)
+    }
+}
+
+impl AsRawFd for std::net::TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Socket(self.as_raw_socket() as SOCKET)
+    }
+}
+
+/// A return type for the EventPoll::wait() function
+pub enum WaitResult<'a, H> {
+    /// Event triggered normally
+    Ok(EventGuard<'a, H>),
+    /// Event triggered due to End of File conditions
+    EoF(EventGuard<'a, H>),
+    /// There was an error
+    Error(String),
+}
+
+/// Implements a registry of pollable events
+pub struct EventPoll<H: Sized> {
+    events: Mutex<HashMap<RawFd, Slot<H>>>,
+    waiter: Mutex<()>, // Only one thread waits on the objects at a time
+    changed: HANDLE,   // Wakes up the waiting thread when the objects to wait on change
+}
+
+/// A type that hold a reference to a triggered Event
+/// While an EventGuard exists for a given Event, it will not be triggered by any other thread
+/// Once the EventGuard goes out of scope, the underlying Event will be re-enabled
+pub struct EventGuard<'a, H> {
+    event: &'a mut Event<H>,
+    poll: &'a EventPoll<H>,
+}
+
+/// A reference to a single event in an EventPoll
+pub struct EventRef {
+    trigger: RawFd,
+}
+
+#[derive(PartialEq)]
+enum EventKind {
+    Handle,   // Waits on the handle of the trigger
+    Socket,   // Waits on an event associated with the socket of the trigger
+    Timer,    // Waits on a waitable timer created for the event
+    Notifier, // Waits on a manual reset event created for the event
+}
+
+// An event, along with the state that is only accessed under the lock of the poll
+struct Slot<H> {
+    event: Box<Event<H>>,
+    object: HANDLE, // The object that is waited on
+    kind: EventKind,
+    armed: bool, // Not held by an EventGuard
+}
+
+struct Event<H> {
+    trigger: RawFd, // The associated trigger
+    handler: H,     // The associated data
+}
+
+fn create_event(manual_reset: bool) -> Result<HANDLE, Error> {
+    match unsafe { CreateEventW(null(), manual_reset.into(), 0, null()) } {
+        0 => Err(Error::EventQueue(io::Error::last_os_error())),
+        event => Ok(event),
+    }
+}
+
+// Release the object created for an event, if any
+fn close_object(trigger: RawFd, object: HANDLE, kind: &EventKind) {
+    match (trigger, kind) {
+        (_, EventKind::Handle) => {}
+        (RawFd::Socket(socket), _) => unsafe {
+            WSAEventSelect(socket, 0, 0);
+            WSACloseEvent(object);
+        },
+        (RawFd::Handle(_), _) => unsafe {
+            CloseHandle(object);
+        },
+    }
+}
+
+impl<H> Drop for EventPoll<H> {
+    fn drop(&mut self) {
+        for (trigger, slot) in self.events.get_mut().drain() {
+            close_object(trigger, slot.object, &slot.kind);
+        }
+        unsafe { CloseHandle(self.changed) };
+    }
+}
+
+impl<H: Sync + Send> EventPoll<H> {
+    /// Create a new event registry
+    pub fn new() -> Result<EventPoll<H>, Error> {
+        Ok(EventPoll {
+            events: Mutex::new(HashMap::new()),
+            waiter: Mutex::new(()),
+            changed: create_event(false)?,
+        })
+    }
+
+    /// Add and enable a new event with the factory.
+    /// The event is triggered when a Read operation on the provided socket becomes available, or
+    /// while the provided handle is signaled.
+    /// If the same trigger is used with multiple events in the same EventPoll, the last added
+    /// event overrides all previous events.
+    /// When triggered, one of the threads waiting on the poll will receive the handler via an
+    /// appropriate EventGuard. It is guaranteed that only a single thread can have a reference to
+    /// the handler at any given time.
+    pub fn new_event(&self, trigger: RawFd, handler: H) -> Result<EventRef, Error> {
+        match trigger {
+            RawFd::Handle(handle) => {
+                self.register_event(trigger, handle, EventKind::Handle, handler)
+            }
+            RawFd::Socket(socket) => {
+                let event = match unsafe { WSACreateEvent() } {
+                    0 => return Err(Error::EventQueue(io::Error::last_os_error())),
+                    event => event,
+                };
+                if unsafe { WSAEventSelect(socket, event, (FD_READ | FD_CLOSE) as i32) }
+                    == SOCKET_ERROR
+                {
+                    let err = io::Error::last_os_error();
+                    unsafe { WSACloseEvent(event) };
+                    return Err(Error::EventQueue(err));
+                }
+                self.register_event(trigger, event, EventKind::Socket, handler)
+            }
+        }
+    }
+
+    /// Add and enable a new timed event with the factory.
+    /// The even will be triggered for the first time after period time, and henceforth triggered
+    /// every period time.
+    pub fn new_periodic_event(&self, handler: H, period: Duration) -> Result<EventRef, Error> {
+        // An auto reset timer is reset by the wait it satisfies
+        let timer = match unsafe { CreateWaitableTimerW(null(), 0, null()) } {
+            0 => return Err(Error::Timer(io::Error::last_os_error())),
+            timer => timer,
+        };
+
+        // A negative due time is relative, in units of 100ns
+        let due = -i64::try_from(period.as_nanos() / 100).unwrap_or(i64::MAX);
+        let period_ms = i32::try_from(period.as_millis().max(1)).unwrap_or(i32::MAX);
+        if unsafe { SetWaitableTimer(timer, &due, period_ms, None, null(), 0) } == 0 {
+            let err = io::Error::last_os_error();
+            unsafe { CloseHandle(timer) };
+            return Err(Error::Timer(err));
+        }
+
+        self.register_event(RawFd::Handle(timer), timer, EventKind::Timer, handler)
+    }
+
+    /// Add a new timer event with the factory. It is not triggered until it is armed with
+    /// set_timer, and it is then triggered once, after the given delay.
+    pub fn new_timer_event(&self, handler: H) -> Result<EventRef, Error> {
+        let timer = match unsafe { CreateWaitableTimerW(null(), 0, null()) } {
+            0 => return Err(Error::Timer(io::Error::last_os_error())),
+            timer => timer,
+        };
+
+        self.register_event(RawFd::Handle(timer), timer, EventKind::Timer, handler)
+    }
+
+    /// Arm a timer event to be triggered once after `delay`, replacing the previous delay, or
+    /// disarm it with `None`. It can be called from the handler of the timer.
+    pub fn set_timer(&self, timer_event: &EventRef, delay: Option<Duration>) {
+        let timer = match timer_event.trigger {
+            RawFd::Handle(timer) => timer,
+            RawFd::Socket(_) => panic!("Can only set a timer event"),
+        };
+
+        match delay {
+            Some(delay) => {
+                // A negative due time is relative, in units of 100ns
+                let due = -i64::try_from(delay.as_nanos() / 100)
+                    .unwrap_or(i64::MAX)
+                    .max(1);
+                unsafe { SetWaitableTimer(timer, &due, 0, None, null(), 0) };
+            }
+            None => unsafe {
+                CancelWaitableTimer(timer);
+            },
+        }
+    }
+
+    /// Add and enable a new notification event with the factory.
+    /// The event can only be triggered manually, using the trigger_notification method.
+    /// The event will remain in a triggered state until the stop_notification method is
+    /// called. Both methods should only be called with the producing EventPoll.
+    pub fn new_notifier(&self, handler: H) -> Result<EventRef, Error> {
+        let event = create_event(true)?;
+        self.register_event(RawFd::Handle(event), event, EventKind::Notifier, handler)
+    }
+
+    /// Wait until one of the registered events becomes triggered. Once an event
+    /// is triggered, a single caller thread gets the handler for that event.
+    /// In case a notifier is triggered, all waiting threads will receive the same
+    /// handler.
+    pub fn wait(&self) -> WaitResult<'_, H> {
+        let _waiter = self.waiter.lock();
+
+        loop {
+            // Wait on our own event first, then on the events not held by a guard
+            let mut objects = vec![self.changed];
+            let mut triggers = vec![None];
+            for (trigger, slot) in self.events.lock().iter() {
+                if slot.armed {
+                    objects.push(slot.object);
+                    triggers.push(Some(*trigger));
+                }
+            }
+
+            let index = unsafe {
+                WaitForMultipleObjects(objects.len() as u32, objects.as_ptr(), 0, INFINITE)
+            }
+            .wrapping_sub(WAIT_OBJECT_0) as usize;
+
+            let trigger = match triggers.get(index) {
+                Some(Some(trigger)) => *trigger,
+                Some(None) => continue, // The events changed
+                None => return WaitResult::Error(io::Error::last_os_error().to_string()),
+            };
+
+            let mut events = self.events.lock();
+            let slot = match events.get_mut(&trigger) {
+                Some(slot) => slot,
+                None => continue, // The event was removed in the meantime
+            };
+
+            let mut eof = false;
+            if let RawFd::Socket(socket) = trigger {
+                // Reset the event, the next read that leaves data to read sets it again
+                let mut network_events = WSANETWORKEVENTS {
+                    lNetworkEvents: 0,
+                    iErrorCode: [0; 10],
+                };
+                unsafe { WSAEnumNetworkEvents(socket, slot.object, &mut network_events) };
+                eof = network_events.lNetworkEvents & FD_CLOSE as i32 != 0;
+            }
+
+            // A notifier stays signaled until it is stopped, so every thread gets it
+            if slot.kind != EventKind::Notifier {
+                slot.armed = false;
+            }
+
+            let event = unsafe { &mut *(slot.event.as_mut() as *mut Event<H>) };
+            let guard = EventGuard { event, poll: self };
+
+            return if eof {
+                WaitResult::EoF(guard)
+            } else {
+                WaitResult::Ok(guard)
+            };
+        }
+    }
+
+    // Register an event with this poll.
+    fn register_event(
+        &self,
+        trigger: RawFd,
+        object: HANDLE,
+        kind: EventKind,
+        handler: H,
+    ) -> Result<EventRef, Error> {
+        let mut events = self.events.lock();
+        if !events.contains_key(&trigger) && events.len() + 1 >= MAXIMUM_WAIT_OBJECTS {
+            close_object(trigger, object, &kind);
+            return Err(Error::EventQueue(io::Error::other(
+                "too many events to wait on",
+            )));
+        }
+
+        let slot = Slot {
+            event: Box::new(Event { trigger, handler }),
+            object,
+            kind,
+            armed: true,
+        };
+        if let Some(previous) = events.insert(trigger, slot) {
+            // Properly remove the previous event first
+            close_object(trigger, previous.object, &previous.kind);
+        }
+        unsafe { SetEvent(self.changed) };
+
+        Ok(EventRef { trigger })
+    }
+
+    /// Trigger a notification
+    pub fn trigger_notification(&self, notification_event: &EventRef) {
+        unsafe { SetEvent(self.notifier_object(notification_event)) };
+    }
+
+    /// Stop a notification
+    pub fn stop_notification(&self, notification_event: &EventRef) {
+        unsafe { ResetEvent(self.notifier_object(notification_event)) };
+    }
+
+    fn notifier_object(&self, notification_event: &EventRef) -> HANDLE {
+        let events = self.events.lock();
+        let slot = events
+            .get(&notification_event.trigger)
+            .expect("Expected an event");
+
+        if slot.kind != EventKind::Notifier {
+            panic!("Can only trigger a notification event");
+        }
+
+        slot.object
+    }
+}
+
+impl<H> EventPoll<H> {
+    /// Disable and remove the event and associated handler, using the trigger that
+    /// was used to register it.
+    ///
+    /// # Safety
+    ///
+    /// This function is only safe to call when the event loop is not running,
+    /// otherwise the memory of the handler may get freed while in use.
+    pub unsafe fn clear_event_by_fd(&self, index: RawFd) {
+        if let Some(slot) = self.events.lock().remove(&index) {
+            close_object(index, slot.object, &slot.kind);
+            SetEvent(self.changed);
+        }
+    }
+}
+
+impl<'a, H> Deref for EventGuard<'a, H> {
+    type Target = H;
+    fn deref(&self) -> &H {
+        &self.event.handler
+    }
+}
+
+impl<'a, H> Drop for EventGuard<'a, H> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.poll.events.lock().get_mut(&self.event.trigger) {
+            if !slot.armed {
+                slot.armed = true;
+                unsafe { SetEvent(self.poll.changed) };
+            }
+        }
+    }
+}
+
+impl<'a, H> EventGuard<'a, H> {
+    /// Get a mutable reference to the stored value
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> &mut H {
+        &mut self.event.handler
+    }
+
+    /// Cancel and remove the event referenced by this guard
+    pub fn cancel(self) {
+        unsafe { self.poll.clear_event_by_fd(self.event.trigger) };
+        std::mem::forget(self); // Don't call the regular drop that would enable the event
+    }
+
+    #[allow(dead_code)]
+    pub fn fd(&self) -> RawFd {
+        self.event.trigger
+    }
+}
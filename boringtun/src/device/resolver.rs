@@ -0,0 +1,507 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The delay before re-resolving again after a successful resolution
+const RERESOLVE_BACKOFF_MIN: Duration = Duration::from_secs(5);
+/// The longest delay between two attempts when resolution keeps failing
+const RERESOLVE_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// How often a resolution in flight is checked for its result
+const RESOLUTION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+type Resolution = Arc<Mutex<Option<io::Result<Vec<SocketAddr>>>>>;
+
+/// Resolves endpoint host names into socket addresses.
+///
+/// The device uses [`SystemResolver`] by default, or `HickoryResolver` with the `hickory-dns`
+/// feature. Library users can supply their own implementation (e.g. DNS over HTTPS) with
+/// [`super::DeviceHandle::set_resolver`]. Resolution may block, but the device is never locked
+/// for writing while it does: the UAPI, the methods of [`super::DeviceHandle`] and the reloads
+/// on `SIGHUP` resolve the host names before they lock it.
+pub trait Resolver: Send + Sync {
+    /// Resolve `host`, given in the `host:port` form, into a list of addresses
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves host names using the resolver of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        host.to_socket_addrs().map(|a| a.collect())
+    }
+}
+
+/// Resolves host names with hickory-resolver, from the configuration of the system, without
+/// going through the resolver of the C library. The device uses it by default with the
+/// `hickory-dns` feature.
+#[cfg(feature = "hickory-dns")]
+pub struct HickoryResolver(hickory_resolver::Resolver);
+
+#[cfg(feature = "hickory-dns")]
+impl HickoryResolver {
+    /// A resolver configured from `/etc/resolv.conf`, or the registry on Windows
+    pub fn from_system_conf() -> io::Result<HickoryResolver> {
+        hickory_resolver::Resolver::from_system_conf().map(HickoryResolver)
+    }
+
+    pub fn new(
+        config: hickory_resolver::config::ResolverConfig,
+        options: hickory_resolver::config::ResolverOpts,
+    ) -> io::Result<HickoryResolver> {
+        hickory_resolver::Resolver::new(config, options).map(HickoryResolver)
+    }
+}
+
+#[cfg(feature = "hickory-dns")]
+impl Resolver for HickoryResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid endpoint");
+        let (name, port) = host.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let lookup = self.0.lookup_ip(name)?;
+        Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+/// The resolver of a new device, [`HickoryResolver`] with the `hickory-dns` feature unless the
+/// configuration of the system can't be read, [`SystemResolver`] otherwise
+pub(crate) fn default_resolver() -> Arc<dyn Resolver> {
+    #[cfg(feature = "hickory-dns")]
+    match HickoryResolver::from_system_conf() {
+        Ok(resolver) => return Arc::new(resolver),
+        Err(e) => {
+            tracing::warn!(message = "Failed to configure hickory-resolver", error = ?e);
+        }
+    }
+    Arc::new(SystemResolver)
+}
+
+/// Parse an endpoint, either a literal socket address or a host name in the `host:port` form.
+/// Host names are resolved with `resolver`. Returns the address, along with the host name it was
+/// resolved from. Fails with [`io::ErrorKind::InvalidInput`] if the endpoint is malformed.
+pub(crate) fn resolve_endpoint(
+    resolver: &dyn Resolver,
+    prefer_ipv4: bool,
+    endpoint: &str,
+) -> io::Result<(SocketAddr, Option<String>)> {
+    if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+        return Ok((addr, None));
+    }
+
+    if !is_host_name(endpoint) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid endpoint",
+        ));
+    }
+
+    let addrs = resolver.resolve(endpoint)?;
+    match pick_address(&addrs, Some(prefer_ipv4)) {
+        Some(addr) => Ok((addr, Some(endpoint.to_owned()))),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Host name has no addresses",
+        )),
+    }
+}
+
+/// True if `endpoint` is a host name in the `host:port` form, rather than a literal address
+fn is_host_name(endpoint: &str) -> bool {
+    match endpoint.rsplit_once(':') {
+        // A colon in the host part means an IPv6 literal that is missing its brackets or port
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains([':', '[', ']']) && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Host names resolved ahead of time, so they can be looked up without blocking, e.g. while the
+/// device is locked for writing. Host names that were not resolved ahead fail to resolve.
+#[derive(Default)]
+pub(crate) struct Preresolved(HashMap<String, Result<Vec<SocketAddr>, (io::ErrorKind, String)>>);
+
+impl Preresolved {
+    /// Resolve the host names among `endpoints`, a comma separated list of endpoints as given to
+    /// [`resolve_endpoints`]. Literal addresses and malformed endpoints are left alone.
+    pub(crate) fn resolve_ahead(&mut self, resolver: &dyn Resolver, endpoints: &str) {
+        for endpoint in endpoints.split(',') {
+            if endpoint.parse::<SocketAddr>().is_ok()
+                || !is_host_name(endpoint)
+                || self.0.contains_key(endpoint)
+            {
+                continue;
+            }
+            let result = resolver
+                .resolve(endpoint)
+                .map_err(|e| (e.kind(), e.to_string()));
+            self.0.insert(endpoint.to_owned(), result);
+        }
+    }
+}
+
+impl Resolver for Preresolved {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        match self.0.get(host) {
+            Some(Ok(addrs)) => Ok(addrs.clone()),
+            Some(Err((kind, error))) => Err(io::Error::new(*kind, error.clone())),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Host name was not resolved ahead",
+            )),
+        }
+    }
+}
+
+/// Addresses of endpoints to fail over between, along with the host name of a single endpoint
+pub(crate) type ResolvedEndpoints = (Vec<SocketAddr>, Option<String>);
+
+/// Parse a comma separated list of endpoints to fail over between, see [`resolve_endpoint`].
+/// Host names are only followed for a single endpoint, so the host name is `None` for a list.
+pub(crate) fn resolve_endpoints(
+    resolver: &dyn Resolver,
+    prefer_ipv4: bool,
+    endpoints: &str,
+) -> io::Result<ResolvedEndpoints> {
+    if !endpoints.contains(',') {
+        let (addr, host) = resolve_endpoint(resolver, prefer_ipv4, endpoints)?;
+        return Ok((vec![addr], host));
+    }
+
+    let addrs = endpoints
+        .split(',')
+        .map(|endpoint| resolve_endpoint(resolver, prefer_ipv4, endpoint).map(|(addr, _)| addr))
+        .collect::<io::Result<_>>()?;
+    Ok((addrs, None))
+}
+
+/// Pick an address from a resolution result, preferring the given address family
+pub(crate) fn pick_address(addrs: &[SocketAddr], prefer_ipv4: Option<bool>) -> Option<SocketAddr> {
+    addrs
+        .iter()
+        .find(|a| prefer_ipv4.is_none_or(|v4| v4 == a.is_ipv4()))
+        .or_else(|| addrs.first())
+        .copied()
+}
+
+/// Keeps track of re-resolving the endpoint of a peer that was configured with a host name.
+/// Resolution is performed on a separate thread, so a slow resolver never stalls the event loop.
+pub(crate) struct Reresolver {
+    /// The endpoint as it was configured, in the `host:port` form
+    host: String,
+    resolver: Arc<dyn Resolver>,
+    /// The result of an in-flight resolution, if any
+    in_flight: Option<Resolution>,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+    /// When the host name was last resolved successfully
+    last_resolved: Instant,
+}
+
+impl Reresolver {
+    pub(crate) fn new(host: String, resolver: Arc<dyn Resolver>) -> Reresolver {
+        Reresolver {
+            host,
+            resolver,
+            in_flight: None,
+            backoff: RERESOLVE_BACKOFF_MIN,
+            next_attempt: None,
+            // The first resolution happens when the endpoint is configured
+            last_resolved: Instant::now(),
+        }
+    }
+
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Should be called periodically while the peer fails to complete a handshake. Starts a new
+    /// resolution when one is due, and returns the new address once it differs from `current`.
+    pub(crate) fn poll(&mut self, current: Option<SocketAddr>, now: Instant) -> Option<SocketAddr> {
+        if let Some(in_flight) = &self.in_flight {
+            let result = in_flight.lock().take()?;
+            self.in_flight = None;
+
+            match result {
+                Ok(addrs) => {
+                    self.last_resolved = now;
+                    self.backoff = RERESOLVE_BACKOFF_MIN;
+                    self.next_attempt = Some(now + self.backoff);
+
+                    // Prefer to stay on the same address family
+                    let addr = pick_address(&addrs, current.map(|c| c.is_ipv4()));
+
+                    return addr.filter(|&a| Some(a) != current);
+                }
+                Err(e) => {
+                    tracing::warn!(message = "Failed to resolve endpoint", host = self.host, error = ?e);
+                    self.next_attempt = Some(now + self.backoff);
+                    self.backoff = (self.backoff * 2).min(RERESOLVE_BACKOFF_MAX);
+                    return None;
+                }
+            }
+        }
+
+        if self.next_attempt.is_none_or(|t| now >= t) {
+            self.in_flight = Some(self.spawn_resolution());
+        }
+
+        None
+    }
+
+    /// Should be called periodically while the peer is healthy, to follow changes of the address
+    /// of the host. Like [`Reresolver::poll`], but only starts a resolution once `interval` has
+    /// passed since the last successful one.
+    pub(crate) fn recheck(
+        &mut self,
+        current: Option<SocketAddr>,
+        now: Instant,
+        interval: Duration,
+    ) -> Option<SocketAddr> {
+        if self.in_flight.is_none() && now < self.last_resolved + interval {
+            return None;
+        }
+        self.poll(current, now)
+    }
+
+    /// When [`Reresolver::poll`] next has something to do if `expired`, or [`Reresolver::recheck`]
+    /// if `interval` is set. `None` if neither is called.
+    pub(crate) fn next_poll(
+        &self,
+        expired: bool,
+        interval: Option<Duration>,
+        now: Instant,
+    ) -> Option<Instant> {
+        if self.in_flight.is_some() {
+            return (expired || interval.is_some()).then(|| now + RESOLUTION_POLL_INTERVAL);
+        }
+        let poll = expired.then(|| self.next_attempt.unwrap_or(now));
+        let recheck = interval.map(|interval| self.last_resolved + interval);
+        poll.into_iter().chain(recheck).min()
+    }
+
+    fn spawn_resolution(&self) -> Resolution {
+        let result: Resolution = Default::default();
+        let host = self.host.clone();
+        let resolver = Arc::clone(&self.resolver);
+        let thread_result = Arc::clone(&result);
+
+        let spawned = thread::Builder::new()
+            .name("resolver".to_owned())
+            .spawn(move || {
+                *thread_result.lock() = Some(resolver.resolve(&host));
+            });
+
+        if let Err(e) = spawned {
+            *result.lock() = Some(Err(e));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll_until_done(r: &mut Reresolver, current: Option<SocketAddr>) -> Option<SocketAddr> {
+        let now = Instant::now();
+        assert!(r.poll(current, now).is_none());
+        for _ in 0..500 {
+            let res = r.poll(current, now);
+            if r.in_flight.is_none() {
+                return res;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("resolution did not complete");
+    }
+
+    #[test]
+    fn test_reresolve_changed_address() {
+        let mut r = Reresolver::new("127.0.0.1:51820".to_owned(), Arc::new(SystemResolver));
+        let old = "127.0.0.2:51820".parse().unwrap();
+
+        let new = poll_until_done(&mut r, Some(old));
+        assert_eq!(new, Some("127.0.0.1:51820".parse().unwrap()));
+        assert_eq!(r.backoff, RERESOLVE_BACKOFF_MIN);
+        // Not due yet
+        assert!(r.poll(new, Instant::now()).is_none());
+        assert!(r.in_flight.is_none());
+    }
+
+    #[test]
+    fn test_reresolve_unchanged_address() {
+        let mut r = Reresolver::new("127.0.0.1:51820".to_owned(), Arc::new(SystemResolver));
+        let current = "127.0.0.1:51820".parse().unwrap();
+        assert_eq!(poll_until_done(&mut r, Some(current)), None);
+    }
+
+    #[test]
+    fn test_reresolve_backoff() {
+        // Missing port, fails to resolve right away
+        let mut r = Reresolver::new("localhost".to_owned(), Arc::new(SystemResolver));
+
+        assert_eq!(poll_until_done(&mut r, None), None);
+        assert_eq!(r.backoff, RERESOLVE_BACKOFF_MIN * 2);
+
+        let next = r.next_attempt.unwrap();
+        assert!(r.poll(None, next - Duration::from_millis(1)).is_none());
+        assert!(r.in_flight.is_none());
+
+        r.next_attempt = Some(Instant::now());
+        assert_eq!(poll_until_done(&mut r, None), None);
+        assert_eq!(r.backoff, RERESOLVE_BACKOFF_MIN * 4);
+
+        for _ in 0..10 {
+            r.next_attempt = Some(Instant::now());
+            poll_until_done(&mut r, None);
+        }
+        assert_eq!(r.backoff, RERESOLVE_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn test_next_poll() {
+        let interval = Duration::from_secs(60);
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let resolver = Arc::new(FixedResolver(vec![addr]));
+        let mut r = Reresolver::new("peer.example:1234".to_owned(), resolver);
+        let now = Instant::now();
+        assert_eq!(r.next_poll(false, None, now), None);
+        assert_eq!(r.next_poll(true, None, now), Some(now));
+        assert_eq!(
+            r.next_poll(false, Some(interval), now),
+            Some(r.last_resolved + interval)
+        );
+
+        assert!(r.poll(None, now).is_none());
+        assert_eq!(
+            r.next_poll(true, None, now),
+            Some(now + RESOLUTION_POLL_INTERVAL)
+        );
+        while r.in_flight.is_some() {
+            r.poll(None, now);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(r.next_poll(true, None, now), r.next_attempt);
+    }
+
+    #[test]
+    fn test_recheck_interval() {
+        let mut r = Reresolver::new("127.0.0.1:51820".to_owned(), Arc::new(SystemResolver));
+        let old = "127.0.0.2:51820".parse().unwrap();
+        let interval = Duration::from_secs(60);
+
+        // Just resolved when configured
+        assert!(r.recheck(Some(old), Instant::now(), interval).is_none());
+        assert!(r.in_flight.is_none());
+
+        // Due, the result is collected by the next calls
+        r.last_resolved -= interval;
+        let mut new = None;
+        for _ in 0..500 {
+            new = r.recheck(Some(old), Instant::now(), interval);
+            if r.in_flight.is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(new, Some("127.0.0.1:51820".parse().unwrap()));
+        assert!(r.recheck(new, Instant::now(), interval).is_none());
+        assert!(r.in_flight.is_none());
+    }
+
+    struct FixedResolver(Vec<SocketAddr>);
+
+    impl Resolver for FixedResolver {
+        fn resolve(&self, _host: &str) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_reresolve_custom_resolver() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let resolver = Arc::new(FixedResolver(vec![v4, v6]));
+        let mut r = Reresolver::new("peer.example.com:51820".to_owned(), resolver);
+
+        let current = "[2001:db8::2]:51820".parse().unwrap();
+        assert_eq!(poll_until_done(&mut r, Some(current)), Some(v6));
+    }
+
+    #[test]
+    fn test_resolve_endpoints() {
+        let parse = |s| resolve_endpoints(&SystemResolver, true, s);
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "192.0.2.1:51820".parse().unwrap(),
+            "[2001:db8::1]:51820".parse().unwrap(),
+        );
+
+        assert_eq!(parse("192.0.2.1:51820").unwrap(), (vec![a], None));
+        assert_eq!(
+            parse("192.0.2.1:51820,[2001:db8::1]:51820").unwrap(),
+            (vec![a, b], None)
+        );
+        assert_eq!(parse("localhost:51820,192.0.2.1:51820").unwrap().1, None);
+        assert_eq!(
+            parse("192.0.2.1:51820,").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_preresolved() {
+        let addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let mut resolved = Preresolved::default();
+        resolved.resolve_ahead(&FixedResolver(vec![addr]), "peer.example:51820,192.0.2.2:1");
+        resolved.resolve_ahead(&FixedResolver(vec![]), "peer.example:51820,[2001:db8::1]");
+
+        assert_eq!(resolved.0.len(), 1);
+        assert_eq!(
+            resolve_endpoints(&resolved, true, "peer.example:51820").unwrap(),
+            (vec![addr], Some("peer.example:51820".to_owned()))
+        );
+        assert_eq!(
+            resolve_endpoints(&resolved, true, "other.example:51820")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(feature = "hickory-dns")]
+    #[test]
+    fn test_hickory_resolver() {
+        let resolver = HickoryResolver::from_system_conf().unwrap();
+        assert_eq!(
+            resolver.resolve("127.0.0.1:51820").unwrap(),
+            vec!["127.0.0.1:51820".parse().unwrap()]
+        );
+        assert_eq!(
+            resolver.resolve("127.0.0.1").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_pick_address() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+
+        assert_eq!(pick_address(&[v6, v4], Some(true)), Some(v4));
+        assert_eq!(pick_address(&[v4, v6], Some(false)), Some(v6));
+        assert_eq!(pick_address(&[v6, v4], None), Some(v6));
+        assert_eq!(pick_address(&[v6], Some(true)), Some(v6));
+        assert_eq!(pick_address(&[], Some(true)), None);
+    }
+}
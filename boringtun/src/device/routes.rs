@@ -0,0 +1,214 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The routes `wg-quick` adds for the allowed IPs of the peers, see
+//! [`super::DeviceHandle::add_routes`].
+//!
+//! A default route, `0.0.0.0/0` or `::/0`, can't go to the main routing table: the datagrams of
+//! the device would be routed into its own interface. It goes to a table numbered after the fwmark
+//! of the device instead, with two policy rules of its family, like `wg-quick` does:
+//! - `not fwmark <mark> lookup <mark>`, everything but the datagrams of the device uses the table
+//! - `lookup main suppress_prefixlength 0`, ahead of it, so the more specific routes of the main
+//!   table still apply
+//!
+//! The routes go away with the interface, the rules are deleted when the device is dropped.
+
+use super::peer::AllowedIP;
+use super::tun::{push_attr, Netlink};
+use super::{in_netns, Device, Error};
+use libc::{AF_INET, AF_INET6, EEXIST, NLM_F_CREATE, NLM_F_EXCL, RTM_DELRULE, RTM_NEWRULE};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The fwmark given to a device without one, and the number of the table of its default routes
+pub const DEFAULT_ROUTE_FWMARK: u32 = 51820;
+
+// From linux/fib_rules.h
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 2;
+
+/// A policy rule, added with [`PolicyRule::add`] and deleted with [`PolicyRule::delete`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PolicyRule {
+    /// `not fwmark <mark> lookup <mark>`
+    NotFwmark { ipv4: bool, mark: u32 },
+    /// `lookup main suppress_prefixlength 0`
+    SuppressMainDefault { ipv4: bool },
+}
+
+impl PolicyRule {
+    /// The netlink message of the rule, without its header
+    fn body(&self) -> Vec<u8> {
+        let (ipv4, table, flags) = match *self {
+            PolicyRule::NotFwmark { ipv4, mark } => (ipv4, mark, FIB_RULE_INVERT),
+            PolicyRule::SuppressMainDefault { ipv4 } => (ipv4, libc::RT_TABLE_MAIN as u32, 0),
+        };
+        let family = if ipv4 { AF_INET } else { AF_INET6 };
+
+        // struct fib_rule_hdr
+        let mut body = vec![family as u8, 0, 0, 0, 0, 0, 0, FR_ACT_TO_TBL];
+        body.extend_from_slice(&flags.to_ne_bytes());
+        push_attr(&mut body, FRA_TABLE, &table.to_ne_bytes());
+        match *self {
+            PolicyRule::NotFwmark { mark, .. } => {
+                push_attr(&mut body, FRA_FWMARK, &mark.to_ne_bytes())
+            }
+            PolicyRule::SuppressMainDefault { .. } => {
+                push_attr(&mut body, FRA_SUPPRESS_PREFIXLEN, &0u32.to_ne_bytes())
+            }
+        }
+        body
+    }
+
+    fn add(&self) -> Result<(), Error> {
+        let netlink = Netlink::open()
+            .map_err(|e| Error::InterfaceConfig(format!("netlink socket: {}", e)))?;
+        netlink
+            .request(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, &self.body())
+            .map_err(|e| match e.raw_os_error() {
+                Some(EEXIST) => Error::InterfaceConfig(format!("the rule {} already exists", self)),
+                _ => Error::InterfaceConfig(format!("failed to add the rule {}: {}", self, e)),
+            })
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        let netlink = Netlink::open()
+            .map_err(|e| Error::InterfaceConfig(format!("netlink socket: {}", e)))?;
+        netlink.request(RTM_DELRULE, 0, &self.body()).map_err(|e| {
+            Error::InterfaceConfig(format!("failed to delete the rule {}: {}", self, e))
+        })
+    }
+}
+
+impl std::fmt::Display for PolicyRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyRule::NotFwmark { ipv4, mark } => {
+                let family = if *ipv4 { "IPv4" } else { "IPv6" };
+                write!(f, "{} not fwmark {} lookup {}", family, mark, mark)
+            }
+            PolicyRule::SuppressMainDefault { ipv4 } => {
+                let family = if *ipv4 { "IPv4" } else { "IPv6" };
+                write!(f, "{} lookup main suppress_prefixlength 0", family)
+            }
+        }
+    }
+}
+
+/// The network of `ip`, without the bits of the host, which the kernel refuses in a route
+fn network(ip: AllowedIP) -> AllowedIP {
+    let addr = match ip.addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(ip.cidr)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(ip.cidr)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    };
+    AllowedIP { addr, ..ip }
+}
+
+/// Split `routes` in the default routes and the others, once each. The networks of `addresses`
+/// are left out, the kernel routes them through the interface already.
+fn plan_routes(routes: &[AllowedIP], addresses: &[AllowedIP]) -> (Vec<AllowedIP>, Vec<AllowedIP>) {
+    let connected: Vec<_> = addresses.iter().copied().map(network).collect();
+    let mut routes: Vec<_> = routes
+        .iter()
+        .copied()
+        .map(network)
+        .filter(|route| !connected.contains(route))
+        .collect();
+    routes.sort();
+    routes.dedup();
+    routes.into_iter().partition(|route| route.cidr == 0)
+}
+
+impl Device {
+    /// Add the routes and the rules, see [`super::DeviceHandle::add_routes`]
+    pub(super) fn add_routes(&mut self, routes: &[AllowedIP]) -> Result<(), Error> {
+        let (defaults, routes) = plan_routes(routes, &self.config.address);
+        let iface = &self.iface;
+        in_netns(&self.config, || {
+            iface.add_routes(&routes, u32::from(libc::RT_TABLE_MAIN))
+        })?;
+        if defaults.is_empty() {
+            return Ok(());
+        }
+
+        let mark = match self.fwmark {
+            Some(mark) => mark,
+            None => {
+                self.set_fwmark(DEFAULT_ROUTE_FWMARK)?;
+                DEFAULT_ROUTE_FWMARK
+            }
+        };
+        let iface = &self.iface;
+        in_netns(&self.config, || iface.add_routes(&defaults, mark))?;
+        for default in &defaults {
+            let ipv4 = default.addr.is_ipv4();
+            for rule in [
+                PolicyRule::NotFwmark { ipv4, mark },
+                PolicyRule::SuppressMainDefault { ipv4 },
+            ] {
+                // The rules outlive the process, unlike the routes of the interface
+                if let Err(e) = in_netns(&self.config, || rule.add()) {
+                    self.delete_policy_rules();
+                    return Err(e);
+                }
+                self.policy_rules.lock().push(rule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the rules [`Device::add_routes`] added, a rule that fails to be deleted is logged
+    pub(super) fn delete_policy_rules(&self) {
+        for rule in self.policy_rules.lock().drain(..) {
+            if let Err(e) = in_netns(&self.config, || rule.delete()) {
+                tracing::warn!(message = "Failed to delete a routing rule", error = ?e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ips: &[&str]) -> Vec<AllowedIP> {
+        ips.iter()
+            .map(|ip| AllowedIP::parse_address(ip).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_routes() {
+        let routes = ips(&[
+            "10.0.0.0/24",
+            "10.1.2.3/16",
+            "0.0.0.0/0",
+            "10.1.0.0/16",
+            "2001:db8::1/64",
+            "::/0",
+        ]);
+        let (defaults, routes) = plan_routes(&routes, &ips(&["10.0.0.1/24"]));
+        assert_eq!(defaults, ips(&["0.0.0.0/0", "::/0"]));
+        assert_eq!(routes, ips(&["10.1.0.0/16", "2001:db8::/64"]));
+    }
+
+    #[test]
+    fn test_policy_rule() {
+        let rule = PolicyRule::NotFwmark {
+            ipv4: true,
+            mark: DEFAULT_ROUTE_FWMARK,
+        };
+        assert_eq!(rule.to_string(), "IPv4 not fwmark 51820 lookup 51820");
+        // The header, then the table and the mark
+        assert_eq!(rule.body().len(), 12 + 8 + 8);
+        assert_eq!(rule.body()[8..12], FIB_RULE_INVERT.to_ne_bytes());
+    }
+}
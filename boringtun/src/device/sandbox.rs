@@ -0,0 +1,85 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Confinement of the process to an empty directory, entered while privileges are dropped.
+//!
+//! The process is chrooted once the tun device, the UDP sockets and the UAPI socket are open, after
+//! the user database is read and before the user changes, so the user can't leave it. Nothing is
+//! opened by path afterwards, which rules out:
+//!
+//! * the configuration file and the peer state file, they can't be used with the sandbox
+//! * host names in the endpoints of peers, the resolver configuration is out of reach, only
+//!   addresses can be set
+//!
+//! The UAPI socket keeps accepting connections, its file is left behind on exit. Connecting a
+//! socket to each peer opens no file, and on Linux the seccomp filter allows the syscalls it needs,
+//! see [`super::seccomp`].
+
+use crate::device::{DeviceConfig, Error};
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+
+/// Check that the device doesn't need files that are out of reach in the sandbox
+pub(crate) fn check_config(config: &DeviceConfig) -> Result<(), Error> {
+    if config.sandbox.is_none() {
+        return Ok(());
+    }
+    if config.config_file.is_some() || config.peer_state_file.is_some() {
+        return Err(Error::InvalidConfig(
+            "the configuration file and the peer state file can't be used in the sandbox"
+                .to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Chroot to `dir`, which is created read-only if it doesn't exist, and must be empty otherwise
+pub fn enter_sandbox(dir: &Path) -> Result<(), Error> {
+    let sandbox_error = |e: io::Error| Error::Sandbox(format!("{}: {}", dir.display(), e));
+
+    match DirBuilder::new().mode(0o555).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(sandbox_error(e)),
+        _ => {}
+    }
+    if fs::read_dir(dir).map_err(sandbox_error)?.next().is_some() {
+        return Err(Error::Sandbox(format!(
+            "{} must be an empty directory",
+            dir.display()
+        )));
+    }
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| Error::Sandbox("Invalid sandbox directory".to_owned()))?;
+    if -1 == unsafe { libc::chroot(path.as_ptr()) } {
+        return Err(sandbox_error(io::Error::last_os_error()));
+    }
+    std::env::set_current_dir("/").map_err(sandbox_error)?;
+
+    tracing::info!(message = "Entered the sandbox", dir = %dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_check_config() {
+        let config = DeviceConfig {
+            sandbox: Some(PathBuf::from("/var/empty")),
+            ..Default::default()
+        };
+        assert!(check_config(&config).is_ok());
+
+        let config = DeviceConfig {
+            config_file: Some(PathBuf::from("/etc/wireguard/wg0.conf")),
+            ..config
+        };
+        assert!(check_config(&config).is_err());
+    }
+}
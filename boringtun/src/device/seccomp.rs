@@ -0,0 +1,168 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A seccomp-BPF allowlist for the running device, installed after privileges are dropped.
+//!
+//! The filter applies to every thread of the process. Besides the syscalls of the packet path
+//! (`read`, `write`, `readv`, `writev`, `recvfrom`, `sendto`, `recvmsg`, `sendmsg`, `epoll_wait`,
+//! `epoll_pwait`, `epoll_ctl`, `futex`, `clock_gettime`, `exit_group`), the allowlist has to
+//! include what the device still does at runtime:
+//!
+//! * reconfiguration over the UAPI socket: `accept`, `accept4`, `socket`, `bind`, `connect`,
+//!   `setsockopt`, `getsockopt`, `getsockname`, `getpeername`, `shutdown`, `fcntl`, `ioctl`,
+//!   `close`, `unlink`, `unlinkat`
+//! * endpoint resolution on a separate thread: `clone`, `clone3`, `set_robust_list`, `rseq`,
+//!   `prctl`, `sigaltstack`, `mprotect`, `poll`, `ppoll`, `openat`, `fstat`, `newfstatat`,
+//!   `statx`, `lseek`, `uname`
+//! * the peer state file: `openat`, `rename`, `renameat`, `renameat2`
+//! * memory allocation: `mmap`, `munmap`, `mremap`, `madvise`, `brk`
+//! * handshakes and timers: `getrandom`, `clock_nanosleep`, `nanosleep`, `gettimeofday`,
+//!   `sched_yield`
+//! * signals and exit: `rt_sigreturn`, `rt_sigaction`, `rt_sigprocmask`, `rt_sigtimedwait`,
+//!   `tgkill`, `getpid`, `gettid`, `exit`
+//!
+//! Syscalls that only exist on some architectures (e.g. `epoll_wait` is missing on aarch64) are
+//! only allowed where they exist.
+//!
+//! Any other syscall raises `SIGSYS`. The handler writes the number of the offending syscall to
+//! stderr, and re-raises the signal with the default action, which kills the process.
+
+use crate::device::Error;
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+
+#[rustfmt::skip]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // The packet path
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+    libc::SYS_recvfrom, libc::SYS_sendto, libc::SYS_recvmsg, libc::SYS_sendmsg,
+    libc::SYS_epoll_pwait, libc::SYS_epoll_ctl, libc::SYS_futex, libc::SYS_clock_gettime,
+    libc::SYS_exit_group,
+    // Reconfiguration
+    libc::SYS_accept, libc::SYS_accept4, libc::SYS_socket, libc::SYS_bind, libc::SYS_connect,
+    libc::SYS_setsockopt, libc::SYS_getsockopt, libc::SYS_getsockname, libc::SYS_getpeername,
+    libc::SYS_shutdown, libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_close, libc::SYS_unlinkat,
+    // Threads and endpoint resolution
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq,
+    libc::SYS_prctl, libc::SYS_sigaltstack, libc::SYS_mprotect, libc::SYS_ppoll,
+    libc::SYS_openat, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek,
+    libc::SYS_uname, libc::SYS_renameat, libc::SYS_renameat2,
+    // Memory
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+    // Handshakes and timers
+    libc::SYS_getrandom, libc::SYS_clock_nanosleep, libc::SYS_gettimeofday, libc::SYS_sched_yield,
+    // Signals
+    libc::SYS_rt_sigreturn, libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigtimedwait, libc::SYS_tgkill, libc::SYS_getpid, libc::SYS_gettid,
+    libc::SYS_exit,
+];
+
+/// Legacy syscalls that newer architectures only provide through their replacements
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const ALLOWED_LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_epoll_wait, libc::SYS_poll, libc::SYS_nanosleep, libc::SYS_unlink,
+    libc::SYS_rename,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const ALLOWED_LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+fn seccomp_error(e: impl std::fmt::Display) -> Error {
+    Error::Seccomp(e.to_string())
+}
+
+// `c_long` is only 32 bits on 32-bit targets
+#[allow(clippy::useless_conversion)]
+fn build_filter() -> Result<BpfProgram, Error> {
+    let rules: BTreeMap<_, _> = ALLOWED_SYSCALLS
+        .iter()
+        .chain(ALLOWED_LEGACY_SYSCALLS)
+        .map(|&syscall| (i64::from(syscall), vec![]))
+        .collect();
+
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(seccomp_error)?;
+    SeccompFilter::new(rules, SeccompAction::Trap, SeccompAction::Allow, arch)
+        .map_err(seccomp_error)?
+        .try_into()
+        .map_err(seccomp_error)
+}
+
+/// Format `n` into `buf` without allocating, returns the used part
+fn format_number(mut n: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buf[i..];
+        }
+    }
+}
+
+/// The layout of `siginfo_t` for `SIGSYS`, which libc doesn't expose
+#[repr(C)]
+struct SigsysInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+extern "C" fn handle_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Only async-signal-safe functions can be used here, so no tracing or allocations
+    let syscall = unsafe { (*(info as *const SigsysInfo)).syscall };
+
+    let mut buf = [0u8; 20];
+    let prefix = b"boringtun: syscall blocked by the seccomp filter: ";
+    unsafe {
+        libc::write(2, prefix.as_ptr() as _, prefix.len());
+        let n = format_number(syscall as u64, &mut buf);
+        libc::write(2, n.as_ptr() as _, n.len());
+        libc::write(2, b"\n".as_ptr() as _, 1);
+
+        // Let the kernel kill us, with the default action of the signal
+        libc::signal(libc::SIGSYS, libc::SIG_DFL);
+        libc::raise(libc::SIGSYS);
+    }
+}
+
+/// Restrict every thread of the process to the syscalls listed in the module documentation
+pub fn apply_seccomp_filter() -> Result<(), Error> {
+    let filter = build_filter()?;
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigsys as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        if libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) == -1 {
+            return Err(seccomp_error(std::io::Error::last_os_error()));
+        }
+    }
+
+    seccompiler::apply_filter_all_threads(&filter).map_err(seccomp_error)?;
+    tracing::info!("Seccomp filter installed");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_seccomp_filter() {
+        assert!(!build_filter().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_format_number() {
+        let mut buf = [0u8; 20];
+        assert_eq!(format_number(0, &mut buf), b"0");
+        assert_eq!(format_number(435, &mut buf), b"435");
+        assert_eq!(format_number(u64::MAX, &mut buf), b"18446744073709551615");
+    }
+}
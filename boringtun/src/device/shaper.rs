@@ -0,0 +1,192 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Per-peer shaping of the traffic sent to a peer, with a token bucket.
+//!
+//! Packets over the limit are dropped, unless the `tx-pacing` feature is enabled on Linux. They
+//! are then delayed until the bucket holds their tokens again, by the kernel with `SO_TXTIME`.
+//! Packets that would wait for more than a second are still dropped.
+
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A limit on the rate of the traffic sent to a peer, in bytes of IP packets before encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    /// The sustained rate
+    pub bytes_per_sec: u64,
+    /// The number of bytes that can be sent at once after being idle
+    pub burst: u64,
+}
+
+impl BandwidthLimit {
+    /// A limit with a burst of one second worth of traffic
+    pub fn new(bytes_per_sec: u64) -> BandwidthLimit {
+        BandwidthLimit {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+        }
+    }
+}
+
+/// A token bucket enforcing a [`BandwidthLimit`]. It lives inside a peer, which is locked by the
+/// caller anyway, so it doesn't need any synchronization of its own.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: BandwidthLimit,
+    tokens: u64,
+    /// The tokens taken by the delayed packets, which the next refills pay back first
+    borrowed: u64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub(crate) fn new(limit: BandwidthLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            borrowed: 0,
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn limit(&self) -> BandwidthLimit {
+        self.limit
+    }
+
+    /// Take `bytes` tokens from the bucket, returns false if there are not enough of them
+    pub(crate) fn try_consume(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+
+        let bytes = bytes as u64;
+        if bytes > self.tokens {
+            return false;
+        }
+        self.tokens -= bytes;
+        true
+    }
+
+    /// Take `bytes` tokens from the bucket, borrowing them from the next refills if there are not
+    /// enough of them. Returns how long until they are refilled, or `None` if that is more than a
+    /// second away.
+    #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+    pub(crate) fn pace(&mut self, bytes: usize, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.borrowed == 0 && self.try_consume(bytes, now) {
+            return Some(Duration::ZERO);
+        }
+
+        // The tokens left are used first, the bucket is empty while tokens are borrowed
+        let borrowed = self.borrowed + bytes as u64 - self.tokens;
+        let rate = self.limit.bytes_per_sec;
+        if borrowed > rate {
+            return None;
+        }
+        self.tokens = 0;
+        self.borrowed = borrowed;
+        let delay = u128::from(borrowed) * NANOS_PER_SEC / u128::from(rate);
+        Some(Duration::from_nanos(delay as u64))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let rate = u128::from(self.limit.bytes_per_sec);
+        let elapsed = now.saturating_duration_since(self.last_refill).as_nanos();
+        let added = elapsed * rate / NANOS_PER_SEC;
+        if added == 0 {
+            return;
+        }
+
+        let repaid = added.min(u128::from(self.borrowed));
+        self.borrowed -= repaid as u64;
+        let tokens = u128::from(self.tokens) + added - repaid;
+        if tokens >= u128::from(self.limit.burst) {
+            self.tokens = self.limit.burst;
+            self.last_refill = now;
+        } else {
+            self.tokens = tokens as u64;
+            // Only account for the time that produced whole tokens, so the remainder is not lost
+            // when the bucket is refilled on every packet
+            let used = added * NANOS_PER_SEC / rate;
+            self.last_refill += Duration::from_nanos(used as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            BandwidthLimit {
+                bytes_per_sec: 1000,
+                burst: 3000,
+            },
+            now,
+        );
+
+        assert!(bucket.try_consume(1500, now));
+        assert!(bucket.try_consume(1500, now));
+        assert!(!bucket.try_consume(1, now));
+
+        // Refills at the sustained rate, up to the burst
+        assert!(!bucket.try_consume(600, now + Duration::from_millis(500)));
+        assert!(bucket.try_consume(500, now + Duration::from_millis(500)));
+        assert!(bucket.try_consume(3000, now + Duration::from_secs(100)));
+        assert!(!bucket.try_consume(1, now + Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn test_token_bucket_frequent_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(BandwidthLimit::new(3), start);
+        assert!(bucket.try_consume(3, start));
+
+        // Refills that each produce less than a token still add up
+        let mut sent = 0;
+        for ms in 1..=1000 {
+            if bucket.try_consume(1, start + Duration::from_millis(ms)) {
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+    fn test_token_bucket_pace() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            BandwidthLimit {
+                bytes_per_sec: 1000,
+                burst: 500,
+            },
+            now,
+        );
+
+        // The burst leaves right away, the next packets once their tokens are refilled
+        assert_eq!(bucket.pace(500, now), Some(Duration::ZERO));
+        assert_eq!(bucket.pace(250, now), Some(Duration::from_millis(250)));
+        assert_eq!(bucket.pace(250, now), Some(Duration::from_millis(500)));
+        assert_eq!(
+            bucket.pace(100, now + Duration::from_millis(100)),
+            Some(Duration::from_millis(500))
+        );
+
+        // Packets more than a second away are dropped, and don't borrow
+        assert_eq!(bucket.pace(700, now + Duration::from_millis(100)), None);
+        assert_eq!(
+            bucket.pace(100, now + Duration::from_millis(100)),
+            Some(Duration::from_millis(600))
+        );
+
+        // Once the borrowed tokens are paid back, the bucket fills again
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.pace(500, later), Some(Duration::ZERO));
+        assert!(!bucket.try_consume(1, later));
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The socket activation protocol of systemd, for a UAPI socket the service manager owns: it
+//! listens on `/var/run/wireguard/<iface>.sock` before boringtun starts, keeps the socket across
+//! restarts, and passes it as a file descriptor from 3 onwards. `LISTEN_PID` is the process the
+//! descriptors are for, `LISTEN_FDS` their number and `LISTEN_FDNAMES` their names, separated by
+//! `:`. The UAPI listener is the one named after the interface, with `FileDescriptorName=` in the
+//! `.socket` unit:
+//!
+//! ```ini
+//! [Socket]
+//! ListenStream=/var/run/wireguard/%i.sock
+//! FileDescriptorName=%i
+//! ```
+//!
+//! Such a socket is used instead of creating one, see [`super::DeviceConfig::uapi_listen_fd`], and
+//! is left in place when the device exits.
+
+use super::Error;
+use socket2::{Domain, SockRef, Type};
+use std::io;
+use std::os::unix::io::{BorrowedFd, RawFd};
+
+/// The first descriptor passed by systemd
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The descriptors passed to the process `own_pid` with their names, from the values of
+/// `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`. Descriptors meant for another process, such as
+/// the parent of a daemon, are ignored.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Vec<(RawFd, String)> {
+    if pid.and_then(|pid| pid.parse().ok()) != Some(own_pid) {
+        return vec![];
+    }
+    let n = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (0..n.max(0))
+        .map(|i| {
+            let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown");
+            (SD_LISTEN_FDS_START + i, name.to_owned())
+        })
+        .collect()
+}
+
+/// The descriptors systemd passed to this process, with their names
+pub fn listen_fds() -> Vec<(RawFd, String)> {
+    let var = |name| std::env::var(name).ok();
+    parse_listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Check that `fd` is a unix stream socket that listens, the only kind the UAPI can be served on
+pub(crate) fn check_unix_listener(fd: RawFd) -> Result<(), Error> {
+    let invalid = |reason: String| {
+        Error::ApiSocket(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("descriptor {} {}", fd, reason),
+        ))
+    };
+    // The descriptor is only borrowed for the checks
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&borrowed);
+    let domain = socket
+        .domain()
+        .map_err(|e| invalid(format!("is not a socket: {}", e)))?;
+    let kind = socket.r#type().map_err(|e| invalid(e.to_string()))?;
+    if domain != Domain::UNIX || kind != Type::STREAM {
+        return Err(invalid("is not a unix stream socket".to_owned()));
+    }
+    match socket.is_listener() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(invalid("is a unix socket that doesn't listen".to_owned())),
+        Err(e) => Err(invalid(e.to_string())),
+    }
+}
+
+/// The listener systemd passed for the UAPI of the interface `name`, if any. A descriptor with
+/// that name that is not a listening unix stream socket is an error.
+pub fn find_uapi_listener(name: &str) -> Result<Option<RawFd>, Error> {
+    match listen_fds().into_iter().find(|(_, n)| n == name) {
+        Some((fd, _)) => check_unix_listener(fd).map(|()| Some(fd)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let fds = parse_listen_fds(Some("7"), Some("2"), Some("wg0:metrics"), 7);
+        assert_eq!(fds, [(3, "wg0".to_owned()), (4, "metrics".to_owned())]);
+        // Names are optional
+        let fds = parse_listen_fds(Some("7"), Some("1"), None, 7);
+        assert_eq!(fds, [(3, "unknown".to_owned())]);
+        // For another process
+        assert!(parse_listen_fds(Some("8"), Some("1"), Some("wg0"), 7).is_empty());
+        assert!(parse_listen_fds(None, Some("1"), Some("wg0"), 7).is_empty());
+        assert!(parse_listen_fds(Some("7"), Some("-1"), None, 7).is_empty());
+    }
+
+    #[test]
+    fn test_check_unix_listener() {
+        let path =
+            std::env::temp_dir().join(format!("boringtun-activation-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(check_unix_listener(listener.as_raw_fd()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let e = check_unix_listener(stream.as_raw_fd()).unwrap_err();
+        assert!(e.to_string().contains("doesn't listen"), "{}", e);
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let e = check_unix_listener(udp.as_raw_fd()).unwrap_err();
+        assert!(e.to_string().contains("not a unix stream socket"), "{}", e);
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(check_unix_listener(file.as_raw_fd()).is_err());
+    }
+}
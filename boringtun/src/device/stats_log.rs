@@ -0,0 +1,162 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A snapshot of the statistics of a device written to the log on `SIGUSR1`, for debugging in
+//! the field, see [`super::DeviceConfig::log_stats_on_sigusr1`].
+//!
+//! The signal is read from a signalfd by an event loop, like the other signals, so nothing runs in
+//! a signal handler. The snapshot is taken under the read lock the event loop holds anyway, with
+//! each peer locked in turn, so the data plane is never stopped. Only the
+//! [`STATS_LOG_MAX_PEERS`] peers that moved the most bytes are listed one by one, the others are
+//! summarized on a single line.
+//!
+//! The lines are logged at the info level with the [`STATS_LOG_TARGET`] target, which a
+//! subscriber can enable whatever the level of the other logs.
+
+use super::peer::Peer;
+use super::{Device, Mutex};
+use crate::x25519;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The target of the lines of the snapshot
+pub const STATS_LOG_TARGET: &str = "boringtun::stats";
+
+/// The number of peers listed one by one in a snapshot
+pub const STATS_LOG_MAX_PEERS: usize = 32;
+
+/// The number of threads of each kind that are running, see [`RunningThread`]
+#[derive(Debug, Default)]
+pub(crate) struct RunningThreads {
+    pub(crate) event_loops: Arc<AtomicUsize>,
+    pub(crate) handshakes: Arc<AtomicUsize>,
+}
+
+/// Counts a thread as running until it is dropped, which happens on a panic too
+pub(crate) struct RunningThread(Arc<AtomicUsize>);
+
+impl RunningThread {
+    pub(crate) fn new(count: &Arc<AtomicUsize>) -> RunningThread {
+        count.fetch_add(1, Ordering::Relaxed);
+        RunningThread(Arc::clone(count))
+    }
+}
+
+impl Drop for RunningThread {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// There are no signals on Windows
+#[cfg_attr(windows, allow(dead_code))]
+impl Device {
+    #[cfg(unix)]
+    pub(super) fn register_stats_log_handler(&self) -> Result<(), super::Error> {
+        self.queue.new_signal_event(
+            libc::SIGUSR1,
+            Box::new(|d, _| {
+                for line in d.stats_report(STATS_LOG_MAX_PEERS) {
+                    tracing::info!(target: STATS_LOG_TARGET, "{}", line);
+                }
+                super::Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// The memory held by the tables of peers, approximately: the entries of the maps and the
+    /// peers themselves, without what the peers allocate
+    fn peer_tables_size(&self) -> usize {
+        let peer = size_of::<Arc<Mutex<Peer>>>();
+        self.peers.capacity() * (size_of::<x25519::PublicKey>() + peer)
+            + self.peers_by_idx.capacity() * (size_of::<u32>() + peer)
+            + self.peers_by_ip.len() * (size_of::<ip_network::IpNetwork>() + peer)
+            + self.peers.len() * size_of::<Mutex<Peer>>()
+    }
+
+    /// The lines of a snapshot, with up to `max_peers` peers listed one by one
+    pub(crate) fn stats_report(&self, max_peers: usize) -> Vec<String> {
+        let stats = self.stats();
+        let handshake_threads = match self.handshakes {
+            Some(_) => self.config.handshake_threads,
+            None => 0,
+        };
+        let mut lines = vec![
+            format!(
+                "device: {} peers, {} connected, {} allowed IPs, listen port {}, {} KiB of peer tables",
+                stats.peers,
+                stats.connected_peers,
+                stats.allowed_ips,
+                self.listen_port,
+                self.peer_tables_size() / 1024
+            ),
+            format!(
+                "threads: {}/{} event loops, {}/{} handshake threads running",
+                self.running.event_loops.load(Ordering::Relaxed),
+                self.config.n_threads,
+                self.running.handshakes.load(Ordering::Relaxed),
+                handshake_threads
+            ),
+            format!(
+                "handshakes: {} dropped under load, {} rate limited",
+                stats.dropped_handshakes,
+                self.rate_limiter.as_ref().map_or(0, |r| r.rate_limited())
+            ),
+        ];
+
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| {
+                let peer = peer.lock();
+                let endpoint = peer.endpoint().addr;
+                (public_key, endpoint, peer.stats())
+            })
+            .collect();
+        // The busiest first
+        peers.sort_by_key(|(_, _, stats)| std::cmp::Reverse(stats.rx_bytes + stats.tx_bytes));
+
+        for (public_key, endpoint, stats) in peers.iter().take(max_peers) {
+            let endpoint = endpoint.map_or("none".to_owned(), |e| e.to_string());
+            let handshake = stats
+                .time_since_last_handshake
+                .map_or("never".to_owned(), |age| format!("{}s ago", age.as_secs()));
+            lines.push(format!(
+                "peer {}: endpoint {}, handshake {}, rx {} B, tx {} B, drops: auth {}, replay {}, \
+                 allowed IPs {}, no session {}, tx {}, filtered {}",
+                self.peer_log_id(public_key),
+                endpoint,
+                handshake,
+                stats.rx_bytes,
+                stats.tx_bytes,
+                stats.rx_drops_auth,
+                stats.rx_drops_replay,
+                stats.rx_drops_allowed_ips,
+                stats.tx_drops_no_session,
+                stats.tx_dropped,
+                stats.filtered_packets
+            ));
+        }
+
+        let rest = &peers[peers.len().min(max_peers)..];
+        if !rest.is_empty() {
+            let (rx, tx) = rest.iter().fold((0, 0), |(rx, tx), (_, _, stats)| {
+                (rx + stats.rx_bytes, tx + stats.tx_bytes)
+            });
+            let handshaked = rest
+                .iter()
+                .filter(|(_, _, stats)| stats.time_since_last_handshake.is_some())
+                .count();
+            lines.push(format!(
+                "{} more peers: {} with a handshake, rx {} B, tx {} B",
+                rest.len(),
+                handshaked,
+                rx,
+                tx
+            ));
+        }
+        lines
+    }
+}
@@ -0,0 +1,2070 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Two devices in the same process that are peers of each other, for end-to-end tests of the
+//! device, the tunnels and their timers without root privileges.
+//!
+//! Each device sits on top of a [`ChannelTun`], and their UDP traffic goes through a relay on
+//! 127.0.0.1, which counts the packets and can move one side to a different address:
+//!
+//! ```
+//! use boringtun::device::test_support::{DevicePair, DevicePairConfig};
+//! use std::time::Duration;
+//!
+//! let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+//! pair.a.send_to(&pair.b, b"ping");
+//! let packet = pair.b.recv_timeout(Duration::from_secs(5)).unwrap();
+//! assert!(packet.ends_with(b"ping"));
+//! ```
+
+use crate::device::api::UapiError;
+use crate::device::channel_tun::{ChannelTun, ChannelTunHandle};
+use crate::device::{DeviceConfig, DeviceHandle, Error};
+use crate::x25519;
+use hex::encode as encode_hex;
+use parking_lot::Mutex;
+use rand_core::OsRng;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const MTU: usize = 1420;
+
+/// Build an IPv4 packet, with a header that is only as valid as the device needs it to be
+pub fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+    packet[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// The configuration of a [`DevicePair`]
+#[derive(Debug, Clone, Default)]
+pub struct DevicePairConfig {
+    /// The persistent keepalive interval of `a` towards `b`
+    pub persistent_keepalive: Option<u16>,
+    /// The configuration of both devices, except for the API file descriptor
+    pub device_config: DeviceConfig,
+    /// The MTU of the tunnel interfaces, 1420 by default
+    pub tun_mtu: Option<usize>,
+}
+
+/// One side of a [`DevicePair`]
+pub struct TestDevice {
+    pub handle: DeviceHandle,
+    pub tun: ChannelTunHandle,
+    pub private_key: x25519::StaticSecret,
+    /// The address of the device inside the tunnel
+    pub ip: Ipv4Addr,
+    pub listen_port: u16,
+    /// The device leaves once this stream is closed
+    uapi: BufReader<UnixStream>,
+}
+
+impl TestDevice {
+    #[cfg(test)]
+    fn new(ip: Ipv4Addr, config: DeviceConfig) -> Result<TestDevice, Error> {
+        TestDevice::with_mtu(ip, config, MTU)
+    }
+
+    fn with_mtu(ip: Ipv4Addr, config: DeviceConfig, mtu: usize) -> Result<TestDevice, Error> {
+        let (tun, tun_handle) = ChannelTun::new("test", mtu).map_err(Error::Socket)?;
+        // Keep the API off the file system
+        let (uapi_device, uapi) = UnixStream::pair().map_err(Error::ApiSocket)?;
+        let config = DeviceConfig {
+            uapi_fd: uapi_device.into_raw_fd(),
+            ..config
+        };
+
+        let mut device = TestDevice {
+            handle: DeviceHandle::with_tun(Arc::new(tun), config)?,
+            tun: tun_handle,
+            private_key: x25519::StaticSecret::random_from_rng(OsRng),
+            ip,
+            listen_port: 0,
+            uapi: BufReader::new(uapi),
+        };
+        let key = encode_hex(device.private_key.to_bytes());
+        device.set(&format!("private_key={}", key))?;
+        device.listen_port = device
+            .get()?
+            .lines()
+            .find_map(|l| l.strip_prefix("listen_port="))
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(|| Error::InvalidConfig("No listen port".to_owned()))?;
+        Ok(device)
+    }
+
+    pub fn public_key(&self) -> x25519::PublicKey {
+        x25519::PublicKey::from(&self.private_key)
+    }
+
+    /// Run a UAPI request, given without its terminating blank line, returns the response
+    /// without the `errno` line. Fails if the errno is not 0.
+    pub fn uapi(&mut self, request: &str) -> Result<String, Error> {
+        let stream = self.uapi.get_mut();
+        write!(stream, "{}\n\n", request).map_err(Error::ApiSocket)?;
+
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            match self.uapi.read_line(&mut line) {
+                Ok(0) => return Err(Error::ApiSocket(io::ErrorKind::UnexpectedEof.into())),
+                Ok(_) => {}
+                Err(e) => return Err(Error::ApiSocket(e)),
+            }
+            if let Some(result) = UapiError::parse_line(&line) {
+                let mut blank = String::new();
+                self.uapi.read_line(&mut blank).map_err(Error::ApiSocket)?;
+                return result.map(|()| response).map_err(Error::Uapi);
+            }
+            response.push_str(&line);
+        }
+    }
+
+    pub fn get(&mut self) -> Result<String, Error> {
+        self.uapi("get=1")
+    }
+
+    /// Run a `set` operation with the given keys, one per line
+    pub fn set(&mut self, keys: &str) -> Result<(), Error> {
+        self.uapi(&format!("set=1\n{}", keys)).map(|_| ())
+    }
+
+    /// Add `peer` as a peer of the device, reached at `endpoint`
+    pub fn add_peer(
+        &mut self,
+        peer: &x25519::PublicKey,
+        ip: Ipv4Addr,
+        endpoint: SocketAddr,
+        persistent_keepalive: Option<u16>,
+    ) -> Result<(), Error> {
+        let mut keys = format!(
+            "public_key={}\nendpoint={}\nallowed_ip={}/32",
+            encode_hex(peer.as_bytes()),
+            endpoint,
+            ip
+        );
+        if let Some(interval) = persistent_keepalive {
+            keys.push_str(&format!("\npersistent_keepalive_interval={}", interval));
+        }
+        self.set(&keys)
+    }
+
+    /// Inject an IP packet, as if it was sent to the tunnel by the operating system
+    pub fn inject(&self, packet: Vec<u8>) {
+        self.tun.send(packet)
+    }
+
+    /// Inject a packet carrying `payload` from this device to `other`
+    pub fn send_to(&self, other: &TestDevice, payload: &[u8]) {
+        self.inject(ipv4_packet(self.ip, other.ip, payload))
+    }
+
+    /// Wait for a packet that came out of the tunnel
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.tun.receiver().recv_timeout(timeout).ok()
+    }
+}
+
+/// Forwards the UDP packets between both devices of a [`DevicePair`]
+struct Relay {
+    /// Where `a` sends its packets to `b`
+    addr_a: SocketAddr,
+    /// Where `b` sends its packets to `a`
+    addr_b: Arc<Mutex<SocketAddr>>,
+    /// Set to give `a` a new address, as seen by `b`
+    roam: Arc<AtomicBool>,
+    /// Set to send the last packet of `a` to `b` again
+    replay: Arc<AtomicBool>,
+    /// The number of packets forwarded from `a` to `b`, and from `b` to `a`
+    forwarded: Arc<[AtomicUsize; 2]>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Relay {
+    fn new(port_a: u16, port_b: u16) -> io::Result<Relay> {
+        let bind = || {
+            let socket = UdpSocket::bind("127.0.0.1:0")?;
+            socket.set_nonblocking(true)?;
+            Ok::<_, io::Error>(socket)
+        };
+        let to_a = SocketAddr::from(([127, 0, 0, 1], port_a));
+        let to_b = SocketAddr::from(([127, 0, 0, 1], port_b));
+        let (socket_a, mut socket_b) = (bind()?, bind()?);
+
+        let roam = Arc::new(AtomicBool::new(false));
+        let replay = Arc::new(AtomicBool::new(false));
+        let forwarded: Arc<[AtomicUsize; 2]> = Default::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let addr_b = Arc::new(Mutex::new(socket_b.local_addr()?));
+        let mut relay = Relay {
+            addr_a: socket_a.local_addr()?,
+            addr_b: Arc::clone(&addr_b),
+            roam: Arc::clone(&roam),
+            replay: Arc::clone(&replay),
+            forwarded: Arc::clone(&forwarded),
+            stop: Arc::clone(&stop),
+            thread: None,
+        };
+
+        relay.thread = Some(thread::spawn(move || {
+            let mut buf = [0u8; 1 << 16];
+            let mut last_of_a = vec![];
+            while !stop.load(Ordering::Relaxed) {
+                if roam.load(Ordering::Relaxed) {
+                    socket_b = bind().expect("Failed to bind the relay");
+                    *addr_b.lock() = socket_b.local_addr().unwrap();
+                    roam.store(false, Ordering::Relaxed);
+                }
+                if replay.load(Ordering::Relaxed) {
+                    socket_b.send_to(&last_of_a, to_b).ok();
+                    replay.store(false, Ordering::Relaxed);
+                }
+
+                let mut idle = true;
+                if let Ok(n) = socket_a.recv(&mut buf) {
+                    forwarded[0].fetch_add(1, Ordering::Relaxed);
+                    socket_b.send_to(&buf[..n], to_b).ok();
+                    last_of_a = buf[..n].to_vec();
+                    idle = false;
+                }
+                if let Ok(n) = socket_b.recv(&mut buf) {
+                    forwarded[1].fetch_add(1, Ordering::Relaxed);
+                    socket_a.send_to(&buf[..n], to_a).ok();
+                    idle = false;
+                }
+                if idle {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }));
+        Ok(relay)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Two devices configured as peers of each other
+pub struct DevicePair {
+    pub a: TestDevice,
+    pub b: TestDevice,
+    relay: Relay,
+}
+
+impl DevicePair {
+    pub fn new(config: DevicePairConfig) -> Result<DevicePair, Error> {
+        let mtu = config.tun_mtu.unwrap_or(MTU);
+        let mut a = TestDevice::with_mtu(
+            Ipv4Addr::new(10, 0, 0, 1),
+            config.device_config.clone(),
+            mtu,
+        )?;
+        let mut b = TestDevice::with_mtu(Ipv4Addr::new(10, 0, 0, 2), config.device_config, mtu)?;
+        let relay = Relay::new(a.listen_port, b.listen_port).map_err(Error::Socket)?;
+
+        let (key_a, key_b) = (a.public_key(), b.public_key());
+        a.add_peer(&key_b, b.ip, relay.addr_a, config.persistent_keepalive)?;
+        b.add_peer(&key_a, a.ip, *relay.addr_b.lock(), None)?;
+        Ok(DevicePair { a, b, relay })
+    }
+
+    /// Make the packets of `a` come from a different address, like a client moving between
+    /// networks. Packets that `b` sends to the previous address are lost.
+    pub fn roam_a(&self) {
+        self.relay.roam.store(true, Ordering::Relaxed);
+        while self.relay.roam.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Send the last UDP packet of `a` to `b` again, like an attacker on the path replaying it
+    pub fn replay_a_to_b(&self) {
+        self.relay.replay.store(true, Ordering::Relaxed);
+        while self.relay.replay.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// The number of UDP packets sent from `a` to `b`
+    pub fn packets_a_to_b(&self) -> usize {
+        self.relay.forwarded[0].load(Ordering::Relaxed)
+    }
+
+    /// The number of UDP packets sent from `b` to `a`
+    pub fn packets_b_to_a(&self) -> usize {
+        self.relay.forwarded[1].load(Ordering::Relaxed)
+    }
+
+    /// Where `a` is reached by `b`, unless its packets were sent from elsewhere since
+    pub fn address_of_a(&self) -> SocketAddr {
+        *self.relay.addr_b.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::config::{ConfigDiff, PeerConfig, WgConfig};
+    use crate::device::Resolver;
+    use crate::device::{
+        KeyReloadError, PaddingMode, ReloadHook, ReloadStage, ReloadSummary, SocketHook,
+    };
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn endpoint_of_peer(device: &mut TestDevice) -> Option<SocketAddr> {
+        let response = device.get().unwrap();
+        let endpoint = response.lines().find_map(|l| l.strip_prefix("endpoint="))?;
+        endpoint.parse().ok()
+    }
+
+    #[test]
+    fn test_initial_handshake_latency() {
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+
+        let start = Instant::now();
+        pair.a.send_to(&pair.b, b"first");
+        let packet = pair.b.recv_timeout(TIMEOUT).expect("No packet");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, b"first"));
+
+        // A single handshake was needed
+        assert_eq!(pair.packets_b_to_a(), 1);
+    }
+
+    /// Blocks every resolution until it is released, or for [`TIMEOUT`]
+    struct BlockingResolver {
+        started: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+        addr: SocketAddr,
+    }
+
+    impl Resolver for BlockingResolver {
+        fn resolve(&self, _host: &str) -> io::Result<Vec<SocketAddr>> {
+            self.started.lock().send(()).ok();
+            self.release.lock().recv_timeout(TIMEOUT).ok();
+            Ok(vec![self.addr])
+        }
+    }
+
+    /// Install a [`BlockingResolver`] on `device` that resolves every host to `addr`. Returns the
+    /// receiver of the started resolutions, and the sender that releases them.
+    fn block_resolution(
+        device: &TestDevice,
+        addr: SocketAddr,
+    ) -> (mpsc::Receiver<()>, mpsc::Sender<()>) {
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        device.handle.set_resolver(Arc::new(BlockingResolver {
+            started: Mutex::new(started_tx),
+            release: Mutex::new(release_rx),
+            addr,
+        }));
+        (started, release)
+    }
+
+    /// Check that `b` can still reach `a` while a resolution started by `op` on `a` is pending,
+    /// then let the resolution finish
+    fn run_during_slow_resolution<R: Send>(
+        pair: &DevicePair,
+        addr: SocketAddr,
+        op: impl FnOnce(&DeviceHandle) -> R + Send,
+    ) -> R {
+        let (a, b) = (&pair.a, &pair.b);
+        let (started, release) = block_resolution(a, addr);
+        let handle = &a.handle;
+        thread::scope(|s| {
+            let op = s.spawn(move || op(handle));
+            started.recv_timeout(TIMEOUT).expect("No resolution");
+
+            for i in 0..5u8 {
+                b.inject(ipv4_packet(b.ip, a.ip, &[i; 100]));
+                let packet = a.tun.receiver().recv_timeout(TIMEOUT);
+                assert_eq!(packet, Ok(ipv4_packet(b.ip, a.ip, &[i; 100])));
+            }
+            assert!(!op.is_finished());
+
+            release.send(()).unwrap();
+            op.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn test_apply_config_during_slow_resolution() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        let resolved: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let new_peer = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+
+        let mut peer_b = PeerConfig::new(pair.b.public_key());
+        peer_b.endpoint = Some(pair.relay.addr_a.to_string());
+        peer_b.allowed_ips = vec![format!("{}/32", pair.b.ip).parse().unwrap()];
+        let mut peer_new = PeerConfig::new(new_peer);
+        peer_new.endpoint = Some("peer.example:51820".to_owned());
+        let config = WgConfig {
+            peers: vec![peer_b, peer_new],
+            ..Default::default()
+        };
+
+        let result =
+            run_during_slow_resolution(&pair, resolved, |handle| handle.apply_config(config));
+        assert!(result.is_ok());
+        let response = pair.a.get().unwrap();
+        assert!(response.contains(&format!("endpoint={}", resolved)));
+    }
+
+    #[test]
+    fn test_apply_diff_during_slow_resolution() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        let resolved: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let new_peer = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+
+        let mut peer_new = PeerConfig::new(new_peer);
+        peer_new.endpoint = Some("peer.example:51820".to_owned());
+        let diff = ConfigDiff {
+            added: vec![peer_new],
+            ..Default::default()
+        };
+
+        let result = run_during_slow_resolution(&pair, resolved, |handle| handle.apply_diff(diff));
+        assert!(result.is_ok());
+        let response = pair.a.get().unwrap();
+        assert!(response.contains(&format!("endpoint={}", resolved)));
+    }
+
+    #[test]
+    fn test_reload_during_slow_resolution() {
+        let path =
+            std::env::temp_dir().join(format!("boringtun-slow-reload-{}", std::process::id()));
+        let config = DevicePairConfig {
+            device_config: DeviceConfig {
+                config_file: Some(path.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut pair = DevicePair::new(config).unwrap();
+        let resolved: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let new_peer = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let file = format!(
+            "[Interface]\n\n[Peer]\nPublicKey = {}\nAllowedIPs = {}/32\nEndpoint = {}\n\n\
+             [Peer]\nPublicKey = {}\nEndpoint = peer.example:51820\n",
+            base64::encode(pair.b.public_key().as_bytes()),
+            pair.b.ip,
+            pair.relay.addr_a,
+            base64::encode(new_peer.as_bytes())
+        );
+        std::fs::write(&path, file).unwrap();
+
+        let result = run_during_slow_resolution(&pair, resolved, |handle| handle.trigger_reload());
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+        let response = pair.a.get().unwrap();
+        assert!(response.contains(&format!("endpoint={}", resolved)));
+    }
+
+    #[test]
+    fn test_traffic_during_slow_resolution() {
+        let DevicePair {
+            mut a,
+            b,
+            relay: _relay,
+        } = DevicePair::new(DevicePairConfig::default()).unwrap();
+        let resolved: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let (started, release) = block_resolution(&a, resolved);
+        let new_peer = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+
+        thread::scope(|s| {
+            let uapi = &mut a.uapi;
+            let set = s.spawn(move || {
+                let request = format!(
+                    "set=1\npublic_key={}\nendpoint=peer.example:51820\n\n",
+                    encode_hex(new_peer.as_bytes())
+                );
+                uapi.get_mut().write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                uapi.read_line(&mut response).unwrap();
+                response
+            });
+            started.recv_timeout(TIMEOUT).expect("No resolution");
+
+            // The device keeps moving packets while the host name is being resolved
+            for i in 0..5u8 {
+                b.inject(ipv4_packet(b.ip, a.ip, &[i; 100]));
+                let packet = a.tun.receiver().recv_timeout(TIMEOUT);
+                assert_eq!(packet, Ok(ipv4_packet(b.ip, a.ip, &[i; 100])));
+            }
+            assert!(!set.is_finished());
+
+            release.send(()).unwrap();
+            assert_eq!(set.join().unwrap(), "errno=0\n");
+        });
+
+        let mut blank = String::new();
+        a.uapi.read_line(&mut blank).unwrap();
+        let response = a.get().unwrap();
+        assert!(response.contains(&format!("endpoint={}", resolved)));
+    }
+
+    #[test]
+    fn test_inline_handshakes() {
+        let pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                handshake_threads: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(pair.a.handle.handshake_threads.is_empty());
+
+        pair.a.send_to(&pair.b, b"request");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        pair.b.send_to(&pair.a, b"response");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+        assert_eq!(pair.a.handle.stats().dropped_handshakes, 0);
+    }
+
+    #[test]
+    fn test_shutdown_joins_handshake_threads() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"request");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        assert!(!pair.a.handle.handshake_threads.is_empty());
+
+        pair.a.handle.shutdown_timeout(TIMEOUT).unwrap();
+        assert!(pair.a.handle.threads.is_empty());
+        assert!(pair.a.handle.handshake_threads.is_empty());
+        let running = &pair.a.handle.device.read().running;
+        assert_eq!(running.handshakes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_data_both_directions() {
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+
+        for i in 0..10u8 {
+            pair.a.send_to(&pair.b, &[i; 100]);
+            let packet = pair.b.recv_timeout(TIMEOUT).expect("No request");
+            assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &[i; 100]));
+
+            pair.b.send_to(&pair.a, &[i; 1000]);
+            let packet = pair.a.recv_timeout(TIMEOUT).expect("No response");
+            assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, &[i; 1000]));
+        }
+
+        // Packets from addresses that are not allowed for the peer are dropped
+        pair.b.inject(ipv4_packet(
+            Ipv4Addr::new(10, 0, 0, 3),
+            pair.a.ip,
+            b"spoofed",
+        ));
+        assert!(pair.a.recv_timeout(Duration::from_millis(500)).is_none());
+    }
+
+    #[test]
+    fn test_strict_source_check() {
+        for strict_source_check in [true, false] {
+            let mut pair = DevicePair::new(DevicePairConfig {
+                device_config: DeviceConfig {
+                    allow_overlapping_ips: true,
+                    strict_source_check,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+            // `b` is allowed the whole /24, of which another peer is routed 10.0.0.3
+            let other = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            let b = encode_hex(pair.b.public_key().as_bytes());
+            pair.a
+                .set(&format!(
+                    "public_key={b}\nremove=true\npublic_key={b}\nendpoint={}\n\
+                     allowed_ip=10.0.0.0/24\npublic_key={}\nallowed_ip=10.0.0.3/32",
+                    pair.relay.addr_a,
+                    encode_hex(other.as_bytes())
+                ))
+                .unwrap();
+            pair.b.send_to(&pair.a, b"ping");
+            assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+
+            let spoofed = ipv4_packet(Ipv4Addr::new(10, 0, 0, 3), pair.a.ip, b"spoofed");
+            pair.b.inject(spoofed.clone());
+            if strict_source_check {
+                assert!(pair.a.recv_timeout(Duration::from_millis(500)).is_none());
+            } else {
+                assert_eq!(pair.a.recv_timeout(TIMEOUT), Some(spoofed));
+            }
+            // Outside of the allowed IPs of `b`, dropped either way
+            pair.b.inject(ipv4_packet(
+                Ipv4Addr::new(10, 0, 1, 1),
+                pair.a.ip,
+                b"spoofed",
+            ));
+            assert!(pair.a.recv_timeout(Duration::from_millis(500)).is_none());
+
+            let stats = pair.a.handle.peer_stats(&pair.b.public_key()).unwrap();
+            assert_eq!(stats.rx_drops_allowed_ips, 1 + strict_source_check as u64);
+        }
+    }
+
+    #[test]
+    fn test_jumbo_packets() {
+        // The MTU of a jumbo frame, less the encapsulation over IPv6
+        let pair = DevicePair::new(DevicePairConfig {
+            tun_mtu: Some(9000 - 80),
+            ..Default::default()
+        })
+        .unwrap();
+
+        for size in [1500, 4000, 9000 - 80 - 20] {
+            pair.a.send_to(&pair.b, &vec![1; size]);
+            let packet = pair.b.recv_timeout(TIMEOUT).expect("No request");
+            assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &vec![1; size]));
+
+            pair.b.send_to(&pair.a, &vec![2; size]);
+            let packet = pair.a.recv_timeout(TIMEOUT).expect("No response");
+            assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, &vec![2; size]));
+        }
+
+        let stats = pair.a.handle.stats();
+        assert!(stats.udp_recv_buffer_size.unwrap() > 0);
+        assert!(stats.udp_send_buffer_size.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_reload_private_key() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"before");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        let current = pair.a.private_key.to_bytes();
+        assert!(matches!(
+            pair.a.handle.reload_private_key(&current),
+            Err(KeyReloadError::KeyUnchanged)
+        ));
+
+        // The peer learns the new public key first
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let public_key = x25519::PublicKey::from(&private_key);
+        let old = encode_hex(pair.a.public_key().as_bytes());
+        pair.b
+            .set(&format!("public_key={}\nremove=true", old))
+            .unwrap();
+        let (ip, addr) = (pair.a.ip, pair.address_of_a());
+        pair.b.add_peer(&public_key, ip, addr, None).unwrap();
+
+        // A handshake is initiated with the peer that had a session, without waiting for data
+        let responses = pair.packets_b_to_a();
+        pair.a
+            .handle
+            .reload_private_key(&private_key.to_bytes())
+            .unwrap();
+        pair.a.private_key = private_key;
+        let deadline = Instant::now() + TIMEOUT;
+        while pair.packets_b_to_a() == responses {
+            assert!(Instant::now() < deadline, "No handshake response");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        pair.a.send_to(&pair.b, b"after");
+        let packet = pair.b.recv_timeout(TIMEOUT).expect("No request");
+        assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, b"after"));
+        pair.b.send_to(&pair.a, b"response");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+        let public_key = base64::encode(pair.a.public_key().as_bytes());
+        assert_eq!(pair.a.handle.dump().public_key, Some(public_key.clone()));
+        assert_eq!(pair.a.handle.local_public_key(), Some(pair.a.public_key()));
+        assert_eq!(
+            pair.a.handle.private_key_fingerprint(),
+            format!("{}...", &public_key[..16])
+        );
+    }
+
+    #[test]
+    fn test_json_dump() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"ping");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        let dump = pair.a.handle.dump();
+        assert_eq!(
+            dump.public_key,
+            Some(base64::encode(pair.a.public_key().as_bytes()))
+        );
+        assert_eq!(dump.listen_port, pair.a.listen_port);
+        let peer = &dump.peers[0];
+        assert_eq!(
+            peer.public_key,
+            base64::encode(pair.b.public_key().as_bytes())
+        );
+        assert_eq!(peer.allowed_ips, [format!("{}/32", pair.b.ip)]);
+        assert!(peer.last_handshake.as_ref().unwrap().ends_with('Z'));
+
+        let response = pair.a.uapi("get=2").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(json["listen_port"], pair.a.listen_port);
+        assert_eq!(json["peers"][0]["public_key"], peer.public_key.as_str());
+        assert!(json["peers"][0]["tx_bytes"].as_u64().unwrap() > 0);
+        assert!(json.get("private_key").is_none());
+        assert_eq!(pair.a.handle.dump_json().trim(), response.trim());
+    }
+
+    #[test]
+    fn test_overlapping_allowed_ips() {
+        let peer = |allowed_ips: &[&str]| {
+            let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            let mut keys = format!("public_key={}", encode_hex(key.as_bytes()));
+            for ip in allowed_ips {
+                keys.push_str(&format!("\nallowed_ip={}", ip));
+            }
+            keys
+        };
+
+        let mut device =
+            TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), DeviceConfig::default()).unwrap();
+        device.set(&peer(&["10.0.0.0/8"])).unwrap();
+        let e = device.set(&peer(&["10.1.0.0/16"])).unwrap_err();
+        assert!(e.to_string().contains("errno 17"), "{}", e);
+        let e = device
+            .set(&peer(&["192.0.2.0/24", "0.0.0.0/0"]))
+            .unwrap_err();
+        assert!(e.to_string().contains("errno 17"), "{}", e);
+        device.set(&peer(&["11.0.0.0/8", "::/0"])).unwrap();
+        assert_eq!(device.handle.dump().peers.len(), 2);
+
+        let config = DeviceConfig {
+            allow_overlapping_ips: true,
+            ..Default::default()
+        };
+        let mut device = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+        device.set(&peer(&["10.0.0.0/8"])).unwrap();
+        device.set(&peer(&["10.1.0.0/16"])).unwrap();
+    }
+
+    #[test]
+    fn test_configured_key_and_port() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (private_key, public_key) = crate::keys::generate_keypair();
+        let path = std::env::temp_dir().join(format!("boringtun-pinned-{}", std::process::id()));
+        let config = DeviceConfig {
+            private_key: Some(private_key),
+            listen_port: Some(port),
+            config_file: Some(path.clone()),
+            ..Default::default()
+        };
+        // The key of the test device is set over the API afterwards
+        let mut device = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+        assert_eq!(device.handle.listen_port(), port);
+
+        // The key and the port of the device configuration win over those of the file
+        let other = encode_hex(x25519::StaticSecret::random_from_rng(OsRng).to_bytes());
+        std::fs::write(
+            &path,
+            format!("[Interface]\nPrivateKey = {}\nListenPort = 9\n", other),
+        )
+        .unwrap();
+        device.handle.trigger_reload().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let response = device.get().unwrap();
+        let own = format!("own_public_key={}\n", encode_hex(public_key.as_bytes()));
+        assert!(response.contains(&own), "{}", response);
+        assert!(response.contains(&format!("listen_port={}\n", port)));
+    }
+
+    #[test]
+    fn test_uapi_errors() {
+        let mut device =
+            TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), DeviceConfig::default()).unwrap();
+        let e = device.uapi("get=3").unwrap_err();
+        assert!(matches!(e, Error::Uapi(UapiError(libc::EIO))), "{}", e);
+        let e = device.set("listen_port=any").unwrap_err();
+        assert!(matches!(e, Error::Uapi(UapiError(libc::EINVAL))), "{}", e);
+
+        // Changing the keys of an existing peer is not supported, the request fails and the
+        // device carries on
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let peer = format!("public_key={}", encode_hex(key.as_bytes()));
+        device
+            .set(&format!("{}\nallowed_ip=10.0.0.2/32", peer))
+            .unwrap();
+        let e = device
+            .set(&format!("{}\npersistent_keepalive_interval=25", peer))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "UAPI: Invalid argument (errno 22)");
+        assert!(device.get().unwrap().contains("allowed_ip=10.0.0.2/32"));
+    }
+
+    #[test]
+    fn test_peer_log_id() {
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        let key = pair.b.public_key();
+        let encoded = base64::encode(key.as_bytes());
+        assert_eq!(
+            pair.a.handle.device.read().peer_log_id(&key),
+            format!("{}...", &encoded[..16])
+        );
+
+        let pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                log_public_keys: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let key = pair.b.public_key();
+        let encoded = base64::encode(key.as_bytes());
+        assert_eq!(pair.a.handle.device.read().peer_log_id(&key), encoded);
+    }
+
+    #[test]
+    fn test_uapi_drop_counters() {
+        for uapi_drop_counters in [true, false] {
+            let mut pair = DevicePair::new(DevicePairConfig {
+                device_config: DeviceConfig {
+                    uapi_drop_counters,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+            pair.a.send_to(&pair.b, b"ping");
+            assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+            // Not from an address that `a` is allowed
+            let spoofed = ipv4_packet(Ipv4Addr::new(10, 0, 0, 3), pair.b.ip, b"spoofed");
+            pair.a.inject(spoofed);
+
+            let deadline = Instant::now() + TIMEOUT;
+            while pair
+                .b
+                .handle
+                .peer_stats(&pair.a.public_key())
+                .unwrap()
+                .rx_drops_allowed_ips
+                == 0
+            {
+                assert!(Instant::now() < deadline, "The packet wasn't dropped");
+                thread::sleep(Duration::from_millis(10));
+            }
+            let response = pair.b.get().unwrap();
+            assert_eq!(
+                response.contains("\nbt_rx_drops_allowed_ips=1\n"),
+                uapi_drop_counters,
+                "{}",
+                response
+            );
+            assert!(!response.contains("bt_rx_drops_auth"));
+        }
+    }
+
+    #[test]
+    fn test_replayed_packet_counted() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"ping");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        let response = pair.b.get().unwrap();
+        assert!(response.contains("\nrx_replay_errors=0\n"), "{}", response);
+
+        // The data packet of the ping, which `b` already received
+        pair.replay_a_to_b();
+        let deadline = Instant::now() + TIMEOUT;
+        while pair
+            .b
+            .handle
+            .peer_stats(&pair.a.public_key())
+            .unwrap()
+            .replay_detected_packets
+            == 0
+        {
+            assert!(Instant::now() < deadline, "The replay wasn't detected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pair.b.recv_timeout(Duration::from_millis(100)).is_none());
+        let response = pair.b.get().unwrap();
+        assert!(response.contains("\nrx_replay_errors=1\n"), "{}", response);
+        assert!(response.contains("\nrx_mac_errors=0\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_persistent_keepalive() {
+        let pair = DevicePair::new(DevicePairConfig {
+            persistent_keepalive: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // The keepalive starts the handshake on its own
+        let start = Instant::now();
+        while pair.packets_b_to_a() == 0 {
+            assert!(start.elapsed() < TIMEOUT, "No handshake");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let sent = pair.packets_a_to_b();
+        thread::sleep(Duration::from_millis(2500));
+        assert!(pair.packets_a_to_b() >= sent + 2);
+        assert!(pair.a.recv_timeout(Duration::ZERO).is_none());
+        assert!(pair.b.recv_timeout(Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_endpoint_roaming() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"before");
+        pair.b
+            .recv_timeout(TIMEOUT)
+            .expect("No packet before roaming");
+        let old = pair.address_of_a();
+        assert_eq!(endpoint_of_peer(&mut pair.b), Some(old));
+
+        // `b` follows `a` to its new address once it gets a packet from there
+        pair.roam_a();
+        let new = pair.address_of_a();
+        assert_ne!(old, new);
+        pair.a.send_to(&pair.b, b"after");
+        pair.b
+            .recv_timeout(TIMEOUT)
+            .expect("No packet after roaming");
+        assert_eq!(endpoint_of_peer(&mut pair.b), Some(new));
+
+        pair.b.send_to(&pair.a, b"response");
+        let packet = pair.a.recv_timeout(TIMEOUT).expect("No response");
+        assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, b"response"));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"old key");
+        pair.b
+            .recv_timeout(TIMEOUT)
+            .expect("No packet with the old key");
+
+        let old_key = pair.b.public_key();
+        pair.b.private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let private_key = encode_hex(pair.b.private_key.to_bytes());
+        pair.b.set(&format!("private_key={}", private_key)).unwrap();
+
+        let relay_addr = pair.relay.addr_a;
+        let (new_key, ip_b) = (pair.b.public_key(), pair.b.ip);
+        pair.a
+            .set(&format!(
+                "public_key={}\nremove=true",
+                encode_hex(old_key.as_bytes())
+            ))
+            .unwrap();
+        pair.a.add_peer(&new_key, ip_b, relay_addr, None).unwrap();
+
+        // A new handshake is needed with the new key, the packet is queued until then
+        pair.a.send_to(&pair.b, b"new key");
+        let packet = pair
+            .b
+            .recv_timeout(TIMEOUT)
+            .expect("No packet with the new key");
+        assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, b"new key"));
+
+        pair.b.send_to(&pair.a, b"response");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn test_padding() {
+        let block = PaddingMode::BlockSize(std::num::NonZeroU16::new(256).unwrap());
+        for padding_mode in [block, PaddingMode::RandomUpTo(300)] {
+            let pair = DevicePair::new(DevicePairConfig {
+                device_config: DeviceConfig {
+                    padding_mode,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+
+            // The padding is stripped, up to packets that can't be padded without exceeding the MTU
+            for size in [1, 255, 256, 1000, MTU - 20] {
+                pair.a.send_to(&pair.b, &vec![7; size]);
+                let packet = pair.b.recv_timeout(TIMEOUT).expect("No packet");
+                assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &vec![7; size]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decrement_inner_ttl() {
+        let pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                decrement_inner_ttl: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+
+        pair.a.send_to(&pair.b, b"ping");
+        let packet = pair.b.recv_timeout(TIMEOUT).expect("No packet");
+        assert_eq!(packet[8], 63);
+        assert_eq!(&packet[20..], b"ping");
+
+        // The last hop, the sender hears about it instead of the peer
+        let mut expiring = ipv4_packet(pair.a.ip, pair.b.ip, b"expiring");
+        expiring[8] = 1;
+        pair.a.inject(expiring.clone());
+        let error = pair.a.recv_timeout(TIMEOUT).expect("No ICMP error");
+        assert_eq!(&error[12..16], &expiring[16..20]);
+        assert_eq!(&error[16..20], &expiring[12..16]);
+        assert_eq!(&error[20..22], &[11, 0]);
+        assert_eq!(&error[28..], &expiring[..]);
+        assert!(pair.b.recv_timeout(Duration::from_millis(200)).is_none());
+    }
+
+    #[test]
+    fn test_pcap_capture() {
+        let path = std::env::temp_dir().join(format!("boringtun-capture-{}", std::process::id()));
+        let config = DeviceConfig {
+            pcap_path: Some(path.clone()),
+            ..Default::default()
+        };
+        // Only `a` captures
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), Default::default()).unwrap();
+        let relay = Relay::new(a.listen_port, b.listen_port).unwrap();
+        a.add_peer(&b.public_key(), b.ip, relay.addr_a, None)
+            .unwrap();
+        b.add_peer(&a.public_key(), a.ip, *relay.addr_b.lock(), None)
+            .unwrap();
+
+        a.send_to(&b, b"request");
+        b.recv_timeout(TIMEOUT).expect("No request");
+        b.send_to(&a, b"response");
+        a.recv_timeout(TIMEOUT).expect("No response");
+
+        // The header of the file, then the inner packets after the header of their record
+        let sent = ipv4_packet(a.ip, b.ip, b"request");
+        let received = ipv4_packet(b.ip, a.ip, b"response");
+        let capture = std::fs::read(&path).unwrap();
+        assert_eq!(capture.len(), 24 + 16 + sent.len() + 16 + received.len());
+        assert_eq!(&capture[40..40 + sent.len()], &sent[..]);
+        assert!(capture.ends_with(&received));
+
+        // Without the peer, the file stays as it was
+        let peer = format!(
+            "public_key={}\nbt_pcap_capture=false",
+            encode_hex(b.public_key().as_bytes())
+        );
+        a.set(&peer).unwrap();
+        assert!(a.get().unwrap().contains("bt_pcap_capture=false\n"));
+        a.send_to(&b, b"not captured");
+        b.recv_timeout(TIMEOUT).expect("No packet");
+        assert_eq!(std::fs::read(&path).unwrap(), capture);
+
+        drop(a);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_on_idle() {
+        let idle = Duration::from_millis(1500);
+        let mut pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                exit_on_idle: Some(idle),
+                ..Default::default()
+            },
+            // More often than the period, without keeping the devices running
+            persistent_keepalive: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Data packets and UAPI connections keep them running
+        for _ in 0..3 {
+            pair.a.send_to(&pair.b, b"data");
+            assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+            assert!(!pair.b.handle.wait_timeout(idle / 4));
+            pair.a.get().unwrap();
+            assert!(!pair.a.handle.wait_timeout(idle / 4));
+        }
+
+        // Then `a` stops on its own, as gracefully as on request, while only UAPI connections
+        // keep `b` running
+        let deadline = Instant::now() + idle * 3;
+        while !pair.a.handle.wait_timeout(idle / 4) {
+            assert!(Instant::now() < deadline, "Still running");
+            pair.b.get().unwrap();
+        }
+        pair.b.get().unwrap();
+        assert!(!pair.b.handle.wait_timeout(idle / 2));
+        assert!(pair.b.handle.wait_timeout(idle * 3));
+    }
+
+    #[test]
+    fn test_idle_peers_wakeups() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+
+        // The peers have an endpoint, but never send anything
+        let mut keys = String::new();
+        for i in 0..10_000u32 {
+            let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            let [_, _, hi, lo] = i.to_be_bytes();
+            keys.push_str(&format!(
+                "public_key={}\nendpoint=127.0.0.1:9\nallowed_ip=10.1.{}.{}/32\n",
+                encode_hex(key.as_bytes()),
+                hi,
+                lo
+            ));
+        }
+        pair.a.set(keys.trim_end()).unwrap();
+        pair.a.send_to(&pair.b, b"ping");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        // No timer is due for seconds after a data packet, and none ever for the idle peers,
+        // the timer event doesn't fire meanwhile rather than walk the peers
+        let wakeups = || {
+            let device = pair.a.handle.device.read();
+            device.timer_wakeups.load(Ordering::Relaxed)
+        };
+        let before = wakeups();
+        thread::sleep(Duration::from_secs(2));
+        assert_eq!(wakeups(), before);
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        use crate::device::{TcpFraming, Transport};
+        use std::net::TcpListener;
+
+        let free_addr = || {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let config = |addr| DeviceConfig {
+            transport: Transport::Tcp {
+                addr,
+                framing: TcpFraming::LengthPrefixed,
+            },
+            ..Default::default()
+        };
+        let (addr_a, addr_b) = (free_addr(), free_addr());
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config(addr_a)).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), config(addr_b)).unwrap();
+
+        // `b` has no endpoint for `a`, it answers over the connection `a` makes
+        a.add_peer(&b.public_key(), b.ip, addr_b, None).unwrap();
+        b.set(&format!(
+            "public_key={}\nallowed_ip={}/32",
+            encode_hex(a.public_key().as_bytes()),
+            a.ip
+        ))
+        .unwrap();
+
+        for i in 0..10u8 {
+            a.send_to(&b, &[i; 1000]);
+            let packet = b.recv_timeout(TIMEOUT).expect("No request");
+            assert_eq!(packet, ipv4_packet(a.ip, b.ip, &[i; 1000]));
+
+            b.send_to(&a, &[i; 100]);
+            let packet = a.recv_timeout(TIMEOUT).expect("No response");
+            assert_eq!(packet, ipv4_packet(b.ip, a.ip, &[i; 100]));
+        }
+    }
+
+    #[test]
+    fn test_socks5_transport() {
+        use crate::device::transport::socks5::tests::Proxy;
+        use crate::device::Socks5;
+
+        let proxy = Proxy::spawn(None);
+        let config = DeviceConfig {
+            outer_transport: Some(Arc::new(Socks5::new(proxy.addr))),
+            ..Default::default()
+        };
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), Default::default()).unwrap();
+
+        // `b` learns the address of the relay as the endpoint of `a`
+        let addr_b = SocketAddr::from((Ipv4Addr::LOCALHOST, b.listen_port));
+        a.add_peer(&b.public_key(), b.ip, addr_b, None).unwrap();
+        b.set(&format!(
+            "public_key={}\nallowed_ip={}/32",
+            encode_hex(a.public_key().as_bytes()),
+            a.ip
+        ))
+        .unwrap();
+
+        for i in 0..10u8 {
+            a.send_to(&b, &[i; 1000]);
+            let packet = b.recv_timeout(TIMEOUT).expect("No request");
+            assert_eq!(packet, ipv4_packet(a.ip, b.ip, &[i; 1000]));
+
+            b.send_to(&a, &[i; 100]);
+            let packet = a.recv_timeout(TIMEOUT).expect("No response");
+            assert_eq!(packet, ipv4_packet(b.ip, a.ip, &[i; 100]));
+        }
+        let relay = endpoint_of_peer(&mut b).unwrap();
+        assert_ne!(relay.port(), a.listen_port);
+
+        // The peer is reported unreachable while the proxy refuses to associate again
+        proxy.set_refuse(true);
+        proxy.drop_associations();
+        let mut unreachable = false;
+        for _ in 0..100 {
+            a.send_to(&b, b"lost");
+            if a.get().unwrap().contains("bt_unreachable=true") {
+                unreachable = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(unreachable);
+
+        // Then `b` follows `a` to the new relay
+        proxy.set_refuse(false);
+        let mut delivered = false;
+        for _ in 0..200 {
+            a.send_to(&b, b"ping");
+            // Skip what went through before the association ended
+            let packet = b.recv_timeout(Duration::from_millis(50));
+            if packet == Some(ipv4_packet(a.ip, b.ip, b"ping")) {
+                delivered = true;
+                break;
+            }
+        }
+        assert!(delivered);
+        assert!(!a.get().unwrap().contains("bt_unreachable"));
+        b.send_to(&a, b"pong");
+
+        // The packets sent while the peer was unreachable were answered with ICMP errors
+        let mut host_unreachable = false;
+        loop {
+            let packet = a.recv_timeout(TIMEOUT).expect("No response");
+            if packet == ipv4_packet(b.ip, a.ip, b"pong") {
+                break;
+            }
+            host_unreachable |= packet[9] == 1 && packet[20..22] == [3, 1];
+        }
+        assert!(host_unreachable);
+        assert_ne!(endpoint_of_peer(&mut b), Some(relay));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_multipath() {
+        let local = |last: u8| SocketAddr::from(([127, 0, 0, last], 0));
+        let config = DeviceConfig {
+            bind_addrs: vec![local(1), local(2)],
+            ..Default::default()
+        };
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), Default::default()).unwrap();
+        let addr_b = SocketAddr::from((Ipv4Addr::LOCALHOST, b.listen_port));
+        a.add_peer(&b.public_key(), b.ip, addr_b, None).unwrap();
+        b.set(&format!(
+            "public_key={}\nallowed_ip={}/32",
+            encode_hex(a.public_key().as_bytes()),
+            a.ip
+        ))
+        .unwrap();
+        // The packets queued during the handshake go out with it
+        a.send_to(&b, b"ping");
+        b.recv_timeout(TIMEOUT).expect("No handshake");
+
+        // `b` sees the flows of `a` come from both addresses, on the same port, and its replies
+        // reach `a` on either
+        let mut paths = std::collections::HashMap::new();
+        for port in 1000..1032u16 {
+            // A UDP header from `port` to port 53
+            let mut ports = port.to_be_bytes().to_vec();
+            ports.extend_from_slice(&53u16.to_be_bytes());
+            for _ in 0..2 {
+                a.send_to(&b, &ports);
+                let packet = b.recv_timeout(TIMEOUT).expect("No request");
+                assert_eq!(packet, ipv4_packet(a.ip, b.ip, &ports));
+
+                let endpoint = endpoint_of_peer(&mut b).unwrap();
+                assert_eq!(endpoint.port(), a.listen_port);
+                // The packets of a flow all take the same path
+                assert_eq!(*paths.entry(port).or_insert(endpoint), endpoint);
+
+                b.send_to(&a, b"pong");
+                let packet = a.recv_timeout(TIMEOUT).expect("No response");
+                assert_eq!(packet, ipv4_packet(b.ip, a.ip, b"pong"));
+            }
+        }
+        let mut addrs: Vec<_> = paths.values().map(|endpoint| endpoint.ip()).collect();
+        addrs.sort();
+        addrs.dedup();
+        assert_eq!(addrs, [local(1).ip(), local(2).ip()]);
+    }
+
+    #[test]
+    #[cfg(feature = "tx-pacing")]
+    fn test_tx_pacing() {
+        use crate::device::shaper::BandwidthLimit;
+
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"handshake");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        // Packets over the burst are delayed rather than dropped, the loopback interface has no
+        // `fq` qdisc to hold them
+        let key_b = pair.b.public_key();
+        let limit = BandwidthLimit {
+            bytes_per_sec: 10_000,
+            burst: 2_000,
+        };
+        pair.a
+            .handle
+            .set_bandwidth_limit(&key_b, Some(limit))
+            .unwrap();
+        for i in 0..8u8 {
+            pair.a.send_to(&pair.b, &[i; 980]);
+        }
+        for i in 0..8u8 {
+            let packet = pair.b.recv_timeout(TIMEOUT).expect("No packet");
+            assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &[i; 980]));
+        }
+        assert_eq!(pair.a.handle.peer_stats(&key_b).unwrap().tx_dropped, 0);
+
+        // Until they would wait for more than a second
+        for _ in 0..20 {
+            pair.a.send_to(&pair.b, &[0; 980]);
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(pair.a.handle.peer_stats(&key_b).unwrap().tx_dropped > 0);
+    }
+
+    #[test]
+    fn test_obfuscation() {
+        use crate::device::Obfuscation;
+
+        let obfuscation = Obfuscation {
+            junk_packet_count: 4,
+            junk_packet_min_size: 40,
+            junk_packet_max_size: 70,
+            init_packet_junk_size: 52,
+            response_packet_junk_size: 16,
+            message_types: [1_733_685_222, 1_204_728_848, 1_496_858_936, 1_419_062_430],
+        };
+        let mut pair = DevicePair::new(DevicePairConfig {
+            device_config: DeviceConfig {
+                obfuscation,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+
+        for i in 0..5u8 {
+            pair.a.send_to(&pair.b, &[i; 500]);
+            let packet = pair.b.recv_timeout(TIMEOUT).expect("No request");
+            assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &[i; 500]));
+            pair.b.send_to(&pair.a, &[i; 50]);
+            let packet = pair.a.recv_timeout(TIMEOUT).expect("No response");
+            assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, &[i; 50]));
+        }
+        // The junk datagrams, the initiation and the data
+        assert!(pair.packets_a_to_b() >= 4 + 1 + 5);
+        let status = pair.a.get().unwrap();
+        assert!(status.contains("jc=4\n"));
+        assert!(status.contains("h4=1419062430\n"));
+
+        // Going back to standard datagrams on one side only breaks the tunnel
+        let standard = "jc=0\njmin=0\njmax=0\ns1=0\ns2=0\nh1=1\nh2=2\nh3=3\nh4=4";
+        pair.b.set(standard).unwrap();
+        assert!(!pair.b.get().unwrap().contains("jc="));
+        pair.a.send_to(&pair.b, b"lost");
+        assert!(pair.b.recv_timeout(Duration::from_millis(500)).is_none());
+
+        // And both sides interoperate again once they agree, on the same ports
+        pair.a.set(standard).unwrap();
+        assert_eq!(pair.a.handle.listen_port(), pair.a.listen_port);
+        pair.a.send_to(&pair.b, b"found");
+        let packet = pair.b.recv_timeout(TIMEOUT).expect("No packet");
+        assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, b"found"));
+
+        // Parameters that are set together are checked together
+        assert!(pair.a.set("jmin=100\njmax=50").is_err());
+        pair.a.set("jmax=200\njmin=100").unwrap();
+    }
+
+    #[test]
+    fn test_apply_diff() {
+        use crate::device::config::{ConfigDiff, PeerChanges, PeerConfig};
+
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"before");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        // The modified peer keeps its session, the added one doesn't disturb it
+        let key_b = pair.b.public_key();
+        let key_c = x25519::PublicKey::from([1; 32]);
+        let diff = ConfigDiff {
+            added: vec![PeerConfig::new(key_c)],
+            modified: vec![(
+                key_b,
+                PeerChanges {
+                    persistent_keepalive: Some(Some(25)),
+                    ..Default::default()
+                },
+            )],
+            ..Default::default()
+        };
+        pair.a.handle.apply_diff(diff).unwrap();
+        let stats = pair.a.handle.peer_stats(&key_b).unwrap();
+        assert!(stats.time_since_last_handshake.is_some());
+        assert!(pair.a.handle.peer_stats(&key_c).is_some());
+        pair.a.send_to(&pair.b, b"after");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        // A diff that doesn't match the peers changes nothing
+        let diff = ConfigDiff {
+            removed: vec![key_c, x25519::PublicKey::from([2; 32])],
+            ..Default::default()
+        };
+        assert!(pair.a.handle.apply_diff(diff).is_err());
+        assert!(pair.a.handle.peer_stats(&key_c).is_some());
+
+        let diff = ConfigDiff {
+            removed: vec![key_c],
+            ..Default::default()
+        };
+        pair.a.handle.apply_diff(diff).unwrap();
+        assert!(pair.a.handle.peer_stats(&key_c).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unreachable_endpoint() {
+        let pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.b.send_to(&pair.a, b"request");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+        pair.a.send_to(&pair.b, b"response");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+
+        // The connected socket of `a` learns that nobody listens on the endpoint anymore
+        let DevicePair { a, b: _b, relay } = pair;
+        drop(relay);
+        let sent = ipv4_packet(a.ip, Ipv4Addr::new(10, 0, 0, 2), b"lost");
+        let error = (0..50).find_map(|_| {
+            a.inject(sent.clone());
+            a.recv_timeout(Duration::from_millis(100))
+        });
+        let error = error.expect("No ICMP error");
+
+        // Destination unreachable, from the destination of the packet, quoting it
+        assert_eq!(error[9], 1);
+        assert_eq!(&error[12..16], &sent[16..20]);
+        assert_eq!(&error[16..20], &sent[12..16]);
+        assert_eq!(&error[20..22], &[3, 0]);
+        assert_eq!(&error[28..], &sent[..]);
+    }
+
+    #[test]
+    fn test_replace_peers_keeps_sessions() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"request");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        pair.b.send_to(&pair.a, b"response");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+
+        let key_b = pair.b.public_key();
+        let key_c = x25519::PublicKey::from([3; 32]);
+        let peer_b = format!(
+            "public_key={}\nendpoint={}\nallowed_ip={}/32",
+            encode_hex(key_b.as_bytes()),
+            pair.relay.addr_a,
+            pair.b.ip
+        );
+        let peer_c = format!(
+            "public_key={}\nallowed_ip=10.0.0.3/32",
+            encode_hex(key_c.as_bytes())
+        );
+        pair.a
+            .set(&format!("replace_peers=true\n{}\n{}", peer_b, peer_c))
+            .unwrap();
+        assert!(pair.a.handle.peer_stats(&key_c).is_some());
+        let tx_bytes = pair.a.handle.peer_stats(&key_b).unwrap().tx_bytes;
+        assert!(tx_bytes > 0);
+
+        // The peer left out is removed, with its allowed IPs
+        pair.a
+            .set(&format!("replace_peers=true\n{}", peer_b))
+            .unwrap();
+        assert!(pair.a.handle.peer_stats(&key_c).is_none());
+        assert_eq!(pair.a.handle.stats().peers, 1);
+        assert_eq!(pair.a.handle.stats().allowed_ips, 1);
+
+        // The data is sent on the same session, without a new handshake
+        let sent = pair.packets_a_to_b();
+        pair.a.send_to(&pair.b, b"again");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        assert_eq!(pair.packets_a_to_b(), sent + 1);
+        assert!(pair.a.handle.peer_stats(&key_b).unwrap().tx_bytes > tx_bytes);
+
+        // A new preshared key needs a new session
+        pair.a
+            .set(&format!(
+                "replace_peers=true\n{}\npreshared_key={}",
+                peer_b,
+                encode_hex([7; 32])
+            ))
+            .unwrap();
+        assert_eq!(pair.a.handle.peer_stats(&key_b).unwrap().tx_bytes, 0);
+    }
+
+    #[test]
+    fn test_listen_port_range() {
+        let taken = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        // The taken port is skipped
+        let config = DeviceConfig {
+            listen_port_range: Some(port..=port + 1),
+            ..Default::default()
+        };
+        let device = TestDevice::new(ip, config).unwrap();
+        assert_eq!(device.handle.listen_port(), port + 1);
+        assert_eq!(device.listen_port, port + 1);
+
+        let config = DeviceConfig {
+            listen_port_range: Some(port..=port),
+            ..Default::default()
+        };
+        assert!(TestDevice::new(ip, config).is_err());
+    }
+
+    #[test]
+    fn test_thread_stack_size() {
+        use crate::device::MIN_THREAD_STACK_SIZE;
+
+        // Unoptimized code takes about twice as much of the stack
+        let small = match cfg!(debug_assertions) {
+            true => 2 * MIN_THREAD_STACK_SIZE,
+            false => MIN_THREAD_STACK_SIZE,
+        };
+        // The handshakes are processed on the event loops, or on a thread of their own
+        for (size, handshake_threads) in [(small, 0), (small, 1), (8 << 20, 1)] {
+            let pair = DevicePair::new(DevicePairConfig {
+                device_config: DeviceConfig {
+                    thread_stack_size: Some(size),
+                    handshake_threads,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+            for i in 0..10u8 {
+                pair.a.send_to(&pair.b, &[i; 1000]);
+                let packet = pair.b.recv_timeout(TIMEOUT).expect("No request");
+                assert_eq!(packet, ipv4_packet(pair.a.ip, pair.b.ip, &[i; 1000]));
+
+                pair.b.send_to(&pair.a, &[i; 100]);
+                let packet = pair.a.recv_timeout(TIMEOUT).expect("No response");
+                assert_eq!(packet, ipv4_packet(pair.b.ip, pair.a.ip, &[i; 100]));
+            }
+        }
+
+        let config = DeviceConfig {
+            thread_stack_size: Some(MIN_THREAD_STACK_SIZE - 1),
+            ..Default::default()
+        };
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        assert!(matches!(
+            TestDevice::new(ip, config),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tun_queue_depth() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        for depth in [0, crate::device::MAX_TUN_QUEUE_DEPTH + 1] {
+            let config = DeviceConfig {
+                tun_queue_depth: Some(depth),
+                ..Default::default()
+            };
+            match TestDevice::new(ip, config) {
+                Err(Error::InvalidConfig(_)) => {}
+                _ => panic!("A queue depth of {} was accepted", depth),
+            }
+        }
+
+        // A channel has no queue to size
+        let config = DeviceConfig {
+            tun_queue_depth: Some(1024),
+            ..Default::default()
+        };
+        match TestDevice::new(ip, config) {
+            Err(Error::InterfaceConfig(_)) => {}
+            _ => panic!("The queue depth of a channel was set"),
+        }
+    }
+
+    #[test]
+    fn test_ephemeral_listen_port() {
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), Default::default()).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), Default::default()).unwrap();
+
+        // The port picked by the system is reported, rather than 0
+        a.set("listen_port=0").unwrap();
+        let port: u16 = a
+            .get()
+            .unwrap()
+            .lines()
+            .find_map(|l| l.strip_prefix("listen_port="))
+            .and_then(|port| port.parse().ok())
+            .expect("No listen port");
+        assert_ne!(port, 0);
+        assert_eq!(a.handle.listen_port(), port);
+
+        let (key_a, key_b) = (a.public_key(), b.public_key());
+        let (ip_a, ip_b) = (a.ip, b.ip);
+        b.add_peer(&key_a, ip_a, (Ipv4Addr::LOCALHOST, port).into(), None)
+            .unwrap();
+        a.add_peer(
+            &key_b,
+            ip_b,
+            (Ipv4Addr::LOCALHOST, b.listen_port).into(),
+            None,
+        )
+        .unwrap();
+        b.send_to(&a, b"ping");
+        let packet = a.recv_timeout(TIMEOUT).expect("No packet");
+        assert_eq!(packet, ipv4_packet(ip_b, ip_a, b"ping"));
+    }
+
+    #[test]
+    fn test_socket_hook() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&created);
+        let config = DevicePairConfig {
+            device_config: DeviceConfig {
+                on_socket_created: Some(SocketHook::new(move |_| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                })),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pair = DevicePair::new(config).unwrap();
+        // The IPv4 and IPv6 listening sockets of both devices
+        assert_eq!(created.load(Ordering::Relaxed), 4);
+
+        pair.a.send_to(&pair.b, b"request");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        pair.b.send_to(&pair.a, b"response");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+        // And the sockets connected to the peers
+        assert!(created.load(Ordering::Relaxed) > 4);
+
+        // A device can't start if its sockets are refused
+        let config = DeviceConfig {
+            on_socket_created: Some(SocketHook::new(|_| {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            })),
+            ..Default::default()
+        };
+        assert!(TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config).is_err());
+    }
+
+    #[test]
+    fn test_refused_port_keeps_fwmark() {
+        let refused = Arc::new(AtomicUsize::new(0));
+        let refuse = Arc::clone(&refused);
+        let config = DevicePairConfig {
+            device_config: DeviceConfig {
+                on_socket_created: Some(SocketHook::new(move |_| {
+                    match refuse
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    {
+                        Ok(_) => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+                        Err(_) => Ok(()),
+                    }
+                })),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut pair = DevicePair::new(config).unwrap();
+
+        // The new port is refused, the mark it came with is taken back
+        refused.store(1, Ordering::Relaxed);
+        let config = WgConfig {
+            private_key: Some(pair.a.private_key.clone()),
+            listen_port: Some(0),
+            fwmark: Some(42),
+            peers: vec![{
+                let mut peer_b = PeerConfig::new(pair.b.public_key());
+                peer_b.endpoint = Some(pair.relay.addr_a.to_string());
+                peer_b.allowed_ips = vec![format!("{}/32", pair.b.ip).parse().unwrap()];
+                peer_b
+            }],
+            ..Default::default()
+        };
+        assert!(pair.a.handle.apply_config(config).is_err());
+        assert_eq!(refused.load(Ordering::Relaxed), 0);
+
+        let response = pair.a.get().unwrap();
+        assert!(!response.contains("fwmark="));
+        assert!(response.contains(&format!("listen_port={}\n", pair.a.listen_port)));
+        pair.b.send_to(&pair.a, b"old port");
+        assert!(pair.a.recv_timeout(TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn test_reload_keeps_config_on_failure() {
+        let path = std::env::temp_dir().join(format!("boringtun-reload-{}", std::process::id()));
+        let stages = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&stages);
+        let config = DevicePairConfig {
+            device_config: DeviceConfig {
+                config_file: Some(path.clone()),
+                on_reload: Some(ReloadHook::new(move |stage| seen.lock().push(stage))),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pair = DevicePair::new(config).unwrap();
+        pair.a.send_to(&pair.b, b"before");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        let handshakes = pair.packets_b_to_a();
+
+        let peer_b = format!(
+            "[Peer]\nPublicKey = {}\nAllowedIPs = {}/32\nEndpoint = {}\n",
+            base64::encode(pair.b.public_key().as_bytes()),
+            pair.b.ip,
+            pair.relay.addr_a
+        );
+        let new_peer = |endpoint: &str| {
+            let key = x25519::StaticSecret::random_from_rng(OsRng);
+            format!(
+                "[Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.3/32\nEndpoint = {}\n",
+                base64::encode(x25519::PublicKey::from(&key).as_bytes()),
+                endpoint
+            )
+        };
+        std::fs::write(
+            &path,
+            format!("[Interface]\n\n{}\n{}", peer_b, new_peer("127.0.0.1:9")),
+        )
+        .unwrap();
+        pair.a.handle.trigger_reload().unwrap();
+
+        // A new private key, without b, and a peer whose endpoint doesn't parse
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let file = format!(
+            "[Interface]\nPrivateKey = {}\n\n{}",
+            base64::encode(private_key.to_bytes()),
+            new_peer("10.0.0.9")
+        );
+        std::fs::write(&path, file).unwrap();
+        assert!(pair.a.handle.trigger_reload().is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let stages = stages.lock().clone();
+        assert_eq!(stages.len(), 4);
+        let summary = ReloadSummary {
+            added: 1,
+            removed: 0,
+            modified: 0,
+        };
+        assert_eq!(stages[1], ReloadStage::Applied(summary));
+        assert!(matches!(&stages[3], ReloadStage::Failed(e) if e.contains("10.0.0.9")));
+
+        // Nothing changed, b kept its session
+        assert_eq!(pair.a.handle.local_public_key(), Some(pair.a.public_key()));
+        assert_eq!(pair.a.handle.stats().peers, 2);
+        pair.a.send_to(&pair.b, b"after");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        assert_eq!(pair.packets_b_to_a(), handshakes);
+    }
+
+    #[test]
+    fn test_uapi_listener() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("boringtun-uapi-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (tun, _tun) = ChannelTun::new("test", MTU).unwrap();
+        let config = DeviceConfig {
+            uapi_listen_fd: Some(listener.into_raw_fd()),
+            ..Default::default()
+        };
+        let device = DeviceHandle::with_tun(Arc::new(tun), config).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"get=1\n\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("listen_port="), "{}", response);
+        assert!(response.ends_with("errno=0\n\n"), "{}", response);
+
+        // The socket belongs to whoever passed it
+        drop(device);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        // A connected socket can't serve the UAPI
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let (tun, _tun) = ChannelTun::new("test", MTU).unwrap();
+        let config = DeviceConfig {
+            uapi_listen_fd: Some(stream.as_raw_fd()),
+            ..Default::default()
+        };
+        let e = DeviceHandle::with_tun(Arc::new(tun), config).err().unwrap();
+        assert!(e.to_string().contains("doesn't listen"), "{}", e);
+    }
+
+    #[test]
+    fn test_reload_hook() {
+        let stages = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&stages);
+        let config = DevicePairConfig {
+            device_config: DeviceConfig {
+                on_reload: Some(ReloadHook::new(move |stage| seen.lock().push(stage))),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut pair = DevicePair::new(config).unwrap();
+        assert_eq!(pair.a.handle.stats().connected_peers, 0);
+        pair.a.send_to(&pair.b, b"ping");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        assert_eq!(pair.a.handle.stats().connected_peers, 1);
+
+        // The hook runs even when the reload fails, here for the lack of a file
+        assert!(pair.a.handle.trigger_reload().is_err());
+        assert_eq!(
+            *stages.lock(),
+            [
+                ReloadStage::Started,
+                ReloadStage::Failed("invalid configuration: No configuration file".to_owned())
+            ]
+        );
+
+        // The event loops are responsive until the device leaves
+        pair.a.handle.sync_event_loops();
+        assert!(!pair.a.handle.wait_timeout(Duration::from_millis(50)));
+        pair.a.handle.initiate_shutdown();
+        assert!(pair.a.handle.wait_timeout(TIMEOUT));
+    }
+
+    #[test]
+    fn test_stats_report() {
+        let mut pair = DevicePair::new(DevicePairConfig::default()).unwrap();
+        pair.a.send_to(&pair.b, b"ping");
+        assert!(pair.b.recv_timeout(TIMEOUT).is_some());
+        let idle = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let addr = pair.address_of_a();
+        pair.a
+            .add_peer(&idle, Ipv4Addr::new(10, 0, 0, 3), addr, None)
+            .unwrap();
+
+        let lines = pair.a.handle.device.read().stats_report(1);
+        assert_eq!(lines.len(), 5, "{:?}", lines);
+        assert!(lines[0].starts_with("device: 2 peers, 1 connected, 2 allowed IPs"));
+        // Only the busiest peer is listed
+        let b = &base64::encode(pair.b.public_key().as_bytes())[..16];
+        assert!(lines[3].starts_with(&format!("peer {}...: endpoint 127.0.0.1:", b)));
+        assert!(lines[3].contains("s ago"), "{}", lines[3]);
+        assert_eq!(lines[4], "1 more peers: 0 with a handshake, rx 0 B, tx 0 B");
+
+        // The event loops that left are not counted
+        pair.a.handle.initiate_shutdown();
+        pair.a.handle.wait();
+        let lines = pair.a.handle.device.read().stats_report(1);
+        assert!(
+            lines[1].starts_with("threads: 0/4 event loops"),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[cfg(feature = "http-health")]
+    #[test]
+    fn test_health_check() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+
+        fn status(addr: SocketAddr) -> String {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            write!(conn, "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response.lines().next().unwrap().to_owned()
+        }
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Not a `DevicePair`, only one of the devices can serve the address
+        let config = DeviceConfig {
+            health_check_addr: Some(addr),
+            ..Default::default()
+        };
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), Default::default()).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), config).unwrap();
+        assert_eq!(status(addr), "HTTP/1.1 503 Service Unavailable");
+
+        let (key_a, key_b) = (a.public_key(), b.public_key());
+        let (ip_a, ip_b) = (a.ip, b.ip);
+        a.add_peer(
+            &key_b,
+            ip_b,
+            (Ipv4Addr::LOCALHOST, b.listen_port).into(),
+            None,
+        )
+        .unwrap();
+        b.add_peer(
+            &key_a,
+            ip_a,
+            (Ipv4Addr::LOCALHOST, a.listen_port).into(),
+            None,
+        )
+        .unwrap();
+        a.send_to(&b, b"ping");
+        assert!(b.recv_timeout(TIMEOUT).is_some());
+        assert_eq!(status(addr), "HTTP/1.1 200 OK");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+
+        fn scrape(addr: SocketAddr, path: &str) -> String {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            write!(conn, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response
+        }
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Not a `DevicePair`, only one of the devices can serve the address
+        let config = DeviceConfig {
+            metrics_listen: Some(addr),
+            ..Default::default()
+        };
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), Default::default()).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), config).unwrap();
+        let (key_a, key_b) = (a.public_key(), b.public_key());
+        a.add_peer(
+            &key_b,
+            b.ip,
+            (Ipv4Addr::LOCALHOST, b.listen_port).into(),
+            None,
+        )
+        .unwrap();
+        b.add_peer(
+            &key_a,
+            a.ip,
+            (Ipv4Addr::LOCALHOST, a.listen_port).into(),
+            None,
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            a.send_to(&b, b"ping");
+            assert!(b.recv_timeout(TIMEOUT).is_some());
+        }
+        b.send_to(&a, b"pong");
+        assert!(a.recv_timeout(TIMEOUT).is_some());
+        // No peer is allowed this destination
+        b.inject(ipv4_packet(b.ip, Ipv4Addr::new(10, 0, 0, 9), b"lost"));
+
+        let fingerprint = &base64::encode(key_a.as_bytes())[..16];
+        let peer = format!("interface=\"test\",peer=\"{}...\"", fingerprint);
+        let deadline = Instant::now() + TIMEOUT;
+        let metrics = loop {
+            let metrics = scrape(addr, "/metrics");
+            if metrics.contains("reason=\"no_route\"} 1") || Instant::now() > deadline {
+                break metrics;
+            }
+        };
+        assert!(metrics.starts_with("HTTP/1.1 200 OK"), "{}", metrics);
+        assert!(metrics.contains("boringtun_peers{interface=\"test\"} 1\n"));
+        assert!(metrics.contains("reason=\"no_route\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains(&format!(
+            "boringtun_handshakes_completed_total{{{}}} 1\n",
+            peer
+        )));
+        assert!(metrics.contains(&format!("boringtun_rx_packets_total{{{}}} 3\n", peer)));
+        assert!(metrics.contains(&format!("boringtun_tx_packets_total{{{}}} 1\n", peer)));
+        assert!(metrics.contains("# TYPE boringtun_loop_iterations_total counter\n"));
+
+        assert!(scrape(addr, "/").starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[cfg(feature = "mdns")]
+    #[test]
+    fn test_mdns_discovery() {
+        let config = || DeviceConfig {
+            mdns: true,
+            ..Default::default()
+        };
+        let mut a = TestDevice::new(Ipv4Addr::new(10, 0, 0, 1), config()).unwrap();
+        let mut b = TestDevice::new(Ipv4Addr::new(10, 0, 0, 2), config()).unwrap();
+
+        // Neither knows where the other is
+        let (key_a, key_b) = (a.public_key(), b.public_key());
+        let peer = |key: &x25519::PublicKey, ip| {
+            format!(
+                "public_key={}\nallowed_ip={}/32\nbt_mdns_discovery=true",
+                encode_hex(key.as_bytes()),
+                ip
+            )
+        };
+        a.set(&peer(&key_b, b.ip)).unwrap();
+        b.set(&peer(&key_a, a.ip)).unwrap();
+        assert!(a.get().unwrap().contains("bt_mdns_discovery=true\n"));
+
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let endpoint = loop {
+            match endpoint_of_peer(&mut a) {
+                Some(endpoint) => break endpoint,
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+                None => panic!("No endpoint discovered"),
+            }
+        };
+        assert_eq!(endpoint.port(), b.listen_port);
+
+        a.send_to(&b, b"ping");
+        let packet = b.recv_timeout(TIMEOUT).expect("No packet");
+        assert_eq!(packet, ipv4_packet(a.ip, b.ip, b"ping"));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The deadlines of the timers of the peers. The device arms a single timer event to the earliest
+//! one, and only visits the peers whose deadline arrived, so idle peers cause no wakeups.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Instant;
+
+/// The peers, by their index, in the order of their deadlines
+#[derive(Debug, Default)]
+pub(crate) struct TimerQueue {
+    heap: BinaryHeap<Reverse<(Instant, u32)>>,
+    /// The deadline of each peer in the heap, the other entries of a peer are stale
+    deadlines: HashMap<u32, Instant>,
+    /// The deadline the timer is armed to, if any
+    armed: Option<Instant>,
+}
+
+impl TimerQueue {
+    /// Schedule the peer with `index` for `at`, unless it is already due earlier. Returns `at` if
+    /// the timer must be armed to it.
+    pub(crate) fn schedule(&mut self, index: u32, at: Instant) -> Option<Instant> {
+        if self.deadlines.get(&index).is_some_and(|&d| d <= at) {
+            return None;
+        }
+        self.deadlines.insert(index, at);
+        self.heap.push(Reverse((at, index)));
+
+        if self.armed.is_some_and(|armed| armed <= at) {
+            return None;
+        }
+        self.armed = Some(at);
+        Some(at)
+    }
+
+    /// Remove the peers whose deadline arrived by `now`, they must be scheduled again
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Vec<u32> {
+        let mut due = vec![];
+        while let Some(&Reverse((at, index))) = self.heap.peek() {
+            if at > now {
+                break;
+            }
+            self.heap.pop();
+            if self.deadlines.get(&index) == Some(&at) {
+                self.deadlines.remove(&index);
+                due.push(index);
+            }
+        }
+        due
+    }
+
+    /// The earliest deadline, which the timer must be armed to once it fired, `None` disarms it
+    pub(crate) fn rearm(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((at, index))) = self.heap.peek() {
+            if self.deadlines.get(&index) == Some(&at) {
+                break;
+            }
+            self.heap.pop();
+        }
+        self.armed = self.heap.peek().map(|&Reverse((at, _))| at);
+        self.armed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timer_queue() {
+        let now = Instant::now();
+        let secs = |s| now + Duration::from_secs(s);
+        let mut queue = TimerQueue::default();
+
+        assert_eq!(queue.schedule(1, secs(10)), Some(secs(10)));
+        assert_eq!(queue.schedule(2, secs(20)), None);
+        // A later deadline is ignored, an earlier one replaces it
+        assert_eq!(queue.schedule(1, secs(30)), None);
+        assert_eq!(queue.schedule(2, secs(5)), Some(secs(5)));
+
+        assert_eq!(queue.pop_due(secs(1)), Vec::<u32>::new());
+        assert_eq!(queue.pop_due(secs(20)), vec![2, 1]);
+        assert_eq!(queue.rearm(), None);
+
+        queue.schedule(1, secs(40));
+        queue.schedule(3, secs(50));
+        queue.schedule(3, secs(45));
+        assert_eq!(queue.rearm(), Some(secs(40)));
+        assert_eq!(queue.pop_due(secs(40)), vec![1]);
+        assert_eq!(queue.rearm(), Some(secs(45)));
+        assert_eq!(queue.pop_due(secs(100)), vec![3]);
+        assert_eq!(queue.rearm(), None);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! How the WireGuard packets are carried between the peers: over UDP, as WireGuard intends, or
+//! over TCP for networks that block UDP, see [`Transport::Tcp`].
+//!
+//! The datagrams are sent through an [`OuterTransport`], a UDP socket by default. Other
+//! transports, such as [`Socks5`], are set with [`super::DeviceConfig::outer_transport`]. Their
+//! datagrams may be disguised, see [`Obfuscation`].
+
+pub(crate) mod obfuscation;
+pub(crate) mod socks5;
+pub(crate) mod tcp;
+pub(crate) mod udp;
+
+pub use obfuscation::Obfuscation;
+pub use socks5::{Socks5, Socks5Credentials};
+
+#[cfg(windows)]
+use super::poll::RawFd;
+use super::{Error, SocketHook};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Carries the encrypted datagrams of a device to and from the endpoints of its peers. The
+/// endpoints are identified by a `SocketAddr`, whatever the transport makes of it.
+pub trait OuterTransport: Send + Sync {
+    /// Send `packet` to `addr`, returns the number of bytes of `packet` that were sent
+    fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Like `send_to`, for `packet` to leave once `delay` elapsed, see [`super::shaper`].
+    /// Transports that can't delay the packets send them right away.
+    fn send_to_delayed(
+        &self,
+        packet: &[u8],
+        addr: SocketAddr,
+        _delay: Duration,
+    ) -> io::Result<usize> {
+        self.send_to(packet, addr)
+    }
+
+    /// Receive a datagram into `buf`, returns its length and the endpoint it came from. Fails with
+    /// `WouldBlock` once no datagram is waiting. A peer is reached at the address it last sent a
+    /// valid packet from, so this is where roaming is noticed.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// A descriptor that becomes readable when a datagram is waiting, polled by the event loop
+    fn as_raw_fd(&self) -> RawFd;
+
+    /// The address the transport receives on, its port is the listen port of the device
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Set the mark of the packets sent, a mark of zero removes it
+    fn set_fwmark(&self, _mark: u32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Open a handle that only exchanges datagrams with the endpoint `addr`, so they reach the
+    /// peer without looking it up. Returns `None` if the transport has no such handle, the
+    /// datagrams of the peer then go through `send_to` and `recv_from`.
+    fn connect(
+        &self,
+        _addr: SocketAddr,
+        _fwmark: Option<u32>,
+        _hook: Option<&SocketHook>,
+    ) -> Result<Option<Arc<dyn ConnectedTransport>>, Error> {
+        Ok(None)
+    }
+}
+
+/// The handle of an [`OuterTransport`] to a single endpoint, see [`OuterTransport::connect`]
+pub trait ConnectedTransport: fmt::Debug + Send + Sync {
+    fn send(&self, packet: &[u8]) -> io::Result<usize>;
+
+    /// Like `send`, for `packet` to leave once `delay` elapsed, see
+    /// [`OuterTransport::send_to_delayed`]
+    fn send_delayed(&self, packet: &[u8], _delay: Duration) -> io::Result<usize> {
+        self.send(packet)
+    }
+
+    /// Receive a datagram from the endpoint into `buf`. Fails with `WouldBlock` once no datagram
+    /// is waiting, or with the error the endpoint reported, such as `ConnectionRefused`.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// A descriptor that becomes readable when a datagram is waiting, polled by the event loop
+    fn as_raw_fd(&self) -> RawFd;
+
+    /// The path MTU to the endpoint, if the transport knows it
+    fn path_mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Stop exchanging datagrams, the descriptor is removed from the event loop once it reports
+    /// the shutdown
+    fn shutdown(&self);
+}
+
+/// Opens the outer transports of a device, when it starts and every time its listen port
+/// changes, see [`super::DeviceConfig::outer_transport`]
+pub trait OuterTransportFactory: fmt::Debug + Send + Sync {
+    /// Open the transports to the IPv4 and to the IPv6 endpoints, receiving on `port`, or on a
+    /// port of their choice when it is 0. Both may be the same transport. `hook` is called on
+    /// the sockets they create, see [`super::DeviceConfig::on_socket_created`].
+    fn bind(&self, port: u16, hook: Option<&SocketHook>) -> Result<OuterTransports, Error>;
+}
+
+/// The transports to the IPv4 and to the IPv6 endpoints
+pub struct OuterTransports {
+    pub v4: Arc<dyn OuterTransport>,
+    pub v6: Arc<dyn OuterTransport>,
+    /// The transports the flows of the peers are spread over, with their local address, see
+    /// [`super::DeviceConfig::bind_addrs`]. `v4` and `v6` are among them. Empty when the
+    /// datagrams all go through `v4` or `v6`.
+    pub paths: Vec<(SocketAddr, Arc<dyn OuterTransport>)>,
+}
+
+impl OuterTransports {
+    /// The transport to use for `addr`
+    pub(crate) fn for_addr(&self, addr: &SocketAddr) -> &dyn OuterTransport {
+        match addr {
+            SocketAddr::V4(_) => &*self.v4,
+            SocketAddr::V6(_) => &*self.v6,
+        }
+    }
+
+    /// The transport to use for the flow of inner packets that hashes to `flow`, see
+    /// [`super::multipath`]. The same flow always takes the same path, so its packets are not
+    /// reordered.
+    pub(crate) fn for_flow(&self, addr: &SocketAddr, flow: u64) -> &dyn OuterTransport {
+        let paths = || {
+            self.paths
+                .iter()
+                .filter(|(local, _)| local.is_ipv4() == addr.is_ipv4())
+        };
+        match paths().count() as u64 {
+            0 | 1 => self.for_addr(addr),
+            count => &*paths().nth((flow % count) as usize).unwrap().1,
+        }
+    }
+
+    /// Whether the datagrams are spread over several transports
+    pub(crate) fn is_multipath(&self) -> bool {
+        self.paths.len() > 1
+    }
+
+    /// Each distinct transport once
+    pub(crate) fn distinct(&self) -> impl Iterator<Item = &Arc<dyn OuterTransport>> {
+        let v6 = Some(&self.v6).filter(|v6| !Arc::ptr_eq(&self.v4, v6));
+        let paths = self
+            .paths
+            .iter()
+            .map(|(_, path)| path)
+            .filter(move |path| !Arc::ptr_eq(&self.v4, path) && !Arc::ptr_eq(&self.v6, path));
+        std::iter::once(&self.v4).chain(v6).chain(paths)
+    }
+}
+
+/// The transport of the packets of the peers, see [`super::DeviceConfig::transport`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Packets are sent as UDP datagrams
+    #[default]
+    Udp,
+    /// Packets are sent over a persistent TCP connection to the endpoint of each peer, and
+    /// connections from peers are accepted on `addr`. Both peers must use TCP. The UDP socket
+    /// is still open, and packets received on it are handled as usual.
+    Tcp {
+        addr: SocketAddr,
+        framing: TcpFraming,
+    },
+}
+
+/// How packets are delimited on a TCP connection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TcpFraming {
+    /// Each packet is prefixed with its length, on 2 bytes in big-endian order
+    #[default]
+    LengthPrefixed,
+}
@@ -0,0 +1,472 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Obfuscation of the datagrams in the manner of AmneziaWG, for networks that block WireGuard by
+//! recognizing its message types and handshake sizes.
+//!
+//! The handshake initiations are preceded by junk datagrams and the handshake messages are
+//! prefixed with random bytes, so their sizes vary. The message types are replaced by other
+//! values. The messages themselves are left alone, so this only wraps an [`OuterTransport`] and
+//! the noise protocol doesn't see it. Both peers must use the same parameters.
+
+use super::{ConnectedTransport, OuterTransport, OuterTransports};
+#[cfg(windows)]
+use crate::device::poll::RawFd;
+use crate::device::{Error, SocketHook};
+use rand_core::{OsRng, RngCore};
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+const HANDSHAKE_INIT: u32 = 1;
+const HANDSHAKE_RESP: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const DATA: u32 = 4;
+
+const HANDSHAKE_INIT_SZ: usize = 148;
+const HANDSHAKE_RESP_SZ: usize = 92;
+const COOKIE_REPLY_SZ: usize = 64;
+const DATA_OVERHEAD_SZ: usize = 32;
+
+/// The largest junk datagram, and the largest handshake once prefixed
+const MAX_JUNK_SZ: usize = 1280;
+const MAX_JUNK_COUNT: u16 = 128;
+
+thread_local! {
+    /// Where the datagrams are obfuscated before they are sent
+    #[allow(clippy::missing_const_for_thread_local)] // Some toolchains miss the `const` block
+    static OBFUSCATED: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The parameters of the obfuscation, named after their AmneziaWG counterparts. The default
+/// leaves the datagrams as standard WireGuard ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Obfuscation {
+    /// `Jc`, the number of junk datagrams sent before each handshake initiation
+    pub junk_packet_count: u16,
+    /// `Jmin`, the smallest size of a junk datagram
+    pub junk_packet_min_size: u16,
+    /// `Jmax`, the largest size of a junk datagram
+    pub junk_packet_max_size: u16,
+    /// `S1`, the number of random bytes before a handshake initiation
+    pub init_packet_junk_size: u16,
+    /// `S2`, the number of random bytes before a handshake response
+    pub response_packet_junk_size: u16,
+    /// `H1` to `H4`, the message types of the handshake initiations, the handshake responses,
+    /// the cookie replies and the data packets
+    pub message_types: [u32; 4],
+}
+
+impl Default for Obfuscation {
+    fn default() -> Obfuscation {
+        Obfuscation {
+            junk_packet_count: 0,
+            junk_packet_min_size: 0,
+            junk_packet_max_size: 0,
+            init_packet_junk_size: 0,
+            response_packet_junk_size: 0,
+            message_types: [HANDSHAKE_INIT, HANDSHAKE_RESP, COOKIE_REPLY, DATA],
+        }
+    }
+}
+
+impl Obfuscation {
+    /// Whether the datagrams are left as they are
+    pub fn is_standard(&self) -> bool {
+        *self == Obfuscation::default()
+    }
+
+    /// Check the parameters have the limits of AmneziaWG, and that the messages can still be told
+    /// apart
+    pub fn validate(&self) -> Result<(), Error> {
+        let err = |e: &str| Err(Error::InvalidConfig(format!("Obfuscation: {}", e)));
+        let [h1, h2, h3, h4] = self.message_types;
+        let (s1, s2) = (
+            usize::from(self.init_packet_junk_size),
+            usize::from(self.response_packet_junk_size),
+        );
+
+        if self.junk_packet_count > MAX_JUNK_COUNT {
+            return err("Jc must be at most 128");
+        }
+        if self.junk_packet_min_size > self.junk_packet_max_size {
+            return err("Jmin must not exceed Jmax");
+        }
+        if usize::from(self.junk_packet_max_size) > MAX_JUNK_SZ {
+            return err("Jmax must be at most 1280");
+        }
+        if s1 + HANDSHAKE_INIT_SZ > MAX_JUNK_SZ || s2 + HANDSHAKE_RESP_SZ > MAX_JUNK_SZ {
+            return err("S1 must be at most 1132 and S2 at most 1188");
+        }
+        if s1 + HANDSHAKE_INIT_SZ == s2 + HANDSHAKE_RESP_SZ {
+            return err("S1 + 56 must not equal S2");
+        }
+        if h1 == h2 || h1 == h3 || h1 == h4 || h2 == h3 || h2 == h4 || h3 == h4 {
+            return err("H1, H2, H3 and H4 must be distinct");
+        }
+        Ok(())
+    }
+
+    /// Write the obfuscated datagram of `packet` to `out`, returns whether it is a handshake
+    /// initiation, which the junk datagrams precede
+    fn obfuscate(&self, packet: &[u8], out: &mut Vec<u8>) -> bool {
+        let [h1, h2, h3, h4] = self.message_types;
+        let msg_type = message_type(packet, 0);
+        let (junk_size, obfuscated_type) = match (msg_type, packet.len()) {
+            (Some(HANDSHAKE_INIT), HANDSHAKE_INIT_SZ) => (self.init_packet_junk_size, h1),
+            (Some(HANDSHAKE_RESP), HANDSHAKE_RESP_SZ) => (self.response_packet_junk_size, h2),
+            (Some(COOKIE_REPLY), COOKIE_REPLY_SZ) => (0, h3),
+            (Some(DATA), len) if len >= DATA_OVERHEAD_SZ => (0, h4),
+            _ => {
+                out.clear();
+                out.extend_from_slice(packet);
+                return false;
+            }
+        };
+
+        let junk_size = usize::from(junk_size);
+        out.clear();
+        out.resize(junk_size, 0);
+        OsRng.fill_bytes(out);
+        out.extend_from_slice(packet);
+        out[junk_size..junk_size + 4].copy_from_slice(&obfuscated_type.to_le_bytes());
+        obfuscated_type == h1
+    }
+
+    /// Restore the first `len` bytes of `buf` to the standard message they carry, at the start of
+    /// `buf`. Returns its length, or `None` if they don't carry one, such as junk datagrams.
+    fn deobfuscate(&self, buf: &mut [u8], len: usize) -> Option<usize> {
+        let [h1, h2, h3, h4] = self.message_types;
+        let s1 = usize::from(self.init_packet_junk_size);
+        let s2 = usize::from(self.response_packet_junk_size);
+        let datagram = &buf[..len];
+
+        let (offset, msg_type) =
+            if len == s1 + HANDSHAKE_INIT_SZ && message_type(datagram, s1) == Some(h1) {
+                (s1, HANDSHAKE_INIT)
+            } else if len == s2 + HANDSHAKE_RESP_SZ && message_type(datagram, s2) == Some(h2) {
+                (s2, HANDSHAKE_RESP)
+            } else if len == COOKIE_REPLY_SZ && message_type(datagram, 0) == Some(h3) {
+                (0, COOKIE_REPLY)
+            } else if len >= DATA_OVERHEAD_SZ && message_type(datagram, 0) == Some(h4) {
+                (0, DATA)
+            } else {
+                return None;
+            };
+
+        buf.copy_within(offset..len, 0);
+        buf[..4].copy_from_slice(&msg_type.to_le_bytes());
+        Some(len - offset)
+    }
+
+    /// The junk datagrams to send before a handshake initiation
+    fn junk(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.junk_packet_count).map(move |_| {
+            let (min, max) = (
+                u32::from(self.junk_packet_min_size),
+                u32::from(self.junk_packet_max_size),
+            );
+            let size = min + OsRng.next_u32() % (max - min + 1);
+            let mut junk = vec![0; size as usize];
+            OsRng.fill_bytes(&mut junk);
+            junk
+        })
+    }
+
+    /// Obfuscate the datagrams of `outer`
+    pub(crate) fn wrap(&self, outer: OuterTransports) -> OuterTransports {
+        let wrap = |inner: &Arc<dyn OuterTransport>| -> Arc<dyn OuterTransport> {
+            Arc::new(Obfuscated {
+                inner: Arc::clone(inner),
+                obfuscation: *self,
+            })
+        };
+        let v4 = wrap(&outer.v4);
+        let v6 = match Arc::ptr_eq(&outer.v4, &outer.v6) {
+            true => Arc::clone(&v4),
+            false => wrap(&outer.v6),
+        };
+        // The paths stay the same transports as `v4` and `v6`
+        let paths = outer
+            .paths
+            .iter()
+            .map(|(addr, path)| {
+                let path = match (Arc::ptr_eq(&outer.v4, path), Arc::ptr_eq(&outer.v6, path)) {
+                    (true, _) => Arc::clone(&v4),
+                    (_, true) => Arc::clone(&v6),
+                    _ => wrap(path),
+                };
+                (*addr, path)
+            })
+            .collect();
+        OuterTransports { v4, v6, paths }
+    }
+}
+
+/// The type of the message at `offset`, on 4 bytes in little-endian order
+fn message_type(datagram: &[u8], offset: usize) -> Option<u32> {
+    let bytes = datagram.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Obfuscate `packet` and pass the datagram to `send`, after the junk datagrams if it is a
+/// handshake initiation
+fn send_obfuscated(
+    obfuscation: &Obfuscation,
+    packet: &[u8],
+    send: impl Fn(&[u8]) -> io::Result<usize>,
+) -> io::Result<usize> {
+    OBFUSCATED.with(|out| {
+        let mut out = out.borrow_mut();
+        if obfuscation.obfuscate(packet, &mut out) {
+            for junk in obfuscation.junk() {
+                let _: Result<_, _> = send(&junk);
+            }
+        }
+        send(&out).map(|_| packet.len())
+    })
+}
+
+/// An [`OuterTransport`] whose datagrams are obfuscated
+struct Obfuscated {
+    inner: Arc<dyn OuterTransport>,
+    obfuscation: Obfuscation,
+}
+
+impl OuterTransport for Obfuscated {
+    fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        send_obfuscated(&self.obfuscation, packet, |datagram| {
+            self.inner.send_to(datagram, addr)
+        })
+    }
+
+    fn send_to_delayed(
+        &self,
+        packet: &[u8],
+        addr: SocketAddr,
+        delay: Duration,
+    ) -> io::Result<usize> {
+        send_obfuscated(&self.obfuscation, packet, |datagram| {
+            self.inner.send_to_delayed(datagram, addr, delay)
+        })
+    }
+
+    /// Datagrams that carry no message are skipped
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, addr) = self.inner.recv_from(buf)?;
+            if let Some(len) = self.obfuscation.deobfuscate(buf, len) {
+                return Ok((len, addr));
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_fwmark(&self, mark: u32) -> io::Result<()> {
+        self.inner.set_fwmark(mark)
+    }
+
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        fwmark: Option<u32>,
+        hook: Option<&SocketHook>,
+    ) -> Result<Option<Arc<dyn ConnectedTransport>>, Error> {
+        let conn = self.inner.connect(addr, fwmark, hook)?;
+        Ok(conn.map(|inner| {
+            Arc::new(ObfuscatedConnection {
+                inner,
+                obfuscation: self.obfuscation,
+            }) as Arc<dyn ConnectedTransport>
+        }))
+    }
+}
+
+/// A [`ConnectedTransport`] whose datagrams are obfuscated
+struct ObfuscatedConnection {
+    inner: Arc<dyn ConnectedTransport>,
+    obfuscation: Obfuscation,
+}
+
+impl fmt::Debug for ObfuscatedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ObfuscatedConnection")
+            .field(&self.inner)
+            .finish()
+    }
+}
+
+impl ConnectedTransport for ObfuscatedConnection {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        send_obfuscated(&self.obfuscation, packet, |datagram| {
+            self.inner.send(datagram)
+        })
+    }
+
+    fn send_delayed(&self, packet: &[u8], delay: Duration) -> io::Result<usize> {
+        send_obfuscated(&self.obfuscation, packet, |datagram| {
+            self.inner.send_delayed(datagram, delay)
+        })
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.inner.recv(buf)?;
+            if let Some(len) = self.obfuscation.deobfuscate(buf, len) {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    fn path_mtu(&self) -> Option<usize> {
+        self.inner.path_mtu()
+    }
+
+    fn shutdown(&self) {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A typical set of AmneziaWG parameters
+    fn amnezia() -> Obfuscation {
+        Obfuscation {
+            junk_packet_count: 4,
+            junk_packet_min_size: 40,
+            junk_packet_max_size: 70,
+            init_packet_junk_size: 52,
+            response_packet_junk_size: 16,
+            message_types: [1_733_685_222, 1_204_728_848, 1_496_858_936, 1_419_062_430],
+        }
+    }
+
+    fn message(msg_type: u32, len: usize) -> Vec<u8> {
+        let mut packet: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        packet[..4].copy_from_slice(&msg_type.to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_obfuscated_messages() {
+        let obfuscation = amnezia();
+        obfuscation.validate().unwrap();
+        let [h1, h2, h3, h4] = obfuscation.message_types;
+
+        let cases = [
+            (message(HANDSHAKE_INIT, HANDSHAKE_INIT_SZ), 52, h1),
+            (message(HANDSHAKE_RESP, HANDSHAKE_RESP_SZ), 16, h2),
+            (message(COOKIE_REPLY, COOKIE_REPLY_SZ), 0, h3),
+            (message(DATA, DATA_OVERHEAD_SZ), 0, h4),
+            (message(DATA, 1000), 0, h4),
+        ];
+        for (packet, junk_size, msg_type) in cases {
+            let mut datagram = vec![];
+            let initiation = obfuscation.obfuscate(&packet, &mut datagram);
+            assert_eq!(initiation, msg_type == h1);
+
+            // The message follows the random bytes, with its type replaced
+            assert_eq!(datagram.len(), junk_size + packet.len());
+            assert_eq!(message_type(&datagram, junk_size), Some(msg_type));
+            assert_eq!(&datagram[junk_size + 4..], &packet[4..]);
+
+            let len = datagram.len();
+            datagram.resize(2048, 0);
+            assert_eq!(
+                obfuscation.deobfuscate(&mut datagram, len),
+                Some(packet.len())
+            );
+            assert_eq!(&datagram[..packet.len()], &packet[..]);
+        }
+    }
+
+    #[test]
+    fn test_junk_datagrams() {
+        let obfuscation = amnezia();
+        let junk: Vec<_> = obfuscation.junk().collect();
+        assert_eq!(junk.len(), 4);
+        for mut datagram in junk {
+            assert!((40..=70).contains(&datagram.len()));
+            let len = datagram.len();
+            assert_eq!(obfuscation.deobfuscate(&mut datagram, len), None);
+        }
+
+        // Standard messages are not recognized either
+        let mut init = message(HANDSHAKE_INIT, HANDSHAKE_INIT_SZ);
+        assert_eq!(obfuscation.deobfuscate(&mut init, HANDSHAKE_INIT_SZ), None);
+        let mut data = message(DATA, 100);
+        assert_eq!(obfuscation.deobfuscate(&mut data, 100), None);
+    }
+
+    #[test]
+    fn test_standard_obfuscation() {
+        let obfuscation = Obfuscation::default();
+        assert!(obfuscation.is_standard());
+        obfuscation.validate().unwrap();
+        assert_eq!(obfuscation.junk().count(), 0);
+
+        for packet in [
+            message(HANDSHAKE_INIT, HANDSHAKE_INIT_SZ),
+            message(HANDSHAKE_RESP, HANDSHAKE_RESP_SZ),
+            message(DATA, 100),
+        ] {
+            let mut datagram = vec![];
+            obfuscation.obfuscate(&packet, &mut datagram);
+            assert_eq!(datagram, packet);
+        }
+    }
+
+    #[test]
+    fn test_validate_obfuscation() {
+        let invalid = [
+            Obfuscation {
+                junk_packet_min_size: 80,
+                ..amnezia()
+            },
+            Obfuscation {
+                junk_packet_max_size: 1281,
+                ..amnezia()
+            },
+            Obfuscation {
+                junk_packet_count: 129,
+                ..amnezia()
+            },
+            Obfuscation {
+                init_packet_junk_size: 1133,
+                ..amnezia()
+            },
+            // The initiations and the responses would have the same size
+            Obfuscation {
+                init_packet_junk_size: 0,
+                response_packet_junk_size: 56,
+                ..amnezia()
+            },
+            Obfuscation {
+                message_types: [5, 6, 7, 5],
+                ..amnezia()
+            },
+        ];
+        for obfuscation in invalid {
+            assert!(obfuscation.validate().is_err(), "{:?}", obfuscation);
+        }
+    }
+}
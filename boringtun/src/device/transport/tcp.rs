@@ -0,0 +1,431 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! TCP connections to the peers, see [`super::Transport::Tcp`].
+//!
+//! A [`TcpConnection`] carries framed packets with the same `send_packet`/`recv_packet` interface
+//! as the UDP path: sending never blocks, and receiving yields whole packets or `WouldBlock`.
+//! A [`TcpLink`] keeps the connection to a peer up. It connects on a separate thread whenever a
+//! packet must be sent and there is no connection, with an exponential backoff between attempts
+//! that fail.
+
+use super::TcpFraming;
+use parking_lot::Mutex;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The delay before connecting again after the first failure
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// The longest delay between two attempts when connecting keeps failing
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// How many bytes may wait to be written to a connection, packets beyond that are dropped
+const MAX_PENDING: usize = 256 * 1024;
+/// The size of the length prefix of [`TcpFraming::LengthPrefixed`]
+const LENGTH_SIZE: usize = 2;
+
+/// A TCP connection to a peer, established or being established
+pub(crate) struct TcpConnection {
+    framing: TcpFraming,
+    peer_addr: SocketAddr,
+    stream: OnceLock<TcpStream>,
+    /// Framed packets waiting for the connection to be established, or for room in the buffer
+    /// of the socket
+    pending: Mutex<Vec<u8>>,
+    /// The bytes received that don't make a whole packet yet
+    received: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+}
+
+impl TcpConnection {
+    /// A connection on an established `stream`, e.g. one that was accepted
+    pub(crate) fn new(stream: TcpStream, framing: TcpFraming) -> io::Result<TcpConnection> {
+        let conn = TcpConnection::connecting(stream.peer_addr()?, framing);
+        conn.attach(stream)?;
+        Ok(conn)
+    }
+
+    /// A connection to `peer_addr` that is being established, packets are queued until it is
+    /// attached to its stream
+    fn connecting(peer_addr: SocketAddr, framing: TcpFraming) -> TcpConnection {
+        TcpConnection {
+            framing,
+            peer_addr,
+            stream: OnceLock::new(),
+            pending: Default::default(),
+            received: Default::default(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn attach(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        let stream = self.stream.get_or_init(|| stream);
+        if self.is_closed() {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.flush(stream, &mut self.pending.lock())
+    }
+
+    /// The stream, once the connection is established
+    pub(crate) fn stream(&self) -> Option<&TcpStream> {
+        self.stream.get()
+    }
+
+    pub(crate) fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Send a packet, without blocking. It is queued if it can't be written right away, and
+    /// dropped with `WouldBlock` if too much is queued already.
+    pub(crate) fn send_packet(&self, packet: &[u8]) -> io::Result<usize> {
+        if self.is_closed() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let len = u16::try_from(packet.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        let mut pending = self.pending.lock();
+        if pending.len() + LENGTH_SIZE + packet.len() > MAX_PENDING {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.framing {
+            TcpFraming::LengthPrefixed => {
+                pending.extend_from_slice(&len.to_be_bytes());
+                pending.extend_from_slice(packet);
+            }
+        }
+
+        if let Some(stream) = self.stream.get() {
+            self.flush(stream, &mut pending)?;
+        }
+        Ok(packet.len())
+    }
+
+    /// Receive a whole packet into `dst`, `WouldBlock` if none arrived yet. The connection is
+    /// closed on any other error.
+    pub(crate) fn recv_packet(&self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self.stream.get().ok_or(io::ErrorKind::WouldBlock)?;
+        let mut received = self.received.lock();
+
+        loop {
+            if let Some(len) = self.next_packet_len(&received) {
+                let frame = match self.framing {
+                    TcpFraming::LengthPrefixed => LENGTH_SIZE..LENGTH_SIZE + len,
+                };
+                if len > dst.len() {
+                    self.close();
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                dst[..len].copy_from_slice(&received[frame.clone()]);
+                received.drain(..frame.end);
+                return Ok(len);
+            }
+
+            let mut chunk = [0u8; 4096];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.close();
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(n) => received.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        self.close();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// The length of the first packet in `received`, if it arrived whole
+    fn next_packet_len(&self, received: &[u8]) -> Option<usize> {
+        match self.framing {
+            TcpFraming::LengthPrefixed => {
+                let prefix = received.get(..LENGTH_SIZE)?;
+                let len = usize::from(u16::from_be_bytes([prefix[0], prefix[1]]));
+                (received.len() >= LENGTH_SIZE + len).then_some(len)
+            }
+        }
+    }
+
+    /// Write as much of `pending` as the socket takes
+    fn flush(&self, mut stream: &TcpStream, pending: &mut Vec<u8>) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == pending.len() {
+                break Ok(());
+            }
+            match stream.write(&pending[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        pending.drain(..written);
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    /// Shut the connection down, the event loop drops it once it sees it closed
+    pub(crate) fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            if let Some(stream) = self.stream.get() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// How a [`TcpLink`] connects, both methods are called on the connecting thread
+pub(crate) trait Connector: Send + Sync {
+    fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream>;
+
+    /// Register an established connection with the event loop
+    fn connected(&self, conn: Arc<TcpConnection>);
+}
+
+/// The connection to a peer, see the [module documentation](self)
+pub(crate) struct TcpLink {
+    framing: TcpFraming,
+    connector: Arc<dyn Connector>,
+    state: Arc<Mutex<LinkState>>,
+}
+
+#[derive(Default)]
+struct LinkState {
+    conn: Option<Arc<TcpConnection>>,
+    backoff: Duration,
+    /// When connecting may be attempted again, after a failure
+    next_attempt: Option<Instant>,
+}
+
+impl LinkState {
+    fn failed(&mut self, now: Instant) {
+        self.backoff = (self.backoff * 2).clamp(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX);
+        self.next_attempt = Some(now + self.backoff);
+    }
+}
+
+impl TcpLink {
+    pub(crate) fn new(framing: TcpFraming, connector: Arc<dyn Connector>) -> TcpLink {
+        TcpLink {
+            framing,
+            connector,
+            state: Default::default(),
+        }
+    }
+
+    /// Send a packet to the peer. If there is no connection, connecting to `endpoint` starts,
+    /// unless the backoff after the last failure didn't elapse, and the packet waits for it.
+    pub(crate) fn send_packet(
+        &self,
+        endpoint: Option<SocketAddr>,
+        packet: &[u8],
+    ) -> io::Result<usize> {
+        let conn = {
+            let mut state = self.state.lock();
+            match state.conn.as_ref().filter(|conn| !conn.is_closed()) {
+                Some(conn) => Arc::clone(conn),
+                None => {
+                    state.conn = None;
+                    let addr = endpoint.ok_or(io::ErrorKind::NotConnected)?;
+                    let now = Instant::now();
+                    if state.next_attempt.is_some_and(|at| now < at) {
+                        return Err(io::ErrorKind::NotConnected.into());
+                    }
+                    self.connect(addr, &mut state, now)
+                }
+            }
+        };
+        conn.send_packet(packet)
+    }
+
+    /// Reach the peer over `conn` from now on, e.g. the connection it made to us
+    pub(crate) fn adopt(&self, conn: &Arc<TcpConnection>) {
+        let mut state = self.state.lock();
+        if !state.conn.as_ref().is_some_and(|c| Arc::ptr_eq(c, conn)) {
+            state.conn = Some(Arc::clone(conn));
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr, state: &mut LinkState, now: Instant) -> Arc<TcpConnection> {
+        let conn = Arc::new(TcpConnection::connecting(addr, self.framing));
+        state.conn = Some(Arc::clone(&conn));
+
+        let thread_conn = Arc::clone(&conn);
+        let thread_state = Arc::clone(&self.state);
+        let connector = Arc::clone(&self.connector);
+        let spawned = thread::Builder::new()
+            .name("tcp-connect".to_owned())
+            .spawn(move || {
+                let connected = connector
+                    .connect(addr, CONNECT_TIMEOUT)
+                    .and_then(|stream| thread_conn.attach(stream));
+                match connected {
+                    Ok(()) => {
+                        tracing::info!(message = "Connected over TCP", endpoint = ?addr);
+                        let mut state = thread_state.lock();
+                        state.backoff = Duration::ZERO;
+                        state.next_attempt = None;
+                        drop(state);
+                        connector.connected(thread_conn);
+                    }
+                    Err(e) => {
+                        tracing::warn!(message = "Failed to connect over TCP", endpoint = ?addr, error = ?e);
+                        thread_conn.close();
+                        thread_state.lock().failed(Instant::now());
+                    }
+                }
+            });
+
+        if let Err(e) = spawned {
+            tracing::error!(message = "Failed to start connecting over TCP", error = ?e);
+            conn.close();
+            state.failed(now);
+        }
+        conn
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        if let Some(conn) = self.state.lock().conn.take() {
+            conn.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    struct TestConnector(Mutex<mpsc::Sender<Arc<TcpConnection>>>);
+
+    impl Connector for TestConnector {
+        fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+            TcpStream::connect_timeout(&addr, timeout)
+        }
+
+        fn connected(&self, conn: Arc<TcpConnection>) {
+            self.0.lock().send(conn).unwrap();
+        }
+    }
+
+    fn new_link() -> (TcpLink, mpsc::Receiver<Arc<TcpConnection>>) {
+        let (tx, rx) = mpsc::channel();
+        let connector = Arc::new(TestConnector(Mutex::new(tx)));
+        (TcpLink::new(TcpFraming::LengthPrefixed, connector), rx)
+    }
+
+    fn recv_timeout(conn: &TcpConnection, dst: &mut [u8]) -> io::Result<usize> {
+        for _ in 0..500 {
+            match conn.recv_packet(dst) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+        panic!("No packet");
+    }
+
+    #[test]
+    fn test_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let client = TcpConnection::new(client, TcpFraming::LengthPrefixed).unwrap();
+        let server = TcpConnection::new(server, TcpFraming::LengthPrefixed).unwrap();
+
+        let mut dst = [0u8; 2048];
+        assert_eq!(
+            server.recv_packet(&mut dst).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        for packet in [&b"first"[..], &[], &[7; 1500]] {
+            client.send_packet(packet).unwrap();
+        }
+        for packet in [&b"first"[..], &[], &[7; 1500]] {
+            let len = recv_timeout(&server, &mut dst).unwrap();
+            assert_eq!(&dst[..len], packet);
+        }
+
+        // A packet split across reads comes out whole
+        let mut raw = client.stream().unwrap();
+        raw.write_all(&[0, 4, b'a']).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            server.recv_packet(&mut dst).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        raw.write_all(b"bcd").unwrap();
+        let len = recv_timeout(&server, &mut dst).unwrap();
+        assert_eq!(&dst[..len], b"abcd");
+
+        client.close();
+        assert!(client.send_packet(b"late").is_err());
+        assert_eq!(
+            recv_timeout(&server, &mut dst).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(server.is_closed());
+    }
+
+    #[test]
+    fn test_link_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (link, rx) = new_link();
+
+        // The packet waits for the connection
+        link.send_packet(Some(addr), b"hello").unwrap();
+        let conn = rx.recv_timeout(CONNECT_TIMEOUT).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = TcpConnection::new(server, TcpFraming::LengthPrefixed).unwrap();
+        let mut dst = [0u8; 64];
+        let len = recv_timeout(&server, &mut dst).unwrap();
+        assert_eq!(&dst[..len], b"hello");
+
+        // Once the connection is lost, the next packet connects again
+        server.close();
+        assert!(recv_timeout(&conn, &mut dst).is_err());
+        link.send_packet(Some(addr), b"again").unwrap();
+        rx.recv_timeout(CONNECT_TIMEOUT).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = TcpConnection::new(server, TcpFraming::LengthPrefixed).unwrap();
+        let len = recv_timeout(&server, &mut dst).unwrap();
+        assert_eq!(&dst[..len], b"again");
+
+        // A failure delays the next attempt
+        drop(listener);
+        drop(server);
+        drop(link);
+        let (link, _rx) = new_link();
+        link.send_packet(Some(addr), b"refused").unwrap();
+        while link.state.lock().next_attempt.is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(link.send_packet(Some(addr), b"refused").is_err());
+        assert_eq!(link.state.lock().backoff, RECONNECT_BACKOFF_MIN);
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The default outer transport: a UDP socket for each address family, and a socket connected to
+//! the endpoint of each peer
+
+use super::{ConnectedTransport, OuterTransport, OuterTransportFactory, OuterTransports};
+#[cfg(windows)]
+use crate::device::poll::{AsRawFd, RawFd};
+#[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+use crate::device::txtime;
+use crate::device::{pmtu, Error, SocketHook};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+#[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+use std::time::Duration;
+
+/// Create a UDP socket and pass it to `hook`, the creation fails if the hook does
+pub(crate) fn new_udp_socket(domain: Domain, hook: Option<&SocketHook>) -> Result<Socket, Error> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+    txtime::enable(&socket)?;
+    if let Some(hook) = hook {
+        (hook.0)(socket.as_raw_fd()).map_err(Error::Socket)?;
+    }
+    Ok(socket)
+}
+
+/// The address to bind to for `port`, in the family of `addr`
+pub(crate) fn unspecified(addr: &SocketAddr, port: u16) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into(),
+        SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into(),
+    }
+}
+
+/// The sizes of the buffers of the sockets, left to the system when `None`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct BufferSizes {
+    /// See [`crate::device::DeviceConfig::udp_recv_buffer_size`]
+    pub(crate) recv: Option<usize>,
+    /// See [`crate::device::DeviceConfig::udp_send_buffer_size`]
+    pub(crate) send: Option<usize>,
+}
+
+impl BufferSizes {
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.recv {
+            #[cfg(target_os = "linux")]
+            let forced = force_buffer_size(socket, libc::SO_RCVBUFFORCE, size).is_ok();
+            #[cfg(not(target_os = "linux"))]
+            let forced = false;
+            if !forced {
+                socket.set_recv_buffer_size(size)?;
+            }
+        }
+        if let Some(size) = self.send {
+            #[cfg(target_os = "linux")]
+            let forced = force_buffer_size(socket, libc::SO_SNDBUFFORCE, size).is_ok();
+            #[cfg(not(target_os = "linux"))]
+            let forced = false;
+            if !forced {
+                socket.set_send_buffer_size(size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The sizes the kernel gave `socket`
+    fn of(socket: &Socket) -> io::Result<BufferSizes> {
+        Ok(BufferSizes {
+            recv: Some(socket.recv_buffer_size()?),
+            send: Some(socket.send_buffer_size()?),
+        })
+    }
+}
+
+/// Set a buffer of `socket` past the cap of `net.core.rmem_max` or `net.core.wmem_max`, with
+/// `SO_RCVBUFFORCE` or `SO_SNDBUFFORCE`. Fails without `CAP_NET_ADMIN`, as once the privileges
+/// were dropped.
+#[cfg(target_os = "linux")]
+fn force_buffer_size(socket: &Socket, option: libc::c_int, size: usize) -> io::Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    match unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &size as *const _ as _,
+            std::mem::size_of_val(&size) as _,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Binds an IPv4 and an IPv6 socket, or a socket to each of `bind_addrs`
+#[derive(Debug, Default)]
+pub(crate) struct UdpFactory {
+    /// Set `SO_REUSEPORT` on the sockets, see [`crate::device::DeviceConfig::reuse_port`]
+    pub(crate) reuse_port: bool,
+    /// See [`crate::device::DeviceConfig::bind_addrs`]
+    pub(crate) bind_addrs: Vec<SocketAddr>,
+    pub(crate) buffer_sizes: BufferSizes,
+    /// The sizes the kernel gave the IPv4 socket once bound, the configured ones or its defaults
+    pub(crate) effective: Mutex<BufferSizes>,
+}
+
+impl UdpFactory {
+    /// Let the sockets connected to the peers bind to the port of `socket`, and with
+    /// `reuse_port` the sockets of other processes
+    fn set_reuse(&self, socket: &Socket) -> io::Result<()> {
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        #[cfg(windows)]
+        if self.reuse_port {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        Ok(())
+    }
+
+    /// Set the buffers of a socket bound to the listen port, and log the sizes the kernel
+    /// picked, which may be capped or doubled for its own bookkeeping
+    fn set_buffer_sizes(&self, socket: &Socket) -> io::Result<()> {
+        let sizes = self.buffer_sizes;
+        sizes.apply(socket)?;
+        if let Some(size) = sizes.recv {
+            tracing::debug!(
+                message = "UDP receive buffer",
+                requested = size,
+                actual = socket.recv_buffer_size()?
+            );
+        }
+        if let Some(size) = sizes.send {
+            tracing::debug!(
+                message = "UDP send buffer",
+                requested = size,
+                actual = socket.send_buffer_size()?
+            );
+        }
+        Ok(())
+    }
+
+    /// Bind a socket to each of `bind_addrs`, on `port` unless the address has its own, or on a
+    /// random port shared by all when it is 0. The first socket of each family is the one of its
+    /// family, the IPv4 one stands in for a family without any.
+    fn bind_paths(
+        &self,
+        mut port: u16,
+        hook: Option<&SocketHook>,
+    ) -> Result<OuterTransports, Error> {
+        let mut paths = Vec::with_capacity(self.bind_addrs.len());
+        for addr in &self.bind_addrs {
+            let socket = new_udp_socket(Domain::for_address(*addr), hook)?;
+            self.set_reuse(&socket)?;
+            self.set_buffer_sizes(&socket)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            let mut addr = *addr;
+            if addr.port() == 0 {
+                addr.set_port(port);
+            }
+            socket.bind(&addr.into())?;
+            socket.set_nonblocking(true)?;
+            let addr = socket.local_addr()?.as_socket().unwrap();
+            if paths.is_empty() {
+                *self.effective.lock() = BufferSizes::of(&socket)?;
+            }
+            if port == 0 {
+                // Random port was assigned
+                port = addr.port();
+            }
+            let transport: Arc<dyn OuterTransport> = Arc::new(UdpTransport {
+                socket,
+                buffer_sizes: self.buffer_sizes,
+            });
+            paths.push((addr, transport));
+        }
+
+        let first = |ipv4: bool| {
+            paths
+                .iter()
+                .find(|(addr, _)| addr.is_ipv4() == ipv4)
+                .map(|(_, transport)| Arc::clone(transport))
+        };
+        let v4 = first(true).unwrap_or_else(|| Arc::clone(&paths[0].1));
+        let v6 = first(false).unwrap_or_else(|| Arc::clone(&v4));
+        Ok(OuterTransports { v4, v6, paths })
+    }
+}
+
+impl OuterTransportFactory for UdpFactory {
+    /// Bind an IPv4 and an IPv6 socket to `port`, or to a random port shared by both when it is 0
+    fn bind(&self, mut port: u16, hook: Option<&SocketHook>) -> Result<OuterTransports, Error> {
+        if !self.bind_addrs.is_empty() {
+            return self.bind_paths(port, hook);
+        }
+
+        let udp_sock4 = new_udp_socket(Domain::IPV4, hook)?;
+        self.set_reuse(&udp_sock4)?;
+        self.set_buffer_sizes(&udp_sock4)?;
+        udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+        udp_sock4.set_nonblocking(true)?;
+        *self.effective.lock() = BufferSizes::of(&udp_sock4)?;
+
+        if port == 0 {
+            // Random port was assigned
+            port = udp_sock4.local_addr()?.as_socket().unwrap().port();
+        }
+
+        let udp_sock6 = new_udp_socket(Domain::IPV6, hook)?;
+        self.set_reuse(&udp_sock6)?;
+        self.set_buffer_sizes(&udp_sock6)?;
+        udp_sock6.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
+        udp_sock6.set_nonblocking(true)?;
+
+        Ok(OuterTransports {
+            v4: Arc::new(UdpTransport {
+                socket: udp_sock4,
+                buffer_sizes: self.buffer_sizes,
+            }),
+            v6: Arc::new(UdpTransport {
+                socket: udp_sock6,
+                buffer_sizes: self.buffer_sizes,
+            }),
+            paths: vec![],
+        })
+    }
+}
+
+pub(crate) struct UdpTransport {
+    pub(crate) socket: Socket,
+    /// Given to the connected sockets as well
+    buffer_sizes: BufferSizes,
+}
+
+impl OuterTransport for UdpTransport {
+    fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(packet, &addr.into())
+    }
+
+    #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+    fn send_to_delayed(
+        &self,
+        packet: &[u8],
+        addr: SocketAddr,
+        delay: Duration,
+    ) -> io::Result<usize> {
+        match delay.is_zero() {
+            true => self.send_to(packet, addr),
+            false => txtime::send_delayed(&self.socket, packet, Some(&addr.into()), delay),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // Safety: the `recv_from` implementation promises not to write uninitialised bytes to the
+        // buffer, so this casting is safe.
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        let (len, addr) = self.socket.recv_from(buf)?;
+        let addr = addr.as_socket().ok_or(io::ErrorKind::InvalidData)?;
+        Ok((len, addr))
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::ErrorKind::InvalidData.into())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn set_fwmark(&self, mark: u32) -> io::Result<()> {
+        self.socket.set_mark(mark)
+    }
+
+    /// A socket bound to the listen port and connected to `addr`. It never fragments the packets
+    /// sent, see [`pmtu`].
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        #[allow(unused_variables)] fwmark: Option<u32>,
+        hook: Option<&SocketHook>,
+    ) -> Result<Option<Arc<dyn ConnectedTransport>>, Error> {
+        let port = self.local_addr()?.port();
+        let udp_conn = new_udp_socket(Domain::for_address(addr), hook)?;
+        udp_conn.set_reuse_address(true)?;
+        self.buffer_sizes.apply(&udp_conn)?;
+        udp_conn.bind(&unspecified(&addr, port).into())?;
+        udp_conn.connect(&addr.into())?;
+        udp_conn.set_nonblocking(true)?;
+        #[cfg(target_os = "linux")]
+        pmtu::set_dont_fragment(&udp_conn, addr.is_ipv4())?;
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(fwmark) = fwmark {
+            udp_conn.set_mark(fwmark)?;
+        }
+
+        Ok(Some(Arc::new(UdpConnection {
+            socket: udp_conn,
+            ipv4: addr.is_ipv4(),
+        })))
+    }
+}
+
+/// A UDP socket connected to the endpoint of a peer
+#[derive(Debug)]
+pub(crate) struct UdpConnection {
+    pub(crate) socket: Socket,
+    ipv4: bool,
+}
+
+impl ConnectedTransport for UdpConnection {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.socket.send(packet)
+    }
+
+    #[cfg(all(feature = "tx-pacing", target_os = "linux"))]
+    fn send_delayed(&self, packet: &[u8], delay: Duration) -> io::Result<usize> {
+        match delay.is_zero() {
+            true => self.send(packet),
+            false => txtime::send_delayed(&self.socket, packet, None, delay),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Safety: the `recv` implementation promises not to write uninitialised bytes to the
+        // buffer, so this casting is safe.
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        self.socket.recv(buf)
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn path_mtu(&self) -> Option<usize> {
+        pmtu::socket_path_mtu(&self.socket, self.ipv4)
+    }
+
+    fn shutdown(&self) {
+        let _: Result<_, _> = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Whether a socket with `SO_REUSEPORT` alone can bind to `port`, which other sockets hold
+    fn can_share(port: u16) -> bool {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket.set_reuse_port(true).unwrap();
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())
+            .is_ok()
+    }
+
+    #[test]
+    fn test_reuse_port() {
+        let factory = UdpFactory {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let shared = factory.bind(0, None).unwrap();
+        assert!(can_share(shared.v4.local_addr().unwrap().port()));
+
+        let exclusive = UdpFactory::default().bind(0, None).unwrap();
+        assert!(!can_share(exclusive.v4.local_addr().unwrap().port()));
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        let (recv, send) = (
+            socket.recv_buffer_size().unwrap(),
+            socket.send_buffer_size().unwrap(),
+        );
+        let factory = UdpFactory {
+            buffer_sizes: BufferSizes {
+                recv: Some(recv * 2),
+                send: Some(send * 2),
+            },
+            ..Default::default()
+        };
+        factory.set_buffer_sizes(&socket).unwrap();
+        assert!(socket.recv_buffer_size().unwrap() > recv);
+        assert!(socket.send_buffer_size().unwrap() > send);
+
+        // The sizes of the bound sockets are recorded, whether configured or not
+        factory.bind(0, None).unwrap();
+        let effective = *factory.effective.lock();
+        assert!(effective.recv.unwrap() > recv);
+        assert!(effective.send.unwrap() > send);
+
+        let factory = UdpFactory::default();
+        factory.bind(0, None).unwrap();
+        assert_eq!(factory.effective.lock().recv, Some(recv));
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use super::peer::AllowedIP;
 use super::Error;
 use libc::*;
 use std::io;
@@ -50,9 +51,16 @@ pub struct ifreq {
 const CTLIOCGINFO: u64 = 0x0000_0000_c064_4e03;
 const SIOCGIFMTU: u64 = 0x0000_0000_c020_6933;
 
+/// The MTU assumed for a descriptor that was passed in, its interface is never queried
+const EXTERNAL_MTU: usize = 1500;
+
 #[derive(Default, Debug)]
 pub struct TunSocket {
     fd: RawFd,
+    /// Whether packets start with the 4 byte address family, as on the utun devices of the kernel
+    af_prefix: bool,
+    /// The name given with a descriptor that was passed in, instead of the one of the interface
+    external_name: Option<String>,
 }
 
 impl Drop for TunSocket {
@@ -89,6 +97,13 @@ pub fn parse_utun_name(name: &str) -> Result<u32, Error> {
 
 impl TunSocket {
     fn write(&self, src: &[u8], af: u8) -> usize {
+        if !self.af_prefix {
+            return match unsafe { libc::write(self.fd, src.as_ptr() as _, src.len()) } {
+                -1 => 0,
+                n => n as usize,
+            };
+        }
+
         let mut hdr = [0u8, 0u8, 0u8, af];
         let mut iov = [
             iovec {
@@ -159,7 +174,27 @@ impl TunSocket {
             return Err(Error::Connect(err_string));
         }
 
-        Ok(TunSocket { fd })
+        Ok(TunSocket {
+            fd,
+            af_prefix: true,
+            external_name: None,
+        })
+    }
+
+    /// Use a descriptor that was created elsewhere, such as the utun of the packet flow of a
+    /// Network Extension, and take ownership of it. No ioctl is made on it, since they fail in
+    /// the sandbox: `name` is used as is, the MTU is assumed to be 1500 and nothing can be
+    /// configured. `af_prefix` tells whether its packets start with the address family word.
+    pub fn from_fd(fd: RawFd, name: &str, af_prefix: bool) -> Result<TunSocket, Error> {
+        if unsafe { fcntl(fd, F_GETFD) } == -1 {
+            return Err(Error::FCntl(io::Error::last_os_error()));
+        }
+
+        Ok(TunSocket {
+            fd,
+            af_prefix,
+            external_name: Some(name.to_owned()),
+        })
     }
 
     pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
@@ -173,6 +208,10 @@ impl TunSocket {
     }
 
     pub fn name(&self) -> Result<String, Error> {
+        if let Some(name) = &self.external_name {
+            return Ok(name.clone());
+        }
+
         let mut tunnel_name = [0u8; 256];
         let mut tunnel_name_len: socklen_t = tunnel_name.len() as u32;
         if unsafe {
@@ -194,6 +233,10 @@ impl TunSocket {
 
     /// Get the current MTU value
     pub fn mtu(&self) -> Result<usize, Error> {
+        if self.external_name.is_some() {
+            return Ok(EXTERNAL_MTU);
+        }
+
         let fd = match unsafe { socket(AF_INET, SOCK_STREAM, IPPROTO_IP) } {
             -1 => return Err(Error::Socket(io::Error::last_os_error())),
             fd => fd,
@@ -217,6 +260,18 @@ impl TunSocket {
         Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
     }
 
+    /// Configuring utun interfaces needs different ioctls, which are not implemented
+    pub fn configure(
+        &self,
+        _addresses: &[AllowedIP],
+        _mtu: Option<u32>,
+        _bring_up: bool,
+    ) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "setting the address, MTU or link state is not supported on this platform".to_owned(),
+        ))
+    }
+
     pub fn write4(&self, src: &[u8]) -> usize {
         self.write(src, AF_INET as u8)
     }
@@ -226,6 +281,13 @@ impl TunSocket {
     }
 
     pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        if !self.af_prefix {
+            return match unsafe { libc::read(self.fd, dst.as_mut_ptr() as _, dst.len()) } {
+                -1 => Err(Error::IfaceRead(io::Error::last_os_error())),
+                n => Ok(&mut dst[..n as usize]),
+            };
+        }
+
         let mut hdr = [0u8; 4];
 
         let mut iov = [
@@ -0,0 +1,278 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::peer::AllowedIP;
+use super::Error;
+use libc::*;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[repr(C)]
+union IfrIfru {
+    ifru_addr: sockaddr,
+    ifru_dstaddr: sockaddr,
+    ifru_broadaddr: sockaddr,
+    ifru_flags: [c_short; 2],
+    ifru_metric: c_int,
+    ifru_mtu: c_int,
+    ifru_phys: c_int,
+    ifru_media: c_int,
+    ifru_data: *mut c_char,
+    ifru_cap: [c_int; 2],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct ifreq {
+    ifr_name: [c_uchar; IF_NAMESIZE],
+    ifr_ifru: IfrIfru,
+}
+
+impl ifreq {
+    fn new(name: &str) -> ifreq {
+        let mut ifr = ifreq {
+            ifr_name: [0; IF_NAMESIZE],
+            ifr_ifru: IfrIfru { ifru_mtu: 0 },
+        };
+        ifr.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        ifr
+    }
+}
+
+// From net/if_tun.h and sys/sockio.h
+const TUNSIFMODE: c_ulong = 0x8004_745e;
+const TUNSIFHEAD: c_ulong = 0x8004_7460;
+const TUNGIFNAME: c_ulong = 0x4020_745d;
+const SIOCSIFNAME: c_ulong = 0x8020_6928;
+const SIOCGIFMTU: c_ulong = 0xc020_6933;
+const SIOCIFDESTROY: c_ulong = 0x8020_6979;
+
+/// The device that creates a new tun interface each time it is opened
+const CLONE_DEVICE: &str = "/dev/tun";
+
+#[derive(Default, Debug)]
+pub struct TunSocket {
+    fd: RawFd,
+}
+
+impl Drop for TunSocket {
+    fn drop(&mut self) {
+        // Unlike on Linux, the interface outlives the file descriptor, so destroy it the way
+        // wireguard-go does
+        if let Ok(name) = self.name() {
+            let ifr = ifreq::new(&name);
+            unsafe {
+                let fd = socket(AF_INET, SOCK_DGRAM, 0);
+                if fd >= 0 {
+                    ioctl(fd, SIOCIFDESTROY, &ifr);
+                    close(fd);
+                }
+            }
+        }
+        unsafe { close(self.fd) };
+    }
+}
+
+impl AsRawFd for TunSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// How a tun interface is obtained for a given name
+#[derive(Debug, PartialEq, Eq)]
+pub enum TunName<'a> {
+    /// `tun`, a new interface with the next free unit
+    Clone,
+    /// `tunN`, the interface with this unit, which is created if it doesn't exist
+    Unit(&'a str),
+    /// Any other name, a new interface that is renamed, like wireguard-go lets `wg0` be used
+    Rename(&'a str),
+}
+
+/// Check that `name` can be used for an interface, and find how to obtain it
+pub fn parse_tun_name(name: &str) -> Result<TunName<'_>, Error> {
+    if name.is_empty()
+        || name.len() >= IF_NAMESIZE
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.')
+    {
+        return Err(Error::InvalidTunnelName);
+    }
+
+    match name.strip_prefix("tun") {
+        Some("") => Ok(TunName::Clone),
+        Some(unit) if unit.bytes().all(|b| b.is_ascii_digit()) => Ok(TunName::Unit(name)),
+        _ => Ok(TunName::Rename(name)),
+    }
+}
+
+fn ioctl_int(fd: RawFd, request: c_ulong, value: c_int) -> Result<(), Error> {
+    match unsafe { ioctl(fd, request, &value) } {
+        -1 => Err(Error::IOCtl(io::Error::last_os_error())),
+        _ => Ok(()),
+    }
+}
+
+impl TunSocket {
+    fn write(&self, src: &[u8], af: u8) -> usize {
+        // The address family is prepended in network byte order, see TUNSIFHEAD
+        let hdr = [0u8, 0u8, 0u8, af];
+        let iov = [
+            iovec {
+                iov_base: hdr.as_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: src.as_ptr() as _,
+                iov_len: src.len(),
+            },
+        ];
+
+        match unsafe { writev(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => 0,
+            n => (n as usize).saturating_sub(hdr.len()),
+        }
+    }
+
+    pub fn new(name: &str) -> Result<TunSocket, Error> {
+        let tun_name = parse_tun_name(name)?;
+        let path = match tun_name {
+            TunName::Unit(unit) => format!("/dev/{}", unit),
+            TunName::Clone | TunName::Rename(_) => CLONE_DEVICE.to_owned(),
+        };
+        let c_path = CString::new(path).map_err(|_| Error::InvalidTunnelName)?;
+
+        let fd = match unsafe { open(c_path.as_ptr(), O_RDWR) } {
+            -1 => return Err(Error::Socket(io::Error::last_os_error())),
+            fd => fd,
+        };
+        let tun = TunSocket { fd };
+
+        // Prefix packets with their address family, like utun on macOS, so IPv6 works, and make
+        // the interface point to point
+        ioctl_int(fd, TUNSIFHEAD, 1)?;
+        ioctl_int(fd, TUNSIFMODE, IFF_POINTOPOINT | IFF_MULTICAST)?;
+
+        if let TunName::Rename(new_name) = tun_name {
+            let new_name = CString::new(new_name).map_err(|_| Error::InvalidTunnelName)?;
+            let mut ifr = ifreq::new(&tun.name()?);
+            ifr.ifr_ifru.ifru_data = new_name.as_ptr() as *mut c_char;
+
+            let sock = match unsafe { socket(AF_INET, SOCK_DGRAM, 0) } {
+                -1 => return Err(Error::Socket(io::Error::last_os_error())),
+                sock => sock,
+            };
+            let res = unsafe { ioctl(sock, SIOCSIFNAME, &ifr) };
+            let err = io::Error::last_os_error();
+            unsafe { close(sock) };
+            if res < 0 {
+                return Err(Error::IOCtl(err));
+            }
+        }
+
+        Ok(tun)
+    }
+
+    pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
+        match unsafe { fcntl(self.fd, F_GETFL) } {
+            -1 => Err(Error::FCntl(io::Error::last_os_error())),
+            flags => match unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } {
+                -1 => Err(Error::FCntl(io::Error::last_os_error())),
+                _ => Ok(self),
+            },
+        }
+    }
+
+    pub fn name(&self) -> Result<String, Error> {
+        let mut ifr = ifreq::new("");
+        if unsafe { ioctl(self.fd, TUNGIFNAME, &mut ifr) } < 0 {
+            return Err(Error::IOCtl(io::Error::last_os_error()));
+        }
+
+        let len = ifr
+            .ifr_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(IF_NAMESIZE);
+        Ok(String::from_utf8_lossy(&ifr.ifr_name[..len]).to_string())
+    }
+
+    /// Get the current MTU value
+    pub fn mtu(&self) -> Result<usize, Error> {
+        let fd = match unsafe { socket(AF_INET, SOCK_DGRAM, 0) } {
+            -1 => return Err(Error::Socket(io::Error::last_os_error())),
+            fd => fd,
+        };
+
+        let ifr = ifreq::new(&self.name()?);
+        if unsafe { ioctl(fd, SIOCGIFMTU, &ifr) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { close(fd) };
+            return Err(Error::IOCtl(err));
+        }
+
+        unsafe { close(fd) };
+
+        Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
+    }
+
+    /// Configuring tun interfaces needs different ioctls, which are not implemented
+    pub fn configure(
+        &self,
+        _addresses: &[AllowedIP],
+        _mtu: Option<u32>,
+        _bring_up: bool,
+    ) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "setting the address, MTU or link state is not supported on this platform".to_owned(),
+        ))
+    }
+
+    pub fn write4(&self, src: &[u8]) -> usize {
+        self.write(src, AF_INET as u8)
+    }
+
+    pub fn write6(&self, src: &[u8]) -> usize {
+        self.write(src, AF_INET6 as u8)
+    }
+
+    pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        let mut hdr = [0u8; 4];
+
+        let iov = [
+            iovec {
+                iov_base: hdr.as_mut_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: dst.as_mut_ptr() as _,
+                iov_len: dst.len(),
+            },
+        ];
+
+        match unsafe { readv(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => Err(Error::IfaceRead(io::Error::last_os_error())),
+            0..=4 => Ok(&mut dst[..0]),
+            n => Ok(&mut dst[..(n - 4) as usize]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tun_name() {
+        assert_eq!(parse_tun_name("tun").unwrap(), TunName::Clone);
+        assert_eq!(parse_tun_name("tun3").unwrap(), TunName::Unit("tun3"));
+        assert_eq!(parse_tun_name("wg0").unwrap(), TunName::Rename("wg0"));
+        assert_eq!(parse_tun_name("tunnel").unwrap(), TunName::Rename("tunnel"));
+        assert!(parse_tun_name("").is_err());
+        assert!(parse_tun_name("wg/0").is_err());
+        assert!(parse_tun_name("a_name_that_is_too_long").is_err());
+    }
+}
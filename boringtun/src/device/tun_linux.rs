@@ -1,12 +1,19 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use super::peer::AllowedIP;
 use super::Error;
 use libc::*;
+use std::convert::TryInto;
+use std::ffi::CString;
 use std::io;
+use std::net::IpAddr;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNGETIFF: u64 = 0x8004_54d2;
+
+const NLMSG_HDR_LEN: usize = 16;
 
 #[repr(C)]
 union IfrIfru {
@@ -37,6 +44,70 @@ pub struct ifreq {
     ifr_ifru: IfrIfru,
 }
 
+/// A rtnetlink socket, to configure the interface
+pub(crate) struct Netlink(RawFd);
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { close(self.0) };
+    }
+}
+
+/// Append a netlink attribute to `buf`, padded to 4 bytes
+pub(crate) fn push_attr(buf: &mut Vec<u8>, kind: c_ushort, data: &[u8]) {
+    buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+impl Netlink {
+    pub(crate) fn open() -> io::Result<Netlink> {
+        match unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Netlink(fd)),
+        }
+    }
+
+    /// Send a request to the kernel, and wait for it to be acknowledged
+    pub(crate) fn request(&self, kind: u16, flags: c_int, body: &[u8]) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(NLMSG_HDR_LEN + body.len());
+        msg.extend_from_slice(&((NLMSG_HDR_LEN + body.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&((flags | NLM_F_REQUEST | NLM_F_ACK) as u16).to_ne_bytes());
+        msg.extend_from_slice(&1u32.to_ne_bytes()); // Sequence number
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // Port id, filled in by the kernel
+        msg.extend_from_slice(body);
+
+        if unsafe { send(self.0, msg.as_ptr() as _, msg.len(), 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match unsafe { recv(self.0, buf.as_mut_ptr() as _, buf.len(), 0) } {
+                -1 => return Err(io::Error::last_os_error()),
+                n => n as usize,
+            };
+
+            let mut reply = &buf[..n];
+            while reply.len() >= NLMSG_HDR_LEN + 4 {
+                let len = u32::from_ne_bytes(reply[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(reply[4..6].try_into().unwrap());
+                if kind == NLMSG_ERROR as u16 {
+                    // An error code of 0 is the acknowledgment
+                    let err = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+                    return match err {
+                        0 => Ok(()),
+                        err => Err(io::Error::from_raw_os_error(-err)),
+                    };
+                }
+                reply = &reply[((len + 3) & !3).clamp(NLMSG_HDR_LEN, reply.len())..];
+            }
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct TunSocket {
     fd: RawFd,
@@ -99,6 +170,70 @@ impl TunSocket {
         Ok(TunSocket { fd, name })
     }
 
+    /// Take ownership of `fds`, queues of the TUN device `name` opened by another process, such
+    /// as a privileged launcher. Each of them must be a TUN device without packet information,
+    /// of the interface `name`, and several of them must have been opened with `IFF_MULTI_QUEUE`.
+    pub fn from_fds(fds: &[RawFd], name: &str) -> Result<Vec<TunSocket>, Error> {
+        // A descriptor given twice would be closed twice
+        if let Some((i, _)) = fds
+            .iter()
+            .enumerate()
+            .find(|(i, fd)| fds[..*i].contains(fd))
+        {
+            return Err(Error::InvalidConfig(format!(
+                "the TUN descriptor {} is given twice",
+                fds[i]
+            )));
+        }
+        // Closed on failure as well
+        let queues: Vec<TunSocket> = fds
+            .iter()
+            .map(|&fd| TunSocket {
+                fd,
+                name: name.to_owned(),
+            })
+            .collect();
+        for queue in &queues {
+            let fd = queue.fd;
+            let mut ifr = ifreq {
+                ifr_name: [0; IFNAMSIZ],
+                ifr_ifru: IfrIfru { ifru_flags: 0 },
+            };
+            if unsafe { ioctl(fd, TUNGETIFF as _, &mut ifr) } < 0 {
+                return Err(Error::InvalidConfig(format!(
+                    "the descriptor {} is not a TUN device: {}",
+                    fd,
+                    io::Error::last_os_error()
+                )));
+            }
+            let flags = unsafe { ifr.ifr_ifru.ifru_flags } as c_int;
+            let len = ifr
+                .ifr_name
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(IFNAMSIZ);
+            let actual = String::from_utf8_lossy(&ifr.ifr_name[..len]);
+            let problem = if flags & IFF_TUN == 0 {
+                Some("is not a TUN device".to_owned())
+            } else if flags & IFF_NO_PI == 0 {
+                Some("has packet information, it must be opened with IFF_NO_PI".to_owned())
+            } else if actual != name {
+                Some(format!("is a queue of {}, not of {}", actual, name))
+            } else if fds.len() > 1 && flags & IFF_MULTI_QUEUE == 0 {
+                Some("has a single queue, it must be opened with IFF_MULTI_QUEUE".to_owned())
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                return Err(Error::InvalidConfig(format!(
+                    "the TUN descriptor {} {}",
+                    fd, problem
+                )));
+            }
+        }
+        Ok(queues)
+    }
+
     pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
         match unsafe { fcntl(self.fd, F_GETFL) } {
             -1 => Err(Error::FCntl(io::Error::last_os_error())),
@@ -143,6 +278,149 @@ impl TunSocket {
         Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
     }
 
+    /// Add `addresses` to the interface, set its MTU, and bring it up if `bring_up` is set
+    pub fn configure(
+        &self,
+        addresses: &[AllowedIP],
+        mtu: Option<u32>,
+        bring_up: bool,
+    ) -> Result<(), Error> {
+        let name = &self.name;
+        let index = self.index()?;
+        let netlink = Netlink::open()
+            .map_err(|e| Error::InterfaceConfig(format!("netlink socket: {}", e)))?;
+
+        for AllowedIP { addr, cidr } in addresses {
+            let (family, octets) = match addr {
+                IpAddr::V4(addr) => (AF_INET, addr.octets().to_vec()),
+                IpAddr::V6(addr) => (AF_INET6, addr.octets().to_vec()),
+            };
+
+            // struct ifaddrmsg
+            let mut body = vec![family as u8, *cidr, 0, RT_SCOPE_UNIVERSE];
+            body.extend_from_slice(&index.to_ne_bytes());
+            push_attr(&mut body, IFA_LOCAL, &octets);
+            push_attr(&mut body, IFA_ADDRESS, &octets);
+
+            netlink
+                .request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, &body)
+                .map_err(|e| match e.raw_os_error() {
+                    Some(EEXIST) => Error::InterfaceConfig(format!(
+                        "{}: address {}/{} is already assigned",
+                        name, addr, cidr
+                    )),
+                    _ => Error::InterfaceConfig(format!(
+                        "{}: failed to add address {}/{}: {}",
+                        name, addr, cidr, e
+                    )),
+                })?;
+        }
+
+        if mtu.is_none() && !bring_up {
+            return Ok(());
+        }
+
+        // struct ifinfomsg
+        let up = if bring_up { IFF_UP as u32 } else { 0 };
+        let mut body = vec![AF_UNSPEC as u8, 0, 0, 0];
+        body.extend_from_slice(&index.to_ne_bytes());
+        body.extend_from_slice(&up.to_ne_bytes()); // Flags
+        body.extend_from_slice(&up.to_ne_bytes()); // Mask of the changed flags
+        if let Some(mtu) = mtu {
+            push_attr(&mut body, IFLA_MTU, &mtu.to_ne_bytes());
+        }
+
+        netlink
+            .request(RTM_NEWLINK, 0, &body)
+            .map_err(|e| Error::InterfaceConfig(format!("{}: failed to set the link: {}", name, e)))
+    }
+
+    /// Set the length of the queue of packets waiting to be read, its `txqueuelen`, 500 by
+    /// default. Each queue of a multi-queue interface holds up to `depth` packets.
+    pub fn set_queue_depth(&self, depth: u32) -> Result<(), Error> {
+        let name = &self.name;
+        let index = self.index()?;
+        let netlink = Netlink::open()
+            .map_err(|e| Error::InterfaceConfig(format!("netlink socket: {}", e)))?;
+
+        // struct ifinfomsg, without flags to change
+        let mut body = vec![AF_UNSPEC as u8, 0, 0, 0];
+        body.extend_from_slice(&index.to_ne_bytes());
+        body.extend_from_slice(&[0; 8]);
+        push_attr(&mut body, IFLA_TXQLEN, &depth.to_ne_bytes());
+
+        netlink.request(RTM_NEWLINK, 0, &body).map_err(|e| {
+            Error::InterfaceConfig(format!("{}: failed to set the queue length: {}", name, e))
+        })
+    }
+
+    /// Route `routes` through the interface, in the routing `table`. Fails on the first route
+    /// that exists already, naming it.
+    pub fn add_routes(&self, routes: &[AllowedIP], table: u32) -> Result<(), Error> {
+        let name = &self.name;
+        let index = self.index()?;
+        let netlink = Netlink::open()
+            .map_err(|e| Error::InterfaceConfig(format!("netlink socket: {}", e)))?;
+
+        for AllowedIP { addr, cidr } in routes {
+            let (family, octets, scope) = match addr {
+                IpAddr::V4(addr) => (AF_INET, addr.octets().to_vec(), RT_SCOPE_LINK),
+                IpAddr::V6(addr) => (AF_INET6, addr.octets().to_vec(), RT_SCOPE_UNIVERSE),
+            };
+
+            // struct rtmsg, the table is in an attribute as it may not fit in a byte
+            let mut body = vec![
+                family as u8,
+                *cidr,
+                0,
+                0,
+                RT_TABLE_UNSPEC,
+                RTPROT_BOOT,
+                scope,
+                RTN_UNICAST,
+            ];
+            body.extend_from_slice(&0u32.to_ne_bytes()); // Flags
+            if *cidr > 0 {
+                push_attr(&mut body, RTA_DST, &octets);
+            }
+            push_attr(&mut body, RTA_OIF, &index.to_ne_bytes());
+            push_attr(&mut body, RTA_TABLE, &table.to_ne_bytes());
+
+            netlink
+                .request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &body)
+                .map_err(|e| match e.raw_os_error() {
+                    Some(EEXIST) => Error::InterfaceConfig(format!(
+                        "{}: a route to {}/{} already exists",
+                        name, addr, cidr
+                    )),
+                    _ => Error::InterfaceConfig(format!(
+                        "{}: failed to add the route to {}/{}: {}",
+                        name, addr, cidr, e
+                    )),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// The index of the interface, for netlink requests
+    fn index(&self) -> Result<c_uint, Error> {
+        let name = &self.name;
+        if name.parse::<i32>().is_ok() {
+            return Err(Error::InterfaceConfig(
+                "a TUN device passed as a file descriptor can't be configured".to_owned(),
+            ));
+        }
+
+        let c_name = CString::new(name.as_str()).map_err(|_| Error::InvalidTunnelName)?;
+        match unsafe { if_nametoindex(c_name.as_ptr()) } {
+            0 => {
+                let e = io::Error::last_os_error();
+                Err(Error::InterfaceConfig(format!("{}: {}", name, e)))
+            }
+            index => Ok(index),
+        }
+    }
+
     pub fn write4(&self, src: &[u8]) -> usize {
         self.write(src)
     }
@@ -0,0 +1,210 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::peer::AllowedIP;
+use super::Error;
+use libc::*;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[repr(C)]
+union IfrIfru {
+    ifru_addr: sockaddr,
+    ifru_dstaddr: sockaddr,
+    ifru_broadaddr: sockaddr,
+    ifru_flags: c_short,
+    ifru_metric: c_int,
+    ifru_mtu: c_int,
+    ifru_data: *mut c_char,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct ifreq {
+    ifr_name: [c_uchar; IF_NAMESIZE],
+    ifr_ifru: IfrIfru,
+}
+
+impl ifreq {
+    fn new(name: &str) -> ifreq {
+        let mut ifr = ifreq {
+            ifr_name: [0; IF_NAMESIZE],
+            ifr_ifru: IfrIfru { ifru_mtu: 0 },
+        };
+        ifr.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        ifr
+    }
+}
+
+// From sys/sockio.h
+const SIOCGIFMTU: c_ulong = 0xc020_697e;
+const SIOCIFDESTROY: c_ulong = 0x8020_6979;
+
+#[derive(Default, Debug)]
+pub struct TunSocket {
+    fd: RawFd,
+    name: String,
+}
+
+impl Drop for TunSocket {
+    fn drop(&mut self) {
+        // The interface outlives the file descriptor, so destroy it the way wireguard-go does
+        let ifr = ifreq::new(&self.name);
+        unsafe {
+            let fd = socket(AF_INET, SOCK_DGRAM, 0);
+            if fd >= 0 {
+                ioctl(fd, SIOCIFDESTROY, &ifr);
+                close(fd);
+            }
+            close(self.fd);
+        }
+    }
+}
+
+impl AsRawFd for TunSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+// On OpenBSD there is no clone device, the tunnel must be named tunN to open /dev/tunN
+pub fn parse_tun_name(name: &str) -> Result<u32, Error> {
+    if name.len() >= IF_NAMESIZE {
+        return Err(Error::InvalidTunnelName);
+    }
+
+    match name.strip_prefix("tun") {
+        Some(unit) if !unit.is_empty() && unit.bytes().all(|b| b.is_ascii_digit()) => {
+            unit.parse::<u32>().map_err(|_| Error::InvalidTunnelName)
+        }
+        _ => Err(Error::InvalidTunnelName),
+    }
+}
+
+impl TunSocket {
+    fn write(&self, src: &[u8], af: u8) -> usize {
+        // Packets are always prefixed with their address family in network byte order
+        let hdr = [0u8, 0u8, 0u8, af];
+        let iov = [
+            iovec {
+                iov_base: hdr.as_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: src.as_ptr() as _,
+                iov_len: src.len(),
+            },
+        ];
+
+        match unsafe { writev(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => 0,
+            n => (n as usize).saturating_sub(hdr.len()),
+        }
+    }
+
+    pub fn new(name: &str) -> Result<TunSocket, Error> {
+        let unit = parse_tun_name(name)?;
+        let path = CString::new(format!("/dev/tun{}", unit)).unwrap();
+
+        // Opening the device node creates the interface if it doesn't exist yet
+        let fd = match unsafe { open(path.as_ptr(), O_RDWR) } {
+            -1 => return Err(Error::Socket(io::Error::last_os_error())),
+            fd => fd,
+        };
+
+        Ok(TunSocket {
+            fd,
+            name: format!("tun{}", unit),
+        })
+    }
+
+    pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
+        match unsafe { fcntl(self.fd, F_GETFL) } {
+            -1 => Err(Error::FCntl(io::Error::last_os_error())),
+            flags => match unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } {
+                -1 => Err(Error::FCntl(io::Error::last_os_error())),
+                _ => Ok(self),
+            },
+        }
+    }
+
+    pub fn name(&self) -> Result<String, Error> {
+        Ok(self.name.clone())
+    }
+
+    /// Get the current MTU value
+    pub fn mtu(&self) -> Result<usize, Error> {
+        let fd = match unsafe { socket(AF_INET, SOCK_DGRAM, 0) } {
+            -1 => return Err(Error::Socket(io::Error::last_os_error())),
+            fd => fd,
+        };
+
+        let ifr = ifreq::new(&self.name);
+        if unsafe { ioctl(fd, SIOCGIFMTU, &ifr) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { close(fd) };
+            return Err(Error::IOCtl(err));
+        }
+
+        unsafe { close(fd) };
+
+        Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as _)
+    }
+
+    /// Configuring tun interfaces needs different ioctls, which are not implemented
+    pub fn configure(
+        &self,
+        _addresses: &[AllowedIP],
+        _mtu: Option<u32>,
+        _bring_up: bool,
+    ) -> Result<(), Error> {
+        Err(Error::InterfaceConfig(
+            "setting the address, MTU or link state is not supported on this platform".to_owned(),
+        ))
+    }
+
+    pub fn write4(&self, src: &[u8]) -> usize {
+        self.write(src, AF_INET as u8)
+    }
+
+    pub fn write6(&self, src: &[u8]) -> usize {
+        self.write(src, AF_INET6 as u8)
+    }
+
+    pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        let mut hdr = [0u8; 4];
+
+        let iov = [
+            iovec {
+                iov_base: hdr.as_mut_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: dst.as_mut_ptr() as _,
+                iov_len: dst.len(),
+            },
+        ];
+
+        match unsafe { readv(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => Err(Error::IfaceRead(io::Error::last_os_error())),
+            0..=4 => Ok(&mut dst[..0]),
+            n => Ok(&mut dst[..(n - 4) as usize]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tun_name() {
+        assert_eq!(parse_tun_name("tun0").unwrap(), 0);
+        assert_eq!(parse_tun_name("tun12").unwrap(), 12);
+        assert!(parse_tun_name("tun").is_err());
+        assert!(parse_tun_name("tun-1").is_err());
+        assert!(parse_tun_name("wg0").is_err());
+        assert!(parse_tun_name("tun000000000000000").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::peer::AllowedIP;
+use super::poll::{AsRawFd, RawFd};
+use super::Error;
+use std::ffi::c_void;
+use std::io;
+use std::net::IpAddr;
+use std::ptr::{copy_nonoverlapping, null};
+use windows_sys::core::PCWSTR;
+use windows_sys::Win32::Foundation::{
+    FreeLibrary, ERROR_NO_MORE_ITEMS, ERROR_OBJECT_ALREADY_EXISTS, HANDLE, HMODULE, NO_ERROR,
+};
+use windows_sys::Win32::NetworkManagement::IpHelper::{
+    CreateUnicastIpAddressEntry, GetIpInterfaceEntry, InitializeIpInterfaceEntry,
+    InitializeUnicastIpAddressEntry, SetIpInterfaceEntry, MIB_IPINTERFACE_ROW,
+    MIB_UNICASTIPADDRESS_ROW,
+};
+use windows_sys::Win32::NetworkManagement::Ndis::NET_LUID_LH;
+use windows_sys::Win32::Networking::WinSock::{
+    IpDadStatePreferred, ADDRESS_FAMILY, AF_INET, AF_INET6,
+};
+use windows_sys::Win32::System::LibraryLoader::{
+    GetProcAddress, LoadLibraryExW, LOAD_LIBRARY_SEARCH_APPLICATION_DIR,
+    LOAD_LIBRARY_SEARCH_SYSTEM32,
+};
+use windows_sys::Win32::System::Threading::SetEvent;
+
+/// The longest name of an adapter, terminating zero included
+const MAX_ADAPTER_NAME: usize = 128;
+
+/// The size of the rings shared with the driver, the same as wireguard-go
+const RING_CAPACITY: u32 = 0x80_0000;
+
+/// The tunnel type of the adapters, shown by Windows as their description
+const TUNNEL_TYPE: &str = "boringtun";
+
+type Adapter = *mut c_void;
+type Session = *mut c_void;
+
+// The functions of wintun.dll, see wintun.h
+#[allow(non_snake_case)]
+struct Wintun {
+    module: HMODULE,
+    WintunCreateAdapter: unsafe extern "system" fn(
+        name: PCWSTR,
+        tunnel_type: PCWSTR,
+        guid: *const c_void,
+    ) -> Adapter,
+    WintunCloseAdapter: unsafe extern "system" fn(adapter: Adapter),
+    WintunGetAdapterLUID: unsafe extern "system" fn(adapter: Adapter, luid: *mut NET_LUID_LH),
+    WintunStartSession: unsafe extern "system" fn(adapter: Adapter, capacity: u32) -> Session,
+    WintunEndSession: unsafe extern "system" fn(session: Session),
+    WintunGetReadWaitEvent: unsafe extern "system" fn(session: Session) -> HANDLE,
+    WintunReceivePacket: unsafe extern "system" fn(session: Session, size: *mut u32) -> *mut u8,
+    WintunReleaseReceivePacket: unsafe extern "system" fn(session: Session, packet: *const u8),
+    WintunAllocateSendPacket: unsafe extern "system" fn(session: Session, size: u32) -> *mut u8,
+    WintunSendPacket: unsafe extern "system" fn(session: Session, packet: *const u8),
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+impl Wintun {
+    /// Load wintun.dll from the directory of the executable or from System32, never from the
+    /// working directory
+    #[allow(clippy::missing_transmute_annotations)]
+    fn load() -> Result<Wintun, Error> {
+        let path = wide("wintun.dll");
+        let module = unsafe {
+            LoadLibraryExW(
+                path.as_ptr(),
+                0,
+                LOAD_LIBRARY_SEARCH_APPLICATION_DIR | LOAD_LIBRARY_SEARCH_SYSTEM32,
+            )
+        };
+        if module == 0 {
+            return Err(Error::Wintun(format!(
+                "failed to load wintun.dll: {}",
+                io::Error::last_os_error()
+            )));
+        }
+
+        macro_rules! load {
+            ($name:ident) => {
+                match unsafe { GetProcAddress(module, concat!(stringify!($name), "\0").as_ptr()) } {
+                    // The signature is the one of the field the function is loaded into
+                    Some(f) => unsafe { std::mem::transmute(f) },
+                    None => {
+                        unsafe { FreeLibrary(module) };
+                        return Err(Error::Wintun(format!(
+                            "wintun.dll has no {}",
+                            stringify!($name)
+                        )));
+                    }
+                }
+            };
+        }
+
+        Ok(Wintun {
+            module,
+            WintunCreateAdapter: load!(WintunCreateAdapter),
+            WintunCloseAdapter: load!(WintunCloseAdapter),
+            WintunGetAdapterLUID: load!(WintunGetAdapterLUID),
+            WintunStartSession: load!(WintunStartSession),
+            WintunEndSession: load!(WintunEndSession),
+            WintunGetReadWaitEvent: load!(WintunGetReadWaitEvent),
+            WintunReceivePacket: load!(WintunReceivePacket),
+            WintunReleaseReceivePacket: load!(WintunReleaseReceivePacket),
+            WintunAllocateSendPacket: load!(WintunAllocateSendPacket),
+            WintunSendPacket: load!(WintunSendPacket),
+        })
+    }
+}
+
+impl Drop for Wintun {
+    fn drop(&mut self) {
+        unsafe { FreeLibrary(self.module) };
+    }
+}
+
+/// A Wintun adapter, that exists as long as this value. The packets are exchanged with the
+/// driver over rings in shared memory, see <https://www.wintun.net>.
+pub struct TunSocket {
+    wintun: Wintun,
+    adapter: Adapter,
+    session: Session,
+    read_event: HANDLE,
+    name: String,
+    luid: NET_LUID_LH,
+}
+
+// The functions of Wintun can be called from any thread
+unsafe impl Send for TunSocket {}
+unsafe impl Sync for TunSocket {}
+
+impl Drop for TunSocket {
+    fn drop(&mut self) {
+        unsafe {
+            (self.wintun.WintunEndSession)(self.session);
+            (self.wintun.WintunCloseAdapter)(self.adapter);
+        }
+    }
+}
+
+impl AsRawFd for TunSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        RawFd::Handle(self.read_event)
+    }
+}
+
+/// Check that `name` can be used for an adapter
+pub fn parse_tun_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.encode_utf16().count() >= MAX_ADAPTER_NAME {
+        return Err(Error::InvalidTunnelName);
+    }
+    Ok(())
+}
+
+fn ip_helper_result(code: u32) -> Result<(), Error> {
+    match code {
+        NO_ERROR => Ok(()),
+        code => Err(Error::IOCtl(io::Error::from_raw_os_error(code as i32))),
+    }
+}
+
+impl TunSocket {
+    pub fn new(name: &str) -> Result<TunSocket, Error> {
+        parse_tun_name(name)?;
+        let wintun = Wintun::load()?;
+
+        let wide_name = wide(name);
+        let tunnel_type = wide(TUNNEL_TYPE);
+        let adapter = unsafe {
+            (wintun.WintunCreateAdapter)(wide_name.as_ptr(), tunnel_type.as_ptr(), null())
+        };
+        if adapter.is_null() {
+            return Err(Error::Wintun(format!(
+                "failed to create the adapter: {}",
+                io::Error::last_os_error()
+            )));
+        }
+
+        let session = unsafe { (wintun.WintunStartSession)(adapter, RING_CAPACITY) };
+        if session.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { (wintun.WintunCloseAdapter)(adapter) };
+            return Err(Error::Wintun(format!(
+                "failed to start the session: {}",
+                err
+            )));
+        }
+
+        let mut luid = NET_LUID_LH { Value: 0 };
+        unsafe { (wintun.WintunGetAdapterLUID)(adapter, &mut luid) };
+        let read_event = unsafe { (wintun.WintunGetReadWaitEvent)(session) };
+
+        Ok(TunSocket {
+            wintun,
+            adapter,
+            session,
+            read_event,
+            name: name.to_owned(),
+            luid,
+        })
+    }
+
+    /// Reads never block, there is nothing to change
+    pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
+        Ok(self)
+    }
+
+    pub fn name(&self) -> Result<String, Error> {
+        Ok(self.name.clone())
+    }
+
+    fn interface_row(&self, family: ADDRESS_FAMILY) -> Result<MIB_IPINTERFACE_ROW, Error> {
+        let mut row = unsafe { std::mem::zeroed::<MIB_IPINTERFACE_ROW>() };
+        unsafe { InitializeIpInterfaceEntry(&mut row) };
+        row.Family = family;
+        row.InterfaceLuid = self.luid;
+        ip_helper_result(unsafe { GetIpInterfaceEntry(&mut row) })?;
+        Ok(row)
+    }
+
+    /// Get the current MTU value
+    pub fn mtu(&self) -> Result<usize, Error> {
+        let row = self
+            .interface_row(AF_INET)
+            .or_else(|_| self.interface_row(AF_INET6))?;
+        Ok(row.NlMtu as usize)
+    }
+
+    /// Add the addresses and set the MTU with the IP helper. The adapter is up as long as its
+    /// session is running.
+    pub fn configure(
+        &self,
+        addresses: &[AllowedIP],
+        mtu: Option<u32>,
+        _bring_up: bool,
+    ) -> Result<(), Error> {
+        for AllowedIP { addr, cidr } in addresses {
+            let mut row = unsafe { std::mem::zeroed::<MIB_UNICASTIPADDRESS_ROW>() };
+            unsafe { InitializeUnicastIpAddressEntry(&mut row) };
+            row.InterfaceLuid = self.luid;
+            row.OnLinkPrefixLength = *cidr;
+            row.DadState = IpDadStatePreferred;
+            match addr {
+                IpAddr::V4(addr) => {
+                    row.Address.Ipv4.sin_family = AF_INET;
+                    row.Address.Ipv4.sin_addr.S_un.S_addr = u32::from_ne_bytes(addr.octets());
+                }
+                IpAddr::V6(addr) => {
+                    row.Address.Ipv6.sin6_family = AF_INET6;
+                    row.Address.Ipv6.sin6_addr.u.Byte = addr.octets();
+                }
+            }
+
+            match unsafe { CreateUnicastIpAddressEntry(&row) } {
+                ERROR_OBJECT_ALREADY_EXISTS => {}
+                code => ip_helper_result(code)?,
+            }
+        }
+
+        if let Some(mtu) = mtu {
+            for family in [AF_INET, AF_INET6] {
+                // IPv6 needs an MTU of at least 1280, it is disabled below
+                if family == AF_INET6 && mtu < 1280 {
+                    continue;
+                }
+                let mut row = self.interface_row(family)?;
+                row.NlMtu = mtu;
+                // Must be zero to set the interface of IPv4
+                row.SitePrefixLength = 0;
+                ip_helper_result(unsafe { SetIpInterfaceEntry(&mut row) })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, src: &[u8]) -> usize {
+        let packet =
+            unsafe { (self.wintun.WintunAllocateSendPacket)(self.session, src.len() as u32) };
+        if packet.is_null() {
+            // The ring is full, or the adapter is going away
+            return 0;
+        }
+
+        unsafe {
+            copy_nonoverlapping(src.as_ptr(), packet, src.len());
+            (self.wintun.WintunSendPacket)(self.session, packet);
+        }
+        src.len()
+    }
+
+    pub fn write4(&self, src: &[u8]) -> usize {
+        self.write(src)
+    }
+
+    pub fn write6(&self, src: &[u8]) -> usize {
+        self.write(src)
+    }
+
+    pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        let mut size = 0u32;
+        let packet = unsafe { (self.wintun.WintunReceivePacket)(self.session, &mut size) };
+        if packet.is_null() {
+            let err = io::Error::last_os_error();
+            return Err(Error::IfaceRead(match err.raw_os_error() {
+                Some(code) if code == ERROR_NO_MORE_ITEMS as i32 => {
+                    io::ErrorKind::WouldBlock.into()
+                }
+                _ => err,
+            }));
+        }
+
+        // The driver only sets the read event once the ring was found empty, set it ourselves
+        // so the event loop comes back for the packets that are left, see Tun
+        unsafe { SetEvent(self.read_event) };
+
+        // Longer packets are truncated, like a read from a TUN device into a short buffer
+        let len = (size as usize).min(dst.len());
+        unsafe {
+            copy_nonoverlapping(packet, dst.as_mut_ptr(), len);
+            (self.wintun.WintunReleaseReceivePacket)(self.session, packet);
+        }
+        Ok(&mut dst[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tun_name() {
+        assert!(parse_tun_name("wg0").is_ok());
+        assert!(parse_tun_name("Office VPN").is_ok());
+        assert!(parse_tun_name("").is_err());
+        assert!(parse_tun_name(&"a".repeat(MAX_ADAPTER_NAME)).is_err());
+    }
+}
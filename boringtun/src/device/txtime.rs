@@ -0,0 +1,117 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Pacing of the traffic sent to a peer by the kernel, with `SO_TXTIME`.
+//!
+//! With the `tx-pacing` feature, the packets over the bandwidth limit of a peer are not dropped
+//! but given a departure time, see [`super::shaper`]. The time is attached to the datagram with
+//! `SCM_TXTIME`, and the `fq` qdisc holds the datagram until then, so the device never sleeps to
+//! pace the packets. Other qdiscs ignore the departure time and send the datagrams right away.
+
+use socket2::{SockAddr, Socket};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// Let the datagrams sent from `socket` carry a departure time on the monotonic clock, the one
+/// `fq` uses
+pub(crate) fn enable(socket: &Socket) -> io::Result<()> {
+    let config = libc::sock_txtime {
+        clockid: libc::CLOCK_MONOTONIC,
+        flags: 0,
+    };
+    match unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &config as *const _ as _,
+            mem::size_of_val(&config) as _,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Send `packet` from `socket`, to `addr` unless the socket is connected, for the kernel to send
+/// it once `delay` elapsed
+pub(crate) fn send_delayed(
+    socket: &Socket,
+    packet: &[u8],
+    addr: Option<&SockAddr>,
+    delay: Duration,
+) -> io::Result<usize> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let txtime = (now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+        .saturating_add(delay.as_nanos().min(u128::from(u64::MAX)) as u64);
+
+    // A buffer aligned for a `cmsghdr`, large enough for the departure time
+    let mut control = [0u64; 4];
+    let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<u64>() as _) } as usize;
+    debug_assert!(control_len <= mem::size_of_val(&control));
+
+    let mut iov = libc::iovec {
+        iov_base: packet.as_ptr() as *mut _,
+        iov_len: packet.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    if let Some(addr) = addr {
+        msg.msg_name = addr.as_ptr() as *mut _;
+        msg.msg_namelen = addr.len();
+    }
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut _;
+    msg.msg_controllen = control_len as _;
+
+    // Safety: the control buffer has room for one header and its data, see `control_len`
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_TXTIME;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, txtime);
+    }
+
+    match unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        sent => Ok(sent as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Type};
+    use std::net::{SocketAddr, UdpSocket};
+
+    #[test]
+    fn test_send_delayed() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = SockAddr::from(receiver.local_addr().unwrap());
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        enable(&socket).unwrap();
+
+        // The loopback interface has no `fq` qdisc, the datagram is not held
+        let sent = send_delayed(&socket, b"paced", Some(&addr), Duration::from_millis(1)).unwrap();
+        assert_eq!(sent, 5);
+        let mut buf = [0; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"paced");
+    }
+}
@@ -6,13 +6,15 @@
 #![allow(clippy::missing_safety_doc)]
 
 //! C bindings for the BoringTun library
-use super::noise::{Tunn, TunnResult};
+#[cfg(all(feature = "device", unix))]
+use super::device::SocketHook;
+use super::noise::{Tunn, TunnResultRaw};
+use crate::keys;
 use crate::x25519::{PublicKey, StaticSecret};
 use base64::{decode, encode};
 use hex::encode as encode_hex;
 use libc::{raise, SIGSEGV};
 use parking_lot::Mutex;
-use rand_core::OsRng;
 use tracing;
 use tracing_subscriber::fmt;
 
@@ -20,6 +22,8 @@ use crate::serialization::KeyBytes;
 use std::ffi::{CStr, CString};
 use std::io::{Error, ErrorKind, Write};
 use std::os::raw::c_char;
+#[cfg(all(feature = "device", unix))]
+use std::os::raw::c_void;
 use std::panic;
 use std::ptr;
 use std::ptr::null_mut;
@@ -63,26 +67,26 @@ pub struct stats {
     reserved: [u8; 56], // Make sure to add new fields in this space, keeping total size constant
 }
 
-impl<'a> From<TunnResult<'a>> for wireguard_result {
-    fn from(res: TunnResult<'a>) -> wireguard_result {
+impl<'a> From<TunnResultRaw<'a>> for wireguard_result {
+    fn from(res: TunnResultRaw<'a>) -> wireguard_result {
         match res {
-            TunnResult::Done => wireguard_result {
+            TunnResultRaw::Done => wireguard_result {
                 op: result_type::WIREGUARD_DONE,
                 size: 0,
             },
-            TunnResult::Err(e) => wireguard_result {
+            TunnResultRaw::Err(e) => wireguard_result {
                 op: result_type::WIREGUARD_ERROR,
                 size: e as _,
             },
-            TunnResult::WriteToNetwork(b) => wireguard_result {
+            TunnResultRaw::WriteToNetwork(b) => wireguard_result {
                 op: result_type::WRITE_TO_NETWORK,
                 size: b.len(),
             },
-            TunnResult::WriteToTunnelV4(b, _) => wireguard_result {
+            TunnResultRaw::WriteToTunnelV4(b, _) => wireguard_result {
                 op: result_type::WRITE_TO_TUNNEL_IPV4,
                 size: b.len(),
             },
-            TunnResult::WriteToTunnelV6(b, _) => wireguard_result {
+            TunnResultRaw::WriteToTunnelV6(b, _) => wireguard_result {
                 op: result_type::WRITE_TO_TUNNEL_IPV6,
                 size: b.len(),
             },
@@ -95,11 +99,19 @@ pub struct x25519_key {
     pub key: [u8; 32],
 }
 
-/// Generates a new x25519 secret key.
+/// Generates a new x25519 secret key, clamped like those of `wg genkey`.
 #[no_mangle]
 pub extern "C" fn x25519_secret_key() -> x25519_key {
     x25519_key {
-        key: StaticSecret::random_from_rng(OsRng).to_bytes(),
+        key: keys::generate_private_key().to_bytes(),
+    }
+}
+
+/// Generates a new preshared key, like `wg genpsk`.
+#[no_mangle]
+pub extern "C" fn x25519_preshared_key() -> x25519_key {
+    x25519_key {
+        key: keys::generate_preshared_key(),
     }
 }
 
@@ -299,6 +311,7 @@ pub unsafe extern "C" fn new_tunnel(
         keep_alive,
         index,
         None,
+        None,
     ) {
         Ok(t) => Box::new(Mutex::new(t)),
         Err(_) => return ptr::null_mut(),
@@ -398,3 +411,27 @@ pub unsafe extern "C" fn wireguard_stats(tunnel: *const Mutex<Tunn>) -> stats {
         reserved: [0u8; 56],
     }
 }
+
+/// Turn a C function into a [`SocketHook`], for a device created from Rust whose sockets are
+/// protected from C, e.g. through `VpnService.protect()` on Android. `protect_func` is called with
+/// the socket and `ctx`, and any return value other than 0 fails the creation of the socket.
+///
+/// # Safety
+///
+/// `protect_func` must be safe to call with `ctx` from any thread, for as long as the device
+/// lives.
+#[cfg(all(feature = "device", unix))]
+pub unsafe fn socket_hook(
+    protect_func: unsafe extern "C" fn(fd: i32, ctx: *mut c_void) -> i32,
+    ctx: *mut c_void,
+) -> SocketHook {
+    // The caller guarantees the pointer may be sent to the threads of the device
+    let ctx = ctx as usize;
+    SocketHook::new(move |fd| match protect_func(fd, ctx as *mut c_void) {
+        0 => Ok(()),
+        code => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("the socket was not protected: {}", code),
+        )),
+    })
+}
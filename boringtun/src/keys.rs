@@ -0,0 +1,256 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Generation of the x25519 keys of WireGuard and their base64 encoding, as in configuration files
+//! and the output of `wg genkey`, `wg pubkey` and `wg genpsk`.
+//!
+//! The private keys generated here are clamped, as those of `wg genkey`: their three lowest bits
+//! and their highest bit are cleared, and their second highest bit set. The keys parsed are taken
+//! as they are, their public key is the same either way.
+//!
+//! ```
+//! use boringtun::keys::{generate_keypair, PublicKey};
+//!
+//! let (private, public) = generate_keypair();
+//! let encoded = public.to_string();
+//! assert_eq!(encoded.parse::<PublicKey>().unwrap(), public);
+//! ```
+
+use crate::x25519;
+use rand_core::{OsRng, RngCore};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// The private key of a device, its `Display` is the base64 encoding of the key, its `Debug`
+/// hides it
+#[derive(Clone)]
+pub struct PrivateKey(x25519::StaticSecret);
+
+/// The public key of a device or of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(x25519::PublicKey);
+
+/// Why a key could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The string is not valid base64
+    InvalidBase64,
+    /// The string decodes to this number of bytes instead of 32
+    InvalidLength(usize),
+}
+
+/// Generate a random private key, from the random number generator of the operating system, and
+/// its public key
+pub fn generate_keypair() -> (PrivateKey, PublicKey) {
+    let private = generate_private_key();
+    let public = public_key_from_private(&private);
+    (private, public)
+}
+
+/// Generate a random, clamped, private key, like `wg genkey`
+pub fn generate_private_key() -> PrivateKey {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    PrivateKey::from(clamp_private_key(bytes))
+}
+
+/// Clamp the bytes of a private key as in RFC 7748, section 5
+pub fn clamp_private_key(mut bytes: [u8; 32]) -> [u8; 32] {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    bytes
+}
+
+/// Generate a random preshared key, like `wg genpsk`
+pub fn generate_preshared_key() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// The public key of `private`, like `wg pubkey`
+pub fn public_key_from_private(private: &PrivateKey) -> PublicKey {
+    PublicKey(x25519::PublicKey::from(&private.0))
+}
+
+/// Encode a key in base64 with padding, 44 characters
+pub fn encode_base64(key: &[u8; 32]) -> String {
+    base64::encode(key)
+}
+
+/// Decode a key encoded in base64 with padding
+pub fn decode_base64(s: &str) -> Result<[u8; 32], KeyError> {
+    let decoded = base64::decode(s).map_err(|_| KeyError::InvalidBase64)?;
+    <[u8; 32]>::try_from(decoded.as_slice()).map_err(|_| KeyError::InvalidLength(decoded.len()))
+}
+
+impl PrivateKey {
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+}
+
+impl From<[u8; 32]> for PrivateKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        PrivateKey(x25519::StaticSecret::from(bytes))
+    }
+}
+
+impl From<x25519::StaticSecret> for PrivateKey {
+    fn from(secret: x25519::StaticSecret) -> Self {
+        PrivateKey(secret)
+    }
+}
+
+impl From<PrivateKey> for x25519::StaticSecret {
+    fn from(key: PrivateKey) -> Self {
+        key.0
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+impl fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_base64(&self.to_bytes()))
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_base64(s).map(PrivateKey::from)
+    }
+}
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    /// A short identifier of the key for logs and status displays, the first 16 characters of
+    /// its base64 encoding followed by `...`. It does not identify the key for sure, use
+    /// [`PublicKey::full_base64`] to configure or compare keys.
+    pub fn fingerprint(&self) -> String {
+        let mut fingerprint = self.full_base64();
+        fingerprint.truncate(16);
+        fingerprint + "..."
+    }
+
+    /// The base64 encoding of the whole key, as its `Display`
+    pub fn full_base64(&self) -> String {
+        encode_base64(self.as_bytes())
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        PublicKey(x25519::PublicKey::from(bytes))
+    }
+}
+
+impl From<x25519::PublicKey> for PublicKey {
+    fn from(key: x25519::PublicKey) -> Self {
+        PublicKey(key)
+    }
+}
+
+impl From<PublicKey> for x25519::PublicKey {
+    fn from(key: PublicKey) -> Self {
+        key.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.full_base64())
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_base64(s).map(PublicKey::from)
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::InvalidBase64 => f.write_str("the key is not valid base64"),
+            KeyError::InvalidLength(len) => {
+                write!(f, "the key is {} bytes long instead of 32", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        // The test vectors of RFC 7748, section 6.1, also those of the curve25519 self test of
+        // WireGuard, their private keys are not clamped
+        for (private, public) in [
+            (
+                "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=",
+                "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=",
+            ),
+            (
+                "XasIfmJKikt54X+Lg4AO5m87sSkmGLb9HC+LJ/+I4Os=",
+                "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08=",
+            ),
+        ] {
+            let private: PrivateKey = private.parse().unwrap();
+            assert_eq!(public_key_from_private(&private).to_string(), public);
+        }
+
+        let private: PrivateKey = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo="
+            .parse()
+            .unwrap();
+        assert_eq!(
+            private.to_string(),
+            "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo="
+        );
+        assert_eq!(format!("{:?}", private), "PrivateKey(..)");
+        let public = public_key_from_private(&private);
+        assert_eq!(public.fingerprint(), "hSDwCYkwp1R0i33c...");
+        assert_eq!(public.full_base64(), public.to_string());
+
+        let (private, public) = generate_keypair();
+        assert_eq!(public_key_from_private(&private), public);
+        assert_eq!(public.to_string().parse::<PublicKey>(), Ok(public));
+        let decoded = decode_base64(&encode_base64(&private.to_bytes())).unwrap();
+        assert_eq!(decoded, private.to_bytes());
+
+        // Generated keys are clamped, the public key of a key is that of the key clamped
+        let bytes = generate_private_key().to_bytes();
+        assert_eq!(clamp_private_key(bytes), bytes);
+        assert_eq!(
+            encode_base64(&clamp_private_key([0xff; 32])),
+            "+P///////////////////////////////////////38="
+        );
+        let unclamped = PrivateKey::from([0xff; 32]);
+        let clamped = PrivateKey::from(clamp_private_key([0xff; 32]));
+        assert_eq!(
+            public_key_from_private(&unclamped),
+            public_key_from_private(&clamped)
+        );
+        assert_ne!(generate_preshared_key(), generate_preshared_key());
+
+        assert_eq!(decode_base64("not base64!"), Err(KeyError::InvalidBase64));
+        assert_eq!(decode_base64("AAAA"), Err(KeyError::InvalidLength(3)));
+    }
+}
@@ -5,6 +5,7 @@
 //!
 //! <code>git clone https://github.com/cloudflare/boringtun.git</code>
 
+pub mod crypto;
 #[cfg(feature = "device")]
 pub mod device;
 
@@ -12,11 +13,15 @@ pub mod device;
 pub mod ffi;
 #[cfg(feature = "jni-bindings")]
 pub mod jni;
+pub mod keys;
 pub mod noise;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_utils;
 
 #[cfg(not(feature = "mock-instant"))]
 pub(crate) mod sleepyinstant;
 
+#[cfg(any(feature = "device", feature = "ffi-bindings"))]
 pub(crate) mod serialization;
 
 /// Re-export of the x25519 types
@@ -0,0 +1,53 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+#[cfg(feature = "mock-instant")]
+use mock_instant::Instant;
+
+#[cfg(not(feature = "mock-instant"))]
+use crate::sleepyinstant::Instant;
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Where a [`super::Tunn`] reads the time from, for the handshakes and the timers. A clock other
+/// than [`SystemClock`] lets a tunnel run in a network stack with its own notion of time, or be
+/// driven step by step in tests, without sleeping.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The wall clock time, only used for the timestamps of the handshake initiations
+    fn system_time(&self) -> SystemTime;
+
+    /// The time elapsed since an arbitrary epoch, which must never go backwards
+    fn elapsed_since_epoch(&self) -> Duration;
+}
+
+/// The clock of the operating system, monotonic time includes the time the system was asleep. Its
+/// epoch is when it was created.
+#[derive(Debug)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn elapsed_since_epoch(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
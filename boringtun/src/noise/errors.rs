@@ -1,7 +1,7 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WireGuardError {
     DestinationBufferTooSmall,
     IncorrectPacketLength,
@@ -20,4 +20,98 @@ pub enum WireGuardError {
     LockFailed,
     ConnectionExpired,
     UnderLoad,
+    CryptoProviderFailed,
 }
+
+impl std::fmt::Display for WireGuardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for WireGuardError {}
+
+/// The errors returned by the `try_` methods of [`Tunn`](super::Tunn), grouped by what the caller
+/// can do about them. [`TunnError::cause`] gives the precise [`WireGuardError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnError {
+    /// The destination buffer cannot hold the result
+    BufferTooSmall,
+    /// The datagram is malformed, or not one the tunnel expects at this point
+    Malformed(WireGuardError),
+    /// The datagram failed authentication: wrong key, bad MAC or AEAD tag, or replayed timestamp
+    Unauthenticated(WireGuardError),
+    /// The data packet was already received, or is too old for the anti-replay window
+    Replayed(WireGuardError),
+    /// There is no session to encrypt or decrypt with yet
+    NoSession,
+    /// The handshake was given up on, the session is gone until a new one is initiated
+    ConnectionExpired,
+    /// The handshake was dropped because the device is under load
+    UnderLoad,
+    /// An internal failure, e.g. of the crypto provider
+    Internal(WireGuardError),
+}
+
+impl TunnError {
+    /// The precise error behind this one
+    pub fn cause(&self) -> WireGuardError {
+        match *self {
+            TunnError::BufferTooSmall => WireGuardError::DestinationBufferTooSmall,
+            TunnError::NoSession => WireGuardError::NoCurrentSession,
+            TunnError::ConnectionExpired => WireGuardError::ConnectionExpired,
+            TunnError::UnderLoad => WireGuardError::UnderLoad,
+            TunnError::Malformed(e)
+            | TunnError::Unauthenticated(e)
+            | TunnError::Replayed(e)
+            | TunnError::Internal(e) => e,
+        }
+    }
+}
+
+impl From<WireGuardError> for TunnError {
+    fn from(err: WireGuardError) -> TunnError {
+        use WireGuardError::*;
+        match err {
+            DestinationBufferTooSmall => TunnError::BufferTooSmall,
+            IncorrectPacketLength
+            | UnexpectedPacket
+            | WrongPacketType
+            | WrongIndex
+            | InvalidPacket => TunnError::Malformed(err),
+            WrongKey
+            | InvalidTai64nTimestamp
+            | WrongTai64nTimestamp
+            | InvalidMac
+            | InvalidAeadTag => TunnError::Unauthenticated(err),
+            InvalidCounter | DuplicateCounter => TunnError::Replayed(err),
+            NoCurrentSession => TunnError::NoSession,
+            ConnectionExpired => TunnError::ConnectionExpired,
+            UnderLoad => TunnError::UnderLoad,
+            LockFailed | CryptoProviderFailed => TunnError::Internal(err),
+        }
+    }
+}
+
+impl From<TunnError> for WireGuardError {
+    fn from(err: TunnError) -> WireGuardError {
+        err.cause()
+    }
+}
+
+impl std::fmt::Display for TunnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnError::BufferTooSmall => f.write_str("destination buffer too small"),
+            TunnError::Malformed(e) => write!(f, "malformed packet ({e})"),
+            TunnError::Unauthenticated(e) => write!(f, "unauthenticated packet ({e})"),
+            TunnError::Replayed(e) => write!(f, "replayed packet ({e})"),
+            TunnError::NoSession => f.write_str("no current session"),
+            TunnError::ConnectionExpired => f.write_str("connection expired"),
+            TunnError::UnderLoad => f.write_str("under load"),
+            TunnError::Internal(e) => write!(f, "internal error ({e})"),
+        }
+    }
+}
+
+impl std::error::Error for TunnError {}
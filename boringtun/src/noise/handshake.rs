@@ -2,10 +2,10 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use super::{HandshakeInit, HandshakeResponse, PacketCookieReply};
+use crate::crypto::CryptoProvider;
+use crate::noise::clock::Clock;
 use crate::noise::errors::WireGuardError;
 use crate::noise::session::Session;
-#[cfg(not(feature = "mock-instant"))]
-use crate::sleepyinstant::Instant;
 use crate::x25519;
 use aead::{Aead, Payload};
 use blake2::digest::{FixedOutput, KeyInit};
@@ -14,11 +14,9 @@ use chacha20poly1305::XChaCha20Poly1305;
 use rand_core::OsRng;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-#[cfg(feature = "mock-instant")]
-use mock_instant::Instant;
-
 pub(crate) const LABEL_MAC1: &[u8; 8] = b"mac1----";
 pub(crate) const LABEL_COOKIE: &[u8; 8] = b"cookie--";
 const KEY_LEN: usize = 32;
@@ -169,25 +167,26 @@ struct Tai64N {
 /// This struct computes a [Tai64N](https://cr.yp.to/libtai/tai64.html) timestamp from current system time
 struct TimeStamper {
     duration_at_start: Duration,
-    instant_at_start: Instant,
+    elapsed_at_start: Duration,
 }
 
 impl TimeStamper {
     /// Create a new TimeStamper
-    pub fn new() -> TimeStamper {
+    pub fn new(clock: &dyn Clock) -> TimeStamper {
         TimeStamper {
-            duration_at_start: SystemTime::now()
+            duration_at_start: clock
+                .system_time()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap(),
-            instant_at_start: Instant::now(),
+            elapsed_at_start: clock.elapsed_since_epoch(),
         }
     }
 
     /// Take time reading and generate a 12 byte timestamp
-    pub fn stamp(&self) -> [u8; 12] {
+    pub fn stamp(&self, clock: &dyn Clock) -> [u8; 12] {
         const TAI64_BASE: u64 = (1u64 << 62) + 37;
         let mut ext_stamp = [0u8; 12];
-        let stamp = Instant::now().duration_since(self.instant_at_start) + self.duration_at_start;
+        let stamp = clock.elapsed_since_epoch() - self.elapsed_at_start + self.duration_at_start;
         ext_stamp[0..8].copy_from_slice(&(stamp.as_secs() + TAI64_BASE).to_be_bytes());
         ext_stamp[8..12].copy_from_slice(&stamp.subsec_nanos().to_be_bytes());
         ext_stamp
@@ -262,7 +261,8 @@ struct HandshakeInitSentState {
     hash: [u8; KEY_LEN],
     chaining_key: [u8; KEY_LEN],
     ephemeral_private: x25519::ReusableSecret,
-    time_sent: Instant,
+    /// When the initiation was sent, see [`Clock::elapsed_since_epoch`]
+    time_sent: Duration,
 }
 
 impl std::fmt::Debug for HandshakeInitSentState {
@@ -307,7 +307,11 @@ pub struct Handshake {
     last_handshake_timestamp: Tai64N,
     // TODO: make TimeStamper a singleton
     stamper: TimeStamper,
-    pub(super) last_rtt: Option<u32>,
+    /// The time between the last initiation we sent and its response
+    pub(super) last_rtt: Option<Duration>,
+    /// Used by the sessions that are established
+    crypto_provider: Option<Arc<dyn CryptoProvider>>,
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Default)]
@@ -437,6 +441,7 @@ impl Handshake {
         peer_static_public: x25519::PublicKey,
         global_idx: u32,
         preshared_key: Option<[u8; 32]>,
+        clock: Arc<dyn Clock>,
     ) -> Result<Handshake, WireGuardError> {
         let params = NoiseParams::new(
             static_private,
@@ -451,9 +456,11 @@ impl Handshake {
             previous: HandshakeState::None,
             state: HandshakeState::None,
             last_handshake_timestamp: Tai64N::zero(),
-            stamper: TimeStamper::new(),
+            stamper: TimeStamper::new(clock.as_ref()),
             cookies: Default::default(),
             last_rtt: None,
+            crypto_provider: None,
+            clock,
         })
     }
 
@@ -461,9 +468,12 @@ impl Handshake {
         !matches!(self.state, HandshakeState::None | HandshakeState::Expired)
     }
 
-    pub(crate) fn timer(&self) -> Option<Instant> {
+    /// How long ago the initiation that awaits a response was sent
+    pub(crate) fn time_since_init_sent(&self) -> Option<Duration> {
         match self.state {
-            HandshakeState::InitSent(HandshakeInitSentState { time_sent, .. }) => Some(time_sent),
+            HandshakeState::InitSent(HandshakeInitSentState { time_sent, .. }) => {
+                Some(self.clock.elapsed_since_epoch().saturating_sub(time_sent))
+            }
             _ => None,
         }
     }
@@ -501,6 +511,19 @@ impl Handshake {
         self.params.set_static_private(private_key, public_key)
     }
 
+    pub(crate) fn peer_static_public(&self) -> x25519::PublicKey {
+        self.params.peer_static_public
+    }
+
+    /// Set a new preshared key, used starting with the next handshake
+    pub(super) fn set_crypto_provider(&mut self, crypto_provider: Option<Arc<dyn CryptoProvider>>) {
+        self.crypto_provider = crypto_provider;
+    }
+
+    pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.params.preshared_key = preshared_key;
+    }
+
     pub(super) fn receive_handshake_initialization<'a>(
         &mut self,
         packet: HandshakeInit,
@@ -630,15 +653,24 @@ impl Handshake {
         let temp2 = b2s_hmac(&temp1, &[0x01]);
         let temp3 = b2s_hmac2(&temp1, &temp2, &[0x02]);
 
-        let rtt_time = Instant::now().duration_since(state.time_sent);
-        self.last_rtt = Some(rtt_time.as_millis() as u32);
+        self.last_rtt = Some(
+            self.clock
+                .elapsed_since_epoch()
+                .saturating_sub(state.time_sent),
+        );
 
         if is_previous {
             self.previous = HandshakeState::None;
         } else {
             self.state = HandshakeState::None;
         }
-        Ok(Session::new(local_index, peer_index, temp3, temp2))
+        Ok(Session::new(
+            local_index,
+            peer_index,
+            temp3,
+            temp2,
+            self.crypto_provider.as_ref(),
+        ))
     }
 
     pub(super) fn receive_cookie_reply(
@@ -763,12 +795,12 @@ impl Handshake {
         // key = HMAC(temp, initiator.chaining_key || 0x2)
         let key = b2s_hmac2(&temp, &chaining_key, &[0x02]);
         // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
-        let timestamp = self.stamper.stamp();
+        let timestamp = self.stamper.stamp(self.clock.as_ref());
         aead_chacha20_seal(encrypted_timestamp, &key, 0, &timestamp, &hash);
         // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
         hash = b2s_hash(&hash, encrypted_timestamp);
 
-        let time_now = Instant::now();
+        let time_now = self.clock.elapsed_since_epoch();
         self.previous = std::mem::replace(
             &mut self.state,
             HandshakeState::InitSent(HandshakeInitSentState {
@@ -873,7 +905,14 @@ impl Handshake {
 
         let dst = self.append_mac1_and_mac2(local_index, &mut dst[..super::HANDSHAKE_RESP_SZ])?;
 
-        Ok((dst, Session::new(local_index, peer_index, temp2, temp3)))
+        let session = Session::new(
+            local_index,
+            peer_index,
+            temp2,
+            temp3,
+            self.crypto_provider.as_ref(),
+        );
+        Ok((dst, session))
     }
 }
 
@@ -5,9 +5,17 @@ pub mod errors;
 pub mod handshake;
 pub mod rate_limiter;
 
+mod clock;
 mod session;
 mod timers;
 
+pub use clock::{Clock, SystemClock};
+pub use errors::TunnError;
+pub use timers::ReconnectPolicy;
+#[cfg(feature = "device")]
+pub(crate) use timers::REJECT_AFTER_TIME;
+
+use crate::crypto::CryptoProvider;
 use crate::noise::errors::WireGuardError;
 use crate::noise::handshake::Handshake;
 use crate::noise::rate_limiter::RateLimiter;
@@ -41,8 +49,10 @@ const MAX_QUEUE_DEPTH: usize = 256;
 /// number of sessions in the ring, better keep a PoT
 const N_SESSIONS: usize = 8;
 
+/// The result of the low level methods of [`Tunn`], which mixes success and errors. The `try_`
+/// methods return a [`TunnAction`] instead.
 #[derive(Debug)]
-pub enum TunnResult<'buf> {
+pub enum TunnResultRaw<'buf> {
     Done,
     Err(WireGuardError),
     WriteToNetwork(&'buf mut [u8]),
@@ -50,9 +60,52 @@ pub enum TunnResult<'buf> {
     WriteToTunnelV6(&'buf mut [u8], Ipv6Addr),
 }
 
-impl<'buf> From<WireGuardError> for TunnResult<'buf> {
-    fn from(err: WireGuardError) -> TunnResult<'buf> {
-        TunnResult::Err(err)
+#[deprecated(
+    since = "0.6.1",
+    note = "renamed to `TunnResultRaw`, the `try_` methods of `Tunn` return a `TunnAction`"
+)]
+pub type TunnResult<'buf> = TunnResultRaw<'buf>;
+
+impl<'buf> From<WireGuardError> for TunnResultRaw<'buf> {
+    fn from(err: WireGuardError) -> TunnResultRaw<'buf> {
+        TunnResultRaw::Err(err)
+    }
+}
+
+/// What to do after a successful call to one of the `try_` methods of [`Tunn`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum TunnAction<'buf> {
+    /// Send the datagram to the endpoint of the peer
+    WriteToNetwork(&'buf [u8]),
+    /// Write the IP packet to the tunnel interface, it was sent from the given address
+    WriteToTunnel(&'buf [u8], IpAddr),
+    /// There is nothing left to do, e.g. all the queued packets were sent
+    Done,
+    /// The input was consumed without producing anything, e.g. a keepalive was received or a
+    /// packet was queued until a handshake completes
+    Noop,
+}
+
+impl<'buf> TunnResultRaw<'buf> {
+    /// Convert to a [`Result`], mapping `Done` to `done`
+    fn into_result(self, done: TunnAction<'buf>) -> Result<TunnAction<'buf>, TunnError> {
+        match self {
+            TunnResultRaw::Done => Ok(done),
+            TunnResultRaw::Err(e) => Err(e.into()),
+            TunnResultRaw::WriteToNetwork(packet) => Ok(TunnAction::WriteToNetwork(packet)),
+            TunnResultRaw::WriteToTunnelV4(packet, addr) => {
+                Ok(TunnAction::WriteToTunnel(packet, addr.into()))
+            }
+            TunnResultRaw::WriteToTunnelV6(packet, addr) => {
+                Ok(TunnAction::WriteToTunnel(packet, addr.into()))
+            }
+        }
+    }
+}
+
+impl<'buf> From<TunnResultRaw<'buf>> for Result<TunnAction<'buf>, TunnError> {
+    fn from(result: TunnResultRaw<'buf>) -> Self {
+        result.into_result(TunnAction::Done)
     }
 }
 
@@ -60,8 +113,9 @@ impl<'buf> From<WireGuardError> for TunnResult<'buf> {
 pub struct Tunn {
     /// The handshake currently in progress
     handshake: handshake::Handshake,
-    /// The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS
-    sessions: [Option<session::Session>; N_SESSIONS],
+    /// The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS. On the heap,
+    /// they take about 10 KiB that would otherwise be copied on the stack with the tunnel.
+    sessions: Box<[Option<session::Session>; N_SESSIONS]>,
     /// Index of most recently used session
     current: usize,
     /// Queue to store blocked packets
@@ -70,9 +124,27 @@ pub struct Tunn {
     timers: timers::Timers,
     tx_bytes: usize,
     rx_bytes: usize,
+    /// See [`Tunn::handshake_stats`]
+    handshakes_completed: u64,
+    handshake_failures: u64,
+    drops: DropStats,
     rate_limiter: Arc<RateLimiter>,
 }
 
+/// The packets a tunnel dropped, by reason, see [`Tunn::drop_stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DropStats {
+    /// Data packets that failed to decrypt, their Poly1305 tag didn't match: corrupted, forged, or
+    /// encrypted with a session key the tunnel doesn't have
+    pub rx_auth: u64,
+    /// Data packets with a counter that was already received, or too old to tell, behind the
+    /// anti-replay window. Replayed packets, or packets reordered further than the window.
+    pub rx_replay: u64,
+    /// Packets to send that were dropped waiting for a session, because too many were waiting
+    /// or the handshake gave up
+    pub tx_no_session: u64,
+}
+
 type MessageType = u32;
 const HANDSHAKE_INIT: MessageType = 1;
 const HANDSHAKE_RESP: MessageType = 2;
@@ -125,7 +197,7 @@ pub enum Packet<'buf> {
 
 impl Tunn {
     #[inline(always)]
-    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet, WireGuardError> {
+    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet<'_>, WireGuardError> {
         if src.len() < 4 {
             return Err(WireGuardError::InvalidPacket);
         }
@@ -166,6 +238,11 @@ impl Tunn {
         self.handshake.is_expired()
     }
 
+    /// Whether the tunnel holds a session, until they expire or the private key changes
+    pub fn has_session(&self) -> bool {
+        self.sessions.iter().any(Option::is_some)
+    }
+
     pub fn dst_address(packet: &[u8]) -> Option<IpAddr> {
         if packet.is_empty() {
             return None;
@@ -190,7 +267,8 @@ impl Tunn {
         }
     }
 
-    /// Create a new tunnel using own private key and the peer public key
+    /// Create a new tunnel using own private key and the peer public key. The time is read from
+    /// `clock`, the [`SystemClock`] of the tunnel by default.
     pub fn new(
         static_private: x25519::StaticSecret,
         peer_static_public: x25519::PublicKey,
@@ -198,8 +276,10 @@ impl Tunn {
         persistent_keepalive: Option<u16>,
         index: u32,
         rate_limiter: Option<Arc<RateLimiter>>,
+        clock: Option<Arc<dyn Clock>>,
     ) -> Result<Self, &'static str> {
         let static_public = x25519::PublicKey::from(&static_private);
+        let clock = clock.unwrap_or_else(|| Arc::new(SystemClock::new()));
 
         let tunn = Tunn {
             handshake: Handshake::new(
@@ -208,18 +288,30 @@ impl Tunn {
                 peer_static_public,
                 index << 8,
                 preshared_key,
+                Arc::clone(&clock),
             )
             .map_err(|_| "Invalid parameters")?,
             sessions: Default::default(),
             current: Default::default(),
             tx_bytes: Default::default(),
             rx_bytes: Default::default(),
+            handshakes_completed: 0,
+            handshake_failures: 0,
+            drops: DropStats::default(),
 
             packet_queue: VecDeque::new(),
-            timers: Timers::new(persistent_keepalive, rate_limiter.is_none()),
+            timers: Timers::new(
+                persistent_keepalive,
+                rate_limiter.is_none(),
+                Arc::clone(&clock),
+            ),
 
             rate_limiter: rate_limiter.unwrap_or_else(|| {
-                Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
+                Arc::new(RateLimiter::with_clock(
+                    &static_public,
+                    PEER_HANDSHAKE_RATE_LIMIT,
+                    Arc::clone(&clock),
+                ))
             }),
         };
 
@@ -235,34 +327,58 @@ impl Tunn {
     ) -> Result<(), WireGuardError> {
         self.timers.should_reset_rr = rate_limiter.is_none();
         self.rate_limiter = rate_limiter.unwrap_or_else(|| {
-            Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
+            Arc::new(RateLimiter::with_clock(
+                &static_public,
+                PEER_HANDSHAKE_RATE_LIMIT,
+                Arc::clone(&self.timers.clock),
+            ))
         });
         self.handshake
             .set_static_private(static_private, static_public)?;
-        for s in &mut self.sessions {
+        for s in self.sessions.iter_mut() {
             *s = None;
         }
         Ok(())
     }
 
+    /// The public key of the peer at the other end of the tunnel
+    pub fn peer_static_public(&self) -> x25519::PublicKey {
+        self.handshake.peer_static_public()
+    }
+
+    /// Update the preshared key. Current sessions are kept, the new key is used starting with
+    /// the next handshake.
+    pub fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.handshake.set_preshared_key(preshared_key);
+    }
+
+    /// Encrypt and decrypt the transport data of sessions established from now on with
+    /// `crypto_provider`, instead of the built-in implementation
+    pub fn set_crypto_provider(&mut self, crypto_provider: Option<Arc<dyn CryptoProvider>>) {
+        self.handshake.set_crypto_provider(crypto_provider);
+    }
+
     /// Encapsulate a single packet from the tunnel interface.
-    /// Returns TunnResult.
+    /// Returns TunnResultRaw.
     ///
     /// # Panics
     /// Panics if dst buffer is too small.
     /// Size of dst should be at least src.len() + 32, and no less than 148 bytes.
-    pub fn encapsulate<'buf>(&mut self, src: &[u8], dst: &'buf mut [u8]) -> TunnResult<'buf> {
+    pub fn encapsulate<'buf>(&mut self, src: &[u8], dst: &'buf mut [u8]) -> TunnResultRaw<'buf> {
         let current = self.current;
         if let Some(session) = &self.sessions[current % N_SESSIONS] {
             // Send the packet using an established session
-            let packet = session.format_packet_data(src, dst);
+            let packet = match session.format_packet_data(src, dst) {
+                Ok(packet) => packet,
+                Err(e) => return TunnResultRaw::Err(e),
+            };
             self.timer_tick(TimerName::TimeLastPacketSent);
             // Exclude Keepalive packets from timer update.
             if !src.is_empty() {
                 self.timer_tick(TimerName::TimeLastDataPacketSent);
             }
             self.tx_bytes += src.len();
-            return TunnResult::WriteToNetwork(packet);
+            return TunnResultRaw::WriteToNetwork(packet);
         }
 
         // If there is no session, queue the packet for future retry
@@ -271,18 +387,27 @@ impl Tunn {
         self.format_handshake_initiation(dst, false)
     }
 
+    /// Like [`Tunn::encapsulate`]. Returns [`TunnAction::Noop`] if the packet was queued.
+    pub fn try_encapsulate<'buf>(
+        &mut self,
+        src: &[u8],
+        dst: &'buf mut [u8],
+    ) -> Result<TunnAction<'buf>, TunnError> {
+        self.encapsulate(src, dst).into_result(TunnAction::Noop)
+    }
+
     /// Receives a UDP datagram from the network and parses it.
-    /// Returns TunnResult.
+    /// Returns TunnResultRaw.
     ///
-    /// If the result is of type TunnResult::WriteToNetwork, should repeat the call with empty datagram,
-    /// until TunnResult::Done is returned. If batch processing packets, it is OK to defer until last
+    /// If the result is of type TunnResultRaw::WriteToNetwork, should repeat the call with empty datagram,
+    /// until TunnResultRaw::Done is returned. If batch processing packets, it is OK to defer until last
     /// packet is processed.
     pub fn decapsulate<'buf>(
         &mut self,
         src_addr: Option<IpAddr>,
         datagram: &[u8],
         dst: &'buf mut [u8],
-    ) -> TunnResult<'buf> {
+    ) -> TunnResultRaw<'buf> {
         if datagram.is_empty() {
             // Indicates a repeated call
             return self.send_queued_packet(dst);
@@ -294,38 +419,59 @@ impl Tunn {
             .verify_packet(src_addr, datagram, &mut cookie)
         {
             Ok(packet) => packet,
-            Err(TunnResult::WriteToNetwork(cookie)) => {
+            Err(TunnResultRaw::WriteToNetwork(cookie)) => {
                 dst[..cookie.len()].copy_from_slice(cookie);
-                return TunnResult::WriteToNetwork(&mut dst[..cookie.len()]);
+                return TunnResultRaw::WriteToNetwork(&mut dst[..cookie.len()]);
             }
-            Err(TunnResult::Err(e)) => return TunnResult::Err(e),
+            Err(TunnResultRaw::Err(e)) => return TunnResultRaw::Err(e),
             _ => unreachable!(),
         };
 
         self.handle_verified_packet(packet, dst)
     }
 
+    /// Like [`Tunn::decapsulate`]. After a [`TunnAction::WriteToNetwork`], the call should be
+    /// repeated with an empty datagram until [`TunnAction::Done`] is returned.
+    pub fn try_decapsulate<'buf>(
+        &mut self,
+        src_addr: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'buf mut [u8],
+    ) -> Result<TunnAction<'buf>, TunnError> {
+        let done = match datagram.is_empty() {
+            true => TunnAction::Done,
+            false => TunnAction::Noop,
+        };
+        self.decapsulate(src_addr, datagram, dst).into_result(done)
+    }
+
     pub(crate) fn handle_verified_packet<'buf>(
         &mut self,
         packet: Packet,
         dst: &'buf mut [u8],
-    ) -> TunnResult<'buf> {
-        match packet {
+    ) -> TunnResultRaw<'buf> {
+        let result = match packet {
             Packet::HandshakeInit(p) => self.handle_handshake_init(p, dst),
             Packet::HandshakeResponse(p) => self.handle_handshake_response(p, dst),
-            Packet::PacketCookieReply(p) => self.handle_cookie_reply(p),
-            Packet::PacketData(p) => self.handle_data(p, dst),
-        }
-        .unwrap_or_else(TunnResult::from)
+            Packet::PacketCookieReply(p) => {
+                return self.handle_cookie_reply(p).unwrap_or_else(From::from)
+            }
+            Packet::PacketData(p) => return self.handle_data(p, dst).unwrap_or_else(From::from),
+        };
+        result.unwrap_or_else(|e| {
+            self.handshake_failures += 1;
+            TunnResultRaw::from(e)
+        })
     }
 
     fn handle_handshake_init<'buf>(
         &mut self,
         p: HandshakeInit,
         dst: &'buf mut [u8],
-    ) -> Result<TunnResult<'buf>, WireGuardError> {
+    ) -> Result<TunnResultRaw<'buf>, WireGuardError> {
         tracing::debug!(
-            message = "Received handshake_initiation",
+            message = "Received message",
+            msg_type = "handshake_initiation",
             remote_idx = p.sender_idx
         );
 
@@ -339,25 +485,30 @@ impl Tunn {
         self.timer_tick(TimerName::TimeLastPacketSent);
         self.timer_tick_session_established(false, index); // New session established, we are not the initiator
 
-        tracing::debug!(message = "Sending handshake_response", local_idx = index);
+        tracing::debug!(
+            message = "Sending message",
+            msg_type = "handshake_response",
+            local_idx = index
+        );
 
-        Ok(TunnResult::WriteToNetwork(packet))
+        Ok(TunnResultRaw::WriteToNetwork(packet))
     }
 
     fn handle_handshake_response<'buf>(
         &mut self,
         p: HandshakeResponse,
         dst: &'buf mut [u8],
-    ) -> Result<TunnResult<'buf>, WireGuardError> {
+    ) -> Result<TunnResultRaw<'buf>, WireGuardError> {
         tracing::debug!(
-            message = "Received handshake_response",
+            message = "Received message",
+            msg_type = "handshake_response",
             local_idx = p.receiver_idx,
             remote_idx = p.sender_idx
         );
 
         let session = self.handshake.receive_handshake_response(p)?;
 
-        let keepalive_packet = session.format_packet_data(&[], dst);
+        let keepalive_packet = session.format_packet_data(&[], dst)?;
         // Store new session in ring buffer
         let l_idx = session.local_index();
         let index = l_idx % N_SESSIONS;
@@ -367,17 +518,22 @@ impl Tunn {
         self.timer_tick_session_established(true, index); // New session established, we are the initiator
         self.set_current_session(l_idx);
 
-        tracing::debug!("Sending keepalive");
+        tracing::debug!(
+            message = "Sending message",
+            msg_type = "keepalive",
+            local_idx = l_idx
+        );
 
-        Ok(TunnResult::WriteToNetwork(keepalive_packet)) // Send a keepalive as a response
+        Ok(TunnResultRaw::WriteToNetwork(keepalive_packet)) // Send a keepalive as a response
     }
 
     fn handle_cookie_reply<'buf>(
         &mut self,
         p: PacketCookieReply,
-    ) -> Result<TunnResult<'buf>, WireGuardError> {
+    ) -> Result<TunnResultRaw<'buf>, WireGuardError> {
         tracing::debug!(
-            message = "Received cookie_reply",
+            message = "Received message",
+            msg_type = "cookie_reply",
             local_idx = p.receiver_idx
         );
 
@@ -385,9 +541,7 @@ impl Tunn {
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeCookieReceived);
 
-        tracing::debug!("Did set cookie");
-
-        Ok(TunnResult::Done)
+        Ok(TunnResultRaw::Done)
     }
 
     /// Update the index of the currently used session, if needed
@@ -402,7 +556,7 @@ impl Tunn {
                 >= self.timers.session_timers[cur_idx % N_SESSIONS]
         {
             self.current = new_idx;
-            tracing::debug!(message = "New session", session = new_idx);
+            tracing::debug!(message = "New session", local_idx = new_idx);
         }
     }
 
@@ -411,7 +565,7 @@ impl Tunn {
         &mut self,
         packet: PacketData,
         dst: &'buf mut [u8],
-    ) -> Result<TunnResult<'buf>, WireGuardError> {
+    ) -> Result<TunnResultRaw<'buf>, WireGuardError> {
         let r_idx = packet.receiver_idx as usize;
         let idx = r_idx % N_SESSIONS;
 
@@ -419,10 +573,23 @@ impl Tunn {
         let decapsulated_packet = {
             let session = self.sessions[idx].as_ref();
             let session = session.ok_or_else(|| {
-                tracing::trace!(message = "No current session available", remote_idx = r_idx);
+                tracing::trace!(message = "No session for the message", local_idx = r_idx);
                 WireGuardError::NoCurrentSession
             })?;
-            session.receive_packet_data(packet, dst)?
+            session.receive_packet_data(packet, dst)
+        };
+        let decapsulated_packet = match decapsulated_packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                match e {
+                    WireGuardError::InvalidAeadTag => self.drops.rx_auth += 1,
+                    WireGuardError::InvalidCounter | WireGuardError::DuplicateCounter => {
+                        self.drops.rx_replay += 1
+                    }
+                    _ => {}
+                }
+                return Err(e);
+            }
         };
 
         self.set_current_session(r_idx);
@@ -438,9 +605,9 @@ impl Tunn {
         &mut self,
         dst: &'buf mut [u8],
         force_resend: bool,
-    ) -> TunnResult<'buf> {
+    ) -> TunnResultRaw<'buf> {
         if self.handshake.is_in_progress() && !force_resend {
-            return TunnResult::Done;
+            return TunnResultRaw::Done;
         }
 
         if self.handshake.is_expired() {
@@ -451,23 +618,40 @@ impl Tunn {
 
         match self.handshake.format_handshake_initiation(dst) {
             Ok(packet) => {
-                tracing::debug!("Sending handshake_initiation");
+                tracing::debug!(
+                    message = "Sending message",
+                    msg_type = "handshake_initiation"
+                );
 
                 if starting_new_handshake {
                     self.timer_tick(TimerName::TimeLastHandshakeStarted);
                 }
                 self.timer_tick(TimerName::TimeLastPacketSent);
-                TunnResult::WriteToNetwork(packet)
+                TunnResultRaw::WriteToNetwork(packet)
             }
-            Err(e) => TunnResult::Err(e),
+            Err(e) => TunnResultRaw::Err(e),
         }
     }
 
+    /// Like [`Tunn::format_handshake_initiation`]. Returns [`TunnAction::Noop`] if a handshake
+    /// is already in progress.
+    pub fn try_format_handshake_initiation<'buf>(
+        &mut self,
+        dst: &'buf mut [u8],
+        force_resend: bool,
+    ) -> Result<TunnAction<'buf>, TunnError> {
+        self.format_handshake_initiation(dst, force_resend)
+            .into_result(TunnAction::Noop)
+    }
+
     /// Check if an IP packet is v4 or v6, truncate to the length indicated by the length field
-    /// Returns the truncated packet and the source IP as TunnResult
-    fn validate_decapsulated_packet<'buf>(&mut self, packet: &'buf mut [u8]) -> TunnResult<'buf> {
+    /// Returns the truncated packet and the source IP as TunnResultRaw
+    fn validate_decapsulated_packet<'buf>(
+        &mut self,
+        packet: &'buf mut [u8],
+    ) -> TunnResultRaw<'buf> {
         let (computed_len, src_ip_address) = match packet.len() {
-            0 => return TunnResult::Done, // This is keepalive, and not an error
+            0 => return TunnResultRaw::Done, // This is keepalive, and not an error
             _ if packet[0] >> 4 == 4 && packet.len() >= IPV4_MIN_HEADER_SIZE => {
                 let len_bytes: [u8; IP_LEN_SZ] = packet[IPV4_LEN_OFF..IPV4_LEN_OFF + IP_LEN_SZ]
                     .try_into()
@@ -494,34 +678,34 @@ impl Tunn {
                     IpAddr::from(addr_bytes),
                 )
             }
-            _ => return TunnResult::Err(WireGuardError::InvalidPacket),
+            _ => return TunnResultRaw::Err(WireGuardError::InvalidPacket),
         };
 
         if computed_len > packet.len() {
-            return TunnResult::Err(WireGuardError::InvalidPacket);
+            return TunnResultRaw::Err(WireGuardError::InvalidPacket);
         }
 
         self.timer_tick(TimerName::TimeLastDataPacketReceived);
         self.rx_bytes += computed_len;
 
         match src_ip_address {
-            IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&mut packet[..computed_len], addr),
-            IpAddr::V6(addr) => TunnResult::WriteToTunnelV6(&mut packet[..computed_len], addr),
+            IpAddr::V4(addr) => TunnResultRaw::WriteToTunnelV4(&mut packet[..computed_len], addr),
+            IpAddr::V6(addr) => TunnResultRaw::WriteToTunnelV6(&mut packet[..computed_len], addr),
         }
     }
 
     /// Get a packet from the queue, and try to encapsulate it
-    fn send_queued_packet<'buf>(&mut self, dst: &'buf mut [u8]) -> TunnResult<'buf> {
+    fn send_queued_packet<'buf>(&mut self, dst: &'buf mut [u8]) -> TunnResultRaw<'buf> {
         if let Some(packet) = self.dequeue_packet() {
             match self.encapsulate(&packet, dst) {
-                TunnResult::Err(_) => {
+                TunnResultRaw::Err(_) => {
                     // On error, return packet to the queue
                     self.requeue_packet(packet);
                 }
                 r => return r,
             }
         }
-        TunnResult::Done
+        TunnResultRaw::Done
     }
 
     /// Push packet to the back of the queue
@@ -529,6 +713,8 @@ impl Tunn {
         if self.packet_queue.len() < MAX_QUEUE_DEPTH {
             // Drop if too many are already in queue
             self.packet_queue.push_back(packet.to_vec());
+        } else {
+            self.drops.tx_no_session += 1;
         }
     }
 
@@ -537,6 +723,8 @@ impl Tunn {
         if self.packet_queue.len() < MAX_QUEUE_DEPTH {
             // Drop if too many are already in queue
             self.packet_queue.push_front(packet);
+        } else {
+            self.drops.tx_no_session += 1;
         }
     }
 
@@ -583,217 +771,81 @@ impl Tunn {
         let tx_bytes = self.tx_bytes;
         let rx_bytes = self.rx_bytes;
         let loss = self.estimate_loss();
-        let rtt = self.handshake.last_rtt;
+        let rtt = self.handshake.last_rtt.map(|rtt| rtt.as_millis() as u32);
 
         (time, tx_bytes, rx_bytes, loss, rtt)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    #[cfg(feature = "mock-instant")]
-    use crate::noise::timers::{REKEY_AFTER_TIME, REKEY_TIMEOUT};
-
-    use super::*;
-    use rand_core::{OsRng, RngCore};
-
-    fn create_two_tuns() -> (Tunn, Tunn) {
-        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
-        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
-        let my_idx = OsRng.next_u32();
-
-        let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
-        let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
-        let their_idx = OsRng.next_u32();
-
-        let my_tun = Tunn::new(my_secret_key, their_public_key, None, None, my_idx, None).unwrap();
 
-        let their_tun =
-            Tunn::new(their_secret_key, my_public_key, None, None, their_idx, None).unwrap();
-
-        (my_tun, their_tun)
-    }
-
-    fn create_handshake_init(tun: &mut Tunn) -> Vec<u8> {
-        let mut dst = vec![0u8; 2048];
-        let handshake_init = tun.format_handshake_initiation(&mut dst, false);
-        assert!(matches!(handshake_init, TunnResult::WriteToNetwork(_)));
-        let handshake_init = if let TunnResult::WriteToNetwork(sent) = handshake_init {
-            sent
-        } else {
-            unreachable!();
-        };
-
-        handshake_init.into()
+    /// The round trip time of the last handshake this side initiated: from sending the initiation
+    /// to receiving its response. `None` until such a handshake completes.
+    pub fn handshake_rtt(&self) -> Option<Duration> {
+        self.handshake.last_rtt
     }
 
-    fn create_handshake_response(tun: &mut Tunn, handshake_init: &[u8]) -> Vec<u8> {
-        let mut dst = vec![0u8; 2048];
-        let handshake_resp = tun.decapsulate(None, handshake_init, &mut dst);
-        assert!(matches!(handshake_resp, TunnResult::WriteToNetwork(_)));
-
-        let handshake_resp = if let TunnResult::WriteToNetwork(sent) = handshake_resp {
-            sent
-        } else {
-            unreachable!();
-        };
-
-        handshake_resp.into()
+    /// The number of handshakes that completed, and of those that failed: the handshake messages
+    /// of the peer that were rejected, and the initiations that gave up without a response
+    pub fn handshake_stats(&self) -> (u64, u64) {
+        (self.handshakes_completed, self.handshake_failures)
     }
 
-    fn parse_handshake_resp(tun: &mut Tunn, handshake_resp: &[u8]) -> Vec<u8> {
-        let mut dst = vec![0u8; 2048];
-        let keepalive = tun.decapsulate(None, handshake_resp, &mut dst);
-        assert!(matches!(keepalive, TunnResult::WriteToNetwork(_)));
-
-        let keepalive = if let TunnResult::WriteToNetwork(sent) = keepalive {
-            sent
-        } else {
-            unreachable!();
-        };
-
-        keepalive.into()
+    /// The packets the tunnel dropped since it was created
+    pub fn drop_stats(&self) -> DropStats {
+        self.drops
     }
+}
 
-    fn parse_keepalive(tun: &mut Tunn, keepalive: &[u8]) {
-        let mut dst = vec![0u8; 2048];
-        let keepalive = tun.decapsulate(None, keepalive, &mut dst);
-        assert!(matches!(keepalive, TunnResult::Done));
+/// Entry points into the individual message handlers, bypassing the rate limiter, so the fuzzer
+/// can reach the parsing and cryptographic code directly.
+#[cfg(fuzzing)]
+impl Tunn {
+    pub fn consume_handshake_initiation<'buf>(
+        &mut self,
+        src: &[u8],
+        dst: &'buf mut [u8],
+    ) -> TunnResultRaw<'buf> {
+        match Tunn::parse_incoming_packet(src) {
+            Ok(Packet::HandshakeInit(p)) => self
+                .handle_handshake_init(p, dst)
+                .unwrap_or_else(TunnResultRaw::from),
+            Ok(_) => TunnResultRaw::Err(WireGuardError::WrongPacketType),
+            Err(e) => TunnResultRaw::Err(e),
+        }
     }
 
-    fn create_two_tuns_and_handshake() -> (Tunn, Tunn) {
-        let (mut my_tun, mut their_tun) = create_two_tuns();
-        let init = create_handshake_init(&mut my_tun);
-        let resp = create_handshake_response(&mut their_tun, &init);
-        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
-        parse_keepalive(&mut their_tun, &keepalive);
-
-        (my_tun, their_tun)
+    pub fn consume_handshake_response<'buf>(
+        &mut self,
+        src: &[u8],
+        dst: &'buf mut [u8],
+    ) -> TunnResultRaw<'buf> {
+        match Tunn::parse_incoming_packet(src) {
+            Ok(Packet::HandshakeResponse(p)) => self
+                .handle_handshake_response(p, dst)
+                .unwrap_or_else(TunnResultRaw::from),
+            Ok(_) => TunnResultRaw::Err(WireGuardError::WrongPacketType),
+            Err(e) => TunnResultRaw::Err(e),
+        }
     }
 
-    fn create_ipv4_udp_packet() -> Vec<u8> {
-        let header =
-            etherparse::PacketBuilder::ipv4([192, 168, 1, 2], [192, 168, 1, 3], 5).udp(5678, 23);
-        let payload = [0, 1, 2, 3];
-        let mut packet = Vec::<u8>::with_capacity(header.size(payload.len()));
-        header.write(&mut packet, &payload).unwrap();
-        packet
+    pub fn consume_cookie_reply<'buf>(&mut self, src: &[u8]) -> TunnResultRaw<'buf> {
+        match Tunn::parse_incoming_packet(src) {
+            Ok(Packet::PacketCookieReply(p)) => self
+                .handle_cookie_reply(p)
+                .unwrap_or_else(TunnResultRaw::from),
+            Ok(_) => TunnResultRaw::Err(WireGuardError::WrongPacketType),
+            Err(e) => TunnResultRaw::Err(e),
+        }
     }
 
-    #[cfg(feature = "mock-instant")]
-    fn update_timer_results_in_handshake(tun: &mut Tunn) {
-        let mut dst = vec![0u8; 2048];
-        let result = tun.update_timers(&mut dst);
-        assert!(matches!(result, TunnResult::WriteToNetwork(_)));
-        let packet_data = if let TunnResult::WriteToNetwork(data) = result {
-            data
-        } else {
-            unreachable!();
-        };
-        let packet = Tunn::parse_incoming_packet(packet_data).unwrap();
-        assert!(matches!(packet, Packet::HandshakeInit(_)));
-    }
-
-    #[test]
-    fn create_two_tunnels_linked_to_eachother() {
-        let (_my_tun, _their_tun) = create_two_tuns();
-    }
-
-    #[test]
-    fn handshake_init() {
-        let (mut my_tun, _their_tun) = create_two_tuns();
-        let init = create_handshake_init(&mut my_tun);
-        let packet = Tunn::parse_incoming_packet(&init).unwrap();
-        assert!(matches!(packet, Packet::HandshakeInit(_)));
-    }
-
-    #[test]
-    fn handshake_init_and_response() {
-        let (mut my_tun, mut their_tun) = create_two_tuns();
-        let init = create_handshake_init(&mut my_tun);
-        let resp = create_handshake_response(&mut their_tun, &init);
-        let packet = Tunn::parse_incoming_packet(&resp).unwrap();
-        assert!(matches!(packet, Packet::HandshakeResponse(_)));
-    }
-
-    #[test]
-    fn full_handshake() {
-        let (mut my_tun, mut their_tun) = create_two_tuns();
-        let init = create_handshake_init(&mut my_tun);
-        let resp = create_handshake_response(&mut their_tun, &init);
-        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
-        let packet = Tunn::parse_incoming_packet(&keepalive).unwrap();
-        assert!(matches!(packet, Packet::PacketData(_)));
-    }
-
-    #[test]
-    fn full_handshake_plus_timers() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        // Time has not yet advanced so their is nothing to do
-        assert!(matches!(my_tun.update_timers(&mut []), TunnResult::Done));
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
-    }
-
-    #[test]
-    #[cfg(feature = "mock-instant")]
-    fn new_handshake_after_two_mins() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        let mut my_dst = [0u8; 1024];
-
-        // Advance time 1 second and "send" 1 packet so that we send a handshake
-        // after the timeout
-        mock_instant::MockClock::advance(Duration::from_secs(1));
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
-        assert!(matches!(
-            my_tun.update_timers(&mut my_dst),
-            TunnResult::Done
-        ));
-        let sent_packet_buf = create_ipv4_udp_packet();
-        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
-        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
-
-        //Advance to timeout
-        mock_instant::MockClock::advance(REKEY_AFTER_TIME);
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
-        update_timer_results_in_handshake(&mut my_tun);
-    }
-
-    #[test]
-    #[cfg(feature = "mock-instant")]
-    fn handshake_no_resp_rekey_timeout() {
-        let (mut my_tun, _their_tun) = create_two_tuns();
-
-        let init = create_handshake_init(&mut my_tun);
-        let packet = Tunn::parse_incoming_packet(&init).unwrap();
-        assert!(matches!(packet, Packet::HandshakeInit(_)));
-
-        mock_instant::MockClock::advance(REKEY_TIMEOUT);
-        update_timer_results_in_handshake(&mut my_tun)
-    }
-
-    #[test]
-    fn one_ip_packet() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        let mut my_dst = [0u8; 1024];
-        let mut their_dst = [0u8; 1024];
-
-        let sent_packet_buf = create_ipv4_udp_packet();
-
-        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
-        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
-        let data = if let TunnResult::WriteToNetwork(sent) = data {
-            sent
-        } else {
-            unreachable!();
-        };
-
-        let data = their_tun.decapsulate(None, data, &mut their_dst);
-        assert!(matches!(data, TunnResult::WriteToTunnelV4(..)));
-        let recv_packet_buf = if let TunnResult::WriteToTunnelV4(recv, _addr) = data {
-            recv
-        } else {
-            unreachable!();
-        };
-        assert_eq!(sent_packet_buf, recv_packet_buf);
+    /// The full dispatch of a datagram received from the network, same as `decapsulate`
+    pub fn handle_incoming_packet<'buf>(
+        &mut self,
+        src_addr: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'buf mut [u8],
+    ) -> TunnResultRaw<'buf> {
+        self.decapsulate(src_addr, datagram, dst)
     }
 }
+
+#[cfg(test)]
+mod tests;
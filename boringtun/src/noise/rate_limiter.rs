@@ -1,14 +1,15 @@
 use super::handshake::{b2s_hash, b2s_keyed_mac_16, b2s_keyed_mac_16_2, b2s_mac_24};
 use crate::noise::handshake::{LABEL_COOKIE, LABEL_MAC1};
-use crate::noise::{HandshakeInit, HandshakeResponse, Packet, Tunn, TunnResult, WireGuardError};
+use crate::noise::{
+    Clock, HandshakeInit, HandshakeResponse, Packet, SystemClock, Tunn, TunnResultRaw,
+    WireGuardError,
+};
 
-#[cfg(feature = "mock-instant")]
-use mock_instant::Instant;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-
-#[cfg(not(feature = "mock-instant"))]
-use crate::sleepyinstant::Instant;
+use std::sync::Arc;
+use std::time::Duration;
 
 use aead::generic_array::GenericArray;
 use aead::{AeadInPlace, KeyInit};
@@ -24,11 +25,17 @@ const COOKIE_NONCE_SIZE: usize = 24;
 /// How often should reset count in seconds
 const RESET_PERIOD: u64 = 1;
 
+/// The number of sources whose handshake initiations are counted. Once there are as many, the
+/// initiations of new sources are answered with cookie replies until the next reset forgets the
+/// sources that are back to a full bucket.
+pub(crate) const MAX_SOURCES: usize = 16384;
+
 type Cookie = [u8; COOKIE_SIZE];
 
 /// There are two places where WireGuard requires "randomness" for cookies
 /// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
 /// * A secret value that changes every two minutes
+///
 /// Because the main goal of the cookie is simply for a party to prove ownership of an IP address
 /// we can relax the randomness definition a bit, in order to avoid locking, because using less
 /// resources is the main goal of any DoS prevention mechanism.
@@ -39,7 +46,9 @@ pub struct RateLimiter {
     nonce_key: [u8; 32],
     /// The key we use to derive the cookie
     secret_key: [u8; 16],
-    start_time: Instant,
+    clock: Arc<dyn Clock>,
+    /// See [`Clock::elapsed_since_epoch`]
+    start_time: Duration,
     /// A single 64 bit counter (should suffice for many years)
     nonce_ctr: AtomicU64,
     mac1_key: [u8; 32],
@@ -47,42 +56,117 @@ pub struct RateLimiter {
     limit: u64,
     /// The counter since last reset
     count: AtomicU64,
+    /// The number of reset periods with more packets than the limit, see
+    /// [`RateLimiter::under_load_seconds`]
+    under_load_periods: AtomicU64,
+    /// See [`RateLimiter::rate_limited`]
+    rate_limited: AtomicU64,
     /// The time last reset was performed on this rate limiter
-    last_reset: Mutex<Instant>,
+    last_reset: Mutex<Duration>,
+    /// See [`RateLimiter::set_source_limit`], 0 when unlimited
+    source_limit: u64,
+    sources: Mutex<HashMap<IpAddr, SourceBucket>>,
+}
+
+/// The handshake initiations a source may still send, refilled at the limit per second up to the
+/// limit
+struct SourceBucket {
+    tokens: f64,
+    refilled: Duration,
+}
+
+impl SourceBucket {
+    /// Refill the bucket up to `now`
+    fn refill(&mut self, now: Duration, limit: f64) {
+        let refill = now.saturating_sub(self.refilled).as_secs_f64() * limit;
+        self.tokens = (self.tokens + refill).min(limit);
+        self.refilled = now;
+    }
 }
 
 impl RateLimiter {
     pub fn new(public_key: &crate::x25519::PublicKey, limit: u64) -> Self {
+        RateLimiter::with_clock(public_key, limit, Arc::new(SystemClock::new()))
+    }
+
+    /// A rate limiter that reads the time from `clock`, like the [`Tunn`] it is used by
+    pub fn with_clock(
+        public_key: &crate::x25519::PublicKey,
+        limit: u64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         let mut secret_key = [0u8; 16];
         OsRng.fill_bytes(&mut secret_key);
+        let now = clock.elapsed_since_epoch();
         RateLimiter {
             nonce_key: Self::rand_bytes(),
             secret_key,
-            start_time: Instant::now(),
+            clock,
+            start_time: now,
             nonce_ctr: AtomicU64::new(0),
             mac1_key: b2s_hash(LABEL_MAC1, public_key.as_bytes()),
             cookie_key: b2s_hash(LABEL_COOKIE, public_key.as_bytes()).into(),
             limit,
             count: AtomicU64::new(0),
-            last_reset: Mutex::new(Instant::now()),
+            under_load_periods: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            last_reset: Mutex::new(now),
+            source_limit: 0,
+            sources: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Limit the handshake initiations of each source IP to `per_second`, with bursts of as many,
+    /// no matter the load. The initiations over the limit are answered with cookie replies like
+    /// those over the limit of all sources, even those with a valid mac2: it proves the initiator
+    /// owns its address, which only lifts the limit of all sources. 0 means unlimited.
+    pub fn set_source_limit(&mut self, per_second: u64) {
+        self.source_limit = per_second;
+    }
+
     fn rand_bytes() -> [u8; 32] {
         let mut key = [0u8; 32];
         OsRng.fill_bytes(&mut key);
         key
     }
 
-    /// Reset packet count (ideally should be called with a period of 1 second)
+    /// Reset packet count if it is at least a second old, this also happens on every handshake
     pub fn reset_count(&self) {
         // The rate limiter is not very accurate, but at the scale we care about it doesn't matter much
-        let current_time = Instant::now();
+        let current_time = self.clock.elapsed_since_epoch();
         let mut last_reset_time = self.last_reset.lock();
-        if current_time.duration_since(*last_reset_time).as_secs() >= RESET_PERIOD {
-            self.count.store(0, Ordering::SeqCst);
+        if current_time.saturating_sub(*last_reset_time).as_secs() >= RESET_PERIOD {
+            if self.count.swap(0, Ordering::SeqCst) > self.limit {
+                self.under_load_periods.fetch_add(1, Ordering::Relaxed);
+            }
             *last_reset_time = current_time;
+            self.forget_idle_sources(current_time);
+        }
+    }
+
+    /// Drop the buckets that are full again, which are the same as no bucket. A bucket refills
+    /// within a second, so this leaves the sources that sent initiations since the last reset.
+    fn forget_idle_sources(&self, now: Duration) {
+        if self.source_limit == 0 {
+            return;
         }
+        let limit = self.source_limit as f64;
+        self.sources.lock().retain(|_, bucket| {
+            bucket.refill(now, limit);
+            bucket.tokens < limit
+        });
+    }
+
+    /// How many seconds the rate limiter had more handshakes than its limit, and answered some
+    /// of them with cookie replies instead
+    pub fn under_load_seconds(&self) -> u64 {
+        self.under_load_periods.load(Ordering::Relaxed) * RESET_PERIOD
+    }
+
+    /// The number of handshake messages that were answered with a cookie reply, or dropped
+    /// without an address to send one to, while under load
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
     }
 
     /// Compute the correct cookie value based on the current secret value and the source IP
@@ -96,7 +180,12 @@ impl RateLimiter {
 
         // The current cookie for a given IP is the MAC(responder.changing_secret_every_two_minutes, initiator.ip_address)
         // First we derive the secret from the current time, the value of cur_counter would change with time.
-        let cur_counter = Instant::now().duration_since(self.start_time).as_secs() / COOKIE_REFRESH;
+        let cur_counter = self
+            .clock
+            .elapsed_since_epoch()
+            .saturating_sub(self.start_time)
+            .as_secs()
+            / COOKIE_REFRESH;
 
         // Next we derive the cookie
         b2s_keyed_mac_16_2(&self.secret_key, &cur_counter.to_le_bytes(), &addr_bytes)
@@ -108,7 +197,30 @@ impl RateLimiter {
         b2s_mac_24(&self.nonce_key, &ctr.to_le_bytes())
     }
 
+    /// Take a token from the bucket of `addr`, false if it's empty, or if there is no room for
+    /// the bucket of a new source until the next reset
+    fn source_allows(&self, addr: IpAddr) -> bool {
+        let limit = self.source_limit as f64;
+        let now = self.clock.elapsed_since_epoch();
+        let mut sources = self.sources.lock();
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&addr) {
+            return false;
+        }
+        let bucket = sources.entry(addr).or_insert(SourceBucket {
+            tokens: limit,
+            refilled: now,
+        });
+        bucket.refill(now, limit);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
     fn is_under_load(&self) -> bool {
+        // Nothing resets the count periodically while the device is idle
+        self.reset_count();
         self.count.fetch_add(1, Ordering::SeqCst) >= self.limit
     }
 
@@ -155,7 +267,7 @@ impl RateLimiter {
         src_addr: Option<IpAddr>,
         src: &'src_buf [u8],
         dst: &'dst_buf mut [u8],
-    ) -> Result<Packet<'src_buf>, TunnResult<'dst_buf>> {
+    ) -> Result<Packet<'src_buf>, TunnResultRaw<'dst_buf>> {
         let packet = Tunn::parse_incoming_packet(src)?;
 
         // Verify and rate limit handshake messages only
@@ -167,24 +279,38 @@ impl RateLimiter {
 
             let computed_mac1 = b2s_keyed_mac_16(&self.mac1_key, msg);
             verify_slices_are_equal(&computed_mac1[..16], mac1)
-                .map_err(|_| TunnResult::Err(WireGuardError::InvalidMac))?;
-
-            if self.is_under_load() {
-                let addr = match src_addr {
-                    None => return Err(TunnResult::Err(WireGuardError::UnderLoad)),
-                    Some(addr) => addr,
-                };
-
-                // Only given an address can we validate mac2
-                let cookie = self.current_cookie(addr);
-                let computed_mac2 = b2s_keyed_mac_16_2(&cookie, msg, mac1);
-
-                if verify_slices_are_equal(&computed_mac2[..16], mac2).is_err() {
-                    let cookie_packet = self
-                        .format_cookie_reply(sender_idx, cookie, mac1, dst)
-                        .map_err(TunnResult::Err)?;
-                    return Err(TunnResult::WriteToNetwork(cookie_packet));
+                .map_err(|_| TunnResultRaw::Err(WireGuardError::InvalidMac))?;
+
+            let under_load = self.is_under_load();
+            let source_limited =
+                matches!(packet, Packet::HandshakeInit(_)) && self.source_limit != 0;
+            if !under_load && !source_limited {
+                return Ok(packet);
+            }
+
+            let addr = match src_addr {
+                None if under_load => {
+                    self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    return Err(TunnResultRaw::Err(WireGuardError::UnderLoad));
                 }
+                None => return Ok(packet),
+                Some(addr) => addr,
+            };
+
+            // Only given an address can we validate mac2, which proves the initiator owns the
+            // address, and lifts the limit of all sources. The limit of the source holds either
+            // way, like the per-IP limit of the kernel does for packets with a valid cookie.
+            let cookie = self.current_cookie(addr);
+            let computed_mac2 = b2s_keyed_mac_16_2(&cookie, msg, mac1);
+            let mac2_valid = verify_slices_are_equal(&computed_mac2[..16], mac2).is_ok();
+            let source_allowed = !source_limited || self.source_allows(addr);
+
+            if !source_allowed || (under_load && !mac2_valid) {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                let cookie_packet = self
+                    .format_cookie_reply(sender_idx, cookie, mac1, dst)
+                    .map_err(TunnResultRaw::Err)?;
+                return Err(TunnResultRaw::WriteToNetwork(cookie_packet));
             }
         }
 
@@ -2,16 +2,37 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use super::PacketData;
+use crate::crypto::CryptoProvider;
 use crate::noise::errors::WireGuardError;
 use parking_lot::Mutex;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The key of one direction of a session, used with ring unless a crypto provider is set
+// Boxing the ring key would cost an indirection for every packet
+#[allow(clippy::large_enum_variant)]
+enum SessionKey {
+    Builtin(LessSafeKey),
+    Provider(Arc<dyn CryptoProvider>, [u8; 32]),
+}
+
+impl SessionKey {
+    fn new(key: [u8; 32], crypto_provider: Option<&Arc<dyn CryptoProvider>>) -> SessionKey {
+        match crypto_provider {
+            Some(provider) => SessionKey::Provider(Arc::clone(provider), key),
+            None => SessionKey::Builtin(LessSafeKey::new(
+                UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap(),
+            )),
+        }
+    }
+}
 
 pub struct Session {
     pub(crate) receiving_index: u32,
     sending_index: u32,
-    receiver: LessSafeKey,
-    sender: LessSafeKey,
+    receiver: SessionKey,
+    sender: SessionKey,
     sending_key_counter: AtomicUsize,
     receiving_key_counter: Mutex<ReceivingKeyCounterValidator>,
 }
@@ -129,7 +150,7 @@ impl ReceivingKeyCounterValidator {
             }
         } else {
             let mut i = self.next;
-            while i % WORD_SIZE != 0 && i < counter {
+            while !i.is_multiple_of(WORD_SIZE) && i < counter {
                 // Clear until i aligned to word size
                 self.clear_bit(i);
                 i += 1;
@@ -157,14 +178,13 @@ impl Session {
         peer_index: u32,
         receiving_key: [u8; 32],
         sending_key: [u8; 32],
+        crypto_provider: Option<&Arc<dyn CryptoProvider>>,
     ) -> Session {
         Session {
             receiving_index: local_index,
             sending_index: peer_index,
-            receiver: LessSafeKey::new(
-                UnboundKey::new(&CHACHA20_POLY1305, &receiving_key).unwrap(),
-            ),
-            sender: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &sending_key).unwrap()),
+            receiver: SessionKey::new(receiving_key, crypto_provider),
+            sender: SessionKey::new(sending_key, crypto_provider),
             sending_key_counter: AtomicUsize::new(0),
             receiving_key_counter: Mutex::new(Default::default()),
         }
@@ -197,7 +217,7 @@ impl Session {
         &self,
         src: &[u8],
         dst: &'dst_buf mut [u8],
-    ) -> &'dst_buf mut [u8] {
+    ) -> Result<&'dst_buf mut [u8], WireGuardError> {
         if dst.len() < src.len() + super::DATA_OVERHEAD_SZ {
             panic!("The destination buffer is too small");
         }
@@ -213,24 +233,38 @@ impl Session {
         counter.copy_from_slice(&sending_key_counter.to_le_bytes());
 
         // TODO: spec requires padding to 16 bytes, but actually works fine without it
-        let n = {
-            let mut nonce = [0u8; 12];
-            nonce[4..12].copy_from_slice(&sending_key_counter.to_le_bytes());
-            data[..src.len()].copy_from_slice(src);
-            self.sender
-                .seal_in_place_separate_tag(
-                    Nonce::assume_unique_for_key(nonce),
-                    Aad::from(&[]),
-                    &mut data[..src.len()],
-                )
-                .map(|tag| {
-                    data[src.len()..src.len() + AEAD_SIZE].copy_from_slice(tag.as_ref());
-                    src.len() + AEAD_SIZE
-                })
-                .unwrap()
+        let n = match &self.sender {
+            SessionKey::Builtin(sender) => {
+                let mut nonce = [0u8; 12];
+                nonce[4..12].copy_from_slice(&sending_key_counter.to_le_bytes());
+                data[..src.len()].copy_from_slice(src);
+                sender
+                    .seal_in_place_separate_tag(
+                        Nonce::assume_unique_for_key(nonce),
+                        Aad::from(&[]),
+                        &mut data[..src.len()],
+                    )
+                    .map(|tag| {
+                        data[src.len()..src.len() + AEAD_SIZE].copy_from_slice(tag.as_ref());
+                        src.len() + AEAD_SIZE
+                    })
+                    .unwrap()
+            }
+            SessionKey::Provider(provider, key) => {
+                provider
+                    .chacha20poly1305_seal(
+                        key,
+                        sending_key_counter,
+                        &[],
+                        src,
+                        &mut data[..src.len() + AEAD_SIZE],
+                    )
+                    .map_err(|_| WireGuardError::CryptoProviderFailed)?;
+                src.len() + AEAD_SIZE
+            }
         };
 
-        &mut dst[..DATA_OFFSET + n]
+        Ok(&mut dst[..DATA_OFFSET + n])
     }
 
     /// packet - a data packet we received from the network
@@ -253,17 +287,34 @@ impl Session {
         // Don't reuse counters, in case this is a replay attack we want to quickly check the counter without running expensive decryption
         self.receiving_counter_quick_check(packet.counter)?;
 
-        let ret = {
-            let mut nonce = [0u8; 12];
-            nonce[4..12].copy_from_slice(&packet.counter.to_le_bytes());
-            dst[..ct_len].copy_from_slice(packet.encrypted_encapsulated_packet);
-            self.receiver
-                .open_in_place(
-                    Nonce::assume_unique_for_key(nonce),
-                    Aad::from(&[]),
-                    &mut dst[..ct_len],
-                )
-                .map_err(|_| WireGuardError::InvalidAeadTag)?
+        let ret = match &self.receiver {
+            SessionKey::Builtin(receiver) => {
+                let mut nonce = [0u8; 12];
+                nonce[4..12].copy_from_slice(&packet.counter.to_le_bytes());
+                dst[..ct_len].copy_from_slice(packet.encrypted_encapsulated_packet);
+                receiver
+                    .open_in_place(
+                        Nonce::assume_unique_for_key(nonce),
+                        Aad::from(&[]),
+                        &mut dst[..ct_len],
+                    )
+                    .map_err(|_| WireGuardError::InvalidAeadTag)?
+            }
+            SessionKey::Provider(provider, key) => {
+                let len = ct_len
+                    .checked_sub(AEAD_SIZE)
+                    .ok_or(WireGuardError::InvalidAeadTag)?;
+                provider
+                    .chacha20poly1305_open(
+                        key,
+                        packet.counter,
+                        &[],
+                        packet.encrypted_encapsulated_packet,
+                        &mut dst[..len],
+                    )
+                    .map_err(|_| WireGuardError::InvalidAeadTag)?;
+                &mut dst[..len]
+            }
         };
 
         // After decryption is done, check counter again, and mark as received
@@ -0,0 +1,794 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+#[cfg(feature = "mock-instant")]
+use crate::noise::timers::REKEY_TIMEOUT;
+
+use super::*;
+use crate::crypto::{CryptoError, SoftwareCryptoProvider};
+use crate::noise::timers::{REJECT_AFTER_TIME, REKEY_AFTER_TIME};
+use rand_core::{OsRng, RngCore};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// A clock that only moves when told to
+#[derive(Debug, Default)]
+struct ManualClock(parking_lot::Mutex<Duration>);
+
+impl ManualClock {
+    fn advance(&self, by: Duration) {
+        *self.0.lock() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000) + *self.0.lock()
+    }
+
+    fn elapsed_since_epoch(&self) -> Duration {
+        *self.0.lock()
+    }
+}
+
+fn create_two_tuns() -> (Tunn, Tunn) {
+    create_two_tuns_with_clock(None)
+}
+
+fn create_two_tuns_with_clock(clock: Option<Arc<dyn Clock>>) -> (Tunn, Tunn) {
+    let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+    let my_idx = OsRng.next_u32();
+
+    let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+    let their_idx = OsRng.next_u32();
+
+    let my_tun = Tunn::new(
+        my_secret_key,
+        their_public_key,
+        None,
+        None,
+        my_idx,
+        None,
+        clock.clone(),
+    )
+    .unwrap();
+
+    let their_tun = Tunn::new(
+        their_secret_key,
+        my_public_key,
+        None,
+        None,
+        their_idx,
+        None,
+        clock,
+    )
+    .unwrap();
+
+    (my_tun, their_tun)
+}
+
+fn create_handshake_init(tun: &mut Tunn) -> Vec<u8> {
+    let mut dst = vec![0u8; 2048];
+    let handshake_init = tun.format_handshake_initiation(&mut dst, false);
+    assert!(matches!(handshake_init, TunnResultRaw::WriteToNetwork(_)));
+    let handshake_init = if let TunnResultRaw::WriteToNetwork(sent) = handshake_init {
+        sent
+    } else {
+        unreachable!();
+    };
+
+    handshake_init.into()
+}
+
+fn create_handshake_response(tun: &mut Tunn, handshake_init: &[u8]) -> Vec<u8> {
+    let mut dst = vec![0u8; 2048];
+    let handshake_resp = tun.decapsulate(None, handshake_init, &mut dst);
+    assert!(matches!(handshake_resp, TunnResultRaw::WriteToNetwork(_)));
+
+    let handshake_resp = if let TunnResultRaw::WriteToNetwork(sent) = handshake_resp {
+        sent
+    } else {
+        unreachable!();
+    };
+
+    handshake_resp.into()
+}
+
+fn parse_handshake_resp(tun: &mut Tunn, handshake_resp: &[u8]) -> Vec<u8> {
+    let mut dst = vec![0u8; 2048];
+    let keepalive = tun.decapsulate(None, handshake_resp, &mut dst);
+    assert!(matches!(keepalive, TunnResultRaw::WriteToNetwork(_)));
+
+    let keepalive = if let TunnResultRaw::WriteToNetwork(sent) = keepalive {
+        sent
+    } else {
+        unreachable!();
+    };
+
+    keepalive.into()
+}
+
+fn parse_keepalive(tun: &mut Tunn, keepalive: &[u8]) {
+    let mut dst = vec![0u8; 2048];
+    let keepalive = tun.decapsulate(None, keepalive, &mut dst);
+    assert!(matches!(keepalive, TunnResultRaw::Done));
+}
+
+fn create_two_tuns_and_handshake() -> (Tunn, Tunn) {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+    parse_keepalive(&mut their_tun, &keepalive);
+
+    (my_tun, their_tun)
+}
+
+fn create_ipv4_udp_packet() -> Vec<u8> {
+    let header =
+        etherparse::PacketBuilder::ipv4([192, 168, 1, 2], [192, 168, 1, 3], 5).udp(5678, 23);
+    let payload = [0, 1, 2, 3];
+    let mut packet = Vec::<u8>::with_capacity(header.size(payload.len()));
+    header.write(&mut packet, &payload).unwrap();
+    packet
+}
+
+fn update_timer_results_in_handshake(tun: &mut Tunn) {
+    let mut dst = vec![0u8; 2048];
+    let result = tun.update_timers(&mut dst);
+    assert!(matches!(result, TunnResultRaw::WriteToNetwork(_)));
+    let packet_data = if let TunnResultRaw::WriteToNetwork(data) = result {
+        data
+    } else {
+        unreachable!();
+    };
+    let packet = Tunn::parse_incoming_packet(packet_data).unwrap();
+    assert!(matches!(packet, Packet::HandshakeInit(_)));
+}
+
+#[test]
+fn create_two_tunnels_linked_to_eachother() {
+    let (_my_tun, _their_tun) = create_two_tuns();
+}
+
+#[test]
+fn handshake_init() {
+    let (mut my_tun, _their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let packet = Tunn::parse_incoming_packet(&init).unwrap();
+    assert!(matches!(packet, Packet::HandshakeInit(_)));
+}
+
+#[test]
+fn handshake_init_and_response() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    let packet = Tunn::parse_incoming_packet(&resp).unwrap();
+    assert!(matches!(packet, Packet::HandshakeResponse(_)));
+}
+
+#[test]
+fn full_handshake() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+    let packet = Tunn::parse_incoming_packet(&keepalive).unwrap();
+    assert!(matches!(packet, Packet::PacketData(_)));
+}
+
+#[test]
+fn full_handshake_plus_timers() {
+    let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+    // Time has not yet advanced so their is nothing to do
+    assert!(matches!(my_tun.update_timers(&mut []), TunnResultRaw::Done));
+    assert!(matches!(
+        their_tun.update_timers(&mut []),
+        TunnResultRaw::Done
+    ));
+}
+
+#[test]
+fn handshake_stats() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    parse_handshake_resp(&mut my_tun, &resp);
+    assert_eq!(my_tun.handshake_stats(), (1, 0));
+    assert_eq!(their_tun.handshake_stats(), (1, 0));
+
+    // A replayed initiation is rejected
+    let mut dst = vec![0u8; 2048];
+    let replayed = their_tun.decapsulate(None, &init, &mut dst);
+    assert!(matches!(replayed, TunnResultRaw::Err(_)));
+    assert_eq!(their_tun.handshake_stats(), (1, 1));
+}
+
+#[test]
+fn handshake_rtt() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    assert_eq!(my_tun.handshake_rtt(), None);
+    parse_handshake_resp(&mut my_tun, &resp);
+    assert!(my_tun.handshake_rtt().is_some());
+    // Only the initiator measures it
+    assert_eq!(their_tun.handshake_rtt(), None);
+}
+
+#[test]
+fn injected_clock() {
+    let clock = Arc::new(ManualClock::default());
+    let (mut my_tun, mut their_tun) = create_two_tuns_with_clock(Some(clock.clone()));
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    clock.advance(Duration::from_millis(30));
+    parse_handshake_resp(&mut my_tun, &resp);
+    assert_eq!(my_tun.handshake_rtt(), Some(Duration::from_millis(30)));
+
+    // The timers only follow the clock
+    clock.advance(Duration::from_secs(1));
+    let mut my_dst = [0u8; 1024];
+    let data = my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst);
+    assert!(matches!(data, TunnResultRaw::WriteToNetwork(_)));
+    assert_eq!(
+        my_tun.time_since_last_handshake(),
+        Some(Duration::from_secs(1))
+    );
+    clock.advance(REKEY_AFTER_TIME);
+    update_timer_results_in_handshake(&mut my_tun);
+}
+
+#[test]
+#[cfg(feature = "mock-instant")]
+fn new_handshake_after_two_mins() {
+    let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+    let mut my_dst = [0u8; 1024];
+
+    // Advance time 1 second and "send" 1 packet so that we send a handshake
+    // after the timeout
+    mock_instant::MockClock::advance(Duration::from_secs(1));
+    assert!(matches!(
+        their_tun.update_timers(&mut []),
+        TunnResultRaw::Done
+    ));
+    assert!(matches!(
+        my_tun.update_timers(&mut my_dst),
+        TunnResultRaw::Done
+    ));
+    let sent_packet_buf = create_ipv4_udp_packet();
+    let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+    assert!(matches!(data, TunnResultRaw::WriteToNetwork(_)));
+
+    //Advance to timeout
+    mock_instant::MockClock::advance(REKEY_AFTER_TIME);
+    assert!(matches!(
+        their_tun.update_timers(&mut []),
+        TunnResultRaw::Done
+    ));
+    update_timer_results_in_handshake(&mut my_tun);
+}
+
+#[test]
+#[cfg(feature = "mock-instant")]
+fn proactive_rekey_by_responder() {
+    let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+    let lead_time = Duration::from_secs(10);
+    their_tun.set_proactive_rekey_lead_time(Some(lead_time));
+    let mut my_dst = [0u8; 1024];
+    let mut their_dst = [0u8; 1024];
+
+    // Only the responder sends data, the initiator answers with a keepalive
+    mock_instant::MockClock::advance(Duration::from_secs(1));
+    let data = match their_tun.encapsulate(&create_ipv4_udp_packet(), &mut their_dst) {
+        TunnResultRaw::WriteToNetwork(data) => data.to_vec(),
+        _ => unreachable!(),
+    };
+    my_tun.decapsulate(None, &data, &mut my_dst);
+    let keepalive = match my_tun.encapsulate(&[], &mut my_dst) {
+        TunnResultRaw::WriteToNetwork(keepalive) => keepalive.to_vec(),
+        _ => unreachable!(),
+    };
+    parse_keepalive(&mut their_tun, &keepalive);
+
+    let next = their_tun.time_to_next_timer().unwrap();
+    assert_eq!(next, REJECT_AFTER_TIME - lead_time - Duration::from_secs(1));
+    mock_instant::MockClock::advance(next - Duration::from_secs(1));
+    assert!(matches!(
+        their_tun.update_timers(&mut their_dst),
+        TunnResultRaw::Done
+    ));
+    mock_instant::MockClock::advance(Duration::from_secs(1));
+    update_timer_results_in_handshake(&mut their_tun);
+
+    // The session is still used meanwhile
+    let data = their_tun.encapsulate(&create_ipv4_udp_packet(), &mut their_dst);
+    assert!(matches!(data, TunnResultRaw::WriteToNetwork(_)));
+}
+
+#[test]
+fn proactive_rekey_idle_session() {
+    let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+    // Without data on the session, nothing is due before it expires
+    my_tun.set_proactive_rekey_lead_time(Some(Duration::from_secs(10)));
+    let next = my_tun.time_to_next_timer().unwrap();
+    assert!(next > REJECT_AFTER_TIME - Duration::from_secs(10));
+}
+
+#[test]
+#[cfg(feature = "mock-instant")]
+fn handshake_no_resp_rekey_timeout() {
+    let (mut my_tun, _their_tun) = create_two_tuns();
+
+    let init = create_handshake_init(&mut my_tun);
+    let packet = Tunn::parse_incoming_packet(&init).unwrap();
+    assert!(matches!(packet, Packet::HandshakeInit(_)));
+
+    mock_instant::MockClock::advance(REKEY_TIMEOUT);
+    update_timer_results_in_handshake(&mut my_tun)
+}
+
+#[test]
+fn time_to_next_timer_after_handshake() {
+    let (my_tun, their_tun) = create_two_tuns_and_handshake();
+    // Nothing is due before the session expires
+    for tun in [my_tun, their_tun] {
+        let next = tun.time_to_next_timer().unwrap();
+        assert!(next > Duration::from_secs(60));
+        assert!(next <= REJECT_AFTER_TIME);
+    }
+}
+
+#[test]
+#[cfg(feature = "mock-instant")]
+fn handshake_retry_at_next_timer() {
+    let (mut my_tun, _their_tun) = create_two_tuns();
+    create_handshake_init(&mut my_tun);
+
+    let next = my_tun.time_to_next_timer().unwrap();
+    assert!(next <= REKEY_TIMEOUT);
+    mock_instant::MockClock::advance(next);
+    update_timer_results_in_handshake(&mut my_tun)
+}
+
+#[test]
+#[cfg(feature = "mock-instant")]
+fn handshake_retry_exponential_backoff() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    my_tun.set_reconnect_policy(ReconnectPolicy::Exponential {
+        initial: Duration::from_secs(1),
+        multiplier: 2.0,
+        max: Duration::from_secs(4),
+    });
+    create_handshake_init(&mut my_tun);
+    assert_eq!(my_tun.next_retry_in(), Some(Duration::from_secs(1)));
+
+    // Each retry waits twice as long, up to the maximum
+    for interval in [1, 2, 4, 4] {
+        let interval = Duration::from_secs(interval);
+        assert_eq!(my_tun.next_retry_in(), Some(interval));
+        mock_instant::MockClock::advance(interval - Duration::from_millis(1));
+        let mut dst = vec![0u8; 2048];
+        assert!(matches!(
+            my_tun.update_timers(&mut dst),
+            TunnResultRaw::Done
+        ));
+        mock_instant::MockClock::advance(Duration::from_millis(1));
+        update_timer_results_in_handshake(&mut my_tun);
+    }
+
+    // A completed handshake starts over from the initial interval
+    mock_instant::MockClock::advance(Duration::from_secs(4));
+    let mut dst = vec![0u8; 2048];
+    let init = match my_tun.update_timers(&mut dst) {
+        TunnResultRaw::WriteToNetwork(init) => init.to_vec(),
+        _ => unreachable!(),
+    };
+    let resp = create_handshake_response(&mut their_tun, &init);
+    parse_handshake_resp(&mut my_tun, &resp);
+    assert_eq!(my_tun.next_retry_in(), None);
+    mock_instant::MockClock::advance(REKEY_AFTER_TIME);
+    create_handshake_init(&mut my_tun);
+    assert_eq!(my_tun.next_retry_in(), Some(Duration::from_secs(1)));
+}
+
+#[test]
+fn one_ip_packet() {
+    let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+    let mut my_dst = [0u8; 1024];
+    let mut their_dst = [0u8; 1024];
+
+    let sent_packet_buf = create_ipv4_udp_packet();
+
+    let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+    assert!(matches!(data, TunnResultRaw::WriteToNetwork(_)));
+    let data = if let TunnResultRaw::WriteToNetwork(sent) = data {
+        sent
+    } else {
+        unreachable!();
+    };
+
+    let data = their_tun.decapsulate(None, data, &mut their_dst);
+    assert!(matches!(data, TunnResultRaw::WriteToTunnelV4(..)));
+    let recv_packet_buf = if let TunnResultRaw::WriteToTunnelV4(recv, _addr) = data {
+        recv
+    } else {
+        unreachable!();
+    };
+    assert_eq!(sent_packet_buf, recv_packet_buf);
+}
+
+#[test]
+fn handshake_initiations_rate_limited_per_source() {
+    let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+    let mut my_tun = Tunn::new(my_secret_key, their_public_key, None, None, 1, None, None).unwrap();
+    let clock = Arc::new(ManualClock::default());
+    let mut rate_limiter = RateLimiter::with_clock(&their_public_key, u64::MAX, clock.clone());
+    rate_limiter.set_source_limit(2);
+
+    let init = create_handshake_init(&mut my_tun);
+    let (source, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+    let mut dst = [0u8; 256];
+    for _ in 0..2 {
+        let packet = rate_limiter.verify_packet(Some(source), &init, &mut dst);
+        assert!(matches!(packet, Ok(Packet::HandshakeInit(_))));
+    }
+
+    // The initiation over the limit is answered with a cookie the initiator accepts
+    let cookie_reply = match rate_limiter.verify_packet(Some(source), &init, &mut dst) {
+        Err(TunnResultRaw::WriteToNetwork(packet)) => packet.to_vec(),
+        _ => panic!("The initiation wasn't rate limited"),
+    };
+    assert!(matches!(
+        Tunn::parse_incoming_packet(&cookie_reply),
+        Ok(Packet::PacketCookieReply(_))
+    ));
+    assert!(matches!(
+        my_tun.decapsulate(None, &cookie_reply, &mut dst),
+        TunnResultRaw::Done
+    ));
+    assert_eq!(rate_limiter.rate_limited(), 1);
+
+    // Other sources have their own limit
+    let packet = rate_limiter.verify_packet(Some(other), &init, &mut dst);
+    assert!(matches!(packet, Ok(Packet::HandshakeInit(_))));
+
+    // With the cookie, the initiator proves it owns its address, but its source is still limited
+    let mut init_dst = [0u8; 256];
+    let init_with_cookie = match my_tun.format_handshake_initiation(&mut init_dst, true) {
+        TunnResultRaw::WriteToNetwork(packet) => packet.to_vec(),
+        _ => panic!("No handshake initiation"),
+    };
+    let packet = rate_limiter.verify_packet(Some(source), &init_with_cookie, &mut dst);
+    assert!(matches!(packet, Err(TunnResultRaw::WriteToNetwork(_))));
+    assert_eq!(rate_limiter.rate_limited(), 2);
+
+    // The bucket refills at the limit per second
+    clock.advance(Duration::from_millis(500));
+    let packet = rate_limiter.verify_packet(Some(source), &init, &mut dst);
+    assert!(matches!(packet, Ok(Packet::HandshakeInit(_))));
+    assert!(rate_limiter
+        .verify_packet(Some(source), &init, &mut dst)
+        .is_err());
+
+    // Over the limit of all sources, but not of its own, the cookie decides between a reply and
+    // the initiation
+    let mut loaded = RateLimiter::with_clock(&their_public_key, 0, clock.clone());
+    loaded.set_source_limit(2);
+    let cookie_reply = match loaded.verify_packet(Some(source), &init_with_cookie, &mut dst) {
+        Err(TunnResultRaw::WriteToNetwork(packet)) => packet.to_vec(),
+        _ => panic!("The initiation wasn't rate limited"),
+    };
+    assert!(matches!(
+        my_tun.decapsulate(None, &cookie_reply, &mut dst),
+        TunnResultRaw::Done
+    ));
+    let init_with_cookie = match my_tun.format_handshake_initiation(&mut init_dst, true) {
+        TunnResultRaw::WriteToNetwork(packet) => packet.to_vec(),
+        _ => panic!("No handshake initiation"),
+    };
+    let packet = loaded.verify_packet(Some(source), &init_with_cookie, &mut dst);
+    assert!(matches!(packet, Ok(Packet::HandshakeInit(_))));
+}
+
+#[test]
+fn handshake_initiation_sources_bounded() {
+    let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+    let mut my_tun = Tunn::new(my_secret_key, their_public_key, None, None, 1, None, None).unwrap();
+    let clock = Arc::new(ManualClock::default());
+    let mut rate_limiter = RateLimiter::with_clock(&their_public_key, u64::MAX, clock.clone());
+    rate_limiter.set_source_limit(1);
+
+    let init = create_handshake_init(&mut my_tun);
+    let mut dst = [0u8; 256];
+    let mut verify = |source: u32| {
+        let source = std::net::Ipv4Addr::from(0x0a00_0000 + source).into();
+        rate_limiter
+            .verify_packet(Some(source), &init, &mut dst)
+            .is_ok()
+    };
+    for source in 0..rate_limiter::MAX_SOURCES as u32 {
+        assert!(verify(source));
+    }
+
+    // No room for a new source until the next reset forgets the idle ones
+    let new_source = rate_limiter::MAX_SOURCES as u32;
+    assert!(!verify(new_source));
+    clock.advance(Duration::from_millis(999));
+    assert!(!verify(new_source));
+    clock.advance(Duration::from_millis(1));
+    assert!(verify(new_source));
+    assert!(verify(0));
+}
+
+#[test]
+fn drop_stats() {
+    let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+    let mut my_dst = [0u8; 1024];
+    let mut their_dst = [0u8; 1024];
+
+    let mut encapsulate = || match my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst) {
+        TunnResultRaw::WriteToNetwork(sent) => sent.to_vec(),
+        _ => unreachable!(),
+    };
+    let data = encapsulate();
+    let decapsulated = their_tun.decapsulate(None, &data, &mut their_dst);
+    assert!(matches!(decapsulated, TunnResultRaw::WriteToTunnelV4(..)));
+
+    // The same packet again is a replay
+    let replayed = their_tun.decapsulate(None, &data, &mut their_dst);
+    assert!(matches!(replayed, TunnResultRaw::Err(_)));
+    // And a packet that was tampered with doesn't decrypt
+    let mut forged = encapsulate();
+    *forged.last_mut().unwrap() ^= 1;
+    let forged = their_tun.decapsulate(None, &forged, &mut their_dst);
+    assert!(matches!(forged, TunnResultRaw::Err(_)));
+
+    assert_eq!(
+        their_tun.drop_stats(),
+        DropStats {
+            rx_auth: 1,
+            rx_replay: 1,
+            tx_no_session: 0,
+        }
+    );
+}
+
+#[test]
+fn typed_results() {
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    let mut my_dst = [0u8; 2048];
+    let mut their_dst = [0u8; 2048];
+    let sent_packet_buf = create_ipv4_udp_packet();
+
+    // The packet is queued behind a handshake, which is only sent once
+    let init = match my_tun.try_encapsulate(&sent_packet_buf, &mut my_dst) {
+        Ok(TunnAction::WriteToNetwork(init)) => init.to_vec(),
+        r => panic!("Unexpected result {:?}", r),
+    };
+    assert_eq!(
+        my_tun.try_encapsulate(&sent_packet_buf, &mut my_dst),
+        Ok(TunnAction::Noop)
+    );
+    assert_eq!(
+        my_tun.try_format_handshake_initiation(&mut my_dst, false),
+        Ok(TunnAction::Noop)
+    );
+
+    let resp = match their_tun.try_decapsulate(None, &init, &mut their_dst) {
+        Ok(TunnAction::WriteToNetwork(resp)) => resp.to_vec(),
+        r => panic!("Unexpected result {:?}", r),
+    };
+    assert!(matches!(
+        my_tun.try_decapsulate(None, &resp, &mut my_dst),
+        Ok(TunnAction::WriteToNetwork(_))
+    ));
+
+    // Flushing sends the queued packets, then is done
+    let data = match my_tun.try_decapsulate(None, &[], &mut my_dst) {
+        Ok(TunnAction::WriteToNetwork(data)) => data.to_vec(),
+        r => panic!("Unexpected result {:?}", r),
+    };
+    assert!(matches!(
+        my_tun.try_decapsulate(None, &[], &mut my_dst),
+        Ok(TunnAction::WriteToNetwork(_))
+    ));
+    assert_eq!(
+        my_tun.try_decapsulate(None, &[], &mut my_dst),
+        Ok(TunnAction::Done)
+    );
+
+    assert_eq!(
+        their_tun.try_decapsulate(None, &data, &mut their_dst),
+        Ok(TunnAction::WriteToTunnel(
+            &sent_packet_buf,
+            Ipv4Addr::new(192, 168, 1, 2).into()
+        ))
+    );
+    assert!(matches!(
+        their_tun.try_decapsulate(None, &data, &mut their_dst),
+        Err(TunnError::Replayed(WireGuardError::DuplicateCounter))
+    ));
+}
+
+/// Counts the packets it encrypts and decrypts, and fails to encrypt once `fail` is set
+#[derive(Debug, Default)]
+struct CountingProvider {
+    sealed: AtomicUsize,
+    opened: AtomicUsize,
+    fail: AtomicBool,
+}
+
+impl CryptoProvider for CountingProvider {
+    fn chacha20poly1305_seal(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(CryptoError::Backend);
+        }
+        self.sealed.fetch_add(1, Ordering::SeqCst);
+        SoftwareCryptoProvider.chacha20poly1305_seal(key, nonce, aad, plaintext, out)
+    }
+
+    fn chacha20poly1305_open(
+        &self,
+        key: &[u8; 32],
+        nonce: u64,
+        aad: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        self.opened.fetch_add(1, Ordering::SeqCst);
+        SoftwareCryptoProvider.chacha20poly1305_open(key, nonce, aad, ciphertext, out)
+    }
+}
+
+#[test]
+fn crypto_provider() {
+    let provider = Arc::new(CountingProvider::default());
+    let (mut my_tun, mut their_tun) = create_two_tuns();
+    my_tun.set_crypto_provider(Some(provider.clone()));
+
+    // The handshake and its keepalive, which is a data packet
+    let init = create_handshake_init(&mut my_tun);
+    let resp = create_handshake_response(&mut their_tun, &init);
+    let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+    parse_keepalive(&mut their_tun, &keepalive);
+    assert_eq!(provider.sealed.load(Ordering::SeqCst), 1);
+
+    // Both ends interoperate with the built-in implementation
+    let mut my_dst = [0u8; 1024];
+    let mut their_dst = [0u8; 1024];
+    let sent_packet_buf = create_ipv4_udp_packet();
+    let data = match my_tun.try_encapsulate(&sent_packet_buf, &mut my_dst) {
+        Ok(TunnAction::WriteToNetwork(data)) => data.to_vec(),
+        r => panic!("Unexpected result {:?}", r),
+    };
+    assert!(matches!(
+        their_tun.try_decapsulate(None, &data, &mut their_dst),
+        Ok(TunnAction::WriteToTunnel(packet, _)) if packet == &sent_packet_buf[..]
+    ));
+
+    let data = match their_tun.try_encapsulate(&sent_packet_buf, &mut their_dst) {
+        Ok(TunnAction::WriteToNetwork(data)) => data.to_vec(),
+        r => panic!("Unexpected result {:?}", r),
+    };
+    assert!(matches!(
+        my_tun.try_decapsulate(None, &data, &mut my_dst),
+        Ok(TunnAction::WriteToTunnel(packet, _)) if packet == &sent_packet_buf[..]
+    ));
+    assert_eq!(provider.sealed.load(Ordering::SeqCst), 2);
+    assert_eq!(provider.opened.load(Ordering::SeqCst), 1);
+
+    provider.fail.store(true, Ordering::SeqCst);
+    assert!(matches!(
+        my_tun.encapsulate(&sent_packet_buf, &mut my_dst),
+        TunnResultRaw::Err(WireGuardError::CryptoProviderFailed)
+    ));
+}
+
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn create_two_tuns_with_keys(
+        my_key: [u8; 32],
+        their_key: [u8; 32],
+        psk: Option<[u8; 32]>,
+    ) -> (Tunn, Tunn) {
+        let my_secret_key = x25519::StaticSecret::from(my_key);
+        let my_public_key = x25519::PublicKey::from(&my_secret_key);
+        let their_secret_key = x25519::StaticSecret::from(their_key);
+        let their_public_key = x25519::PublicKey::from(&their_secret_key);
+
+        let my_tun = Tunn::new(my_secret_key, their_public_key, psk, None, 1, None, None).unwrap();
+        let their_tun =
+            Tunn::new(their_secret_key, my_public_key, psk, None, 2, None, None).unwrap();
+
+        (my_tun, their_tun)
+    }
+
+    fn handshake(my_tun: &mut Tunn, their_tun: &mut Tunn) {
+        let init = create_handshake_init(my_tun);
+        let resp = create_handshake_response(their_tun, &init);
+        let keepalive = parse_handshake_resp(my_tun, &resp);
+        parse_keepalive(their_tun, &keepalive);
+    }
+
+    fn create_ipv4_udp_packet_with_payload(payload: &[u8]) -> Vec<u8> {
+        let header =
+            etherparse::PacketBuilder::ipv4([192, 168, 1, 2], [192, 168, 1, 3], 5).udp(5678, 23);
+        let mut packet = Vec::<u8>::with_capacity(header.size(payload.len()));
+        header.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    proptest! {
+        #[test]
+        fn data_packet_round_trip(
+            my_key in any::<[u8; 32]>(),
+            their_key in any::<[u8; 32]>(),
+            psk in any::<Option<[u8; 32]>>(),
+            payload in proptest::collection::vec(any::<u8>(), 0..1400),
+        ) {
+            let (mut my_tun, mut their_tun) = create_two_tuns_with_keys(my_key, their_key, psk);
+            handshake(&mut my_tun, &mut their_tun);
+
+            let sent_packet = create_ipv4_udp_packet_with_payload(&payload);
+            let mut my_dst = vec![0u8; 2048];
+            let mut their_dst = vec![0u8; 2048];
+
+            let data = match my_tun.encapsulate(&sent_packet, &mut my_dst) {
+                TunnResultRaw::WriteToNetwork(data) => data,
+                r => panic!("Unexpected result {:?}", r),
+            };
+
+            match their_tun.decapsulate(None, data, &mut their_dst) {
+                TunnResultRaw::WriteToTunnelV4(recv, addr) => {
+                    prop_assert_eq!(&sent_packet[..], &recv[..]);
+                    prop_assert_eq!(addr, Ipv4Addr::new(192, 168, 1, 2));
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        #[test]
+        fn data_packet_bit_flip_is_rejected(
+            my_key in any::<[u8; 32]>(),
+            their_key in any::<[u8; 32]>(),
+            psk in any::<Option<[u8; 32]>>(),
+            payload in proptest::collection::vec(any::<u8>(), 0..1400),
+            flip in any::<proptest::sample::Index>(),
+            bit in 0..8u8,
+        ) {
+            let (mut my_tun, mut their_tun) = create_two_tuns_with_keys(my_key, their_key, psk);
+            handshake(&mut my_tun, &mut their_tun);
+
+            let sent_packet = create_ipv4_udp_packet_with_payload(&payload);
+            let mut my_dst = vec![0u8; 2048];
+            let mut their_dst = vec![0u8; 2048];
+
+            let data = match my_tun.encapsulate(&sent_packet, &mut my_dst) {
+                TunnResultRaw::WriteToNetwork(data) => data,
+                r => panic!("Unexpected result {:?}", r),
+            };
+
+            data[flip.index(data.len())] ^= 1 << bit;
+
+            let result = their_tun.decapsulate(None, data, &mut their_dst);
+            prop_assert!(matches!(result, TunnResultRaw::Err(_)), "Unexpected result {:?}", result);
+        }
+    }
+}
@@ -2,30 +2,68 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use super::errors::WireGuardError;
-use crate::noise::{Tunn, TunnResult};
+use crate::noise::{Clock, Tunn, TunnAction, TunnError, TunnResultRaw};
 use std::mem;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 use std::time::Duration;
 
-#[cfg(feature = "mock-instant")]
-use mock_instant::Instant;
-
-#[cfg(not(feature = "mock-instant"))]
-use crate::sleepyinstant::Instant;
-
 // Some constants, represent time in seconds
 // https://www.wireguard.com/papers/wireguard.pdf#page=14
 pub(crate) const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
-const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+pub(crate) const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
 const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
 pub(crate) const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
 const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 const COOKIE_EXPIRATION_TIME: Duration = Duration::from_secs(120);
 
+/// How long to wait for a response before sending a handshake initiation again. The retries stop
+/// after `REKEY_ATTEMPT_TIME` whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconnectPolicy {
+    /// Retry after the same interval every time
+    Fixed(Duration),
+    /// Retry after `initial`, then multiply the interval by `multiplier` after each initiation
+    /// that got no response, up to `max`. It goes back to `initial` once a handshake completes.
+    Exponential {
+        initial: Duration,
+        multiplier: f32,
+        max: Duration,
+    },
+}
+
+impl Default for ReconnectPolicy {
+    /// Retry every `REKEY_TIMEOUT`, as the protocol specifies
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::Fixed(REKEY_TIMEOUT)
+    }
+}
+
+impl ReconnectPolicy {
+    fn initial(&self) -> Duration {
+        match *self {
+            ReconnectPolicy::Fixed(interval) => interval,
+            ReconnectPolicy::Exponential { initial, .. } => initial,
+        }
+    }
+
+    /// The interval after an initiation sent `interval` after the previous one got no response
+    fn backoff(&self, interval: Duration) -> Duration {
+        match *self {
+            ReconnectPolicy::Fixed(interval) => interval,
+            ReconnectPolicy::Exponential {
+                multiplier, max, ..
+            } => Duration::try_from_secs_f32(interval.as_secs_f32() * multiplier)
+                .unwrap_or(max)
+                .min(max),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TimerName {
-    /// Current time, updated each call to `update_timers`
+    /// Current time, updated each call to `update_timers` and on every timer tick
     TimeCurrent,
     /// Time when last handshake was completed
     TimeSessionEstablished,
@@ -52,8 +90,9 @@ use self::TimerName::*;
 pub struct Timers {
     /// Is the owner of the timer the initiator or the responder for the last handshake?
     is_initiator: bool,
-    /// Start time of the tunnel
-    time_started: Instant,
+    pub(super) clock: Arc<dyn Clock>,
+    /// Start time of the tunnel, see [`Clock::elapsed_since_epoch`]
+    time_started: Duration,
     timers: [Duration; TimerName::Top as usize],
     pub(super) session_timers: [Duration; super::N_SESSIONS],
     /// Did we receive data without sending anything back?
@@ -61,20 +100,33 @@ pub struct Timers {
     /// Did we send data without hearing back?
     want_handshake: bool,
     persistent_keepalive: usize,
+    /// How long before the current session expires a new handshake is initiated, by either side
+    proactive_rekey: Option<Duration>,
+    reconnect_policy: ReconnectPolicy,
+    /// How long the handshake initiation in progress waits for a response before it is retried
+    retry_interval: Duration,
     /// Should this timer call reset rr function (if not a shared rr instance)
     pub(super) should_reset_rr: bool,
 }
 
 impl Timers {
-    pub(super) fn new(persistent_keepalive: Option<u16>, reset_rr: bool) -> Timers {
+    pub(super) fn new(
+        persistent_keepalive: Option<u16>,
+        reset_rr: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Timers {
         Timers {
             is_initiator: false,
-            time_started: Instant::now(),
+            time_started: clock.elapsed_since_epoch(),
+            clock,
             timers: Default::default(),
             session_timers: Default::default(),
             want_keepalive: Default::default(),
             want_handshake: Default::default(),
             persistent_keepalive: usize::from(persistent_keepalive.unwrap_or(0)),
+            proactive_rekey: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            retry_interval: REKEY_TIMEOUT,
             should_reset_rr: reset_rr,
         }
     }
@@ -83,10 +135,24 @@ impl Timers {
         self.is_initiator
     }
 
+    /// When a session established at `session_established` is rekeyed proactively, if data was
+    /// exchanged on it
+    fn proactive_rekey_at(&self, session_established: Duration) -> Option<Duration> {
+        let lead = self.proactive_rekey?;
+        let data_exchanged = session_established < self[TimeLastDataPacketSent]
+            || session_established < self[TimeLastDataPacketReceived];
+        data_exchanged.then(|| session_established + REJECT_AFTER_TIME - lead)
+    }
+
+    /// The time since the tunnel started
+    pub(super) fn now(&self) -> Duration {
+        self.clock.elapsed_since_epoch() - self.time_started
+    }
+
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
     pub(super) fn clear(&mut self) {
-        let now = Instant::now().duration_since(self.time_started);
+        let now = self.now();
         for t in &mut self.timers[..] {
             *t = now;
         }
@@ -109,6 +175,16 @@ impl IndexMut<TimerName> for Timers {
 }
 
 impl Tunn {
+    /// Initiate a new handshake `lead_time` before the current session expires, if data was
+    /// exchanged on it, whichever side initiated it. The session is used until the new one is
+    /// established, so traffic doesn't wait for a handshake once it expired. The lead time is
+    /// capped so sessions are never rekeyed before `REKEY_AFTER_TIME`. `None` only rekeys as the
+    /// protocol requires.
+    pub fn set_proactive_rekey_lead_time(&mut self, lead_time: Option<Duration>) {
+        self.timers.proactive_rekey =
+            lead_time.map(|lead| lead.min(REJECT_AFTER_TIME - REKEY_AFTER_TIME));
+    }
+
     pub(super) fn timer_tick(&mut self, timer_name: TimerName) {
         match timer_name {
             TimeLastPacketReceived => {
@@ -122,7 +198,9 @@ impl Tunn {
             _ => {}
         }
 
-        let time = self.timers[TimeCurrent];
+        // The timers are not updated periodically, the time of the last update may be long past
+        let time = self.timers.now();
+        self.timers[TimeCurrent] = time;
         self.timers[timer_name] = time;
     }
 
@@ -132,18 +210,21 @@ impl Tunn {
         session_idx: usize,
     ) {
         self.timer_tick(TimeSessionEstablished);
+        self.handshakes_completed += 1;
         self.timers.session_timers[session_idx % crate::noise::N_SESSIONS] =
             self.timers[TimeCurrent];
         self.timers.is_initiator = is_initiator;
+        self.timers.retry_interval = self.timers.reconnect_policy.initial();
     }
 
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
     fn clear_all(&mut self) {
-        for session in &mut self.sessions {
+        for session in self.sessions.iter_mut() {
             *session = None;
         }
 
+        self.drops.tx_no_session += self.packet_queue.len() as u64;
         self.packet_queue.clear();
 
         self.timers.clear();
@@ -156,8 +237,9 @@ impl Tunn {
             if time_now - *t > REJECT_AFTER_TIME {
                 if let Some(session) = self.sessions[i].take() {
                     tracing::debug!(
-                        message = "SESSION_EXPIRED(REJECT_AFTER_TIME)",
-                        session = session.receiving_index
+                        message = "Session expired",
+                        timer = "REJECT_AFTER_TIME",
+                        local_idx = session.receiving_index
                     );
                 }
                 *t = time_now;
@@ -165,19 +247,25 @@ impl Tunn {
         }
     }
 
-    pub fn update_timers<'buf>(&mut self, dst: &'buf mut [u8]) -> TunnResult<'buf> {
+    /// Like [`Tunn::update_timers`], returns [`TunnAction::Done`] if there was nothing to send
+    pub fn try_update_timers<'buf>(
+        &mut self,
+        dst: &'buf mut [u8],
+    ) -> Result<TunnAction<'buf>, TunnError> {
+        self.update_timers(dst).into()
+    }
+
+    pub fn update_timers<'buf>(&mut self, dst: &'buf mut [u8]) -> TunnResultRaw<'buf> {
         let mut handshake_initiation_required = false;
         let mut keepalive_required = false;
 
-        let time = Instant::now();
-
         if self.timers.should_reset_rr {
             self.rate_limiter.reset_count();
         }
 
         // All the times are counted from tunnel initiation, for efficiency our timers are rounded
         // to a second, as there is no real benefit to having highly accurate timers.
-        let now = time.duration_since(self.timers.time_started);
+        let now = self.timers.now();
         self.timers[TimeCurrent] = now;
 
         self.update_session_timers(now);
@@ -193,7 +281,7 @@ impl Tunn {
 
         {
             if self.handshake.is_expired() {
-                return TunnResult::Err(WireGuardError::ConnectionExpired);
+                return TunnResultRaw::Err(WireGuardError::ConnectionExpired);
             }
 
             // Clear cookie after COOKIE_EXPIRATION_TIME
@@ -206,32 +294,42 @@ impl Tunn {
             // All ephemeral private keys and symmetric session keys are zeroed out after
             // (REJECT_AFTER_TIME * 3) ms if no new keys have been exchanged.
             if now - session_established >= REJECT_AFTER_TIME * 3 {
-                tracing::error!("CONNECTION_EXPIRED(REJECT_AFTER_TIME * 3)");
+                tracing::error!(
+                    message = "Connection expired",
+                    timer = "REJECT_AFTER_TIME * 3"
+                );
                 self.handshake.set_expired();
                 self.clear_all();
-                return TunnResult::Err(WireGuardError::ConnectionExpired);
+                return TunnResultRaw::Err(WireGuardError::ConnectionExpired);
             }
 
-            if let Some(time_init_sent) = self.handshake.timer() {
+            if let Some(since_init_sent) = self.handshake.time_since_init_sent() {
                 // Handshake Initiation Retransmission
                 if now - handshake_started >= REKEY_ATTEMPT_TIME {
                     // After REKEY_ATTEMPT_TIME ms of trying to initiate a new handshake,
                     // the retries give up and cease, and clear all existing packets queued
                     // up to be sent. If a packet is explicitly queued up to be sent, then
                     // this timer is reset.
-                    tracing::error!("CONNECTION_EXPIRED(REKEY_ATTEMPT_TIME)");
+                    tracing::error!(message = "Connection expired", timer = "REKEY_ATTEMPT_TIME");
+                    self.handshake_failures += 1;
                     self.handshake.set_expired();
                     self.clear_all();
-                    return TunnResult::Err(WireGuardError::ConnectionExpired);
+                    return TunnResultRaw::Err(WireGuardError::ConnectionExpired);
                 }
 
-                if time_init_sent.elapsed() >= REKEY_TIMEOUT {
-                    // We avoid using `time` here, because it can be earlier than `time_init_sent`.
-                    // Once `checked_duration_since` is stable we can use that.
+                let retry_interval = self.timers.retry_interval;
+                if since_init_sent >= retry_interval {
                     // A handshake initiation is retried after REKEY_TIMEOUT + jitter ms,
                     // if a response has not been received, where jitter is some random
-                    // value between 0 and 333 ms.
-                    tracing::warn!("HANDSHAKE(REKEY_TIMEOUT)");
+                    // value between 0 and 333 ms. The reconnect policy may back off from
+                    // REKEY_TIMEOUT.
+                    tracing::warn!(
+                        message = "Retrying handshake",
+                        timer = "REKEY_TIMEOUT",
+                        ?retry_interval
+                    );
+                    self.timers.retry_interval =
+                        self.timers.reconnect_policy.backoff(retry_interval);
                     handshake_initiation_required = true;
                 }
             } else {
@@ -244,7 +342,10 @@ impl Tunn {
                     if session_established < data_packet_sent
                         && now - session_established >= REKEY_AFTER_TIME
                     {
-                        tracing::debug!("HANDSHAKE(REKEY_AFTER_TIME (on send))");
+                        tracing::debug!(
+                            message = "Initiating handshake",
+                            timer = "REKEY_AFTER_TIME (on send)"
+                        );
                         handshake_initiation_required = true;
                     }
 
@@ -257,14 +358,27 @@ impl Tunn {
                             >= REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
                     {
                         tracing::warn!(
-                            "HANDSHAKE(REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - \
-                        REKEY_TIMEOUT \
-                        (on receive))"
+                            message = "Initiating handshake",
+                            timer = "REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT \
+                                     (on receive)"
                         );
                         handshake_initiation_required = true;
                     }
                 }
 
+                // Before the current session expires, we initiate a new handshake if the
+                // proactive rekey is enabled
+                let has_session = self.sessions[self.current % super::N_SESSIONS].is_some();
+                if has_session
+                    && self
+                        .timers
+                        .proactive_rekey_at(session_established)
+                        .is_some_and(|at| now >= at)
+                {
+                    tracing::debug!(message = "Initiating handshake", timer = "PROACTIVE_REKEY");
+                    handshake_initiation_required = true;
+                }
+
                 // If we have sent a packet to a given peer but have not received a
                 // packet after from that peer for (KEEPALIVE + REKEY_TIMEOUT) ms,
                 // we initiate a new handshake.
@@ -272,7 +386,10 @@ impl Tunn {
                     && now - aut_packet_received >= KEEPALIVE_TIMEOUT + REKEY_TIMEOUT
                     && mem::replace(&mut self.timers.want_handshake, false)
                 {
-                    tracing::warn!("HANDSHAKE(KEEPALIVE + REKEY_TIMEOUT)");
+                    tracing::warn!(
+                        message = "Initiating handshake",
+                        timer = "KEEPALIVE + REKEY_TIMEOUT"
+                    );
                     handshake_initiation_required = true;
                 }
 
@@ -283,7 +400,7 @@ impl Tunn {
                         && now - aut_packet_sent >= KEEPALIVE_TIMEOUT
                         && mem::replace(&mut self.timers.want_keepalive, false)
                     {
-                        tracing::debug!("KEEPALIVE(KEEPALIVE_TIMEOUT)");
+                        tracing::debug!(message = "Sending keepalive", timer = "KEEPALIVE_TIMEOUT");
                         keepalive_required = true;
                     }
 
@@ -292,7 +409,10 @@ impl Tunn {
                         && (now - self.timers[TimePersistentKeepalive]
                             >= Duration::from_secs(persistent_keepalive as _))
                     {
-                        tracing::debug!("KEEPALIVE(PERSISTENT_KEEPALIVE)");
+                        tracing::debug!(
+                            message = "Sending keepalive",
+                            timer = "PERSISTENT_KEEPALIVE"
+                        );
                         self.timer_tick(TimePersistentKeepalive);
                         keepalive_required = true;
                     }
@@ -308,13 +428,77 @@ impl Tunn {
             return self.encapsulate(&[], dst);
         }
 
-        TunnResult::Done
+        TunnResultRaw::Done
+    }
+
+    /// How long until [`Tunn::update_timers`] has something to do, if no packet is sent or
+    /// received meanwhile. `None` if nothing is due until then, e.g. once the connection expired.
+    pub fn time_to_next_timer(&self) -> Option<Duration> {
+        if self.handshake.is_expired() {
+            return None;
+        }
+
+        let now = self.timers.now();
+        let timers = &self.timers;
+        let mut next: Option<Duration> = None;
+        let mut due = |at: Duration| next = Some(next.map_or(at, |next| next.min(at)));
+
+        // The same conditions as `update_timers`, in the same order
+        for (session, established) in self.sessions.iter().zip(&timers.session_timers) {
+            if session.is_some() {
+                due(*established + REJECT_AFTER_TIME);
+            }
+        }
+        if self.handshake.has_cookie() {
+            due(timers[TimeCookieReceived] + COOKIE_EXPIRATION_TIME);
+        }
+
+        let session_established = timers[TimeSessionEstablished];
+        due(session_established + REJECT_AFTER_TIME * 3);
+
+        if let Some(since_init_sent) = self.handshake.time_since_init_sent() {
+            due(timers[TimeLastHandshakeStarted] + REKEY_ATTEMPT_TIME);
+            due(now + timers.retry_interval.saturating_sub(since_init_sent));
+        } else {
+            let aut_packet_received = timers[TimeLastPacketReceived];
+            let aut_packet_sent = timers[TimeLastPacketSent];
+            let data_packet_received = timers[TimeLastDataPacketReceived];
+            let data_packet_sent = timers[TimeLastDataPacketSent];
+
+            if timers.is_initiator() {
+                if session_established < data_packet_sent {
+                    due(session_established + REKEY_AFTER_TIME);
+                }
+                if session_established < data_packet_received {
+                    due(session_established + REJECT_AFTER_TIME
+                        - KEEPALIVE_TIMEOUT
+                        - REKEY_TIMEOUT);
+                }
+            }
+            if self.sessions[self.current % super::N_SESSIONS].is_some() {
+                if let Some(at) = timers.proactive_rekey_at(session_established) {
+                    due(at);
+                }
+            }
+            if data_packet_sent > aut_packet_received && timers.want_handshake {
+                due(aut_packet_received + KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+            }
+            if data_packet_received > aut_packet_sent && timers.want_keepalive {
+                due(aut_packet_sent + KEEPALIVE_TIMEOUT);
+            }
+            if timers.persistent_keepalive > 0 {
+                let interval = Duration::from_secs(timers.persistent_keepalive as _);
+                due(timers[TimePersistentKeepalive] + interval);
+            }
+        }
+
+        next.map(|at| at.saturating_sub(now))
     }
 
     pub fn time_since_last_handshake(&self) -> Option<Duration> {
         let current_session = self.current;
         if self.sessions[current_session % super::N_SESSIONS].is_some() {
-            let duration_since_tun_start = Instant::now().duration_since(self.timers.time_started);
+            let duration_since_tun_start = self.timers.now();
             let duration_since_session_established = self.timers[TimeSessionEstablished];
 
             Some(duration_since_tun_start - duration_since_session_established)
@@ -323,6 +507,31 @@ impl Tunn {
         }
     }
 
+    /// How long the handshake in progress has been waiting for a response, across the
+    /// retransmissions of the initiation, or `None` if no initiation awaits a response
+    pub fn time_since_handshake_started(&self) -> Option<Duration> {
+        self.handshake.time_since_init_sent()?;
+        let now = self.timers.now();
+        Some(now.saturating_sub(self.timers[TimeLastHandshakeStarted]))
+    }
+
+    /// How long until the handshake initiation in progress is sent again, or `None` if no
+    /// initiation awaits a response
+    pub fn next_retry_in(&self) -> Option<Duration> {
+        let since_init_sent = self.handshake.time_since_init_sent()?;
+        Some(self.timers.retry_interval.saturating_sub(since_init_sent))
+    }
+
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.timers.reconnect_policy
+    }
+
+    /// Change how the handshake initiations are retried, the retry interval starts over
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.timers.reconnect_policy = policy;
+        self.timers.retry_interval = policy.initial();
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
         let keepalive = self.timers.persistent_keepalive;
 
@@ -332,4 +541,9 @@ impl Tunn {
             None
         }
     }
+
+    /// Change the persistent keepalive interval, takes effect on the next timer update
+    pub fn set_persistent_keepalive(&mut self, persistent_keepalive: Option<u16>) {
+        self.timers.persistent_keepalive = usize::from(persistent_keepalive.unwrap_or(0));
+    }
 }
@@ -0,0 +1,337 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An in-process virtual network, for testing a pair of [`Tunn`]s without root privileges.
+//!
+//! Both ends are connected by in-memory channels, so no UDP sockets or TUN devices are needed:
+//!
+//! ```
+//! use boringtun::test_utils::{VirtualConfig, VirtualNetwork};
+//!
+//! let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+//! VirtualNetwork::handshake(&mut a, &mut b).unwrap();
+//! assert!(a.tunn().time_since_last_handshake().is_some());
+//! ```
+
+use crate::noise::errors::WireGuardError;
+use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::{Clock, Tunn, TunnResultRaw};
+use crate::x25519;
+use rand_core::OsRng;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Large enough for any message produced by [`Tunn`]
+const MAX_PACKET_SIZE: usize = 1 << 16;
+/// The number of exchanges after which [`VirtualNetwork::handshake`] gives up
+const MAX_HANDSHAKE_ROUNDS: usize = 16;
+
+/// The configuration of one side of a [`VirtualNetwork`]
+pub struct VirtualConfig {
+    pub private_key: x25519::StaticSecret,
+    pub preshared_key: Option<[u8; 32]>,
+    pub persistent_keepalive: Option<u16>,
+    pub index: u32,
+    /// The handshake rate limiter, a default one is used if none is given
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// The clock of the tunnel, the system clock if none is given
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+impl VirtualConfig {
+    /// A configuration with a random private key, and the given session index
+    pub fn new(index: u32) -> VirtualConfig {
+        VirtualConfig {
+            private_key: x25519::StaticSecret::random_from_rng(OsRng),
+            preshared_key: None,
+            persistent_keepalive: None,
+            index,
+            rate_limiter: None,
+            clock: None,
+        }
+    }
+}
+
+/// Creates pairs of [`VirtualEndpoint`]s connected to each other
+pub struct VirtualNetwork;
+
+impl VirtualNetwork {
+    /// Create two tunnels that are peers of each other, connected by an in-memory channel in
+    /// each direction. `a` and `b` must share the same preshared key, if any.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(a: VirtualConfig, b: VirtualConfig) -> (VirtualEndpoint, VirtualEndpoint) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+
+        let a_public = x25519::PublicKey::from(&a.private_key);
+        let b_public = x25519::PublicKey::from(&b.private_key);
+
+        let a_tunn = Tunn::new(
+            a.private_key,
+            b_public,
+            a.preshared_key,
+            a.persistent_keepalive,
+            a.index,
+            a.rate_limiter,
+            a.clock,
+        )
+        .unwrap();
+        let b_tunn = Tunn::new(
+            b.private_key,
+            a_public,
+            b.preshared_key,
+            b.persistent_keepalive,
+            b.index,
+            b.rate_limiter,
+            b.clock,
+        )
+        .unwrap();
+
+        (
+            VirtualEndpoint::new(a_tunn, a_tx, a_rx),
+            VirtualEndpoint::new(b_tunn, b_tx, b_rx),
+        )
+    }
+
+    /// Have `a` initiate a handshake with `b`, and shuttle messages between the two until both
+    /// sides have a session. Returns the time it took.
+    pub fn handshake(
+        a: &mut VirtualEndpoint,
+        b: &mut VirtualEndpoint,
+    ) -> Result<Duration, WireGuardError> {
+        let start = Instant::now();
+
+        a.initiate_handshake()?;
+        for _ in 0..MAX_HANDSHAKE_ROUNDS {
+            b.receive()?;
+            a.receive()?;
+
+            if a.tunn.time_since_last_handshake().is_some()
+                && b.tunn.time_since_last_handshake().is_some()
+            {
+                return Ok(start.elapsed());
+            }
+        }
+
+        Err(WireGuardError::ConnectionExpired)
+    }
+}
+
+/// One side of a [`VirtualNetwork`].
+///
+/// Nothing happens in the background: messages are only sent and processed when the methods of
+/// the endpoint are called.
+pub struct VirtualEndpoint {
+    tunn: Tunn,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    drop_outgoing: bool,
+    sent: usize,
+    dropped: usize,
+    buf: Vec<u8>,
+}
+
+impl VirtualEndpoint {
+    fn new(tunn: Tunn, tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> VirtualEndpoint {
+        VirtualEndpoint {
+            tunn,
+            tx,
+            rx,
+            drop_outgoing: false,
+            sent: 0,
+            dropped: 0,
+            buf: vec![0u8; MAX_PACKET_SIZE],
+        }
+    }
+
+    pub fn tunn(&self) -> &Tunn {
+        &self.tunn
+    }
+
+    pub fn tunn_mut(&mut self) -> &mut Tunn {
+        &mut self.tunn
+    }
+
+    /// When set, messages written to the network by this endpoint are discarded, simulating a
+    /// lossy link
+    pub fn set_drop_outgoing(&mut self, drop: bool) {
+        self.drop_outgoing = drop;
+    }
+
+    /// The number of messages written to the network, including dropped ones
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// The number of messages discarded because of [`VirtualEndpoint::set_drop_outgoing`]
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Encrypt an IP packet and send it to the other side. If there is no session yet, the
+    /// packet is queued and a handshake is initiated instead.
+    pub fn send(&mut self, packet: &[u8]) -> Result<(), WireGuardError> {
+        match self.tunn.encapsulate(packet, &mut self.buf) {
+            TunnResultRaw::Done => Ok(()),
+            TunnResultRaw::Err(e) => Err(e),
+            TunnResultRaw::WriteToNetwork(data) => {
+                let data = data.to_vec();
+                self.transmit(data);
+                Ok(())
+            }
+            _ => panic!("Unexpected result from encapsulate"),
+        }
+    }
+
+    /// Send a handshake initiation to the other side
+    pub fn initiate_handshake(&mut self) -> Result<(), WireGuardError> {
+        match self.tunn.format_handshake_initiation(&mut self.buf, false) {
+            TunnResultRaw::Done => Ok(()),
+            TunnResultRaw::Err(e) => Err(e),
+            TunnResultRaw::WriteToNetwork(data) => {
+                let data = data.to_vec();
+                self.transmit(data);
+                Ok(())
+            }
+            _ => panic!("Unexpected result from format_handshake_initiation"),
+        }
+    }
+
+    /// Process every message received from the other side, and send any replies. Returns the
+    /// decrypted IP packets, in the order they were received.
+    pub fn receive(&mut self) -> Result<Vec<Vec<u8>>, WireGuardError> {
+        let mut packets = vec![];
+
+        while let Ok(datagram) = self.rx.try_recv() {
+            let mut src: &[u8] = &datagram;
+            loop {
+                match self.tunn.decapsulate(None, src, &mut self.buf) {
+                    TunnResultRaw::Done => break,
+                    TunnResultRaw::Err(e) => return Err(e),
+                    TunnResultRaw::WriteToNetwork(data) => {
+                        let data = data.to_vec();
+                        self.transmit(data);
+                        // Flush the packets that were queued while waiting for the handshake
+                        src = &[];
+                    }
+                    TunnResultRaw::WriteToTunnelV4(packet, _)
+                    | TunnResultRaw::WriteToTunnelV6(packet, _) => {
+                        packets.push(packet.to_vec());
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Run the timers of the tunnel, sending any resulting message to the other side
+    pub fn update_timers(&mut self) -> Result<(), WireGuardError> {
+        match self.tunn.update_timers(&mut self.buf) {
+            TunnResultRaw::Done => Ok(()),
+            TunnResultRaw::Err(e) => Err(e),
+            TunnResultRaw::WriteToNetwork(data) => {
+                let data = data.to_vec();
+                self.transmit(data);
+                Ok(())
+            }
+            _ => panic!("Unexpected result from update_timers"),
+        }
+    }
+
+    fn transmit(&mut self, data: Vec<u8>) {
+        self.sent += 1;
+        if self.drop_outgoing {
+            self.dropped += 1;
+            return;
+        }
+        // The other side may have been dropped already, in which case the message is lost
+        let _ = self.tx.send(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_udp_packet(payload: &[u8]) -> Vec<u8> {
+        let header =
+            etherparse::PacketBuilder::ipv4([192, 168, 1, 2], [192, 168, 1, 3], 5).udp(5678, 23);
+        let mut packet = Vec::<u8>::with_capacity(header.size(payload.len()));
+        header.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    #[test]
+    fn virtual_network_handshake() {
+        let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+        VirtualNetwork::handshake(&mut a, &mut b).unwrap();
+
+        assert!(a.tunn().time_since_last_handshake().is_some());
+        assert!(b.tunn().time_since_last_handshake().is_some());
+        // Initiation and keepalive from a, response from b
+        assert_eq!(a.sent(), 2);
+        assert_eq!(b.sent(), 1);
+    }
+
+    #[test]
+    fn virtual_network_queued_packet() {
+        let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+        let packet = ipv4_udp_packet(b"hello");
+
+        // No session yet, the packet is queued behind a handshake initiation
+        a.send(&packet).unwrap();
+        assert!(b.receive().unwrap().is_empty());
+        assert_eq!(a.receive().unwrap(), Vec::<Vec<u8>>::new());
+        assert_eq!(b.receive().unwrap(), vec![packet.clone()]);
+
+        b.send(&packet).unwrap();
+        assert_eq!(a.receive().unwrap(), vec![packet]);
+    }
+
+    #[test]
+    fn virtual_network_preshared_key() {
+        let mut config_a = VirtualConfig::new(1);
+        let mut config_b = VirtualConfig::new(2);
+        config_a.preshared_key = Some([7; 32]);
+        config_b.preshared_key = Some([7; 32]);
+
+        let (mut a, mut b) = VirtualNetwork::new(config_a, config_b);
+        VirtualNetwork::handshake(&mut a, &mut b).unwrap();
+
+        let packet = ipv4_udp_packet(&[0xaa; 1200]);
+        a.send(&packet).unwrap();
+        assert_eq!(b.receive().unwrap(), vec![packet]);
+    }
+
+    #[test]
+    fn virtual_network_dropped_handshake() {
+        let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+
+        a.set_drop_outgoing(true);
+        assert!(VirtualNetwork::handshake(&mut a, &mut b).is_err());
+        assert_eq!(a.dropped(), 1);
+        assert!(b.tunn().time_since_last_handshake().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn virtual_network_handshake_retry() {
+        let (mut a, mut b) = VirtualNetwork::new(VirtualConfig::new(1), VirtualConfig::new(2));
+
+        a.set_drop_outgoing(true);
+        a.send(&ipv4_udp_packet(b"hello")).unwrap();
+        assert_eq!(a.dropped(), 1);
+
+        a.set_drop_outgoing(false);
+        mock_instant::MockClock::advance(Duration::from_secs(6)); // Past REKEY_TIMEOUT
+        a.update_timers().unwrap();
+        assert_eq!(a.sent(), 2);
+
+        b.receive().unwrap();
+        a.receive().unwrap();
+        assert!(a.tunn().time_since_last_handshake().is_some());
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Shared setup for the fuzz targets. The keys are fixed, so the fuzzer can learn valid
+//! handshake messages between the two peers.
+
+#![allow(dead_code)]
+
+use boringtun::noise::{Tunn, TunnResultRaw};
+use boringtun::x25519::{PublicKey, StaticSecret};
+
+/// Size of the destination buffer given to the tunnel, large enough for any datagram
+pub const DST_SIZE: usize = 1 << 16;
+
+/// Create two tunnels that are peers of each other
+pub fn two_tuns() -> (Tunn, Tunn) {
+    let my_secret_key = StaticSecret::from([0x11u8; 32]);
+    let their_secret_key = StaticSecret::from([0x22u8; 32]);
+    let my_public_key = PublicKey::from(&my_secret_key);
+    let their_public_key = PublicKey::from(&their_secret_key);
+
+    let my_tun = Tunn::new(my_secret_key, their_public_key, None, None, 1, None, None).unwrap();
+    let their_tun = Tunn::new(their_secret_key, my_public_key, None, None, 2, None, None).unwrap();
+
+    (my_tun, their_tun)
+}
+
+/// Create two tunnels that have completed a handshake, so data packets can be decrypted
+pub fn two_tuns_and_handshake() -> (Tunn, Tunn) {
+    let (mut my_tun, mut their_tun) = two_tuns();
+    let mut dst = vec![0u8; DST_SIZE];
+    let mut their_dst = vec![0u8; DST_SIZE];
+
+    let init = match my_tun.format_handshake_initiation(&mut dst, false) {
+        TunnResultRaw::WriteToNetwork(init) => init,
+        _ => unreachable!(),
+    };
+    let resp = match their_tun.decapsulate(None, init, &mut their_dst) {
+        TunnResultRaw::WriteToNetwork(resp) => resp,
+        _ => unreachable!(),
+    };
+    let keepalive = match my_tun.decapsulate(None, resp, &mut dst) {
+        TunnResultRaw::WriteToNetwork(keepalive) => keepalive,
+        _ => unreachable!(),
+    };
+    assert!(matches!(
+        their_tun.decapsulate(None, keepalive, &mut their_dst),
+        TunnResultRaw::Done
+    ));
+
+    (my_tun, their_tun)
+}
@@ -0,0 +1,17 @@
+#![no_main]
+
+use boringtun::noise::TunnResultRaw;
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let (mut my_tun, _) = common::two_tuns();
+    let mut dst = vec![0u8; common::DST_SIZE];
+    // A cookie reply is only accepted while a handshake is in progress
+    assert!(matches!(
+        my_tun.format_handshake_initiation(&mut dst, false),
+        TunnResultRaw::WriteToNetwork(_)
+    ));
+    let _ = my_tun.consume_cookie_reply(data);
+});
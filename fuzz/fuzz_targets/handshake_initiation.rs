@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let (_, mut their_tun) = common::two_tuns();
+    let mut dst = vec![0u8; common::DST_SIZE];
+    let _ = their_tun.consume_handshake_initiation(data, &mut dst);
+});
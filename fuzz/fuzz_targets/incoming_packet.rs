@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::net::{IpAddr, Ipv4Addr};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // Use tunnels with an established session, so data packets reach the decryption path
+    let (mut my_tun, mut their_tun) = common::two_tuns_and_handshake();
+    let mut dst = vec![0u8; common::DST_SIZE];
+    let src_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    let _ = their_tun.handle_incoming_packet(src_addr, data, &mut dst);
+    let _ = my_tun.handle_incoming_packet(None, data, &mut dst);
+});